@@ -16,7 +16,7 @@
 
 //! I/O and event context generalizations.
 
-use network::{NetworkContext, PeerId, NodeId};
+use network::{NetworkContext, PeerId, NodeId, DisconnectReason};
 
 use super::{Announcement, LightProtocol, ReqId};
 use super::error::Error;
@@ -62,7 +62,7 @@ impl<T> IoContext for T where T: ?Sized + NetworkContext {
 
 	fn disconnect_peer(&self, peer: PeerId) {
 		trace!(target: "pip", "Initiating disconnect of peer {}", peer);
-		NetworkContext::disconnect_peer(self, peer);
+		NetworkContext::disconnect_peer(self, peer, DisconnectReason::DisconnectRequested, false);
 	}
 
 	fn disable_peer(&self, peer: PeerId) {
@@ -54,4 +54,9 @@ pub trait ChainNotify : Send + Sync {
 	) {
 		// does nothing by default
 	}
+
+	/// fires when new transactions are imported to the queue and should be propagated
+	fn transactions_imported(&self, _hashes: Vec<H256>) {
+		// does nothing by default
+	}
 }
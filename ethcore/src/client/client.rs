@@ -160,6 +160,10 @@ struct Importer {
 
 	/// Ethereum engine to be used during import
 	pub engine: Arc<EthEngine>,
+
+	/// Senders of the transactions of imported blocks, as recovered during verification.
+	/// Entries are kept until the miner has been notified of the new chain state.
+	block_senders: Mutex<HashMap<H256, Vec<Address>>>,
 }
 
 /// Blockchain database client backed by a persistent database. Owns and manages a blockchain and a block queue.
@@ -241,6 +245,7 @@ impl Importer {
 			ancient_verifier: Mutex::new(None),
 			rng: Mutex::new(OsRng::new()?),
 			engine,
+			block_senders: Mutex::new(HashMap::new()),
 		})
 	}
 
@@ -333,7 +338,13 @@ impl Importer {
 				let (enacted, retracted) = self.calculate_enacted_retracted(&import_results);
 
 				if is_empty {
+					// Blocks imported while the queue was busy are covered by this notification too.
+					let consumed: Vec<H256> = self.block_senders.lock().keys().cloned().collect();
 					self.miner.chain_new_blocks(client, &imported_blocks, &invalid_blocks, &enacted, &retracted);
+					let mut block_senders = self.block_senders.lock();
+					for hash in &consumed {
+						block_senders.remove(hash);
+					}
 				}
 
 				client.notify(|notify| {
@@ -501,6 +512,8 @@ impl Importer {
 
 		// Commit results
 		let receipts = block.receipts().to_owned();
+		let senders = block.transactions().iter().map(|tx| tx.sender()).collect();
+		self.block_senders.lock().insert(*hash, senders);
 		let traces = block.traces().clone().drain();
 
 		assert_eq!(header.hash(), BlockView::new(block_data).header_view().hash());
@@ -1332,6 +1345,10 @@ impl TransactionInfo for Client {
 	fn transaction_block(&self, id: TransactionId) -> Option<H256> {
 		self.transaction_address(id).map(|addr| addr.block_hash)
 	}
+
+	fn block_senders(&self, hash: &H256) -> Option<Vec<Address>> {
+		self.importer.block_senders.lock().get(hash).cloned()
+	}
 }
 
 impl BlockChainTrait for Client {}
@@ -1902,6 +1919,11 @@ impl BlockChainClient for Client {
 		self.importer.miner.ready_transactions(number, timestamp)
 	}
 
+	fn pending_transaction(&self, hash: &H256) -> Option<PendingTransaction> {
+		let number = self.chain.read().best_block_number();
+		self.importer.miner.transaction(number, hash)
+	}
+
 	fn queue_consensus_message(&self, message: Bytes) {
 		let channel = self.io_channel.lock().clone();
 		if let Err(e) = channel.send(ClientIoMessage::NewMessage(message)) {
@@ -2051,6 +2073,7 @@ impl ImportSealedBlock for Client {
 		};
 		let (enacted, retracted) = self.importer.calculate_enacted_retracted(&[route]);
 		self.importer.miner.chain_new_blocks(self, &[h.clone()], &[], &enacted, &retracted);
+		self.importer.block_senders.lock().remove(&h);
 		self.notify(|notify| {
 			notify.new_blocks(
 				vec![h.clone()],
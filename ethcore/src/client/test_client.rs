@@ -814,6 +814,10 @@ impl BlockChainClient for TestBlockChainClient {
 		self.miner.ready_transactions(info.best_block_number, info.best_block_timestamp)
 	}
 
+	fn pending_transaction(&self, hash: &H256) -> Option<PendingTransaction> {
+		self.miner.transaction(self.chain_info().best_block_number, hash)
+	}
+
 	fn signing_chain_id(&self) -> Option<u64> { None }
 
 	fn mode(&self) -> Mode { Mode::Active }
@@ -134,6 +134,9 @@ pub trait BlockInfo {
 pub trait TransactionInfo {
 	/// Get the hash of block that contains the transaction, if any.
 	fn transaction_block(&self, id: TransactionId) -> Option<H256>;
+
+	/// Get the senders of the transactions of a block being imported, as recovered during its verification.
+	fn block_senders(&self, _hash: &H256) -> Option<Vec<Address>> { None }
 }
 
 /// Provides methods to access chain state
@@ -317,6 +320,9 @@ pub trait BlockChainClient : Sync + Send + AccountData + BlockChain + CallContra
 	/// List all transactions that are allowed into the next block.
 	fn ready_transactions(&self) -> Vec<PendingTransaction>;
 
+	/// Get a pending transaction with given hash.
+	fn pending_transaction(&self, hash: &H256) -> Option<PendingTransaction>;
+
 	/// Sorted list of transaction gas prices from at least last sample_size blocks.
 	fn gas_price_corpus(&self, sample_size: usize) -> ::stats::Corpus<U256> {
 		let mut h = self.chain_info().best_block_hash;
@@ -113,6 +113,21 @@ pub enum Seal {
 	None,
 }
 
+/// Summary of the contents of a block about to be sealed.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BlockContext {
+	/// Gas used by the transactions of the block.
+	pub gas_used: U256,
+	/// Gas limit of the block.
+	pub gas_limit: U256,
+	/// Number of transactions.
+	pub tx_count: usize,
+	/// Number of distinct senders of the transactions.
+	pub unique_senders: usize,
+	/// Sum of the fees paid by the transactions, gas used times gas price.
+	pub total_fees: U256,
+}
+
 /// Type alias for a function we can get headers by hash through.
 pub type Headers<'a, H> = Fn(H256) -> Option<H> + 'a;
 
@@ -223,6 +238,12 @@ pub trait Engine<M: Machine>: Sync + Send {
 	/// light clients do not generate seals.
 	fn generate_seal(&self, _block: &M::LiveBlock, _parent: &M::Header) -> Seal { Seal::None }
 
+	/// Attempt to seal the block internally, knowing how full it is and who sent its transactions.
+	/// Engines with dynamic gas limits or inclusion rules implement this rather than `generate_seal`.
+	fn generate_seal_with_context(&self, block: &M::LiveBlock, parent: &M::Header, _context: &BlockContext) -> Seal {
+		self.generate_seal(block, parent)
+	}
+
 	/// Verify a locally-generated seal of a header.
 	///
 	/// If this engine seals internally,
@@ -107,6 +107,9 @@ extern crate ethabi_contract;
 #[macro_use]
 extern crate rlp_derive;
 extern crate rustc_hex;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate stats;
 extern crate stop_guard;
 extern crate time;
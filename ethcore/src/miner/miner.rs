@@ -14,16 +14,19 @@
 // You should have received a copy of the GNU General Public License
 // along with Parity.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::cell::Cell;
 use std::time::{Instant, Duration};
 use std::collections::{BTreeMap, HashSet};
+use std::iter::Zip;
+use std::slice;
 use std::sync::Arc;
 
 use account_provider::{AccountProvider, SignError as AccountError};
 use ansi_term::Colour;
 use ethereum_types::{H256, U256, Address};
-use parking_lot::{Mutex, RwLock};
+use parking_lot::{Mutex, MutexGuard, RwLock};
 use bytes::Bytes;
-use engines::{EthEngine, Seal};
+use engines::{EthEngine, Seal, BlockContext};
 use error::*;
 use ethcore_miner::banning_queue::{BanningTransactionQueue, Threshold};
 use ethcore_miner::local_transactions::{Status as LocalTransactionStatus};
@@ -34,10 +37,11 @@ use ethcore_miner::transaction_queue::{
 	PrioritizationStrategy,
 	AccountDetails,
 	TransactionOrigin,
+	QueueBreakdown,
 };
 use ethcore_miner::work_notify::{WorkPoster, NotifyWork};
 use miner::service_transaction_checker::ServiceTransactionChecker;
-use miner::{MinerService, MinerStatus};
+use miner::{MinerService, MinerStatus, MinerStatusReport, PendingState, AuthoringStats, AuthoringStopReason};
 use price_info::fetch::Client as FetchClient;
 use price_info::{Client as PriceInfoClient, PriceInfo};
 use transaction::{
@@ -52,14 +56,21 @@ use transaction::{
 use using_queue::{UsingQueue, GetAction};
 use block::{ClosedBlock, IsBlock, Block};
 use client::{
-	AccountData, BlockChain, RegistryInfo, ScheduleInfo, CallContract, BlockProducer, SealedBlockImporter
+	AccountData, BlockChain, RegistryInfo, ScheduleInfo, CallContract, BlockProducer, SealedBlockImporter,
+	TransactionInfo,
 };
 use client::{BlockId, TransactionId, MiningBlockChainClient};
 use executive::contract_address;
 use header::{Header, BlockNumber};
 use receipt::{Receipt, RichReceipt};
 use spec::Spec;
-use state::State;
+use rlp;
+
+/// Upper bound of the encoded size of a seal field. Signatures are the largest fields of the engines' seals.
+const SEAL_FIELD_SIZE: usize = 67;
+
+/// Bytes added to the encoded header, uncles and transactions of an authored block by the headers of the lists.
+const BLOCK_LISTS_SIZE: usize = 16;
 
 /// Different possible definitions for pending transaction set.
 #[derive(Debug, PartialEq)]
@@ -123,6 +134,12 @@ pub struct MinerOptions {
 	pub tx_queue_size: usize,
 	/// Maximum memory usage of transactions in the queue (current / future).
 	pub tx_queue_memory_limit: Option<usize>,
+	/// Maximum data size of external transactions accepted to the queue.
+	pub tx_data_size_limit: Option<usize>,
+	/// Maximum data size of local transactions accepted to the queue.
+	pub local_tx_data_size_limit: Option<usize>,
+	/// Maximum RLP-encoded size of authored blocks. Transactions stop being included once it would be exceeded.
+	pub max_block_size_bytes: Option<usize>,
 	/// Strategy to use for prioritizing transactions in the queue.
 	pub tx_queue_strategy: PrioritizationStrategy,
 	/// Whether we should fallback to providing all the queue's transactions or just pending.
@@ -141,6 +158,9 @@ pub struct MinerOptions {
 	/// NOTE: Such block will contain all pending transactions but
 	/// will be invalid if mined.
 	pub infinite_pending_block: bool,
+	/// Only consider a run of consecutive nonces of each sender ready. The run ends at the first missing
+	/// nonce or transaction over the gas limits, and at the first transaction the pending block rejects.
+	pub strict_nonce_continuity: bool,
 }
 
 impl Default for MinerOptions {
@@ -154,6 +174,9 @@ impl Default for MinerOptions {
 			tx_gas_limit: !U256::zero(),
 			tx_queue_size: 8192,
 			tx_queue_memory_limit: Some(2 * 1024 * 1024),
+			tx_data_size_limit: None,
+			local_tx_data_size_limit: None,
+			max_block_size_bytes: None,
 			tx_queue_gas_limit: GasLimit::None,
 			tx_queue_strategy: PrioritizationStrategy::GasPriceOnly,
 			pending_set: PendingSet::AlwaysQueue,
@@ -164,6 +187,7 @@ impl Default for MinerOptions {
 			tx_queue_banning: Banning::Disabled,
 			refuse_service_transactions: false,
 			infinite_pending_block: false,
+			strict_nonce_continuity: false,
 		}
 	}
 }
@@ -242,6 +266,62 @@ impl GasPricer {
 struct SealingWork {
 	queue: UsingQueue<ClosedBlock>,
 	enabled: bool,
+	/// Pow hash and parent of the last work notified to the listeners.
+	last_notified: Option<(H256, H256)>,
+}
+
+thread_local! {
+	/// Set while a `with_pending_*` callback runs on this thread, with the sealing lock held.
+	static IN_PENDING_CALLBACK: Cell<bool> = Cell::new(false);
+}
+
+/// Marks the thread as running a `with_pending_*` callback until dropped.
+struct PendingCallbackGuard;
+
+impl PendingCallbackGuard {
+	fn enter() -> Self {
+		IN_PENDING_CALLBACK.with(|running| running.set(true));
+		PendingCallbackGuard
+	}
+}
+
+impl Drop for PendingCallbackGuard {
+	fn drop(&mut self) {
+		IN_PENDING_CALLBACK.with(|running| running.set(false));
+	}
+}
+
+/// Source of the current time for reseal scheduling.
+enum ResealClock {
+	System,
+	/// Time that only moves when advanced.
+	#[cfg(test)]
+	Manual(Mutex<Instant>),
+}
+
+impl ResealClock {
+	fn now(&self) -> Instant {
+		match *self {
+			ResealClock::System => Instant::now(),
+			#[cfg(test)]
+			ResealClock::Manual(ref now) => *now.lock(),
+		}
+	}
+
+	#[cfg(test)]
+	fn advance(&self, by: Duration) {
+		if let ResealClock::Manual(ref now) = *self {
+			let mut now = now.lock();
+			*now = *now + by;
+		}
+	}
+}
+
+/// Callback propagating newly imported transactions.
+struct PropagationHook {
+	propagate: Box<Fn(Vec<H256>) + Send + Sync>,
+	/// Whether new transactions from the network are reported too.
+	external: bool,
 }
 
 /// Keeps track of transactions using priority queue and holds currently mined block.
@@ -250,9 +330,15 @@ pub struct Miner {
 	// NOTE [ToDr]  When locking always lock in this order!
 	transaction_queue: Arc<RwLock<BanningTransactionQueue>>,
 	transaction_listener: RwLock<Vec<Box<Fn(&[H256]) + Send + Sync>>>,
+	seal_listeners: RwLock<Vec<Box<Fn(H256, &BlockContext) + Send + Sync>>>,
+	propagation_hook: RwLock<Option<PropagationHook>>,
 	sealing_work: Mutex<SealingWork>,
+	/// Snapshot of the state of the pending block with the given hash.
+	pending_state: Mutex<Option<(H256, PendingState)>>,
+	authoring_stats: Mutex<Option<AuthoringStats>>,
 	next_allowed_reseal: Mutex<Instant>,
 	next_mandatory_reseal: RwLock<Instant>,
+	clock: ResealClock,
 	sealing_block_last_request: Mutex<u64>,
 	// for sealing...
 	options: MinerOptions,
@@ -269,10 +355,51 @@ pub struct Miner {
 }
 
 impl Miner {
+	/// Snapshot of queue and sealing metrics for monitoring.
+	pub fn status_report(&self) -> MinerStatusReport {
+		let mut report = MinerStatusReport::from(self.status());
+		report.sealing = self.lock_sealing_work().enabled;
+		report.pending_state_memory = self.pending_state.lock().as_ref().map_or(0, |&(_, ref state)| state.mem_used());
+		report.last_authoring = self.authoring_stats.lock().clone();
+		report
+	}
+
+	/// Queued transactions grouped into those ready at the given block and time, those delayed by
+	/// their condition and future ones, with up to `exemplars_per_group` hashes each. Ready
+	/// transactions are the ones `ready_transactions` takes from the queue.
+	pub fn queue_breakdown(&self, best_block: BlockNumber, best_block_timestamp: u64, exemplars_per_group: usize) -> QueueBreakdown {
+		self.transaction_queue.read().breakdown(best_block, best_block_timestamp, exemplars_per_group)
+	}
+
+	/// Call `f` with the transactions of the pending block if it is newer than `best_block`, without
+	/// cloning them. Returns `None` if there is no such block.
+	///
+	/// `f` runs with the sealing lock held and must not call back into the miner. Doing so would
+	/// deadlock; debug builds panic instead.
+	pub fn with_pending_transactions<F, T>(&self, best_block: BlockNumber, f: F) -> Option<T> where
+		F: for<'a> FnOnce(slice::Iter<'a, SignedTransaction>) -> T,
+	{
+		self.map_pending_block(|block| {
+			let _guard = PendingCallbackGuard::enter();
+			f(block.transactions().iter())
+		}, best_block)
+	}
+
+	/// Call `f` with the transactions of the pending block if it is newer than `best_block`, paired
+	/// with their receipts, without cloning them. Same constraints as `with_pending_transactions`.
+	pub fn with_pending_receipts<F, T>(&self, best_block: BlockNumber, f: F) -> Option<T> where
+		F: for<'a> FnOnce(Zip<slice::Iter<'a, SignedTransaction>, slice::Iter<'a, Receipt>>) -> T,
+	{
+		self.map_pending_block(|block| {
+			let _guard = PendingCallbackGuard::enter();
+			f(block.transactions().iter().zip(block.receipts().iter()))
+		}, best_block)
+	}
+
 	/// Push notifier that will handle new jobs
 	pub fn push_notifier(&self, notifier: Box<NotifyWork>) {
 		self.notifiers.write().push(notifier);
-		self.sealing_work.lock().enabled = true;
+		self.lock_sealing_work().enabled = true;
 	}
 
 	/// Creates new instance of miner Arc.
@@ -288,13 +415,15 @@ impl Miner {
 		};
 		let mem_limit = options.tx_queue_memory_limit.unwrap_or_else(usize::max_value);
 
-		let txq = TransactionQueue::with_limits(
+		let mut txq = TransactionQueue::with_limits(
 			options.tx_queue_strategy,
 			options.tx_queue_size,
 			mem_limit,
 			gas_limit,
 			options.tx_gas_limit
 		);
+		txq.set_max_tx_data_size(options.tx_data_size_limit, options.local_tx_data_size_limit);
+		txq.set_strict_nonce_continuity(options.strict_nonce_continuity);
 		let txq = match options.tx_queue_banning {
 			Banning::Disabled => BanningTransactionQueue::new(txq, Threshold::NeverBan, Duration::from_secs(180)),
 			Banning::Enabled { ban_duration, min_offends, .. } => BanningTransactionQueue::new(
@@ -317,15 +446,21 @@ impl Miner {
 		Miner {
 			transaction_queue: Arc::new(RwLock::new(txq)),
 			transaction_listener: RwLock::new(vec![]),
+			seal_listeners: RwLock::new(vec![]),
+			propagation_hook: RwLock::new(None),
 			next_allowed_reseal: Mutex::new(Instant::now()),
 			next_mandatory_reseal: RwLock::new(Instant::now() + options.reseal_max_period),
+			clock: ResealClock::System,
 			sealing_block_last_request: Mutex::new(0),
 			sealing_work: Mutex::new(SealingWork{
 				queue: UsingQueue::new(options.work_queue_size),
 				enabled: options.force_sealing
 					|| !options.new_work_notify.is_empty()
-					|| spec.engine.seals_internally().is_some()
+					|| spec.engine.seals_internally().is_some(),
+				last_notified: None,
 			}),
+			pending_state: Mutex::new(None),
+			authoring_stats: Mutex::new(None),
 			gas_range_target: RwLock::new((U256::zero(), U256::zero())),
 			author: RwLock::new(Address::default()),
 			extra_data: RwLock::new(Vec::new()),
@@ -354,12 +489,25 @@ impl Miner {
 
 	/// Clear all pending block states
 	pub fn clear(&self) {
-		self.sealing_work.lock().queue.reset();
-	}
-
-	/// Get `Some` `clone()` of the current pending block's state or `None` if we're not sealing.
-	pub fn pending_state(&self, latest_block_number: BlockNumber) -> Option<State<::state_db::StateDB>> {
-		self.map_pending_block(|b| b.state().clone(), latest_block_number)
+		self.lock_sealing_work().queue.reset();
+		*self.pending_state.lock() = None;
+	}
+
+	/// Get `Some` read-only view of the current pending block's state or `None` if we're not sealing.
+	/// The snapshot is taken once and shared until the pending block changes.
+	pub fn pending_state(&self, latest_block_number: BlockNumber) -> Option<PendingState> {
+		self.map_pending_block(|b| {
+			let hash = b.hash();
+			let mut cached = self.pending_state.lock();
+			if let Some((ref cached_hash, ref state)) = *cached {
+				if *cached_hash == hash {
+					return state.clone();
+				}
+			}
+			let state = PendingState::new(b.state());
+			*cached = Some((hash, state.clone()));
+			state
+		}, latest_block_number)
 	}
 
 	/// Get `Some` `clone()` of the current pending block or `None` if we're not sealing.
@@ -377,6 +525,41 @@ impl Miner {
 		self.transaction_listener.write().push(f);
 	}
 
+	/// Set a callback to be notified about blocks sealed internally and imported, with the context
+	/// the engine sealed them with.
+	pub fn add_seal_listener(&self, f: Box<Fn(H256, &BlockContext) + Send + Sync>) {
+		self.seal_listeners.write().push(f);
+	}
+
+	/// Set a callback propagating new local transactions right after their import, rather than
+	/// leaving them for the next round of the sync. `external` also passes new transactions from
+	/// the network. Hashes of the transactions imported to the current queue at once are passed
+	/// together; future transactions wait for the sync.
+	pub fn set_propagation_hook(&self, f: Box<Fn(Vec<H256>) + Send + Sync>, external: bool) {
+		*self.propagation_hook.write() = Some(PropagationHook {
+			propagate: f,
+			external: external,
+		});
+	}
+
+	/// Pass transactions newly imported to the current queue to the propagation hook.
+	/// Must be called without the queue and sealing locks held.
+	fn propagate(&self, hashes: Vec<H256>, external: bool) {
+		if hashes.is_empty() {
+			return;
+		}
+		if let Some(ref hook) = *self.propagation_hook.read() {
+			if !external || hook.external {
+				(hook.propagate)(hashes);
+			}
+		}
+	}
+
+	fn lock_sealing_work(&self) -> MutexGuard<SealingWork> {
+		debug_assert!(!IN_PENDING_CALLBACK.with(|running| running.get()), "The miner was called from a `with_pending_*` callback");
+		self.sealing_work.lock()
+	}
+
 	fn map_pending_block<F, T>(&self, f: F, latest_block_number: BlockNumber) -> Option<T> where
 		F: FnOnce(&ClosedBlock) -> T,
 	{
@@ -396,7 +579,7 @@ impl Miner {
 				Some((self.engine.params().nonce_cap_increment * (chain_info.best_block_number + 1)).into())
 			} else { None };
 			let transactions = {self.transaction_queue.read().top_transactions_at(chain_info.best_block_number, chain_info.best_block_timestamp, nonce_cap)};
-			let mut sealing_work = self.sealing_work.lock();
+			let mut sealing_work = self.lock_sealing_work();
 			let last_work_hash = sealing_work.queue.peek_last_ref().map(|pb| pb.block().header().hash());
 			let best_hash = chain_info.best_block_hash;
 
@@ -423,6 +606,11 @@ impl Miner {
 				}
 			};
 
+			// Rapid reseals must not author a block older than its parent.
+			if open_block.header().timestamp() <= chain_info.best_block_timestamp {
+				open_block.set_timestamp(chain_info.best_block_timestamp + 1);
+			}
+
 			if self.options.infinite_pending_block {
 				open_block.remove_gas_limit();
 			}
@@ -433,12 +621,36 @@ impl Miner {
 		let mut invalid_transactions = HashSet::new();
 		let mut non_allowed_transactions = HashSet::new();
 		let mut transactions_to_penalize = HashSet::new();
+		let mut broken_senders = HashSet::new();
 		let block_number = open_block.block().header().number();
 
+		// Sizes are only worked out when the block size is limited. A reopened block already has transactions.
+		let max_block_size = self.options.max_block_size_bytes;
+		let mut block_size = max_block_size.map(|_| {
+			rlp::encode(open_block.header()).len()
+				+ open_block.uncles().iter().map(|uncle| rlp::encode(uncle).len()).sum::<usize>()
+				+ open_block.transactions().iter().map(|tx| rlp::encode(tx).len()).sum::<usize>()
+				+ self.block_size_overhead(open_block.header())
+		});
+		let mut stop_reason = AuthoringStopReason::Exhausted;
+
 		let mut tx_count: usize = 0;
 		let tx_total = transactions.len();
 		for tx in transactions {
 			let hash = tx.hash();
+			let sender = tx.sender();
+			if broken_senders.contains(&sender) {
+				debug!(target: "miner", "Skipping transaction {:?} after an earlier transaction of the sender failed", hash);
+				continue;
+			}
+			let tx_size = block_size.map(|_| rlp::encode(&tx).len());
+			if let (Some(max_size), Some(size), Some(tx_size)) = (max_block_size, block_size, tx_size) {
+				if size + tx_size > max_size {
+					debug!(target: "miner", "Block size limit reached at {} bytes, skipping transaction {:?} of {} bytes", size, hash, tx_size);
+					stop_reason = AuthoringStopReason::SizeLimited;
+					break;
+				}
+			}
 			let start = Instant::now();
 			// Check whether transaction type is allowed for sender
 			let result = match self.engine.machine().verify_transaction(&tx, open_block.header(), chain) {
@@ -467,6 +679,13 @@ impl Miner {
 				_ => {},
 			}
 			trace!(target: "miner", "Adding tx {:?} took {:?}", hash, took);
+			// With strict nonce continuity the run of the sender ends at its first transaction left out.
+			if self.options.strict_nonce_continuity {
+				match result {
+					Ok(_) | Err(Error::Transaction(TransactionError::AlreadyImported)) => {},
+					Err(_) => { broken_senders.insert(sender); },
+				}
+			}
 			match result {
 				Err(Error::Execution(ExecutionError::BlockGasLimitReached { gas_limit, gas_used, gas })) => {
 					debug!(target: "miner", "Skipping adding transaction to block because of gas limit: {:?} (limit: {:?}, used: {:?}, gas: {:?})", hash, gas_limit, gas_used, gas);
@@ -479,6 +698,7 @@ impl Miner {
 					// Exit early if gas left is smaller then min_tx_gas
 					let min_tx_gas: U256 = 21000.into();	// TODO: figure this out properly.
 					if gas_limit - gas_used < min_tx_gas {
+						stop_reason = AuthoringStopReason::GasLimited;
 						break;
 					}
 				},
@@ -503,10 +723,18 @@ impl Miner {
 				},
 				_ => {
 					tx_count += 1;
+					if let (Some(size), Some(tx_size)) = (block_size.as_mut(), tx_size) {
+						*size += tx_size;
+					}
 				}	// imported ok
 			}
 		}
 		trace!(target: "miner", "Pushed {}/{} transactions", tx_count, tx_total);
+		*self.authoring_stats.lock() = Some(AuthoringStats {
+			transactions: open_block.transactions().len(),
+			size_bytes: block_size,
+			stop_reason: stop_reason,
+		});
 
 		let block = open_block.close();
 
@@ -540,7 +768,7 @@ impl Miner {
 	/// Check is reseal is allowed and necessary.
 	fn requires_reseal(&self, best_block: BlockNumber) -> bool {
 		let has_local_transactions = self.transaction_queue.read().has_local_pending_transactions();
-		let mut sealing_work = self.sealing_work.lock();
+		let mut sealing_work = self.lock_sealing_work();
 		if sealing_work.enabled {
 			trace!(target: "miner", "requires_reseal: sealing enabled");
 			let last_request = *self.sealing_block_last_request.lock();
@@ -556,10 +784,11 @@ impl Miner {
 				trace!(target: "miner", "Miner sleeping (current {}, last {})", best_block, last_request);
 				sealing_work.enabled = false;
 				sealing_work.queue.reset();
+				*self.pending_state.lock() = None;
 				false
 			} else {
 				// sealing enabled and we don't want to sleep.
-				*self.next_allowed_reseal.lock() = Instant::now() + self.options.reseal_min_period;
+				*self.next_allowed_reseal.lock() = self.clock.now() + self.options.reseal_min_period;
 				true
 			}
 		} else {
@@ -569,10 +798,10 @@ impl Miner {
 	}
 
 	/// Attempts to perform internal sealing (one that does not require work) and handles the result depending on the type of Seal.
-	fn seal_and_import_block_internally<C>(&self, chain: &C, block: ClosedBlock) -> bool
+	fn seal_and_import_block_internally<C>(&self, chain: &C, block: ClosedBlock, context: &BlockContext) -> bool
 		where C: BlockChain + SealedBlockImporter
 	{
-		if !block.transactions().is_empty() || self.forced_sealing() || Instant::now() > *self.next_mandatory_reseal.read() {
+		if !block.transactions().is_empty() || self.forced_sealing() || self.clock.now() > *self.next_mandatory_reseal.read() {
 			trace!(target: "miner", "seal_block_internally: attempting internal seal.");
 
 			let parent_header = match chain.block_header(BlockId::Hash(*block.header().parent_hash())) {
@@ -580,13 +809,13 @@ impl Miner {
 				None => return false,
 			};
 
-			match self.engine.generate_seal(block.block(), &parent_header) {
+			match self.engine.generate_seal_with_context(block.block(), &parent_header, context) {
 				// Save proposal for later seal submission and broadcast it.
 				Seal::Proposal(seal) => {
 					trace!(target: "miner", "Received a Proposal seal.");
-					*self.next_mandatory_reseal.write() = Instant::now() + self.options.reseal_max_period;
+					*self.next_mandatory_reseal.write() = self.clock.now() + self.options.reseal_max_period;
 					{
-						let mut sealing_work = self.sealing_work.lock();
+						let mut sealing_work = self.lock_sealing_work();
 						sealing_work.queue.push(block.clone());
 						sealing_work.queue.use_last_ref();
 					}
@@ -601,11 +830,20 @@ impl Miner {
 				},
 				// Directly import a regular sealed block.
 				Seal::Regular(seal) => {
-					*self.next_mandatory_reseal.write() = Instant::now() + self.options.reseal_max_period;
+					*self.next_mandatory_reseal.write() = self.clock.now() + self.options.reseal_max_period;
 					block
 						.lock()
 						.seal(&*self.engine, seal)
-						.map(|sealed| chain.import_sealed_block(sealed).is_ok())
+						.map(|sealed| {
+							let hash = sealed.header().hash();
+							let imported = chain.import_sealed_block(sealed).is_ok();
+							if imported {
+								for listener in &*self.seal_listeners.read() {
+									listener(hash, context);
+								}
+							}
+							imported
+						})
 						.unwrap_or_else(|e| {
 							warn!("ERROR: seal failed when given internally generated seal: {}", e);
 							false
@@ -620,8 +858,8 @@ impl Miner {
 
 	/// Prepares work which has to be done to seal.
 	fn prepare_work(&self, block: ClosedBlock, original_work_hash: Option<H256>) {
-		let (work, is_new) = {
-			let mut sealing_work = self.sealing_work.lock();
+		let (work, is_new, stale) = {
+			let mut sealing_work = self.lock_sealing_work();
 			let last_work_hash = sealing_work.queue.peek_last_ref().map(|pb| pb.block().header().hash());
 			trace!(target: "miner", "prepare_work: Checking whether we need to reseal: orig={:?} last={:?}, this={:?}", original_work_hash, last_work_hash, block.block().header().hash());
 			let (work, is_new) = if last_work_hash.map_or(true, |h| h != block.block().header().hash()) {
@@ -629,22 +867,43 @@ impl Miner {
 				let pow_hash = block.block().header().hash();
 				let number = block.block().header().number();
 				let difficulty = *block.block().header().difficulty();
+				let parent_hash = *block.block().header().parent_hash();
 				let is_new = original_work_hash.map_or(true, |h| block.block().header().hash() != h);
 				sealing_work.queue.push(block);
+				*self.pending_state.lock() = None;
 				// If push notifications are enabled we assume all work items are used.
 				if !self.notifiers.read().is_empty() && is_new {
 					sealing_work.queue.use_last_ref();
 				}
-				(Some((pow_hash, difficulty, number)), is_new)
+				(Some((pow_hash, difficulty, number, parent_hash)), is_new)
 			} else {
 				(None, false)
 			};
+			// Work on another parent makes the previous job stale.
+			let stale = match work {
+				Some((pow_hash, _, _, parent_hash)) if is_new => {
+					let stale = match sealing_work.last_notified {
+						Some((old_hash, old_parent)) if old_parent != parent_hash => Some(old_hash),
+						_ => None,
+					};
+					sealing_work.last_notified = Some((pow_hash, parent_hash));
+					stale
+				},
+				_ => None,
+			};
 			trace!(target: "miner", "prepare_work: leaving (last={:?})", sealing_work.queue.peek_last_ref().map(|b| b.block().header().hash()));
-			(work, is_new)
+			(work, is_new, stale)
 		};
 		if is_new {
-			work.map(|(pow_hash, difficulty, number)| {
-				for notifier in self.notifiers.read().iter() {
+			work.map(|(pow_hash, difficulty, number, _)| {
+				let notifiers = self.notifiers.read();
+				if let Some(stale) = stale {
+					trace!(target: "miner", "prepare_work: job {} is stale", stale);
+					for notifier in notifiers.iter() {
+						notifier.notify_stale(stale)
+					}
+				}
+				for notifier in notifiers.iter() {
 					notifier.notify(pow_hash, difficulty, number)
 				}
 			});
@@ -665,7 +924,7 @@ impl Miner {
 	fn prepare_work_sealing<C: AccountData + BlockChain + BlockProducer + CallContract>(&self, client: &C) -> bool {
 		trace!(target: "miner", "prepare_work_sealing: entering");
 		let prepare_new = {
-			let mut sealing_work = self.sealing_work.lock();
+			let mut sealing_work = self.lock_sealing_work();
 			let have_work = sealing_work.queue.peek_last_ref().is_some();
 			trace!(target: "miner", "prepare_work_sealing: have_work={}", have_work);
 			if !have_work {
@@ -756,12 +1015,20 @@ impl Miner {
 		results
 	}
 
-	/// Are we allowed to do a non-mandatory reseal?
-	fn tx_reseal_allowed(&self) -> bool { Instant::now() > *self.next_allowed_reseal.lock() }
+	/// Bytes the seal of the engine and the list headers add to the encoded parts of a block.
+	fn block_size_overhead(&self, header: &Header) -> usize {
+		self.engine.seal_fields(header) * SEAL_FIELD_SIZE + BLOCK_LISTS_SIZE
+	}
+
+	/// Are we allowed to do a non-mandatory reseal? Always if `reseal_min_period` is zero, otherwise
+	/// once the period has passed since the last reseal.
+	fn tx_reseal_allowed(&self) -> bool {
+		self.options.reseal_min_period == Duration::new(0, 0) || self.clock.now() >= *self.next_allowed_reseal.lock()
+	}
 
 	fn from_pending_block<H, F, G>(&self, latest_block_number: BlockNumber, from_chain: F, map_block: G) -> H
 		where F: Fn() -> H, G: FnOnce(&ClosedBlock) -> H {
-		let sealing_work = self.sealing_work.lock();
+		let sealing_work = self.lock_sealing_work();
 		sealing_work.queue.peek_last_ref().map_or_else(
 			|| from_chain(),
 			|b| {
@@ -778,7 +1045,7 @@ impl Miner {
 const SEALING_TIMEOUT_IN_BLOCKS : u64 = 5;
 
 impl MinerService for Miner {
-	type State = State<::state_db::StateDB>;
+	type State = PendingState;
 
 	fn clear_and_reset<C: MiningBlockChainClient>(&self, chain: &C) {
 		self.transaction_queue.write().clear();
@@ -791,7 +1058,7 @@ impl MinerService for Miner {
 
 	fn status(&self) -> MinerStatus {
 		let status = self.transaction_queue.read().status();
-		let sealing_work = self.sealing_work.lock();
+		let sealing_work = self.lock_sealing_work();
 		MinerStatus {
 			transactions_in_pending_queue: status.pending,
 			transactions_in_future_queue: status.future,
@@ -801,7 +1068,7 @@ impl MinerService for Miner {
 
 	fn set_author(&self, author: Address) {
 		if self.engine.seals_internally().is_some() {
-			let mut sealing_work = self.sealing_work.lock();
+			let mut sealing_work = self.lock_sealing_work();
 			sealing_work.enabled = true;
 		}
 		*self.author.write() = author;
@@ -813,7 +1080,7 @@ impl MinerService for Miner {
 				ap.sign(address.clone(), Some(password.clone()), Default::default())?;
 				// Limit the scope of the locks.
 				{
-					let mut sealing_work = self.sealing_work.lock();
+					let mut sealing_work = self.lock_sealing_work();
 					sealing_work.enabled = true;
 					*self.author.write() = address;
 				}
@@ -902,12 +1169,21 @@ impl MinerService for Miner {
 		transactions: Vec<UnverifiedTransaction>
 	) -> Vec<Result<TransactionImportResult, Error>> {
 		trace!(target: "external_tx", "Importing external transactions");
+		let hashes: Vec<H256> = transactions.iter().map(|tx| tx.hash()).collect();
 		let results = {
 			let mut transaction_queue = self.transaction_queue.write();
 			self.add_transactions_to_queue(
 				client, transactions, TransactionOrigin::External, None, &mut transaction_queue
 			)
 		};
+		self.propagate(hashes.into_iter()
+			.zip(&results)
+			.filter(|&(_, result)| match *result {
+				Ok(TransactionImportResult::Current) => true,
+				_ => false,
+			})
+			.map(|(hash, _)| hash)
+			.collect(), true);
 
 		if !results.is_empty() && self.options.reseal_on_external_tx &&	self.tx_reseal_allowed() {
 			// --------------------------------------------------------------------------
@@ -927,6 +1203,7 @@ impl MinerService for Miner {
 
 		trace!(target: "own_tx", "Importing transaction: {:?}", pending);
 
+		let hash = pending.transaction.hash();
 		let imported = {
 			// Be sure to release the lock before we call prepare_work_sealing
 			let mut transaction_queue = self.transaction_queue.write();
@@ -947,6 +1224,10 @@ impl MinerService for Miner {
 			import
 		};
 
+		if let Ok(TransactionImportResult::Current) = imported {
+			self.propagate(vec![hash], false);
+		}
+
 		// --------------------------------------------------------------------------
 		// | NOTE Code below requires transaction_queue and sealing_work locks.     |
 		// | Make sure to release the locks before calling that method.             |
@@ -1141,7 +1422,8 @@ impl MinerService for Miner {
 			match self.engine.seals_internally() {
 				Some(true) => {
 					trace!(target: "miner", "update_sealing: engine indicates internal sealing");
-					if self.seal_and_import_block_internally(chain, block) {
+					let context = block_context(&block);
+					if self.seal_and_import_block_internally(chain, block, &context) {
 						trace!(target: "miner", "update_sealing: imported internally sealed block");
 					}
 				},
@@ -1155,7 +1437,7 @@ impl MinerService for Miner {
 	}
 
 	fn is_currently_sealing(&self) -> bool {
-		self.sealing_work.lock().queue.is_in_use()
+		self.lock_sealing_work().queue.is_in_use()
 	}
 
 	fn map_sealing_work<C, F, T>(&self, client: &C, f: F) -> Option<T>
@@ -1165,7 +1447,7 @@ impl MinerService for Miner {
 		trace!(target: "miner", "map_sealing_work: entering");
 		self.prepare_work_sealing(client);
 		trace!(target: "miner", "map_sealing_work: sealing prepared");
-		let mut sealing_work = self.sealing_work.lock();
+		let mut sealing_work = self.lock_sealing_work();
 		let ret = sealing_work.queue.use_last_ref();
 		trace!(target: "miner", "map_sealing_work: leaving use_last_ref={:?}", ret.as_ref().map(|b| b.block().header().hash()));
 		ret.map(f)
@@ -1173,7 +1455,7 @@ impl MinerService for Miner {
 
 	fn submit_seal<C: SealedBlockImporter>(&self, chain: &C, block_hash: H256, seal: Vec<Bytes>) -> Result<(), Error> {
 		let result =
-			if let Some(b) = self.sealing_work.lock().queue.get_used_if(
+			if let Some(b) = self.lock_sealing_work().queue.get_used_if(
 				if self.options.enable_resubmission {
 					GetAction::Clone
 				} else {
@@ -1229,6 +1511,9 @@ impl MinerService for Miner {
 			}
 		}
 
+		// Only the balances of accounts touched by the new blocks are checked again.
+		let touched = touched_accounts(chain, enacted);
+
 		// ...and at the end remove the old ones
 		{
 			let fetch_account = |a: &Address| AccountDetails {
@@ -1238,6 +1523,7 @@ impl MinerService for Miner {
 			let time = chain.chain_info().best_block_number;
 			let mut transaction_queue = self.transaction_queue.write();
 			transaction_queue.remove_old(&fetch_account, time);
+			transaction_queue.park_unaffordable(&touched, &fetch_account);
 		}
 
 		if enacted.len() > 0 || (imported.len() > 0 && self.options.reseal_on_uncle) {
@@ -1313,21 +1599,74 @@ impl<'a, C> TransactionQueueDetailsProvider for TransactionDetailsProvider<'a, C
 	}
 }
 
+/// Summary of a closed block for the engine, from the transactions and receipts it holds.
+fn block_context(block: &ClosedBlock) -> BlockContext {
+	let mut senders = HashSet::new();
+	let mut total_fees = U256::zero();
+	let mut previous_gas_used = U256::zero();
+	for (tx, receipt) in block.transactions().iter().zip(block.receipts()) {
+		senders.insert(tx.sender());
+		// Receipts hold the gas used by the block so far.
+		total_fees = total_fees.saturating_add((receipt.gas_used - previous_gas_used).saturating_mul(tx.gas_price));
+		previous_gas_used = receipt.gas_used;
+	}
+	BlockContext {
+		gas_used: *block.header().gas_used(),
+		gas_limit: *block.header().gas_limit(),
+		tx_count: block.transactions().len(),
+		unique_senders: senders.len(),
+		total_fees: total_fees,
+	}
+}
+
+/// Accounts whose balance the given blocks may have changed: the authors and the senders and recipients
+/// of the transactions. Senders are recovered again only for blocks the client no longer has them for.
+fn touched_accounts<C: BlockChain>(chain: &C, blocks: &[H256]) -> HashSet<Address> {
+	let mut touched = HashSet::new();
+	for hash in blocks {
+		let block = match chain.block(BlockId::Hash(*hash)) {
+			Some(block) => block,
+			None => continue,
+		};
+		touched.insert(block.author());
+		let transactions = block.transactions();
+		for tx in &transactions {
+			if let Action::Call(ref to) = tx.action {
+				touched.insert(*to);
+			}
+		}
+		match chain.block_senders(hash) {
+			Some(senders) => touched.extend(senders),
+			None => touched.extend(transactions.into_iter()
+				.filter_map(|tx| SignedTransaction::new(tx).ok())
+				.map(|tx| tx.sender())),
+		}
+	}
+	touched
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
-	use ethcore_miner::transaction_queue::PrioritizationStrategy;
+	use ethcore_miner::transaction_queue::{PrioritizationStrategy, ParkReason};
 	use ethereum_types::U256;
 	use ethkey::{Generator, Random};
-	use client::{TestBlockChainClient, EachBlockWith, ChainInfo};
+	use client::{TestBlockChainClient, EachBlockWith, ChainInfo, ImportBlock, BlockInfo, Nonce};
 	use hash::keccak;
 	use header::BlockNumber;
 	use rustc_hex::FromHex;
 	use spec::Spec;
 	use transaction::{SignedTransaction, Transaction, PendingTransaction, Action};
 	use miner::MinerService;
+	use state::{State, StateInfo, CleanupMode};
+	use state_db::StateDB;
 
-	use tests::helpers::{generate_dummy_client, generate_dummy_client_with_spec_and_accounts};
+	use block::OpenBlock;
+	use tests::helpers::{generate_dummy_client, generate_dummy_client_with_spec_and_accounts, generate_dummy_client_with_spec_and_data, get_temp_state_db};
+	use block::ExecutedBlock;
+	use engines::Engine;
+	use machine::EthereumMachine;
+	use ethkey::KeyPair;
 
 	#[test]
 	fn should_prepare_block_to_seal() {
@@ -1374,6 +1713,9 @@ mod tests {
 				tx_gas_limit: !U256::zero(),
 				tx_queue_size: 1024,
 				tx_queue_memory_limit: None,
+				tx_data_size_limit: None,
+				local_tx_data_size_limit: None,
+				max_block_size_bytes: None,
 				tx_queue_gas_limit: GasLimit::None,
 				tx_queue_strategy: PrioritizationStrategy::GasFactorAndGasPrice,
 				pending_set: PendingSet::AlwaysSealing,
@@ -1382,6 +1724,7 @@ mod tests {
 				tx_queue_banning: Banning::Disabled,
 				refuse_service_transactions: false,
 				infinite_pending_block: false,
+				strict_nonce_continuity: false,
 			},
 			GasPricer::new_fixed(0u64.into()),
 			&Spec::new_test(),
@@ -1515,4 +1858,552 @@ mod tests {
 		let client = generate_dummy_client_with_spec_and_accounts(spec, None);
 		assert!(match client.miner().set_engine_signer(addr, "".into()) { Err(AccountError::NotFound) => true, _ => false });
 	}
+
+	#[test]
+	fn should_report_queue_status() {
+		// given
+		let client = TestBlockChainClient::default();
+		let miner = miner();
+		assert_eq!(miner.status_report().pending_transactions, 0);
+
+		// when
+		let res = miner.import_own_transaction(&client, PendingTransaction::new(transaction(), None));
+
+		// then
+		assert_eq!(res.unwrap(), TransactionImportResult::Current);
+		let report = miner.status_report();
+		assert_eq!(report.pending_transactions, 1);
+		assert_eq!(report.future_transactions, 0);
+	}
+
+	#[test]
+	fn should_share_pending_state_until_reseal() {
+		// given
+		let client = TestBlockChainClient::default();
+		let miner = miner();
+		let best_block = 0;
+		miner.import_own_transaction(&client, PendingTransaction::new(transaction(), None)).unwrap();
+
+		// when
+		let first = miner.pending_state(best_block).unwrap();
+		let second = miner.pending_state(best_block).unwrap();
+
+		// then
+		assert!(first.is_same(&second));
+		assert_eq!(miner.status_report().pending_state_memory, first.mem_used());
+
+		// when
+		miner.import_own_transaction(&client, PendingTransaction::new(transaction(), None)).unwrap();
+		miner.update_sealing(&client);
+		let third = miner.pending_state(best_block).unwrap();
+
+		// then
+		assert!(!third.is_same(&first));
+		assert!(third.is_same(&miner.pending_state(best_block).unwrap()));
+	}
+
+	#[test]
+	fn should_keep_changes_to_pending_state_views_apart() {
+		// given
+		let client = TestBlockChainClient::default();
+		let miner = miner();
+		let best_block = 0;
+		miner.import_own_transaction(&client, PendingTransaction::new(transaction(), None)).unwrap();
+		let pending = miner.pending_state(best_block).unwrap();
+		let address = Address::from(7);
+
+		// when
+		let mut view: State<StateDB> = pending.clone().into();
+		view.add_balance(&address, &10.into(), CleanupMode::ForceCreate).unwrap();
+		view.commit().unwrap();
+
+		// then
+		assert_eq!(view.balance(&address).unwrap(), 10.into());
+		assert_eq!(pending.balance(&address).unwrap(), 0.into());
+		assert_eq!(pending.view().balance(&address).unwrap(), 0.into());
+	}
+
+	fn transaction_with_data(data_len: usize) -> SignedTransaction {
+		let keypair = Random.generate().unwrap();
+		Transaction {
+			action: Action::Call(Address::default()),
+			value: U256::zero(),
+			data: vec![0; data_len],
+			gas: U256::from(100_000),
+			gas_price: U256::zero(),
+			nonce: U256::zero(),
+		}.sign(keypair.secret(), Some(2))
+	}
+
+	#[test]
+	fn should_stop_including_transactions_at_block_size_limit() {
+		// given
+		const MAX_SIZE: usize = 10_000;
+		let client = TestBlockChainClient::default();
+		let miner = Miner::new_raw(
+			MinerOptions { max_block_size_bytes: Some(MAX_SIZE), ..Default::default() },
+			GasPricer::new_fixed(0u64.into()),
+			&Spec::new_test(),
+			None,
+		);
+		let transactions: Vec<UnverifiedTransaction> = (0..10).map(|_| transaction_with_data(2_000).into()).collect();
+		let tx_size = rlp::encode(&transactions[0]).len();
+		assert!(miner.import_external_transactions(&client, transactions).iter().all(|r| r.is_ok()));
+
+		// when
+		let (block, _) = miner.prepare_block(&client);
+
+		// then
+		let included = block.transactions().len();
+		assert!(included > 0 && included < 10);
+		let size = block.to_base().rlp_bytes(::header::Seal::With).len();
+		assert!(size < MAX_SIZE);
+		// The next transaction would not have fit, though there was gas left for it.
+		assert!(size + tx_size + miner.block_size_overhead(block.header()) > MAX_SIZE);
+		assert!(*block.header().gas_used() + U256::from(100_000) <= *block.header().gas_limit());
+		let stats = miner.status_report().last_authoring.unwrap();
+		assert_eq!(stats.stop_reason, AuthoringStopReason::SizeLimited);
+		assert_eq!(stats.transactions, included);
+		assert!(stats.size_bytes.unwrap() >= size);
+	}
+
+	#[test]
+	fn should_not_size_blocks_without_limit() {
+		// given
+		let client = TestBlockChainClient::default();
+		let miner = miner();
+		miner.import_own_transaction(&client, PendingTransaction::new(transaction(), None)).unwrap();
+
+		// when
+		miner.prepare_block(&client);
+
+		// then
+		let stats = miner.status_report().last_authoring.unwrap();
+		assert_eq!(stats.transactions, 1);
+		assert_eq!(stats.size_bytes, None);
+	}
+
+	struct RecordingNotifier(Arc<Mutex<Vec<(&'static str, H256)>>>);
+
+	impl NotifyWork for RecordingNotifier {
+		fn notify(&self, pow_hash: H256, _difficulty: U256, _number: u64) {
+			self.0.lock().push(("work", pow_hash));
+		}
+
+		fn notify_stale(&self, pow_hash: H256) {
+			self.0.lock().push(("stale", pow_hash));
+		}
+	}
+
+	fn work_on(spec: &Spec, parent: &Header, timestamp: u64) -> ClosedBlock {
+		let db = spec.ensure_db_good(get_temp_state_db(), &Default::default()).unwrap();
+		let mut block = OpenBlock::new(
+			&*spec.engine,
+			Default::default(),
+			false,
+			db,
+			parent,
+			Arc::new(vec![parent.hash()]),
+			Address::default(),
+			(3141562.into(), 31415620.into()),
+			vec![],
+			false,
+		).unwrap();
+		block.set_timestamp(timestamp);
+		block.close()
+	}
+
+	#[test]
+	fn should_notify_stale_work_before_work_on_another_parent() {
+		// given
+		let client = TestBlockChainClient::default();
+		let spec = Spec::new_test();
+		let miner = Miner::with_spec(&spec);
+		let events = Arc::new(Mutex::new(Vec::new()));
+		miner.push_notifier(Box::new(RecordingNotifier(events.clone())));
+		miner.update_sealing(&client);
+		let first = events.lock()[0].1;
+		let child_of = |parent: &Header, extra_data: &[u8]| {
+			let mut header = Header::new();
+			header.set_parent_hash(parent.hash());
+			header.set_number(parent.number() + 1);
+			header.set_gas_limit(U256::from(1_000_000));
+			header.set_extra_data(extra_data.to_vec());
+			let mut block = rlp::RlpStream::new_list(3);
+			block.append(&header);
+			block.begin_list(0);
+			block.begin_list(0);
+			block.out()
+		};
+		let header_of = |hash: H256| client.block_header(BlockId::Hash(hash)).unwrap().decode();
+		let genesis = client.best_block_header().decode();
+
+		// when
+		// Branch A takes over from the genesis the first job was built on.
+		let a1 = client.import_block(child_of(&genesis, b"a")).unwrap();
+		assert_eq!(client.chain_info().best_block_hash, a1);
+		let (a_work, a_refresh) = (work_on(&spec, &header_of(a1), 1), work_on(&spec, &header_of(a1), 2));
+		let (a_hash, a_refresh_hash) = (a_work.hash(), a_refresh.hash());
+		miner.prepare_work(a_work, None);
+		miner.prepare_work(a_refresh, None);
+		// The longer branch B replaces it.
+		let b1 = client.import_block(child_of(&genesis, b"b")).unwrap();
+		assert_eq!(client.chain_info().best_block_hash, a1);
+		let b2 = client.import_block(child_of(&header_of(b1), b"b")).unwrap();
+		assert_eq!(client.chain_info().best_block_hash, b2);
+		assert_eq!(client.block_header(BlockId::Number(1)).unwrap().hash(), b1);
+		let b_work = work_on(&spec, &client.best_block_header().decode(), 3);
+		let b_hash = b_work.hash();
+		miner.prepare_work(b_work, None);
+
+		// then
+		assert_eq!(*events.lock(), vec![
+			("work", first),
+			("stale", first),
+			("work", a_hash),
+			// Same parent, the previous job is still good.
+			("work", a_refresh_hash),
+			("stale", a_refresh_hash),
+			("work", b_hash),
+		]);
+		let mut sealing_work = miner.sealing_work.lock();
+		assert_eq!(sealing_work.queue.peek_last_ref().map(|b| *b.block().header().parent_hash()), Some(b2));
+		// Stale jobs are kept for late solutions.
+		assert!(sealing_work.queue.get_used_if(GetAction::Clone, |b| b.hash() == first).is_some());
+		assert!(sealing_work.queue.get_used_if(GetAction::Clone, |b| b.hash() == a_refresh_hash).is_some());
+	}
+
+	fn recording_hook(miner: &Miner, external: bool) -> Arc<Mutex<Vec<Vec<H256>>>> {
+		let calls = Arc::new(Mutex::new(Vec::new()));
+		let recorded = calls.clone();
+		miner.set_propagation_hook(Box::new(move |hashes| recorded.lock().push(hashes)), external);
+		calls
+	}
+
+	#[test]
+	fn should_propagate_new_own_transactions_once() {
+		// given
+		let client = TestBlockChainClient::default();
+		let miner = miner();
+		let calls = recording_hook(&miner, false);
+		let tx = transaction();
+		let hash = tx.hash();
+
+		// when
+		miner.import_own_transaction(&client, PendingTransaction::new(tx.clone(), None)).unwrap();
+		miner.import_own_transaction(&client, PendingTransaction::new(tx, None)).unwrap_err();
+		miner.import_external_transactions(&client, vec![transaction().into()]);
+
+		// then
+		assert_eq!(*calls.lock(), vec![vec![hash]]);
+	}
+
+	#[test]
+	fn should_propagate_new_external_transactions_in_one_batch() {
+		// given
+		let client = TestBlockChainClient::default();
+		let miner = miner();
+		let calls = recording_hook(&miner, true);
+		let (tx1, tx2) = (transaction(), transaction());
+		miner.import_external_transactions(&client, vec![tx1.clone().into()]);
+
+		// when
+		let results = miner.import_external_transactions(&client, vec![tx1.clone().into(), tx2.clone().into()]);
+
+		// then
+		assert!(results[0].is_err());
+		assert_eq!(*calls.lock(), vec![vec![tx1.hash()], vec![tx2.hash()]]);
+	}
+
+	#[test]
+	fn should_not_propagate_future_transactions() {
+		// given
+		let client = TestBlockChainClient::default();
+		let miner = miner();
+		let calls = recording_hook(&miner, false);
+		let keypair = Random.generate().unwrap();
+		let future = Transaction {
+			action: Action::Create,
+			value: U256::zero(),
+			data: "3331600055".from_hex().unwrap(),
+			gas: U256::from(100_000),
+			gas_price: U256::zero(),
+			nonce: U256::one(),
+		}.sign(keypair.secret(), Some(2));
+
+		// when
+		let res = miner.import_own_transaction(&client, PendingTransaction::new(future, None));
+
+		// then
+		assert_eq!(res.unwrap(), TransactionImportResult::Future);
+		assert!(calls.lock().is_empty());
+	}
+
+	#[test]
+	fn should_break_down_queue() {
+		// given
+		let client = TestBlockChainClient::default();
+		let miner = miner();
+		let (ready, delayed) = (transaction(), transaction());
+		miner.import_own_transaction(&client, PendingTransaction::new(ready.clone(), None)).unwrap();
+		miner.import_own_transaction(&client, PendingTransaction::new(delayed.clone(), Some(TransactionCondition::Number(10)))).unwrap();
+
+		// when
+		let breakdown = miner.queue_breakdown(0, 0, 5);
+
+		// then
+		assert_eq!(breakdown.ready.count, 1);
+		assert_eq!(breakdown.ready.exemplars, vec![ready.hash()]);
+		assert_eq!(breakdown.delayed.count, 1);
+		assert_eq!(breakdown.delayed.exemplars, vec![delayed.hash()]);
+		assert_eq!(breakdown.future.count, 0);
+		assert_eq!(miner.queue_breakdown(10, 0, 5).ready.count, 2);
+	}
+
+	#[test]
+	fn should_park_transactions_of_senders_drained_by_new_block() {
+		// given
+		let client = TestBlockChainClient::default();
+		let miner = miner();
+		let keypair = Random.generate().unwrap();
+		let transfer = |nonce: u64, value: u64| Transaction {
+			action: Action::Call(Address::default()),
+			value: value.into(),
+			data: vec![],
+			gas: U256::from(100_000),
+			gas_price: U256::zero(),
+			nonce: nonce.into(),
+		}.sign(keypair.secret(), Some(2));
+		client.set_balance(keypair.address(), 3_000.into());
+		let queued: Vec<SignedTransaction> = (0..3).map(|nonce| transfer(nonce, 1_000)).collect();
+		for tx in &queued {
+			miner.import_external_transactions(&client, vec![tx.clone().into()]).pop().unwrap().unwrap();
+		}
+		assert_eq!(miner.status().transactions_in_pending_queue, 3);
+
+		// when
+		// A transaction sent elsewhere takes most of the balance.
+		let chain_info = client.chain_info();
+		let mut header = Header::new();
+		header.set_parent_hash(chain_info.best_block_hash);
+		header.set_number(chain_info.best_block_number + 1);
+		header.set_gas_limit(U256::from(1_000_000));
+		let mut block = rlp::RlpStream::new_list(3);
+		block.append(&header);
+		block.begin_list(1).append(&transfer(0, 2_500));
+		block.begin_list(0);
+		let hash = client.import_block(block.out()).unwrap();
+		client.set_nonce(keypair.address(), 1.into());
+		client.set_balance(keypair.address(), 500.into());
+		miner.chain_new_blocks(&client, &[hash], &[], &[hash], &[]);
+
+		// then
+		let status = miner.status();
+		assert_eq!(status.transactions_in_pending_queue, 0);
+		assert_eq!(status.transactions_in_future_queue, 2);
+		assert_eq!(miner.transaction_queue.read().parked_reason(&queued[1].hash()), Some(ParkReason::InsufficientBalance {
+			balance: 500.into(),
+			cost: 1_000.into(),
+		}));
+
+		// when
+		// A later block leaves the sender alone.
+		let mut header = Header::new();
+		header.set_parent_hash(hash);
+		header.set_number(chain_info.best_block_number + 2);
+		header.set_gas_limit(U256::from(1_000_000));
+		let mut block = rlp::RlpStream::new_list(3);
+		block.append(&header);
+		block.begin_list(0);
+		block.begin_list(0);
+		let hash = client.import_block(block.out()).unwrap();
+		miner.chain_new_blocks(&client, &[hash], &[], &[hash], &[]);
+
+		// then
+		let status = miner.status();
+		assert_eq!(status.transactions_in_pending_queue, 0);
+		assert_eq!(status.transactions_in_future_queue, 2);
+		assert!(miner.transaction_queue.read().parked_reason(&queued[1].hash()).is_some());
+	}
+
+	fn miner_with_pending_block(client: &TestBlockChainClient) -> Miner {
+		let miner = miner();
+		let transactions = vec![transaction().into(), transaction().into()];
+		for res in miner.import_external_transactions(client, transactions) {
+			res.unwrap();
+		}
+		assert!(miner.prepare_work_sealing(client));
+		miner
+	}
+
+	#[test]
+	fn should_serialize_pending_block_without_cloning() {
+		// given
+		let client = TestBlockChainClient::default();
+		let miner = miner_with_pending_block(&client);
+
+		// when
+		let mut stream = rlp::RlpStream::new();
+		stream.begin_unbounded_list();
+		let count = miner.with_pending_transactions(0, |txs| txs.map(|tx| { stream.append(tx); }).count());
+		stream.complete_unbounded_list();
+		let receipts = miner.with_pending_receipts(0, |pending| pending
+			.map(|(tx, receipt)| (tx.hash(), rlp::encode(receipt).into_vec()))
+			.collect::<BTreeMap<_, _>>()
+		);
+
+		// then
+		assert_eq!(count, Some(2));
+		let cloned: Vec<SignedTransaction> = miner.ready_transactions(0, 0).into_iter().map(|tx| tx.transaction).collect();
+		assert_eq!(stream.out(), rlp::encode_list::<SignedTransaction, _>(&cloned).into_vec());
+		let cloned = miner.pending_receipts(0).into_iter()
+			.map(|(hash, receipt)| (hash, rlp::encode(&receipt).into_vec()))
+			.collect::<BTreeMap<_, _>>();
+		assert_eq!(receipts, Some(cloned));
+		// The pending block is not newer than the best block.
+		assert_eq!(miner.with_pending_transactions(1, |txs| txs.count()), None);
+	}
+
+	#[test]
+	#[cfg(debug_assertions)]
+	#[should_panic(expected = "called from a `with_pending_*` callback")]
+	fn should_panic_when_miner_is_called_from_pending_callback() {
+		let client = TestBlockChainClient::default();
+		let miner = miner_with_pending_block(&client);
+		miner.with_pending_transactions(0, |_| miner.pending_receipts(0));
+	}
+
+	fn miner_with_manual_clock(reseal_min_period: Duration) -> Miner {
+		let mut miner = miner();
+		miner.options.reseal_min_period = reseal_min_period;
+		let start = Instant::now();
+		miner.clock = ResealClock::Manual(Mutex::new(start));
+		*miner.next_allowed_reseal.lock() = start;
+		miner
+	}
+
+	#[test]
+	fn should_reseal_on_every_transaction_without_min_period() {
+		let client = TestBlockChainClient::default();
+		let miner = miner_with_manual_clock(Duration::from_secs(0));
+		for count in 1..4 {
+			miner.import_own_transaction(&client, PendingTransaction::new(transaction(), None)).unwrap();
+			assert_eq!(miner.ready_transactions(0, 0).len(), count);
+			assert!(miner.tx_reseal_allowed());
+		}
+	}
+
+	#[test]
+	fn should_honour_sub_second_min_period() {
+		// given
+		let client = TestBlockChainClient::default();
+		let miner = miner_with_manual_clock(Duration::from_millis(100));
+		let import = || miner.import_own_transaction(&client, PendingTransaction::new(transaction(), None)).unwrap();
+
+		// when
+		import();
+		import();
+		assert_eq!(miner.ready_transactions(0, 0).len(), 2);
+		import();
+
+		// then
+		assert_eq!(miner.ready_transactions(0, 0).len(), 2);
+		miner.clock.advance(Duration::from_millis(99));
+		assert!(!miner.tx_reseal_allowed());
+		miner.clock.advance(Duration::from_millis(1));
+		assert!(miner.tx_reseal_allowed());
+		import();
+		assert_eq!(miner.ready_transactions(0, 0).len(), 4);
+		assert!(!miner.tx_reseal_allowed());
+	}
+
+	#[test]
+	fn should_not_regress_pending_timestamp_below_parent() {
+		let client = TestBlockChainClient::default();
+		// Blocks are opened with a timestamp not after the parent's.
+		client.set_latest_block_timestamp(0);
+		let parent_timestamp = client.chain_info().best_block_timestamp;
+		let miner = miner_with_manual_clock(Duration::from_secs(0));
+		for _ in 0..3 {
+			miner.import_own_transaction(&client, PendingTransaction::new(transaction(), None)).unwrap();
+			let timestamp = miner.map_pending_block(|block| block.block().header().timestamp(), 0);
+			assert_eq!(timestamp, Some(parent_timestamp + 1));
+		}
+	}
+
+	/// Seals internally, recording the context of each block.
+	struct ContextEngine {
+		machine: EthereumMachine,
+		contexts: Mutex<Vec<BlockContext>>,
+	}
+
+	impl Engine<EthereumMachine> for ContextEngine {
+		fn name(&self) -> &str { "ContextEngine" }
+
+		fn machine(&self) -> &EthereumMachine { &self.machine }
+
+		fn seals_internally(&self) -> Option<bool> { Some(true) }
+
+		fn generate_seal_with_context(&self, _block: &ExecutedBlock, _parent: &Header, context: &BlockContext) -> Seal {
+			self.contexts.lock().push(context.clone());
+			Seal::Regular(Vec::new())
+		}
+
+		fn verify_local_seal(&self, _header: &Header) -> Result<(), Error> { Ok(()) }
+	}
+
+	#[test]
+	fn should_pass_block_context_to_engine_and_seal_listeners() {
+		// given
+		let engine = Arc::new(ContextEngine {
+			machine: Spec::new_test_machine(),
+			contexts: Mutex::new(Vec::new()),
+		});
+		let mut spec = Spec::new_test();
+		spec.engine = engine.clone();
+		let miner = Miner::with_spec(&spec);
+		let sealed = Arc::new(Mutex::new(Vec::new()));
+		let listener_sealed = sealed.clone();
+		miner.add_seal_listener(Box::new(move |hash, context| listener_sealed.lock().push((hash, context.clone()))));
+
+		// Authored the first blocks, so it has the rewards to pay fees.
+		let client = generate_dummy_client_with_spec_and_data(Spec::new_test_with_reward, 2, 0, &[]);
+		let rich = KeyPair::from_secret_slice(&keccak("")).unwrap();
+		let poor = Random.generate().unwrap();
+		let transfer = |keypair: &KeyPair, nonce: U256, gas_price: u64| Transaction {
+			action: Action::Call(Address::from(0x10)),
+			value: U256::zero(),
+			data: vec![],
+			gas: U256::from(21_000),
+			gas_price: gas_price.into(),
+			nonce: nonce,
+		}.sign(keypair.secret(), Some(spec.chain_id()));
+		let rich_nonce = client.latest_nonce(&rich.address());
+
+		// when
+		let external = vec![
+			transfer(&rich, rich_nonce, 30_000_000_000).into(),
+			transfer(&rich, rich_nonce + 1.into(), 20_000_000_000).into(),
+		];
+		for res in miner.import_external_transactions(&*client, external) {
+			res.unwrap();
+		}
+		// Reseals right away. Own transactions may pay nothing.
+		let own = transfer(&poor, client.latest_nonce(&poor.address()), 0);
+		miner.import_own_transaction(&*client, PendingTransaction::new(own, None)).unwrap();
+		client.flush_queue();
+
+		// then
+		let header = client.block_header(BlockId::Latest).unwrap();
+		assert_eq!(header.number(), 3);
+		let expected = BlockContext {
+			gas_used: U256::from(63_000),
+			gas_limit: header.gas_limit(),
+			tx_count: 3,
+			unique_senders: 2,
+			total_fees: U256::from(21_000) * U256::from(50_000_000_000u64),
+		};
+		assert_eq!(*engine.contexts.lock(), vec![expected.clone()]);
+		assert_eq!(*sealed.lock(), vec![(header.hash(), expected)]);
+	}
 }
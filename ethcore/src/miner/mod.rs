@@ -41,8 +41,10 @@
 mod miner;
 mod stratum;
 mod service_transaction_checker;
+mod pending_state;
 
 pub use self::miner::{Miner, MinerOptions, Banning, PendingSet, GasPricer, GasPriceCalibratorOptions, GasLimit};
+pub use self::pending_state::PendingState;
 pub use self::stratum::{Stratum, Error as StratumError, Options as StratumOptions};
 
 pub use ethcore_miner::local_transactions::Status as LocalTransactionStatus;
@@ -185,7 +187,7 @@ pub trait MinerService : Send + Sync {
 	/// Suggested gas limit.
 	fn sensible_gas_limit(&self) -> U256 { 21000.into() }
 
-	/// Get `Some` `clone()` of the current pending block's state or `None` if we're not sealing.
+	/// Get `Some` read-only view of the current pending block's state or `None` if we're not sealing.
 	fn pending_state(&self, latest_block_number: BlockNumber) -> Option<Self::State>;
 
 	/// Get `Some` `clone()` of the current pending block header or `None` if we're not sealing.
@@ -205,3 +207,58 @@ pub struct MinerStatus {
 	/// Number of transactions included in currently mined block
 	pub transactions_in_pending_block: usize,
 }
+
+/// Queue and sealing metrics for monitoring. Field names are serialized as they are
+/// and dashboards key on them, so they must not be renamed.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct MinerStatusReport {
+	/// Transactions ready to be included in a block.
+	pub pending_transactions: usize,
+	/// Transactions waiting for transactions with lower nonces.
+	pub future_transactions: usize,
+	/// Transactions included in the block being sealed.
+	pub pending_block_transactions: usize,
+	/// Whether blocks are being prepared for sealing.
+	pub sealing: bool,
+	/// Approximate memory in bytes held by the snapshot of the pending block state.
+	pub pending_state_memory: usize,
+	/// Outcome of the last block authoring, if any.
+	pub last_authoring: Option<AuthoringStats>,
+}
+
+/// Why block authoring stopped including transactions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthoringStopReason {
+	/// Every ready transaction has been tried.
+	Exhausted,
+	/// Gas left in the block is too low for another transaction.
+	GasLimited,
+	/// Another transaction would exceed the maximum block size.
+	SizeLimited,
+}
+
+/// Outcome of preparing a block for sealing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuthoringStats {
+	/// Transactions included in the block.
+	pub transactions: usize,
+	/// Estimated RLP-encoded size of the sealed block in bytes, if the block size is limited.
+	pub size_bytes: Option<usize>,
+	/// Why no more transactions were included.
+	pub stop_reason: AuthoringStopReason,
+}
+
+/// Queue metrics. Sealing, pending state and authoring are left unset.
+impl From<MinerStatus> for MinerStatusReport {
+	fn from(status: MinerStatus) -> MinerStatusReport {
+		MinerStatusReport {
+			pending_transactions: status.transactions_in_pending_queue,
+			future_transactions: status.transactions_in_future_queue,
+			pending_block_transactions: status.transactions_in_pending_block,
+			sealing: false,
+			pending_state_memory: 0,
+			last_authoring: None,
+		}
+	}
+}
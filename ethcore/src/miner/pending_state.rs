@@ -0,0 +1,196 @@
+// Copyright 2015-2017 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Shared snapshot of the pending block state.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use ethereum_types::{H256, U256, Address};
+use bytes::Bytes;
+use factory::Factories;
+use hashdb::{HashDB, DBValue};
+use journaldb::JournalDB;
+use kvdb::{DBTransaction, KeyValueDB};
+use memorydb::MemoryDB;
+use state::{State, StateInfo};
+use state_db::StateDB;
+use trie;
+use util_error::UtilError;
+
+/// Handle to a snapshot of the pending block state. Clones are cheap and share the snapshot.
+/// Every caller works on a view of its own, which reads through to the snapshot and keeps
+/// changes to itself, so views are neither locked nor copies of the snapshot.
+#[derive(Clone)]
+pub struct PendingState {
+	snapshot: Arc<Snapshot>,
+}
+
+struct Snapshot {
+	db: StateDB,
+	root: H256,
+	account_start_nonce: U256,
+	factories: Factories,
+	mem_used: usize,
+}
+
+impl PendingState {
+	/// Take a snapshot of the state.
+	pub fn new(state: &State<StateDB>) -> Self {
+		let journal = state.db().journal_db().boxed_clone();
+		let mem_used = journal.transaction_mem_used();
+		PendingState {
+			snapshot: Arc::new(Snapshot {
+				db: state.db().boxed_clone_with(Box::new(ViewDB::new(Arc::from(journal)))),
+				root: *state.root(),
+				account_start_nonce: *state.account_start_nonce(),
+				factories: state.factories().clone(),
+				mem_used: mem_used,
+			}),
+		}
+	}
+
+	/// Approximate memory held by the snapshot in bytes, leaving out memory shared with the
+	/// state of the chain.
+	pub fn mem_used(&self) -> usize {
+		self.snapshot.mem_used
+	}
+
+	/// Check if both handles share the same snapshot.
+	pub fn is_same(&self, other: &PendingState) -> bool {
+		Arc::ptr_eq(&self.snapshot, &other.snapshot)
+	}
+
+	/// New view of the snapshot. Changes made to the view are not seen by other views.
+	pub fn view(&self) -> State<StateDB> {
+		let snapshot = &*self.snapshot;
+		State::from_existing(snapshot.db.boxed_clone(), snapshot.root, snapshot.account_start_nonce, snapshot.factories.clone())
+			.expect("the snapshot was taken from a state with this root; qed")
+	}
+}
+
+impl StateInfo for PendingState {
+	fn nonce(&self, a: &Address) -> trie::Result<U256> { self.view().nonce(a) }
+	fn balance(&self, a: &Address) -> trie::Result<U256> { self.view().balance(a) }
+	fn storage_at(&self, address: &Address, key: &H256) -> trie::Result<H256> { self.view().storage_at(address, key) }
+	fn code(&self, address: &Address) -> trie::Result<Option<Arc<Bytes>>> { self.view().code(address) }
+}
+
+/// View of the snapshot, for callers that modify the state.
+impl From<PendingState> for State<StateDB> {
+	fn from(pending: PendingState) -> State<StateDB> {
+		pending.view()
+	}
+}
+
+/// Journal database of a view. Reads fall through to the snapshot shared by all views,
+/// writes stay in the view. Views are never written to disk.
+struct ViewDB {
+	snapshot: Arc<JournalDB>,
+	changes: MemoryDB,
+}
+
+impl ViewDB {
+	fn new(snapshot: Arc<JournalDB>) -> Self {
+		ViewDB {
+			snapshot: snapshot,
+			changes: MemoryDB::new(),
+		}
+	}
+}
+
+impl HashDB for ViewDB {
+	fn keys(&self) -> HashMap<H256, i32> {
+		let mut keys = self.snapshot.keys();
+		keys.extend(self.changes.keys());
+		keys
+	}
+
+	fn get(&self, key: &H256) -> Option<DBValue> {
+		self.changes.get(key).or_else(|| self.snapshot.get(key))
+	}
+
+	fn contains(&self, key: &H256) -> bool {
+		self.get(key).is_some()
+	}
+
+	fn insert(&mut self, value: &[u8]) -> H256 {
+		self.changes.insert(value)
+	}
+
+	fn emplace(&mut self, key: H256, value: DBValue) {
+		self.changes.emplace(key, value)
+	}
+
+	fn remove(&mut self, key: &H256) {
+		// only remove from `changes`
+		if self.changes.contains(key) {
+			self.changes.remove(key)
+		}
+	}
+}
+
+impl JournalDB for ViewDB {
+	fn boxed_clone(&self) -> Box<JournalDB> {
+		Box::new(ViewDB {
+			snapshot: self.snapshot.clone(),
+			changes: self.changes.clone(),
+		})
+	}
+
+	fn mem_used(&self) -> usize {
+		self.changes.mem_used()
+	}
+
+	fn is_empty(&self) -> bool {
+		self.snapshot.is_empty()
+	}
+
+	fn earliest_era(&self) -> Option<u64> {
+		self.snapshot.earliest_era()
+	}
+
+	fn latest_era(&self) -> Option<u64> {
+		self.snapshot.latest_era()
+	}
+
+	fn journal_under(&mut self, _batch: &mut DBTransaction, _now: u64, _id: &H256) -> Result<u32, UtilError> {
+		Err("Views of the pending state are not journalled".into())
+	}
+
+	fn mark_canonical(&mut self, _batch: &mut DBTransaction, _era: u64, _id: &H256) -> Result<u32, UtilError> {
+		Err("Views of the pending state are not journalled".into())
+	}
+
+	fn inject(&mut self, _batch: &mut DBTransaction) -> Result<u32, UtilError> {
+		Err("Views of the pending state are not journalled".into())
+	}
+
+	fn state(&self, id: &H256) -> Option<Bytes> {
+		self.snapshot.state(id)
+	}
+
+	fn is_pruned(&self) -> bool {
+		self.snapshot.is_pruned()
+	}
+
+	fn backing(&self) -> &Arc<KeyValueDB> {
+		self.snapshot.backing()
+	}
+
+	fn consolidate(&mut self, overlay: MemoryDB) {
+		self.changes.consolidate(overlay)
+	}
+}
@@ -494,11 +494,26 @@ impl<B: Backend> State<B> {
 		(self.root, self.db)
 	}
 
+	/// Return reference to the database.
+	pub fn db(&self) -> &B {
+		&self.db
+	}
+
 	/// Return reference to root
 	pub fn root(&self) -> &H256 {
 		&self.root
 	}
 
+	/// Return the nonce of newly created accounts.
+	pub fn account_start_nonce(&self) -> &U256 {
+		&self.account_start_nonce
+	}
+
+	/// Return reference to the factories.
+	pub fn factories(&self) -> &Factories {
+		&self.factories
+	}
+
 	/// Create a new contract at address `contract`. If there is already an account at the address
 	/// it will have its code reset, ready for `init_code()`.
 	pub fn new_contract(&mut self, contract: &Address, balance: U256, nonce_offset: U256) {
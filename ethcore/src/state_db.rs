@@ -321,8 +321,13 @@ impl StateDB {
 
 	/// Clone the database.
 	pub fn boxed_clone(&self) -> StateDB {
+		self.boxed_clone_with(self.db.boxed_clone())
+	}
+
+	/// Clone the database caches on top of the given journal database.
+	pub fn boxed_clone_with(&self, db: Box<JournalDB>) -> StateDB {
 		StateDB {
-			db: self.db.boxed_clone(),
+			db: db,
 			account_cache: self.account_cache.clone(),
 			code_cache: self.code_cache.clone(),
 			local_cache: Vec::new(),
@@ -60,6 +60,13 @@ pub enum Error {
 		/// Declared transaction gas
 		got: U256,
 	},
+	/// Transaction's data is larger than the limit of the queue.
+	TooBig {
+		/// Maximal data size
+		limit: usize,
+		/// Transaction data size
+		got: usize,
+	},
 	/// Transaction's gas limit (aka gas) is invalid.
 	InvalidGasLimit(OutOfBounds<U256>),
 	/// Transaction sender is banned.
@@ -99,6 +106,8 @@ impl fmt::Display for Error {
 					balance, cost),
 			GasLimitExceeded { limit, got } =>
 				format!("Gas limit exceeded. Limit={}, Given={}", limit, got),
+			TooBig { limit, got } =>
+				format!("Transaction data too big. Limit={}, Given={}", limit, got),
 			InvalidGasLimit(ref err) => format!("Invalid gas limit. {}", err),
 			SenderBanned => "Sender is temporarily banned.".into(),
 			RecipientBanned => "Recipient is temporarily banned.".into(),
@@ -100,14 +100,17 @@
 //!     - It moves matching `future` transactions to `current`
 //! 4. `remove_old` is used as convenient method to update the state nonce for all senders in the queue.
 //!		- Invokes `cull` with latest state nonce for all senders.
+//! 5. `park_unaffordable` re-checks the balance of given senders (e.g. those touched by a new block).
+//!		- Moves `current` transactions the sender can no longer pay for to `future`
+//!		- Moves them back once the sender can pay for them again
 
 use std::cmp::Ordering;
 use std::cmp;
 use std::collections::{HashSet, HashMap, BTreeSet, BTreeMap};
 use std::ops::Deref;
+use std::mem;
 
 use ethereum_types::{H256, U256, Address};
-use heapsize::HeapSizeOf;
 use linked_hash_map::LinkedHashMap;
 use local_transactions::{LocalTransactionsList, Status as LocalTransactionStatus};
 use table::Table;
@@ -172,7 +175,7 @@ struct TransactionOrder {
 	/// Gas (limit) of the transaction. Usage depends on strategy.
 	/// Low gas limit = High priority (processed earlier)
 	gas: U256,
-	/// Heap usage of this transaction.
+	/// Memory usage of this transaction.
 	mem_usage: usize,
 	/// Transaction ordering strategy
 	strategy: PrioritizationStrategy,
@@ -196,7 +199,7 @@ impl TransactionOrder {
 			gas_price: tx.transaction.gas_price,
 			gas_factor: factor,
 			gas: tx.transaction.gas,
-			mem_usage: tx.transaction.heap_size_of_children(),
+			mem_usage: tx.mem_usage(),
 			strategy: strategy,
 			hash: tx.hash(),
 			insertion_id: tx.insertion_id,
@@ -306,6 +309,11 @@ impl VerifiedTransaction {
 		}
 	}
 
+	/// Memory taken by the transaction in the queue, data included.
+	fn mem_usage(&self) -> usize {
+		mem::size_of::<VerifiedTransaction>() + self.transaction.data.len()
+	}
+
 	fn hash(&self) -> H256 {
 		self.transaction.hash()
 	}
@@ -499,6 +507,43 @@ pub struct TransactionQueueStatus {
 	pub future: usize,
 }
 
+/// Size of a group of queued transactions, with a few of its transactions.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct QueueGroup {
+	/// Number of transactions.
+	pub count: usize,
+	/// Sum of the gas limits of the transactions.
+	pub gas: U256,
+	/// Sum of the fees the transactions pay at most (gas limit times gas price).
+	pub fees: U256,
+	/// Hashes of the first transactions of the group in priority order.
+	pub exemplars: Vec<H256>,
+}
+
+impl QueueGroup {
+	fn add(&mut self, tx: &SignedTransaction, max_exemplars: usize) {
+		self.count += 1;
+		self.gas = self.gas.saturating_add(tx.gas);
+		self.fees = self.fees.saturating_add(tx.gas.saturating_mul(tx.gas_price));
+		if self.exemplars.len() < max_exemplars {
+			self.exemplars.push(tx.hash());
+		}
+	}
+}
+
+/// Queued transactions grouped by readiness.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct QueueBreakdown {
+	/// Transactions that can go to the next block, as returned by `pending_transactions`.
+	pub ready: QueueGroup,
+	/// Transactions with the expected nonces that are held back by their condition, or by the
+	/// condition of an earlier transaction of the same sender. With strict nonce continuity also
+	/// the transactions after a nonce gap or a transaction over the gas limits.
+	pub delayed: QueueGroup,
+	/// Transactions waiting for transactions with lower nonces.
+	pub future: QueueGroup,
+}
+
 /// Details of account
 pub struct AccountDetails {
 	/// Most recent account nonce
@@ -544,6 +589,18 @@ pub enum RemovalReason {
 	NotAllowed,
 }
 
+/// Reason a transaction with the expected nonce was moved back to `future`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParkReason {
+	/// The sender can't pay for the transaction together with its earlier transactions in the queue.
+	InsufficientBalance {
+		/// Balance of the sender.
+		balance: U256,
+		/// Cost of the transaction and of the earlier ones.
+		cost: U256,
+	},
+}
+
 /// Point in time when transaction was inserted.
 pub type QueuingInstant = BlockNumber;
 const DEFAULT_QUEUING_PERIOD: BlockNumber = 128;
@@ -568,6 +625,12 @@ pub struct TransactionQueue {
 	tx_gas_limit: U256,
 	/// Current gas limit (block gas limit). Transactions above the limit will not be accepted (default to !0)
 	block_gas_limit: U256,
+	/// The maximum data size of a non-local transaction. No limit if `None`.
+	max_tx_data_size: Option<usize>,
+	/// The maximum data size of a local transaction. No limit if `None`.
+	max_local_tx_data_size: Option<usize>,
+	/// Stop the ready run of a sender at its first nonce gap or transaction over the gas limits.
+	strict_nonce_continuity: bool,
 	/// Maximal time transaction may occupy the queue.
 	/// When we reach `max_time_in_queue / 2^3` we re-validate
 	/// account balance.
@@ -584,6 +647,9 @@ pub struct TransactionQueue {
 	local_transactions: LocalTransactionsList,
 	/// Next id that should be assigned to a transaction imported to the queue.
 	next_transaction_id: u64,
+	/// Transactions moved to `future` by `park_unaffordable` and why. Entries of transactions no longer
+	/// in the queue are cleared on the next call.
+	parked: HashMap<H256, ParkReason>,
 }
 
 impl Default for TransactionQueue {
@@ -629,6 +695,9 @@ impl TransactionQueue {
 			minimal_gas_price: U256::zero(),
 			block_gas_limit: !U256::zero(),
 			tx_gas_limit,
+			max_tx_data_size: None,
+			max_local_tx_data_size: None,
+			strict_nonce_continuity: false,
 			max_time_in_queue: DEFAULT_QUEUING_PERIOD,
 			current,
 			future,
@@ -636,6 +705,7 @@ impl TransactionQueue {
 			last_nonces: HashMap::new(),
 			local_transactions: LocalTransactionsList::default(),
 			next_transaction_id: 0,
+			parked: HashMap::new(),
 		}
 	}
 
@@ -689,6 +759,28 @@ impl TransactionQueue {
 		self.tx_gas_limit = limit;
 	}
 
+	/// Set the new limits for the data size of non-local and local transactions. `None` removes the limit.
+	/// Any transaction already imported to the queue is not affected.
+	pub fn set_max_tx_data_size(&mut self, limit: Option<usize>, local_limit: Option<usize>) {
+		self.max_tx_data_size = limit;
+		self.max_local_tx_data_size = local_limit;
+	}
+
+	/// Require the ready transactions of each sender to form a run of consecutive nonces. When set, the run
+	/// stops at the first missing nonce or transaction over the block or transaction gas limit, and the
+	/// following transactions of the sender are delayed instead of being left for the block to reject.
+	pub fn set_strict_nonce_continuity(&mut self, strict: bool) {
+		self.strict_nonce_continuity = strict;
+	}
+
+	/// Returns the memory taken by the transactions in the queue, as counted against the memory limit.
+	pub fn mem_usage(&self) -> usize {
+		self.current.by_priority.iter()
+			.chain(self.future.by_priority.iter())
+			.map(|order| order.mem_usage)
+			.sum()
+	}
+
 	/// Returns current status for this queue
 	pub fn status(&self) -> TransactionQueueStatus {
 		TransactionQueueStatus {
@@ -806,6 +898,27 @@ impl TransactionQueue {
 			});
 		}
 
+		let max_data_size = match origin {
+			TransactionOrigin::Local => self.max_local_tx_data_size,
+			TransactionOrigin::External => self.max_tx_data_size,
+			// Already made it into a block once.
+			TransactionOrigin::RetractedBlock => None,
+		};
+		if let Some(limit) = max_data_size {
+			if tx.data.len() > limit {
+				trace!(target: "txqueue",
+					"Dropping transaction above data size limit: {:?} ({} > {})",
+					tx.hash(),
+					tx.data.len(),
+					limit
+				);
+				return Err(transaction::Error::TooBig {
+					limit: limit,
+					got: tx.data.len(),
+				});
+			}
+		}
+
 		let gas_limit = cmp::min(self.tx_gas_limit, self.block_gas_limit);
 		if tx.gas > gas_limit {
 			trace!(target: "txqueue",
@@ -941,6 +1054,101 @@ impl TransactionQueue {
 		}
 	}
 
+	/// Re-checks the balance of given senders, e.g. the accounts touched by newly imported blocks.
+	/// Transactions in `current` a sender can't pay for together with its earlier transactions are moved
+	/// to `future` with all following ones. Parked transactions the sender can pay for again are moved back.
+	/// Balances of other senders are not fetched.
+	pub fn park_unaffordable<F>(&mut self, senders: &HashSet<Address>, fetch_account: &F) where
+		F: Fn(&Address) -> AccountDetails,
+	{
+		for sender in senders {
+			if self.current.by_address.row(sender).is_none() && self.future.by_address.row(sender).is_none() {
+				continue;
+			}
+			let details = fetch_account(sender);
+			// Heights are relative to the state nonce.
+			self.cull(*sender, details.nonce);
+			self.park_sender(*sender, &details);
+		}
+		let by_hash = &self.by_hash;
+		self.parked.retain(|hash, _| by_hash.contains_key(hash));
+	}
+
+	/// Why a transaction was moved back to `future` by `park_unaffordable`, if it is still there.
+	pub fn parked_reason(&self, hash: &H256) -> Option<ParkReason> {
+		self.parked.get(hash).cloned()
+	}
+
+	fn park_sender(&mut self, sender: Address, details: &AccountDetails) {
+		let mut nonces = match self.current.by_address.row(&sender) {
+			Some(row_map) => row_map.keys().cloned().collect::<Vec<U256>>(),
+			None => vec![],
+		};
+		nonces.sort();
+
+		let mut cost = U256::zero();
+		let mut parking = false;
+		for k in nonces {
+			let order = self.current.by_address.get(&sender, &k).cloned().expect("iterating over a collection that has been retrieved above; qed");
+			cost = cost.saturating_add(self.by_hash[&order.hash].cost());
+			if !parking && cost <= details.balance {
+				continue;
+			}
+			if !parking {
+				parking = true;
+				if k > details.nonce {
+					self.last_nonces.insert(sender, k - U256::one());
+				} else {
+					self.last_nonces.remove(&sender);
+				}
+			}
+			trace!(target: "txqueue", "Parking unaffordable transaction: {:?} (cost: {} > balance: {})", order.hash, cost, details.balance);
+			self.current.drop(&sender, &k).expect("transaction known to be in self.current; qed");
+			let order = order.update_height(k, details.nonce);
+			if order.origin.is_local() {
+				self.local_transactions.mark_future(order.hash);
+			}
+			self.parked.insert(order.hash, ParkReason::InsufficientBalance { balance: details.balance, cost: cost });
+			if let Some(old) = self.future.insert(sender, k, order.clone()) {
+				Self::replace_orders(sender, k, old, order, &mut self.future, &mut self.by_hash, &mut self.local_transactions);
+			}
+		}
+		if parking {
+			self.future.enforce_limit(&mut self.by_hash, &mut self.local_transactions);
+			return;
+		}
+
+		// Everything in `current` is affordable, bring back the parked transactions that are too.
+		let mut next_nonce = self.last_nonces.get(&sender).map_or(details.nonce, |n| *n + U256::one());
+		let mut unparked = false;
+		loop {
+			let hash = match self.future.by_address.get(&sender, &next_nonce) {
+				Some(order) => order.hash,
+				None => break,
+			};
+			cost = cost.saturating_add(self.by_hash[&hash].cost());
+			if cost > details.balance {
+				break;
+			}
+			let order = self.future.drop(&sender, &next_nonce).expect("transaction known to be in self.future; qed");
+			let order = order.update_height(next_nonce, details.nonce);
+			if order.origin.is_local() {
+				self.local_transactions.mark_pending(order.hash);
+			}
+			self.parked.remove(&order.hash);
+			if let Some(old) = self.current.insert(sender, next_nonce, order.clone()) {
+				Self::replace_orders(sender, next_nonce, old, order, &mut self.current, &mut self.by_hash, &mut self.local_transactions);
+			}
+			self.last_nonces.insert(sender, next_nonce);
+			next_nonce = next_nonce + U256::one();
+			unparked = true;
+		}
+		if unparked {
+			let removed = self.current.enforce_limit(&mut self.by_hash, &mut self.local_transactions);
+			self.update_last_nonces(&removed);
+		}
+	}
+
 	/// Penalize transactions from sender of transaction with given hash.
 	/// I.e. it should change the priority of the transaction in the queue.
 	///
@@ -1125,31 +1333,101 @@ impl TransactionQueue {
 	fn filter_pending_transaction<F>(&self, best_block: BlockNumber, best_timestamp: u64, nonce_cap: Option<U256>, mut f: F)
 		where F: FnMut(&VerifiedTransaction) {
 
+		self.visit_current(best_block, best_timestamp, nonce_cap, |tx, ready| if ready { f(tx) });
+	}
+
+	/// Visit transactions in `current` in priority order, telling if each one is ready. A transaction with
+	/// an unmet condition delays all following transactions of its sender, and so does a nonce gap or a
+	/// transaction over the gas limits with strict nonce continuity. Nonces over `nonce_cap` are skipped.
+	fn visit_current<F>(&self, best_block: BlockNumber, best_timestamp: u64, nonce_cap: Option<U256>, mut f: F)
+		where F: FnMut(&VerifiedTransaction, bool) {
+
+		let is_delayed = |tx: &VerifiedTransaction| match tx.condition {
+			Some(transaction::Condition::Number(n)) => n > best_block,
+			Some(transaction::Condition::Timestamp(t)) => t > best_timestamp,
+			None => false,
+		};
+
+		if self.strict_nonce_continuity {
+			let run_ends = self.current_runs(&is_delayed);
+			for t in self.current.by_priority.iter() {
+				let tx = self.by_hash.get(&t.hash).expect("All transactions in `current` and `future` are always included in `by_hash`");
+				if let Some(max_nonce) = nonce_cap {
+					if tx.nonce() >= max_nonce {
+						continue;
+					}
+				}
+				let end = run_ends.get(&tx.sender()).expect("Every sender in `current` has a run computed above; qed");
+				f(&tx, tx.nonce() < *end);
+			}
+			return;
+		}
+
 		let mut delayed = HashSet::new();
 		for t in self.current.by_priority.iter() {
 			let tx = self.by_hash.get(&t.hash).expect("All transactions in `current` and `future` are always included in `by_hash`");
-			let sender = tx.sender();
-			if delayed.contains(&sender) {
-				continue;
-			}
 			if let Some(max_nonce) = nonce_cap {
 				if tx.nonce() >= max_nonce {
 					continue;
 				}
 			}
-			let delay = match tx.condition {
-				Some(transaction::Condition::Number(n)) => n > best_block,
-				Some(transaction::Condition::Timestamp(t)) => t > best_timestamp,
-				None => false,
-			};
-			if delay {
+			let sender = tx.sender();
+			if delayed.contains(&sender) {
+				f(&tx, false);
+				continue;
+			}
+			if is_delayed(tx) {
 				delayed.insert(sender);
+				f(&tx, false);
 				continue;
 			}
-			f(&tx);
+			f(&tx, true);
 		}
 	}
 
+	/// Returns, for each sender in `current`, the first nonce past its contiguous run starting at the
+	/// state nonce. The run stops at a nonce gap, a transaction over the gas limits or a delayed one.
+	fn current_runs<F>(&self, is_delayed: &F) -> HashMap<Address, U256>
+		where F: Fn(&VerifiedTransaction) -> bool {
+
+		self.current.by_address.keys().map(|sender| {
+			let row = self.current.by_address.row(sender).expect("Iterating over the keys of `by_address`; qed");
+			let mut orders: Vec<_> = row.iter().collect();
+			orders.sort_by_key(|&(nonce, _)| *nonce);
+
+			let mut end = match orders.first() {
+				Some(&(nonce, order)) if order.nonce_height.is_zero() => *nonce,
+				_ => return (*sender, U256::zero()),
+			};
+			for (nonce, order) in orders {
+				let tx = self.by_hash.get(&order.hash).expect("All transactions in `current` and `future` are always included in `by_hash`");
+				if *nonce != end || tx.transaction.gas > self.block_gas_limit || tx.transaction.gas > self.tx_gas_limit || is_delayed(tx) {
+					break;
+				}
+				end = end + U256::one();
+			}
+			(*sender, end)
+		}).collect()
+	}
+
+	/// Count the transactions ready at the given block and time, those delayed by a condition and the
+	/// future ones, with their gas and fees and up to `exemplars` hashes of each group. Readiness is
+	/// decided as in `pending_transactions`.
+	pub fn breakdown(&self, best_block: BlockNumber, best_timestamp: u64, exemplars: usize) -> QueueBreakdown {
+		let mut breakdown = QueueBreakdown::default();
+		{
+			let (ready, delayed) = (&mut breakdown.ready, &mut breakdown.delayed);
+			self.visit_current(best_block, best_timestamp, None, |tx, is_ready| {
+				if is_ready { ready.add(&tx.transaction, exemplars) } else { delayed.add(&tx.transaction, exemplars) }
+			});
+		}
+		for t in self.future.by_priority.iter() {
+			let tx = self.by_hash.get(&t.hash).expect("All transactions in `current` and `future` are always included in `by_hash`");
+			breakdown.future.add(&tx.transaction, exemplars);
+		}
+		breakdown
+	}
+
 	/// Returns top transactions from the queue ordered by priority.
 	pub fn top_transactions_at(&self, best_block: BlockNumber, best_timestamp: u64, nonce_cap: Option<U256>) -> Vec<SignedTransaction> {
 		let mut r = Vec::new();
@@ -1202,6 +1480,7 @@ impl TransactionQueue {
 		self.future.clear();
 		self.by_hash.clear();
 		self.last_nonces.clear();
+		self.parked.clear();
 	}
 
 	/// Returns highest transaction nonce for given address.
@@ -1210,7 +1489,7 @@ impl TransactionQueue {
 	}
 
 	/// Checks if there are any transactions in `future` that should actually be promoted to `current`
-	/// (because nonce matches).
+	/// (because nonce matches). Parked transactions stay until `park_unaffordable` finds them affordable.
 	fn move_matching_future_to_current(&mut self, address: Address, mut current_nonce: U256, first_nonce: U256) {
 		let mut update_last_nonce_to = None;
 		{
@@ -1219,7 +1498,9 @@ impl TransactionQueue {
 				return;
 			}
 			let by_nonce = by_nonce.expect("None is tested in early-exit condition above; qed");
-			while let Some(order) = by_nonce.remove(&current_nonce) {
+			let parked = &self.parked;
+			while by_nonce.get(&current_nonce).map_or(false, |order| !parked.contains_key(&order.hash)) {
+				let order = by_nonce.remove(&current_nonce).expect("checked in the loop condition; qed");
 				// remove also from priority and gas_price
 				self.future.by_priority.remove(&order);
 				self.future.by_gas_price.remove(&order.gas_price, &order.hash);
@@ -1265,6 +1546,8 @@ impl TransactionQueue {
 		let address = tx.sender();
 		let nonce = tx.nonce();
 		let hash = tx.hash();
+		// A transaction parked before it left the queue is checked afresh.
+		self.parked.remove(&hash);
 
 		// The transaction might be old, let's check that.
 		// This has to be the first test, otherwise calculating
@@ -2420,6 +2703,30 @@ pub mod test {
 		assert_eq!(txq.status().future, 1);
 	}
 
+	#[test]
+	fn should_limit_transaction_data_size() {
+		// given
+		let mut txq = TransactionQueue::default();
+		txq.set_max_tx_data_size(Some(100), Some(1000));
+		let tx_with_data = |size: usize| {
+			let mut tx = new_unsigned_tx(default_nonce(), default_gas_val(), default_gas_price());
+			tx.data = vec![0; size];
+			tx.sign(Random.generate().unwrap().secret(), None)
+		};
+
+		// when
+		let over = txq.add(tx_with_data(101), TransactionOrigin::External, 0, None, &default_tx_provider());
+		let local_over = txq.add(tx_with_data(1001), TransactionOrigin::Local, 0, None, &default_tx_provider());
+		txq.add(tx_with_data(100), TransactionOrigin::External, 0, None, &default_tx_provider()).unwrap();
+		txq.add(tx_with_data(101), TransactionOrigin::Local, 0, None, &default_tx_provider()).unwrap();
+
+		// then
+		assert_eq!(unwrap_tx_err(over), transaction::Error::TooBig { limit: 100, got: 101 });
+		assert_eq!(unwrap_tx_err(local_over), transaction::Error::TooBig { limit: 1000, got: 1001 });
+		assert_eq!(txq.status().pending, 2);
+		assert_eq!(txq.mem_usage(), 2 * mem::size_of::<VerifiedTransaction>() + 100 + 101);
+	}
+
 	#[test]
 	fn should_limit_by_gas() {
 		let mut txq = TransactionQueue::with_limits(
@@ -2941,4 +3248,187 @@ pub mod test {
 		// then
 		assert_eq!(txq.top_transactions_at(BlockNumber::max_value(), u64::max_value(), Some(127.into())).len(), 4);
 	}
+
+	#[test]
+	fn should_break_down_queue_by_readiness() {
+		// given
+		let mut txq = TransactionQueue::default();
+		let (delayed1, delayed2) = new_tx_pair_default(1.into(), 0.into());
+		let ready = new_tx(default_nonce(), 2.into());
+		let future = new_tx(default_nonce() + U256::from(2), 3.into());
+		txq.add(delayed1.clone(), TransactionOrigin::External, 0, Some(transaction::Condition::Number(5)), &default_tx_provider()).unwrap();
+		txq.add(delayed2.clone(), TransactionOrigin::External, 0, None, &default_tx_provider()).unwrap();
+		txq.add(ready.clone(), TransactionOrigin::External, 0, None, &default_tx_provider()).unwrap();
+		let res = txq.add(future.clone(), TransactionOrigin::External, 0, None, &default_tx_provider()).unwrap();
+		assert_eq!(res, transaction::ImportResult::Future);
+
+		// when
+		let breakdown = txq.breakdown(4, 0, 1);
+
+		// then
+		assert_eq!(breakdown.ready, QueueGroup {
+			count: 1,
+			gas: default_gas_val(),
+			fees: default_gas_val() * U256::from(2),
+			exemplars: vec![ready.hash()],
+		});
+		assert_eq!(breakdown.delayed.count, 2);
+		assert_eq!(breakdown.delayed.gas, default_gas_val() * U256::from(2));
+		assert_eq!(breakdown.delayed.exemplars, vec![delayed1.hash()]);
+		assert_eq!(breakdown.future.count, 1);
+		assert_eq!(breakdown.future.exemplars, vec![future.hash()]);
+		let pending: Vec<H256> = txq.pending_transactions(4, 0).iter().map(|t| t.transaction.hash()).collect();
+		assert_eq!(pending, breakdown.ready.exemplars);
+
+		// Once the condition is met the sender's transactions are ready.
+		let breakdown = txq.breakdown(5, 0, 10);
+		assert_eq!(breakdown.ready.count, 3);
+		assert_eq!(breakdown.delayed, QueueGroup::default());
+		for tx in &[&ready, &delayed1, &delayed2] {
+			assert!(breakdown.ready.exemplars.contains(&tx.hash()));
+		}
+		assert_eq!(breakdown.future.count, 1);
+	}
+
+	#[test]
+	fn should_park_transactions_the_sender_cannot_pay_for() {
+		// given
+		let mut txq = TransactionQueue::default();
+		let (tx1, tx2) = new_tx_pair_default(1.into(), 0.into());
+		txq.add(tx1.clone(), TransactionOrigin::External, 0, None, &default_tx_provider()).unwrap();
+		txq.add(tx2.clone(), TransactionOrigin::External, 0, None, &default_tx_provider()).unwrap();
+		assert_eq!(txq.status().pending, 2);
+		let senders = vec![tx1.sender()].into_iter().collect::<HashSet<_>>();
+		let with_balance = |balance: u64| move |_: &Address| AccountDetails {
+			nonce: default_nonce(),
+			balance: balance.into(),
+		};
+
+		// when
+		txq.park_unaffordable(&HashSet::new(), &with_balance(0));
+		assert_eq!(txq.status().pending, 2);
+		txq.park_unaffordable(&senders, &with_balance(150_000));
+
+		// then
+		assert_eq!(txq.status().pending, 1);
+		assert_eq!(txq.status().future, 1);
+		assert_eq!(txq.top_transactions(), vec![tx1.clone()]);
+		assert_eq!(txq.parked_reason(&tx2.hash()), Some(ParkReason::InsufficientBalance {
+			balance: 150_000.into(),
+			cost: 200_200.into(),
+		}));
+
+		// Nothing is affordable.
+		txq.park_unaffordable(&senders, &with_balance(0));
+		assert_eq!(txq.status().pending, 0);
+		assert_eq!(txq.status().future, 2);
+		assert_eq!(txq.parked_reason(&tx1.hash()), Some(ParkReason::InsufficientBalance {
+			balance: 0.into(),
+			cost: 100_100.into(),
+		}));
+
+		// Both are moved back once the sender can pay for them.
+		txq.park_unaffordable(&senders, &with_balance(200_200));
+		assert_eq!(txq.top_transactions(), vec![tx1.clone(), tx2.clone()]);
+		assert_eq!(txq.status().future, 0);
+		assert_eq!(txq.parked_reason(&tx1.hash()), None);
+		assert_eq!(txq.parked_reason(&tx2.hash()), None);
+		assert_eq!(txq.last_nonce(&tx1.sender()), Some(tx2.nonce));
+	}
+
+	#[test]
+	fn should_keep_parked_transactions_in_future_until_affordable() {
+		// given
+		let mut txq = TransactionQueue::default();
+		let (tx1, tx2) = new_tx_pair_default(1.into(), 0.into());
+		txq.add(tx1.clone(), TransactionOrigin::External, 0, None, &default_tx_provider()).unwrap();
+		txq.add(tx2.clone(), TransactionOrigin::External, 0, None, &default_tx_provider()).unwrap();
+		let senders = vec![tx1.sender()].into_iter().collect::<HashSet<_>>();
+		let with_balance = |balance: u64| move |_: &Address| AccountDetails {
+			nonce: default_nonce(),
+			balance: balance.into(),
+		};
+		txq.park_unaffordable(&senders, &with_balance(0));
+		assert_eq!(txq.status().future, 2);
+
+		// when
+		txq.remove_old(&with_balance(0), 0);
+		txq.cull(tx1.sender(), default_nonce());
+
+		// then
+		assert_eq!(txq.status().pending, 0);
+		assert_eq!(txq.status().future, 2);
+		txq.park_unaffordable(&senders, &with_balance(200_200));
+		assert_eq!(txq.status().pending, 2);
+	}
+
+	#[test]
+	fn should_only_expose_consecutive_nonces_with_strict_continuity() {
+		fn queue(strict: bool) -> (TransactionQueue, Vec<SignedTransaction>) {
+			let mut txq = TransactionQueue::default();
+			txq.set_strict_nonce_continuity(strict);
+			let keypair = Random.generate().unwrap();
+			let txs: Vec<SignedTransaction> = [(0u64, 100_000u64), (1, 200_000), (2, 100_000), (3, 100_000)].iter()
+				.map(|&(nonce, gas)| new_unsigned_tx(default_nonce() + nonce.into(), gas.into(), default_gas_price()).sign(keypair.secret(), None))
+				.collect();
+			// The local nonce 1 has priority over the external nonce 0; nonce 3 waits in `future`.
+			txq.add(txs[0].clone(), TransactionOrigin::External, 0, None, &default_tx_provider()).unwrap();
+			txq.add(txs[1].clone(), TransactionOrigin::Local, 0, None, &default_tx_provider()).unwrap();
+			txq.add(txs[3].clone(), TransactionOrigin::External, 0, None, &default_tx_provider()).unwrap();
+			(txq, txs)
+		}
+		fn ready(txq: &TransactionQueue) -> Vec<H256> {
+			txq.pending_transactions(BlockNumber::max_value(), u64::max_value()).into_iter().map(|tx| tx.hash()).collect()
+		}
+
+		// given
+		let (mut strict, txs) = queue(true);
+		let (mut default, default_txs) = queue(false);
+
+		// then
+		assert_eq!(ready(&strict), vec![txs[1].hash(), txs[0].hash()]);
+		assert_eq!(ready(&default), vec![default_txs[1].hash(), default_txs[0].hash()]);
+
+		// when
+		// Filling the gap promotes nonce 3, the second transaction is then over the lowered gas limit.
+		strict.add(txs[2].clone(), TransactionOrigin::External, 0, None, &default_tx_provider()).unwrap();
+		default.add(default_txs[2].clone(), TransactionOrigin::External, 0, None, &default_tx_provider()).unwrap();
+		strict.set_gas_limit(150_000.into());
+		default.set_gas_limit(150_000.into());
+
+		// then
+		assert_eq!(ready(&strict), vec![txs[0].hash()]);
+		assert_eq!(ready(&default), [1, 0, 2, 3].iter().map(|&i| default_txs[i].hash()).collect::<Vec<_>>());
+	}
+
+	#[test]
+	fn should_not_expose_higher_priority_transaction_after_nonce_gap_with_strict_continuity() {
+		fn queue(strict: bool) -> (TransactionQueue, Vec<SignedTransaction>) {
+			let mut txq = TransactionQueue::default();
+			txq.set_strict_nonce_continuity(strict);
+			let keypair = Random.generate().unwrap();
+			let txs: Vec<SignedTransaction> = (0..3u64)
+				.map(|nonce| new_unsigned_tx(default_nonce() + nonce.into(), default_gas_val(), default_gas_price()).sign(keypair.secret(), None))
+				.collect();
+			txq.add(txs[0].clone(), TransactionOrigin::External, 0, None, &default_tx_provider()).unwrap();
+			txq.add(txs[1].clone(), TransactionOrigin::External, 0, None, &default_tx_provider()).unwrap();
+			txq.add(txs[2].clone(), TransactionOrigin::RetractedBlock, 0, None, &default_tx_provider()).unwrap();
+			// The retracted nonce 2 has priority, so the lowered limit drops the external nonce 1 and leaves a gap in `current`.
+			txq.set_limit(2);
+			(txq, txs)
+		}
+		fn ready(txq: &TransactionQueue) -> Vec<H256> {
+			txq.pending_transactions(BlockNumber::max_value(), u64::max_value()).into_iter().map(|tx| tx.hash()).collect()
+		}
+
+		// given
+		let (strict, txs) = queue(true);
+		let (default, default_txs) = queue(false);
+		assert_eq!(strict.status().pending, 2);
+		assert_eq!(default.status().pending, 2);
+
+		// then
+		assert_eq!(ready(&strict), vec![txs[0].hash()]);
+		assert_eq!(ready(&default), vec![default_txs[2].hash(), default_txs[0].hash()]);
+	}
 }
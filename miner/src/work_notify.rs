@@ -35,6 +35,9 @@ use parking_lot::Mutex;
 pub trait NotifyWork : Send + Sync {
 	/// Fired when new mining job available
 	fn notify(&self, pow_hash: H256, difficulty: U256, number: u64);
+	/// Fired before a new job on a different parent block, when the job of `pow_hash` goes stale.
+	/// Solutions for it may still be accepted while it is kept.
+	fn notify_stale(&self, _pow_hash: H256) {}
 }
 
 /// POSTs info about new work to given urls.
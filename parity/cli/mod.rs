@@ -603,6 +603,10 @@ usage! {
 			"--infinite-pending-block",
 			"Pending block will be created with maximal possible gas limit and will execute all transactions in the queue. Note that such block is invalid and should never be attempted to be mined.",
 
+			FLAG flag_tx_queue_strict_nonces: (bool) = false, or |c: &Config| c.mining.as_ref()?.tx_queue_strict_nonces.clone(),
+			"--tx-queue-strict-nonces",
+			"Only include a run of consecutive nonces of each sender in authored blocks, stopping at the first missing nonce or transaction that can't be included.",
+
 			FLAG flag_no_persistent_txqueue: (bool) = false, or |c: &Config| c.parity.as_ref()?.no_persistent_txqueue,
 			"--no-persistent-txqueue",
 			"Don't save pending local transactions to disk to be restored whenever the node restarts.",
@@ -703,6 +707,18 @@ usage! {
 			"--tx-gas-limit=[GAS]",
 			"Apply a limit of GAS as the maximum amount of gas a single transaction may have for it to be mined.",
 
+			ARG arg_tx_data_limit: (Option<usize>) = None, or |c: &Config| c.mining.as_ref()?.tx_data_limit.clone(),
+			"--tx-data-limit=[BYTES]",
+			"Maximum size of the data of a transaction received from the network for it to be accepted to the transaction queue.",
+
+			ARG arg_local_tx_data_limit: (Option<usize>) = None, or |c: &Config| c.mining.as_ref()?.local_tx_data_limit.clone(),
+			"--local-tx-data-limit=[BYTES]",
+			"Maximum size of the data of a local transaction for it to be accepted to the transaction queue.",
+
+			ARG arg_max_block_size: (Option<usize>) = None, or |c: &Config| c.mining.as_ref()?.max_block_size.clone(),
+			"--max-block-size=[BYTES]",
+			"Stop including transactions in authored blocks once their RLP-encoded size would exceed BYTES.",
+
 			ARG arg_tx_time_limit: (Option<u64>) = None, or |c: &Config| c.mining.as_ref()?.tx_time_limit.clone(),
 			"--tx-time-limit=[MS]",
 			"Maximal time for processing single transaction. If enabled senders/recipients/code of transactions offending the limit will be banned from being included in transaction queue for 180 seconds.",
@@ -1135,6 +1151,9 @@ struct Mining {
 	reseal_max_period: Option<u64>,
 	work_queue_size: Option<usize>,
 	tx_gas_limit: Option<String>,
+	tx_data_limit: Option<usize>,
+	local_tx_data_limit: Option<usize>,
+	max_block_size: Option<usize>,
 	tx_time_limit: Option<u64>,
 	relay_set: Option<String>,
 	min_gas_price: Option<u64>,
@@ -1155,6 +1174,7 @@ struct Mining {
 	notify_work: Option<Vec<String>>,
 	refuse_service_transactions: Option<bool>,
 	infinite_pending_block: Option<bool>,
+	tx_queue_strict_nonces: Option<bool>,
 }
 
 #[derive(Default, Debug, PartialEq, Deserialize)]
@@ -1545,6 +1565,9 @@ mod tests {
 			flag_reseal_on_uncle: false,
 			arg_work_queue_size: 20usize,
 			arg_tx_gas_limit: Some("6283184".into()),
+			arg_tx_data_limit: None,
+			arg_local_tx_data_limit: None,
+			arg_max_block_size: None,
 			arg_tx_time_limit: Some(100u64),
 			arg_relay_set: "cheap".into(),
 			arg_min_gas_price: Some(0u64),
@@ -1565,6 +1588,7 @@ mod tests {
 			arg_notify_work: Some("http://localhost:3001".into()),
 			flag_refuse_service_transactions: false,
 			flag_infinite_pending_block: false,
+			flag_tx_queue_strict_nonces: false,
 
 			flag_stratum: false,
 			arg_stratum_interface: "local".to_owned(),
@@ -1811,12 +1835,16 @@ mod tests {
 				tx_queue_ban_count: None,
 				tx_queue_ban_time: None,
 				tx_gas_limit: None,
+				tx_data_limit: None,
+				local_tx_data_limit: None,
+				max_block_size: None,
 				tx_time_limit: None,
 				extra_data: None,
 				remove_solved: None,
 				notify_work: None,
 				refuse_service_transactions: None,
 				infinite_pending_block: None,
+				tx_queue_strict_nonces: None,
 			}),
 			footprint: Some(Footprint {
 				tracing: Some("on".into()),
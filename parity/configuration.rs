@@ -529,6 +529,9 @@ impl Configuration {
 			tx_queue_memory_limit: if self.args.arg_tx_queue_mem_limit > 0 {
 				Some(self.args.arg_tx_queue_mem_limit as usize * 1024 * 1024)
 			} else { None },
+			tx_data_size_limit: self.args.arg_tx_data_limit,
+			local_tx_data_size_limit: self.args.arg_local_tx_data_limit,
+			max_block_size_bytes: self.args.arg_max_block_size,
 			tx_queue_gas_limit: to_gas_limit(&self.args.arg_tx_queue_gas)?,
 			tx_queue_strategy: to_queue_strategy(&self.args.arg_tx_queue_strategy)?,
 			pending_set: to_pending_set(&self.args.arg_relay_set)?,
@@ -546,6 +549,7 @@ impl Configuration {
 			},
 			refuse_service_transactions: self.args.flag_refuse_service_transactions,
 			infinite_pending_block: self.args.flag_infinite_pending_block,
+			strict_nonce_continuity: self.args.flag_tx_queue_strict_nonces,
 		};
 
 		Ok(options)
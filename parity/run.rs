@@ -679,6 +679,11 @@ pub fn execute_impl(cmd: RunCmd, can_restart: bool, logger: Arc<RotatingLogger>)
 	).map_err(|e| format!("Sync error: {}", e))?;
 
 	service.add_notify(chain_notify.clone());
+	// propagate local transactions right after import
+	let weak_notify = Arc::downgrade(&chain_notify);
+	miner.set_propagation_hook(Box::new(move |hashes| if let Some(notify) = weak_notify.upgrade() {
+		notify.transactions_imported(hashes);
+	}), false);
 	if let Some(filter) = connection_filter {
 		service.add_notify(filter);
 	}
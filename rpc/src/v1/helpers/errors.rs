@@ -312,6 +312,9 @@ pub fn transaction_message(error: TransactionError) -> String {
 		GasLimitExceeded { limit, got } => {
 			format!("Transaction cost exceeds current gas limit. Limit: {}, got: {}. Try decreasing supplied gas.", limit, got)
 		},
+		TooBig { limit, got } => {
+			format!("Transaction data is too big. Limit: {} bytes, got: {}. Try sending less data.", limit, got)
+		},
 		InvalidSignature(sig) => format!("Invalid signature: {}", sig),
 		InvalidChainId => "Invalid chain id.".into(),
 		InvalidGasLimit(_) => "Supplied gas is beyond limit.".into(),
@@ -146,7 +146,8 @@ impl<C, SN: ?Sized, S: ?Sized, M, EM, T: StateInfo + 'static> EthClient<C, SN, S
 	C: MiningBlockChainClient + StateClient<State=T> + Call<State=T> + EngineInfo,
 	SN: SnapshotService,
 	S: SyncProvider,
-	M: MinerService<State=T>,
+	M: MinerService,
+	M::State: Into<T>,
 	EM: ExternalMinerService {
 
 	/// Creates new EthClient.
@@ -453,7 +454,8 @@ impl<C, SN: ?Sized, S: ?Sized, M, EM, T: StateInfo + 'static> Eth for EthClient<
 	C: MiningBlockChainClient + StateClient<State=T> + Call<State=T> + EngineInfo + 'static,
 	SN: SnapshotService + 'static,
 	S: SyncProvider + 'static,
-	M: MinerService<State=T> + 'static,
+	M: MinerService + 'static,
+	M::State: Into<T>,
 	EM: ExternalMinerService + 'static,
 {
 	type Metadata = Metadata;
@@ -836,7 +838,7 @@ impl<C, SN: ?Sized, S: ?Sized, M, EM, T: StateInfo + 'static> Eth for EthClient<
 
 		let (mut state, header) = if num == BlockNumber::Pending {
 			let info = self.client.chain_info();
-			let state = try_bf!(self.miner.pending_state(info.best_block_number).ok_or(errors::state_pruned()));
+			let state: T = try_bf!(self.miner.pending_state(info.best_block_number).ok_or(errors::state_pruned())).into();
 			let header = try_bf!(self.miner.pending_block_header(info.best_block_number).ok_or(errors::state_pruned()));
 
 			(state, header)
@@ -869,7 +871,7 @@ impl<C, SN: ?Sized, S: ?Sized, M, EM, T: StateInfo + 'static> Eth for EthClient<
 
 		let (state, header) = if num == BlockNumber::Pending {
 			let info = self.client.chain_info();
-			let state = try_bf!(self.miner.pending_state(info.best_block_number).ok_or(errors::state_pruned()));
+			let state: T = try_bf!(self.miner.pending_state(info.best_block_number).ok_or(errors::state_pruned())).into();
 			let header = try_bf!(self.miner.pending_block_header(info.best_block_number).ok_or(errors::state_pruned()));
 
 			(state, header)
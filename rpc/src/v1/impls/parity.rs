@@ -117,7 +117,8 @@ impl<C, M, U> ParityClient<C, M, U> where
 impl<C, M, U, S> Parity for ParityClient<C, M, U> where
 	S: StateInfo + 'static,
 	C: MiningBlockChainClient + StateClient<State=S> + Call<State=S> + 'static,
-	M: MinerService<State=S> + 'static,
+	M: MinerService + 'static,
+	M::State: Into<S>,
 	U: UpdateService + 'static,
 {
 	type Metadata = Metadata;
@@ -462,7 +463,7 @@ impl<C, M, U, S> Parity for ParityClient<C, M, U> where
 
 		let (mut state, header) = if num == BlockNumber::Pending {
 			let info = self.client.chain_info();
-			let state = self.miner.pending_state(info.best_block_number).ok_or(errors::state_pruned())?;
+			let state: S = self.miner.pending_state(info.best_block_number).ok_or(errors::state_pruned())?.into();
 			let header = self.miner.pending_block_header(info.best_block_number).ok_or(errors::state_pruned())?;
 
 			(state, header)
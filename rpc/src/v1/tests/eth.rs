@@ -71,6 +71,9 @@ fn miner_service(spec: &Spec, accounts: Arc<AccountProvider>) -> Arc<Miner> {
 			tx_queue_gas_limit: GasLimit::None,
 			tx_queue_banning: Banning::Disabled,
 			tx_queue_memory_limit: None,
+			tx_data_size_limit: None,
+			local_tx_data_size_limit: None,
+			max_block_size_bytes: None,
 			pending_set: PendingSet::SealingOrElseQueue,
 			reseal_min_period: Duration::from_secs(0),
 			reseal_max_period: Duration::from_secs(120),
@@ -78,6 +81,7 @@ fn miner_service(spec: &Spec, accounts: Arc<AccountProvider>) -> Arc<Miner> {
 			enable_resubmission: true,
 			refuse_service_transactions: false,
 			infinite_pending_block: false,
+			strict_nonce_continuity: false,
 		},
 		GasPricer::new_fixed(20_000_000_000u64.into()),
 		&spec,
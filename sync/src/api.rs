@@ -295,7 +295,7 @@ impl SyncProvider for EthSync {
 
 				Some(PeerInfo {
 					id: session_info.id.map(|id| format!("{:x}", id)),
-					client_version: session_info.client_version,
+					client_version: session_info.client_version.to_string(),
 					capabilities: session_info.peer_capabilities.into_iter().map(|c| c.to_string()).collect(),
 					remote_address: session_info.remote_address,
 					local_address: session_info.local_address,
@@ -445,6 +445,13 @@ impl ChainNotify for EthSync {
 		let mut sync = self.eth_handler.sync.write();
 		sync.transactions_received(hashes, peer_id);
 	}
+
+	fn transactions_imported(&self, hashes: Vec<H256>) {
+		self.network.with_context(self.subprotocol_name, |context| {
+			let mut sync_io = NetSyncIo::new(context, &*self.eth_handler.chain, &*self.eth_handler.snapshot_service, &self.eth_handler.overlay);
+			self.eth_handler.sync.write().propagate_imported_transactions(&mut sync_io, &hashes);
+		});
+	}
 }
 
 /// PIP event handler.
@@ -592,6 +599,7 @@ impl NetworkConfiguration {
 			ip_filter: self.ip_filter,
 			non_reserved_mode: if self.allow_non_reserved { NonReservedPeerMode::Accept } else { NonReservedPeerMode::Deny },
 			client_version: self.client_version,
+			..BasicNetworkConfiguration::new()
 		})
 	}
 }
@@ -823,7 +831,7 @@ impl LightSyncProvider for LightSync {
 
 				Some(PeerInfo {
 					id: session_info.id.map(|id| format!("{:x}", id)),
-					client_version: session_info.client_version,
+					client_version: session_info.client_version.to_string(),
 					capabilities: session_info.peer_capabilities.into_iter().map(|c| c.to_string()).collect(),
 					remote_address: session_info.remote_address,
 					local_address: session_info.local_address,
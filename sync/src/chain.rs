@@ -1453,9 +1453,14 @@ impl ChainSync {
 
 	/// Generic packet sender
 	fn send_packet(&mut self, sync: &mut SyncIo, peer_id: PeerId, packet_id: PacketId, packet: Bytes) {
-		if let Err(e) = sync.send(peer_id, packet_id, packet) {
-			debug!(target:"sync", "Error sending packet: {:?}", e);
-			sync.disconnect_peer(peer_id);
+		match sync.send(peer_id, packet_id, packet) {
+			Ok(()) => {},
+			// the session is closed already, the peer is cleaned up on the disconnect notification.
+			Err(network::Error(network::ErrorKind::PeerGone, _)) => trace!(target:"sync", "Peer {} is gone", peer_id),
+			Err(e) => {
+				debug!(target:"sync", "Error sending packet: {:?}", e);
+				sync.disconnect_peer(peer_id);
+			},
 		}
 	}
 
@@ -1975,6 +1980,25 @@ impl ChainSync {
 		}
 
 		let transactions = io.chain().ready_transactions();
+		self.propagate_pending_transactions(io, transactions, true)
+	}
+
+	/// propagates given newly imported transactions to all peers
+	pub fn propagate_imported_transactions(&mut self, io: &mut SyncIo, hashes: &[H256]) -> usize {
+		// Early out if nobody to send to.
+		if self.peers.is_empty() {
+			return 0;
+		}
+
+		let transactions = hashes.iter()
+			.filter_map(|hash| io.chain().pending_transaction(hash))
+			.collect();
+		self.propagate_pending_transactions(io, transactions, false)
+	}
+
+	/// `complete` is true when `transactions` is the whole pending set, so that transactions
+	/// no longer pending are forgotten.
+	fn propagate_pending_transactions(&mut self, io: &mut SyncIo, transactions: Vec<PendingTransaction>, complete: bool) -> usize {
 		if transactions.is_empty() {
 			return 0;
 		}
@@ -1986,14 +2010,14 @@ impl ChainSync {
 		let mut affected_peers = HashSet::new();
 		if !transactions.is_empty() {
 			let peers = self.select_peers_for_transactions(|_| true);
-			affected_peers = self.propagate_transactions_to_peers(io, peers, transactions);
+			affected_peers = self.propagate_transactions_to_peers(io, peers, transactions, complete);
 		}
 
 		// most of times service_transactions will be empty
 		// => there's no need to merge packets
 		if !service_transactions.is_empty() {
 			let service_transactions_peers = self.select_peers_for_transactions(|peer_id| accepts_service_transaction(&io.peer_info(*peer_id)));
-			let service_transactions_affected_peers = self.propagate_transactions_to_peers(io, service_transactions_peers, service_transactions);
+			let service_transactions_affected_peers = self.propagate_transactions_to_peers(io, service_transactions_peers, service_transactions, complete);
 			affected_peers.extend(&service_transactions_affected_peers);
 		}
 
@@ -2015,7 +2039,7 @@ impl ChainSync {
 			.collect()
 	}
 
-	fn propagate_transactions_to_peers(&mut self, io: &mut SyncIo, peers: Vec<PeerId>, transactions: Vec<PendingTransaction>) -> HashSet<PeerId> {
+	fn propagate_transactions_to_peers(&mut self, io: &mut SyncIo, peers: Vec<PeerId>, transactions: Vec<PendingTransaction>, complete: bool) -> HashSet<PeerId> {
 		let all_transactions_hashes = transactions.iter()
 			.map(|tx| tx.transaction.hash())
 			.collect::<HashSet<H256>>();
@@ -2026,7 +2050,9 @@ impl ChainSync {
 		};
 
 		// Clear old transactions from stats
-		self.transactions_stats.retain(&all_transactions_hashes);
+		if complete {
+			self.transactions_stats.retain(&all_transactions_hashes);
+		}
 
 		// sqrt(x)/x scaled to max u32
 		let block_number = io.chain().chain_info().best_block_number;
@@ -2090,12 +2116,17 @@ impl ChainSync {
 						stats.propagated(hash, id, block_number);
 					}
 
-					peer_info.last_sent_transactions = all_transactions_hashes
-						.intersection(&peer_info.last_sent_transactions)
-						.chain(&to_send)
-						.cloned()
-						.collect();
-					Some((peer_id, to_send.len(), packet.out()))
+					let sent = to_send.len();
+					if complete {
+						peer_info.last_sent_transactions = all_transactions_hashes
+							.intersection(&peer_info.last_sent_transactions)
+							.chain(&to_send)
+							.cloned()
+							.collect();
+					} else {
+						peer_info.last_sent_transactions.extend(to_send);
+					}
+					Some((peer_id, sent, packet.out()))
 				})
 				.collect::<Vec<_>>()
 		};
@@ -2720,6 +2751,33 @@ mod tests {
 		assert_eq!(0x02, queue.read()[1].packet_id);
 	}
 
+	#[test]
+	fn propagates_only_imported_transactions() {
+		let mut client = TestBlockChainClient::new();
+		client.add_blocks(100, EachBlockWith::Uncle);
+		let tx1_hash = client.insert_transaction_to_queue();
+		let tx2_hash = client.insert_transaction_to_queue();
+		let mut sync = dummy_sync_with_peer(client.block_hash_delta_minus(1), &client);
+		let queue = RwLock::new(VecDeque::new());
+		let ss = TestSnapshotService::new();
+		let mut io = TestIo::new(&mut client, &ss, &queue, None);
+		let peer_count = sync.propagate_imported_transactions(&mut io, &[tx2_hash]);
+		// The rest of the pending set goes with the next round
+		let peer_count2 = sync.propagate_new_transactions(&mut io);
+		let peer_count3 = sync.propagate_new_transactions(&mut io);
+
+		assert_eq!(1, peer_count);
+		assert_eq!(1, peer_count2);
+		assert_eq!(0, peer_count3);
+		let sent: Vec<Vec<H256>> = io.packets.iter()
+			.map(|p| UntrustedRlp::new(&*p.data).as_list::<UnverifiedTransaction>().unwrap()
+				.into_iter()
+				.map(|tx| tx.hash())
+				.collect())
+			.collect();
+		assert_eq!(sent, vec![vec![tx2_hash], vec![tx1_hash]]);
+	}
+
 	#[test]
 	fn should_maintain_transations_propagation_stats() {
 		let mut client = TestBlockChainClient::new();
@@ -40,11 +40,13 @@ extern crate ipnetwork;
 extern crate keccak_hash as hash;
 extern crate triehash;
 extern crate kvdb;
+extern crate serde;
 
 extern crate ethcore_light as light;
 
 #[cfg(test)] extern crate ethkey;
 #[cfg(test)] extern crate kvdb_memorydb;
+#[cfg(test)] extern crate serde_json;
 
 #[macro_use]
 extern crate macros;
@@ -52,6 +54,8 @@ extern crate macros;
 extern crate log;
 #[macro_use]
 extern crate heapsize;
+#[macro_use]
+extern crate serde_derive;
 
 mod chain;
 mod blocks;
@@ -59,6 +63,7 @@ mod block_sync;
 mod sync_io;
 mod snapshot;
 mod transactions_stats;
+mod status_report;
 
 pub mod light_sync;
 
@@ -69,5 +74,7 @@ mod api;
 
 pub use api::*;
 pub use chain::{SyncStatus, SyncState};
+pub use status_report::StatusReport;
+pub use devp2p::{NetworkStatusReport, DiscoveryStatusReport};
 pub use devp2p::{validate_node_url, ConnectionFilter, ConnectionDirection};
 pub use network::{NonReservedPeerMode, Error, ErrorKind};
@@ -0,0 +1,76 @@
+// Copyright 2015-2017 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Combined status report for monitoring.
+
+use devp2p::NetworkStatusReport;
+use ethcore::miner::MinerStatusReport;
+
+/// Network and miner metrics in one serializable snapshot, meant to be dumped as JSON at an
+/// interval. Field names are serialized as they are and dashboards key on them, so they must
+/// not be renamed.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct StatusReport {
+	/// Network half, from `NetworkService::status_report`.
+	pub network: NetworkStatusReport,
+	/// Miner half, from `Miner::status_report`. `None` for nodes without a miner.
+	pub miner: Option<MinerStatusReport>,
+}
+
+impl StatusReport {
+	/// Combine the halves.
+	pub fn new(network: NetworkStatusReport, miner: Option<MinerStatusReport>) -> StatusReport {
+		StatusReport {
+			network: network,
+			miner: miner,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use devp2p::{NetworkStats, DiscoveryStatusReport};
+	use ethcore::miner::MinerStatus;
+	use serde_json;
+
+	#[test]
+	fn serializes_both_halves() {
+		let stats = NetworkStats::new();
+		stats.inc_send(250);
+		let mut network = NetworkStatusReport::from(&stats);
+		network.peers = 5;
+		network.discovery = Some(DiscoveryStatusReport { lookups: 7, ..DiscoveryStatusReport::default() });
+		let mut miner = MinerStatusReport::from(MinerStatus {
+			transactions_in_pending_queue: 12,
+			transactions_in_future_queue: 3,
+			transactions_in_pending_block: 10,
+		});
+		miner.sealing = true;
+
+		let json = serde_json::to_value(&StatusReport::new(network, Some(miner))).unwrap();
+		assert_eq!(json["network"]["peers"], 5);
+		assert_eq!(json["network"]["bytes_sent"], 250);
+		assert_eq!(json["network"]["discovery"]["lookups"], 7);
+		assert_eq!(json["miner"]["pending_transactions"], 12);
+		assert_eq!(json["miner"]["future_transactions"], 3);
+		assert_eq!(json["miner"]["sealing"], true);
+
+		let json = serde_json::to_value(&StatusReport::default()).unwrap();
+		assert!(json["miner"].is_null());
+		assert!(json["network"]["discovery"].is_null());
+	}
+}
@@ -15,7 +15,7 @@
 // along with Parity.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::collections::HashMap;
-use network::{NetworkContext, PeerId, PacketId, Error, SessionInfo, ProtocolId};
+use network::{NetworkContext, PeerId, PacketId, Error, SessionInfo, ProtocolId, DisconnectReason};
 use bytes::Bytes;
 use ethcore::client::BlockChainClient;
 use ethcore::header::BlockNumber;
@@ -89,7 +89,7 @@ impl<'s> SyncIo for NetSyncIo<'s> {
 	}
 
 	fn disconnect_peer(&mut self, peer_id: PeerId) {
-		self.network.disconnect_peer(peer_id);
+		self.network.disconnect_peer(peer_id, DisconnectReason::DisconnectRequested, false);
 	}
 
 	fn respond(&mut self, packet_id: PacketId, data: Vec<u8>) -> Result<(), Error>{
@@ -133,7 +133,7 @@ impl<'s> SyncIo for NetSyncIo<'s> {
 	}
 
 	fn peer_info(&self, peer_id: PeerId) -> String {
-		self.network.peer_client_version(peer_id)
+		self.network.peer_client_version(peer_id).to_string()
 	}
 }
 
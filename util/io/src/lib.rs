@@ -54,10 +54,8 @@
 //! }
 //! ```
 
-//TODO: use Poll from mio
-#![allow(deprecated)]
-
 extern crate mio;
+extern crate mio_extras;
 #[macro_use]
 extern crate log as rlog;
 extern crate slab;
@@ -68,8 +66,8 @@ mod service;
 mod worker;
 
 use std::{fmt, error};
-use mio::deprecated::{EventLoop, NotifyError};
-use mio::Token;
+use mio::{Poll, Token};
+use mio_extras::channel::{SendError, TrySendError};
 
 pub use worker::LOCAL_STACK_SIZE;
 
@@ -105,8 +103,14 @@ impl From<::std::io::Error> for IoError {
 	}
 }
 
-impl<Message> From<NotifyError<service::IoMessage<Message>>> for IoError where Message: Send + Clone {
-	fn from(_err: NotifyError<service::IoMessage<Message>>) -> IoError {
+impl<Message> From<TrySendError<service::IoMessage<Message>>> for IoError where Message: Send + Clone {
+	fn from(_err: TrySendError<service::IoMessage<Message>>) -> IoError {
+		IoError::Mio(::std::io::Error::new(::std::io::ErrorKind::ConnectionAborted, "Network IO notification error"))
+	}
+}
+
+impl<Message> From<SendError<service::IoMessage<Message>>> for IoError where Message: Send + Clone {
+	fn from(_err: SendError<service::IoMessage<Message>>) -> IoError {
 		IoError::Mio(::std::io::Error::new(::std::io::ErrorKind::ConnectionAborted, "Network IO notification error"))
 	}
 }
@@ -128,15 +132,16 @@ pub trait IoHandler<Message>: Send + Sync where Message: Send + Sync + Clone + '
 	/// Called when an IO stream can be written to
 	fn stream_writable(&self, _io: &IoContext<Message>, _stream: StreamToken) {}
 	/// Register a new stream with the event loop
-	fn register_stream(&self, _stream: StreamToken, _reg: Token, _event_loop: &mut EventLoop<IoManager<Message>>) {}
+	fn register_stream(&self, _stream: StreamToken, _reg: Token, _poll: &Poll) {}
 	/// Re-register a stream with the event loop
-	fn update_stream(&self, _stream: StreamToken, _reg: Token, _event_loop: &mut EventLoop<IoManager<Message>>) {}
+	fn update_stream(&self, _stream: StreamToken, _reg: Token, _poll: &Poll) {}
 	/// Deregister a stream. Called whenstream is removed from event loop
-	fn deregister_stream(&self, _stream: StreamToken, _event_loop: &mut EventLoop<IoManager<Message>>) {}
+	fn deregister_stream(&self, _stream: StreamToken, _poll: &Poll) {}
 }
 
 pub use service::TimerToken;
 pub use service::StreamToken;
+pub use service::HandlerId;
 pub use service::IoContext;
 pub use service::IoService;
 pub use service::IoChannel;
@@ -147,6 +152,9 @@ pub use service::TOKENS_PER_HANDLER;
 mod tests {
 
 	use std::sync::Arc;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use std::thread;
+	use std::time::Duration;
 	use super::*;
 
 	struct MyHandler;
@@ -175,4 +183,76 @@ mod tests {
 		let service = IoService::<MyMessage>::start().expect("Error creating network service");
 		service.register_handler(Arc::new(MyHandler)).unwrap();
 	}
+
+	#[derive(Default)]
+	struct SumHandler(AtomicUsize);
+
+	impl IoHandler<MyMessage> for SumHandler {
+		fn message(&self, _io: &IoContext<MyMessage>, message: &MyMessage) {
+			self.0.fetch_add(message.data as usize, Ordering::SeqCst);
+		}
+	}
+
+	#[test]
+	fn test_service_handler_message() {
+		let service = IoService::<MyMessage>::start().expect("Error creating network service");
+		let first = Arc::new(SumHandler::default());
+		let second = Arc::new(SumHandler::default());
+		let first_id = service.register_handler_with_id(first.clone()).unwrap();
+		let second_id = service.register_handler_with_id(second.clone()).unwrap();
+		assert!(first_id != second_id);
+		service.channel().send_to(first_id, MyMessage { data: 1 }).unwrap();
+		service.send_message(MyMessage { data: 2 }).unwrap();
+		for _ in 0..100 {
+			if first.0.load(Ordering::SeqCst) == 3 && second.0.load(Ordering::SeqCst) == 2 {
+				break;
+			}
+			thread::sleep(Duration::from_millis(10));
+		}
+		assert_eq!(first.0.load(Ordering::SeqCst), 3);
+		assert_eq!(second.0.load(Ordering::SeqCst), 2);
+	}
+
+	#[derive(Default)]
+	struct TimerHandler {
+		once: AtomicUsize,
+		recurring: AtomicUsize,
+		cleared: AtomicUsize,
+	}
+
+	impl IoHandler<MyMessage> for TimerHandler {
+		fn initialize(&self, io: &IoContext<MyMessage>) {
+			io.register_timer_once(0, 10).unwrap();
+			io.register_timer(1, 10).unwrap();
+			io.register_timer(2, 10).unwrap();
+			io.clear_timer(2).unwrap();
+			// Replaced by the second registration.
+			io.register_timer(3, 10).unwrap();
+			io.register_timer_once(3, 10).unwrap();
+		}
+
+		fn timeout(&self, _io: &IoContext<MyMessage>, timer: TimerToken) {
+			match timer {
+				0 | 3 => self.once.fetch_add(1, Ordering::SeqCst),
+				1 => self.recurring.fetch_add(1, Ordering::SeqCst),
+				_ => self.cleared.fetch_add(1, Ordering::SeqCst),
+			};
+		}
+	}
+
+	#[test]
+	fn test_service_timers() {
+		let service = IoService::<MyMessage>::start().expect("Error creating network service");
+		let handler = Arc::new(TimerHandler::default());
+		service.register_handler(handler.clone()).unwrap();
+		for _ in 0..100 {
+			if handler.recurring.load(Ordering::SeqCst) >= 3 {
+				break;
+			}
+			thread::sleep(Duration::from_millis(50));
+		}
+		assert!(handler.recurring.load(Ordering::SeqCst) >= 3);
+		assert_eq!(handler.once.load(Ordering::SeqCst), 2);
+		assert_eq!(handler.cleared.load(Ordering::SeqCst), 0);
+	}
 }
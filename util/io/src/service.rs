@@ -17,9 +17,10 @@
 use std::sync::{Arc, Weak};
 use std::thread::{self, JoinHandle};
 use std::collections::HashMap;
-use mio::*;
-use mio::timer::{Timeout};
-use mio::deprecated::{EventLoop, Handler, Sender, EventLoopBuilder};
+use std::io;
+use mio::{Poll, Events, Token, Ready, PollOpt};
+use mio_extras::channel::{self, SyncSender, Receiver};
+use mio_extras::timer::{self, Timer, Timeout};
 use crossbeam::sync::chase_lev;
 use slab::Slab;
 use {IoError, IoHandler};
@@ -39,6 +40,19 @@ pub type HandlerId = usize;
 pub const TOKENS_PER_HANDLER: usize = 16384;
 const MAX_HANDLERS: usize = 8;
 
+/// Token of the message channel, after the tokens of all handlers.
+const CHANNEL_TOKEN: Token = Token(MAX_HANDLERS * TOKENS_PER_HANDLER);
+/// Token of the timer wheel.
+const TIMER_TOKEN: Token = Token(MAX_HANDLERS * TOKENS_PER_HANDLER + 1);
+/// Messages waiting for the event loop before sending fails.
+const CHANNEL_CAPACITY: usize = 4096;
+/// Messages handled before polling the streams and timers again.
+const MESSAGES_PER_TICK: usize = 1024;
+const EVENTS_CAPACITY: usize = 1024;
+const TIMER_TICK_MS: u64 = 100;
+const TIMER_SLOTS: usize = 1024;
+const TIMER_CAPACITY: usize = 65536;
+
 /// Messages used to communicate with the event loop from other threads.
 #[derive(Clone)]
 pub enum IoMessage<Message> where Message: Send + Clone + Sized {
@@ -48,6 +62,10 @@ pub enum IoMessage<Message> where Message: Send + Clone + Sized {
 	AddHandler {
 		handler: Arc<IoHandler<Message>+Send>,
 	},
+	/// Initialize a handler added with `IoService::register_handler_with_id`.
+	InitializeHandler {
+		handler_id: HandlerId,
+	},
 	RemoveHandler {
 		handler_id: HandlerId,
 	},
@@ -74,7 +92,12 @@ pub enum IoMessage<Message> where Message: Send + Clone + Sized {
 		token: StreamToken,
 	},
 	/// Broadcast a message across all protocol handlers.
-	UserMessage(Message)
+	UserMessage(Message),
+	/// Send a message to a single protocol handler.
+	HandlerMessage {
+		handler_id: HandlerId,
+		message: Message,
+	},
 }
 
 /// IO access point. This is passed to all IO handlers and provides an interface to the IO subsystem.
@@ -83,6 +106,15 @@ pub struct IoContext<Message> where Message: Send + Clone + Sync + 'static {
 	handler: HandlerId,
 }
 
+impl<Message> Clone for IoContext<Message> where Message: Send + Clone + Sync + 'static {
+	fn clone(&self) -> IoContext<Message> {
+		IoContext {
+			channel: self.channel.clone(),
+			handler: self.handler,
+		}
+	}
+}
+
 impl<Message> IoContext<Message> where Message: Send + Clone + Sync + 'static {
 	/// Create a new IO access point. Takes references to all the data that can be updated within the IO handler.
 	pub fn new(channel: IoChannel<Message>, handler: HandlerId) -> IoContext<Message> {
@@ -93,6 +125,7 @@ impl<Message> IoContext<Message> where Message: Send + Clone + Sync + 'static {
 	}
 
 	/// Register a new recurring IO timer. 'IoHandler::timeout' will be called with the token.
+	/// Replaces any previous timer registered with the same token.
 	pub fn register_timer(&self, token: TimerToken, ms: u64) -> Result<(), IoError> {
 		self.channel.send_io(IoMessage::AddTimer {
 			token: token,
@@ -156,6 +189,11 @@ impl<Message> IoContext<Message> where Message: Send + Clone + Sync + 'static {
 		Ok(())
 	}
 
+	/// Send a message to the handler of this context only.
+	pub fn message_self(&self, message: Message) -> Result<(), IoError> {
+		self.channel.send_to(self.handler, message)
+	}
+
 	/// Get message channel
 	pub fn channel(&self) -> IoChannel<Message> {
 		self.channel.clone()
@@ -179,8 +217,12 @@ struct UserTimer {
 }
 
 /// Root IO handler. Manages user handlers, messages and IO timers.
-pub struct IoManager<Message> where Message: Send + Sync {
-	timers: Arc<RwLock<HashMap<HandlerId, UserTimer>>>,
+pub struct IoManager<Message> where Message: Send + Sync + Clone + 'static {
+	poll: Poll,
+	channel: SyncSender<IoMessage<Message>>,
+	receiver: Receiver<IoMessage<Message>>,
+	timer: Timer<Token>,
+	timers: HashMap<usize, UserTimer>,
 	handlers: Arc<RwLock<Slab<Arc<IoHandler<Message>>, HandlerId>>>,
 	workers: Vec<Worker>,
 	worker_channel: chase_lev::Worker<Work<Message>>,
@@ -188,11 +230,20 @@ pub struct IoManager<Message> where Message: Send + Sync {
 }
 
 impl<Message> IoManager<Message> where Message: Send + Sync + Clone + 'static {
-	/// Creates a new instance and registers it with the event loop.
+	/// Creates a new instance and runs the event loop until shutdown. `receiver` must be registered
+	/// with `poll` under `CHANNEL_TOKEN`.
 	pub fn start(
-		event_loop: &mut EventLoop<IoManager<Message>>,
+		poll: Poll,
+		channel: SyncSender<IoMessage<Message>>,
+		receiver: Receiver<IoMessage<Message>>,
 		handlers: Arc<RwLock<Slab<Arc<IoHandler<Message>>, HandlerId>>>
 	) -> Result<(), IoError> {
+		let timer = timer::Builder::default()
+			.tick_duration(Duration::from_millis(TIMER_TICK_MS))
+			.num_slots(TIMER_SLOTS)
+			.capacity(TIMER_CAPACITY)
+			.build();
+		poll.register(&timer, TIMER_TOKEN, Ready::readable(), PollOpt::edge())?;
 		let (worker, stealer) = chase_lev::deque();
 		let num_workers = 4;
 		let work_ready_mutex =  Arc::new(SMutex::new(()));
@@ -201,33 +252,66 @@ impl<Message> IoManager<Message> where Message: Send + Sync + Clone + 'static {
 			Worker::new(
 				i,
 				stealer.clone(),
-				IoChannel::new(event_loop.channel(), Arc::downgrade(&handlers)),
+				IoChannel::new(channel.clone(), Arc::downgrade(&handlers)),
 				work_ready.clone(),
 				work_ready_mutex.clone(),
 			)
 		).collect();
 
 		let mut io = IoManager {
-			timers: Arc::new(RwLock::new(HashMap::new())),
+			poll: poll,
+			channel: channel,
+			receiver: receiver,
+			timer: timer,
+			timers: HashMap::new(),
 			handlers: handlers,
 			worker_channel: worker,
 			workers: workers,
 			work_ready: work_ready,
 		};
-		event_loop.run(&mut io)?;
-		Ok(())
+		io.run()
 	}
-}
 
-impl<Message> Handler for IoManager<Message> where Message: Send + Clone + Sync + 'static {
-	type Timeout = Token;
-	type Message = IoMessage<Message>;
+	fn run(&mut self) -> Result<(), IoError> {
+		let mut events = Events::with_capacity(EVENTS_CAPACITY);
+		loop {
+			match self.poll.poll(&mut events, None) {
+				Ok(_) => {},
+				Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+				Err(e) => return Err(e.into()),
+			}
+			for event in events.iter() {
+				match event.token() {
+					CHANNEL_TOKEN => {
+						// The channel is level triggered, messages left over are polled again.
+						for _ in 0..MESSAGES_PER_TICK {
+							let message = match self.receiver.try_recv() {
+								Ok(message) => message,
+								Err(_) => break,
+							};
+							if let IoMessage::Shutdown = message {
+								self.workers.clear();
+								return Ok(());
+							}
+							self.notify(message);
+						}
+					},
+					TIMER_TOKEN => {
+						while let Some(token) = self.timer.poll() {
+							self.timeout(token);
+						}
+					},
+					token => self.ready(token, event.readiness()),
+				}
+			}
+		}
+	}
 
-	fn ready(&mut self, _event_loop: &mut EventLoop<Self>, token: Token, events: Ready) {
+	fn ready(&mut self, token: Token, events: Ready) {
 		let handler_index  = token.0 / TOKENS_PER_HANDLER;
 		let token_id  = token.0 % TOKENS_PER_HANDLER;
 		if let Some(handler) = self.handlers.read().get(handler_index) {
-			if events.is_hup() {
+			if is_hup(events) {
 				self.worker_channel.push(Work { work_type: WorkType::Hup, token: token_id, handler: handler.clone(), handler_id: handler_index });
 			}
 			else {
@@ -242,74 +326,82 @@ impl<Message> Handler for IoManager<Message> where Message: Send + Clone + Sync
 		}
 	}
 
-	fn timeout(&mut self, event_loop: &mut EventLoop<Self>, token: Token) {
+	fn timeout(&mut self, token: Token) {
 		let handler_index  = token.0  / TOKENS_PER_HANDLER;
 		let token_id  = token.0  % TOKENS_PER_HANDLER;
-		if let Some(handler) = self.handlers.read().get(handler_index) {
-			let maybe_timer = self.timers.read().get(&token.0).cloned();
-			if let Some(timer) = maybe_timer {
-				if timer.once {
-					self.timers.write().remove(&token_id);
-					event_loop.clear_timeout(&timer.timeout);
-				} else {
-					event_loop.timeout(token, Duration::from_millis(timer.delay)).expect("Error re-registering user timer");
-				}
-				self.worker_channel.push(Work { work_type: WorkType::Timeout, token: token_id, handler: handler.clone(), handler_id: handler_index });
-				self.work_ready.notify_all();
-			}
+		let handler = match self.handlers.read().get(handler_index) {
+			Some(handler) => handler.clone(),
+			None => return,
+		};
+		let timer = match self.timers.get(&token.0).cloned() {
+			Some(timer) => timer,
+			None => return,
+		};
+		if timer.once {
+			self.timers.remove(&token.0);
+		} else {
+			let timeout = self.timer.set_timeout(Duration::from_millis(timer.delay), token);
+			self.timers.insert(token.0, UserTimer { timeout: timeout, ..timer });
 		}
+		self.worker_channel.push(Work { work_type: WorkType::Timeout, token: token_id, handler: handler, handler_id: handler_index });
+		self.work_ready.notify_all();
 	}
 
-	fn notify(&mut self, event_loop: &mut EventLoop<Self>, msg: Self::Message) {
+	/// Cancel and forget the timer with the given id, if any.
+	fn remove_timer(&mut self, timer_id: usize) {
+		if let Some(timer) = self.timers.remove(&timer_id) {
+			self.timer.cancel_timeout(&timer.timeout);
+		}
+	}
+
+	fn notify(&mut self, msg: IoMessage<Message>) {
 		match msg {
-			IoMessage::Shutdown => {
-				self.workers.clear();
-				event_loop.shutdown();
-			},
+			IoMessage::Shutdown => {},
 			IoMessage::AddHandler { handler } => {
 				let handler_id = self.handlers.write().insert(handler.clone()).unwrap_or_else(|_| panic!("Too many handlers registered"));
-				handler.initialize(&IoContext::new(IoChannel::new(event_loop.channel(), Arc::downgrade(&self.handlers)), handler_id));
+				handler.initialize(&IoContext::new(IoChannel::new(self.channel.clone(), Arc::downgrade(&self.handlers)), handler_id));
+			},
+			IoMessage::InitializeHandler { handler_id } => {
+				let handler = self.handlers.read().get(handler_id).cloned();
+				if let Some(handler) = handler {
+					handler.initialize(&IoContext::new(IoChannel::new(self.channel.clone(), Arc::downgrade(&self.handlers)), handler_id));
+				}
 			},
 			IoMessage::RemoveHandler { handler_id } => {
 				// TODO: flush event loop
 				self.handlers.write().remove(handler_id);
 				// unregister timers
-				let mut timers = self.timers.write();
-				let to_remove: Vec<_> = timers.keys().cloned().filter(|timer_id| timer_id / TOKENS_PER_HANDLER == handler_id).collect();
+				let to_remove: Vec<_> = self.timers.keys().cloned().filter(|timer_id| timer_id / TOKENS_PER_HANDLER == handler_id).collect();
 				for timer_id in to_remove {
-					let timer = timers.remove(&timer_id).expect("to_remove only contains keys from timers; qed");
-					event_loop.clear_timeout(&timer.timeout);
+					self.remove_timer(timer_id);
 				}
 			},
 			IoMessage::AddTimer { handler_id, token, delay, once } => {
 				let timer_id = token + handler_id * TOKENS_PER_HANDLER;
-				let timeout = event_loop.timeout(Token(timer_id), Duration::from_millis(delay)).expect("Error registering user timer");
-				self.timers.write().insert(timer_id, UserTimer { delay: delay, timeout: timeout, once: once });
+				// a new schedule replaces the previous one
+				self.remove_timer(timer_id);
+				let timeout = self.timer.set_timeout(Duration::from_millis(delay), Token(timer_id));
+				self.timers.insert(timer_id, UserTimer { delay: delay, timeout: timeout, once: once });
 			},
 			IoMessage::RemoveTimer { handler_id, token } => {
-				let timer_id = token + handler_id * TOKENS_PER_HANDLER;
-				if let Some(timer) = self.timers.write().remove(&timer_id) {
-					event_loop.clear_timeout(&timer.timeout);
-				}
+				self.remove_timer(token + handler_id * TOKENS_PER_HANDLER);
 			},
 			IoMessage::RegisterStream { handler_id, token } => {
 				if let Some(handler) = self.handlers.read().get(handler_id) {
-					handler.register_stream(token, Token(token + handler_id * TOKENS_PER_HANDLER), event_loop);
+					handler.register_stream(token, Token(token + handler_id * TOKENS_PER_HANDLER), &self.poll);
 				}
 			},
 			IoMessage::DeregisterStream { handler_id, token } => {
-				if let Some(handler) = self.handlers.read().get(handler_id) {
-					handler.deregister_stream(token, event_loop);
+				let handler = self.handlers.read().get(handler_id).cloned();
+				if let Some(handler) = handler {
+					handler.deregister_stream(token, &self.poll);
 					// unregister a timer associated with the token (if any)
-					let timer_id = token + handler_id * TOKENS_PER_HANDLER;
-					if let Some(timer) = self.timers.write().remove(&timer_id) {
-						event_loop.clear_timeout(&timer.timeout);
-					}
+					self.remove_timer(token + handler_id * TOKENS_PER_HANDLER);
 				}
 			},
 			IoMessage::UpdateStreamRegistration { handler_id, token } => {
 				if let Some(handler) = self.handlers.read().get(handler_id) {
-					handler.update_stream(token, Token(token + handler_id * TOKENS_PER_HANDLER), event_loop);
+					handler.update_stream(token, Token(token + handler_id * TOKENS_PER_HANDLER), &self.poll);
 				}
 			},
 			IoMessage::UserMessage(data) => {
@@ -321,11 +413,30 @@ impl<Message> Handler for IoManager<Message> where Message: Send + Clone + Sync
 					}
 				}
 				self.work_ready.notify_all();
-			}
+			},
+			IoMessage::HandlerMessage { handler_id, message } => {
+				let handler = self.handlers.read().get(handler_id).cloned();
+				if let Some(handler) = handler {
+					self.worker_channel.push(Work { work_type: WorkType::Message(message), token: 0, handler: handler, handler_id: handler_id });
+					self.work_ready.notify_all();
+				}
+			},
 		}
 	}
 }
 
+#[cfg(unix)]
+fn is_hup(events: Ready) -> bool {
+	::mio::unix::UnixReady::from(events).is_hup()
+}
+
+/// Outside of unix the hang-up is still reported through the deprecated `Ready` flag.
+#[cfg(not(unix))]
+#[allow(deprecated)]
+fn is_hup(events: Ready) -> bool {
+	events.is_hup()
+}
+
 #[derive(Clone)]
 enum Handlers<Message> where Message: Send + Clone {
 	SharedCollection(Weak<RwLock<Slab<Arc<IoHandler<Message>>, HandlerId>>>),
@@ -335,7 +446,7 @@ enum Handlers<Message> where Message: Send + Clone {
 /// Allows sending messages into the event loop. All the IO handlers will get the message
 /// in the `message` callback.
 pub struct IoChannel<Message> where Message: Send + Clone{
-	channel: Option<Sender<IoMessage<Message>>>,
+	channel: Option<SyncSender<IoMessage<Message>>>,
 	handlers: Handlers<Message>,
 
 }
@@ -353,7 +464,7 @@ impl<Message> IoChannel<Message> where Message: Send + Clone + Sync + 'static {
 	/// Send a message through the channel
 	pub fn send(&self, message: Message) -> Result<(), IoError> {
 		match self.channel {
-			Some(ref channel) => channel.send(IoMessage::UserMessage(message))?,
+			Some(ref channel) => channel.try_send(IoMessage::UserMessage(message))?,
 			None => self.send_sync(message)?
 		}
 		Ok(())
@@ -381,10 +492,31 @@ impl<Message> IoChannel<Message> where Message: Send + Clone + Sync + 'static {
 		Ok(())
 	}
 
+	/// Send a message to a single handler through the channel
+	pub fn send_to(&self, handler_id: HandlerId, message: Message) -> Result<(), IoError> {
+		match self.channel {
+			Some(ref channel) => channel.try_send(IoMessage::HandlerMessage { handler_id: handler_id, message: message })?,
+			None => match self.handlers {
+				Handlers::SharedCollection(ref handlers) => {
+					let handler = handlers.upgrade().and_then(|handlers| handlers.read().get(handler_id).cloned());
+					if let Some(handler) = handler {
+						handler.message(&IoContext::new(self.clone(), handler_id), &message);
+					}
+				},
+				Handlers::Single(ref handler) => {
+					if let Some(handler) = handler.upgrade() {
+						handler.message(&IoContext::new(self.clone(), 0), &message);
+					}
+				}
+			}
+		}
+		Ok(())
+	}
+
 	/// Send low level io message
 	pub fn send_io(&self, message: IoMessage<Message>) -> Result<(), IoError> {
 		if let Some(ref channel) = self.channel {
-			channel.send(message)?
+			channel.try_send(message)?
 		}
 		Ok(())
 	}
@@ -403,7 +535,7 @@ impl<Message> IoChannel<Message> where Message: Send + Clone + Sync + 'static {
 			handlers: Handlers::Single(handler),
 		}
 	}
-	fn new(channel: Sender<IoMessage<Message>>, handlers: Weak<RwLock<Slab<Arc<IoHandler<Message>>, HandlerId>>>) -> IoChannel<Message> {
+	fn new(channel: SyncSender<IoMessage<Message>>, handlers: Weak<RwLock<Slab<Arc<IoHandler<Message>>, HandlerId>>>) -> IoChannel<Message> {
 		IoChannel {
 			channel: Some(channel),
 			handlers: Handlers::SharedCollection(handlers),
@@ -415,21 +547,21 @@ impl<Message> IoChannel<Message> where Message: Send + Clone + Sync + 'static {
 /// 'Message' is a notification message type
 pub struct IoService<Message> where Message: Send + Sync + Clone + 'static {
 	thread: Mutex<Option<JoinHandle<()>>>,
-	host_channel: Mutex<Sender<IoMessage<Message>>>,
+	host_channel: Mutex<SyncSender<IoMessage<Message>>>,
 	handlers: Arc<RwLock<Slab<Arc<IoHandler<Message>>, HandlerId>>>,
 }
 
 impl<Message> IoService<Message> where Message: Send + Sync + Clone + 'static {
 	/// Starts IO event loop
 	pub fn start() -> Result<IoService<Message>, IoError> {
-		let mut config = EventLoopBuilder::new();
-		config.messages_per_tick(1024);
-		let mut event_loop = config.build().expect("Error creating event loop");
-		let channel = event_loop.channel();
+		let poll = Poll::new()?;
+		let (channel, receiver) = channel::sync_channel(CHANNEL_CAPACITY);
+		poll.register(&receiver, CHANNEL_TOKEN, Ready::readable(), PollOpt::level())?;
 		let handlers = Arc::new(RwLock::new(Slab::new(MAX_HANDLERS)));
 		let h = handlers.clone();
+		let c = channel.clone();
 		let thread = thread::spawn(move || {
-			IoManager::<Message>::start(&mut event_loop, h).expect("Error starting IO service");
+			IoManager::<Message>::start(poll, c, receiver, h).expect("Error starting IO service");
 		});
 		Ok(IoService {
 			thread: Mutex::new(Some(thread)),
@@ -443,6 +575,7 @@ impl<Message> IoService<Message> where Message: Send + Sync + Clone + 'static {
 		// Clear handlers so that shared pointers are not stuck on stack
 		// in Channel::send_sync
 		self.handlers.write().clear();
+		// Control messages wait for room in the channel rather than being dropped when it is full.
 		self.host_channel.lock().send(IoMessage::Shutdown).unwrap_or_else(|e| warn!("Error on IO service shutdown: {:?}", e));
 		if let Some(thread) = self.thread.lock().take() {
 			thread.join().unwrap_or_else(|e| {
@@ -460,9 +593,20 @@ impl<Message> IoService<Message> where Message: Send + Sync + Clone + 'static {
 		Ok(())
 	}
 
+	/// Register an IO handler and return the id it is known by in its `IoContext`. The id is assigned
+	/// right away, while the handler is initialized on the event loop thread.
+	pub fn register_handler_with_id(&self, handler: Arc<IoHandler<Message>+Send>) -> Result<HandlerId, IoError> {
+		let handler_id = self.handlers.write().insert(handler)
+			.map_err(|_| IoError::StdIo(::std::io::Error::new(::std::io::ErrorKind::Other, "Too many handlers registered")))?;
+		self.host_channel.lock().send(IoMessage::InitializeHandler {
+			handler_id: handler_id,
+		})?;
+		Ok(handler_id)
+	}
+
 	/// Send a message over the network. Normaly `HostIo::send` should be used. This can be used from non-io threads.
 	pub fn send_message(&self, message: Message) -> Result<(), IoError> {
-		self.host_channel.lock().send(IoMessage::UserMessage(message))?;
+		self.host_channel.lock().try_send(IoMessage::UserMessage(message))?;
 		Ok(())
 	}
 
@@ -361,6 +361,10 @@ impl JournalDB for EarlyMergeDB {
 		}
  	}
 
+	fn transaction_mem_used(&self) -> usize {
+		self.overlay.mem_used()
+	}
+
 	fn state(&self, id: &H256) -> Option<Bytes> {
 		self.backing.get_by_prefix(self.column, &id[0..DB_PREFIX_LEN]).map(|b| b.into_vec())
 	}
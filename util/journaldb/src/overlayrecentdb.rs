@@ -223,6 +223,10 @@ impl JournalDB for OverlayRecentDB {
 		mem
 	}
 
+	fn transaction_mem_used(&self) -> usize {
+		self.transaction_overlay.mem_used()
+	}
+
 	fn journal_size(&self) -> usize {
 		self.journal_overlay.read().cumulative_size
 
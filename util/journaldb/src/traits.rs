@@ -32,6 +32,10 @@ pub trait JournalDB: HashDB {
 	/// Returns heap memory size used
 	fn mem_used(&self) -> usize;
 
+	/// Returns heap memory size used by changes not yet journalled, leaving out memory shared
+	/// with clones.
+	fn transaction_mem_used(&self) -> usize { self.mem_used() }
+
 	/// Returns the size of journalled state in memory.
 	/// This function has a considerable speed requirement --
 	/// it must be fast enough to call several times per block imported.
@@ -0,0 +1,87 @@
+// Copyright 2015-2018 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Sending packets below and above the small packet size over a loopback session.
+//! should be started with:
+//! ```bash
+//! multirust run nightly cargo bench
+//! ```
+
+#![feature(test)]
+
+extern crate test;
+extern crate parking_lot;
+extern crate ethcore_network;
+extern crate ethcore_network_devp2p;
+
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use parking_lot::Mutex;
+use test::Bencher;
+use ethcore_network::{NetworkConfiguration, NetworkContext, NetworkProtocolHandler, PeerId};
+use ethcore_network_devp2p::NetworkService;
+
+#[derive(Default)]
+struct Peers(Mutex<Vec<PeerId>>);
+
+impl NetworkProtocolHandler for Peers {
+	fn read(&self, _io: &NetworkContext, _peer: &PeerId, _packet_id: u8, _data: &[u8]) {}
+
+	fn connected(&self, _io: &NetworkContext, peer: &PeerId) {
+		self.0.lock().push(*peer);
+	}
+
+	fn disconnected(&self, _io: &NetworkContext, peer: &PeerId) {
+		self.0.lock().retain(|p| p != peer);
+	}
+}
+
+fn service() -> (NetworkService, Arc<Peers>) {
+	let mut config = NetworkConfiguration::new_local();
+	config.discovery_enabled = false;
+	let service = NetworkService::new(config, None).unwrap();
+	service.start().unwrap();
+	let peers = Arc::new(Peers::default());
+	service.register_protocol(peers.clone(), *b"bnc", 1, &[1u8]).unwrap();
+	(service, peers)
+}
+
+/// Send packets with `size` bytes of payload from one service to another.
+fn send_packets(b: &mut Bencher, size: usize) {
+	let (service1, peers1) = service();
+	let (service2, _peers2) = service();
+	service2.connect_peer(&service1.local_url().unwrap(), false).unwrap()
+		.recv_timeout(Duration::from_secs(10)).unwrap().unwrap();
+	while peers1.0.lock().is_empty() {
+		thread::sleep(Duration::from_millis(10));
+	}
+	let peer = peers1.0.lock()[0];
+	let payload = vec![0x42u8; size];
+	b.iter(|| service1.with_context(*b"bnc", |io| io.send(peer, 0, payload.clone()).unwrap()));
+}
+
+/// Encrypted on the stack and written right away.
+#[bench]
+fn send_small_packet(b: &mut Bencher) {
+	send_packets(b, 100);
+}
+
+/// Encrypted into a pooled buffer and queued.
+#[bench]
+fn send_large_packet(b: &mut Bencher) {
+	send_packets(b, 1000);
+}
@@ -0,0 +1,187 @@
+// Copyright 2015-2017 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Boot node health tracking.
+
+use std::cmp::min;
+use std::collections::HashMap;
+use std::time::Duration;
+use node_table::NodeId;
+
+/// Connection health of a single boot node.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BootNodeHealth {
+	/// Failed connection attempts since the last successful session.
+	pub consecutive_failures: u32,
+	/// Time of the last successful session, in nanoseconds of `time::precise_time_ns`.
+	pub last_success_ns: Option<u64>,
+	/// The node is not dialed before this time.
+	next_attempt_ns: u64,
+}
+
+/// Tracks configured boot nodes and applies exponential backoff to the ones that fail.
+pub struct BootNodes {
+	nodes: HashMap<NodeId, BootNodeHealth>,
+	backoff_ns: u64,
+	max_backoff_ns: u64,
+	fallback_threshold: u32,
+	/// Set while all boot nodes are considered down.
+	fallback: bool,
+}
+
+fn duration_ns(d: Duration) -> u64 {
+	d.as_secs() * 1000_000_000 + d.subsec_nanos() as u64
+}
+
+impl BootNodes {
+	/// Create a tracker for the given boot nodes.
+	pub fn new<I>(ids: I, backoff: Duration, max_backoff: Duration, fallback_threshold: u32) -> BootNodes where I: IntoIterator<Item=NodeId> {
+		BootNodes {
+			nodes: ids.into_iter().map(|id| (id, BootNodeHealth::default())).collect(),
+			backoff_ns: duration_ns(backoff),
+			max_backoff_ns: duration_ns(max_backoff),
+			fallback_threshold: fallback_threshold,
+			fallback: false,
+		}
+	}
+
+	/// Health of a boot node. `None` if the node is not a boot node.
+	pub fn health(&self, id: &NodeId) -> Option<&BootNodeHealth> {
+		self.nodes.get(id)
+	}
+
+	/// Check if the node may be dialed now. Always true for nodes that are not boot nodes.
+	pub fn can_dial(&self, id: &NodeId, now_ns: u64) -> bool {
+		self.nodes.get(id).map_or(true, |h| now_ns >= h.next_attempt_ns)
+	}
+
+	/// Delay before the next attempt after the given number of consecutive failures.
+	fn backoff_ns(&self, failures: u32) -> u64 {
+		if failures == 0 {
+			return 0;
+		}
+		let factor = 1u64 << min(failures - 1, 32);
+		min(self.backoff_ns.saturating_mul(factor), self.max_backoff_ns)
+	}
+
+	/// Record a failed connection attempt.
+	pub fn note_failure(&mut self, id: &NodeId, now_ns: u64) {
+		let backoff = match self.nodes.get(id) {
+			Some(h) => self.backoff_ns(h.consecutive_failures + 1),
+			None => return,
+		};
+		if let Some(h) = self.nodes.get_mut(id) {
+			h.consecutive_failures += 1;
+			h.next_attempt_ns = now_ns + backoff;
+			trace!(target: "network", "Boot node {} failed {} times, next attempt in {}ms", id, h.consecutive_failures, backoff / 1000_000);
+		}
+	}
+
+	/// Record a successful session. Resets the backoff of the node.
+	pub fn note_success(&mut self, id: &NodeId, now_ns: u64) {
+		if let Some(h) = self.nodes.get_mut(id) {
+			h.consecutive_failures = 0;
+			h.next_attempt_ns = 0;
+			h.last_success_ns = Some(now_ns);
+		}
+	}
+
+	/// Check if every boot node has failed at least `fallback_threshold` times in a row.
+	/// Always false when no boot nodes are configured.
+	pub fn all_failed(&self) -> bool {
+		!self.nodes.is_empty() && self.nodes.values().all(|h| h.consecutive_failures >= self.fallback_threshold)
+	}
+
+	/// Update the fallback state. Returns `Some(active)` if it has changed.
+	pub fn update_fallback(&mut self) -> Option<bool> {
+		let fallback = self.all_failed();
+		if fallback == self.fallback {
+			return None;
+		}
+		self.fallback = fallback;
+		Some(fallback)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use ethereum_types::H512;
+
+	const SEC: u64 = 1000_000_000;
+
+	fn boot_nodes(count: u64) -> (BootNodes, Vec<NodeId>) {
+		let ids: Vec<NodeId> = (1..count + 1).map(H512::from).collect();
+		(BootNodes::new(ids.clone(), Duration::from_secs(1), Duration::from_secs(30), 3), ids)
+	}
+
+	#[test]
+	fn backoff_schedule() {
+		let (mut boot, ids) = boot_nodes(1);
+		let id = &ids[0];
+		let mut now = 100 * SEC;
+		assert!(boot.can_dial(id, now));
+		for &expected in &[1, 2, 4, 8, 16, 30, 30] {
+			boot.note_failure(id, now);
+			assert!(!boot.can_dial(id, now + expected * SEC - 1), "backoff {}", expected);
+			assert!(boot.can_dial(id, now + expected * SEC), "backoff {}", expected);
+			now += expected * SEC;
+		}
+		assert_eq!(boot.health(id).unwrap().consecutive_failures, 7);
+		// Other nodes are not affected.
+		assert!(boot.can_dial(&H512::from(100), 0));
+	}
+
+	#[test]
+	fn success_resets_backoff() {
+		let (mut boot, ids) = boot_nodes(1);
+		let id = &ids[0];
+		for _ in 0..5 {
+			boot.note_failure(id, 0);
+		}
+		assert!(!boot.can_dial(id, SEC));
+		boot.note_success(id, SEC);
+		assert!(boot.can_dial(id, SEC));
+		assert_eq!(boot.health(id).unwrap().consecutive_failures, 0);
+		assert_eq!(boot.health(id).unwrap().last_success_ns, Some(SEC));
+		boot.note_failure(id, 2 * SEC);
+		assert!(boot.can_dial(id, 3 * SEC));
+	}
+
+	#[test]
+	fn fallback_when_all_failed() {
+		let (mut boot, ids) = boot_nodes(2);
+		assert_eq!(boot.update_fallback(), None);
+		for _ in 0..3 {
+			boot.note_failure(&ids[0], 0);
+		}
+		assert!(!boot.all_failed());
+		for _ in 0..2 {
+			boot.note_failure(&ids[1], 0);
+		}
+		assert!(!boot.all_failed());
+		boot.note_failure(&ids[1], 0);
+		assert!(boot.all_failed());
+		assert_eq!(boot.update_fallback(), Some(true));
+		assert_eq!(boot.update_fallback(), None);
+		// A recovered boot node ends the fallback.
+		boot.note_success(&ids[0], 0);
+		assert_eq!(boot.update_fallback(), Some(false));
+
+		let (empty, _) = boot_nodes(0);
+		assert!(!empty.all_failed());
+	}
+}
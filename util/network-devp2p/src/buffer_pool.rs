@@ -0,0 +1,184 @@
+// Copyright 2015-2017 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Reusable byte buffers for connection read and write paths.
+
+use ethcore_bytes::Bytes;
+
+/// Capacity of the smallest size class. Fits frame headers and small packets.
+const MIN_CLASS_SIZE: usize = 64;
+/// Number of power-of-two size classes. The largest one is 2 MiB.
+const SIZE_CLASSES: usize = 16;
+/// Maximum number of idle buffers kept in a single size class.
+const MAX_BUFFERS_PER_CLASS: usize = 4;
+/// Maximum total capacity of idle buffers kept by a pool.
+const MAX_POOLED_BYTES: usize = 4 * 1024 * 1024;
+
+/// Size-classed pool of byte buffers. Buffers in class `i` have at least `MIN_CLASS_SIZE << i` bytes
+/// of capacity. Requests larger than the largest class are not pooled.
+#[derive(Debug)]
+pub struct BufferPool {
+	classes: Vec<Vec<Bytes>>,
+	pooled_bytes: usize,
+	/// Set when the pool is used; cleared by `shrink_idle`.
+	used: bool,
+	allocated: usize,
+	reused: usize,
+}
+
+impl Default for BufferPool {
+	fn default() -> Self {
+		BufferPool::new()
+	}
+}
+
+impl BufferPool {
+	/// Create an empty pool.
+	pub fn new() -> BufferPool {
+		BufferPool {
+			classes: vec![Vec::new(); SIZE_CLASSES],
+			pooled_bytes: 0,
+			used: false,
+			allocated: 0,
+			reused: 0,
+		}
+	}
+
+	/// Get an empty buffer with capacity for at least `size` bytes.
+	/// Sizes over the largest class get an unallocated buffer that grows on demand.
+	pub fn take(&mut self, size: usize) -> Bytes {
+		self.used = true;
+		let class = match class_for_size(size) {
+			Some(class) => class,
+			None => {
+				self.allocated += 1;
+				return Bytes::new();
+			}
+		};
+		match self.classes[class].pop() {
+			Some(buf) => {
+				self.pooled_bytes -= buf.capacity();
+				self.reused += 1;
+				buf
+			},
+			None => {
+				self.allocated += 1;
+				Bytes::with_capacity(MIN_CLASS_SIZE << class)
+			}
+		}
+	}
+
+	/// Return a buffer to the pool. The buffer is dropped if the pool is full or it does not fit any class.
+	pub fn put(&mut self, mut buf: Bytes) {
+		let class = match class_for_capacity(buf.capacity()) {
+			Some(class) => class,
+			None => return,
+		};
+		if self.classes[class].len() >= MAX_BUFFERS_PER_CLASS || self.pooled_bytes + buf.capacity() > MAX_POOLED_BYTES {
+			return;
+		}
+		buf.clear();
+		self.pooled_bytes += buf.capacity();
+		self.classes[class].push(buf);
+	}
+
+	/// Release all idle buffers if the pool has not been used since the previous call.
+	pub fn shrink_idle(&mut self) {
+		if !self.used {
+			for class in &mut self.classes {
+				class.clear();
+				class.shrink_to_fit();
+			}
+			self.pooled_bytes = 0;
+		}
+		self.used = false;
+	}
+
+	/// Number of buffers that had to be allocated.
+	pub fn allocated(&self) -> usize {
+		self.allocated
+	}
+
+	/// Number of requests served from the pool.
+	pub fn reused(&self) -> usize {
+		self.reused
+	}
+
+	/// Total capacity of idle buffers.
+	pub fn pooled_bytes(&self) -> usize {
+		self.pooled_bytes
+	}
+}
+
+/// Smallest class with enough capacity for `size` bytes.
+fn class_for_size(size: usize) -> Option<usize> {
+	(0..SIZE_CLASSES).find(|&class| MIN_CLASS_SIZE << class >= size)
+}
+
+/// Largest class a buffer with the given capacity can serve.
+fn class_for_capacity(capacity: usize) -> Option<usize> {
+	if capacity < MIN_CLASS_SIZE || capacity > MIN_CLASS_SIZE << (SIZE_CLASSES - 1) {
+		return None;
+	}
+	(0..SIZE_CLASSES).rev().find(|&class| MIN_CLASS_SIZE << class <= capacity)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn reuses_returned_buffers() {
+		let mut pool = BufferPool::new();
+		for _ in 0..10 {
+			let mut buf = pool.take(100);
+			assert!(buf.is_empty());
+			assert!(buf.capacity() >= 100);
+			buf.resize(100, 1);
+			pool.put(buf);
+		}
+		assert_eq!(pool.allocated(), 1);
+		assert_eq!(pool.reused(), 9);
+	}
+
+	#[test]
+	fn buffers_are_size_classed() {
+		let mut pool = BufferPool::new();
+		pool.put(Bytes::with_capacity(64));
+		// Too small for the request, a new buffer is allocated.
+		assert!(pool.take(65).capacity() >= 65);
+		assert_eq!(pool.reused(), 0);
+		assert_eq!(pool.take(64).capacity(), 64);
+		assert_eq!(pool.reused(), 1);
+		// Oversized and undersized buffers are not kept.
+		pool.put(Bytes::with_capacity(16));
+		pool.put(Bytes::with_capacity(MIN_CLASS_SIZE << SIZE_CLASSES));
+		assert_eq!(pool.pooled_bytes(), 0);
+	}
+
+	#[test]
+	fn shrinks_when_idle() {
+		let mut pool = BufferPool::new();
+		let buf = pool.take(1000);
+		pool.put(buf);
+		assert!(pool.pooled_bytes() > 0);
+		// Used since the last check.
+		pool.shrink_idle();
+		assert!(pool.pooled_bytes() > 0);
+		pool.shrink_idle();
+		assert_eq!(pool.pooled_bytes(), 0);
+	}
+}
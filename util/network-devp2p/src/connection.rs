@@ -15,19 +15,22 @@
 // along with Parity.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::sync::Arc;
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::net::SocketAddr;
 use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
 use hash::{keccak, write_keccak};
-use mio::{Token, Ready, PollOpt};
-use mio::deprecated::{Handler, EventLoop, TryRead, TryWrite};
-use mio::tcp::*;
+use mio::{Token, Ready, PollOpt, Poll};
+use mio::net::TcpStream;
+#[cfg(unix)]
+use mio::unix::UnixReady;
 use ethereum_types::{H128, H256, H512};
 use ethcore_bytes::*;
 use rlp::*;
 use std::io::{self, Cursor, Read, Write};
+use std::time::Duration;
 use io::{IoContext, StreamToken};
 use handshake::Handshake;
+use buffer_pool::BufferPool;
 use stats::NetworkStats;
 use rcrypto::blockmodes::*;
 use rcrypto::aessafe::*;
@@ -36,12 +39,39 @@ use rcrypto::buffer::*;
 use tiny_keccak::Keccak;
 use bytes::{Buf, BufMut};
 use crypto;
-use network::{Error, ErrorKind};
+use network::{Error, ErrorKind, SocketOptions};
+use time;
 
 const ENCRYPTED_HEADER_LEN: usize = 32;
+/// Packets shorter than this are encrypted on the stack and written without allocating.
+pub const SMALL_PACKET_SIZE: usize = 128;
+/// Largest encrypted frame of a small packet: header, header MAC, padded payload and frame MAC.
+const SMALL_FRAME_SIZE: usize = ENCRYPTED_HEADER_LEN + SMALL_PACKET_SIZE + 16;
 const RECIEVE_PAYLOAD_TIMEOUT: u64 = 30000;
 pub const MAX_PAYLOAD_SIZE: usize = (1 << 24) - 1;
 
+/// Map the `WouldBlock` error of a non-blocking socket to `None`.
+pub fn map_non_block<T>(result: io::Result<T>) -> io::Result<Option<T>> {
+	match result {
+		Ok(value) => Ok(Some(value)),
+		Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+		Err(e) => Err(e),
+	}
+}
+
+/// Interest in the hang-up of a socket only.
+#[cfg(unix)]
+fn hup_interest() -> Ready {
+	UnixReady::hup().into()
+}
+
+/// Outside of unix the hang-up is still polled for through the deprecated `Ready` flag.
+#[cfg(not(unix))]
+#[allow(deprecated)]
+fn hup_interest() -> Ready {
+	Ready::hup()
+}
+
 pub trait GenericSocket : Read + Write {
 }
 
@@ -59,12 +89,18 @@ pub struct GenericConnection<Socket: GenericSocket> {
 	rec_size: usize,
 	/// Send out packets FIFO
 	send_queue: VecDeque<Cursor<Bytes>>,
+	/// Bytes in the send queue not yet written to the socket
+	queued_bytes: usize,
 	/// Event flags this connection expects
 	interest: Ready,
 	/// Shared network statistics
 	stats: Arc<NetworkStats>,
 	/// Registered flag
 	registered: AtomicBool,
+	/// Reusable buffers for frame assembly
+	pool: BufferPool,
+	/// Peer address for sockets connected through a proxy.
+	proxied_peer: Option<SocketAddr>,
 }
 
 impl<Socket: GenericSocket> GenericConnection<Socket> {
@@ -74,6 +110,19 @@ impl<Socket: GenericSocket> GenericConnection<Socket> {
 			warn!(target:"network", "Unexpected connection read start");
 		}
 		self.rec_size = size;
+		if self.rec_buf.is_empty() && self.rec_buf.capacity() < size {
+			self.rec_buf = self.pool.take(size);
+		}
+	}
+
+	/// Return a buffer obtained from this connection for reuse.
+	pub fn recycle(&mut self, buf: Bytes) {
+		self.pool.put(buf);
+	}
+
+	/// Reusable buffers of this connection.
+	pub fn buffer_pool(&mut self) -> &mut BufferPool {
+		&mut self.pool
 	}
 
 	/// Readable IO handler. Called when there is some data to be read.
@@ -84,7 +133,7 @@ impl<Socket: GenericSocket> GenericConnection<Socket> {
 		let sock_ref = <Socket as Read>::by_ref(&mut self.socket);
 		loop {
 			let max = self.rec_size - self.rec_buf.len();
-			match sock_ref.take(max as u64).try_read(unsafe { self.rec_buf.bytes_mut() }) {
+			match map_non_block(sock_ref.take(max as u64).read(unsafe { self.rec_buf.bytes_mut() })) {
 				Ok(Some(size)) if size != 0  => {
 					unsafe { self.rec_buf.advance_mut(size); }
 					self.stats.inc_recv(size);
@@ -111,6 +160,7 @@ impl<Socket: GenericSocket> GenericConnection<Socket> {
 	pub fn send<Message>(&mut self, io: &IoContext<Message>, data: Bytes) where Message: Send + Clone + Sync + 'static {
 		if !data.is_empty() {
 			trace!(target:"network", "{}: Sending {} bytes", self.token, data.len());
+			self.queued_bytes += data.len();
 			self.send_queue.push_back(Cursor::new(data));
 			if !self.interest.is_writable() {
 				self.interest.insert(Ready::writable());
@@ -119,11 +169,57 @@ impl<Socket: GenericSocket> GenericConnection<Socket> {
 		}
 	}
 
+	/// Send a small packet without allocating. The packet is written to the socket right away if
+	/// nothing is queued before it. Whatever the socket does not take is copied to a pooled buffer and queued.
+	pub fn send_slice<Message>(&mut self, io: &IoContext<Message>, data: &[u8]) where Message: Send + Clone + Sync + 'static {
+		if data.is_empty() {
+			return;
+		}
+		let mut written = 0;
+		if self.send_queue.is_empty() {
+			match map_non_block(self.socket.write(data)) {
+				Ok(Some(size)) => {
+					written = size;
+					self.stats.inc_send(size);
+				},
+				Ok(None) => {},
+				// Reported again by the next write of the queued data.
+				Err(e) => debug!(target:"network", "{}: Write error {}", self.token, e),
+			}
+		}
+		if written == data.len() {
+			trace!(target:"network", "{}: Wrote {} bytes", self.token, written);
+			return;
+		}
+		let mut buf = self.pool.take(data.len() - written);
+		buf.extend_from_slice(&data[written..]);
+		self.send(io, buf);
+	}
+
 	/// Check if this connection has data to be sent.
 	pub fn is_sending(&self) -> bool {
 		self.interest.is_writable()
 	}
 
+	/// Number of bytes waiting in the send queue.
+	pub fn queue_depth(&self) -> usize {
+		self.queued_bytes
+	}
+
+	/// Stop or resume waiting for incoming data. Takes effect on the next registration update.
+	pub fn set_reading(&mut self, reading: bool) {
+		if reading {
+			self.interest.insert(Ready::readable());
+		} else {
+			self.interest.remove(Ready::readable());
+		}
+	}
+
+	/// Check if the socket is polled for incoming data.
+	pub fn is_reading(&self) -> bool {
+		self.interest.is_readable()
+	}
+
 	/// Writable IO handler. Called when the socket is ready to send.
 	pub fn writable<Message>(&mut self, io: &IoContext<Message>) -> Result<WriteStatus, Error> where Message: Send + Clone + Sync + 'static {
 		{
@@ -138,13 +234,15 @@ impl<Socket: GenericSocket> GenericConnection<Socket> {
 				return Ok(WriteStatus::Complete)
 			}
 
-			match self.socket.try_write(Buf::bytes(&buf)) {
+			match map_non_block(self.socket.write(Buf::bytes(&buf))) {
 				Ok(Some(size)) if (pos + size) < send_size => {
 					buf.advance(size);
+					self.queued_bytes = self.queued_bytes.saturating_sub(size);
 					self.stats.inc_send(size);
 					Ok(WriteStatus::Ongoing)
 				},
 				Ok(Some(size)) if (pos + size) == send_size => {
+					self.queued_bytes = self.queued_bytes.saturating_sub(size);
 					self.stats.inc_send(size);
 					trace!(target:"network", "{}: Wrote {} bytes", self.token, send_size);
 					Ok(WriteStatus::Complete)
@@ -155,7 +253,9 @@ impl<Socket: GenericSocket> GenericConnection<Socket> {
 			}
 		}.and_then(|r| {
 			if r == WriteStatus::Complete {
-				self.send_queue.pop_front();
+				if let Some(buf) = self.send_queue.pop_front() {
+					self.pool.put(buf.into_inner());
+				}
 			}
 			if self.send_queue.is_empty() {
 				self.interest.remove(Ready::writable());
@@ -176,11 +276,14 @@ impl Connection {
 			token: token,
 			socket: socket,
 			send_queue: VecDeque::new(),
+			queued_bytes: 0,
 			rec_buf: Bytes::new(),
 			rec_size: 0,
-			interest: Ready::hup() | Ready::readable(),
+			interest: hup_interest() | Ready::readable(),
 			stats: stats,
 			registered: AtomicBool::new(false),
+			pool: BufferPool::new(),
+			proxied_peer: None,
 		}
 	}
 
@@ -189,19 +292,58 @@ impl Connection {
 		self.token
 	}
 
-	/// Get remote peer address
+	/// Get remote peer address. For proxied connections this is the peer behind the proxy.
 	pub fn remote_addr(&self) -> io::Result<SocketAddr> {
-		self.socket.peer_addr()
+		match self.proxied_peer {
+			Some(address) => Ok(address),
+			None => self.socket.peer_addr(),
+		}
 	}
 
 	/// Get remote peer address string
 	pub fn remote_addr_str(&self) -> String {
-		self.socket.peer_addr().map(|a| a.to_string()).unwrap_or_else(|_| "Unknown".to_owned())
+		self.remote_addr().map(|a| a.to_string()).unwrap_or_else(|_| "Unknown".to_owned())
+	}
+
+	/// Set the address of the peer for a socket connected through a proxy.
+	pub fn set_proxied_peer(&mut self, address: SocketAddr) {
+		self.proxied_peer = Some(address);
+	}
+
+	/// Get local address of the socket
+	pub fn local_addr(&self) -> io::Result<SocketAddr> {
+		self.socket.local_addr()
 	}
 
 	/// Get local peer address string
 	pub fn local_addr_str(&self) -> String {
-		self.socket.local_addr().map(|a| a.to_string()).unwrap_or_else(|_| "Unknown".to_owned())
+		self.local_addr().map(|a| a.to_string()).unwrap_or_else(|_| "Unknown".to_owned())
+	}
+
+	/// Apply TCP socket options. Values rejected by the OS are logged and ignored.
+	pub fn apply_socket_options(&self, options: &SocketOptions) {
+		if let Some(nodelay) = options.nodelay {
+			check_socket_option("TCP_NODELAY", self.socket.set_nodelay(nodelay));
+		}
+		if let Some(keepalive) = options.keepalive {
+			check_socket_option("SO_KEEPALIVE", self.socket.set_keepalive(Some(keepalive)));
+		}
+		if let Some(size) = options.send_buffer_size {
+			check_socket_option("SO_SNDBUF", self.socket.set_send_buffer_size(size));
+		}
+		if let Some(size) = options.recv_buffer_size {
+			check_socket_option("SO_RCVBUF", self.socket.set_recv_buffer_size(size));
+		}
+	}
+
+	/// Get TCP socket options currently in effect. Values that can't be read are `None`.
+	pub fn socket_options(&self) -> SocketOptions {
+		SocketOptions {
+			nodelay: self.socket.nodelay().ok(),
+			keepalive: self.socket.keepalive().ok().and_then(|k| k),
+			send_buffer_size: self.socket.send_buffer_size().ok(),
+			recv_buffer_size: self.socket.recv_buffer_size().ok(),
+		}
 	}
 
 	/// Clone this connection. Clears the receiving buffer of the returned connection.
@@ -212,19 +354,22 @@ impl Connection {
 			rec_buf: Vec::new(),
 			rec_size: 0,
 			send_queue: self.send_queue.clone(),
-			interest: Ready::hup(),
+			queued_bytes: self.queued_bytes,
+			interest: hup_interest(),
 			stats: self.stats.clone(),
 			registered: AtomicBool::new(false),
+			pool: BufferPool::new(),
+			proxied_peer: self.proxied_peer,
 		})
 	}
 
 	/// Register this connection with the IO event loop.
-	pub fn register_socket<Host: Handler>(&self, reg: Token, event_loop: &mut EventLoop<Host>) -> io::Result<()> {
+	pub fn register_socket(&self, reg: Token, poll: &Poll) -> io::Result<()> {
 		if self.registered.load(AtomicOrdering::SeqCst) {
 			return Ok(());
         }
 		trace!(target: "network", "connection register; token={:?}", reg);
-		if let Err(e) = event_loop.register(&self.socket, reg, self.interest, PollOpt::edge() /* | PollOpt::oneshot() */) { // TODO: oneshot is broken on windows
+		if let Err(e) = poll.register(&self.socket, reg, self.interest, PollOpt::edge() /* | PollOpt::oneshot() */) { // TODO: oneshot is broken on windows
 			trace!(target: "network", "Failed to register {:?}, {:?}", reg, e);
 		}
 		self.registered.store(true, AtomicOrdering::SeqCst);
@@ -232,12 +377,12 @@ impl Connection {
 	}
 
 	/// Update connection registration. Should be called at the end of the IO handler.
-	pub fn update_socket<Host: Handler>(&self, reg: Token, event_loop: &mut EventLoop<Host>) -> io::Result<()> {
+	pub fn update_socket(&self, reg: Token, poll: &Poll) -> io::Result<()> {
 		trace!(target: "network", "connection reregister; token={:?}", reg);
 		if !self.registered.load(AtomicOrdering::SeqCst) {
-			self.register_socket(reg, event_loop)
+			self.register_socket(reg, poll)
         } else {
-			event_loop.reregister(&self.socket, reg, self.interest, PollOpt::edge() /* | PollOpt::oneshot() */ ).unwrap_or_else(|e| {  // TODO: oneshot is broken on windows
+			poll.reregister(&self.socket, reg, self.interest, PollOpt::edge() /* | PollOpt::oneshot() */ ).unwrap_or_else(|e| {  // TODO: oneshot is broken on windows
 				trace!(target: "network", "Failed to reregister {:?}, {:?}", reg, e);
 			});
 			Ok(())
@@ -245,13 +390,19 @@ impl Connection {
 	}
 
 	/// Delete connection registration. Should be called at the end of the IO handler.
-	pub fn deregister_socket<Host: Handler>(&self, event_loop: &mut EventLoop<Host>) -> io::Result<()> {
+	pub fn deregister_socket(&self, poll: &Poll) -> io::Result<()> {
 		trace!(target: "network", "connection deregister; token={:?}", self.token);
-		event_loop.deregister(&self.socket).ok(); // ignore errors here
+		poll.deregister(&self.socket).ok(); // ignore errors here
 		Ok(())
 	}
 }
 
+fn check_socket_option(option: &str, result: io::Result<()>) {
+	if let Err(e) = result {
+		warn!(target: "network", "Error setting socket option {}: {}", option, e);
+	}
+}
+
 /// Connection write status.
 #[derive(PartialEq, Eq)]
 pub enum WriteStatus {
@@ -267,6 +418,90 @@ pub struct Packet {
 	pub data: Bytes,
 }
 
+/// Chunked packet being reassembled.
+struct ChunkedPacket {
+	data: Bytes,
+	/// Total packet size announced in the first frame.
+	total: usize,
+	/// Time the first frame was received.
+	started_ns: u64,
+}
+
+/// Reassembles chunked packets. Frames of different packets may be interleaved,
+/// packets are told apart by protocol and context id.
+struct Reassembly {
+	pending: HashMap<(u16, u16), ChunkedPacket>,
+	/// Limit for a single packet and for all pending packets together.
+	max_packet_size: usize,
+	timeout_ns: u64,
+}
+
+impl Reassembly {
+	fn new(max_packet_size: usize, timeout_ns: u64) -> Reassembly {
+		Reassembly {
+			pending: HashMap::new(),
+			max_packet_size: max_packet_size,
+			timeout_ns: timeout_ns,
+		}
+	}
+
+	/// Add a frame of a chunked packet. `total` is set for the first frame only.
+	/// Returns the packet once all frames are received.
+	fn frame(&mut self, protocol: u16, context: u16, total: Option<usize>, data: &[u8], now_ns: u64) -> Result<Option<Packet>, Error> {
+		let key = (protocol, context);
+		if let Some(total) = total {
+			if self.pending.contains_key(&key) {
+				debug!(target: "network", "Chunked packet {} restarted before completion", context);
+				bail!(ErrorKind::BadProtocol);
+			}
+			let pending_size: usize = self.pending.values().map(|p| p.total).sum();
+			if total > self.max_packet_size || pending_size + total > self.max_packet_size {
+				bail!(ErrorKind::OversizedPacket);
+			}
+			self.pending.insert(key, ChunkedPacket { data: Bytes::new(), total: total, started_ns: now_ns });
+		}
+		let complete = match self.pending.get_mut(&key) {
+			Some(packet) => {
+				if packet.data.len() + data.len() > packet.total {
+					bail!(ErrorKind::BadProtocol);
+				}
+				packet.data.extend_from_slice(data);
+				packet.data.len() == packet.total
+			},
+			None => {
+				debug!(target: "network", "Frame for unknown chunked packet {}", context);
+				bail!(ErrorKind::BadProtocol);
+			}
+		};
+		if complete {
+			let packet = self.pending.remove(&key).expect("checked above; qed");
+			return Ok(Some(Packet { protocol: protocol, data: packet.data }));
+		}
+		Ok(None)
+	}
+
+	/// Drop packets that have been incomplete for longer than the timeout. Returns the number of dropped packets.
+	fn expire(&mut self, now_ns: u64) -> usize {
+		let timeout_ns = self.timeout_ns;
+		let before = self.pending.len();
+		self.pending.retain(|_, p| now_ns.saturating_sub(p.started_ns) < timeout_ns);
+		before - self.pending.len()
+	}
+}
+
+/// Frame header: 3 bytes of frame size followed by the RLP header data and zero padding.
+fn frame_header(len: usize, header_data: &[u8]) -> [u8; 16] {
+	let mut header = [0u8; 16];
+	header[0..3].copy_from_slice(&[(len >> 16) as u8, (len >> 8) as u8, len as u8]);
+	header[3..(3 + header_data.len())].copy_from_slice(header_data);
+	header
+}
+
+/// Size of the encrypted frame of a payload: header, header MAC, padded payload and frame MAC.
+fn encrypted_frame_len(len: usize) -> usize {
+	ENCRYPTED_HEADER_LEN + len + (16 - (len % 16)) % 16 + 16
+}
+
 /// Encrypted connection receiving state.
 enum EncryptedConnectionState {
 	/// Reading a header.
@@ -296,6 +531,14 @@ pub struct EncryptedConnection {
 	protocol_id: u16,
 	/// Payload expected to be received for the last header.
 	payload_len: usize,
+	/// Context id and, for the first frame, total packet size of the last received chunked frame.
+	frame_context: Option<(u16, Option<usize>)>,
+	/// Packets larger than this are sent in chunks.
+	frame_size: usize,
+	/// Context id of the last chunked packet sent.
+	context_id: u16,
+	/// Chunked packets being received.
+	reassembly: Reassembly,
 }
 
 impl EncryptedConnection {
@@ -347,27 +590,81 @@ impl EncryptedConnection {
 			read_state: EncryptedConnectionState::Header,
 			protocol_id: 0,
 			payload_len: 0,
+			frame_context: None,
+			frame_size: MAX_PAYLOAD_SIZE,
+			context_id: 0,
+			reassembly: Reassembly::new(MAX_PAYLOAD_SIZE, RECIEVE_PAYLOAD_TIMEOUT * 1000_000),
 		};
 		enc.connection.expect(ENCRYPTED_HEADER_LEN);
 		Ok(enc)
 	}
 
-	/// Send a packet
+	/// Set the frame size for outgoing packets, and the size limit and timeout for incoming chunked packets.
+	pub fn set_frame_limits(&mut self, frame_size: usize, max_packet_size: usize, timeout: Duration) {
+		self.frame_size = ::std::cmp::max(1, ::std::cmp::min(frame_size, MAX_PAYLOAD_SIZE));
+		self.reassembly.max_packet_size = max_packet_size;
+		self.reassembly.timeout_ns = timeout.as_secs() * 1000_000_000 + timeout.subsec_nanos() as u64;
+	}
+
+	/// Send a packet. Packets over the frame size are split into chunked frames.
 	pub fn send_packet<Message>(&mut self, io: &IoContext<Message>, payload: &[u8]) -> Result<(), Error> where Message: Send + Clone + Sync + 'static {
-		let mut header = RlpStream::new();
 		let len = payload.len();
-		if len > MAX_PAYLOAD_SIZE {
+		if len <= self.frame_size {
+			// Header data `[0, 0]`.
+			self.send_frame(io, &[0xc2u8, 0x80u8, 0x80u8], payload);
+			return Ok(());
+		}
+		if len > ::std::cmp::max(self.reassembly.max_packet_size, MAX_PAYLOAD_SIZE) {
 			bail!(ErrorKind::OversizedPacket);
 		}
-		header.append_raw(&[(len >> 16) as u8, (len >> 8) as u8, len as u8], 1);
-		header.append_raw(&[0xc2u8, 0x80u8, 0x80u8], 1);
-		//TODO: ger rid of vectors here
-		let mut header = header.out();
-		let padding = (16 - (payload.len() % 16)) % 16;
-		header.resize(16, 0u8);
+		self.context_id = self.context_id.wrapping_add(1);
+		if self.context_id == 0 {
+			self.context_id = 1;
+		}
+		let context_id = self.context_id;
+		for (i, chunk) in payload.chunks(self.frame_size).enumerate() {
+			// The first frame is `[0, context-id, total-packet-size]`, the rest are `[0, context-id]`.
+			let mut header_data = RlpStream::new_list(if i == 0 { 3 } else { 2 });
+			header_data.append(&0u16).append(&context_id);
+			if i == 0 {
+				header_data.append(&(len as u32));
+			}
+			self.send_frame(io, &header_data.drain(), chunk);
+		}
+		trace!(target: "network", "{}: Sent {} bytes in chunks of {}", self.connection.token, len, self.frame_size);
+		Ok(())
+	}
+
+	/// Encrypt a single frame and send it. Small frames are assembled on the stack.
+	fn send_frame<Message>(&mut self, io: &IoContext<Message>, header_data: &[u8], payload: &[u8]) where Message: Send + Clone + Sync + 'static {
+		if payload.len() >= SMALL_PACKET_SIZE {
+			self.queue_frame(io, header_data, payload);
+			return;
+		}
+		let mut frame = [0u8; SMALL_FRAME_SIZE];
+		let frame_len = encrypted_frame_len(payload.len());
+		self.encrypt_frame(header_data, payload, &mut frame[0..frame_len]);
+		self.connection.send_slice(io, &frame[0..frame_len]);
+	}
+
+	/// Encrypt a single frame into a pooled buffer and add it to the send queue.
+	fn queue_frame<Message>(&mut self, io: &IoContext<Message>, header_data: &[u8], payload: &[u8]) where Message: Send + Clone + Sync + 'static {
+		let frame_len = encrypted_frame_len(payload.len());
+		let mut packet = self.connection.buffer_pool().take(frame_len);
+		packet.resize(frame_len, 0u8);
+		self.encrypt_frame(header_data, payload, &mut packet[..]);
+		self.connection.send(io, packet);
+	}
+
+	/// Encrypt a frame into `packet`, which must be `encrypted_frame_len(payload.len())` bytes long.
+	/// Advances the egress cipher and MAC.
+	fn encrypt_frame(&mut self, header_data: &[u8], payload: &[u8], packet: &mut [u8]) {
+		let len = payload.len();
+		let header = frame_header(len, header_data);
+		let padding = (16 - (len % 16)) % 16;
+		debug_assert_eq!(packet.len(), encrypted_frame_len(len));
 
-		let mut packet = vec![0u8; 32 + payload.len() + padding + 16];
-		self.encoder.encrypt(&mut RefReadBuffer::new(&header), &mut RefWriteBuffer::new(&mut packet), false).expect("Invalid length or padding");
+		self.encoder.encrypt(&mut RefReadBuffer::new(&header), &mut RefWriteBuffer::new(&mut packet[0..16]), false).expect("Invalid length or padding");
 		EncryptedConnection::update_mac(&mut self.egress_mac, &mut self.mac_encoder,  &packet[0..16]);
 		self.egress_mac.clone().finalize(&mut packet[16..32]);
 		self.encoder.encrypt(&mut RefReadBuffer::new(payload), &mut RefWriteBuffer::new(&mut packet[32..(32 + len)]), padding == 0).expect("Invalid length or padding");
@@ -378,8 +675,6 @@ impl EncryptedConnection {
 		self.egress_mac.update(&packet[32..(32 + len + padding)]);
 		EncryptedConnection::update_mac(&mut self.egress_mac, &mut self.mac_encoder, &[0u8; 0]);
 		self.egress_mac.clone().finalize(&mut packet[(32 + len + padding)..]);
-		self.connection.send(io, packet);
-		Ok(())
 	}
 
 	/// Decrypt and authenticate an incoming packet header. Prepare for receiving payload.
@@ -399,11 +694,15 @@ impl EncryptedConnection {
 		self.decoder.decrypt(&mut RefReadBuffer::new(&header[0..16]), &mut RefWriteBuffer::new(&mut hdec), false).expect("Invalid length or padding");
 
 		let length = ((((hdec[0] as u32) << 8) + (hdec[1] as u32)) << 8) + (hdec[2] as u32);
-		let header_rlp = UntrustedRlp::new(&hdec[3..6]);
+		let header_rlp = UntrustedRlp::new(&hdec[3..]);
 		let protocol_id = header_rlp.val_at::<u16>(0)?;
+		let items = header_rlp.item_count()?;
+		let context_id = if items > 1 { header_rlp.val_at::<u16>(1)? } else { 0 };
+		let total = if items > 2 { Some(header_rlp.val_at::<u32>(2)? as usize) } else { None };
 
 		self.payload_len = length as usize;
 		self.protocol_id = protocol_id;
+		self.frame_context = if context_id != 0 || total.is_some() { Some((context_id, total)) } else { None };
 		self.read_state = EncryptedConnectionState::Payload;
 
 		let padding = (16 - (length % 16)) % 16;
@@ -428,7 +727,8 @@ impl EncryptedConnection {
 			return Err(ErrorKind::Auth.into());
 		}
 
-		let mut packet = vec![0u8; self.payload_len];
+		let mut packet = self.connection.buffer_pool().take(self.payload_len);
+		packet.resize(self.payload_len, 0u8);
 		self.decoder.decrypt(&mut RefReadBuffer::new(&payload[0..self.payload_len]), &mut RefWriteBuffer::new(&mut packet), false).expect("Invalid length or padding");
 		let mut pad_buf = [0u8; 16];
 		self.decoder.decrypt(&mut RefReadBuffer::new(&payload[self.payload_len..(payload.len() - 16)]), &mut RefWriteBuffer::new(&mut pad_buf), false).expect("Invalid length or padding");
@@ -451,28 +751,46 @@ impl EncryptedConnection {
 	}
 
 	/// Readable IO handler. Tracker receive status and returns decoded packet if avaialable.
+	/// Frames of chunked packets are consumed until a packet is complete or no more data is available.
 	pub fn readable<Message>(&mut self, io: &IoContext<Message>) -> Result<Option<Packet>, Error> where Message: Send + Clone + Sync + 'static {
 		io.clear_timer(self.connection.token)?;
-		if let EncryptedConnectionState::Header = self.read_state {
-			if let Some(data) = self.connection.readable()? {
-				self.read_header(&data)?;
-				io.register_timer(self.connection.token, RECIEVE_PAYLOAD_TIMEOUT)?;
+		loop {
+			if let EncryptedConnectionState::Header = self.read_state {
+				if let Some(data) = self.connection.readable()? {
+					self.read_header(&data)?;
+					self.connection.recycle(data);
+					io.register_timer(self.connection.token, RECIEVE_PAYLOAD_TIMEOUT)?;
+				}
+			};
+			if let EncryptedConnectionState::Header = self.read_state {
+				return Ok(None);
 			}
-		};
-		if let EncryptedConnectionState::Payload = self.read_state {
-			match self.connection.readable()? {
-				Some(data) => {
-					self.read_state = EncryptedConnectionState::Header;
-					self.connection.expect(ENCRYPTED_HEADER_LEN);
-					Ok(Some(self.read_payload(&data)?))
+			let data = match self.connection.readable()? {
+				Some(data) => data,
+				None => return Ok(None),
+			};
+			self.read_state = EncryptedConnectionState::Header;
+			let packet = self.read_payload(&data)?;
+			self.connection.recycle(data);
+			self.connection.expect(ENCRYPTED_HEADER_LEN);
+			match self.frame_context.take() {
+				None => return Ok(Some(packet)),
+				Some((context_id, total)) => {
+					let complete = self.reassembly.frame(packet.protocol, context_id, total, &packet.data, time::precise_time_ns())?;
+					self.connection.recycle(packet.data);
+					if complete.is_some() {
+						return Ok(complete);
+					}
 				},
-				None => Ok(None)
 			}
-		} else {
-			Ok(None)
 		}
 	}
 
+	/// Drop chunked packets that have not been completed in time. Returns the number of dropped packets.
+	pub fn expire_chunks(&mut self, now_ns: u64) -> usize {
+		self.reassembly.expire(now_ns)
+	}
+
 	/// Writable IO handler. Processes send queeue.
 	pub fn writable<Message>(&mut self, io: &IoContext<Message>) -> Result<(), Error> where Message: Send + Clone + Sync + 'static {
 		self.connection.writable(io)?;
@@ -509,8 +827,11 @@ mod tests {
 	use std::io::{Read, Write, Cursor, ErrorKind, Result, Error};
 	use std::sync::Arc;
 	use std::sync::atomic::AtomicBool;
+	use std::thread;
+	use std::time::Instant;
 
 	use mio::{Ready};
+	use mio::net::{TcpListener, TcpStream};
 	use ethcore_bytes::Bytes;
 	use io::*;
 	use super::super::stats::*;
@@ -622,11 +943,14 @@ mod tests {
 				token: 999998888usize,
 				socket: TestSocket::new(),
 				send_queue: VecDeque::new(),
+				queued_bytes: 0,
 				rec_buf: Bytes::new(),
 				rec_size: 0,
-				interest: Ready::hup() | Ready::readable(),
+				interest: hup_interest() | Ready::readable(),
 				stats: Arc::<NetworkStats>::new(NetworkStats::new()),
 				registered: AtomicBool::new(false),
+				pool: BufferPool::new(),
+				proxied_peer: None,
 			}
 		}
 	}
@@ -645,11 +969,14 @@ mod tests {
 				token: 999998888usize,
 				socket: TestBrokenSocket { error: "test broken socket".to_owned() },
 				send_queue: VecDeque::new(),
+				queued_bytes: 0,
 				rec_buf: Bytes::new(),
 				rec_size: 0,
-				interest: Ready::hup() | Ready::readable(),
+				interest: hup_interest() | Ready::readable(),
 				stats: Arc::<NetworkStats>::new(NetworkStats::new()),
 				registered: AtomicBool::new(false),
+				pool: BufferPool::new(),
+				proxied_peer: None,
 			}
 		}
 	}
@@ -658,6 +985,46 @@ mod tests {
 		IoContext::new(IoChannel::disconnected(), 0)
 	}
 
+	/// Connected pair of loopback sockets.
+	fn loopback_pair() -> (TcpStream, TcpStream) {
+		let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+		let client = TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+		loop {
+			match listener.accept() {
+				Ok((server, _)) => return (client, server),
+				Err(ref e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(1)),
+				Err(e) => panic!("accept failed: {}", e),
+			}
+		}
+	}
+
+	/// Encrypted connection with fixed keys, the same for both directions. Connections made
+	/// with this can decrypt each other's packets.
+	fn test_encrypted(token: StreamToken, socket: TcpStream) -> EncryptedConnection {
+		let key = [0x11u8; 32];
+		let mac_key = [0x22u8; 32];
+		let mut egress_mac = Keccak::new_keccak256();
+		egress_mac.update(b"test mac seed");
+		let ingress_mac = egress_mac.clone();
+		let mut connection = EncryptedConnection {
+			connection: Connection::new(token, socket, Arc::new(NetworkStats::new())),
+			encoder: CtrMode::new(AesSafe256Encryptor::new(&key), vec![0u8; 16]),
+			decoder: CtrMode::new(AesSafe256Encryptor::new(&key), vec![0u8; 16]),
+			mac_encoder: EcbEncryptor::new(AesSafe256Encryptor::new(&mac_key), NoPadding),
+			egress_mac: egress_mac,
+			ingress_mac: ingress_mac,
+			read_state: EncryptedConnectionState::Header,
+			protocol_id: 0,
+			payload_len: 0,
+			frame_context: None,
+			frame_size: MAX_PAYLOAD_SIZE,
+			context_id: 0,
+			reassembly: Reassembly::new(MAX_PAYLOAD_SIZE, RECIEVE_PAYLOAD_TIMEOUT * 1000_000),
+		};
+		connection.connection.expect(ENCRYPTED_HEADER_LEN);
+		connection
+	}
+
 	#[test]
 	fn connection_expect() {
 		let mut connection = TestConnection::new();
@@ -699,6 +1066,22 @@ mod tests {
 		assert_eq!(1024, connection.socket.write_buffer.len());
 	}
 
+	#[test]
+	fn connection_queue_depth() {
+		let mut connection = TestConnection::new();
+		connection.socket = TestSocket::new_buf(1024);
+		connection.send(&test_io(), vec![0; 1500]);
+		connection.send(&test_io(), vec![0; 500]);
+		assert_eq!(connection.queue_depth(), 2000);
+
+		assert!(WriteStatus::Ongoing == connection.writable(&test_io()).unwrap());
+		assert_eq!(connection.queue_depth(), 976);
+		assert!(WriteStatus::Complete == connection.writable(&test_io()).unwrap());
+		assert_eq!(connection.queue_depth(), 500);
+		assert!(WriteStatus::Complete == connection.writable(&test_io()).unwrap());
+		assert_eq!(connection.queue_depth(), 0);
+	}
+
 	#[test]
 	fn connection_write_to_broken() {
 		let mut connection = TestBrokenConnection::new();
@@ -745,6 +1128,92 @@ mod tests {
 		assert_eq!(0, connection.rec_buf.len());
 	}
 
+	#[test]
+	fn connection_read_reuses_buffers() {
+		let mut connection = TestConnection::new();
+		connection.socket.read_buffer = vec![7; 32 * 10];
+		for _ in 0..10 {
+			connection.expect(32);
+			let data = connection.readable().unwrap().unwrap();
+			assert_eq!(data, vec![7; 32]);
+			connection.recycle(data);
+		}
+		assert_eq!(connection.pool.allocated(), 1);
+		assert_eq!(connection.pool.reused(), 9);
+	}
+
+	#[test]
+	fn connection_write_reuses_buffers() {
+		let mut connection = TestConnection::new();
+		for i in 0..10 {
+			let mut data = connection.buffer_pool().take(100);
+			data.resize(100, i as u8);
+			connection.send_queue.push_back(Cursor::new(data));
+			assert!(WriteStatus::Complete == connection.writable(&test_io()).unwrap());
+		}
+		assert_eq!(connection.socket.write_buffer.len(), 1000);
+		assert_eq!(connection.socket.write_buffer[999], 9);
+		assert_eq!(connection.pool.allocated(), 1);
+		assert_eq!(connection.pool.reused(), 9);
+	}
+
+	#[test]
+	fn connection_send_slice() {
+		let mut connection = TestConnection::new();
+		connection.socket = TestSocket::new_buf(100);
+		// Written right away.
+		connection.send_slice(&test_io(), &[1u8; 50]);
+		assert_eq!(connection.socket.write_buffer.len(), 50);
+		assert_eq!(connection.queue_depth(), 0);
+		assert_eq!(connection.pool.allocated(), 0);
+		// The rest of a partial write is queued, and so is everything sent after it.
+		connection.send_slice(&test_io(), &[2u8; 150]);
+		connection.send_slice(&test_io(), &[3u8; 10]);
+		assert_eq!(connection.socket.write_buffer.len(), 150);
+		assert_eq!(connection.queue_depth(), 60);
+		assert_eq!(connection.pool.allocated(), 2);
+		while connection.queue_depth() != 0 {
+			connection.writable(&test_io()).unwrap();
+		}
+		assert_eq!(connection.socket.write_buffer.len(), 210);
+		assert_eq!(&connection.socket.write_buffer[150..200], &[2u8; 50][..]);
+		assert_eq!(&connection.socket.write_buffer[200..], &[3u8; 10][..]);
+	}
+
+	#[test]
+	fn small_and_large_frames_interoperate() {
+		let (client, server) = loopback_pair();
+		let mut sender = test_encrypted(1, client);
+		let mut receiver = test_encrypted(2, server);
+		sender.set_frame_limits(300, MAX_PAYLOAD_SIZE, Duration::from_secs(30));
+		let io = test_io();
+
+		// Sizes around the small packet limit and the padding boundaries. Chunked packets end with
+		// small frames, so both kinds of frames are interleaved on the wire.
+		let sizes = [0usize, 1, 15, 16, 17, 100, 126, 127, 128, 129, 1000, 5, 127, 300, 301, 2, 0];
+		let packets: Vec<Bytes> = sizes.iter().enumerate()
+			.map(|(i, &size)| (0..size).map(|b| (b * 7 + i) as u8).collect())
+			.collect();
+		for packet in &packets {
+			sender.send_packet(&io, packet).unwrap();
+		}
+
+		let started = Instant::now();
+		let mut received = Vec::new();
+		while received.len() < packets.len() {
+			sender.writable(&io).unwrap();
+			match receiver.readable(&io).unwrap() {
+				Some(packet) => received.push(packet.data),
+				None => {
+					assert!(started.elapsed() < Duration::from_secs(10), "received {} of {} packets", received.len(), packets.len());
+					thread::sleep(Duration::from_millis(1));
+				},
+			}
+		}
+		assert_eq!(received, packets);
+		assert!(!sender.connection.is_sending());
+	}
+
 	#[test]
 	fn connection_read_full() {
 		let mut connection = TestConnection::new();
@@ -756,4 +1225,71 @@ mod tests {
 		assert!(status.is_ok());
 		assert_eq!(0, connection.socket.cursor);
 	}
+
+	#[test]
+	fn chunked_frame_header() {
+		let mut header_data = RlpStream::new_list(3);
+		header_data.append(&0u16).append(&513u16).append(&(70000u32));
+		let header = frame_header(1024, &header_data.drain());
+		assert_eq!(&header[0..3], &[0, 4, 0]);
+		let rlp = UntrustedRlp::new(&header[3..]);
+		assert_eq!(rlp.item_count().unwrap(), 3);
+		assert_eq!(rlp.val_at::<u16>(1).unwrap(), 513);
+		assert_eq!(rlp.val_at::<u32>(2).unwrap(), 70000);
+	}
+
+	#[test]
+	fn chunked_packet_reassembly() {
+		let first: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+		let second: Vec<u8> = (0..5000).map(|i| (i * 7) as u8).collect();
+		let mut reassembly = Reassembly::new(64 * 1024, 1000);
+
+		// Frames of the two packets are interleaved.
+		let mut frames1 = first.chunks(1024);
+		let mut frames2 = second.chunks(1024);
+		assert!(reassembly.frame(0, 1, Some(first.len()), frames1.next().unwrap(), 0).unwrap().is_none());
+		assert!(reassembly.frame(0, 2, Some(second.len()), frames2.next().unwrap(), 0).unwrap().is_none());
+		let mut complete = Vec::new();
+		loop {
+			let (f1, f2) = (frames1.next(), frames2.next());
+			if f1.is_none() && f2.is_none() {
+				break;
+			}
+			if let Some(f) = f1 {
+				complete.extend(reassembly.frame(0, 1, None, f, 0).unwrap());
+			}
+			if let Some(f) = f2 {
+				complete.extend(reassembly.frame(0, 2, None, f, 0).unwrap());
+			}
+		}
+		assert_eq!(complete.len(), 2);
+		assert_eq!(complete[0].data, second);
+		assert_eq!(complete[1].data, first);
+		assert!(reassembly.pending.is_empty());
+	}
+
+	#[test]
+	fn chunked_packet_limits() {
+		let mut reassembly = Reassembly::new(8192, 1000);
+		// Too large, or too large together with the pending ones.
+		assert!(reassembly.frame(0, 1, Some(8193), &[0u8; 16], 0).is_err());
+		assert!(reassembly.frame(0, 1, Some(6000), &[0u8; 16], 0).unwrap().is_none());
+		assert!(reassembly.frame(0, 2, Some(3000), &[0u8; 16], 0).is_err());
+		// Restarted or unknown packets and frames past the announced size.
+		assert!(reassembly.frame(0, 1, Some(100), &[0u8; 16], 0).is_err());
+		assert!(reassembly.frame(0, 3, None, &[0u8; 16], 0).is_err());
+		assert!(reassembly.frame(0, 1, None, &vec![0u8; 6000], 0).is_err());
+	}
+
+	#[test]
+	fn chunked_packet_never_finishes() {
+		let mut reassembly = Reassembly::new(64 * 1024, 1000);
+		assert!(reassembly.frame(0, 1, Some(60000), &[0u8; 1024], 100).unwrap().is_none());
+		assert!(reassembly.frame(0, 1, None, &[0u8; 1024], 500).unwrap().is_none());
+		assert_eq!(reassembly.expire(1099), 0);
+		assert_eq!(reassembly.expire(1100), 1);
+		assert!(reassembly.pending.is_empty());
+		// Late frames of the dropped packet are refused.
+		assert!(reassembly.frame(0, 1, None, &[0u8; 1024], 1200).is_err());
+	}
 }
@@ -16,16 +16,502 @@
 
 //! Connection filter trait.
 
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::{Receiver, TryRecvError};
+use parking_lot::RwLock;
+use io::StreamToken;
+use network::{Error, ErrorKind, ProtocolId};
+pub use network::ConnectionDirection;
+use node_table::Node;
 use super::NodeId;
 
-/// Filtered connection direction.
-pub enum ConnectionDirection {
-	Inbound,
-	Outbound,
+/// Information about a connection being filtered and the sessions already established.
+#[derive(Debug, Clone)]
+pub struct ConnectionContext<'a> {
+	/// Our own node id.
+	pub own_id: &'a NodeId,
+	/// Remote node id.
+	pub connecting_id: &'a NodeId,
+	/// Connection direction.
+	pub direction: ConnectionDirection,
+	/// Remote address, if known.
+	pub remote_address: Option<SocketAddr>,
+	/// Whether the remote node is a reserved peer.
+	pub reserved: bool,
+	/// Number of established inbound sessions.
+	pub inbound_sessions: usize,
+	/// Number of established outbound sessions.
+	pub outbound_sessions: usize,
+	/// Number of established sessions with the same remote IP.
+	pub same_ip_sessions: usize,
+	/// Number of established sessions from the same subnet (/24 for IPv4, /64 for IPv6) in the same direction.
+	pub same_subnet_sessions: usize,
 }
 
-/// Connection filter. Each connection is checked against `connection_allowed`.
+impl<'a> ConnectionContext<'a> {
+	/// Create a new context. `peers` lists remote addresses and directions of the established sessions,
+	/// not including the connection being filtered.
+	pub fn new(own_id: &'a NodeId, connecting_id: &'a NodeId, direction: ConnectionDirection, remote_address: Option<SocketAddr>, reserved: bool, peers: &[(IpAddr, ConnectionDirection)]) -> ConnectionContext<'a> {
+		let ip = remote_address.map(|a| a.ip());
+		ConnectionContext {
+			own_id: own_id,
+			connecting_id: connecting_id,
+			direction: direction,
+			remote_address: remote_address,
+			reserved: reserved,
+			inbound_sessions: peers.iter().filter(|&&(_, d)| d == ConnectionDirection::Inbound).count(),
+			outbound_sessions: peers.iter().filter(|&&(_, d)| d == ConnectionDirection::Outbound).count(),
+			same_ip_sessions: ip.map_or(0, |ip| peers.iter().filter(|&&(a, _)| a == ip).count()),
+			same_subnet_sessions: ip.map_or(0, |ip| peers.iter().filter(|&&(a, d)| d == direction && same_subnet(&a, &ip)).count()),
+		}
+	}
+}
+
+/// Connection refused by the connection filter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterDenial {
+	/// Remote node id.
+	pub node_id: NodeId,
+	/// Remote address, if known.
+	pub address: Option<SocketAddr>,
+	/// Connection direction.
+	pub direction: ConnectionDirection,
+	/// Unix time in seconds of the decision.
+	pub timestamp: u64,
+}
+
+/// Connection filter decisions since the host was started.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FilterStats {
+	/// Inbound connections allowed.
+	pub allowed_inbound: usize,
+	/// Outbound connections and dial candidates allowed.
+	pub allowed_outbound: usize,
+	/// Inbound connections denied.
+	pub denied_inbound: usize,
+	/// Outbound connections and dial candidates denied.
+	pub denied_outbound: usize,
+	/// Established sessions disconnected when checked again against the filter or the node allowlist.
+	pub dropped_sessions: usize,
+	/// Pending decisions that did not arrive in time. These are counted as denied as well.
+	pub decision_timeouts: usize,
+	/// Most recent denials, oldest first.
+	pub recent_denials: Vec<FilterDenial>,
+}
+
+/// Counts connection filter decisions and keeps the most recent denials.
+pub struct FilterAudit {
+	stats: FilterStats,
+	denials: VecDeque<FilterDenial>,
+	capacity: usize,
+}
+
+impl FilterAudit {
+	/// Create an audit keeping up to `capacity` denials.
+	pub fn new(capacity: usize) -> FilterAudit {
+		FilterAudit {
+			stats: FilterStats::default(),
+			denials: VecDeque::with_capacity(capacity),
+			capacity: capacity,
+		}
+	}
+
+	/// Count the decision for the connection at unix time `now`.
+	pub fn note(&mut self, context: &ConnectionContext, allowed: bool, now: u64) {
+		match (context.direction, allowed) {
+			(ConnectionDirection::Inbound, true) => self.stats.allowed_inbound += 1,
+			(ConnectionDirection::Outbound, true) => self.stats.allowed_outbound += 1,
+			(ConnectionDirection::Inbound, false) => self.stats.denied_inbound += 1,
+			(ConnectionDirection::Outbound, false) => self.stats.denied_outbound += 1,
+		}
+		if allowed || self.capacity == 0 {
+			return;
+		}
+		if self.denials.len() == self.capacity {
+			self.denials.pop_front();
+		}
+		self.denials.push_back(FilterDenial {
+			node_id: context.connecting_id.clone(),
+			address: context.remote_address,
+			direction: context.direction,
+			timestamp: now,
+		});
+	}
+
+	/// Count an established session disconnected by the filter.
+	pub fn note_dropped(&mut self) {
+		self.stats.dropped_sessions += 1;
+	}
+
+	/// Count a pending decision that timed out.
+	pub fn note_timeout(&mut self) {
+		self.stats.decision_timeouts += 1;
+	}
+
+	/// Counters with the most recent denials.
+	pub fn stats(&self) -> FilterStats {
+		let mut stats = self.stats.clone();
+		stats.recent_denials = self.denials.iter().cloned().collect();
+		stats
+	}
+}
+
+/// Decision of the connection filter on an established connection.
+#[derive(Debug)]
+pub enum FilterDecision {
+	/// Accept the connection.
+	Allow,
+	/// Refuse the connection.
+	Deny,
+	/// The decision is sent on the channel later. The connection is held until then; no decision
+	/// within `filter_decision_timeout` or a closed channel refuses it.
+	Pending(Receiver<bool>),
+}
+
+/// Connections held until the connection filter decides.
+#[derive(Default)]
+pub struct PendingDecisions {
+	/// Decision channel and deadline in nanoseconds of the waiting connections.
+	waiting: HashMap<StreamToken, (Receiver<bool>, u64)>,
+	/// Decisions that arrived and were not taken yet.
+	decided: HashMap<StreamToken, bool>,
+}
+
+impl PendingDecisions {
+	/// Wait for the decision on `receiver` until `deadline_ns`. Returns false if `max` connections
+	/// are waiting already.
+	pub fn park(&mut self, token: StreamToken, receiver: Receiver<bool>, deadline_ns: u64, max: usize) -> bool {
+		if self.waiting.len() >= max {
+			return false;
+		}
+		self.waiting.insert(token, (receiver, deadline_ns));
+		true
+	}
+
+	/// Collect the decisions that arrived or timed out by `now_ns`. Returns the connections that can
+	/// continue and whether the decision timed out.
+	pub fn poll(&mut self, now_ns: u64) -> Vec<(StreamToken, bool)> {
+		let mut ready = Vec::new();
+		for (token, &(ref receiver, deadline)) in &self.waiting {
+			match receiver.try_recv() {
+				Ok(allowed) => ready.push((*token, allowed, false)),
+				Err(TryRecvError::Disconnected) => ready.push((*token, false, false)),
+				Err(TryRecvError::Empty) if now_ns >= deadline => ready.push((*token, false, true)),
+				Err(TryRecvError::Empty) => {},
+			}
+		}
+		ready.into_iter().map(|(token, allowed, timed_out)| {
+			self.waiting.remove(&token);
+			self.decided.insert(token, allowed);
+			(token, timed_out)
+		}).collect()
+	}
+
+	/// Take the decision for the connection, if it has arrived.
+	pub fn take(&mut self, token: StreamToken) -> Option<bool> {
+		self.decided.remove(&token)
+	}
+
+	/// Forget the connection.
+	pub fn remove(&mut self, token: StreamToken) {
+		self.waiting.remove(&token);
+		self.decided.remove(&token);
+	}
+
+	/// Forget all connections.
+	pub fn clear(&mut self) {
+		self.waiting.clear();
+		self.decided.clear();
+	}
+
+	/// Number of connections waiting for a decision.
+	pub fn waiting(&self) -> usize {
+		self.waiting.len()
+	}
+}
+
+/// Check if both addresses belong to the same /24 (IPv4) or /64 (IPv6) subnet.
+pub fn same_subnet(a: &IpAddr, b: &IpAddr) -> bool {
+	match (*a, *b) {
+		(IpAddr::V4(ref a), IpAddr::V4(ref b)) => a.octets()[..3] == b.octets()[..3],
+		(IpAddr::V6(ref a), IpAddr::V6(ref b)) => a.segments()[..4] == b.segments()[..4],
+		_ => false,
+	}
+}
+
+/// Connection filter. Each connection is checked against `connection_allowed_with_context`,
+/// which defaults to `connection_allowed`. Implement either of them.
 pub trait ConnectionFilter : Send + Sync {
 	/// Filter a connection. Returns `true` if connection should be allowed. `false` if rejected.
-	fn connection_allowed(&self, own_id: &NodeId, connecting_id: &NodeId, direction: ConnectionDirection) -> bool;
+	fn connection_allowed(&self, _own_id: &NodeId, _connecting_id: &NodeId, _direction: ConnectionDirection) -> bool {
+		true
+	}
+
+	/// Filter a connection given the current session counts. Returns `true` if connection should be allowed.
+	fn connection_allowed_with_context(&self, context: &ConnectionContext) -> bool {
+		self.connection_allowed(context.own_id, context.connecting_id, context.direction)
+	}
+
+	/// Decide on a connection once the peer's Hello has been received. Filters consulting an external
+	/// source return `FilterDecision::Pending` instead of blocking. Dial candidates and established
+	/// sessions can't wait and are still checked with `connection_allowed_with_context`.
+	fn connection_decision(&self, context: &ConnectionContext) -> FilterDecision {
+		if self.connection_allowed_with_context(context) { FilterDecision::Allow } else { FilterDecision::Deny }
+	}
+
+	/// Filter a protocol negotiated with an accepted peer. Refused protocols are left out of the session;
+	/// the session is disconnected if none remain.
+	fn protocol_allowed(&self, _node_id: &NodeId, _protocol: ProtocolId, _direction: ConnectionDirection) -> bool {
+		true
+	}
+}
+
+/// Limits the number of sessions per IP address and per subnet. Reserved peers are not limited.
+#[derive(Debug, Clone, Default)]
+pub struct SubnetLimitFilter {
+	/// Maximum number of sessions with a single IP address.
+	pub max_per_ip: Option<usize>,
+	/// Maximum number of inbound sessions from a single subnet.
+	pub max_inbound_per_subnet: Option<usize>,
+	/// Maximum number of outbound sessions to a single subnet.
+	pub max_outbound_per_subnet: Option<usize>,
+}
+
+impl ConnectionFilter for SubnetLimitFilter {
+	fn connection_allowed_with_context(&self, context: &ConnectionContext) -> bool {
+		if context.reserved {
+			return true;
+		}
+		let subnet_limit = match context.direction {
+			ConnectionDirection::Inbound => self.max_inbound_per_subnet,
+			ConnectionDirection::Outbound => self.max_outbound_per_subnet,
+		};
+		self.max_per_ip.map_or(true, |max| context.same_ip_sessions < max) &&
+			subnet_limit.map_or(true, |max| context.same_subnet_sessions < max)
+	}
+}
+
+/// Only allows peers whose node id is on the list, in both directions. Reserved peers are not exempt.
+/// The list can be replaced or reloaded from its file at runtime.
+#[derive(Debug)]
+pub struct NodeIdAllowlistFilter {
+	ids: RwLock<HashSet<NodeId>>,
+	/// Ids given on creation, kept when the file is reloaded.
+	configured: HashSet<NodeId>,
+	path: Option<PathBuf>,
+}
+
+impl NodeIdAllowlistFilter {
+	/// Create a filter allowing `ids` and the ids listed in the file at `path`, if given.
+	/// See `reload` for the file format.
+	pub fn new<I: IntoIterator<Item = NodeId>>(ids: I, path: Option<PathBuf>) -> Result<NodeIdAllowlistFilter, Error> {
+		let filter = NodeIdAllowlistFilter {
+			ids: RwLock::new(HashSet::new()),
+			configured: ids.into_iter().collect(),
+			path: path,
+		};
+		filter.reload()?;
+		Ok(filter)
+	}
+
+	/// Check if the node is on the list.
+	pub fn contains(&self, id: &NodeId) -> bool {
+		self.ids.read().contains(id)
+	}
+
+	/// Currently allowed node ids.
+	pub fn ids(&self) -> Vec<NodeId> {
+		self.ids.read().iter().cloned().collect()
+	}
+
+	/// Replace the list.
+	pub fn set<I: IntoIterator<Item = NodeId>>(&self, ids: I) {
+		*self.ids.write() = ids.into_iter().collect();
+	}
+
+	/// Reset the list to the ids given on creation and the ones in the file. The file has one hex
+	/// node id or enode URL per line, empty lines and lines starting with `#` are ignored.
+	/// The list is left unchanged if the file can't be read or parsed.
+	pub fn reload(&self) -> Result<(), Error> {
+		let mut ids = self.configured.clone();
+		if let Some(ref path) = self.path {
+			ids.extend(read_node_ids(path)?);
+		}
+		*self.ids.write() = ids;
+		Ok(())
+	}
+}
+
+impl ConnectionFilter for NodeIdAllowlistFilter {
+	fn connection_allowed(&self, _own_id: &NodeId, connecting_id: &NodeId, _direction: ConnectionDirection) -> bool {
+		self.contains(connecting_id)
+	}
+}
+
+/// Parse a node id given in hex or as an enode URL.
+pub fn parse_node_id(s: &str) -> Result<NodeId, Error> {
+	if s.starts_with("enode://") {
+		Node::from_str(s).map(|n| n.id)
+	} else {
+		NodeId::from_str(s.trim_left_matches("0x")).map_err(|_| ErrorKind::InvalidNodeId.into())
+	}
+}
+
+fn read_node_ids(path: &Path) -> Result<Vec<NodeId>, Error> {
+	let mut text = String::new();
+	fs::File::open(path)?.read_to_string(&mut text)?;
+	text.lines()
+		.map(str::trim)
+		.filter(|l| !l.is_empty() && !l.starts_with('#'))
+		.map(parse_node_id)
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::{IpAddr, SocketAddr};
+	use std::str::FromStr;
+	use std::sync::mpsc;
+	use super::*;
+
+	fn allowed(filter: &SubnetLimitFilter, address: &str, direction: ConnectionDirection, reserved: bool, peers: &[(IpAddr, ConnectionDirection)]) -> bool {
+		let own_id = NodeId::from(1);
+		let id = NodeId::from(2);
+		let address = SocketAddr::from_str(address).unwrap();
+		filter.connection_allowed_with_context(&ConnectionContext::new(&own_id, &id, direction, Some(address), reserved, peers))
+	}
+
+	#[test]
+	fn subnet_limit_filter_caps_inbound() {
+		let filter = SubnetLimitFilter { max_per_ip: Some(2), max_inbound_per_subnet: Some(3), max_outbound_per_subnet: None };
+		let mut peers = Vec::new();
+		let candidates = ["10.0.0.1:30303", "10.0.0.1:30304", "10.0.0.2:30303"];
+		for address in &candidates {
+			assert!(allowed(&filter, address, ConnectionDirection::Inbound, false, &peers));
+			peers.push((SocketAddr::from_str(address).unwrap().ip(), ConnectionDirection::Inbound));
+		}
+		// subnet is full
+		assert!(!allowed(&filter, "10.0.0.3:30303", ConnectionDirection::Inbound, false, &peers));
+		// a third connection from the same IP is refused
+		assert!(!allowed(&filter, "10.0.0.1:30305", ConnectionDirection::Inbound, false, &peers));
+		// other subnets and outbound connections are not affected
+		assert!(allowed(&filter, "10.0.1.1:30303", ConnectionDirection::Inbound, false, &peers));
+		assert!(allowed(&filter, "10.0.0.3:30303", ConnectionDirection::Outbound, false, &peers));
+		// reserved peers are exempt
+		assert!(allowed(&filter, "10.0.0.3:30303", ConnectionDirection::Inbound, true, &peers));
+	}
+
+	#[test]
+	fn subnet_limit_filter_ipv6() {
+		let filter = SubnetLimitFilter { max_per_ip: None, max_inbound_per_subnet: None, max_outbound_per_subnet: Some(1) };
+		let peers = vec![(IpAddr::from_str("2001:db8::1").unwrap(), ConnectionDirection::Outbound)];
+		assert!(!allowed(&filter, "[2001:db8::2]:30303", ConnectionDirection::Outbound, false, &peers));
+		assert!(allowed(&filter, "[2001:db8:0:1::2]:30303", ConnectionDirection::Outbound, false, &peers));
+	}
+
+	#[test]
+	fn filter_audit_keeps_recent_denials() {
+		let own_id = NodeId::from(1);
+		let ids: Vec<NodeId> = (2..6).map(NodeId::from).collect();
+		let address = SocketAddr::from_str("10.0.0.1:30303").unwrap();
+		let mut audit = FilterAudit::new(2);
+		for (i, id) in ids.iter().enumerate() {
+			let direction = if i % 2 == 0 { ConnectionDirection::Inbound } else { ConnectionDirection::Outbound };
+			audit.note(&ConnectionContext::new(&own_id, id, direction, Some(address), false, &[]), false, 1000 + i as u64);
+		}
+		audit.note(&ConnectionContext::new(&own_id, &ids[0], ConnectionDirection::Outbound, None, false, &[]), true, 1010);
+		audit.note_dropped();
+
+		let stats = audit.stats();
+		assert_eq!((stats.allowed_inbound, stats.allowed_outbound), (0, 1));
+		assert_eq!((stats.denied_inbound, stats.denied_outbound), (2, 2));
+		assert_eq!(stats.dropped_sessions, 1);
+		assert_eq!(stats.recent_denials, vec![
+			FilterDenial { node_id: ids[2], address: Some(address), direction: ConnectionDirection::Inbound, timestamp: 1002 },
+			FilterDenial { node_id: ids[3], address: Some(address), direction: ConnectionDirection::Outbound, timestamp: 1003 },
+		]);
+	}
+
+	#[test]
+	fn pending_decisions_resolve_or_time_out() {
+		let mut pending = PendingDecisions::default();
+		let (allow, allow_receiver) = mpsc::channel();
+		let (_deny, deny_receiver) = mpsc::channel();
+		let (_, closed_receiver) = mpsc::channel();
+		assert!(pending.park(1, allow_receiver, 1000, 3));
+		assert!(pending.park(2, deny_receiver, 2000, 3));
+		assert!(pending.park(3, closed_receiver, 2000, 3));
+		let (_extra, extra_receiver) = mpsc::channel();
+		assert!(!pending.park(4, extra_receiver, 2000, 3));
+		assert_eq!(pending.take(1), None);
+
+		allow.send(true).unwrap();
+		let mut ready = pending.poll(500);
+		ready.sort();
+		assert_eq!(ready, vec![(1, false), (3, false)]);
+		assert_eq!(pending.take(1), Some(true));
+		assert_eq!(pending.take(3), Some(false));
+		assert_eq!(pending.waiting(), 1);
+
+		assert!(pending.poll(1999).is_empty());
+		assert_eq!(pending.poll(2000), vec![(2, true)]);
+		assert_eq!(pending.take(2), Some(false));
+		assert_eq!(pending.waiting(), 0);
+	}
+
+	#[test]
+	fn context_counts() {
+		let own_id = NodeId::from(1);
+		let id = NodeId::from(2);
+		let peers = vec![
+			(IpAddr::from_str("10.0.0.1").unwrap(), ConnectionDirection::Inbound),
+			(IpAddr::from_str("10.0.0.2").unwrap(), ConnectionDirection::Outbound),
+			(IpAddr::from_str("192.168.0.1").unwrap(), ConnectionDirection::Inbound),
+		];
+		let context = ConnectionContext::new(&own_id, &id, ConnectionDirection::Inbound, Some(SocketAddr::from_str("10.0.0.1:1").unwrap()), false, &peers);
+		assert_eq!(context.inbound_sessions, 2);
+		assert_eq!(context.outbound_sessions, 1);
+		assert_eq!(context.same_ip_sessions, 1);
+		assert_eq!(context.same_subnet_sessions, 1);
+	}
+
+	#[test]
+	fn node_id_allowlist_file() {
+		use std::io::Write;
+		use tempdir::TempDir;
+
+		let dir = TempDir::new("allowlist").unwrap();
+		let path = dir.path().join("nodes");
+		let own_id = NodeId::from(1);
+		let listed = NodeId::from_str("a979fb575495b8d6db44f750317d0f4622bf4c2aa3365d6af7c284339968eef29b69ad0dce72a4d8db5ebb4968de0e3bec910127f134779fbcb0cb6d3331163c").unwrap();
+		let configured = NodeId::from(3);
+		fs::File::create(&path).unwrap().write_all(format!(
+			"# consortium members\n\n0x{}\nenode://{}@127.0.0.1:30303\n", NodeId::from(4).hex(), listed.hex()).as_bytes()).unwrap();
+
+		let filter = NodeIdAllowlistFilter::new(vec![configured], Some(path.clone())).unwrap();
+		assert_eq!(filter.ids().len(), 3);
+		assert!(filter.connection_allowed(&own_id, &listed, ConnectionDirection::Inbound));
+		assert!(filter.connection_allowed(&own_id, &configured, ConnectionDirection::Outbound));
+		assert!(!filter.connection_allowed(&own_id, &NodeId::from(5), ConnectionDirection::Outbound));
+
+		filter.set(vec![NodeId::from(5)]);
+		assert!(filter.contains(&NodeId::from(5)));
+		assert!(!filter.contains(&listed));
+
+		// A broken file leaves the list unchanged.
+		fs::File::create(&path).unwrap().write_all(b"not a node id\n").unwrap();
+		assert!(filter.reload().is_err());
+		assert_eq!(filter.ids(), vec![NodeId::from(5)]);
+
+		fs::File::create(&path).unwrap().write_all(format!("{}\n", listed.hex()).as_bytes()).unwrap();
+		filter.reload().unwrap();
+		assert!(filter.contains(&listed));
+		assert!(filter.contains(&configured));
+		assert!(!filter.contains(&NodeId::from(5)));
+	}
 }
@@ -0,0 +1,141 @@
+// Copyright 2015-2017 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Ad-hoc dials requested through `NetworkService::connect_peer`.
+
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Sender, Receiver};
+use network::{DisconnectReason, PeerId, SessionCapabilityInfo};
+use node_table::{Node, NodeId};
+
+/// Session established by an ad-hoc dial.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DialedPeer {
+	/// Peer node id.
+	pub node_id: NodeId,
+	/// Session token.
+	pub peer: PeerId,
+	/// Capabilities negotiated with the peer.
+	pub caps: Vec<SessionCapabilityInfo>,
+}
+
+/// Reason an ad-hoc dial failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DialError {
+	/// The handshake did not complete in time.
+	Timeout,
+	/// The connection could not be established or was closed during the handshake,
+	/// e.g. because the remote node has a different id.
+	HandshakeFailed,
+	/// The session was refused by us or by the peer.
+	Rejected(DisconnectReason),
+	/// There already is a session or a handshake with the node.
+	AlreadyConnected,
+}
+
+impl DialError {
+	/// Failure for a session closed with the given reason before the dial completed.
+	pub fn from_reason(reason: Option<DisconnectReason>) -> DialError {
+		match reason {
+			None => DialError::HandshakeFailed,
+			Some(DisconnectReason::DuplicatePeer) => DialError::AlreadyConnected,
+			Some(reason) => DialError::Rejected(reason),
+		}
+	}
+}
+
+/// Outcome of an ad-hoc dial.
+pub type DialResult = Result<DialedPeer, DialError>;
+
+struct PendingDial {
+	/// Node added to the node table once the session is established.
+	node: Option<Node>,
+	/// Bypass the peer limits.
+	force: bool,
+	result: Sender<DialResult>,
+}
+
+/// Ad-hoc dials in progress, by node id.
+#[derive(Default)]
+pub struct PendingDials {
+	dials: HashMap<NodeId, PendingDial>,
+}
+
+impl PendingDials {
+	/// Start tracking a dial to `node`. Returns the receiver for its result.
+	/// A previous dial to the same node is failed with `AlreadyConnected`.
+	pub fn add(&mut self, node: Node, force: bool) -> Receiver<DialResult> {
+		let (sender, receiver) = mpsc::channel();
+		let id = node.id.clone();
+		let previous = self.dials.insert(id, PendingDial { node: Some(node), force: force, result: sender });
+		if let Some(previous) = previous {
+			previous.result.send(Err(DialError::AlreadyConnected)).ok();
+		}
+		receiver
+	}
+
+	/// Check if the dial to the node bypasses the peer limits.
+	pub fn is_forced(&self, id: &NodeId) -> bool {
+		self.dials.get(id).map_or(false, |d| d.force)
+	}
+
+	/// Take the node of a pending dial to add it to the node table.
+	pub fn take_node(&mut self, id: &NodeId) -> Option<Node> {
+		self.dials.get_mut(id).and_then(|d| d.node.take())
+	}
+
+	/// Complete the dial to the node. Does nothing if there is none.
+	pub fn resolve(&mut self, id: &NodeId, result: DialResult) {
+		if let Some(dial) = self.dials.remove(id) {
+			// The caller may have dropped the receiver.
+			dial.result.send(result).ok();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::str::FromStr;
+
+	const URL: &'static str = "enode://a979fb575495b8d6db44f750317d0f4622bf4c2aa3365d6af7c284339968eef29b69ad0dce72a4d8db5ebb4968de0e3bec910127f134779fbcb0cb6d3331163c@22.99.55.44:7770";
+
+	#[test]
+	fn dial_resolved_once() {
+		let mut dials = PendingDials::default();
+		let node = Node::from_str(URL).unwrap();
+		let id = node.id.clone();
+		let receiver = dials.add(node, true);
+		assert!(dials.is_forced(&id));
+		assert!(dials.take_node(&id).is_some());
+		assert!(dials.take_node(&id).is_none());
+		dials.resolve(&id, Err(DialError::from_reason(Some(DisconnectReason::TooManyPeers))));
+		dials.resolve(&id, Err(DialError::Timeout));
+		assert_eq!(receiver.recv(), Ok(Err(DialError::Rejected(DisconnectReason::TooManyPeers))));
+		assert!(receiver.recv().is_err());
+		assert!(!dials.is_forced(&id));
+	}
+
+	#[test]
+	fn repeated_dial_replaces_previous() {
+		let mut dials = PendingDials::default();
+		let first = dials.add(Node::from_str(URL).unwrap(), false);
+		let second = dials.add(Node::from_str(URL).unwrap(), false);
+		assert_eq!(first.recv(), Ok(Err(DialError::AlreadyConnected)));
+		dials.resolve(&Node::from_str(URL).unwrap().id, Err(DialError::HandshakeFailed));
+		assert_eq!(second.recv(), Ok(Err(DialError::HandshakeFailed)));
+	}
+}
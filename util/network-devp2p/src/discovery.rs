@@ -15,22 +15,32 @@
 // along with Parity.  If not, see <http://www.gnu.org/licenses/>.
 
 use ethcore_bytes::Bytes;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::collections::{HashSet, HashMap, BTreeMap, VecDeque};
+use std::cmp::{min, max};
+use std::io;
 use std::mem;
 use std::default::Default;
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
 use mio::*;
-use mio::deprecated::{Handler, EventLoop};
-use mio::udp::*;
+use mio::net::UdpSocket;
+use connection::map_non_block;
 use hash::keccak;
 use time;
 use ethereum_types::{H256, H520};
 use rlp::*;
 use node_table::*;
-use network::{Error, ErrorKind};
+use network::{Error, ErrorKind, FindNodeRateLimit};
 use io::{StreamToken, IoContext};
 use ethkey::{Secret, KeyPair, sign, recover};
 use network::IpFilter;
+use ip_utils::{ip_class, is_allowed_by_lists, IpClass};
+use ipnetwork::IpNetwork;
+use lru_cache::LruCache;
+use stats::NetworkStats;
+use connection_filter::NodeIdAllowlistFilter;
 
 use PROTOCOL_VERSION;
 
@@ -41,14 +51,31 @@ const DISCOVERY_MAX_STEPS: u16 = 8;							// Max iterations of discovery. (disco
 const BUCKET_SIZE: usize = 16;		// Denoted by k in [Kademlia]. Number of nodes stored in each bucket.
 const ALPHA: usize = 3;				// Denoted by \alpha in [Kademlia]. Number of concurrent FindNode requests.
 const MAX_DATAGRAM_SIZE: usize = 1280;
+const MAX_NEIGHBOURS_PER_PACKET: usize = (MAX_DATAGRAM_SIZE - 109) / 90; // Entries of up to 90 bytes after 109 bytes of header and signature
 
 const PACKET_PING: u8 = 1;
 const PACKET_PONG: u8 = 2;
 const PACKET_FIND_NODE: u8 = 3;
 const PACKET_NEIGHBOURS: u8 = 4;
 
-const PING_TIMEOUT_MS: u64 = 300;
+const DEFAULT_PING_TIMEOUT_MS: u64 = 1000;
+const DEFAULT_PING_RETRIES: u32 = 2;
 const MAX_NODES_PING: usize = 32; // Max nodes to add/ping at once
+const MAX_OBSERVED_ADDRESSES: usize = 64; // Max nodes whose view of our address is kept
+const MAX_PENDING_BONDS: usize = 256; // Max nodes from Neighbours packets waiting for a pong
+const MAX_FIND_NODE_SOURCES: usize = 1024; // Max nodes whose served FindNode requests are tracked
+const FIND_NODE_RESPONSE_NS: u64 = 2 * 1000_000_000; // Time to wait for all Neighbours packets answering a FindNode
+const MAX_VIOLATIONS: u32 = 8; // Malformed packets after which a node is ignored
+const MAX_VIOLATION_SOURCES: usize = 1024; // Max nodes whose malformed packets are tracked
+const VIOLATION_DECAY_NS: u64 = 10 * MINUTE_NS; // Time for a node to be forgiven `MAX_VIOLATIONS` malformed packets
+const DEFAULT_PACKET_CACHE_SIZE: usize = 1024; // Packets whose sender and arrival are remembered
+const DEFAULT_SEND_QUEUE_LIMIT: usize = 1024; // Max packets waiting to be sent
+const LOOKUP_STEP_NS: u64 = 300 * 1000_000; // Interval between the steps of a lookup at the full rate
+const DEFAULT_MIN_LOOKUP_INTERVAL_NS: u64 = 60 * 1000_000_000;
+const DEFAULT_MAX_LOOKUP_INTERVAL_NS: u64 = 30 * MINUTE_NS;
+const MINUTE_NS: u64 = 60 * 1000_000_000;
+const MAX_TARGETED_LOOKUPS: usize = 16; // Max lookups for given targets running at once
+const NO_FIND_NODE_LIMIT: FindNodeRateLimit = FindNodeRateLimit { requests_per_minute: 0, burst: 0, targets_per_minute: 0 };
 
 #[derive(Clone, Debug)]
 pub struct NodeEntry {
@@ -56,14 +83,34 @@ pub struct NodeEntry {
 	pub endpoint: NodeEndpoint,
 }
 
+/// Node of the discovery table near a lookup target.
+#[derive(Debug, Clone)]
+pub struct NearNode {
+	/// Node id.
+	pub id: NodeId,
+	/// Node endpoint.
+	pub endpoint: NodeEndpoint,
+	/// Log distance of the node id hash to the target.
+	pub distance: u32,
+	/// Unix time in seconds the node was last added or reported by discovery. `None` if it is not in the node table.
+	pub last_seen: Option<u64>,
+	/// Unix time in seconds of the last successful session with the node.
+	pub last_contact: Option<u64>,
+}
+
 pub struct BucketEntry {
 	pub address: NodeEntry,
 	pub id_hash: H256,
+	/// Time the last unanswered ping was sent.
 	pub timeout: Option<u64>,
+	/// Number of pings sent without a reply.
+	pub ping_attempts: u32,
 }
 
 pub struct NodeBucket {
 	nodes: VecDeque<BucketEntry>, //sorted by last active
+	/// Node waiting to replace the least active entry if it fails to respond.
+	pending: Option<NodeEntry>,
 }
 
 impl Default for NodeBucket {
@@ -75,14 +122,295 @@ impl Default for NodeBucket {
 impl NodeBucket {
 	fn new() -> Self {
 		NodeBucket {
-			nodes: VecDeque::new()
+			nodes: VecDeque::new(),
+			pending: None,
+		}
+	}
+}
+
+/// Number of discovery packets of each type.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct DiscoveryPacketCounts {
+	pub ping: u64,
+	pub pong: u64,
+	pub find_node: u64,
+	pub neighbours: u64,
+}
+
+impl DiscoveryPacketCounts {
+	fn inc(&mut self, packet_id: u8) {
+		match packet_id {
+			PACKET_PING => self.ping += 1,
+			PACKET_PONG => self.pong += 1,
+			PACKET_FIND_NODE => self.find_node += 1,
+			PACKET_NEIGHBOURS => self.neighbours += 1,
+			_ => {},
+		}
+	}
+}
+
+/// Number of discovery packets rejected, by reason.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct DiscoveryRejections {
+	/// Packets past their expiry time and the clock skew allowance.
+	pub expired: u64,
+	/// Pongs that echo none of the pings sent to the node.
+	pub unmatched_pong: u64,
+	/// Pongs that arrived after the deadline of the echoed ping.
+	pub late_pong: u64,
+	/// Copies of a packet received from the same address within the duplicate window.
+	pub duplicate: u64,
+}
+
+/// Snapshot of discovery counters and routing table occupancy.
+/// Counters only grow until reset with `Discovery::reset_stats`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct DiscoveryStats {
+	/// Packets sent, by type.
+	pub sent: DiscoveryPacketCounts,
+	/// Valid packets received, by type.
+	pub received: DiscoveryPacketCounts,
+	/// Pongs received from nodes in the table.
+	pub bonds: u64,
+	/// Completed lookups.
+	pub lookups: u64,
+	/// Completed lookups that got at least one neighbour.
+	pub successful_lookups: u64,
+	/// Total duration of completed lookups in milliseconds.
+	pub lookup_time_ms: u64,
+	/// Unix time in seconds of the last lookup that got at least one neighbour.
+	pub last_successful_lookup: Option<u64>,
+	/// Number of nodes in each bucket, indexed by log distance minus one.
+	pub buckets: Vec<usize>,
+	/// Total number of nodes in the table.
+	pub nodes: usize,
+	/// Number of nodes from Neighbours packets waiting to answer a ping.
+	pub pending_bonds: usize,
+	/// FindNode requests dropped for exceeding the per-node limits.
+	pub dropped_find_node: u64,
+	/// Outgoing packets dropped because the send queue was full.
+	pub dropped_outgoing: u64,
+	/// Queued packets dropped instead of sent because their expiry time had passed.
+	pub expired_outgoing: u64,
+	/// Packets rejected as malformed, such as oversized ones or Neighbours with more than k entries.
+	pub malformed_packets: u64,
+	/// Packets rejected as expired or unsolicited.
+	pub rejected: DiscoveryRejections,
+	/// Packets whose sender was taken from the signature cache instead of recovered.
+	pub signature_cache_hits: u64,
+	/// Lookup rate in percent of the full rate. Always 100 unless discovery is adaptive.
+	pub activity: u8,
+	/// Current interval between lookups in milliseconds.
+	pub lookup_interval_ms: u64,
+}
+
+/// Node learned from a Neighbours packet that has not proven its endpoint yet.
+struct PendingBond {
+	entry: NodeEntry,
+	/// Hashes of the pings sent to the node. The pong must echo one of them.
+	ping_hashes: Vec<H256>,
+	/// Time the last ping was sent.
+	sent_at: u64,
+}
+
+/// Pace of lookups. Adaptive schedules slow down as the number of connected peers grows.
+struct LookupSchedule {
+	adaptive: bool,
+	/// Interval between lookups at the full rate.
+	min_interval_ns: u64,
+	/// Interval between lookups at `max_peers`.
+	max_interval_ns: u64,
+	/// Percent of the full rate.
+	activity: u8,
+	last_lookup: Option<u64>,
+	last_step: u64,
+}
+
+impl LookupSchedule {
+	fn new() -> LookupSchedule {
+		LookupSchedule {
+			adaptive: false,
+			min_interval_ns: DEFAULT_MIN_LOOKUP_INTERVAL_NS,
+			max_interval_ns: DEFAULT_MAX_LOOKUP_INTERVAL_NS,
+			activity: 100,
+			last_lookup: None,
+			last_step: 0,
+		}
+	}
+
+	/// Full rate below `min_peers`, slowing down linearly to the slowest rate at `max_peers`.
+	fn set_peer_count(&mut self, peers: usize, min_peers: usize, max_peers: usize) {
+		self.activity = if peers < min_peers {
+			100
+		} else if peers >= max_peers {
+			0
+		} else {
+			((max_peers - peers) * 100 / (max_peers - min_peers)) as u8
+		};
+	}
+
+	fn interval_ns(&self) -> u64 {
+		if !self.adaptive {
+			return self.min_interval_ns;
+		}
+		self.max_interval_ns - (self.max_interval_ns - self.min_interval_ns) * self.activity as u64 / 100
+	}
+
+	/// Steps of a lookup slow down in proportion to the lookup interval.
+	fn step_ns(&self) -> u64 {
+		if self.min_interval_ns == 0 {
+			return LOOKUP_STEP_NS;
+		}
+		LOOKUP_STEP_NS.saturating_mul(self.interval_ns() / self.min_interval_ns)
+	}
+
+	/// Check if a new lookup is due and remember its start.
+	fn lookup_due(&mut self, now: u64) -> bool {
+		match self.last_lookup {
+			Some(last) if now.saturating_sub(last) < self.interval_ns() => false,
+			_ => {
+				self.last_lookup = Some(now);
+				true
+			},
+		}
+	}
+
+	/// Check if the next step of the lookup is due and remember its time.
+	fn step_due(&mut self, now: u64) -> bool {
+		if now.saturating_sub(self.last_step) < self.step_ns() {
+			return false;
+		}
+		self.last_step = now;
+		true
+	}
+}
+
+/// Ping waiting for a pong.
+/// Lookup of the nodes nearest to a given point of the node hash space.
+struct TargetedLookup {
+	target: H256,
+	/// Nodes already asked for their neighbours.
+	queried: HashSet<NodeId>,
+	round: u16,
+	result: Sender<Vec<NearNode>>,
+}
+
+struct InFlightPing {
+	/// Hash of the ping packet, echoed in the pong.
+	echo_hash: H256,
+	/// Pongs arriving after this time are rejected.
+	deadline: u64,
+}
+
+/// FindNode request sent by us. The answer may span several Neighbours packets.
+struct FindNodeResponse {
+	sent_at: u64,
+	/// Neighbours received so far.
+	received: usize,
+}
+
+/// Malformed packets received from a single node.
+struct Violations {
+	count: u32,
+	updated: u64,
+}
+
+impl Violations {
+	/// Count at `now`, after forgiving `MAX_VIOLATIONS` packets every `VIOLATION_DECAY_NS`.
+	fn count_at(&self, now: u64) -> u32 {
+		let decay = now.saturating_sub(self.updated).saturating_mul(MAX_VIOLATIONS as u64) / VIOLATION_DECAY_NS;
+		self.count.saturating_sub(min(decay, u32::max_value() as u64) as u32)
+	}
+}
+
+/// FindNode requests served to a single node.
+struct FindNodeQuota {
+	/// Requests that may be served now, scaled by `MINUTE_NS`.
+	tokens: u64,
+	last_request: u64,
+	/// Distinct targets queried since `window_start`.
+	targets: HashSet<NodeId>,
+	window_start: u64,
+}
+
+impl FindNodeQuota {
+	fn new(limit: &FindNodeRateLimit, now: u64) -> FindNodeQuota {
+		FindNodeQuota {
+			tokens: max(limit.burst, 1) as u64 * MINUTE_NS,
+			last_request: now,
+			targets: HashSet::new(),
+			window_start: now,
+		}
+	}
+
+	/// Account a request for `target`. Returns `false` if the request is over one of the limits.
+	fn allow(&mut self, limit: &FindNodeRateLimit, target: &NodeId, now: u64) -> bool {
+		if now.saturating_sub(self.window_start) >= MINUTE_NS {
+			self.targets.clear();
+			self.window_start = now;
 		}
+		let new_target = !self.targets.contains(target);
+		if new_target && limit.targets_per_minute != 0 && self.targets.len() >= limit.targets_per_minute as usize {
+			return false;
+		}
+		if limit.requests_per_minute != 0 {
+			let capacity = max(limit.burst, 1) as u64 * MINUTE_NS;
+			let refill = now.saturating_sub(self.last_request).saturating_mul(limit.requests_per_minute as u64);
+			self.tokens = min(capacity, self.tokens.saturating_add(refill));
+			self.last_request = now;
+			if self.tokens < MINUTE_NS {
+				return false;
+			}
+			self.tokens -= MINUTE_NS;
+		}
+		if new_target {
+			self.targets.insert(target.clone());
+		}
+		true
+	}
+
+	/// Check if the node has not sent a request for a minute.
+	fn is_idle(&self, now: u64) -> bool {
+		now.saturating_sub(self.last_request) >= MINUTE_NS && now.saturating_sub(self.window_start) >= MINUTE_NS
 	}
 }
 
 struct Datagramm {
 	payload: Bytes,
 	address: SocketAddr,
+	packet_id: u8,
+	/// Unix time in seconds the packet expires at.
+	expires: u64,
+}
+
+/// Send queued packets with `send` until it would block. Packets that have expired by `now` are
+/// dropped. Returns true if the queue has been emptied.
+fn drain_send_queue<F>(queue: &mut VecDeque<Datagramm>, metrics: &mut DiscoveryStats, now: u64, mut send: F) -> bool
+	where F: FnMut(&Datagramm) -> io::Result<Option<usize>>
+{
+	while let Some(data) = queue.pop_front() {
+		if data.expires <= now {
+			trace!(target: "discovery", "Dropping expired packet to {:?}", &data.address);
+			metrics.expired_outgoing += 1;
+			continue;
+		}
+		match send(&data) {
+			Ok(Some(size)) if size == data.payload.len() => {
+			},
+			Ok(Some(_)) => {
+				warn!("UDP sent incomplete datagramm");
+			},
+			Ok(None) => {
+				queue.push_front(data);
+				return false;
+			}
+			Err(e) => {
+				debug!("UDP send error: {:?}, address: {:?}", e, &data.address);
+				return false;
+			}
+		}
+	}
+	true
 }
 
 pub struct Discovery {
@@ -97,9 +425,52 @@ pub struct Discovery {
 	discovery_nodes: HashSet<NodeId>,
 	node_buckets: Vec<NodeBucket>,
 	send_queue: VecDeque<Datagramm>,
+	/// Max packets waiting to be sent. Pongs displace our own queued packets when it is reached.
+	send_queue_limit: usize,
 	check_timestamps: bool,
 	adding_nodes: Vec<NodeEntry>,
 	ip_filter: IpFilter,
+	ping_timeout_ns: u64,
+	ping_retries: u32,
+	/// Time in seconds packets are accepted past their expiry.
+	clock_skew_secs: u64,
+	/// Pings waiting for a pong, by node.
+	in_flight_pings: HashMap<NodeId, Vec<InFlightPing>>,
+	/// Accept nodes with private, loopback and link-local addresses.
+	allow_non_global: bool,
+	ip_allowlist: Vec<IpNetwork>,
+	ip_denylist: Vec<IpNetwork>,
+	/// Only bond with these nodes, if set.
+	node_allowlist: Option<Arc<NodeIdAllowlistFilter>>,
+	stats: Arc<NetworkStats>,
+	/// Packet and lookup counters. Table occupancy is filled in by `discovery_stats`.
+	metrics: DiscoveryStats,
+	/// Start time of the current lookup and the number of neighbours it got so far.
+	lookup: Option<(u64, usize)>,
+	/// Our IP address as reported in pongs, by node.
+	observed: HashMap<NodeId, IpAddr>,
+	/// Nodes from Neighbours packets that are added to the table once they answer a ping.
+	pending_bonds: HashMap<NodeId, PendingBond>,
+	find_node_limit: FindNodeRateLimit,
+	/// Limits for reserved nodes in the table.
+	reserved_find_node_limit: FindNodeRateLimit,
+	reserved_nodes: HashSet<NodeId>,
+	/// FindNode requests served, by requesting node.
+	find_node_quotas: HashMap<NodeId, FindNodeQuota>,
+	/// FindNode requests sent, by queried node.
+	find_node_responses: HashMap<NodeId, FindNodeResponse>,
+	/// Malformed packets received, by sending node.
+	violations: HashMap<NodeId, Violations>,
+	/// Senders recovered from the signatures of recent packets, by packet hash.
+	signature_cache: LruCache<H256, NodeId>,
+	/// Arrival time of recent packets, by source address and packet hash.
+	recent_packets: LruCache<(SocketAddr, H256), u64>,
+	/// Copies of a packet from the same address are dropped for this long. Zero disables the check.
+	duplicate_window_ns: u64,
+	lookup_schedule: LookupSchedule,
+	targeted_lookups: Vec<TargetedLookup>,
+	/// Results of the targeted lookups that are done, waiting to be sent.
+	finished_lookups: Vec<(Sender<Vec<NearNode>>, Vec<NearNode>)>,
 }
 
 pub struct TableUpdates {
@@ -108,8 +479,9 @@ pub struct TableUpdates {
 }
 
 impl Discovery {
-	pub fn new(key: &KeyPair, listen: SocketAddr, public: NodeEndpoint, token: StreamToken, ip_filter: IpFilter) -> Discovery {
+	pub fn new(key: &KeyPair, listen: SocketAddr, public: NodeEndpoint, token: StreamToken, ip_filter: IpFilter, stats: Arc<NetworkStats>) -> Discovery {
 		let socket = UdpSocket::bind(&listen).expect("Error binding UDP socket");
+		let local = ip_class(&public.address.ip()) != IpClass::Global;
 		Discovery {
 			id: key.public().clone(),
 			id_hash: keccak(key.public()),
@@ -122,18 +494,149 @@ impl Discovery {
 			node_buckets: (0..NODE_BINS).map(|_| NodeBucket::new()).collect(),
 			udp_socket: socket,
 			send_queue: VecDeque::new(),
+			send_queue_limit: DEFAULT_SEND_QUEUE_LIMIT,
 			check_timestamps: true,
 			adding_nodes: Vec::new(),
 			ip_filter: ip_filter,
+			ping_timeout_ns: DEFAULT_PING_TIMEOUT_MS * 1000_000,
+			ping_retries: DEFAULT_PING_RETRIES,
+			clock_skew_secs: 0,
+			in_flight_pings: HashMap::new(),
+			allow_non_global: local,
+			ip_allowlist: Vec::new(),
+			ip_denylist: Vec::new(),
+			node_allowlist: None,
+			stats: stats,
+			metrics: DiscoveryStats::default(),
+			lookup: None,
+			observed: HashMap::new(),
+			pending_bonds: HashMap::new(),
+			find_node_limit: NO_FIND_NODE_LIMIT,
+			reserved_find_node_limit: NO_FIND_NODE_LIMIT,
+			reserved_nodes: HashSet::new(),
+			find_node_quotas: HashMap::new(),
+			find_node_responses: HashMap::new(),
+			violations: HashMap::new(),
+			signature_cache: LruCache::new(DEFAULT_PACKET_CACHE_SIZE),
+			recent_packets: LruCache::new(DEFAULT_PACKET_CACHE_SIZE),
+			duplicate_window_ns: 0,
+			lookup_schedule: LookupSchedule::new(),
+			targeted_lookups: Vec::new(),
+			finished_lookups: Vec::new(),
+		}
+	}
+
+	/// Set how long to wait for a pong and how many times to repeat an unanswered ping
+	/// before the node is evicted from the table.
+	pub fn set_ping_policy(&mut self, timeout: Duration, retries: u32) {
+		self.ping_timeout_ns = timeout.as_secs() * 1000_000_000 + timeout.subsec_nanos() as u64;
+		self.ping_retries = retries;
+	}
+
+	/// Accept packets for `allowance` past their expiry time, to allow for clocks running apart.
+	pub fn set_clock_skew(&mut self, allowance: Duration) {
+		self.clock_skew_secs = allowance.as_secs();
+	}
+
+	/// Set the number of packets whose sender and arrival time are remembered, and how long copies
+	/// of a packet from the same address are dropped. Zero size disables the cache, zero window the check.
+	pub fn set_packet_cache(&mut self, size: usize, duplicate_window: Duration) {
+		self.signature_cache.set_capacity(size);
+		self.recent_packets.set_capacity(size);
+		self.duplicate_window_ns = duplicate_window.as_secs() * 1000_000_000 + duplicate_window.subsec_nanos() as u64;
+	}
+
+	/// Set the max number of packets waiting to be sent.
+	pub fn set_send_queue_limit(&mut self, limit: usize) {
+		self.send_queue_limit = max(limit, 1);
+	}
+
+	/// Schedule lookups by the number of connected peers, from every `min_interval` below `min_peers`
+	/// to every `max_interval` at `max_peers`. Queries are answered and bonds kept at any rate.
+	/// Lookups are started with `refresh` unless adaptive.
+	pub fn set_adaptive(&mut self, adaptive: bool, min_interval: Duration, max_interval: Duration) {
+		let min_interval_ns = min_interval.as_secs() * 1000_000_000 + min_interval.subsec_nanos() as u64;
+		let max_interval_ns = max_interval.as_secs() * 1000_000_000 + max_interval.subsec_nanos() as u64;
+		self.lookup_schedule.adaptive = adaptive;
+		self.lookup_schedule.min_interval_ns = min_interval_ns;
+		self.lookup_schedule.max_interval_ns = max(min_interval_ns, max_interval_ns);
+	}
+
+	/// Update the number of connected peers the adaptive lookup rate is based on.
+	pub fn set_peer_count(&mut self, peers: usize, min_peers: usize, max_peers: usize) {
+		let activity = self.lookup_schedule.activity;
+		self.lookup_schedule.set_peer_count(peers, min_peers, max_peers);
+		if self.lookup_schedule.adaptive && activity != self.lookup_schedule.activity {
+			trace!(target: "discovery", "Lookup activity {}% with {} peers", self.lookup_schedule.activity, peers);
 		}
 	}
 
+	/// Set the limits for FindNode requests served to a node and to reserved nodes in the table.
+	pub fn set_find_node_limits(&mut self, limit: FindNodeRateLimit, reserved_limit: FindNodeRateLimit) {
+		self.find_node_limit = limit;
+		self.reserved_find_node_limit = reserved_limit;
+	}
+
+	/// Update the set of reserved nodes.
+	pub fn set_reserved_nodes(&mut self, reserved: HashSet<NodeId>) {
+		self.reserved_nodes = reserved;
+	}
+
+	/// Accept nodes with non-global addresses even though our public endpoint is global.
+	/// Non-global addresses are always accepted when the public endpoint itself is not global.
+	pub fn set_allow_non_global_ips(&mut self, allow: bool) {
+		self.allow_non_global = allow || ip_class(&self.public_endpoint.address.ip()) != IpClass::Global;
+	}
+
+	/// Update the endpoint advertised in pings.
+	pub fn set_public_endpoint(&mut self, endpoint: NodeEndpoint) {
+		self.public_endpoint = endpoint;
+	}
+
+	/// Our IP address as seen by the nodes that answered our pings, one entry per node.
+	pub fn observed_addresses(&self) -> Vec<IpAddr> {
+		self.observed.values().cloned().collect()
+	}
+
+	/// Only accept nodes with addresses in `allowlist`, unless empty, and not in `denylist`.
+	pub fn set_ip_lists(&mut self, allowlist: Vec<IpNetwork>, denylist: Vec<IpNetwork>) {
+		self.ip_allowlist = allowlist;
+		self.ip_denylist = denylist;
+	}
+
+	/// Only bond with nodes on the allowlist and ignore packets from other nodes. `None` allows all nodes.
+	pub fn set_node_allowlist(&mut self, allowlist: Option<Arc<NodeIdAllowlistFilter>>) {
+		self.node_allowlist = allowlist;
+	}
+
+	fn node_allowed(&self, id: &NodeId) -> bool {
+		self.node_allowlist.as_ref().map_or(true, |allowlist| allowlist.contains(id))
+	}
+
+	/// Current packet and lookup counters with per-bucket node counts.
+	pub fn discovery_stats(&self) -> DiscoveryStats {
+		let mut stats = self.metrics.clone();
+		stats.buckets = self.node_buckets.iter().map(|b| b.nodes.len()).collect();
+		stats.nodes = stats.buckets.iter().sum();
+		stats.pending_bonds = self.pending_bonds.len();
+		stats.activity = if self.lookup_schedule.adaptive { self.lookup_schedule.activity } else { 100 };
+		stats.lookup_interval_ms = self.lookup_schedule.interval_ns() / 1000_000;
+		stats
+	}
+
+	/// Reset packet and lookup counters. The time of the last successful lookup is kept.
+	pub fn reset_stats(&mut self) {
+		let last_successful_lookup = self.metrics.last_successful_lookup;
+		self.metrics = DiscoveryStats::default();
+		self.metrics.last_successful_lookup = last_successful_lookup;
+	}
+
 	/// Add a new node to discovery table. Pings the node.
 	pub fn add_node(&mut self, e: NodeEntry) {
 		if self.is_allowed(&e) {
-			let endpoint = e.endpoint.clone();
+			let node = e.clone();
 			self.update_node(e);
-			self.ping(&endpoint);
+			self.ping(&node.id, &node.endpoint);
 		}
 	}
 
@@ -143,6 +646,21 @@ impl Discovery {
 		self.update_new_nodes();
 	}
 
+	/// Forget all nodes, e.g. when switching to another network.
+	pub fn clear(&mut self) {
+		self.node_buckets = (0..NODE_BINS).map(|_| NodeBucket::new()).collect();
+		self.discovery_nodes.clear();
+		self.discovery_round = 0;
+		self.adding_nodes.clear();
+		self.pending_bonds.clear();
+		self.in_flight_pings.clear();
+		self.find_node_responses.clear();
+		self.lookup = None;
+		self.lookup_schedule.last_lookup = None;
+		self.targeted_lookups.clear();
+		self.finished_lookups.clear();
+	}
+
 	/// Add a list of known nodes to the table.
 	pub fn init_node_list(&mut self, mut nodes: Vec<NodeEntry>) {
 		for n in nodes.drain(..) {
@@ -160,30 +678,102 @@ impl Discovery {
 			let updated = if let Some(node) = bucket.nodes.iter_mut().find(|n| n.address.id == e.id) {
 				node.address = e.clone();
 				node.timeout = None;
+				node.ping_attempts = 0;
 				true
 			} else { false };
 
-			if !updated {
-				bucket.nodes.push_front(BucketEntry { address: e, timeout: None, id_hash: id_hash, });
-			}
-
-			if bucket.nodes.len() > BUCKET_SIZE {
-				//ping least active node
+			if updated {
+				None
+			} else if bucket.nodes.len() < BUCKET_SIZE {
+				bucket.nodes.push_front(BucketEntry { address: e, timeout: None, ping_attempts: 0, id_hash: id_hash, });
+				None
+			} else {
+				// Bucket is full: the new node waits until the least active node fails to respond.
+				bucket.pending = Some(e);
 				let last = bucket.nodes.back_mut().expect("Last item is always present when len() > 0");
-				last.timeout = Some(time::precise_time_ns());
-				Some(last.address.endpoint.clone())
-			} else { None }
+				if last.timeout.is_none() {
+					last.timeout = Some(time::precise_time_ns());
+					last.ping_attempts = 1;
+					Some(last.address.clone())
+				} else { None }
+			}
 		};
-		if let Some(endpoint) = ping {
-			self.ping(&endpoint);
+		if let Some(node) = ping {
+			self.ping(&node.id, &node.endpoint);
+		}
+	}
+
+	fn in_table(&self, id: &NodeId) -> bool {
+		let bucket = &self.node_buckets[Discovery::distance(&self.id_hash, &keccak(id)) as usize];
+		bucket.nodes.iter().any(|n| &n.address.id == id)
+	}
+
+	/// Ping a node learned from a Neighbours packet. The node is added to the table once it answers.
+	fn bond(&mut self, entry: NodeEntry, now: u64) {
+		if self.in_table(&entry.id) || self.pending_bonds.contains_key(&entry.id) {
+			return;
+		}
+		if self.pending_bonds.len() >= MAX_PENDING_BONDS {
+			trace!(target: "discovery", "Too many pending bonds, ignoring {:?}", &entry);
+			return;
+		}
+		if let Some(hash) = self.ping(&entry.id, &entry.endpoint) {
+			self.pending_bonds.insert(entry.id.clone(), PendingBond { entry: entry, ping_hashes: vec![hash], sent_at: now });
+		}
+	}
+
+	/// Complete the bond with a pending node if the pong echoes one of our pings. Returns the node added to the table.
+	fn complete_bond(&mut self, id: &NodeId, echo_hash: &H256) -> Option<NodeEntry> {
+		let matches = self.pending_bonds.get(id).map_or(false, |bond| bond.ping_hashes.contains(echo_hash));
+		if !matches {
+			return None;
+		}
+		let bond = self.pending_bonds.remove(id).expect("Pending bond was found above; qed");
+		trace!(target: "discovery", "Bonded with {:?}", &bond.entry);
+		self.update_node(bond.entry.clone());
+		Some(bond.entry)
+	}
+
+	/// Repeat unanswered bonding pings and drop nodes that have not answered any of the attempts.
+	fn check_pending_bonds(&mut self, now: u64) {
+		let ping_timeout_ns = self.ping_timeout_ns;
+		let max_pings = self.ping_retries as usize + 1;
+		let mut retry = Vec::new();
+		self.pending_bonds.retain(|_, bond| {
+			if now.saturating_sub(bond.sent_at) < ping_timeout_ns {
+				true
+			} else if bond.ping_hashes.len() < max_pings {
+				retry.push(bond.entry.clone());
+				true
+			} else {
+				trace!(target: "discovery", "Dropped unbonded node {:?}", &bond.entry);
+				false
+			}
+		});
+		for entry in retry {
+			if let Some(hash) = self.ping(&entry.id, &entry.endpoint) {
+				if let Some(bond) = self.pending_bonds.get_mut(&entry.id) {
+					bond.ping_hashes.push(hash);
+					bond.sent_at = now;
+				}
+			}
 		}
 	}
 
-	/// Removes the timeout of a given NodeId if it can be found in one of the discovery buckets
+	/// Removes the timeout of a given NodeId if it can be found in one of the discovery buckets.
+	/// A node that answered a challenge becomes the most active one and the pending replacement is dropped.
 	fn clear_ping(&mut self, id: &NodeId) {
 		let bucket = &mut self.node_buckets[Discovery::distance(&self.id_hash, &keccak(id)) as usize];
-		if let Some(node) = bucket.nodes.iter_mut().find(|n| &n.address.id == id) {
+		if let Some(index) = bucket.nodes.iter().position(|n| &n.address.id == id) {
+			self.metrics.bonds += 1;
+			let challenged = bucket.nodes[index].timeout.is_some();
+			let mut node = bucket.nodes.remove(index).expect("index is valid; qed");
 			node.timeout = None;
+			node.ping_attempts = 0;
+			bucket.nodes.push_front(node);
+			if challenged {
+				bucket.pending = None;
+			}
 		}
 	}
 
@@ -193,6 +783,19 @@ impl Discovery {
 		self.discovery_round = 0;
 		self.discovery_id.randomize(); //TODO: use cryptographic nonce
 		self.discovery_nodes.clear();
+		self.lookup = Some((time::precise_time_ns(), 0));
+	}
+
+	/// Record the completion of the current lookup.
+	fn finish_lookup(&mut self) {
+		if let Some((started, neighbours)) = self.lookup.take() {
+			self.metrics.lookups += 1;
+			self.metrics.lookup_time_ms += time::precise_time_ns().saturating_sub(started) / 1000_000;
+			if neighbours > 0 {
+				self.metrics.successful_lookups += 1;
+				self.metrics.last_successful_lookup = Some(time::get_time().sec as u64);
+			}
+		}
 	}
 
 	fn update_new_nodes(&mut self) {
@@ -205,18 +808,19 @@ impl Discovery {
 	}
 
 	fn discover(&mut self) {
-		self.update_new_nodes();
 		if self.discovery_round == DISCOVERY_MAX_STEPS {
 			return;
 		}
 		trace!(target: "discovery", "Starting round {:?}", self.discovery_round);
 		let mut tried_count = 0;
 		{
+			let now = time::precise_time_ns();
 			let nearest = Discovery::nearest_node_entries(&self.discovery_id, &self.node_buckets).into_iter();
 			let nearest = nearest.filter(|x| !self.discovery_nodes.contains(&x.id)).take(ALPHA).collect::<Vec<_>>();
 			for r in nearest {
 				let rlp = encode_list(&(&[self.discovery_id.clone()][..]));
 				self.send_packet(PACKET_FIND_NODE, &r.endpoint.udp_address(), &rlp);
+				self.find_node_responses.insert(r.id.clone(), FindNodeResponse { sent_at: now, received: 0 });
 				self.discovery_nodes.insert(r.id.clone());
 				tried_count += 1;
 				trace!(target: "discovery", "Sent FindNode to {:?}", &r.endpoint);
@@ -227,9 +831,73 @@ impl Discovery {
 			trace!(target: "discovery", "Completing discovery");
 			self.discovery_round = DISCOVERY_MAX_STEPS;
 			self.discovery_nodes.clear();
+			self.finish_lookup();
 			return;
 		}
 		self.discovery_round += 1;
+		if self.discovery_round == DISCOVERY_MAX_STEPS {
+			self.finish_lookup();
+		}
+	}
+
+	/// Nodes of the table nearest to `target`, closest first.
+	pub fn nearest_nodes(&self, target: &H256) -> Vec<NearNode> {
+		Discovery::nearest_hash_entries(target, &self.node_buckets).into_iter().map(|e| NearNode {
+			distance: Discovery::distance(target, &keccak(e.id)),
+			id: e.id,
+			endpoint: e.endpoint,
+			last_seen: None,
+			last_contact: None,
+		}).collect()
+	}
+
+	/// Look up the nodes nearest to `target` and send them to `result` once done. Lookups beyond
+	/// `MAX_TARGETED_LOOKUPS` are answered from the table.
+	pub fn find_nearest(&mut self, target: H256, result: Sender<Vec<NearNode>>) {
+		if self.targeted_lookups.len() >= MAX_TARGETED_LOOKUPS {
+			debug!(target: "discovery", "Too many lookups running, answering lookup for {:?} from the table", target);
+			let nodes = self.nearest_nodes(&target);
+			self.finished_lookups.push((result, nodes));
+			return;
+		}
+		self.targeted_lookups.push(TargetedLookup {
+			target: target,
+			queried: HashSet::new(),
+			round: 0,
+			result: result,
+		});
+	}
+
+	/// Targeted lookups that are done, with the nodes to send as their results.
+	pub fn take_finished_lookups(&mut self) -> Vec<(Sender<Vec<NearNode>>, Vec<NearNode>)> {
+		mem::replace(&mut self.finished_lookups, Vec::new())
+	}
+
+	/// Query the nodes nearest to the target of each targeted lookup that were not queried yet.
+	/// FindNode takes a public key rather than a hash, so each node is asked for the neighbours
+	/// of its own id, which cover the target once the node is close to it.
+	fn step_targeted_lookups(&mut self, now: u64) {
+		let lookups = mem::replace(&mut self.targeted_lookups, Vec::new());
+		for mut lookup in lookups {
+			let next: Vec<NodeEntry> = Discovery::nearest_hash_entries(&lookup.target, &self.node_buckets).into_iter()
+				.filter(|n| !lookup.queried.contains(&n.id))
+				.take(ALPHA)
+				.collect();
+			if next.is_empty() || lookup.round == DISCOVERY_MAX_STEPS {
+				trace!(target: "discovery", "Completed lookup for {:?} after {} rounds", lookup.target, lookup.round);
+				let nodes = self.nearest_nodes(&lookup.target);
+				self.finished_lookups.push((lookup.result, nodes));
+				continue;
+			}
+			for node in next {
+				let rlp = encode_list(&(&[node.id.clone()][..]));
+				self.send_packet(PACKET_FIND_NODE, &node.endpoint.udp_address(), &rlp);
+				self.find_node_responses.insert(node.id.clone(), FindNodeResponse { sent_at: now, received: 0 });
+				lookup.queried.insert(node.id);
+			}
+			lookup.round += 1;
+			self.targeted_lookups.push(lookup);
+		}
 	}
 
 	fn distance(a: &H256, b: &H256) -> u32 {
@@ -245,16 +913,33 @@ impl Discovery {
 		ret
 	}
 
-	fn ping(&mut self, node: &NodeEndpoint) {
+	/// Send a ping to node `id`. Returns the packet hash echoed in the pong.
+	fn ping(&mut self, id: &NodeId, node: &NodeEndpoint) -> Option<H256> {
 		let mut rlp = RlpStream::new_list(3);
 		rlp.append(&PROTOCOL_VERSION);
 		self.public_endpoint.to_rlp_list(&mut rlp);
 		node.to_rlp_list(&mut rlp);
 		trace!(target: "discovery", "Sent Ping to {:?}", &node);
-		self.send_packet(PACKET_PING, &node.udp_address(), &rlp.drain());
+		let hash = self.send_packet(PACKET_PING, &node.udp_address(), &rlp.drain());
+		if let Some(hash) = hash {
+			let deadline = time::precise_time_ns() + self.ping_timeout_ns;
+			self.in_flight_pings.entry(id.clone()).or_insert_with(Vec::new).push(InFlightPing { echo_hash: hash, deadline: deadline });
+		}
+		hash
 	}
 
-	fn send_packet(&mut self, packet_id: u8, address: &SocketAddr, payload: &[u8]) {
+	/// Sign and queue a packet. Returns the packet hash.
+	fn send_packet(&mut self, packet_id: u8, address: &SocketAddr, payload: &[u8]) -> Option<H256> {
+		let timestamp = time::get_time().sec as u32 + 60;
+		let packet = self.sign_packet(packet_id, payload, timestamp)?;
+		let signed_hash = H256::from_slice(&packet[0..32]);
+		self.metrics.sent.inc(packet_id);
+		self.send_to(packet_id, packet, address.clone(), timestamp as u64);
+		Some(signed_hash)
+	}
+
+	/// Append the expiry `timestamp` to the payload and sign the packet.
+	fn sign_packet(&self, packet_id: u8, payload: &[u8], timestamp: u32) -> Option<Bytes> {
 		let mut rlp = RlpStream::new();
 		rlp.append_raw(&[packet_id], 1);
 		let source = Rlp::new(payload);
@@ -262,7 +947,6 @@ impl Discovery {
 		for i in 0 .. source.item_count() {
 			rlp.append_raw(source.at(i).as_raw(), 1);
 		}
-		let timestamp = time::get_time().sec as u32 + 60;
 		rlp.append(&timestamp);
 
 		let bytes = rlp.drain();
@@ -271,7 +955,7 @@ impl Discovery {
 			Ok(s) => s,
 			Err(_) => {
 				warn!("Error signing UDP packet");
-				return;
+				return None;
 			}
 		};
 		let mut packet = Bytes::with_capacity(bytes.len() + 32 + 65);
@@ -280,18 +964,21 @@ impl Discovery {
 		packet.extend(bytes.iter());
 		let signed_hash = keccak(&packet[32..]);
 		packet[0..32].clone_from_slice(&signed_hash);
-		self.send_to(packet, address.clone());
+		Some(packet)
 	}
 
 	fn nearest_node_entries(target: &NodeId, buckets: &[NodeBucket]) -> Vec<NodeEntry> {
+		Discovery::nearest_hash_entries(&keccak(target), buckets)
+	}
+
+	fn nearest_hash_entries(target_hash: &H256, buckets: &[NodeBucket]) -> Vec<NodeEntry> {
 		let mut found: BTreeMap<u32, Vec<&NodeEntry>> = BTreeMap::new();
 		let mut count = 0;
-		let target_hash = keccak(target);
 
 		// Sort nodes by distance to target
 		for bucket in buckets {
 			for node in &bucket.nodes {
-				let distance = Discovery::distance(&target_hash, &node.id_hash);
+				let distance = Discovery::distance(target_hash, &node.id_hash);
 				found.entry(distance).or_insert_with(Vec::new).push(&node.address);
 				if count == BUCKET_SIZE {
 					// delete the most distant element
@@ -318,34 +1005,45 @@ impl Discovery {
 	}
 
 	pub fn writable<Message>(&mut self, io: &IoContext<Message>) where Message: Send + Sync + Clone {
-		while let Some(data) = self.send_queue.pop_front() {
-			match self.udp_socket.send_to(&data.payload, &data.address) {
-				Ok(Some(size)) if size == data.payload.len() => {
-				},
-				Ok(Some(_)) => {
-					warn!("UDP sent incomplete datagramm");
-				},
-				Ok(None) => {
-					self.send_queue.push_front(data);
-					return;
-				}
-				Err(e) => {
-					debug!("UDP send error: {:?}, address: {:?}", e, &data.address);
-					return;
-				}
-			}
+		let now = time::get_time().sec as u64;
+		let socket = &self.udp_socket;
+		if drain_send_queue(&mut self.send_queue, &mut self.metrics, now, |data| map_non_block(socket.send_to(&data.payload, &data.address))) {
+			io.update_registration(self.token).unwrap_or_else(|e| debug!("Error updating discovery registration: {:?}", e));
 		}
-		io.update_registration(self.token).unwrap_or_else(|e| debug!("Error updating discovery registration: {:?}", e));
 	}
 
-	fn send_to(&mut self, payload: Bytes, address: SocketAddr) {
-		self.send_queue.push_back(Datagramm { payload: payload, address: address });
+	/// Queue a packet. Pongs are sent ahead of our own requests so that bonds are kept under load.
+	/// If the queue is full, a pong replaces the most recently queued request; other packets are dropped.
+	fn send_to(&mut self, packet_id: u8, payload: Bytes, address: SocketAddr, expires: u64) {
+		if self.send_queue.len() >= self.send_queue_limit {
+			self.metrics.dropped_outgoing += 1;
+			let replaced = if packet_id == PACKET_PONG {
+				self.send_queue.iter().rposition(|d| d.packet_id != PACKET_PONG)
+			} else {
+				None
+			};
+			match replaced {
+				Some(index) => { self.send_queue.remove(index); },
+				None => {
+					trace!(target: "discovery", "Send queue full, dropping packet to {:?}", address);
+					return;
+				},
+			}
+		}
+		let datagramm = Datagramm { payload: payload, address: address, packet_id: packet_id, expires: expires };
+		if packet_id == PACKET_PONG {
+			let index = self.send_queue.iter().position(|d| d.packet_id != PACKET_PONG).unwrap_or(self.send_queue.len());
+			self.send_queue.insert(index, datagramm);
+		} else {
+			self.send_queue.push_back(datagramm);
+		}
 	}
 
 	pub fn readable<Message>(&mut self, io: &IoContext<Message>) -> Option<TableUpdates> where Message: Send + Sync + Clone {
-		let mut buf: [u8; MAX_DATAGRAM_SIZE] = unsafe { mem::uninitialized() };
+		// One byte more than allowed to detect oversized packets.
+		let mut buf: [u8; MAX_DATAGRAM_SIZE + 1] = unsafe { mem::uninitialized() };
 		let writable = !self.send_queue.is_empty();
-		let res = match self.udp_socket.recv_from(&mut buf) {
+		let res = match map_non_block(self.udp_socket.recv_from(&mut buf)) {
 			Ok(Some((len, address))) => self.on_packet(&buf[0..len], address).unwrap_or_else(|e| {
 				debug!("Error processing UDP packet: {:?}", e);
 				None
@@ -375,40 +1073,151 @@ impl Discovery {
 		}
 
 		let signed = &packet[(32 + 65)..];
-		let signature = H520::from_slice(&packet[32..(32 + 65)]);
-		let node_id = recover(&signature.into(), &keccak(signed))?;
+		let node_id = self.recover_sender(&hash_signed, packet)?;
+
+		// Violations are only accounted to recovered senders, the source address may be forged.
+		if self.is_violator(&node_id, time::precise_time_ns()) {
+			trace!(target: "discovery", "Ignoring packet from {:?}, too many malformed packets", from);
+			return Ok(None);
+		}
+		if packet.len() > MAX_DATAGRAM_SIZE {
+			debug!(target: "discovery", "Oversized packet of {} bytes from {:?}", packet.len(), from);
+			self.note_violation(&node_id, time::precise_time_ns());
+			return Err(ErrorKind::BadProtocol.into());
+		}
 
 		let packet_id = signed[0];
+		// Don't reveal ourselves to nodes that are not allowed.
+		if !self.node_allowed(&node_id) {
+			trace!(target: "discovery", "Ignoring packet from {:?}, not on the allowlist", from);
+			return Ok(None);
+		}
+		if self.is_duplicate(&from, &hash_signed, time::precise_time_ns()) {
+			trace!(target: "discovery", "Dropped duplicate packet from {:?}", from);
+			self.metrics.rejected.duplicate += 1;
+			return Ok(None);
+		}
 		let rlp = UntrustedRlp::new(&signed[1..]);
-		match packet_id {
+		let result = match packet_id {
 			PACKET_PING => self.on_ping(&rlp, &node_id, &from, &hash_signed),
 			PACKET_PONG => self.on_pong(&rlp, &node_id, &from),
 			PACKET_FIND_NODE => self.on_find_node(&rlp, &node_id, &from),
 			PACKET_NEIGHBOURS => self.on_neighbours(&rlp, &node_id, &from),
 			_ => {
 				debug!("Unknown UDP packet: {}", packet_id);
-				Ok(None)
+				return Ok(None);
+			}
+		};
+		if result.is_ok() {
+			self.metrics.received.inc(packet_id);
+		}
+		result
+	}
+
+	/// Recover the sender of the packet with the given hash. The hash covers the signature and
+	/// the signed data, so packets with the same hash have the same sender.
+	fn recover_sender(&mut self, hash: &H256, packet: &[u8]) -> Result<NodeId, Error> {
+		if let Some(id) = self.signature_cache.get_mut(hash) {
+			self.metrics.signature_cache_hits += 1;
+			return Ok(id.clone());
+		}
+		let signature = H520::from_slice(&packet[32..(32 + 65)]);
+		let id = recover(&signature.into(), &keccak(&packet[(32 + 65)..]))?;
+		if self.signature_cache.capacity() != 0 {
+			self.signature_cache.insert(hash.clone(), id.clone());
+		}
+		Ok(id)
+	}
+
+	/// Check if the packet was already received from `from` within the duplicate window, and remember its arrival.
+	fn is_duplicate(&mut self, from: &SocketAddr, hash: &H256, now: u64) -> bool {
+		if self.duplicate_window_ns == 0 || self.recent_packets.capacity() == 0 {
+			return false;
+		}
+		let key = (from.clone(), hash.clone());
+		if let Some(seen) = self.recent_packets.get_mut(&key) {
+			if now.saturating_sub(*seen) < self.duplicate_window_ns {
+				return true;
 			}
+			*seen = now;
+			return false;
 		}
+		self.recent_packets.insert(key, now);
+		false
+	}
+
+	/// Check if the node sent `MAX_VIOLATIONS` malformed packets not yet forgiven at `now`.
+	fn is_violator(&self, node: &NodeId, now: u64) -> bool {
+		self.violations.get(node).map_or(false, |v| v.count_at(now) >= MAX_VIOLATIONS)
+	}
+
+	/// Account a malformed packet from `node` at `now`. Nodes with `MAX_VIOLATIONS` malformed packets are
+	/// ignored until enough of them are forgiven.
+	fn note_violation(&mut self, node: &NodeId, now: u64) {
+		self.metrics.malformed_packets += 1;
+		if !self.violations.contains_key(node) && self.violations.len() >= MAX_VIOLATION_SOURCES {
+			// Forget forgiven offenders first.
+			self.violations.retain(|_, v| v.count_at(now) > 0);
+			if self.violations.len() >= MAX_VIOLATION_SOURCES {
+				return;
+			}
+		}
+		let violations = self.violations.entry(node.clone()).or_insert(Violations { count: 0, updated: now });
+		violations.count = violations.count_at(now) + 1;
+		violations.updated = now;
+	}
+
+	/// Validate that the expiry timestamp is not further in the past than the clock skew allowance.
+	fn check_timestamp(&mut self, timestamp: u64) -> Result<(), Error> {
+		self.check_timestamp_at(timestamp, time::get_time().sec as u64)
 	}
 
-	/// Validate that given timestamp is in within one second of now or in the future
-	fn check_timestamp(&self, timestamp: u64) -> Result<(), Error> {
-		if self.check_timestamps && timestamp < time::get_time().sec as u64{
+	fn check_timestamp_at(&mut self, timestamp: u64, now: u64) -> Result<(), Error> {
+		if self.check_timestamps && timestamp.saturating_add(self.clock_skew_secs) < now {
 			debug!(target: "discovery", "Expired packet");
+			self.metrics.rejected.expired += 1;
 			return Err(ErrorKind::Expired.into());
 		}
 		Ok(())
 	}
 
+	/// Take the ping to `node` echoed by a pong. Returns `false` if there is no such ping or its deadline has passed.
+	fn take_in_flight_ping(&mut self, node: &NodeId, echo_hash: &H256, now: u64) -> bool {
+		let ping = match self.in_flight_pings.get_mut(node) {
+			Some(pings) => match pings.iter().position(|p| &p.echo_hash == echo_hash) {
+				Some(index) => Some(pings.remove(index)),
+				None => None,
+			},
+			None => None,
+		};
+		if self.in_flight_pings.get(node).map_or(false, |pings| pings.is_empty()) {
+			self.in_flight_pings.remove(node);
+		}
+		match ping {
+			Some(ref ping) if ping.deadline >= now => true,
+			Some(_) => {
+				trace!(target: "discovery", "Late pong from {:?}", node);
+				self.metrics.rejected.late_pong += 1;
+				false
+			},
+			None => {
+				trace!(target: "discovery", "Pong from {:?} echoes none of our pings", node);
+				self.metrics.rejected.unmatched_pong += 1;
+				false
+			},
+		}
+	}
+
 	fn is_allowed(&self, entry: &NodeEntry) -> bool {
-		entry.endpoint.is_allowed(&self.ip_filter) && entry.id != self.id
+		entry.endpoint.is_allowed(&self.ip_filter) && entry.id != self.id && self.node_allowed(&entry.id) &&
+			ip_class(&entry.endpoint.address.ip()).is_accepted(self.allow_non_global) &&
+			is_allowed_by_lists(&entry.endpoint.address.ip(), &self.ip_allowlist, &self.ip_denylist)
 	}
 
 	fn on_ping(&mut self, rlp: &UntrustedRlp, node: &NodeId, from: &SocketAddr, echo_hash: &[u8]) -> Result<Option<TableUpdates>, Error> {
 		trace!(target: "discovery", "Got Ping from {:?}", &from);
 		let source = NodeEndpoint::from_rlp(&rlp.at(1)?)?;
-		let dest = NodeEndpoint::from_rlp(&rlp.at(2)?)?;
+		let _dest = NodeEndpoint::from_rlp(&rlp.at(2)?)?;
 		let timestamp: u64 = rlp.val_at(3)?;
 		self.check_timestamp(timestamp)?;
 		let mut added_map = HashMap::new();
@@ -421,8 +1230,10 @@ impl Discovery {
 			self.update_node(entry.clone());
 			added_map.insert(node.clone(), entry);
 		}
+		// Echo the sender address as we see it, so the node can learn its public address.
+		let observed = NodeEndpoint { address: SocketAddr::new(from.ip(), source.address.port()), udp_port: from.port() };
 		let mut response = RlpStream::new_list(2);
-		dest.to_rlp_list(&mut response);
+		observed.to_rlp_list(&mut response);
 		response.append(&echo_hash);
 		self.send_packet(PACKET_PONG, from, &response.drain());
 
@@ -431,24 +1242,58 @@ impl Discovery {
 
 	fn on_pong(&mut self, rlp: &UntrustedRlp, node: &NodeId, from: &SocketAddr) -> Result<Option<TableUpdates>, Error> {
 		trace!(target: "discovery", "Got Pong from {:?}", &from);
-		// TODO: validate pong packet in rlp.val_at(1)
 		let dest = NodeEndpoint::from_rlp(&rlp.at(0)?)?;
+		let echo_hash: H256 = rlp.val_at(1)?;
 		let timestamp: u64 = rlp.val_at(2)?;
 		self.check_timestamp(timestamp)?;
-		let mut entry = NodeEntry { id: node.clone(), endpoint: dest };
-		if !entry.endpoint.is_valid() {
-			debug!(target: "discovery", "Bad address: {:?}", entry);
-			entry.endpoint.address = from.clone();
+		// Only a pong to one of our pings proves the node is alive.
+		if !self.take_in_flight_ping(node, &echo_hash, time::precise_time_ns()) {
+			return Ok(None);
 		}
+		// The pong echoes our endpoint as the node sees it.
+		if dest.is_valid() && (self.observed.len() < MAX_OBSERVED_ADDRESSES || self.observed.contains_key(node)) {
+			self.observed.insert(node.clone(), dest.address.ip());
+		}
+		let bonded = self.complete_bond(node, &echo_hash);
 		self.clear_ping(node);
-		Ok(None)
+		Ok(bonded.map(|entry| {
+			let mut added = HashMap::new();
+			added.insert(node.clone(), entry);
+			TableUpdates { added: added, removed: HashSet::new() }
+		}))
 	}
 
-	fn on_find_node(&mut self, rlp: &UntrustedRlp, _node: &NodeId, from: &SocketAddr) -> Result<Option<TableUpdates>, Error> {
+	/// Account a FindNode request from `node`. Reserved nodes that are in the table get the higher limits.
+	fn find_node_allowed(&mut self, node: &NodeId, target: &NodeId, now: u64) -> bool {
+		let limit = if self.reserved_nodes.contains(node) && self.in_table(node) {
+			self.reserved_find_node_limit
+		} else {
+			self.find_node_limit
+		};
+		if limit.requests_per_minute == 0 && limit.targets_per_minute == 0 {
+			return true;
+		}
+		if !self.find_node_quotas.contains_key(node) && self.find_node_quotas.len() >= MAX_FIND_NODE_SOURCES {
+			self.find_node_quotas.retain(|_, quota| !quota.is_idle(now));
+			if self.find_node_quotas.len() >= MAX_FIND_NODE_SOURCES {
+				return false;
+			}
+		}
+		self.find_node_quotas.entry(node.clone())
+			.or_insert_with(|| FindNodeQuota::new(&limit, now))
+			.allow(&limit, target, now)
+	}
+
+	fn on_find_node(&mut self, rlp: &UntrustedRlp, node: &NodeId, from: &SocketAddr) -> Result<Option<TableUpdates>, Error> {
 		trace!(target: "discovery", "Got FindNode from {:?}", &from);
 		let target: NodeId = rlp.val_at(0)?;
 		let timestamp: u64 = rlp.val_at(1)?;
 		self.check_timestamp(timestamp)?;
+		if !self.find_node_allowed(node, &target, time::precise_time_ns()) {
+			trace!(target: "discovery", "Dropped FindNode from {:?}, over the limit", &from);
+			self.metrics.dropped_find_node += 1;
+			return Ok(None);
+		}
 		let nearest = Discovery::nearest_node_entries(&target, &self.node_buckets);
 		if nearest.is_empty() {
 			return Ok(None);
@@ -461,9 +1306,9 @@ impl Discovery {
 		Ok(None)
 	}
 
+	/// Split the nodes into Neighbours packets that fit a datagram each.
 	fn prepare_neighbours_packets(nearest: &[NodeEntry]) -> Vec<Bytes> {
-		let limit = (MAX_DATAGRAM_SIZE - 109) / 90;
-		let chunks = nearest.chunks(limit);
+		let chunks = nearest.chunks(MAX_NEIGHBOURS_PER_PACKET);
 		let packets = chunks.map(|c| {
 			let mut rlp = RlpStream::new_list(1);
 			rlp.begin_list(c.len());
@@ -477,10 +1322,27 @@ impl Discovery {
 		packets.collect()
 	}
 
-	fn on_neighbours(&mut self, rlp: &UntrustedRlp, _node: &NodeId, from: &SocketAddr) -> Result<Option<TableUpdates>, Error> {
-		// TODO: validate packet
-		let mut added = HashMap::new();
-		trace!(target: "discovery", "Got {} Neighbours from {:?}", rlp.at(0)?.item_count()?, &from);
+	/// Neighbours are only pinged. They become dial candidates once they have answered, so a
+	/// node can't make us dial arbitrary addresses.
+	fn on_neighbours(&mut self, rlp: &UntrustedRlp, node: &NodeId, from: &SocketAddr) -> Result<Option<TableUpdates>, Error> {
+		let now = time::precise_time_ns();
+		let count = rlp.at(0)?.item_count()?;
+		trace!(target: "discovery", "Got {} Neighbours from {:?}", count, &from);
+		// An answer to our FindNode may span several packets, with no more than k entries in total.
+		let mut received = count;
+		if let Some(response) = self.find_node_responses.get_mut(node) {
+			if now.saturating_sub(response.sent_at) < FIND_NODE_RESPONSE_NS {
+				response.received += count;
+				received = response.received;
+			}
+		}
+		if received > BUCKET_SIZE {
+			debug!(target: "discovery", "Got {} Neighbours from {:?}, more than {}", received, &from, BUCKET_SIZE);
+			self.note_violation(node, now);
+			return Err(ErrorKind::BadProtocol.into());
+		}
+		let timestamp: u64 = rlp.val_at(1)?;
+		self.check_timestamp(timestamp)?;
 		for r in rlp.at(0)?.iter() {
 			let endpoint = NodeEndpoint::from_rlp(&r)?;
 			if !endpoint.is_valid() {
@@ -496,36 +1358,83 @@ impl Discovery {
 				debug!(target: "discovery", "Address not allowed: {:?}", entry);
 				continue;
 			}
-			added.insert(node_id, entry.clone());
-			self.ping(&entry.endpoint);
-			self.update_node(entry);
+			if let Some((_, ref mut neighbours)) = self.lookup {
+				*neighbours += 1;
+			}
+			self.bond(entry, now);
 		}
-		Ok(Some(TableUpdates { added: added, removed: HashSet::new() }))
+		Ok(None)
 	}
 
-	fn check_expired(&mut self, force: bool) -> HashSet<NodeId> {
-		let now = time::precise_time_ns();
+	/// Retry unanswered pings and evict nodes that have not answered any of the attempts.
+	fn check_expired(&mut self, now: u64, force: bool) -> HashSet<NodeId> {
 		let mut removed: HashSet<NodeId> = HashSet::new();
+		let mut retry = Vec::new();
 		for bucket in &mut self.node_buckets {
+			let ping_timeout_ns = self.ping_timeout_ns;
+			let ping_retries = self.ping_retries;
+			let stats = &self.stats;
 			bucket.nodes.retain(|node| {
 				if let Some(timeout) = node.timeout {
-					if !force && now - timeout < PING_TIMEOUT_MS * 1000_0000 {
+					if !force && now.saturating_sub(timeout) < ping_timeout_ns {
 						true
-					}
-					else {
+					} else if !force && node.ping_attempts <= ping_retries {
+						retry.push(node.address.id.clone());
+						true
+					} else {
 						trace!(target: "discovery", "Removed expired node {:?}", &node.address);
+						stats.inc_discovery_ping_failures();
 						removed.insert(node.address.id.clone());
 						false
 					}
 				} else { true }
 			});
+			if bucket.nodes.len() < BUCKET_SIZE {
+				if let Some(pending) = bucket.pending.take() {
+					let id_hash = keccak(pending.id);
+					bucket.nodes.push_front(BucketEntry { address: pending, timeout: None, ping_attempts: 0, id_hash: id_hash });
+				}
+			}
+		}
+		for id in retry {
+			let endpoint = {
+				let bucket = &mut self.node_buckets[Discovery::distance(&self.id_hash, &keccak(&id)) as usize];
+				let node = bucket.nodes.iter_mut().find(|n| n.address.id == id).expect("Node to retry was kept in the bucket; qed");
+				node.timeout = Some(now);
+				node.ping_attempts += 1;
+				node.address.endpoint.clone()
+			};
+			trace!(target: "discovery", "Retrying ping to {:?}", &endpoint);
+			self.stats.inc_discovery_ping_retries();
+			self.ping(&id, &endpoint);
 		}
 		removed
 	}
 
 	pub fn round(&mut self) -> Option<TableUpdates> {
-		let removed = self.check_expired(false);
-		self.discover();
+		self.round_at(time::precise_time_ns())
+	}
+
+	fn round_at(&mut self, now: u64) -> Option<TableUpdates> {
+		let removed = self.check_expired(now, false);
+		self.check_pending_bonds(now);
+		self.in_flight_pings.retain(|_, pings| {
+			pings.retain(|p| p.deadline >= now);
+			!pings.is_empty()
+		});
+		self.find_node_responses.retain(|_, response| now.saturating_sub(response.sent_at) < FIND_NODE_RESPONSE_NS);
+		self.update_new_nodes();
+		if !self.lookup_schedule.adaptive {
+			self.discover();
+		} else {
+			if self.lookup_schedule.lookup_due(now) {
+				self.start();
+			}
+			if self.lookup_schedule.step_due(now) {
+				self.discover();
+			}
+		}
+		self.step_targeted_lookups(now);
 		if !removed.is_empty() {
 			Some(TableUpdates { added: HashMap::new(), removed: removed })
 		} else { None }
@@ -535,18 +1444,18 @@ impl Discovery {
 		self.start();
 	}
 
-	pub fn register_socket<Host:Handler>(&self, event_loop: &mut EventLoop<Host>) -> Result<(), Error> {
-		event_loop.register(&self.udp_socket, Token(self.token), Ready::all(), PollOpt::edge()).expect("Error registering UDP socket");
+	pub fn register_socket(&self, poll: &Poll) -> Result<(), Error> {
+		poll.register(&self.udp_socket, Token(self.token), Ready::all(), PollOpt::edge()).expect("Error registering UDP socket");
 		Ok(())
 	}
 
-	pub fn update_registration<Host:Handler>(&self, event_loop: &mut EventLoop<Host>) -> Result<(), Error> {
+	pub fn update_registration(&self, poll: &Poll) -> Result<(), Error> {
 		let registration = if !self.send_queue.is_empty() {
 			Ready::readable() | Ready::writable()
 		} else {
 			Ready::readable()
 		};
-		event_loop.reregister(&self.udp_socket, Token(self.token), registration, PollOpt::edge()).expect("Error reregistering UDP socket");
+		poll.reregister(&self.udp_socket, Token(self.token), registration, PollOpt::edge()).expect("Error reregistering UDP socket");
 		Ok(())
 	}
 }
@@ -558,6 +1467,7 @@ mod tests {
 	use node_table::{Node, NodeId, NodeEndpoint};
 
 	use std::str::FromStr;
+	use std::sync::mpsc;
 	use rustc_hex::FromHex;
 	use ethkey::{Random, Generator};
 
@@ -584,8 +1494,8 @@ mod tests {
 		let key2 = Random.generate().unwrap();
 		let ep1 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40444").unwrap(), udp_port: 40444 };
 		let ep2 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40445").unwrap(), udp_port: 40445 };
-		let mut discovery1 = Discovery::new(&key1, ep1.address.clone(), ep1.clone(), 0, IpFilter::default());
-		let mut discovery2 = Discovery::new(&key2, ep2.address.clone(), ep2.clone(), 0, IpFilter::default());
+		let mut discovery1 = Discovery::new(&key1, ep1.address.clone(), ep1.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		let mut discovery2 = Discovery::new(&key2, ep2.address.clone(), ep2.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
 
 		let node1 = Node::from_str("enode://a979fb575495b8d6db44f750317d0f4622bf4c2aa3365d6af7c284339968eef29b69ad0dce72a4d8db5ebb4968de0e3bec910127f134779fbcb0cb6d3331163c@127.0.0.1:7770").unwrap();
 		let node2 = Node::from_str("enode://b979fb575495b8d6db44f750317d0f4622bf4c2aa3365d6af7c284339968eef29b69ad0dce72a4d8db5ebb4968de0e3bec910127f134779fbcb0cb6d3331163c@127.0.0.1:7771").unwrap();
@@ -610,22 +1520,737 @@ mod tests {
 			}
 			discovery2.round();
 		}
-		assert_eq!(Discovery::nearest_node_entries(&NodeId::new(), &discovery2.node_buckets).len(), 3)
+		// The neighbours of the first node never answer pings, so they are not added to the table.
+		assert_eq!(Discovery::nearest_node_entries(&NodeId::new(), &discovery2.node_buckets).len(), 1);
+		assert_eq!(discovery2.pending_bonds.len(), 2);
+	}
+
+	#[test]
+	fn discovery_stats() {
+		let key1 = Random.generate().unwrap();
+		let key2 = Random.generate().unwrap();
+		let ep1 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40452").unwrap(), udp_port: 40452 };
+		let ep2 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40453").unwrap(), udp_port: 40453 };
+		let mut discovery1 = Discovery::new(&key1, ep1.address.clone(), ep1.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		let mut discovery2 = Discovery::new(&key2, ep2.address.clone(), ep2.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+
+		// Ping, then pong and deliver both.
+		discovery2.add_node(NodeEntry { id: key1.public().clone(), endpoint: ep1.clone() });
+		let ping = discovery2.send_queue.pop_front().unwrap();
+		discovery1.on_packet(&ping.payload, ep2.address.clone()).unwrap();
+		let pong = discovery1.send_queue.pop_front().unwrap();
+		discovery2.on_packet(&pong.payload, ep1.address.clone()).unwrap();
+
+		let stats1 = discovery1.discovery_stats();
+		assert_eq!(stats1.received.ping, 1);
+		assert_eq!(stats1.sent.pong, 1);
+		let stats2 = discovery2.discovery_stats();
+		assert_eq!(stats2.sent.ping, 1);
+		assert_eq!(stats2.received.pong, 1);
+		assert_eq!(stats2.bonds, 1);
+
+		// Both nodes are in each other's table, in the bucket of their distance.
+		for &(ref stats, ref id_hash, ref other) in &[(stats1, discovery1.id_hash, key2.public()), (stats2, discovery2.id_hash, key1.public())] {
+			assert_eq!(stats.nodes, 1);
+			assert_eq!(stats.buckets.len(), NODE_BINS as usize);
+			assert_eq!(stats.buckets[Discovery::distance(id_hash, &keccak(other)) as usize], 1);
+		}
+
+		// A lookup that gets neighbours is recorded as successful.
+		discovery1.add_node(NodeEntry { id: NodeId::random(), endpoint: ep1.clone() });
+		discovery1.send_queue.clear();
+		discovery2.refresh();
+		for _ in 0..DISCOVERY_MAX_STEPS {
+			discovery2.round();
+			while let Some(datagramm) = discovery2.send_queue.pop_front() {
+				if datagramm.address == ep1.address {
+					discovery1.on_packet(&datagramm.payload, ep2.address.clone()).ok();
+				}
+			}
+			while let Some(datagramm) = discovery1.send_queue.pop_front() {
+				if datagramm.address == ep2.address {
+					discovery2.on_packet(&datagramm.payload, ep1.address.clone()).ok();
+				}
+			}
+		}
+		let stats2 = discovery2.discovery_stats();
+		assert!(stats2.sent.find_node > 0);
+		assert!(stats2.received.neighbours > 0);
+		assert_eq!(stats2.lookups, 1);
+		assert_eq!(stats2.successful_lookups, 1);
+		assert!(stats2.last_successful_lookup.is_some());
+
+		discovery2.reset_stats();
+		let reset = discovery2.discovery_stats();
+		assert_eq!(reset.sent, DiscoveryPacketCounts::default());
+		assert_eq!(reset.lookups, 0);
+		assert_eq!(reset.last_successful_lookup, stats2.last_successful_lookup);
+		assert_eq!(reset.nodes, stats2.nodes);
+	}
+
+	#[test]
+	fn pong_reports_observed_address() {
+		let key1 = Random.generate().unwrap();
+		let key2 = Random.generate().unwrap();
+		let ep1 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40454").unwrap(), udp_port: 40454 };
+		let ep2 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40455").unwrap(), udp_port: 40455 };
+		let mut discovery1 = Discovery::new(&key1, ep1.address.clone(), ep1.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		let mut discovery2 = Discovery::new(&key2, ep2.address.clone(), ep2.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+
+		// The ping arrives from a translated address.
+		discovery2.add_node(NodeEntry { id: key1.public().clone(), endpoint: ep1.clone() });
+		let ping = discovery2.send_queue.pop_front().unwrap();
+		let nat = SocketAddr::from_str("127.0.0.2:50000").unwrap();
+		discovery1.on_packet(&ping.payload, nat).unwrap();
+		let pong = discovery1.send_queue.pop_front().unwrap();
+		assert_eq!(pong.address, nat);
+		assert!(discovery2.observed_addresses().is_empty());
+		discovery2.on_packet(&pong.payload, ep1.address.clone()).unwrap();
+		assert_eq!(discovery2.observed_addresses(), vec![nat.ip()]);
+	}
+
+	#[test]
+	fn ignores_nodes_missing_from_allowlist() {
+		let key1 = Random.generate().unwrap();
+		let key2 = Random.generate().unwrap();
+		let ep1 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40462").unwrap(), udp_port: 40462 };
+		let ep2 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40463").unwrap(), udp_port: 40463 };
+		let mut discovery1 = Discovery::new(&key1, ep1.address.clone(), ep1.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		let mut discovery2 = Discovery::new(&key2, ep2.address.clone(), ep2.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		let allowlist = Arc::new(NodeIdAllowlistFilter::new(vec![key2.public().clone()], None).unwrap());
+		discovery1.set_node_allowlist(Some(allowlist.clone()));
+
+		// Pings from unlisted nodes are not answered.
+		allowlist.set(Vec::new());
+		discovery2.add_node(NodeEntry { id: key1.public().clone(), endpoint: ep1.clone() });
+		let ping = discovery2.send_queue.pop_front().unwrap();
+		assert!(discovery1.on_packet(&ping.payload, ep2.address.clone()).unwrap().is_none());
+		assert!(discovery1.send_queue.is_empty());
+
+		// Unlisted nodes are not pinged.
+		discovery1.add_node(NodeEntry { id: key2.public().clone(), endpoint: ep2.clone() });
+		assert!(discovery1.send_queue.is_empty());
+
+		allowlist.set(vec![key2.public().clone()]);
+		discovery1.on_packet(&ping.payload, ep2.address.clone()).unwrap();
+		assert!(discovery1.send_queue.pop_front().is_some());
+	}
+
+	/// Deliver a Neighbours packet listing `nodes` from `sender` to `discovery`.
+	fn deliver_neighbours(discovery: &mut Discovery, sender: &mut Discovery, nodes: &[NodeEntry]) -> Vec<Option<TableUpdates>> {
+		let to = discovery.public_endpoint.address.clone();
+		let from = sender.public_endpoint.address.clone();
+		Discovery::prepare_neighbours_packets(nodes).iter().map(|p| {
+			sender.send_packet(PACKET_NEIGHBOURS, &to, p);
+			let datagramm = sender.send_queue.pop_front().unwrap();
+			discovery.on_packet(&datagramm.payload, from).unwrap()
+		}).collect()
+	}
+
+	#[test]
+	fn unanswered_neighbours_are_not_added() {
+		let key1 = Random.generate().unwrap();
+		let key2 = Random.generate().unwrap();
+		let ep1 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40464").unwrap(), udp_port: 40464 };
+		let ep2 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40465").unwrap(), udp_port: 40465 };
+		let mut discovery1 = Discovery::new(&key1, ep1.address.clone(), ep1.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		let mut discovery2 = Discovery::new(&key2, ep2.address.clone(), ep2.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		discovery1.set_ping_policy(Duration::from_millis(100), 2);
+		let ms = 1000_000;
+
+		// The second node claims a silent node lives at its own address.
+		let silent = NodeEntry { id: NodeId::random(), endpoint: ep2.clone() };
+		let updates = deliver_neighbours(&mut discovery1, &mut discovery2, &[silent.clone()]);
+		assert!(updates.iter().all(|u| u.is_none()));
+		assert!(discovery1.pending_bonds.contains_key(&silent.id));
+		assert!(!discovery1.in_table(&silent.id));
+
+		// The pong comes from a different node id and does not complete the bond.
+		let ping = discovery1.send_queue.pop_front().unwrap();
+		assert_eq!(ping.address, ep2.address);
+		assert!(discovery2.on_packet(&ping.payload, ep1.address.clone()).unwrap().is_some());
+		let pong = discovery2.send_queue.pop_front().unwrap();
+		assert!(discovery1.on_packet(&pong.payload, ep2.address.clone()).unwrap().is_none());
+		assert!(!discovery1.in_table(&silent.id));
+
+		// Two retries, then the node is dropped.
+		let sent_at = discovery1.pending_bonds[&silent.id].sent_at;
+		discovery1.check_pending_bonds(sent_at + 100 * ms);
+		discovery1.check_pending_bonds(sent_at + 200 * ms);
+		assert_eq!(discovery1.send_queue.len(), 2);
+		assert_eq!(discovery1.pending_bonds[&silent.id].ping_hashes.len(), 3);
+		discovery1.check_pending_bonds(sent_at + 300 * ms);
+		assert!(discovery1.pending_bonds.is_empty());
+		assert!(!discovery1.in_table(&silent.id));
+		assert_eq!(discovery1.discovery_stats().pending_bonds, 0);
+	}
+
+	#[test]
+	fn neighbour_added_after_matching_pong() {
+		let key1 = Random.generate().unwrap();
+		let key2 = Random.generate().unwrap();
+		let key3 = Random.generate().unwrap();
+		let ep1 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40466").unwrap(), udp_port: 40466 };
+		let ep2 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40467").unwrap(), udp_port: 40467 };
+		let ep3 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40468").unwrap(), udp_port: 40468 };
+		let mut discovery1 = Discovery::new(&key1, ep1.address.clone(), ep1.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		let mut discovery2 = Discovery::new(&key2, ep2.address.clone(), ep2.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		let mut discovery3 = Discovery::new(&key3, ep3.address.clone(), ep3.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+
+		let node3 = NodeEntry { id: key3.public().clone(), endpoint: ep3.clone() };
+		deliver_neighbours(&mut discovery1, &mut discovery2, &[node3.clone()]);
+		let ping = discovery1.send_queue.pop_front().unwrap();
+		assert_eq!(ping.address, ep3.address);
+
+		// A pong that does not echo our ping is ignored.
+		let mut forged = RlpStream::new_list(2);
+		ep1.to_rlp_list(&mut forged);
+		forged.append(&H256::random());
+		discovery3.send_packet(PACKET_PONG, &ep1.address, &forged.drain());
+		let forged = discovery3.send_queue.pop_front().unwrap();
+		assert!(discovery1.on_packet(&forged.payload, ep3.address.clone()).unwrap().is_none());
+		assert!(!discovery1.in_table(&node3.id));
+
+		discovery3.on_packet(&ping.payload, ep1.address.clone()).unwrap();
+		let pong = discovery3.send_queue.pop_front().unwrap();
+		let updates = discovery1.on_packet(&pong.payload, ep3.address.clone()).unwrap().unwrap();
+		assert!(updates.added.contains_key(&node3.id));
+		assert!(discovery1.in_table(&node3.id));
+		assert!(discovery1.pending_bonds.is_empty());
+	}
+
+	#[test]
+	fn pending_bonds_are_capped() {
+		let key1 = Random.generate().unwrap();
+		let key2 = Random.generate().unwrap();
+		let ep1 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40469").unwrap(), udp_port: 40469 };
+		let ep2 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40470").unwrap(), udp_port: 40470 };
+		let mut discovery1 = Discovery::new(&key1, ep1.address.clone(), ep1.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		let mut discovery2 = Discovery::new(&key2, ep2.address.clone(), ep2.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+
+		let nodes: Vec<_> = (0..MAX_PENDING_BONDS + 50).map(|_| NodeEntry { id: NodeId::random(), endpoint: ep2.clone() }).collect();
+		deliver_neighbours(&mut discovery1, &mut discovery2, &nodes);
+		assert_eq!(discovery1.pending_bonds.len(), MAX_PENDING_BONDS);
+		assert_eq!(discovery1.send_queue.len(), MAX_PENDING_BONDS);
+		assert_eq!(discovery1.discovery_stats().nodes, 0);
+	}
+
+	#[test]
+	fn full_bucket_neighbours_round_trip() {
+		let key1 = Random.generate().unwrap();
+		let key2 = Random.generate().unwrap();
+		let ep1 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40456").unwrap(), udp_port: 40456 };
+		let ep2 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40457").unwrap(), udp_port: 40457 };
+		let mut discovery1 = Discovery::new(&key1, ep1.address.clone(), ep1.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		let mut discovery2 = Discovery::new(&key2, ep2.address.clone(), ep2.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		let nodes: Vec<_> = (0..BUCKET_SIZE).map(|i| NodeEntry {
+			id: NodeId::random(),
+			endpoint: NodeEndpoint { address: SocketAddr::new(ep2.address.ip(), 41000 + i as u16), udp_port: 41000 + i as u16 },
+		}).collect();
+		discovery2.init_node_list(nodes.clone());
+
+		discovery1.add_node(NodeEntry { id: key2.public().clone(), endpoint: ep2.clone() });
+		discovery1.send_queue.clear();
+		discovery1.refresh();
+		discovery1.round();
+		let find_node = discovery1.send_queue.pop_front().unwrap();
+		assert_eq!(find_node.address, ep2.address);
+		discovery2.on_packet(&find_node.payload, ep1.address.clone()).unwrap();
+
+		// The answer spans several packets, each within the datagram limit.
+		let answer: Vec<_> = discovery2.send_queue.drain(..).collect();
+		assert_eq!(answer.len(), (BUCKET_SIZE + MAX_NEIGHBOURS_PER_PACKET - 1) / MAX_NEIGHBOURS_PER_PACKET);
+		for datagramm in &answer {
+			assert_eq!(datagramm.address, ep1.address);
+			assert!(datagramm.payload.len() <= MAX_DATAGRAM_SIZE);
+			discovery1.on_packet(&datagramm.payload, ep2.address.clone()).unwrap();
+		}
+		for node in &nodes {
+			assert!(discovery1.pending_bonds.contains_key(&node.id));
+		}
+
+		// A repeated packet takes the answer over k entries.
+		assert!(discovery1.on_packet(&answer[0].payload, ep2.address.clone()).is_err());
+		assert_eq!(discovery1.discovery_stats().malformed_packets, 1);
+	}
+
+	#[test]
+	fn oversized_neighbours_are_rejected() {
+		let key1 = Random.generate().unwrap();
+		let key2 = Random.generate().unwrap();
+		let ep1 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40458").unwrap(), udp_port: 40458 };
+		let ep2 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40459").unwrap(), udp_port: 40459 };
+		let mut discovery1 = Discovery::new(&key1, ep1.address.clone(), ep1.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		let mut discovery2 = Discovery::new(&key2, ep2.address.clone(), ep2.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+
+		// More than k entries in a single packet, too large for a datagram.
+		let nodes: Vec<_> = (0..BUCKET_SIZE + 4).map(|_| NodeEntry { id: NodeId::random(), endpoint: ep2.clone() }).collect();
+		let mut rlp = RlpStream::new_list(1);
+		rlp.begin_list(nodes.len());
+		for n in &nodes {
+			rlp.begin_list(4);
+			n.endpoint.to_rlp(&mut rlp);
+			rlp.append(&n.id);
+		}
+		let payload = rlp.out();
+		discovery2.send_packet(PACKET_NEIGHBOURS, &ep1.address, &payload);
+		let oversized = discovery2.send_queue.pop_front().unwrap();
+		assert!(oversized.payload.len() > MAX_DATAGRAM_SIZE);
+		assert!(discovery1.on_packet(&oversized.payload, ep2.address.clone()).is_err());
+		assert!(discovery1.pending_bonds.is_empty());
+		assert!(discovery1.send_queue.is_empty());
+
+		// The same entry count is refused even if the datagram was cut to size.
+		assert!(discovery1.on_neighbours(&UntrustedRlp::new(&payload), key2.public(), &ep2.address).is_err());
+		assert!(discovery1.pending_bonds.is_empty());
+
+		// The sender is ignored after too many malformed packets.
+		for _ in 2..MAX_VIOLATIONS {
+			assert!(discovery1.on_packet(&oversized.payload, ep2.address.clone()).is_err());
+		}
+		assert_eq!(discovery1.discovery_stats().malformed_packets, MAX_VIOLATIONS as u64);
+		let valid = NodeEntry { id: NodeId::random(), endpoint: ep2.clone() };
+		assert!(deliver_neighbours(&mut discovery1, &mut discovery2, &[valid.clone()]).iter().all(|u| u.is_none()));
+		assert!(!discovery1.pending_bonds.contains_key(&valid.id));
+
+		// Other nodes at the same source address are not.
+		let key3 = Random.generate().unwrap();
+		let ep3 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40474").unwrap(), udp_port: 40474 };
+		let mut discovery3 = Discovery::new(&key3, ep3.address.clone(), ep3.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		let other = NodeEntry { id: NodeId::random(), endpoint: ep3.clone() };
+		discovery3.send_packet(PACKET_NEIGHBOURS, &ep1.address, &Discovery::prepare_neighbours_packets(&[other.clone()])[0]);
+		let forged = discovery3.send_queue.pop_front().unwrap();
+		assert!(discovery1.on_packet(&forged.payload, ep2.address.clone()).is_ok());
+		assert!(discovery1.pending_bonds.contains_key(&other.id));
+
+		// The sender is accepted again once its malformed packets are forgiven.
+		discovery1.violations.get_mut(key2.public()).unwrap().updated -= VIOLATION_DECAY_NS;
+		assert!(!discovery1.is_violator(key2.public(), time::precise_time_ns()));
+		deliver_neighbours(&mut discovery1, &mut discovery2, &[valid.clone()]);
+		assert!(discovery1.pending_bonds.contains_key(&valid.id));
+	}
+
+	#[test]
+	fn forged_pong_is_rejected() {
+		let key1 = Random.generate().unwrap();
+		let key2 = Random.generate().unwrap();
+		let ep1 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40475").unwrap(), udp_port: 40475 };
+		let ep2 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40476").unwrap(), udp_port: 40476 };
+		let mut discovery1 = Discovery::new(&key1, ep1.address.clone(), ep1.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		let mut discovery2 = Discovery::new(&key2, ep2.address.clone(), ep2.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		let ms = 1000_000;
+		discovery1.add_node(NodeEntry { id: key2.public().clone(), endpoint: ep2.clone() });
+		let ping = discovery1.send_queue.pop_front().unwrap();
+		let hash = H256::from_slice(&ping.payload[0..32]);
+
+		// A pong echoing a hash we never sent is ignored.
+		let pong = |discovery2: &mut Discovery, echo_hash: &H256| {
+			let mut rlp = RlpStream::new_list(2);
+			ep1.to_rlp_list(&mut rlp);
+			rlp.append(echo_hash);
+			discovery2.send_packet(PACKET_PONG, &ep1.address, &rlp.drain());
+			discovery2.send_queue.pop_front().unwrap().payload
+		};
+		let forged = pong(&mut discovery2, &H256::random());
+		discovery1.on_packet(&forged, ep2.address.clone()).unwrap();
+		assert!(discovery1.observed_addresses().is_empty());
+		assert_eq!(discovery1.discovery_stats().rejected.unmatched_pong, 1);
+		assert_eq!(discovery1.discovery_stats().bonds, 0);
+
+		// So is a matching pong after the deadline.
+		for ping in discovery1.in_flight_pings.get_mut(key2.public()).unwrap() {
+			ping.deadline = time::precise_time_ns() - 10 * ms;
+		}
+		discovery1.on_packet(&pong(&mut discovery2, &hash), ep2.address.clone()).unwrap();
+		assert_eq!(discovery1.discovery_stats().rejected.late_pong, 1);
+		assert_eq!(discovery1.discovery_stats().bonds, 0);
+
+		// Each ping is answered once.
+		let hash = discovery1.ping(key2.public(), &ep2).unwrap();
+		let answer = pong(&mut discovery2, &hash);
+		discovery1.on_packet(&answer, ep2.address.clone()).unwrap();
+		assert_eq!(discovery1.observed_addresses(), vec![ep1.address.ip()]);
+		assert_eq!(discovery1.discovery_stats().bonds, 1);
+		discovery1.on_packet(&answer, ep2.address.clone()).unwrap();
+		assert_eq!(discovery1.discovery_stats().rejected.unmatched_pong, 2);
+		assert_eq!(discovery1.discovery_stats().bonds, 1);
+	}
+
+	#[test]
+	fn expired_ping_is_rejected() {
+		let key1 = Random.generate().unwrap();
+		let key2 = Random.generate().unwrap();
+		let ep1 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40477").unwrap(), udp_port: 40477 };
+		let ep2 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40478").unwrap(), udp_port: 40478 };
+		let mut discovery1 = Discovery::new(&key1, ep1.address.clone(), ep1.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		let discovery2 = Discovery::new(&key2, ep2.address.clone(), ep2.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+
+		let mut rlp = RlpStream::new_list(3);
+		rlp.append(&PROTOCOL_VERSION);
+		ep2.to_rlp_list(&mut rlp);
+		ep1.to_rlp_list(&mut rlp);
+		let expired = discovery2.sign_packet(PACKET_PING, &rlp.drain(), time::get_time().sec as u32 - 10).unwrap();
+		assert!(discovery1.on_packet(&expired, ep2.address.clone()).is_err());
+		assert!(discovery1.send_queue.is_empty());
+		assert!(!discovery1.in_table(key2.public()));
+		let stats = discovery1.discovery_stats();
+		assert_eq!(stats.rejected.expired, 1);
+		assert_eq!(stats.received.ping, 0);
+
+		// Within the clock skew allowance the ping is answered.
+		discovery1.set_clock_skew(Duration::from_secs(30));
+		assert!(discovery1.on_packet(&expired, ep2.address.clone()).is_ok());
+		assert_eq!(discovery1.send_queue.len(), 1);
+		assert!(discovery1.in_table(key2.public()));
+	}
+
+	#[test]
+	fn clock_skew_allowance() {
+		let key = Random.generate().unwrap();
+		let ep = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40479").unwrap(), udp_port: 40479 };
+		let mut discovery = Discovery::new(&key, ep.address.clone(), ep.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		let now = 1_500_000_000;
+		assert!(discovery.check_timestamp_at(now, now).is_ok());
+		assert!(discovery.check_timestamp_at(now - 1, now).is_err());
+
+		discovery.set_clock_skew(Duration::from_secs(5));
+		assert!(discovery.check_timestamp_at(now - 5, now).is_ok());
+		assert!(discovery.check_timestamp_at(now - 6, now).is_err());
+		assert_eq!(discovery.discovery_stats().rejected.expired, 2);
+	}
+
+	#[test]
+	fn signature_cache_hit_path() {
+		use std::time::Instant;
+		let key1 = Random.generate().unwrap();
+		let key2 = Random.generate().unwrap();
+		let ep1 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40480").unwrap(), udp_port: 40480 };
+		let ep2 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40481").unwrap(), udp_port: 40481 };
+		let mut discovery1 = Discovery::new(&key1, ep1.address.clone(), ep1.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		let mut discovery2 = Discovery::new(&key2, ep2.address.clone(), ep2.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+
+		// An unsolicited pong is dropped as soon as its sender is known, so recovery is most of the work.
+		let mut rlp = RlpStream::new_list(2);
+		ep1.to_rlp_list(&mut rlp);
+		rlp.append(&H256::random());
+		discovery2.send_packet(PACKET_PONG, &ep1.address, &rlp.drain());
+		let pong = discovery2.send_queue.pop_front().unwrap().payload;
+		let rounds = 200;
+		let deliver = |discovery: &mut Discovery| {
+			let start = Instant::now();
+			for _ in 0..rounds {
+				discovery.on_packet(&pong, ep2.address.clone()).unwrap();
+			}
+			start.elapsed()
+		};
+
+		discovery1.set_packet_cache(0, Duration::from_secs(0));
+		let uncached = deliver(&mut discovery1);
+		assert_eq!(discovery1.discovery_stats().signature_cache_hits, 0);
+		discovery1.set_packet_cache(16, Duration::from_secs(0));
+		let cached = deliver(&mut discovery1);
+		assert_eq!(discovery1.discovery_stats().signature_cache_hits, rounds - 1);
+		assert_eq!(discovery1.discovery_stats().rejected.unmatched_pong, 2 * rounds);
+		assert!(cached < uncached, "Cached: {:?}, uncached: {:?}", cached, uncached);
+	}
+
+	#[test]
+	fn signature_cache_never_collides() {
+		let keys: Vec<_> = (0..3).map(|_| Random.generate().unwrap()).collect();
+		let eps: Vec<_> = (40482..40485).map(|port| NodeEndpoint { address: SocketAddr::from_str(&format!("127.0.0.1:{}", port)).unwrap(), udp_port: port }).collect();
+		let mut discovery = Discovery::new(&keys[0], eps[0].address.clone(), eps[0].clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		let sender1 = Discovery::new(&keys[1], eps[1].address.clone(), eps[1].clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		let sender2 = Discovery::new(&keys[2], eps[2].address.clone(), eps[2].clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+
+		// The same data signed by two nodes.
+		let mut rlp = RlpStream::new_list(2);
+		eps[0].to_rlp_list(&mut rlp);
+		rlp.append(&H256::random());
+		let payload = rlp.drain();
+		let timestamp = time::get_time().sec as u32 + 60;
+		let packet1 = sender1.sign_packet(PACKET_PONG, &payload, timestamp).unwrap();
+		let packet2 = sender2.sign_packet(PACKET_PONG, &payload, timestamp).unwrap();
+		let hash1 = H256::from_slice(&packet1[0..32]);
+		let hash2 = H256::from_slice(&packet2[0..32]);
+		assert!(hash1 != hash2);
+		for _ in 0..2 {
+			assert_eq!(discovery.recover_sender(&hash1, &packet1).unwrap(), *keys[1].public());
+			assert_eq!(discovery.recover_sender(&hash2, &packet2).unwrap(), *keys[2].public());
+		}
+		assert_eq!(discovery.discovery_stats().signature_cache_hits, 2);
+
+		// A different signature over the same data is recovered on its own.
+		let mut tampered = packet1.clone();
+		tampered[32] ^= 1;
+		let hash = keccak(&tampered[32..]);
+		tampered[0..32].clone_from_slice(&hash);
+		if let Ok(id) = discovery.recover_sender(&hash, &tampered) {
+			assert!(id != *keys[1].public());
+		}
+		assert_eq!(discovery.discovery_stats().signature_cache_hits, 2);
+	}
+
+	#[test]
+	fn duplicate_packets_are_dropped() {
+		let key1 = Random.generate().unwrap();
+		let key2 = Random.generate().unwrap();
+		let ep1 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40485").unwrap(), udp_port: 40485 };
+		let ep2 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40486").unwrap(), udp_port: 40486 };
+		let mut discovery1 = Discovery::new(&key1, ep1.address.clone(), ep1.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		let mut discovery2 = Discovery::new(&key2, ep2.address.clone(), ep2.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		discovery1.set_packet_cache(16, Duration::from_secs(10));
+
+		discovery2.add_node(NodeEntry { id: key1.public().clone(), endpoint: ep1.clone() });
+		let ping = discovery2.send_queue.pop_front().unwrap().payload;
+		discovery1.on_packet(&ping, ep2.address.clone()).unwrap();
+		discovery1.on_packet(&ping, ep2.address.clone()).unwrap();
+		assert_eq!(discovery1.send_queue.len(), 1);
+		assert_eq!(discovery1.discovery_stats().rejected.duplicate, 1);
+		assert_eq!(discovery1.discovery_stats().received.ping, 1);
+
+		// A copy from another address is not dropped.
+		let other = SocketAddr::from_str("127.0.0.1:40487").unwrap();
+		discovery1.on_packet(&ping, other).unwrap();
+		assert_eq!(discovery1.send_queue.len(), 2);
+
+		// Nor is one after the window.
+		discovery1.set_packet_cache(16, Duration::from_millis(1));
+		::std::thread::sleep(Duration::from_millis(5));
+		discovery1.on_packet(&ping, ep2.address.clone()).unwrap();
+		assert_eq!(discovery1.send_queue.len(), 3);
+		assert_eq!(discovery1.discovery_stats().rejected.duplicate, 1);
+	}
+
+	#[test]
+	fn adaptive_lookup_schedule() {
+		let key = Random.generate().unwrap();
+		let ep = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40488").unwrap(), udp_port: 40488 };
+		let mut discovery = Discovery::new(&key, ep.address.clone(), ep.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		discovery.add_node(NodeEntry { id: NodeId::random(), endpoint: ep.clone() });
+		discovery.set_adaptive(true, Duration::from_secs(60), Duration::from_secs(1800));
+		let sec = 1000_000_000;
+		let start = 1000 * sec;
+		let lookups = |discovery: &Discovery| discovery.discovery_stats().sent.find_node;
+
+		// Below min_peers lookups run at the full rate.
+		discovery.set_peer_count(4, 5, 25);
+		assert_eq!(discovery.discovery_stats().activity, 100);
+		assert_eq!(discovery.discovery_stats().lookup_interval_ms, 60_000);
+		discovery.round_at(start);
+		assert_eq!(lookups(&discovery), 1);
+		discovery.round_at(start + 59 * sec);
+		assert_eq!(lookups(&discovery), 1);
+
+		// At max_peers discovery goes nearly dormant.
+		discovery.set_peer_count(25, 5, 25);
+		assert_eq!(discovery.discovery_stats().activity, 0);
+		assert_eq!(discovery.discovery_stats().lookup_interval_ms, 1800_000);
+		discovery.round_at(start + 61 * sec);
+		assert_eq!(lookups(&discovery), 1);
+
+		// In between the rate scales with the number of peers.
+		discovery.set_peer_count(5, 5, 25);
+		assert_eq!(discovery.discovery_stats().activity, 100);
+		discovery.set_peer_count(15, 5, 25);
+		assert_eq!(discovery.discovery_stats().activity, 50);
+		assert_eq!(discovery.discovery_stats().lookup_interval_ms, 930_000);
+		discovery.round_at(start + 62 * sec);
+		assert_eq!(lookups(&discovery), 1);
+
+		// Falling below min_peers resumes lookups on the next round.
+		discovery.set_peer_count(3, 5, 25);
+		discovery.round_at(start + 63 * sec);
+		assert_eq!(lookups(&discovery), 2);
+
+		// Without adaptive scheduling the peer count has no effect.
+		discovery.set_adaptive(false, Duration::from_secs(60), Duration::from_secs(1800));
+		discovery.set_peer_count(25, 5, 25);
+		assert_eq!(discovery.discovery_stats().activity, 100);
+		assert_eq!(discovery.discovery_stats().lookup_interval_ms, 60_000);
+	}
+
+	fn find_node_packet(sender: &mut Discovery, to: &NodeEndpoint, target: &NodeId) -> Bytes {
+		let rlp = encode_list(&(&[target.clone()][..]));
+		sender.send_packet(PACKET_FIND_NODE, &to.udp_address(), &rlp);
+		sender.send_queue.pop_back().unwrap().payload
+	}
+
+	/// Send `count` FindNode requests and return the number of them that were answered.
+	fn served_find_nodes(discovery: &mut Discovery, sender: &mut Discovery, count: usize) -> usize {
+		let target = NodeId::random();
+		let mut answered = 0;
+		for _ in 0..count {
+			let packet = find_node_packet(sender, &discovery.public_endpoint, &target);
+			discovery.on_packet(&packet, sender.public_endpoint.address).unwrap();
+			if !discovery.send_queue.is_empty() {
+				answered += 1;
+			}
+			discovery.send_queue.clear();
+		}
+		answered
+	}
+
+	#[test]
+	fn find_node_requests_are_rate_limited() {
+		let key1 = Random.generate().unwrap();
+		let key2 = Random.generate().unwrap();
+		let key3 = Random.generate().unwrap();
+		let ep1 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40471").unwrap(), udp_port: 40471 };
+		let ep2 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40472").unwrap(), udp_port: 40472 };
+		let ep3 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40473").unwrap(), udp_port: 40473 };
+		let mut discovery1 = Discovery::new(&key1, ep1.address.clone(), ep1.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		let mut discovery2 = Discovery::new(&key2, ep2.address.clone(), ep2.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		let mut discovery3 = Discovery::new(&key3, ep3.address.clone(), ep3.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		discovery1.set_find_node_limits(
+			FindNodeRateLimit { requests_per_minute: 6, burst: 3, targets_per_minute: 0 },
+			FindNodeRateLimit { requests_per_minute: 60, burst: 20, targets_per_minute: 0 },
+		);
+		// Give the node something to answer with.
+		discovery1.add_node(NodeEntry { id: NodeId::random(), endpoint: ep2.clone() });
+		discovery1.send_queue.clear();
+
+		assert_eq!(served_find_nodes(&mut discovery1, &mut discovery2, 10), 3);
+		assert_eq!(discovery1.discovery_stats().dropped_find_node, 7);
+
+		// Twenty seconds refill two requests.
+		discovery1.find_node_quotas.get_mut(key2.public()).unwrap().last_request -= 20 * 1000_000_000;
+		assert_eq!(served_find_nodes(&mut discovery1, &mut discovery2, 5), 2);
+		assert_eq!(discovery1.discovery_stats().dropped_find_node, 10);
+
+		// Reserved nodes get the higher limits once they are in the table.
+		let mut reserved = HashSet::new();
+		reserved.insert(key3.public().clone());
+		discovery1.set_reserved_nodes(reserved);
+		discovery3.add_node(NodeEntry { id: key1.public().clone(), endpoint: ep1.clone() });
+		let ping = discovery3.send_queue.pop_front().unwrap();
+		discovery1.on_packet(&ping.payload, ep3.address.clone()).unwrap();
+		discovery1.send_queue.clear();
+		assert_eq!(served_find_nodes(&mut discovery1, &mut discovery3, 10), 10);
+	}
+
+	#[test]
+	fn find_node_targets_are_limited() {
+		let limit = FindNodeRateLimit { requests_per_minute: 0, burst: 0, targets_per_minute: 2 };
+		let mut quota = FindNodeQuota::new(&limit, 0);
+		let targets: Vec<NodeId> = (0..3).map(|_| NodeId::random()).collect();
+		assert!(quota.allow(&limit, &targets[0], 0));
+		assert!(quota.allow(&limit, &targets[1], 1));
+		assert!(!quota.allow(&limit, &targets[2], 2));
+		// Repeated targets are not limited.
+		assert!(quota.allow(&limit, &targets[0], 3));
+		assert!(quota.allow(&limit, &targets[2], MINUTE_NS));
+		assert!(!quota.allow(&limit, &targets[1], MINUTE_NS + 1));
+	}
+
+	#[test]
+	fn rejects_non_global_addresses() {
+		let key = Random.generate().unwrap();
+		let listen = SocketAddr::from_str("127.0.0.1:40450").unwrap();
+		let public = NodeEndpoint { address: SocketAddr::from_str("22.99.55.44:40450").unwrap(), udp_port: 40450 };
+		let mut discovery = Discovery::new(&key, listen, public, 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		let entry = |address: &str| NodeEntry { id: NodeId::random(), endpoint: NodeEndpoint::from_str(address).unwrap() };
+		let addresses = ["22.99.55.45:30303", "10.0.0.1:30303", "127.0.0.1:30303", "169.254.0.1:30303", "224.0.0.1:30303", "192.0.2.1:30303", "[fd00::1]:30303"];
+
+		let allowed: Vec<bool> = addresses.iter().map(|a| discovery.is_allowed(&entry(a))).collect();
+		assert_eq!(allowed, vec![true, false, false, false, false, false, false]);
+
+		discovery.set_allow_non_global_ips(true);
+		let allowed: Vec<bool> = addresses.iter().map(|a| discovery.is_allowed(&entry(a))).collect();
+		assert_eq!(allowed, vec![true, true, true, true, false, false, true]);
+	}
+
+	#[test]
+	fn local_discovery_accepts_non_global_addresses() {
+		let key = Random.generate().unwrap();
+		let ep = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40451").unwrap(), udp_port: 40451 };
+		let mut discovery = Discovery::new(&key, ep.address.clone(), ep.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		discovery.set_allow_non_global_ips(false);
+		let entry = |address: &str| NodeEntry { id: NodeId::random(), endpoint: NodeEndpoint::from_str(address).unwrap() };
+		assert!(discovery.is_allowed(&entry("10.0.0.1:30303")));
+		assert!(discovery.is_allowed(&entry("127.0.0.2:30303")));
+		assert!(!discovery.is_allowed(&entry("239.0.0.1:30303")));
+
+		discovery.set_ip_lists(vec!["10.0.0.0/8".parse().unwrap()], vec!["10.1.0.0/16".parse().unwrap()]);
+		assert!(discovery.is_allowed(&entry("10.0.0.1:30303")));
+		assert!(!discovery.is_allowed(&entry("10.1.0.1:30303")));
+		assert!(!discovery.is_allowed(&entry("127.0.0.2:30303")));
 	}
 
 	#[test]
 	fn removes_expired() {
 		let key = Random.generate().unwrap();
 		let ep = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40446").unwrap(), udp_port: 40447 };
-		let mut discovery = Discovery::new(&key, ep.address.clone(), ep.clone(), 0, IpFilter::default());
+		let mut discovery = Discovery::new(&key, ep.address.clone(), ep.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
 		for _ in 0..1200 {
 			discovery.add_node(NodeEntry { id: NodeId::random(), endpoint: ep.clone() });
 		}
 		assert!(Discovery::nearest_node_entries(&NodeId::new(), &discovery.node_buckets).len() <= 16);
-		let removed = discovery.check_expired(true).len();
+		let removed = discovery.check_expired(time::precise_time_ns(), true).len();
 		assert!(removed > 0);
 	}
 
+	fn challenged_node(discovery: &mut Discovery, ep: &NodeEndpoint, sent_at: u64) -> (NodeId, usize) {
+		let id = NodeId::random();
+		let id_hash = keccak(&id);
+		let index = Discovery::distance(&discovery.id_hash, &id_hash) as usize;
+		discovery.node_buckets[index].nodes.push_back(BucketEntry {
+			address: NodeEntry { id: id.clone(), endpoint: ep.clone() },
+			timeout: Some(sent_at),
+			ping_attempts: 1,
+			id_hash: id_hash,
+		});
+		discovery.node_buckets[index].pending = Some(NodeEntry { id: NodeId::random(), endpoint: ep.clone() });
+		(id, index)
+	}
+
+	#[test]
+	fn ping_retries_before_eviction() {
+		let key = Random.generate().unwrap();
+		let ep = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40448").unwrap(), udp_port: 40448 };
+		let stats = Arc::new(NetworkStats::new());
+		let mut discovery = Discovery::new(&key, ep.address.clone(), ep.clone(), 0, IpFilter::default(), stats.clone());
+		discovery.set_ping_policy(Duration::from_millis(100), 2);
+		let ms = 1000_000;
+		let (id, index) = challenged_node(&mut discovery, &ep, 1000 * ms);
+
+		// not timed out yet
+		assert!(discovery.check_expired(1099 * ms, false).is_empty());
+		assert!(discovery.send_queue.is_empty());
+
+		// two retries, each with a fresh timeout
+		assert!(discovery.check_expired(1100 * ms, false).is_empty());
+		assert_eq!(discovery.send_queue.len(), 1);
+		assert_eq!(discovery.node_buckets[index].nodes[0].ping_attempts, 2);
+		assert!(discovery.check_expired(1150 * ms, false).is_empty());
+		assert!(discovery.check_expired(1200 * ms, false).is_empty());
+		assert_eq!(discovery.send_queue.len(), 2);
+		assert_eq!(stats.discovery_ping_retries(), 2);
+		assert_eq!(stats.discovery_ping_failures(), 0);
+
+		// all attempts failed, the pending node takes the slot
+		let pending = discovery.node_buckets[index].pending.as_ref().unwrap().id.clone();
+		let removed = discovery.check_expired(1300 * ms, false);
+		assert!(removed.contains(&id));
+		assert_eq!(stats.discovery_ping_failures(), 1);
+		assert_eq!(discovery.node_buckets[index].nodes.len(), 1);
+		assert_eq!(discovery.node_buckets[index].nodes[0].address.id, pending);
+	}
+
+	#[test]
+	fn answered_challenge_keeps_node() {
+		let key = Random.generate().unwrap();
+		let ep = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40449").unwrap(), udp_port: 40449 };
+		let mut discovery = Discovery::new(&key, ep.address.clone(), ep.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		discovery.set_ping_policy(Duration::from_millis(100), 2);
+		let ms = 1000_000;
+		let (id, index) = challenged_node(&mut discovery, &ep, 1000 * ms);
+		assert!(discovery.check_expired(1100 * ms, false).is_empty());
+		discovery.clear_ping(&id);
+		assert!(discovery.node_buckets[index].pending.is_none());
+		assert!(discovery.check_expired(5000 * ms, false).is_empty());
+		assert_eq!(discovery.node_buckets[index].nodes[0].address.id, id);
+	}
+
 	#[test]
 	fn find_nearest_saturated() {
 		use super::*;
@@ -635,6 +2260,7 @@ mod tests {
 			buckets[0].nodes.push_back(BucketEntry {
 				address: NodeEntry { id: NodeId::new(), endpoint: ep.clone() },
 				timeout: None,
+				ping_attempts: 0,
 				id_hash: keccak(NodeId::new()),
 			});
 		}
@@ -646,7 +2272,7 @@ mod tests {
 	fn packets() {
 		let key = Random.generate().unwrap();
 		let ep = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40447").unwrap(), udp_port: 40447 };
-		let mut discovery = Discovery::new(&key, ep.address.clone(), ep.clone(), 0, IpFilter::default());
+		let mut discovery = Discovery::new(&key, ep.address.clone(), ep.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
 		discovery.check_timestamps = false;
 		let from = SocketAddr::from_str("99.99.99.99:40445").unwrap();
 
@@ -713,10 +2339,10 @@ mod tests {
 		let key2 = Random.generate().unwrap();
 		let ep1 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40344").unwrap(), udp_port: 40344 };
 		let ep2 = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40345").unwrap(), udp_port: 40345 };
-		let mut discovery1 = Discovery::new(&key1, ep1.address.clone(), ep1.clone(), 0, IpFilter::default());
-		let mut discovery2 = Discovery::new(&key2, ep2.address.clone(), ep2.clone(), 0, IpFilter::default());
+		let mut discovery1 = Discovery::new(&key1, ep1.address.clone(), ep1.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		let mut discovery2 = Discovery::new(&key2, ep2.address.clone(), ep2.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
 
-		discovery1.ping(&ep2);
+		discovery1.ping(key2.public(), &ep2);
 		let ping_data = discovery1.send_queue.pop_front().unwrap();
 		discovery2.on_packet(&ping_data.payload, ep1.address.clone()).ok();
 		let pong_data = discovery2.send_queue.pop_front().unwrap();
@@ -724,4 +2350,128 @@ mod tests {
 		let rlp = UntrustedRlp::new(&data[1..]);
 		assert_eq!(ping_data.payload[0..32], rlp.val_at::<Vec<u8>>(1).unwrap()[..])
 	}
+
+	#[test]
+	fn nearest_nodes_are_ordered_by_distance() {
+		let key = Random.generate().unwrap();
+		let ep = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40460").unwrap(), udp_port: 40460 };
+		let mut discovery = Discovery::new(&key, ep.address.clone(), ep.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		discovery.init_node_list((0..64).map(|_| NodeEntry { id: NodeId::random(), endpoint: ep.clone() }).collect());
+		let table: Vec<H256> = discovery.node_buckets.iter().flat_map(|b| b.nodes.iter().map(|n| n.id_hash.clone())).collect();
+		assert!(table.len() > BUCKET_SIZE);
+
+		for target in &[table[0], H256::random()] {
+			let nearest = discovery.nearest_nodes(target);
+			assert_eq!(nearest.len(), BUCKET_SIZE);
+			for node in &nearest {
+				assert_eq!(node.distance, Discovery::distance(target, &keccak(node.id)));
+				assert!(node.last_seen.is_none());
+			}
+			assert!(nearest.windows(2).all(|w| w[0].distance <= w[1].distance));
+			// No node left out is closer than the furthest one returned.
+			let furthest = nearest.last().unwrap().distance;
+			assert!(table.iter().all(|h| nearest.iter().any(|n| keccak(n.id) == *h) || Discovery::distance(target, h) >= furthest));
+		}
+		assert_eq!(discovery.nearest_nodes(&table[0])[0].distance, 0);
+	}
+
+	#[test]
+	fn targeted_lookup_finds_nearest_nodes() {
+		let keys: Vec<KeyPair> = (0..3).map(|_| Random.generate().unwrap()).collect();
+		let addresses: Vec<SocketAddr> = ["127.0.0.1:40461", "127.0.0.1:40494", "127.0.0.1:40495"].iter().map(|a| SocketAddr::from_str(a).unwrap()).collect();
+		let endpoint = |i: usize| NodeEndpoint { address: addresses[i], udp_port: addresses[i].port() };
+		let mut nodes: Vec<Discovery> = (0..3).map(|i| Discovery::new(&keys[i], addresses[i], endpoint(i), 0, IpFilter::default(), Arc::new(NetworkStats::new()))).collect();
+		// The first node only knows the second one, which knows the third.
+		nodes[0].init_node_list(vec![NodeEntry { id: keys[1].public().clone(), endpoint: endpoint(1) }]);
+		nodes[1].init_node_list(vec![NodeEntry { id: keys[2].public().clone(), endpoint: endpoint(2) }]);
+
+		let (sender, receiver) = mpsc::channel();
+		nodes[0].find_nearest(keccak(keys[2].public()), sender);
+		for _ in 0..DISCOVERY_MAX_STEPS {
+			nodes[0].step_targeted_lookups(time::precise_time_ns());
+			loop {
+				let mut delivered = false;
+				for i in 0..nodes.len() {
+					let queued: Vec<Datagramm> = nodes[i].send_queue.drain(..).collect();
+					for datagramm in queued {
+						if let Some(j) = addresses.iter().position(|a| *a == datagramm.address) {
+							nodes[j].on_packet(&datagramm.payload, addresses[i]).ok();
+							delivered = true;
+						}
+					}
+				}
+				if !delivered {
+					break;
+				}
+			}
+			for (result, nearest) in nodes[0].take_finished_lookups() {
+				result.send(nearest).unwrap();
+			}
+		}
+
+		let nearest = receiver.try_recv().unwrap();
+		assert_eq!(nearest.len(), 2);
+		assert_eq!(nearest[0].id, *keys[2].public());
+		assert_eq!(nearest[0].distance, 0);
+		assert_eq!(nearest[0].endpoint.address, addresses[2]);
+		assert_eq!(nearest[1].id, *keys[1].public());
+		assert!(nodes[0].targeted_lookups.is_empty());
+	}
+
+	#[test]
+	fn send_queue_skips_expired_packets() {
+		let key = Random.generate().unwrap();
+		let ep = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40496").unwrap(), udp_port: 40496 };
+		let mut discovery = Discovery::new(&key, ep.address.clone(), ep.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		let now = 1_500_000_000;
+		for (i, expires) in [now - 1, now + 60, now, now + 60, now + 60].iter().enumerate() {
+			discovery.send_to(PACKET_FIND_NODE, vec![i as u8], ep.address.clone(), *expires);
+		}
+
+		// The socket takes two packets, then would block.
+		let mut sent = Vec::new();
+		let drained = {
+			let Discovery { ref mut send_queue, ref mut metrics, .. } = discovery;
+			drain_send_queue(send_queue, metrics, now, |data| {
+				if sent.len() == 2 {
+					return Ok(None);
+				}
+				sent.push(data.payload[0]);
+				Ok(Some(data.payload.len()))
+			})
+		};
+		assert!(!drained);
+		assert_eq!(sent, vec![1, 3]);
+		assert_eq!(discovery.send_queue.len(), 1);
+		assert_eq!(discovery.discovery_stats().expired_outgoing, 2);
+
+		// Everything still queued expires before the socket is writable again.
+		let drained = {
+			let Discovery { ref mut send_queue, ref mut metrics, .. } = discovery;
+			drain_send_queue(send_queue, metrics, now + 60, |_| panic!("Expired packet sent"))
+		};
+		assert!(drained);
+		assert_eq!(discovery.discovery_stats().expired_outgoing, 3);
+	}
+
+	#[test]
+	fn pongs_jump_send_queue() {
+		let key = Random.generate().unwrap();
+		let ep = NodeEndpoint { address: SocketAddr::from_str("127.0.0.1:40497").unwrap(), udp_port: 40497 };
+		let mut discovery = Discovery::new(&key, ep.address.clone(), ep.clone(), 0, IpFilter::default(), Arc::new(NetworkStats::new()));
+		discovery.set_send_queue_limit(3);
+		let expires = time::get_time().sec as u64 + 60;
+		discovery.send_to(PACKET_FIND_NODE, vec![1], ep.address.clone(), expires);
+		discovery.send_to(PACKET_FIND_NODE, vec![2], ep.address.clone(), expires);
+		discovery.send_to(PACKET_PONG, vec![3], ep.address.clone(), expires);
+		let queued: Vec<u8> = discovery.send_queue.iter().map(|d| d.payload[0]).collect();
+		assert_eq!(queued, vec![3, 1, 2]);
+
+		// A full queue drops our own requests but makes room for pongs.
+		discovery.send_to(PACKET_FIND_NODE, vec![4], ep.address.clone(), expires);
+		discovery.send_to(PACKET_PONG, vec![5], ep.address.clone(), expires);
+		let queued: Vec<u8> = discovery.send_queue.iter().map(|d| d.payload[0]).collect();
+		assert_eq!(queued, vec![3, 5, 1]);
+		assert_eq!(discovery.discovery_stats().dropped_outgoing, 2);
+	}
 }
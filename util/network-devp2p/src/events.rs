@@ -0,0 +1,232 @@
+// Copyright 2015-2017 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Network event subscriptions.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{RecvError, TryRecvError, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use parking_lot::{Mutex, Condvar};
+use network::{DisconnectReason, PeerCapabilityInfo, PeerId};
+use connection_filter::ConnectionDirection;
+use node_table::NodeId;
+
+/// Connection lifecycle event delivered to subscribers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetworkEvent {
+	/// A session with a peer has been established.
+	PeerConnected {
+		/// Peer node id.
+		node_id: NodeId,
+		/// Remote socket address, if known.
+		address: Option<SocketAddr>,
+		/// Direction of the connection.
+		direction: ConnectionDirection,
+		/// Capabilities announced by the peer.
+		caps: Vec<PeerCapabilityInfo>,
+	},
+	/// An established session has been closed.
+	PeerDisconnected {
+		/// Peer node id.
+		node_id: NodeId,
+		/// Disconnect reason sent or received, if any.
+		reason: Option<DisconnectReason>,
+	},
+	/// Packets held with `send_when_ready` were dropped because the session closed before it was ready.
+	DeferredPacketsDropped {
+		/// Peer the packets were held for.
+		peer: PeerId,
+		/// Number of packets dropped.
+		packets: usize,
+	},
+	/// Discovery has found a new node.
+	Discovered {
+		/// Node id.
+		node_id: NodeId,
+	},
+	/// The public address of this node has changed.
+	ExternalAddressChanged {
+		/// New public address.
+		address: SocketAddr,
+		/// New enode URL.
+		url: String,
+	},
+}
+
+struct EventQueue {
+	events: Mutex<VecDeque<NetworkEvent>>,
+	available: Condvar,
+	capacity: usize,
+	dropped: AtomicUsize,
+	/// Set under the `events` lock once the subscriber set is dropped.
+	closed: AtomicBool,
+}
+
+/// Receiving end of an event subscription. When the queue is full the oldest event is dropped,
+/// so a slow consumer never blocks the network.
+pub struct EventReceiver {
+	queue: Arc<EventQueue>,
+}
+
+impl EventReceiver {
+	/// Wait for the next event. Fails once the network service is dropped and all events have been received.
+	pub fn recv(&self) -> Result<NetworkEvent, RecvError> {
+		let mut events = self.queue.events.lock();
+		loop {
+			if let Some(event) = events.pop_front() {
+				return Ok(event);
+			}
+			if self.queue.closed.load(Ordering::SeqCst) {
+				return Err(RecvError);
+			}
+			self.queue.available.wait(&mut events);
+		}
+	}
+
+	/// Get the next event without waiting.
+	pub fn try_recv(&self) -> Result<NetworkEvent, TryRecvError> {
+		let mut events = self.queue.events.lock();
+		match events.pop_front() {
+			Some(event) => Ok(event),
+			None if self.queue.closed.load(Ordering::SeqCst) => Err(TryRecvError::Disconnected),
+			None => Err(TryRecvError::Empty),
+		}
+	}
+
+	/// Wait for the next event for at most `timeout`.
+	pub fn recv_timeout(&self, timeout: Duration) -> Result<NetworkEvent, RecvTimeoutError> {
+		let deadline = Instant::now() + timeout;
+		let mut events = self.queue.events.lock();
+		loop {
+			if let Some(event) = events.pop_front() {
+				return Ok(event);
+			}
+			if self.queue.closed.load(Ordering::SeqCst) {
+				return Err(RecvTimeoutError::Disconnected);
+			}
+			if self.queue.available.wait_until(&mut events, deadline).timed_out() {
+				return events.pop_front().ok_or(RecvTimeoutError::Timeout);
+			}
+		}
+	}
+
+	/// Number of events dropped because the queue was full.
+	pub fn dropped(&self) -> usize {
+		self.queue.dropped.load(Ordering::Relaxed)
+	}
+}
+
+/// Set of event subscribers shared by the network service and the host.
+#[derive(Default)]
+pub struct EventSubscribers {
+	queues: Mutex<Vec<Weak<EventQueue>>>,
+}
+
+impl EventSubscribers {
+	/// Create an empty set.
+	pub fn new() -> EventSubscribers {
+		EventSubscribers::default()
+	}
+
+	/// Add a subscriber that buffers at most `capacity` events.
+	pub fn subscribe(&self, capacity: usize) -> EventReceiver {
+		let queue = Arc::new(EventQueue {
+			events: Mutex::new(VecDeque::new()),
+			available: Condvar::new(),
+			capacity: if capacity == 0 { 1 } else { capacity },
+			dropped: AtomicUsize::new(0),
+			closed: AtomicBool::new(false),
+		});
+		self.queues.lock().push(Arc::downgrade(&queue));
+		EventReceiver { queue: queue }
+	}
+
+	/// Deliver an event to every subscriber. Subscribers whose receiver has been dropped are removed.
+	pub fn publish(&self, event: NetworkEvent) {
+		let mut queues = self.queues.lock();
+		queues.retain(|queue| queue.upgrade().is_some());
+		for queue in queues.iter().filter_map(|q| q.upgrade()) {
+			let mut events = queue.events.lock();
+			if events.len() >= queue.capacity {
+				events.pop_front();
+				queue.dropped.fetch_add(1, Ordering::Relaxed);
+			}
+			events.push_back(event.clone());
+			queue.available.notify_one();
+		}
+	}
+}
+
+impl Drop for EventSubscribers {
+	fn drop(&mut self) {
+		for queue in self.queues.lock().iter().filter_map(|q| q.upgrade()) {
+			let _events = queue.events.lock();
+			queue.closed.store(true, Ordering::SeqCst);
+			queue.available.notify_all();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use ethereum_types::H512;
+
+	fn discovered(id: u64) -> NetworkEvent {
+		NetworkEvent::Discovered { node_id: H512::from(id) }
+	}
+
+	#[test]
+	fn drops_oldest_when_full() {
+		let subscribers = EventSubscribers::new();
+		let receiver = subscribers.subscribe(2);
+		for i in 0..5 {
+			subscribers.publish(discovered(i));
+		}
+		assert_eq!(receiver.dropped(), 3);
+		assert_eq!(receiver.try_recv(), Ok(discovered(3)));
+		assert_eq!(receiver.try_recv(), Ok(discovered(4)));
+		assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+	}
+
+	#[test]
+	fn multiple_subscribers() {
+		let subscribers = EventSubscribers::new();
+		let first = subscribers.subscribe(10);
+		let second = subscribers.subscribe(10);
+		subscribers.publish(discovered(1));
+		assert_eq!(first.try_recv(), Ok(discovered(1)));
+		assert_eq!(second.try_recv(), Ok(discovered(1)));
+		drop(first);
+		subscribers.publish(discovered(2));
+		assert_eq!(second.try_recv(), Ok(discovered(2)));
+		assert_eq!(subscribers.queues.lock().len(), 1);
+	}
+
+	#[test]
+	fn closed_when_dropped() {
+		let subscribers = EventSubscribers::new();
+		let receiver = subscribers.subscribe(10);
+		subscribers.publish(discovered(1));
+		assert_eq!(receiver.recv_timeout(Duration::from_millis(10)), Ok(discovered(1)));
+		assert_eq!(receiver.recv_timeout(Duration::from_millis(10)), Err(RecvTimeoutError::Timeout));
+		drop(subscribers);
+		assert_eq!(receiver.recv(), Err(RecvError));
+	}
+}
@@ -17,13 +17,13 @@
 use std::sync::Arc;
 use rand::random;
 use hash::write_keccak;
-use mio::tcp::*;
+use mio::net::TcpStream;
 use ethereum_types::{H256, H520};
 use ethcore_bytes::Bytes;
 use rlp::*;
 use connection::{Connection};
 use node_table::NodeId;
-use stats::NetworkStats;
+use stats::{NetworkStats, HandshakeFailure};
 use io::{IoContext, StreamToken};
 use ethkey::{KeyPair, Public, Secret, recover, sign, Generator, Random};
 use crypto::{ecdh, ecies};
@@ -49,6 +49,8 @@ enum HandshakeState {
 pub struct Handshake {
 	/// Remote node public key
 	pub id: NodeId,
+	/// Our own node id, refused as the remote one
+	local_id: NodeId,
 	/// Underlying connection
 	pub connection: Connection,
 	/// Handshake state
@@ -71,6 +73,8 @@ pub struct Handshake {
 	pub ack_cipher: Bytes,
 	/// This Handshake is marked for deleteion flag
 	pub expired: bool,
+	/// Network statistics, for counting failed handshakes
+	stats: Arc<NetworkStats>,
 }
 
 const V4_AUTH_PACKET_SIZE: usize = 307;
@@ -85,7 +89,8 @@ impl Handshake {
 	pub fn new(token: StreamToken, id: Option<&NodeId>, socket: TcpStream, nonce: &H256, stats: Arc<NetworkStats>) -> Result<Handshake, Error> {
 		Ok(Handshake {
 			id: if let Some(id) = id { id.clone()} else { NodeId::new() },
-			connection: Connection::new(token, socket, stats),
+			local_id: NodeId::new(),
+			connection: Connection::new(token, socket, stats.clone()),
 			originated: false,
 			state: HandshakeState::New,
 			ecdhe: Random.generate()?,
@@ -96,6 +101,7 @@ impl Handshake {
 			auth_cipher: Bytes::new(),
 			ack_cipher: Bytes::new(),
 			expired: false,
+			stats: stats,
 		})
 	}
 
@@ -107,6 +113,7 @@ impl Handshake {
 	/// Start a handhsake
 	pub fn start<Message>(&mut self, io: &IoContext<Message>, host: &HostInfo, originated: bool) -> Result<(), Error> where Message: Send + Clone+ Sync + 'static {
 		self.originated = originated;
+		self.local_id = host.id().clone();
 		io.register_timer(self.connection.token, HANDSHAKE_TIMEOUT).ok();
 		if originated {
 			self.write_auth(io, host.secret(), host.id())?;
@@ -123,6 +130,11 @@ impl Handshake {
 		self.state == HandshakeState::StartSession
 	}
 
+	/// Check if our auth has been sent and no part of the ack has arrived yet.
+	pub fn awaiting_ack(&self) -> bool {
+		self.state == HandshakeState::ReadingAck && !self.connection.is_sending()
+	}
+
 	/// Readable IO handler. Drives the state change.
 	pub fn readable<Message>(&mut self, io: &IoContext<Message>, host: &HostInfo) -> Result<(), Error> where Message: Send + Clone + Sync + 'static {
 		if !self.expired() {
@@ -131,16 +143,20 @@ impl Handshake {
 					HandshakeState::New => {},
 					HandshakeState::StartSession => {},
 					HandshakeState::ReadingAuth => {
-						self.read_auth(io, host.secret(), &data)?;
+						let result = self.read_auth(io, host.secret(), &data);
+						self.note_failure(result, HandshakeFailure::AuthDecrypt)?;
 					},
 					HandshakeState::ReadingAuthEip8 => {
-						self.read_auth_eip8(io, host.secret(), &data)?;
+						let result = self.read_auth_eip8(io, host.secret(), &data);
+						self.note_failure(result, HandshakeFailure::AuthDecrypt)?;
 					},
 					HandshakeState::ReadingAck => {
-						self.read_ack(host.secret(), &data)?;
+						let result = self.read_ack(host.secret(), &data);
+						self.note_failure(result, HandshakeFailure::AckDecode)?;
 					},
 					HandshakeState::ReadingAckEip8 => {
-						self.read_ack_eip8(host.secret(), &data)?;
+						let result = self.read_ack_eip8(host.secret(), &data);
+						self.note_failure(result, HandshakeFailure::AckDecode)?;
 					},
 				}
 				if self.state == HandshakeState::StartSession {
@@ -152,6 +168,19 @@ impl Handshake {
 		Ok(())
 	}
 
+	/// Count a failed handshake step. Individual failures are only traced, `Host` logs a periodic summary.
+	fn note_failure<T>(&self, result: Result<T, Error>, failure: HandshakeFailure) -> Result<T, Error> {
+		if let Err(ref e) = result {
+			trace!(target: "network", "Handshake with {:?} failed ({:?}): {}", self.connection.remote_addr_str(), failure, e);
+			// `Host` tells a connection to ourselves from an impersonation and counts it
+			if let ErrorKind::SelfConnection = *e.kind() {
+				return result;
+			}
+			self.stats.inc_handshake_failure(failure);
+		}
+		result
+	}
+
 	/// Writabe IO handler.
 	pub fn writable<Message>(&mut self, io: &IoContext<Message>) -> Result<(), Error> where Message: Send + Clone + Sync + 'static {
 		if !self.expired() {
@@ -162,6 +191,9 @@ impl Handshake {
 
 	fn set_auth(&mut self, host_secret: &Secret, sig: &[u8], remote_public: &[u8], remote_nonce: &[u8], remote_version: u64) -> Result<(), Error> {
 		self.id.clone_from_slice(remote_public);
+		if self.id == self.local_id {
+			return Err(ErrorKind::SelfConnection.into());
+		}
 		self.remote_nonce.clone_from_slice(remote_nonce);
 		self.remote_version = remote_version;
 		let shared = *ecdh::agree(host_secret, &self.id)?;
@@ -174,7 +206,7 @@ impl Handshake {
 	fn read_auth<Message>(&mut self, io: &IoContext<Message>, secret: &Secret, data: &[u8]) -> Result<(), Error> where Message: Send + Clone + Sync + 'static {
 		trace!(target: "network", "Received handshake auth from {:?}", self.connection.remote_addr_str());
 		if data.len() != V4_AUTH_PACKET_SIZE {
-			debug!(target: "network", "Wrong auth packet size");
+			trace!(target: "network", "Wrong auth packet size");
 			return Err(ErrorKind::BadProtocol.into());
 		}
 		self.auth_cipher = data.to_vec();
@@ -191,7 +223,7 @@ impl Handshake {
 				// Try to interpret as EIP-8 packet
 				let total = (((data[0] as u16) << 8 | (data[1] as u16)) as usize) + 2;
 				if total < V4_AUTH_PACKET_SIZE {
-					debug!(target: "network", "Wrong EIP8 auth packet size");
+					trace!(target: "network", "Wrong EIP8 auth packet size");
 					return Err(ErrorKind::BadProtocol.into());
 				}
 				let rest = total - data.len();
@@ -220,7 +252,7 @@ impl Handshake {
 	fn read_ack(&mut self, secret: &Secret, data: &[u8]) -> Result<(), Error> {
 		trace!(target: "network", "Received handshake ack from {:?}", self.connection.remote_addr_str());
 		if data.len() != V4_ACK_PACKET_SIZE {
-			debug!(target: "network", "Wrong ack packet size");
+			trace!(target: "network", "Wrong ack packet size");
 			return Err(ErrorKind::BadProtocol.into());
 		}
 		self.ack_cipher = data.to_vec();
@@ -234,7 +266,7 @@ impl Handshake {
 				// Try to interpret as EIP-8 packet
 				let total = (((data[0] as u16) << 8 | (data[1] as u16)) as usize) + 2;
 				if total < V4_ACK_PACKET_SIZE {
-					debug!(target: "network", "Wrong EIP8 ack packet size");
+					trace!(target: "network", "Wrong EIP8 ack packet size");
 					return Err(ErrorKind::BadProtocol.into());
 				}
 				let rest = total - data.len();
@@ -334,7 +366,7 @@ mod test {
 	use super::*;
 	use ethereum_types::H256;
 	use io::*;
-	use mio::tcp::TcpStream;
+	use mio::net::TcpStream;
 	use stats::NetworkStats;
 	use ethkey::Public;
 
@@ -14,66 +14,117 @@
 // You should have received a copy of the GNU General Public License
 // along with Parity.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::net::{SocketAddr, SocketAddrV4, Ipv4Addr};
+use std::net::{SocketAddr, SocketAddrV4, Ipv4Addr, IpAddr};
 use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering};
 use std::ops::*;
-use std::cmp::{min, max};
+use std::cmp::{min, max, Reverse};
 use std::path::{Path, PathBuf};
 use std::io::{Read, Write, self};
 use std::fs;
+use std::thread;
+use std::time::{Duration, Instant};
 use ethkey::{KeyPair, Secret, Random, Generator};
 use hash::keccak;
+use rand::{self, Rng};
 use mio::*;
-use mio::deprecated::{EventLoop};
-use mio::tcp::*;
+use mio::net::{TcpListener, TcpStream};
+use net2::TcpBuilder;
+use ansi_term::Colour;
 use ethereum_types::H256;
 use rlp::*;
 use session::{Session, SessionData};
 use io::*;
-use PROTOCOL_VERSION;
+use {PROTOCOL_VERSION, UNCOMPRESSED_PROTOCOL_VERSION};
 use node_table::*;
-use network::{NetworkConfiguration, NetworkIoMessage, ProtocolId, PeerId, PacketId};
-use network::{NonReservedPeerMode, NetworkContext as NetworkContextTrait};
+use network::{NetworkConfiguration, NetworkIoMessage, ProtocolId, PeerId, PacketId, SocksConfig};
+use network::{NonReservedPeerMode, NetworkContext as NetworkContextTrait, PeerSelector, BroadcastResult, PeerReport, Severity, ProtocolPeerTarget, SlotReservation};
 use network::HostInfo as HostInfoTrait;
-use network::{SessionInfo, Error, ErrorKind, DisconnectReason, NetworkProtocolHandler};
-use stats::NetworkStats;
-use discovery::{Discovery, TableUpdates, NodeEntry};
-use ip_utils::{map_external_address, select_public_address};
+use network::{SessionInfo, Error, ErrorKind, DisconnectReason, NetworkProtocolHandler, ClientVersion, PeerTraffic};
+use network::{RequestHandle, encode_request, encode_response};
+use stats::{NetworkStats, HandshakeFailure, HandshakeFailures, DialFailure, CompressionCounters, FLAPPING_SESSION_SECS};
+use discovery::{Discovery, DiscoveryStats, TableUpdates, NodeEntry, NearNode};
+use lan_discovery::LanDiscovery;
+use socks;
+use packet_trace::PacketTrace;
+use peer_sampling;
+use boot_nodes::BootNodes;
+use peer_watermarks::{PeerWatermarks, PeerCountEvent};
+use startup_burst::{StartupBurst, round_dials};
+use peer_ticks::PeerTicks;
+use requests::{PendingRequests, PacketRoute};
+use events::{EventSubscribers, NetworkEvent};
+use timers::ProtocolTimers;
+use dial::{PendingDials, DialResult, DialError, DialedPeer};
+use ip_utils::{select_public_listen_address, ip_class, is_allowed_by_lists, IpClass};
+use ip_utils::{AddressDetector, AddressSource, ConfiguredAddress, DetectionContext, ExternalAddress, HttpProbeDetector, PeerQuorumDetector, UpnpDetector};
 use path::restrict_permissions_owner;
-use parking_lot::{Mutex, RwLock};
-use connection_filter::{ConnectionFilter, ConnectionDirection};
+use parking_lot::{Mutex, RwLock, Condvar};
+use time;
+use connection_filter::{ConnectionFilter, ConnectionDirection, ConnectionContext, NodeIdAllowlistFilter, FilterAudit, FilterStats, FilterDecision, PendingDecisions, parse_node_id};
 
 type Slab<T> = ::slab::Slab<T, usize>;
 
+// Size of the session token range. The session slab is sized within it by `session_capacity`.
 const MAX_SESSIONS: usize = 1024 + MAX_HANDSHAKES;
 const MAX_HANDSHAKES: usize = 1024;
 
 const DEFAULT_PORT: u16 = 30303;
 
+const MAX_LISTENERS: usize = 16;
+
+// Number of most recently contacted nodes used as seeds when no boot node is reachable.
+const EMERGENCY_SEEDS: usize = 8;
+
+// Misbehaviour score added for reports of each severity. Fatal reports ban the node.
+const MINOR_MISBEHAVIOUR_PENALTY: u32 = 10;
+const MAJOR_MISBEHAVIOUR_PENALTY: u32 = 50;
+
+// Maximum length of the client identifier sent in the Hello packet.
+const MAX_CLIENT_VERSION_LEN: usize = 256;
+
+// Minimum interval between handshake failure summaries in the log, in nanoseconds.
+const HANDSHAKE_SUMMARY_INTERVAL_NS: u64 = 60 * 1000_000_000;
+// Interval between session churn summaries in the log, in nanoseconds.
+const CHURN_SUMMARY_INTERVAL_NS: u64 = 60 * 60 * 1000_000_000;
+
 // StreamToken/TimerToken
-const TCP_ACCEPT: StreamToken = SYS_TIMER + 1;
 const IDLE: TimerToken = SYS_TIMER + 2;
 const DISCOVERY: StreamToken = SYS_TIMER + 3;
 const DISCOVERY_REFRESH: TimerToken = SYS_TIMER + 4;
 const DISCOVERY_ROUND: TimerToken = SYS_TIMER + 5;
 const NODE_TABLE: TimerToken = SYS_TIMER + 6;
+const EXTERNAL_ADDRESS: TimerToken = SYS_TIMER + 7;
+const LAN_DISCOVERY: StreamToken = SYS_TIMER + 8;
+const LAN_ANNOUNCE: TimerToken = SYS_TIMER + 9;
+const RESERVED_RESOLVE: TimerToken = SYS_TIMER + 10;
+const FILTER_DECISIONS: TimerToken = SYS_TIMER + 11;
+const STARTUP_BURST: TimerToken = SYS_TIMER + 12;
+const PEER_TICKS: TimerToken = SYS_TIMER + 13;
+const REQUEST_TIMEOUTS: TimerToken = SYS_TIMER + 14;
 const FIRST_SESSION: StreamToken = 0;
 const LAST_SESSION: StreamToken = FIRST_SESSION + MAX_SESSIONS - 1;
 const USER_TIMER: TimerToken = LAST_SESSION + 256;
 const SYS_TIMER: TimerToken = LAST_SESSION + 1;
+const TCP_ACCEPT: StreamToken = SYS_TIMER + 32;
+const LAST_TCP_ACCEPT: StreamToken = TCP_ACCEPT + MAX_LISTENERS - 1;
 
 // Timeouts
-// for IDLE TimerToken
-const MAINTENANCE_TIMEOUT: u64 = 1000;
-// for DISCOVERY_REFRESH TimerToken
-const DISCOVERY_REFRESH_TIMEOUT: u64 = 60_000;
+// lower bound of the IDLE TimerToken interval
+const MIN_MAINTAIN_INTERVAL_MS: u64 = 100;
 // for DISCOVERY_ROUND TimerToken
 const DISCOVERY_ROUND_TIMEOUT: u64 = 300;
-// for NODE_TABLE TimerToken
+// for NODE_TABLE TimerToken if periodic saving is disabled
 const NODE_TABLE_TIMEOUT: u64 = 300_000;
+// for FILTER_DECISIONS TimerToken
+const FILTER_DECISIONS_TIMEOUT: u64 = 100;
+// for PEER_TICKS TimerToken, the resolution of peer ticks
+const PEER_TICKS_TIMEOUT: u64 = 20;
+// for REQUEST_TIMEOUTS TimerToken, the resolution of request timeouts
+const REQUEST_TIMEOUTS_TIMEOUT: u64 = 100;
 
 #[derive(Debug, PartialEq, Eq)]
 /// Protocol info
@@ -92,6 +143,65 @@ impl Encodable for CapabilityInfo {
 	}
 }
 
+/// Negotiated subprotocol of a connected peer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PeerProtocolInfo {
+	/// Protocol name, e.g. `eth`.
+	pub protocol: String,
+	/// Negotiated protocol version.
+	pub version: u8,
+}
+
+/// TCP options in effect for a peer connection.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PeerSocketInfo {
+	/// `TCP_NODELAY` flag.
+	pub nodelay: Option<bool>,
+	/// TCP keepalive idle time in seconds, if enabled.
+	pub keepalive_secs: Option<u64>,
+	/// Send buffer size as reported by the OS.
+	pub send_buffer_size: Option<usize>,
+	/// Receive buffer size as reported by the OS.
+	pub recv_buffer_size: Option<usize>,
+}
+
+/// Information about an active session.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PeerInfo {
+	/// Peer public key as hex.
+	pub id: String,
+	/// Peer enode URL.
+	pub enode: String,
+	/// Remote socket address.
+	pub remote_address: String,
+	/// True if the peer has connected to us.
+	pub inbound: bool,
+	/// Negotiated subprotocols.
+	pub protocols: Vec<PeerProtocolInfo>,
+	/// Client identifier the peer sent in Hello.
+	pub client_version: String,
+	/// Unix time in seconds at which the session became ready.
+	pub connected_since: u64,
+	/// Subprotocol payload bytes sent to the peer.
+	pub bytes_sent: u64,
+	/// Subprotocol payload bytes received from the peer.
+	pub bytes_received: u64,
+	/// TCP socket options.
+	pub socket: PeerSocketInfo,
+	/// True if the peer is a reserved node. Only reserved peers are kept in reserved-only mode.
+	pub reserved: bool,
+	/// Current misbehaviour score from protocol handler reports.
+	pub misbehaviour_score: u32,
+	/// True if the peer supports a protocol peer slots are reserved for.
+	pub reserved_slot: bool,
+	/// Negotiated p2p protocol version.
+	pub p2p_version: u32,
+	/// True if packets are snappy compressed.
+	pub compression: bool,
+	/// Payload sizes before and after compression.
+	pub compression_counters: CompressionCounters,
+}
+
 /// IO access point. This is passed to all IO handlers and provides an interface to the IO subsystem.
 pub struct NetworkContext<'s> {
 	io: &'s IoContext<NetworkIoMessage>,
@@ -100,6 +210,8 @@ pub struct NetworkContext<'s> {
 	session: Option<SharedSession>,
 	session_id: Option<StreamToken>,
 	_reserved_peers: &'s HashSet<NodeId>,
+	timers: &'s Mutex<ProtocolTimers>,
+	requests: &'s Mutex<PendingRequests>,
 }
 
 impl<'s> NetworkContext<'s> {
@@ -107,7 +219,7 @@ impl<'s> NetworkContext<'s> {
 	fn new(io: &'s IoContext<NetworkIoMessage>,
 		protocol: ProtocolId,
 		session: Option<SharedSession>, sessions: Arc<RwLock<Slab<SharedSession>>>,
-		reserved_peers: &'s HashSet<NodeId>) -> NetworkContext<'s> {
+		reserved_peers: &'s HashSet<NodeId>, timers: &'s Mutex<ProtocolTimers>, requests: &'s Mutex<PendingRequests>) -> NetworkContext<'s> {
 		let id = session.as_ref().map(|s| s.lock().token());
 		NetworkContext {
 			io: io,
@@ -116,6 +228,8 @@ impl<'s> NetworkContext<'s> {
 			session: session,
 			sessions: sessions,
 			_reserved_peers: reserved_peers,
+			timers: timers,
+			requests: requests,
 		}
 	}
 
@@ -125,6 +239,36 @@ impl<'s> NetworkContext<'s> {
 			_ => self.sessions.read().get(peer).cloned(),
 		}
 	}
+
+	fn schedule_timer(&self, token: TimerToken, ms: u64, once: bool) -> Result<(), Error> {
+		// The lock keeps the IO timer updates in the same order as the schedule changes.
+		let mut timers = self.timers.lock();
+		let (io_token, replaced) = match timers.add(self.protocol, token, once) {
+			Some(tokens) => tokens,
+			None => bail!(ErrorKind::TooManyTimers),
+		};
+		if let Some(replaced) = replaced {
+			self.io.clear_timer(replaced)?;
+		}
+		if once {
+			self.io.register_timer_once(io_token, ms)?;
+		} else {
+			self.io.register_timer(io_token, ms)?;
+		}
+		Ok(())
+	}
+
+	/// Ready peers of this protocol.
+	fn protocol_peers(&self) -> Vec<PeerId> {
+		self.sessions.read().iter().filter_map(|session| {
+			let s = session.lock();
+			if s.is_ready() && !s.expired() && s.have_capability(self.protocol) {
+				Some(s.token())
+			} else {
+				None
+			}
+		}).collect()
+	}
 }
 
 impl<'s> NetworkContextTrait for NetworkContext<'s> {
@@ -133,13 +277,88 @@ impl<'s> NetworkContextTrait for NetworkContext<'s> {
 	}
 
 	fn send_protocol(&self, protocol: ProtocolId, peer: PeerId, packet_id: PacketId, data: Vec<u8>) -> Result<(), Error> {
-		let session = self.resolve_session(peer);
-		if let Some(session) = session {
-			session.lock().send_packet(self.io, Some(protocol), packet_id as u8, &data)?;
-		} else  {
-			trace!(target: "network", "Send: Peer no longer exist")
+		match self.resolve_session(peer) {
+			Some(session) => session.lock().send_packet(self.io, Some(protocol), packet_id as u8, &data),
+			None => {
+				trace!(target: "network", "Send: Peer no longer exist");
+				Err(ErrorKind::PeerGone.into())
+			},
 		}
-		Ok(())
+	}
+
+	fn send_lossy(&self, peer: PeerId, packet_id: PacketId, data: Vec<u8>) {
+		if let Err(e) = self.send(peer, packet_id, data) {
+			trace!(target: "network", "Send to {} failed: {}", peer, e);
+		}
+	}
+
+	fn send_when_ready(&self, peer: PeerId, packet_id: PacketId, data: Vec<u8>) -> Result<(), Error> {
+		match self.resolve_session(peer) {
+			Some(session) => session.lock().send_when_ready(self.io, self.protocol, packet_id, data),
+			None => {
+				trace!(target: "network", "Send: Peer no longer exist");
+				Err(ErrorKind::PeerGone.into())
+			},
+		}
+	}
+
+	fn send_request(&self, peer: PeerId, packet_id: PacketId, data: Vec<u8>, timeout: Duration) -> Result<RequestHandle, Error> {
+		let deadline = time::precise_time_ns() + timeout.as_secs() * 1000_000_000 + timeout.subsec_nanos() as u64;
+		// Added before sending, the response may be read on another thread before the send returns.
+		let request = self.requests.lock().add(peer, self.protocol, packet_id, deadline);
+		if let Err(e) = self.send(peer, packet_id, encode_request(request.id, &data)) {
+			self.requests.lock().cancel(self.protocol, &request);
+			return Err(e);
+		}
+		Ok(request)
+	}
+
+	fn respond_to(&self, request: RequestHandle, data: Vec<u8>) -> Result<(), Error> {
+		self.send(request.peer, request.packet_id, encode_response(request.id, &data))
+	}
+
+	fn broadcast(&self, packet_id: PacketId, data: Vec<u8>, selector: PeerSelector) -> BroadcastResult {
+		let mut peers: Vec<(PeerId, SharedSession)> = self.sessions.read().iter().filter_map(|session| {
+			let s = session.lock();
+			if s.is_ready() && !s.expired() && s.have_capability(self.protocol) {
+				Some((s.token(), session.clone()))
+			} else {
+				None
+			}
+		}).collect();
+		match selector {
+			PeerSelector::AllPeers => {},
+			PeerSelector::RandomSubset(count) => {
+				rand::thread_rng().shuffle(&mut peers);
+				peers.truncate(count);
+			},
+			PeerSelector::Matching(predicate) => peers.retain(|&(peer, _)| predicate(peer)),
+		}
+
+		let mut result = BroadcastResult::default();
+		for (peer, session) in peers {
+			match session.lock().send_packet(self.io, Some(self.protocol), packet_id, &data) {
+				Ok(()) => result.sent.push(peer),
+				Err(e) => {
+					trace!(target: "network", "Broadcast to {} failed: {}", peer, e);
+					result.failed.push((peer, e));
+				},
+			}
+		}
+		result
+	}
+
+	fn sample_peers(&self, count: usize, exclude: &HashSet<PeerId>) -> Vec<PeerId> {
+		peer_sampling::sample(self.protocol_peers(), count, exclude, &mut rand::thread_rng())
+	}
+
+	fn sample_peers_weighted(&self, count: usize, exclude: &HashSet<PeerId>, weight: &Fn(PeerId) -> f64) -> Vec<PeerId> {
+		// Weights are taken once the session locks are released.
+		let peers: Vec<(PeerId, f64)> = self.protocol_peers().into_iter()
+			.filter(|peer| !exclude.contains(peer))
+			.map(|peer| (peer, weight(peer)))
+			.collect();
+		peer_sampling::sample_weighted(&peers, count, exclude, &mut rand::thread_rng())
 	}
 
 	fn respond(&self, packet_id: PacketId, data: Vec<u8>) -> Result<(), Error> {
@@ -152,12 +371,22 @@ impl<'s> NetworkContextTrait for NetworkContext<'s> {
 	}
 
 	fn disable_peer(&self, peer: PeerId) {
-		self.io.message(NetworkIoMessage::DisablePeer(peer))
+		self.io.message_self(NetworkIoMessage::DisablePeer(peer))
+			.unwrap_or_else(|e| warn!("Error sending network IO message: {:?}", e));
+	}
+
+	fn disconnect_peer(&self, peer: PeerId, reason: DisconnectReason, ban: bool) {
+		self.io.message_self(NetworkIoMessage::Disconnect { peer: peer, reason: reason, ban: ban })
+			.unwrap_or_else(|e| warn!("Error sending network IO message: {:?}", e));
+	}
+
+	fn report_peer(&self, peer: PeerId, report: PeerReport) {
+		self.io.message_self(NetworkIoMessage::ReportPeer { peer: peer, report: report })
 			.unwrap_or_else(|e| warn!("Error sending network IO message: {:?}", e));
 	}
 
-	fn disconnect_peer(&self, peer: PeerId) {
-		self.io.message(NetworkIoMessage::Disconnect(peer))
+	fn set_read_paused(&self, protocol: ProtocolId, paused: bool) {
+		self.io.message_self(NetworkIoMessage::SetReadPaused { protocol: protocol, paused: paused })
 			.unwrap_or_else(|e| warn!("Error sending network IO message: {:?}", e));
 	}
 
@@ -166,27 +395,73 @@ impl<'s> NetworkContextTrait for NetworkContext<'s> {
 	}
 
 	fn register_timer(&self, token: TimerToken, ms: u64) -> Result<(), Error> {
-		self.io.message(NetworkIoMessage::AddTimer {
-			token: token,
-			delay: ms,
-			protocol: self.protocol,
-		}).unwrap_or_else(|e| warn!("Error sending network IO message: {:?}", e));
+		self.schedule_timer(token, ms, false)
+	}
+
+	fn register_timer_once(&self, token: TimerToken, delay: Duration) -> Result<(), Error> {
+		self.schedule_timer(token, delay.as_secs() * 1000 + delay.subsec_nanos() as u64 / 1000_000, true)
+	}
+
+	fn cancel_timer(&self, token: TimerToken) -> Result<(), Error> {
+		let mut timers = self.timers.lock();
+		if let Some(io_token) = timers.cancel(self.protocol, token) {
+			self.io.clear_timer(io_token)?;
+		}
 		Ok(())
 	}
 
-	fn peer_client_version(&self, peer: PeerId) -> String {
-		self.resolve_session(peer).map_or("unknown".to_owned(), |s| s.lock().info.client_version.clone())
+	fn peer_client_version(&self, peer: PeerId) -> ClientVersion {
+		self.resolve_session(peer).map_or(ClientVersion::from("unknown"), |s| s.lock().info.client_version.clone())
 	}
 
 	fn session_info(&self, peer: PeerId) -> Option<SessionInfo> {
 		self.resolve_session(peer).map(|s| s.lock().info.clone())
 	}
 
+	fn peer_traffic(&self, peer: PeerId) -> PeerTraffic {
+		self.resolve_session(peer).map_or_else(PeerTraffic::default, |s| s.lock().traffic(self.protocol))
+	}
+
+	fn peer_compression_enabled(&self, peer: PeerId) -> bool {
+		self.resolve_session(peer).map_or(false, |s| {
+			let s = s.lock();
+			!s.expired() && s.compression_enabled()
+		})
+	}
+
+	fn queue_depth(&self, peer: PeerId) -> usize {
+		self.resolve_session(peer).map_or(0, |s| s.lock().queue_depth())
+	}
+
+	fn peer_socket_addr(&self, peer: PeerId) -> Option<SocketAddr> {
+		self.resolve_session(peer).and_then(|s| {
+			let s = s.lock();
+			if s.expired() { None } else { s.info.remote_socket_addr }
+		})
+	}
+
+	fn peer_direction(&self, peer: PeerId) -> Option<ConnectionDirection> {
+		self.resolve_session(peer).and_then(|s| {
+			let s = s.lock();
+			if s.expired() { None } else { Some(s.info.direction) }
+		})
+	}
+
 	fn protocol_version(&self, protocol: ProtocolId, peer: PeerId) -> Option<u8> {
 		let session = self.resolve_session(peer);
 		session.and_then(|s| s.lock().capability_version(protocol))
 	}
 
+	fn peer_protocols(&self, peer: PeerId) -> Vec<(ProtocolId, u8)> {
+		self.resolve_session(peer).map_or_else(Vec::new, |s| {
+			let s = s.lock();
+			if s.expired() {
+				return Vec::new();
+			}
+			s.info.capabilities.iter().map(|c| (c.protocol, c.version)).collect()
+		})
+	}
+
 	fn subprotocol_name(&self) -> ProtocolId { self.protocol }
 }
 
@@ -206,6 +481,17 @@ pub struct HostInfo {
 	pub local_endpoint: NodeEndpoint,
 	/// Public address + discovery port
 	pub public_endpoint: Option<NodeEndpoint>,
+	/// Node ids allowed to connect, `None` if all are.
+	pub node_allowlist: Option<Arc<NodeIdAllowlistFilter>>,
+	/// Packet tracer handed to new sessions.
+	pub packet_trace: PacketTrace,
+}
+
+impl HostInfo {
+	/// Current network configuration.
+	pub fn config(&self) -> &NetworkConfiguration {
+		&self.config
+	}
 }
 
 impl HostInfoTrait for HostInfo {
@@ -223,38 +509,149 @@ impl HostInfoTrait for HostInfo {
 	}
 
 	fn client_version(&self) -> &str {
-		&self.config.client_version
+		self.config.client_version_override.as_ref().unwrap_or(&self.config.client_version)
 	}
 }
 
 type SharedSession = Arc<Mutex<Session>>;
 
-#[derive(Copy, Clone)]
-struct ProtocolTimer {
-	pub protocol: ProtocolId,
-	pub token: TimerToken, // Handler level token
+/// Number of non-reserved peer slots available for each connection direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PeerSlots {
+	ingress: usize,
+	egress: usize,
+}
+
+impl PeerSlots {
+	fn new(min_peers: u32, max_peers: u32, inbound_ratio: Option<(u32, u32)>) -> PeerSlots {
+		let min_peers = min_peers as usize;
+		let max_peers = max(max_peers as usize, min_peers);
+		match inbound_ratio {
+			Some((inbound, outbound)) if inbound + outbound > 0 => {
+				let ingress = max_peers * inbound as usize / (inbound as usize + outbound as usize);
+				PeerSlots { ingress: ingress, egress: max_peers - ingress }
+			},
+			_ => PeerSlots {
+				// Outgoing connections are allowed as long as their count is <= min_peers
+				// Incoming connections are allowed to take all of the max_peers reserve, or at most half of the slots.
+				ingress: max(max_peers - min_peers, min_peers / 2),
+				egress: min_peers,
+			},
+		}
+	}
+
+	/// Check if a session fits into the slots. Counts must include the session itself.
+	fn allows(&self, originated: bool, egress_count: usize, ingress_count: usize) -> bool {
+		if originated {
+			egress_count <= self.egress
+		} else {
+			ingress_count <= self.ingress
+		}
+	}
+}
+
+/// Check if the session negotiated the protocol of the reservation in its minimum version or later.
+fn matches_reservation(session: &Session, reservation: &SlotReservation) -> bool {
+	session.capability_version(reservation.protocol).map_or(false, |v| v >= reservation.min_version)
+}
+
+/// What to do with the addresses of a node once its host name is resolved.
+enum AfterResolve {
+	/// Dial the node for the maintenance cycle.
+	Connect,
+	/// Dial the node for `Host::dial`.
+	Dial { force: bool },
+	/// Check the session with a reserved node against the addresses of its host name.
+	CheckReserved { hostname: String },
 }
 
+/// Host name lookup completed in the background.
+struct Resolved {
+	id: NodeId,
+	addresses: Vec<SocketAddr>,
+	then: AfterResolve,
+}
+
+/// What to do once the connection for a dial is made or has failed.
+#[derive(Clone)]
+enum AfterConnect {
+	/// Dial for the maintenance cycle.
+	Connect,
+	/// Dial of the addresses left after `failure`. A pending dial is resolved with `dial_error` if they fail too.
+	Fallback { failure: DialFailure, dial_error: Option<DialError> },
+	/// Dial for `Host::dial`.
+	Dial,
+}
+
+/// Connection through the outbound proxy completed in the background.
+struct Proxied {
+	id: NodeId,
+	connected: Result<(SocketAddr, ::std::net::TcpStream, Vec<SocketAddr>), DialFailure>,
+	then: AfterConnect,
+}
+
+/// Callback for peer count watermark transitions.
+pub type PeerCountCallback = Arc<Fn(PeerCountEvent) + Send + Sync>;
+
 /// Root IO handler. Manages protocol handlers, IO timers and network connections.
 pub struct Host {
 	pub info: RwLock<HostInfo>,
-	tcp_listener: Mutex<TcpListener>,
+	tcp_listeners: Mutex<Vec<TcpListener>>,
 	sessions: Arc<RwLock<Slab<SharedSession>>>,
+	/// Number of slots of the session slab.
+	session_slots: AtomicUsize,
 	discovery: Mutex<Option<Discovery>>,
+	lan_discovery: Mutex<Option<LanDiscovery>>,
 	nodes: RwLock<NodeTable>,
 	handlers: RwLock<HashMap<ProtocolId, Arc<NetworkProtocolHandler + Sync>>>,
-	timers: RwLock<HashMap<TimerToken, ProtocolTimer>>,
-	timer_counter: RwLock<usize>,
+	timers: Mutex<ProtocolTimers>,
 	stats: Arc<NetworkStats>,
 	reserved_nodes: RwLock<HashSet<NodeId>>,
 	stopping: AtomicBool,
-	filter: Option<Arc<ConnectionFilter>>,
+	filter: RwLock<Option<Arc<ConnectionFilter>>>,
+	/// Decisions of the connection filter.
+	filter_audit: Mutex<FilterAudit>,
+	/// Connections held until the connection filter decides.
+	pending_decisions: Mutex<PendingDecisions>,
+	resolver: RwLock<Arc<HostResolver>>,
+	/// Nodes whose host name is being resolved for a maintenance dial or a reserved node check.
+	resolving: Mutex<HashSet<NodeId>>,
+	/// Lookups completed in the background, waiting for the IO thread.
+	resolved: Arc<Mutex<Vec<Resolved>>>,
+	/// Nodes being connected to through the outbound proxy.
+	proxying: Mutex<HashSet<NodeId>>,
+	/// Connections through the proxy completed in the background, waiting for the IO thread.
+	proxied: Arc<Mutex<Vec<Proxied>>>,
+	boot_nodes: Mutex<BootNodes>,
+	/// Time and handshake failure counters of the last logged summary.
+	handshake_summary: Mutex<(u64, HandshakeFailures)>,
+	/// Time of the last logged session churn summary.
+	churn_summary: Mutex<u64>,
+	peer_watermarks: Mutex<PeerWatermarks>,
+	/// Faster dialing until `min_peers` is first reached.
+	startup_burst: Mutex<StartupBurst>,
+	/// Schedule of handler peer ticks. Held while ticks are delivered.
+	peer_ticks: Mutex<PeerTicks>,
+	/// Requests sent by handlers awaiting a response.
+	requests: Mutex<PendingRequests>,
+	peer_count_callback: RwLock<Option<PeerCountCallback>>,
+	events: Arc<EventSubscribers>,
+	external_address: Mutex<ExternalAddress>,
+	dials: Mutex<PendingDials>,
+	/// Addresses left to try for outbound connections still in the handshake.
+	dial_fallbacks: Mutex<HashMap<StreamToken, Vec<SocketAddr>>>,
+	/// Protocols whose handlers have paused reading.
+	paused_protocols: RwLock<HashSet<ProtocolId>>,
+	/// Sessions still sending their disconnect packet on shutdown.
+	shutdown_flush: Mutex<HashSet<StreamToken>>,
+	/// Signalled when a session leaves `shutdown_flush`.
+	shutdown_flushed: Condvar,
 }
 
 impl Host {
 	/// Create a new instance
-	pub fn new(mut config: NetworkConfiguration, stats: Arc<NetworkStats>, filter: Option<Arc<ConnectionFilter>>) -> Result<Host, Error> {
-		let mut listen_address = match config.listen_address {
+	pub fn new(mut config: NetworkConfiguration, stats: Arc<NetworkStats>, events: Arc<EventSubscribers>, filter: Option<Arc<ConnectionFilter>>) -> Result<Host, Error> {
+		let listen_address = match config.listen_address {
 			None => SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), DEFAULT_PORT)),
 			Some(addr) => addr,
 		};
@@ -272,39 +669,103 @@ impl Host {
 			},
 			|s| KeyPair::from_secret(s).expect("Error creating node secret key"))
 		};
-		let path = config.net_config_path.clone();
-		// Setup the server socket
-		let tcp_listener = TcpListener::bind(&listen_address)?;
-		listen_address = SocketAddr::new(listen_address.ip(), tcp_listener.local_addr()?.port());
-		debug!(target: "network", "Listening at {:?}", listen_address);
+		// Setup the server sockets. Failing to bind one of the addresses is not fatal as long as we listen on some.
+		let mut tcp_listeners = Vec::new();
+		let mut bind_error = None;
+		let addresses: Vec<SocketAddr> = Some(listen_address).into_iter().chain(config.additional_listen_addresses.iter().cloned()).take(MAX_LISTENERS).collect();
+		for (i, address) in addresses.into_iter().enumerate() {
+			let bound = if i == 0 { bind_listen_address(&address, &config) } else { bind_listener(&address) };
+			match bound {
+				Ok(listener) => {
+					debug!(target: "network", "Listening at {:?}", listener.local_addr());
+					tcp_listeners.push(listener);
+				},
+				Err(e) => {
+					warn!(target: "network", "Error binding to {}: {}", address, e);
+					bind_error = Some(e);
+				}
+			}
+		}
+		let configured_port = listen_address.port();
+		let listen_address = match tcp_listeners.first() {
+			Some(listener) => listener.local_addr()?,
+			None => return Err(bind_error.expect("no listeners means at least one bind failed; qed").into()),
+		};
+		// Advertise the fallback port instead of the configured one.
+		if configured_port != 0 && listen_address.port() != configured_port {
+			if let Some(ref mut public_address) = config.public_address {
+				if public_address.port() == configured_port {
+					public_address.set_port(listen_address.port());
+				}
+			}
+		}
 		let udp_port = config.udp_port.unwrap_or(listen_address.port());
 		let local_endpoint = NodeEndpoint { address: listen_address, udp_port: udp_port };
 
 		let boot_nodes = config.boot_nodes.clone();
 		let reserved_nodes = config.reserved_nodes.clone();
 		config.max_handshakes = min(config.max_handshakes, MAX_HANDSHAKES as u32);
+		config.maintain_interval = max(config.maintain_interval, Duration::from_millis(MIN_MAINTAIN_INTERVAL_MS));
+		config.max_dials_per_tick = max(config.max_dials_per_tick, 1);
+		let node_table = create_node_table(&config);
+		let boot_node_health = create_boot_nodes(&config);
+		let peer_watermarks = PeerWatermarks::new(config.peer_count_grace);
+		let startup_burst = StartupBurst::new(config.startup_burst.as_ref(), time::precise_time_ns());
+		let external_address = ExternalAddress::new(address_detectors(&config));
+		let node_allowlist = configured_node_allowlist(&config)?;
+		let filter_audit = FilterAudit::new(config.filter_audit_size);
+		if let Some(ref version) = config.client_version_override {
+			validate_client_version(version)?;
+		}
+		let protocol_version = if config.compression { PROTOCOL_VERSION } else { UNCOMPRESSED_PROTOCOL_VERSION };
+		let session_slots = session_capacity(&config, 0);
 
 		let mut host = Host {
 			info: RwLock::new(HostInfo {
 				keys: keys,
 				config: config,
 				nonce: H256::random(),
-				protocol_version: PROTOCOL_VERSION,
+				protocol_version: protocol_version,
 				capabilities: Vec::new(),
 				public_endpoint: None,
 				local_endpoint: local_endpoint,
+				node_allowlist: node_allowlist,
+				packet_trace: PacketTrace::default(),
 			}),
 			discovery: Mutex::new(None),
-			tcp_listener: Mutex::new(tcp_listener),
-			sessions: Arc::new(RwLock::new(Slab::new_starting_at(FIRST_SESSION, MAX_SESSIONS))),
-			nodes: RwLock::new(NodeTable::new(path)),
+			lan_discovery: Mutex::new(None),
+			tcp_listeners: Mutex::new(tcp_listeners),
+			sessions: Arc::new(RwLock::new(Slab::new_starting_at(FIRST_SESSION, session_slots))),
+			session_slots: AtomicUsize::new(session_slots),
+			nodes: RwLock::new(node_table),
 			handlers: RwLock::new(HashMap::new()),
-			timers: RwLock::new(HashMap::new()),
-			timer_counter: RwLock::new(USER_TIMER),
+			paused_protocols: RwLock::new(HashSet::new()),
+			timers: Mutex::new(ProtocolTimers::new(USER_TIMER)),
 			stats: stats,
 			reserved_nodes: RwLock::new(HashSet::new()),
 			stopping: AtomicBool::new(false),
-			filter: filter,
+			filter: RwLock::new(filter),
+			filter_audit: Mutex::new(filter_audit),
+			pending_decisions: Mutex::new(PendingDecisions::default()),
+			resolver: RwLock::new(Arc::new(DnsResolver)),
+			resolving: Mutex::new(HashSet::new()),
+			resolved: Arc::new(Mutex::new(Vec::new())),
+			proxying: Mutex::new(HashSet::new()),
+			proxied: Arc::new(Mutex::new(Vec::new())),
+			boot_nodes: Mutex::new(boot_node_health),
+			handshake_summary: Mutex::new((time::precise_time_ns(), HandshakeFailures::default())),
+			churn_summary: Mutex::new(time::precise_time_ns()),
+			peer_watermarks: Mutex::new(peer_watermarks),
+			startup_burst: Mutex::new(startup_burst),
+			peer_ticks: Mutex::new(PeerTicks::default()),
+			requests: Mutex::new(PendingRequests::default()),
+			peer_count_callback: RwLock::new(None),
+			events: events,
+			external_address: Mutex::new(external_address),
+			dials: Mutex::new(PendingDials::default()),
+			dial_fallbacks: Mutex::new(HashMap::new()),
+			shutdown_flush: Mutex::new(HashSet::new()),
+			shutdown_flushed: Condvar::new(),
 		};
 
 		for n in boot_nodes {
@@ -319,223 +780,1246 @@ impl Host {
 		Ok(host)
 	}
 
+	/// Add a boot node.
 	pub fn add_node(&mut self, id: &str) {
 		match Node::from_str(id) {
 			Err(e) => { debug!(target: "network", "Could not add node {}: {:?}", id, e); },
-			Ok(n) => {
-				let entry = NodeEntry { endpoint: n.endpoint.clone(), id: n.id.clone() };
+			Ok(mut n) => {
+				n.source = NodeSource::Boot;
+				// discovery only works with numeric endpoints
+				let entry = match n.hostname {
+					None => Some(NodeEntry { endpoint: n.endpoint.clone(), id: n.id.clone() }),
+					Some(_) => None,
+				};
 
 				self.nodes.write().add_node(n);
-				if let Some(ref mut discovery) = *self.discovery.lock() {
-					discovery.add_node(entry);
+				if let Some(entry) = entry {
+					if let Some(ref mut discovery) = *self.discovery.lock() {
+						discovery.add_node(entry);
+					}
 				}
 			}
 		}
 	}
 
+	/// Switch to another network: disconnect all peers, save the node table and load the one kept for
+	/// the new network, and replace the boot nodes. Reserved nodes are kept.
+	pub fn switch_network(&self, network_id: u64, boot_nodes: Vec<String>, io: &IoContext<NetworkIoMessage>) -> Result<(), Error> {
+		let mut boot: Vec<Node> = boot_nodes.iter().map(|n| Node::from_str(n)).collect::<Result<_, _>>()?;
+		for node in &mut boot {
+			node.source = NodeSource::Boot;
+		}
+		info!(target: "network", "Switching to network {}", network_id);
+
+		let mut to_kill = Vec::new();
+		for e in self.sessions.read().iter() {
+			let mut s = e.lock();
+			s.disconnect(io, DisconnectReason::DisconnectRequested);
+			to_kill.push(s.token());
+		}
+		for p in to_kill {
+			self.kill_connection(p, io, false);
+		}
+
+		let (table, boot_node_health) = {
+			let mut info = self.info.write();
+			info.config.network_id = Some(network_id);
+			info.config.boot_nodes = boot_nodes;
+			(create_node_table(&info.config), create_boot_nodes(&info.config))
+		};
+		*self.boot_nodes.lock() = boot_node_health;
+		let entries = {
+			let reserved = self.reserved_nodes.read();
+			let mut nodes = self.nodes.write();
+			let kept: Vec<Node> = reserved.iter().filter_map(|id| nodes.get(id).cloned()).collect();
+			// The table of the previous network is saved as it is dropped.
+			*nodes = table;
+			for node in kept.into_iter().chain(boot) {
+				nodes.add_node(node);
+			}
+			nodes.unordered_entries()
+		};
+		if let Some(ref mut discovery) = *self.discovery.lock() {
+			discovery.clear();
+			discovery.init_node_list(entries.clone());
+			discovery.add_node_list(entries);
+		}
+		Ok(())
+	}
+
 	pub fn add_reserved_node(&self, id: &str) -> Result<(), Error> {
 		let n = Node::from_str(id)?;
 
-		let entry = NodeEntry { endpoint: n.endpoint.clone(), id: n.id.clone() };
+		// discovery only works with numeric endpoints
+		let entry = match n.hostname {
+			None => Some(NodeEntry { endpoint: n.endpoint.clone(), id: n.id.clone() }),
+			Some(_) => None,
+		};
 		self.reserved_nodes.write().insert(n.id.clone());
-		self.nodes.write().add_node(Node::new(entry.id.clone(), entry.endpoint.clone()));
+		self.nodes.write().add_node(n);
 
+		let reserved = self.reserved_nodes.read().clone();
 		if let Some(ref mut discovery) = *self.discovery.lock() {
-			discovery.add_node(entry);
+			discovery.set_reserved_nodes(reserved);
+			if let Some(entry) = entry {
+				discovery.add_node(entry);
+			}
 		}
 
 		Ok(())
 	}
 
 	pub fn set_non_reserved_mode(&self, mode: NonReservedPeerMode, io: &IoContext<NetworkIoMessage>) {
-		let mut info = self.info.write();
-
-		if info.config.non_reserved_mode != mode {
+		let graceful = {
+			let mut info = self.info.write();
+			if info.config.non_reserved_mode == mode {
+				return;
+			}
 			info.config.non_reserved_mode = mode.clone();
-			drop(info);
-			if let NonReservedPeerMode::Deny = mode {
+			info.config.graceful_non_reserved_disconnect
+		};
+		debug!(target: "network", "Non-reserved peer mode set to {:?}", mode);
+
+		match mode {
+			// non-reserved peers are shed one at a time by `maintain_network`.
+			NonReservedPeerMode::Deny if graceful => {},
+			NonReservedPeerMode::Deny => {
 				// disconnect all non-reserved peers here.
 				let reserved: HashSet<NodeId> = self.reserved_nodes.read().clone();
 				let mut to_kill = Vec::new();
 				for e in self.sessions.read().iter() {
-					let mut s = e.lock();
-					{
-						let id = s.id();
-						if id.map_or(false, |id| reserved.contains(id)) {
-							continue;
-						}
+					let s = e.lock();
+					if s.id().map_or(false, |id| reserved.contains(id)) {
+						continue;
 					}
-
-					s.disconnect(io, DisconnectReason::ClientQuit);
 					to_kill.push(s.token());
 				}
 				for p in to_kill {
 					trace!(target: "network", "Disconnecting on reserved-only mode: {}", p);
-					self.kill_connection(p, io, false);
+					self.disconnect_gracefully(p, DisconnectReason::TooManyPeers, io);
 				}
-			}
+			},
+			NonReservedPeerMode::Accept => self.connect_peers(io),
 		}
 	}
 
-	pub fn remove_reserved_node(&self, id: &str) -> Result<(), Error> {
-		let n = Node::from_str(id)?;
-		self.reserved_nodes.write().remove(&n.id);
+	/// Current non-reserved peer mode.
+	pub fn non_reserved_mode(&self) -> NonReservedPeerMode {
+		self.info.read().config.non_reserved_mode.clone()
+	}
 
-		Ok(())
+	/// Update peer limits. A lower maximum stops new sessions from being accepted right away,
+	/// while peers over the limit are disconnected one per maintenance round.
+	pub fn set_peer_limits(&self, min_peers: u32, max_peers: u32, io: &IoContext<NetworkIoMessage>) {
+		{
+			let mut info = self.info.write();
+			info.config.min_peers = min(min_peers, max_peers);
+			info.config.max_peers = max_peers;
+		}
+		debug!(target: "network", "Peer limits set to {}..{}", min(min_peers, max_peers), max_peers);
+		self.connect_peers(io);
 	}
 
-	pub fn external_url(&self) -> Option<String> {
-		let info = self.info.read();
-		info.public_endpoint.as_ref().map(|e| format!("{}", Node::new(info.id().clone(), e.clone())))
+	/// Set the interval of the connection maintenance and the number of dials per round.
+	/// The interval is at least 100 ms, at least one dial is made per round.
+	pub fn set_maintenance_pace(&self, maintain_interval: Duration, max_dials_per_tick: u32, io: &IoContext<NetworkIoMessage>) {
+		let maintain_interval = max(maintain_interval, Duration::from_millis(MIN_MAINTAIN_INTERVAL_MS));
+		let interval_changed = {
+			let mut info = self.info.write();
+			let changed = info.config.maintain_interval != maintain_interval;
+			info.config.maintain_interval = maintain_interval;
+			info.config.max_dials_per_tick = max(max_dials_per_tick, 1);
+			changed
+		};
+		debug!(target: "network", "Maintenance every {:?}, up to {} dials", maintain_interval, max(max_dials_per_tick, 1));
+		if interval_changed {
+			io.register_timer(IDLE, self.maintain_interval_ms()).unwrap_or_else(|e| debug!(target: "network", "Error registering maintenance timer: {:?}", e));
+		}
 	}
 
-	pub fn local_url(&self) -> String {
+	/// Current interval of the connection maintenance and number of dials per round.
+	pub fn maintenance_pace(&self) -> (Duration, u32) {
 		let info = self.info.read();
-		format!("{}", Node::new(info.id().clone(), info.local_endpoint.clone()))
+		(info.config.maintain_interval, info.config.max_dials_per_tick)
 	}
 
-	pub fn stop(&self, io: &IoContext<NetworkIoMessage>) -> Result<(), Error> {
-		self.stopping.store(true, AtomicOrdering::Release);
-		let mut to_kill = Vec::new();
-		for e in self.sessions.read().iter() {
-			let mut s = e.lock();
-			s.disconnect(io, DisconnectReason::ClientQuit);
-			to_kill.push(s.token());
-		}
-		for p in to_kill {
-			trace!(target: "network", "Disconnecting on shutdown: {}", p);
-			self.kill_connection(p, io, true);
-		}
-		io.unregister_handler()?;
-		Ok(())
+	fn maintain_interval_ms(&self) -> u64 {
+		let interval = self.info.read().config.maintain_interval;
+		interval.as_secs() * 1000 + interval.subsec_nanos() as u64 / 1000_000
 	}
 
-	/// Get all connected peers.
-	pub fn connected_peers(&self) -> Vec<PeerId> {
-		let sessions = self.sessions.read();
-		let sessions = &*sessions;
+	/// Number of connected peers we have dialed and of those that have dialed us.
+	pub fn peer_counts(&self) -> (usize, usize) {
+		let (_, egress, ingress) = self.session_count();
+		(egress, ingress)
+	}
 
-		let mut peers = Vec::with_capacity(sessions.count());
-		for i in (0..MAX_SESSIONS).map(|x| x + FIRST_SESSION) {
-			if sessions.get(i).is_some() {
-				peers.push(i);
-			}
-		}
-		peers
+	/// Current minimum and maximum number of peers.
+	pub fn peer_limits(&self) -> (u32, u32) {
+		let info = self.info.read();
+		(info.config.min_peers, info.config.max_peers)
 	}
 
-	fn init_public_interface(&self, io: &IoContext<NetworkIoMessage>) -> Result<(), Error> {
-		if self.info.read().public_endpoint.is_some() {
-			return Ok(());
+	/// Keep `count` peer slots for peers supporting `protocol` in `min_version` or later, replacing
+	/// the previous reservation for the protocol. Zero `count` removes the reservation.
+	/// Connected peers are kept, the reservation applies to new sessions.
+	pub fn reserve_slots_for(&self, protocol: ProtocolId, min_version: u8, count: u32) {
+		let mut info = self.info.write();
+		let reservations = &mut info.config.slot_reservations;
+		reservations.retain(|r| r.protocol != protocol);
+		if count > 0 {
+			reservations.push(SlotReservation { protocol: protocol, min_version: min_version, slots: count });
 		}
-		let local_endpoint = self.info.read().local_endpoint.clone();
-		let public_address = self.info.read().config.public_address.clone();
-		let allow_ips = self.info.read().config.ip_filter.clone();
-		let public_endpoint = match public_address {
-			None => {
-				let public_address = select_public_address(local_endpoint.address.port());
-				let public_endpoint = NodeEndpoint { address: public_address, udp_port: local_endpoint.udp_port };
-				if self.info.read().config.nat_enabled {
-					match map_external_address(&local_endpoint) {
-						Some(endpoint) => {
-							info!("NAT mapped to external address {}", endpoint.address);
-							endpoint
-						},
-						None => public_endpoint
-					}
-				} else {
-					public_endpoint
-				}
-			}
-			Some(addr) => NodeEndpoint { address: addr, udp_port: local_endpoint.udp_port }
-		};
+		debug!(target: "network", "Reserved {} slots for {}/{}", count, String::from_utf8_lossy(&protocol), min_version);
+	}
 
-		self.info.write().public_endpoint = Some(public_endpoint.clone());
+	/// Advertise `version` in the Hello packet of sessions created from now on. Must be validated with
+	/// `validate_client_version`.
+	pub fn set_client_version(&self, version: String) {
+		debug!(target: "network", "Client version set to {}", version);
+		self.info.write().config.client_version_override = Some(version);
+	}
 
-		if let Some(url) = self.external_url() {
-			io.message(NetworkIoMessage::NetworkStarted(url)).unwrap_or_else(|e| warn!("Error sending IO notification: {:?}", e));
-		}
+	/// Client identifier advertised to new peers.
+	pub fn client_version(&self) -> String {
+		self.info.read().client_version().to_owned()
+	}
 
-		// Initialize discovery.
-		let discovery = {
-			let info = self.info.read();
-			if info.config.discovery_enabled && info.config.non_reserved_mode == NonReservedPeerMode::Accept {
-				let mut udp_addr = local_endpoint.address.clone();
-				udp_addr.set_port(local_endpoint.udp_port);
-				Some(Discovery::new(&info.keys, udp_addr, public_endpoint, DISCOVERY, allow_ips))
-			} else { None }
-		};
+	/// Set the peer count watermarks checked on every maintenance tick. A zero `low` disables the alerts.
+	pub fn set_peer_watermarks(&self, low: usize, high: usize) {
+		self.peer_watermarks.lock().set(low, high);
+	}
 
-		if let Some(mut discovery) = discovery {
-			discovery.init_node_list(self.nodes.read().unordered_entries());
-			discovery.add_node_list(self.nodes.read().unordered_entries());
-			*self.discovery.lock() = Some(discovery);
-			io.register_stream(DISCOVERY)?;
-			io.register_timer(DISCOVERY_REFRESH, DISCOVERY_REFRESH_TIMEOUT)?;
-			io.register_timer(DISCOVERY_ROUND, DISCOVERY_ROUND_TIMEOUT)?;
-		}
-		io.register_timer(NODE_TABLE, NODE_TABLE_TIMEOUT)?;
-		io.register_stream(TCP_ACCEPT)?;
-		Ok(())
+	/// Use the tracer for the packets of sessions created from now on.
+	pub fn set_packet_trace(&self, trace: PacketTrace) {
+		self.info.write().packet_trace = trace;
 	}
 
-	fn maintain_network(&self, io: &IoContext<NetworkIoMessage>) {
-		self.keep_alive(io);
-		self.connect_peers(io);
+	/// Use the resolver for nodes given by host name.
+	pub fn set_resolver(&self, resolver: Arc<HostResolver>) {
+		*self.resolver.write() = resolver;
 	}
 
-	fn have_session(&self, id: &NodeId) -> bool {
-		self.sessions.read().iter().any(|e| e.lock().info.id == Some(id.clone()))
+	/// Set the callback notified of peer count watermark transitions.
+	pub fn set_peer_count_callback(&self, callback: Option<PeerCountCallback>) {
+		*self.peer_count_callback.write() = callback;
 	}
 
-	// returns (handshakes, egress, ingress)
-	fn session_count(&self) -> (usize, usize, usize) {
-		let mut handshakes = 0;
-		let mut egress = 0;
-		let mut ingress = 0;
-		for s in self.sessions.read().iter() {
-			match s.try_lock() {
-				Some(ref s) if s.is_ready() && s.info.originated => egress += 1,
-				Some(ref s) if s.is_ready() && !s.info.originated => ingress += 1,
-				_ => handshakes +=1,
-			}
+	/// Replace the connection filter. `None` disables filtering.
+	/// If `recheck` is set, established sessions that the new filter rejects are disconnected.
+	pub fn set_connection_filter(&self, filter: Option<Arc<ConnectionFilter>>, recheck: bool, io: &IoContext<NetworkIoMessage>) {
+		let enabled = filter.is_some();
+		*self.filter.write() = filter;
+		if enabled && recheck {
+			self.recheck_sessions(io);
 		}
-		(handshakes, egress, ingress)
 	}
 
-	fn connecting_to(&self, id: &NodeId) -> bool {
-		self.sessions.read().iter().any(|e| e.lock().id() == Some(id))
+	/// Replace the node id allowlist. `None` allows all nodes. Sessions with nodes that are not on the new
+	/// list are disconnected.
+	pub fn set_node_allowlist(&self, ids: Option<Vec<NodeId>>, io: &IoContext<NetworkIoMessage>) {
+		let allowlist = match (ids, self.node_allowlist()) {
+			(None, _) => None,
+			(Some(ids), Some(allowlist)) => {
+				allowlist.set(ids);
+				Some(allowlist)
+			},
+			(Some(ids), None) => Some(Arc::new(NodeIdAllowlistFilter::new(ids, None).expect("Nothing to read without a file; qed"))),
+		};
+		self.info.write().node_allowlist = allowlist.clone();
+		if let Some(ref mut discovery) = *self.discovery.lock() {
+			discovery.set_node_allowlist(allowlist);
+		}
+		self.recheck_sessions(io);
 	}
 
-	fn keep_alive(&self, io: &IoContext<NetworkIoMessage>) {
-		let mut to_kill = Vec::new();
-		for e in self.sessions.read().iter() {
-			let mut s = e.lock();
-			if !s.keep_alive(io) {
-				s.disconnect(io, DisconnectReason::PingTimeout);
-				to_kill.push(s.token());
-			}
+	/// Reload the node id allowlist from its file. Sessions with nodes that are no longer on the list are disconnected.
+	pub fn reload_node_allowlist(&self, io: &IoContext<NetworkIoMessage>) -> Result<(), Error> {
+		if let Some(allowlist) = self.node_allowlist() {
+			allowlist.reload()?;
+			self.recheck_sessions(io);
 		}
-		for p in to_kill {
-			trace!(target: "network", "Ping timeout: {}", p);
+		Ok(())
+	}
+
+	/// Returns the node id allowlist, if enabled.
+	pub fn node_allowlist(&self) -> Option<Arc<NodeIdAllowlistFilter>> {
+		self.info.read().node_allowlist.clone()
+	}
+
+	/// Check a connection against the node id allowlist and the connection filter.
+	/// Decisions of the connection filter are counted in the filter audit.
+	fn connection_allowed(&self, context: &ConnectionContext) -> bool {
+		let allowlist = self.info.read().node_allowlist.clone();
+		if !allowlist.map_or(true, |l| l.connection_allowed_with_context(context)) {
+			return false;
+		}
+		match *self.filter.read() {
+			Some(ref filter) => {
+				let allowed = filter.connection_allowed_with_context(context);
+				self.filter_audit.lock().note(context, allowed, time::get_time().sec as u64);
+				allowed
+			},
+			None => true,
+		}
+	}
+
+	/// Decide on a connection whose Hello has been received. Returns `None` if the connection filter
+	/// decides later; the connection is parked and the decision is taken on the next call for `token`.
+	fn connection_decision(&self, token: StreamToken, context: &ConnectionContext) -> Option<bool> {
+		let now = time::get_time().sec as u64;
+		if let Some(allowed) = self.pending_decisions.lock().take(token) {
+			self.filter_audit.lock().note(context, allowed, now);
+			return Some(allowed);
+		}
+		let allowlist = self.info.read().node_allowlist.clone();
+		if !allowlist.map_or(true, |l| l.connection_allowed_with_context(context)) {
+			return Some(false);
+		}
+		let decision = match *self.filter.read() {
+			Some(ref filter) => filter.connection_decision(context),
+			None => return Some(true),
+		};
+		let allowed = match decision {
+			FilterDecision::Allow => true,
+			FilterDecision::Deny => false,
+			FilterDecision::Pending(receiver) => {
+				let (max, timeout) = {
+					let info = self.info.read();
+					(info.config.max_pending_filter_decisions, info.config.filter_decision_timeout)
+				};
+				let deadline = time::precise_time_ns() + timeout.as_secs() * 1000_000_000 + timeout.subsec_nanos() as u64;
+				if self.pending_decisions.lock().park(token, receiver, deadline, max) {
+					return None;
+				}
+				debug!(target: "network", "Too many connections waiting for the connection filter, refusing {:?}", context.connecting_id);
+				false
+			},
+		};
+		self.filter_audit.lock().note(context, allowed, now);
+		Some(allowed)
+	}
+
+	/// Continue the parked connections whose connection filter decision has arrived or timed out.
+	fn resume_parked_connections(&self, io: &IoContext<NetworkIoMessage>) {
+		let decided = self.pending_decisions.lock().poll(time::precise_time_ns());
+		for (token, timed_out) in decided {
+			if timed_out {
+				debug!(target: "network", "Connection filter decision for {} timed out", token);
+				self.filter_audit.lock().note_timeout();
+			}
+			let session = { self.sessions.read().get(token).cloned() };
+			match session {
+				Some(session) => session.lock().resume(),
+				None => {
+					self.pending_decisions.lock().remove(token);
+					continue;
+				},
+			}
+			self.session_readable(token, io);
+		}
+	}
+
+	/// Connection filter decisions with the most recent denials.
+	pub fn filter_stats(&self) -> FilterStats {
+		self.filter_audit.lock().stats()
+	}
+
+	/// Disconnect established sessions that are no longer allowed by the node id allowlist or the connection filter.
+	fn recheck_sessions(&self, io: &IoContext<NetworkIoMessage>) {
+		let self_id = self.info.read().id().clone();
+		let peers = self.session_addresses();
+		let reserved = self.reserved_nodes.read().clone();
+		let mut to_kill = Vec::new();
+		for e in self.sessions.read().iter() {
+			let s = e.lock();
+			if !s.is_ready() || s.expired() || s.is_draining() {
+				continue;
+			}
+			let allowed = {
+				let id = s.id().expect("Ready session always has id");
+				let direction = if s.info.originated { ConnectionDirection::Outbound } else { ConnectionDirection::Inbound };
+				let others = other_addresses(&peers, s.token());
+				self.connection_allowed(&ConnectionContext::new(&self_id, id, direction, s.remote_addr().ok(), reserved.contains(id), &others))
+			};
+			if !allowed {
+				to_kill.push(s.token());
+			}
+		}
+		for p in to_kill {
+			trace!(target: "network", "Disconnecting filtered peer: {}", p);
+			self.stats.inc_filtered();
+			self.filter_audit.lock().note_dropped();
+			self.disconnect_gracefully(p, DisconnectReason::ConnectionFiltered, io);
+		}
+	}
+
+	pub fn remove_reserved_node(&self, id: &str) -> Result<(), Error> {
+		let n = Node::from_str(id)?;
+		self.reserved_nodes.write().remove(&n.id);
+		let reserved = self.reserved_nodes.read().clone();
+		if let Some(ref mut discovery) = *self.discovery.lock() {
+			discovery.set_reserved_nodes(reserved);
+		}
+
+		Ok(())
+	}
+
+	pub fn external_url(&self) -> Option<String> {
+		let info = self.info.read();
+		info.public_endpoint.as_ref().map(|e| format!("{}", Node::new(info.id().clone(), e.clone())))
+	}
+
+	/// Mechanism the advertised public address was obtained with. `None` until the network is started.
+	pub fn external_address_source(&self) -> Option<AddressSource> {
+		self.external_address.lock().current().map(|&(_, source)| source)
+	}
+
+	/// Discovery counters and table occupancy. `None` if discovery is not running.
+	pub fn discovery_stats(&self) -> Option<DiscoveryStats> {
+		self.discovery.lock().as_ref().map(|d| d.discovery_stats())
+	}
+
+	/// Reset discovery counters.
+	pub fn reset_discovery_stats(&self) {
+		if let Some(ref mut discovery) = *self.discovery.lock() {
+			discovery.reset_stats();
+		}
+	}
+
+	/// Nodes of the discovery table nearest to `target`, closest first. With `live` set a lookup
+	/// is run first and its result sent when done. The sender is dropped if discovery is not running.
+	pub fn find_nodes_near(&self, target: H256, live: bool) -> Receiver<Vec<NearNode>> {
+		let (sender, receiver) = mpsc::channel();
+		let nearest = {
+			let mut discovery = self.discovery.lock();
+			match discovery.as_mut() {
+				None => return receiver,
+				Some(discovery) => {
+					if live {
+						discovery.find_nearest(target, sender);
+						return receiver;
+					}
+					discovery.nearest_nodes(&target)
+				},
+			}
+		};
+		sender.send(self.with_node_info(nearest)).ok();
+		receiver
+	}
+
+	/// Fill in when the nodes were last seen and contacted from the node table.
+	fn with_node_info(&self, mut nearest: Vec<NearNode>) -> Vec<NearNode> {
+		let nodes = self.nodes.read();
+		for near in &mut nearest {
+			if let Some(node) = nodes.get(&near.id) {
+				near.last_seen = Some(node.last_seen);
+				near.last_contact = node.last_contact;
+			}
+		}
+		nearest
+	}
+
+	/// Nodes not dialed because of repeated connection failures.
+	pub fn quarantined_nodes(&self) -> Vec<QuarantinedNode> {
+		self.nodes.read().quarantined(time::get_time().sec as u64)
+	}
+
+	/// Node table entries as dial candidates, with their last failed connection attempt.
+	pub fn dial_candidates(&self) -> Vec<DialCandidate> {
+		self.nodes.read().dial_candidates(time::get_time().sec as u64)
+	}
+
+	/// Enode URLs of the node table entries, optionally only those from `source`.
+	pub fn export_nodes(&self, source: Option<NodeSource>) -> Vec<String> {
+		self.nodes.read().export(source)
+	}
+
+	/// Write the node table to disk now.
+	pub fn flush_node_table(&self) -> Result<(), Error> {
+		self.nodes.read().flush()?;
+		Ok(())
+	}
+
+	/// Address of the main listener. The port is the one actually bound.
+	pub fn local_addr(&self) -> SocketAddr {
+		self.info.read().local_endpoint.address
+	}
+
+	pub fn local_url(&self) -> String {
+		let info = self.info.read();
+		format!("{}", Node::new(info.id().clone(), info.local_endpoint.clone()))
+	}
+
+	/// Node id of this host.
+	pub fn node_id(&self) -> NodeId {
+		self.info.read().id().clone()
+	}
+
+	/// Enode URL with the advertised endpoint. Falls back to the listen address until the public
+	/// endpoint is resolved; the flag is set in that case.
+	pub fn local_enode(&self) -> (String, bool) {
+		match self.external_url() {
+			Some(url) => (url, false),
+			None => (self.local_url(), true),
+		}
+	}
+
+	pub fn stop(&self, io: &IoContext<NetworkIoMessage>) -> Result<(), Error> {
+		self.stopping.store(true, AtomicOrdering::Release);
+		let deadline = Instant::now() + self.info.read().config.shutdown_drain_timeout;
+		let mut to_kill = Vec::new();
+		// Held until waiting, so that no session is reported flushed before it is added.
+		let mut flushing = self.shutdown_flush.lock();
+		for e in self.sessions.read().iter() {
+			let mut s = e.lock();
+			s.disconnect(io, DisconnectReason::ClientQuit);
+			if s.is_sending() {
+				flushing.insert(s.token());
+			}
+			to_kill.push(s.token());
+		}
+
+		// Let writable events flush the disconnect packets so that peers don't see us as a failed node.
+		while !flushing.is_empty() {
+			if self.shutdown_flushed.wait_until(&mut flushing, deadline).timed_out() {
+				debug!(target: "network", "{} sessions not flushed on shutdown", flushing.len());
+				break;
+			}
+		}
+		flushing.clear();
+		drop(flushing);
+
+		for p in to_kill {
+			trace!(target: "network", "Disconnecting on shutdown: {}", p);
+			self.kill_connection(p, io, false);
+		}
+		self.pending_decisions.lock().clear();
+		self.nodes.read().save();
+		io.unregister_handler()?;
+		Ok(())
+	}
+
+	/// Get all connected peers.
+	pub fn connected_peers(&self) -> Vec<PeerId> {
+		let sessions = self.sessions.read();
+		let sessions = &*sessions;
+
+		let mut peers = Vec::with_capacity(sessions.count());
+		for i in (0..MAX_SESSIONS).map(|x| x + FIRST_SESSION) {
+			if sessions.get(i).is_some() {
+				peers.push(i);
+			}
+		}
+		peers
+	}
+
+	/// Get information about all ready sessions. Sessions that are being disconnected are skipped.
+	pub fn peers_info(&self) -> Vec<PeerInfo> {
+		let reservations = self.info.read().config.slot_reservations.clone();
+		let reserved = self.reserved_nodes.read().clone();
+		let mut peers: Vec<(NodeId, Option<SocketAddr>, PeerInfo)> = self.sessions.read().iter().filter_map(|e| {
+			let s = e.lock();
+			if !s.is_ready() || s.expired() || s.info.disconnect_reason.is_some() {
+				return None;
+			}
+			let id = match s.id() {
+				Some(id) => id.clone(),
+				None => return None,
+			};
+			let traffic = s.total_traffic();
+			let socket = s.socket_options();
+			let info = PeerInfo {
+				id: id.hex(),
+				enode: String::new(),
+				remote_address: s.info.remote_address.clone(),
+				inbound: !s.info.originated,
+				protocols: s.info.capabilities.iter().map(|c| PeerProtocolInfo {
+					protocol: String::from_utf8_lossy(&c.protocol).into_owned(),
+					version: c.version,
+				}).collect(),
+				client_version: s.info.client_version.to_string(),
+				connected_since: s.connected_since().unwrap_or(0),
+				bytes_sent: traffic.bytes_sent,
+				bytes_received: traffic.bytes_received,
+				socket: PeerSocketInfo {
+					nodelay: socket.nodelay,
+					keepalive_secs: socket.keepalive.map(|k| k.as_secs()),
+					send_buffer_size: socket.send_buffer_size,
+					recv_buffer_size: socket.recv_buffer_size,
+				},
+				reserved: reserved.contains(&id),
+				misbehaviour_score: 0,
+				reserved_slot: reservations.iter().any(|r| matches_reservation(&s, r)),
+				p2p_version: s.info.protocol_version,
+				compression: s.compression_enabled(),
+				compression_counters: s.compression_counters(),
+			};
+			Some((id, s.remote_addr().ok(), info))
+		}).collect();
+
+		// Prefer the advertised endpoint from the node table over the session's remote port,
+		// which is ephemeral for inbound connections.
+		let nodes = self.nodes.read();
+		let now = time::get_time().sec as u64;
+		for &mut (ref id, ref remote, ref mut info) in &mut peers {
+			info.misbehaviour_score = nodes.misbehaviour_score(id, now);
+			let endpoint = match (nodes.get(id), *remote) {
+				(Some(node), _) => Some(node.endpoint.clone()),
+				(None, Some(address)) => Some(NodeEndpoint { address: address, udp_port: address.port() }),
+				(None, None) => None,
+			};
+			if let Some(endpoint) = endpoint {
+				info.enode = format!("{}", Node::new(id.clone(), endpoint));
+			}
+		}
+		peers.into_iter().map(|(_, _, info)| info).collect()
+	}
+
+	fn init_public_interface(&self, io: &IoContext<NetworkIoMessage>) -> Result<(), Error> {
+		if self.info.read().public_endpoint.is_some() {
+			return Ok(());
+		}
+		let local_endpoint = self.info.read().local_endpoint.clone();
+		let allow_ips = self.info.read().config.ip_filter.clone();
+		let listen_addresses: Vec<_> = self.tcp_listeners.lock().iter().filter_map(|l| l.local_addr().ok()).collect();
+		let public_endpoint = {
+			let fallback = NodeEndpoint { address: select_public_listen_address(&listen_addresses), udp_port: local_endpoint.udp_port };
+			let mut external = self.external_address.lock();
+			external.check(&DetectionContext { local: &local_endpoint, observed: &[] }, fallback);
+			let &(ref endpoint, source) = external.current().expect("The first check always sets an address; qed");
+			info!(target: "network", "Public address {} ({:?})", endpoint.address, source);
+			endpoint.clone()
+		};
+
+		self.info.write().public_endpoint = Some(public_endpoint.clone());
+
+		// Nodes with non-global addresses are only useful on local networks.
+		let allow_non_global = self.info.read().config.allow_non_global_ips || ip_class(&public_endpoint.address.ip()) != IpClass::Global;
+		self.nodes.write().set_allow_non_global(allow_non_global);
+
+		if let Some(url) = self.external_url() {
+			self.events.publish(NetworkEvent::ExternalAddressChanged { address: public_endpoint.address, url: url.clone() });
+			self.announce_public_url(url, io);
+		}
+
+		// Initialize discovery.
+		let reserved = self.reserved_nodes.read().clone();
+		let discovery = {
+			let info = self.info.read();
+			if info.config.discovery_enabled && info.config.non_reserved_mode == NonReservedPeerMode::Accept {
+				if info.config.outbound_proxy.is_some() {
+					warn!(target: "network", "Discovery traffic is not sent through the outbound proxy");
+				}
+				let mut udp_addr = local_endpoint.address.clone();
+				udp_addr.set_port(local_endpoint.udp_port);
+				let mut discovery = Discovery::new(&info.keys, udp_addr, public_endpoint, DISCOVERY, allow_ips, self.stats.clone());
+				discovery.set_ping_policy(info.config.discovery_ping_timeout, info.config.discovery_ping_retries);
+				discovery.set_clock_skew(info.config.discovery_clock_skew);
+				discovery.set_packet_cache(info.config.discovery_packet_cache_size, info.config.discovery_duplicate_window);
+				discovery.set_send_queue_limit(info.config.discovery_send_queue_limit);
+				discovery.set_adaptive(info.config.discovery_adaptive, info.config.discovery_min_lookup_interval, info.config.discovery_max_lookup_interval);
+				discovery.set_allow_non_global_ips(info.config.allow_non_global_ips);
+				discovery.set_ip_lists(info.config.ip_allowlist.clone(), info.config.ip_denylist.clone());
+				discovery.set_node_allowlist(info.node_allowlist.clone());
+				discovery.set_find_node_limits(info.config.discovery_find_node_limit, info.config.discovery_reserved_find_node_limit);
+				discovery.set_reserved_nodes(reserved);
+				Some(discovery)
+			} else { None }
+		};
+
+		if let Some(mut discovery) = discovery {
+			discovery.init_node_list(self.nodes.read().unordered_entries());
+			discovery.add_node_list(self.nodes.read().unordered_entries());
+			*self.discovery.lock() = Some(discovery);
+			io.register_stream(DISCOVERY)?;
+			// Adaptive discovery starts lookups on its own.
+			let (adaptive, refresh) = {
+				let info = self.info.read();
+				(info.config.discovery_adaptive, info.config.discovery_min_lookup_interval)
+			};
+			if !adaptive {
+				io.register_timer(DISCOVERY_REFRESH, refresh.as_secs() * 1000 + refresh.subsec_nanos() as u64 / 1000_000)?;
+			}
+			io.register_timer(DISCOVERY_ROUND, DISCOVERY_ROUND_TIMEOUT)?;
+		}
+
+		// Initialize LAN discovery. Nodes with a public address are not announced on the local network unless forced.
+		let lan_discovery = {
+			let info = self.info.read();
+			match info.config.lan_discovery {
+				Some(ref lan) if !lan.force && info.config.public_address.map_or(false, |a| ip_class(&a.ip()) == IpClass::Global) => {
+					info!(target: "network", "LAN discovery disabled, a public address is configured");
+					None
+				},
+				Some(ref lan) if info.config.non_reserved_mode == NonReservedPeerMode::Accept => {
+					match LanDiscovery::new(&info.keys, lan, local_endpoint.address.port(), LAN_DISCOVERY) {
+						Ok(lan_discovery) => Some((lan_discovery, lan.interval)),
+						Err(e) => {
+							warn!(target: "network", "Error starting LAN discovery on {}: {:?}", lan.address, e);
+							None
+						}
+					}
+				},
+				_ => None,
+			}
+		};
+		if let Some((lan_discovery, interval)) = lan_discovery {
+			lan_discovery.announce();
+			*self.lan_discovery.lock() = Some(lan_discovery);
+			io.register_stream(LAN_DISCOVERY)?;
+			io.register_timer(LAN_ANNOUNCE, max(interval.as_secs() * 1000 + interval.subsec_nanos() as u64 / 1000_000, 1))?;
+		}
+		let save = self.info.read().config.node_table_save_interval;
+		let save_ms = save.as_secs() * 1000 + save.subsec_nanos() as u64 / 1000_000;
+		io.register_timer(NODE_TABLE, if save_ms > 0 { save_ms } else { NODE_TABLE_TIMEOUT })?;
+		let recheck = self.info.read().config.external_address_recheck;
+		let recheck_ms = recheck.as_secs() * 1000 + recheck.subsec_nanos() as u64 / 1000_000;
+		if recheck_ms > 0 {
+			io.register_timer(EXTERNAL_ADDRESS, recheck_ms)?;
+		}
+		let resolve = self.info.read().config.reserved_resolve_interval;
+		let resolve_ms = resolve.as_secs() * 1000 + resolve.subsec_nanos() as u64 / 1000_000;
+		if resolve_ms > 0 {
+			io.register_timer(RESERVED_RESOLVE, resolve_ms)?;
+		}
+		for i in 0..listen_addresses.len() {
+			io.register_stream(TCP_ACCEPT + i)?;
+		}
+		Ok(())
+	}
+
+	/// Log the public node URL and notify all IO handlers with `NetworkStarted`.
+	fn announce_public_url(&self, url: String, io: &IoContext<NetworkIoMessage>) {
+		info!(target: "network", "Public node URL: {}", Colour::White.bold().paint(url.as_ref()));
+		io.message(NetworkIoMessage::NetworkStarted(url)).unwrap_or_else(|e| warn!("Error sending IO notification: {:?}", e));
+	}
+
+	/// Run public address detection again and advertise the new address if it has changed.
+	fn recheck_external_address(&self, io: &IoContext<NetworkIoMessage>) {
+		let local_endpoint = self.info.read().local_endpoint.clone();
+		let listen_addresses: Vec<_> = self.tcp_listeners.lock().iter().filter_map(|l| l.local_addr().ok()).collect();
+		let fallback = NodeEndpoint { address: select_public_listen_address(&listen_addresses), udp_port: local_endpoint.udp_port };
+		let observed = self.discovery.lock().as_ref().map_or_else(Vec::new, |d| d.observed_addresses());
+		let changed = self.external_address.lock().check(&DetectionContext { local: &local_endpoint, observed: &observed }, fallback);
+		let (endpoint, source) = match changed {
+			Some(changed) => changed,
+			None => return,
+		};
+		info!(target: "network", "Public address changed to {} ({:?})", endpoint.address, source);
+		self.info.write().public_endpoint = Some(endpoint.clone());
+		if let Some(ref mut discovery) = *self.discovery.lock() {
+			discovery.set_public_endpoint(endpoint.clone());
+		}
+		if let Some(url) = self.external_url() {
+			self.events.publish(NetworkEvent::ExternalAddressChanged { address: endpoint.address, url: url.clone() });
+			self.announce_public_url(url, io);
+		}
+	}
+
+	fn maintain_network(&self, io: &IoContext<NetworkIoMessage>) {
+		self.keep_alive(io);
+		self.shed_excess_peers(io);
+		self.connect_peers(io);
+		self.log_handshake_failures();
+		self.log_session_churn();
+		self.check_peer_count();
+		self.update_discovery_activity();
+	}
+
+	/// Let discovery adapt its lookup rate to the number of connected peers.
+	fn update_discovery_activity(&self) {
+		let (_, egress, ingress) = self.session_count();
+		let (min_peers, max_peers) = {
+			let info = self.info.read();
+			(info.config.min_peers as usize, info.config.max_peers as usize)
+		};
+		if let Some(ref mut discovery) = *self.discovery.lock() {
+			discovery.set_peer_count(egress + ingress, min_peers, max_peers);
+		}
+	}
+
+	/// Check the number of connected peers against the watermarks and notify the callback of transitions.
+	fn check_peer_count(&self) {
+		let (_, egress, ingress) = self.session_count();
+		let peers = egress + ingress;
+		let event = match self.peer_watermarks.lock().update(peers, time::precise_time_ns()) {
+			Some(event) => event,
+			None => return,
+		};
+		match event {
+			PeerCountEvent::BelowLow { .. } => warn!(target: "network", "Low peer count: {} connected peers", peers),
+			PeerCountEvent::Recovered { .. } => info!(target: "network", "Peer count recovered: {} connected peers", peers),
+		}
+		let callback = self.peer_count_callback.read().clone();
+		if let Some(callback) = callback {
+			callback(event);
+		}
+	}
+
+	/// Log the handshake failures since the previous summary, at most once per `HANDSHAKE_SUMMARY_INTERVAL_NS`.
+	fn log_handshake_failures(&self) {
+		let now = time::precise_time_ns();
+		let mut summary = self.handshake_summary.lock();
+		if now < summary.0 + HANDSHAKE_SUMMARY_INTERVAL_NS {
+			return;
+		}
+		let failures = self.stats.handshake_failures();
+		let delta = failures.since(&summary.1);
+		if delta.total() > 0 {
+			debug!(target: "network", "{} failed handshakes in the last {}s: {}", delta.total(), (now - summary.0) / 1000_000_000, delta);
+		}
+		*summary = (now, failures);
+	}
+
+	/// Log the session churn of the last hour once per hour, if enabled.
+	fn log_session_churn(&self) {
+		if !self.info.read().config.log_session_churn {
+			return;
+		}
+		let now = time::precise_time_ns();
+		let mut summary = self.churn_summary.lock();
+		if now < *summary + CHURN_SUMMARY_INTERVAL_NS {
+			return;
+		}
+		*summary = now;
+		let churn = self.stats.churn();
+		info!(target: "network", "Sessions in the last hour: {} established, {} ended, mean duration {}s, median {}s, {:.1}% under {}s",
+			churn.established, churn.ended, churn.mean_duration_secs, churn.median_duration_secs, churn.flapping * 100.0, FLAPPING_SESSION_SECS);
+	}
+
+	/// Disconnect the longest connected non-reserved peer if there are more than `max_peers`, reserved peers included.
+	/// At most one peer is disconnected per call to avoid churn after the limit is lowered.
+	fn shed_excess_peers(&self, io: &IoContext<NetworkIoMessage>) {
+		let max_peers = {
+			let info = self.info.read();
+			match info.config.non_reserved_mode {
+				NonReservedPeerMode::Deny => 0,
+				NonReservedPeerMode::Accept => info.config.max_peers as usize,
+			}
+		};
+		let (egress, ingress) = self.non_reserved_session_count();
+		if egress + ingress + self.reserved_session_count() <= max_peers {
+			return;
+		}
+		let oldest = {
+			let reserved = self.reserved_nodes.read();
+			self.sessions.read().iter().filter_map(|e| {
+				let s = e.lock();
+				if !s.is_ready() || s.expired() || s.is_draining() || s.id().map_or(false, |id| reserved.contains(id)) {
+					return None;
+				}
+				s.connected_at_ns().map(|t| (t, s.token()))
+			}).min().map(|(_, token)| token)
+		};
+		if let Some(token) = oldest {
+			debug!(target: "network", "Disconnecting {}: over the peer limit of {}", token, max_peers);
+			self.disconnect_gracefully(token, DisconnectReason::TooManyPeers, io);
+		}
+	}
+
+	/// Disconnect a peer that did nothing wrong. Protocol packets are refused from now on, but the
+	/// data already queued is sent before the Disconnect packet, waiting at most `disconnect_drain_timeout`.
+	fn disconnect_gracefully(&self, token: StreamToken, reason: DisconnectReason, io: &IoContext<NetworkIoMessage>) {
+		let session = { self.sessions.read().get(token).cloned() };
+		if let Some(session) = session {
+			let timeout = self.info.read().config.disconnect_drain_timeout;
+			let deadline = time::precise_time_ns() + timeout.as_secs() * 1000_000_000 + timeout.subsec_nanos() as u64;
+			let mut s = session.lock();
+			if s.start_draining(reason, deadline) {
+				return;
+			}
+			s.disconnect(io, reason);
+		}
+		self.kill_connection(token, io, false);
+	}
+
+	/// Send the Disconnect packet to a draining session once its send queue is empty and close it.
+	/// If the drain timeout passes first the connection is closed with the data still queued.
+	fn finish_draining(&self, token: StreamToken, io: &IoContext<NetworkIoMessage>) {
+		let session = { self.sessions.read().get(token).cloned() };
+		let timed_out = match session {
+			Some(session) => {
+				let mut s = session.lock();
+				match s.drained(time::precise_time_ns()) {
+					Some(_) if s.is_sending() => {
+						debug!(target: "network", "{}: Drain timeout, dropping {} bytes", token, s.queue_depth());
+						true
+					},
+					Some(reason) => {
+						s.disconnect(io, reason);
+						false
+					},
+					None => return,
+				}
+			},
+			None => return,
+		};
+		self.kill_connection(token, io, false);
+		if timed_out {
+			io.deregister_stream(token).unwrap_or_else(|e| debug!("Error deregistering stream: {:?}", e));
+		}
+	}
+
+	/// Check a peer address against the configured allow and deny lists.
+	fn address_allowed(&self, ip: &IpAddr, reserved: bool) -> bool {
+		let info = self.info.read();
+		(reserved && info.config.reserved_bypass_ip_lists) || is_allowed_by_lists(ip, &info.config.ip_allowlist, &info.config.ip_denylist)
+	}
+
+	/// Check if any reserved node is known at the address. The node id of an incoming
+	/// connection is only known after the handshake.
+	fn is_reserved_address(&self, ip: &IpAddr) -> bool {
+		let reserved = self.reserved_nodes.read();
+		let nodes = self.nodes.read();
+		reserved.iter().any(|id| nodes.get(id).map_or(false, |n| n.endpoint.address.ip() == *ip))
+	}
+
+	/// Resolve the reserved nodes given by host name again. A node we dialed that no longer resolves to
+	/// the address of its session is disconnected if configured, and dialed at the new address by the
+	/// next maintenance round. Nodes that fail to resolve keep their last address.
+	fn resolve_reserved_nodes(&self, io: &IoContext<NetworkIoMessage>) {
+		let reserved: Vec<NodeId> = self.reserved_nodes.read().iter().cloned().collect();
+		for id in reserved {
+			let (hostname, port) = match self.nodes.read().get(&id) {
+				Some(&Node { hostname: Some(ref hostname), ref endpoint, .. }) => (hostname.clone(), endpoint.address.port()),
+				_ => continue,
+			};
+			if !self.resolving.lock().insert(id.clone()) {
+				continue;
+			}
+			let host = hostname.clone();
+			self.resolve_in_background(&id, AfterResolve::CheckReserved { hostname: hostname }, move |resolver| {
+				match resolver.resolve(&host, port) {
+					Ok(addresses) => addresses,
+					Err(e) => {
+						debug!(target: "network", "Error resolving reserved node {}: {:?}", host, e);
+						Vec::new()
+					}
+				}
+			}, io);
+		}
+	}
+
+	/// Check the session we dialed to a reserved node against the addresses its host name resolved to.
+	fn check_reserved_address(&self, id: &NodeId, hostname: &str, addresses: Vec<SocketAddr>, io: &IoContext<NetworkIoMessage>) {
+		if addresses.is_empty() {
+			debug!(target: "network", "No address found for reserved node {}", hostname);
+			return;
+		}
+		let session = self.sessions.read().iter().find(|e| e.lock().info.id.as_ref() == Some(id)).cloned();
+		let session = match session {
+			Some(session) => session,
+			None => return,
+		};
+		let mut s = session.lock();
+		if !s.is_ready() || s.expired() || !s.info.originated {
+			return;
+		}
+		let remote = match s.remote_addr() {
+			Ok(remote) => remote,
+			Err(_) => return,
+		};
+		if addresses.iter().any(|a| a.ip() == remote.ip()) {
+			return;
+		}
+		info!(target: "network", "Reserved node {} moved from {} to {}", hostname, remote.ip(), addresses[0]);
+		self.nodes.write().note_resolved(id, addresses[0]);
+		if self.info.read().config.reconnect_on_address_change {
+			let token = s.token();
+			s.disconnect(io, DisconnectReason::DisconnectRequested);
+			drop(s);
+			self.kill_connection(token, io, false);
+		}
+	}
+
+	/// Run a host name lookup for the node on a thread of its own, so that a slow resolver does not hold
+	/// the IO thread. The addresses are handled by `process_resolved` once the IO thread is notified.
+	fn resolve_in_background<F>(&self, id: &NodeId, then: AfterResolve, resolve: F, io: &IoContext<NetworkIoMessage>)
+		where F: FnOnce(&HostResolver) -> Vec<SocketAddr> + Send + 'static {
+
+		let is_dial = match then { AfterResolve::Dial { .. } => true, _ => false };
+		let resolver = self.resolver.read().clone();
+		let resolved = self.resolved.clone();
+		let notify = io.clone();
+		let node = id.clone();
+		let spawned = thread::Builder::new().name("devp2p-resolve".into()).spawn(move || {
+			let addresses = resolve(&*resolver);
+			resolved.lock().push(Resolved { id: node, addresses: addresses, then: then });
+			notify.message_self(NetworkIoMessage::NodesResolved)
+				.unwrap_or_else(|e| debug!(target: "network", "Error sending IO notification: {:?}", e));
+		});
+		if let Err(e) = spawned {
+			warn!(target: "network", "Error starting host name lookup: {:?}", e);
+			self.resolving.lock().remove(id);
+			if is_dial {
+				self.dials.lock().resolve(id, Err(DialError::HandshakeFailed));
+			}
+		}
+	}
+
+	/// Continue the dials and reserved node checks whose host name lookups have completed.
+	fn process_resolved(&self, io: &IoContext<NetworkIoMessage>) {
+		let resolved: Vec<Resolved> = self.resolved.lock().drain(..).collect();
+		for r in resolved {
+			match r.then {
+				AfterResolve::Connect => {
+					self.resolving.lock().remove(&r.id);
+					self.connect_addresses(&r.id, r.addresses, io);
+				},
+				AfterResolve::Dial { force } => {
+					if let Err(e) = self.start_dial(&r.id, r.addresses, force, io) {
+						debug!(target: "network", "Dial to {} failed: {:?}", r.id.hex(), e);
+						self.dials.lock().resolve(&r.id, Err(e));
+					}
+				},
+				AfterResolve::CheckReserved { ref hostname } => {
+					self.resolving.lock().remove(&r.id);
+					self.check_reserved_address(&r.id, hostname, r.addresses, io);
+				},
+			}
+		}
+	}
+
+	fn have_session(&self, id: &NodeId) -> bool {
+		self.sessions.read().iter().any(|e| e.lock().info.id == Some(id.clone()))
+	}
+
+	// returns (handshakes, egress, ingress)
+	fn session_count(&self) -> (usize, usize, usize) {
+		let mut handshakes = 0;
+		let mut egress = 0;
+		let mut ingress = 0;
+		for s in self.sessions.read().iter() {
+			match s.try_lock() {
+				Some(ref s) if s.is_ready() && s.info.originated => egress += 1,
+				Some(ref s) if s.is_ready() && !s.info.originated => ingress += 1,
+				_ => handshakes +=1,
+			}
+		}
+		(handshakes, egress, ingress)
+	}
+
+	// returns (outgoing, incoming) handshakes in progress
+	fn handshake_count(&self) -> (usize, usize) {
+		let mut outgoing = 0;
+		let mut incoming = 0;
+		for s in self.sessions.read().iter() {
+			let s = s.lock();
+			if !s.is_ready() && !s.expired() {
+				if s.info.originated { outgoing += 1 } else { incoming += 1 }
+			}
+		}
+		self.stats.set_handshakes(outgoing + incoming);
+		(outgoing, incoming)
+	}
+
+	// returns (egress, ingress) for ready sessions with non-reserved peers
+	fn non_reserved_session_count(&self) -> (usize, usize) {
+		let reserved = self.reserved_nodes.read();
+		let mut egress = 0;
+		let mut ingress = 0;
+		for s in self.sessions.read().iter() {
+			match s.try_lock() {
+				Some(ref s) if s.is_ready() && !s.is_draining() && !s.id().map_or(false, |id| reserved.contains(id)) => {
+					if s.info.originated { egress += 1 } else { ingress += 1 }
+				},
+				_ => {},
+			}
+		}
+		(egress, ingress)
+	}
+
+	/// Number of ready non-reserved sessions other than `token` matching each reservation.
+	fn reservation_counts(&self, token: StreamToken, reservations: &[SlotReservation]) -> Vec<usize> {
+		let reserved = self.reserved_nodes.read();
+		let mut counts = vec![0; reservations.len()];
+		for e in self.sessions.read().iter() {
+			if let Some(ref s) = e.try_lock() {
+				if s.token() == token || !s.is_ready() || s.is_draining() || s.id().map_or(false, |id| reserved.contains(id)) {
+					continue;
+				}
+				for (count, r) in counts.iter_mut().zip(reservations) {
+					if matches_reservation(s, r) {
+						*count += 1;
+					}
+				}
+			}
+		}
+		counts
+	}
+
+	/// Number of ready sessions with reserved nodes.
+	fn reserved_session_count(&self) -> usize {
+		let reserved = self.reserved_nodes.read();
+		self.sessions.read().iter().filter(|e| match e.try_lock() {
+			Some(ref s) => s.is_ready() && !s.is_draining() && s.id().map_or(false, |id| reserved.contains(id)),
+			None => false,
+		}).count()
+	}
+
+	/// Ready non-reserved session other than `token` that matches no reservation and has the lowest
+	/// priority. It is disconnected to make room for a reserved peer or a peer a reservation is kept for.
+	/// The session with the lowest scored node is chosen, ties go to the most recently connected one.
+	fn eviction_victim(&self, token: StreamToken, reservations: &[SlotReservation]) -> Option<StreamToken> {
+		let candidates: Vec<(NodeId, Option<u64>, StreamToken)> = {
+			let reserved = self.reserved_nodes.read();
+			self.sessions.read().iter().filter_map(|e| {
+				let s = match e.try_lock() {
+					Some(s) => s,
+					None => return None,
+				};
+				if s.token() == token || !s.is_ready() || s.expired() || s.is_draining() || reservations.iter().any(|r| matches_reservation(&s, r)) {
+					return None;
+				}
+				let id = match s.id() {
+					Some(id) if !reserved.contains(id) => id.clone(),
+					_ => return None,
+				};
+				Some((id, s.connected_at_ns(), s.token()))
+			}).collect()
+		};
+		let nodes = self.nodes.read();
+		let now = time::get_time().sec as u64;
+		candidates.into_iter()
+			.min_by_key(|&(ref id, connected_at, token)| (nodes.get(id).map_or(i64::min_value(), |n| n.dial_score(now)), Reverse(connected_at), Reverse(token)))
+			.map(|(_, _, token)| token)
+	}
+
+	/// Number of established sessions that negotiated the protocol in `min_version` or later.
+	fn protocol_peer_count(&self, protocol: ProtocolId, min_version: u8) -> usize {
+		self.sessions.read().iter().filter(|e| match e.try_lock() {
+			Some(ref s) => s.is_ready() && s.capability_version(protocol).map_or(false, |v| v >= min_version),
+			None => false,
+		}).count()
+	}
+
+	/// Move the nodes known to support a protocol that has fewer peers than its target to the front of `candidates`.
+	fn prefer_protocol_peers(&self, candidates: Vec<NodeId>, targets: &[ProtocolPeerTarget]) -> Vec<NodeId> {
+		let mut preferred = HashSet::new();
+		for target in targets {
+			if self.protocol_peer_count(target.protocol, target.min_version) < target.peers as usize {
+				preferred.extend(self.nodes.read().nodes_supporting(target.protocol, target.min_version));
+			}
+		}
+		prefer_nodes(candidates, &preferred)
+	}
+
+	/// Remote addresses and directions of established sessions. Sessions locked by another thread are skipped.
+	fn session_addresses(&self) -> Vec<(StreamToken, IpAddr, ConnectionDirection)> {
+		self.sessions.read().iter().filter_map(|e| {
+			let s = match e.try_lock() {
+				Some(s) => s,
+				None => return None,
+			};
+			if !s.is_ready() || s.expired() {
+				return None;
+			}
+			let direction = if s.info.originated { ConnectionDirection::Outbound } else { ConnectionDirection::Inbound };
+			s.remote_addr().ok().map(|a| (s.token(), a.ip(), direction))
+		}).collect()
+	}
+
+	fn connecting_to(&self, id: &NodeId) -> bool {
+		self.sessions.read().iter().any(|e| e.lock().id() == Some(id))
+	}
+
+	/// Find the outbound handshake whose local end is at `address`. Returns its token and the dialed node id.
+	fn outbound_handshake_from(&self, address: &SocketAddr) -> Option<(StreamToken, Option<NodeId>)> {
+		self.sessions.read().iter().filter_map(|e| {
+			let s = e.lock();
+			if !s.info.originated || s.is_ready() || s.expired() {
+				return None;
+			}
+			match s.local_addr() {
+				Ok(ref a) if a == address => Some((s.token(), s.id().cloned())),
+				_ => None,
+			}
+		}).next()
+	}
+
+	/// Check a failed inbound handshake for a connection to ourselves. If the other end is one of our own
+	/// dials, the dialed node is marked as ourselves and both connections are closed. A peer presenting our
+	/// node id without being us is an impersonation. Returns true if either was the case.
+	fn check_self_connection(&self, session: &SharedSession, e: &Error, io: &IoContext<NetworkIoMessage>) -> bool {
+		let remote = {
+			let s = session.lock();
+			if s.info.originated || s.is_ready() {
+				return false;
+			}
+			s.remote_addr().ok()
+		};
+		if let Some((token, id)) = remote.and_then(|a| self.outbound_handshake_from(&a)) {
+			debug!(target: "network", "Dialed ourselves as {:?} at {:?}", id, remote);
+			self.stats.inc_handshake_failure(HandshakeFailure::SelfConnection);
+			if let Some(id) = id {
+				self.nodes.write().mark_as_self(&id);
+			}
+			let dial = self.sessions.read().get(token).cloned();
+			if let Some(dial) = dial {
+				dial.lock().disconnect(io, DisconnectReason::LocalIdentity);
+			}
+			self.kill_connection(token, io, false);
+			return true;
+		}
+		if let ErrorKind::SelfConnection = *e.kind() {
+			debug!(target: "network", "Inbound peer at {:?} presented our node id", remote);
+			self.stats.inc_handshake_failure(HandshakeFailure::Impersonation);
+			return true;
+		}
+		false
+	}
+
+	fn keep_alive(&self, io: &IoContext<NetworkIoMessage>) {
+		let mut to_kill = Vec::new();
+		let mut drained = Vec::new();
+		for e in self.sessions.read().iter() {
+			let mut s = e.lock();
+			if s.is_draining() {
+				if !s.expired() {
+					drained.push(s.token());
+				}
+			} else if s.send_queue_overflow() {
+				debug!(target: "network", "Peer {} is not reading, {} bytes queued", s.token(), s.queue_depth());
+				s.disconnect(io, DisconnectReason::TCPError);
+				to_kill.push(s.token());
+			} else if !s.keep_alive(io) {
+				s.disconnect(io, DisconnectReason::PingTimeout);
+				to_kill.push(s.token());
+			}
+		}
+		for p in to_kill {
+			trace!(target: "network", "Ping timeout: {}", p);
 			self.kill_connection(p, io, true);
 		}
+		for p in drained {
+			self.finish_draining(p, io);
+		}
 	}
 
 	fn connect_peers(&self, io: &IoContext<NetworkIoMessage>) {
-		let (min_peers, mut pin, max_handshakes, allow_ips, self_id) = {
+		let (min_peers, mut pin, max_handshakes, max_dials_per_tick, allow_ips, self_id, slots, exploration, protocol_targets) = {
 			let info = self.info.read();
 			if info.capabilities.is_empty() {
 				return;
 			}
 			let config = &info.config;
+			let slots = PeerSlots::new(config.min_peers, config.max_peers, config.inbound_ratio);
+			let protocol_targets: Vec<ProtocolPeerTarget> = config.protocol_peer_targets.iter()
+				.filter(|t| info.capabilities.iter().any(|c| c.protocol == t.protocol))
+				.cloned()
+				.collect();
 
-			(config.min_peers, config.non_reserved_mode == NonReservedPeerMode::Deny, config.max_handshakes as usize, config.ip_filter.clone(), info.id().clone())
+			(config.min_peers, config.non_reserved_mode == NonReservedPeerMode::Deny, config.max_handshakes as usize, config.max_dials_per_tick as usize, config.ip_filter.clone(), info.id().clone(), slots, config.dial_exploration_percent, protocol_targets)
 		};
 
-		let (handshake_count, egress_count, ingress_count) = self.session_count();
+		let (_, egress_count, ingress_count) = self.session_count();
+		let (outgoing, incoming) = self.handshake_count();
+		let handshake_count = outgoing + incoming;
+		let (non_reserved_egress, _) = self.non_reserved_session_count();
+		let burst = self.startup_burst.lock().update(egress_count + ingress_count, min_peers as usize, time::precise_time_ns());
 		let reserved_nodes = self.reserved_nodes.read();
-		if egress_count + ingress_count >= min_peers as usize + reserved_nodes.len() {
+		if egress_count + ingress_count >= min_peers as usize + reserved_nodes.len() || non_reserved_egress >= slots.egress {
 			// check if all pinned nodes are connected.
 			if reserved_nodes.iter().all(|n| self.have_session(n) && self.connecting_to(n)) {
 				return;
@@ -551,94 +2035,479 @@ impl Host {
 		}
 
 		// iterate over all nodes, reserved ones coming first.
+		// if no boot node is reachable, the most recently contacted nodes follow.
 		// if we are pinned to only reserved nodes, ignore all others.
-		let nodes = reserved_nodes.iter().cloned().chain(if !pin {
-			self.nodes.read().nodes(allow_ips)
+		// other nodes are dialed best scored first, with a share picked at random,
+		// and those supporting protocols short of their peer target ahead of the rest.
+		let seeds = if !pin { self.emergency_seeds() } else { Vec::new() };
+		let nodes = reserved_nodes.iter().cloned().chain(seeds).chain(if !pin {
+			let candidates = self.nodes.read().nodes(allow_ips);
+			let candidates = with_exploration(candidates, exploration, &mut rand::thread_rng());
+			self.prefer_protocol_peers(candidates, &protocol_targets)
 		} else {
 			Vec::new()
 		});
 
-		let max_handshakes_per_round = max_handshakes / 2;
+		let dials = round_dials(burst, max_dials_per_tick, max_handshakes, handshake_count);
 		let mut started: usize = 0;
+		let peers: Vec<_> = self.session_addresses().into_iter().map(|(_, ip, direction)| (ip, direction)).collect();
+		let mut attempted = HashSet::new();
+		let now = time::precise_time_ns();
+		let now_secs = time::get_time().sec as u64;
 		for id in nodes.filter(|id|
+				attempted.insert(*id) &&
 				!self.have_session(id) &&
 				!self.connecting_to(id) &&
 				*id != self_id &&
-				self.filter.as_ref().map_or(true, |f| f.connection_allowed(&self_id, &id, ConnectionDirection::Outbound))
-			).take(min(max_handshakes_per_round, max_handshakes - handshake_count)) {
+				self.boot_nodes.lock().can_dial(id, now) &&
+				self.nodes.read().can_dial(id, now_secs) &&
+				(reserved_nodes.contains(id) || !self.nodes.read().is_blocked(id, now_secs)) &&
+				{
+					let address = self.nodes.read().get(id).and_then(|n| if n.endpoint.address.ip().is_unspecified() { None } else { Some(n.endpoint.address) });
+					self.connection_allowed(&ConnectionContext::new(&self_id, id, ConnectionDirection::Outbound, address, reserved_nodes.contains(id), &peers))
+				}
+			).take(dials) {
 			self.connect_peer(&id, io);
 			started += 1;
 		}
 		debug!(target: "network", "Connecting peers: {} sessions, {} pending + {} started", egress_count + ingress_count, handshake_count, started);
 	}
 
-	fn connect_peer(&self, id: &NodeId, io: &IoContext<NetworkIoMessage>) {
-		if self.have_session(id) {
-			trace!(target: "network", "Aborted connect. Node already connected.");
+	fn connect_peer(&self, id: &NodeId, io: &IoContext<NetworkIoMessage>) {
+		if self.have_session(id) {
+			trace!(target: "network", "Aborted connect. Node already connected.");
+			return;
+		}
+		if self.connecting_to(id) || self.resolving.lock().contains(id) || self.proxying.lock().contains(id) {
+			trace!(target: "network", "Aborted connect. Node already connecting.");
+			return;
+		}
+
+		let target = {
+			let mut nodes = self.nodes.write();
+			match nodes.get_mut(id) {
+				Some(node) => node.attempts += 1,
+				None => {
+					debug!(target: "network", "Connection to expired node aborted");
+					return;
+				}
+			}
+			nodes.dial_target(id)
+		};
+		let target = match target {
+			Some(target) => target,
+			None => return,
+		};
+		if target.needs_resolution() {
+			// The dial goes on in `process_resolved`.
+			self.resolving.lock().insert(id.clone());
+			self.resolve_in_background(id, AfterResolve::Connect, move |resolver| target.addresses(resolver), io);
+			return;
+		}
+		let addresses = target.addresses(&**self.resolver.read());
+		self.connect_addresses(id, addresses, io);
+	}
+
+	/// Dial the node at the first of the addresses that accepts a connection attempt.
+	fn connect_addresses(&self, id: &NodeId, addresses: Vec<SocketAddr>, io: &IoContext<NetworkIoMessage>) {
+		if self.have_session(id) {
+			trace!(target: "network", "Aborted connect. Node connected while its name was resolved.");
+			return;
+		}
+		if addresses.is_empty() {
+			debug!(target: "network", "No address to connect to for node {:?}", id);
+			self.stats.inc_dial_failure(DialFailure::Other);
+			self.note_failure(id, DialFailure::Other);
+			return;
+		}
+		let reserved = self.reserved_nodes.read().contains(id);
+		let addresses: Vec<_> = addresses.into_iter().filter(|a| self.address_allowed(&a.ip(), reserved)).collect();
+		if addresses.is_empty() {
+			debug!(target: "network", "Connection to {:?} not allowed by the IP lists", id);
+			self.stats.inc_dial_failure(DialFailure::Filtered);
+			self.nodes.write().mark_as_useless(id);
+			return;
+		}
+
+		self.connect_and_then(id, addresses, AfterConnect::Connect, io);
+	}
+
+	/// Connect to the first of the addresses that accepts a connection attempt, then go on as `then` says.
+	/// Connections through the outbound proxy are made on a thread of their own, as each step of the proxy
+	/// exchange may take up to its timeout.
+	fn connect_and_then(&self, id: &NodeId, addresses: Vec<SocketAddr>, then: AfterConnect, io: &IoContext<NetworkIoMessage>) {
+		let proxy = match self.info.read().config.outbound_proxy.clone() {
+			Some(proxy) => proxy,
+			None => {
+				let connected = self.connect_first(addresses);
+				self.connected(id, connected, then, io);
+				return;
+			}
+		};
+		self.proxying.lock().insert(id.clone());
+		let on_error = then.clone();
+		let proxied = self.proxied.clone();
+		let notify = io.clone();
+		let node = id.clone();
+		let spawned = thread::Builder::new().name("devp2p-proxy".into()).spawn(move || {
+			let connected = connect_first_through(&proxy, addresses);
+			proxied.lock().push(Proxied { id: node, connected: connected, then: then });
+			notify.message_self(NetworkIoMessage::ProxyConnected)
+				.unwrap_or_else(|e| debug!(target: "network", "Error sending IO notification: {:?}", e));
+		});
+		if let Err(e) = spawned {
+			warn!(target: "network", "Error starting proxy connection: {:?}", e);
+			self.proxying.lock().remove(id);
+			self.connected(id, Err(DialFailure::Other), on_error, io);
+		}
+	}
+
+	/// Start the handshakes on the connections made through the proxy.
+	fn process_proxied(&self, io: &IoContext<NetworkIoMessage>) {
+		let proxied: Vec<Proxied> = self.proxied.lock().drain(..).collect();
+		for p in proxied {
+			self.proxying.lock().remove(&p.id);
+			let connected = p.connected.and_then(|(address, stream, fallback)| match TcpStream::from_stream(stream) {
+				Ok(socket) => Ok((address, (socket, Some(address)), fallback)),
+				Err(e) => {
+					debug!(target: "network", "Can't use proxy connection to {:?}: {:?}", address, e);
+					Err(DialFailure::Other)
+				}
+			});
+			self.connected(&p.id, connected, p.then, io);
+		}
+	}
+
+	/// Start the handshake on the connection made for a dial, or record the failure of the dial.
+	fn connected(&self, id: &NodeId, connected: Result<(SocketAddr, (TcpStream, Option<SocketAddr>), Vec<SocketAddr>), DialFailure>, then: AfterConnect, io: &IoContext<NetworkIoMessage>) {
+		match (connected, then) {
+			(Ok((address, (socket, proxied_peer), fallback)), then) => {
+				trace!(target: "network", "Connecting to {:?}", address);
+				match then {
+					AfterConnect::Dial => {},
+					_ => self.nodes.write().note_resolved(id, address),
+				}
+				let e = match self.create_connection(socket, Some(id), proxied_peer, io) {
+					Ok(token) => {
+						self.keep_fallback(token, fallback);
+						return;
+					},
+					Err(e) => e,
+				};
+				debug!(target: "network", "Can't create connection: {:?}", e);
+				match then {
+					AfterConnect::Connect => {},
+					AfterConnect::Fallback { failure, dial_error } => self.dial_failed(id, failure, dial_error),
+					AfterConnect::Dial => {
+						let error = match *e.kind() {
+							ErrorKind::AtCapacity => DialError::Rejected(DisconnectReason::TooManyPeers),
+							_ => DialError::HandshakeFailed,
+						};
+						self.dials.lock().resolve(id, Err(error));
+					},
+				}
+			},
+			(Err(failure), AfterConnect::Connect) => self.dial_failed(id, failure, None),
+			(Err(_), AfterConnect::Fallback { failure, dial_error }) => self.dial_failed(id, failure, dial_error),
+			(Err(_), AfterConnect::Dial) => self.dials.lock().resolve(id, Err(DialError::HandshakeFailed)),
+		}
+	}
+
+	/// Record the failure of a dial that has run out of addresses, and resolve the pending dial if any.
+	fn dial_failed(&self, id: &NodeId, failure: DialFailure, dial_error: Option<DialError>) {
+		self.stats.inc_dial_failure(failure);
+		self.note_failure(id, failure);
+		if let Some(error) = dial_error {
+			self.dials.lock().resolve(id, Err(error));
+		}
+	}
+
+	/// Start connecting directly to the first of the addresses that accepts a connection attempt. Returns
+	/// the address, the socket and the addresses after it, or the cause of the last failure.
+	fn connect_first(&self, addresses: Vec<SocketAddr>) -> Result<(SocketAddr, (TcpStream, Option<SocketAddr>), Vec<SocketAddr>), DialFailure> {
+		let mut failure = DialFailure::Other;
+		let mut addresses = addresses.into_iter();
+		while let Some(address) = addresses.next() {
+			match TcpStream::connect(&address) {
+				Ok(socket) => return Ok((address, (socket, None), addresses.collect())),
+				Err(e) => {
+					debug!(target: "network", "Can't connect to address {:?}: {:?}", address, e);
+					failure = dial_failure(&e);
+				}
+			}
+		}
+		Err(failure)
+	}
+
+	/// Keep the addresses to try if the connection is refused, times out or our auth is not answered.
+	fn keep_fallback(&self, token: StreamToken, addresses: Vec<SocketAddr>) {
+		if !addresses.is_empty() {
+			self.dial_fallbacks.lock().insert(token, addresses);
+		}
+	}
+
+	/// Dial the addresses left for a node whose connection failed before the handshake completed.
+	/// The failure is recorded if none of them can be connected to.
+	fn dial_fallback(&self, id: &NodeId, failure: DialFailure, addresses: Vec<SocketAddr>, dial_error: Option<DialError>, io: &IoContext<NetworkIoMessage>) {
+		debug!(target: "network", "Connection to {} failed, trying {} more addresses", id.hex(), addresses.len());
+		self.connect_and_then(id, addresses, AfterConnect::Fallback { failure: failure, dial_error: dial_error }, io);
+	}
+
+	/// Dial the node now, outside of the maintenance cycle. The peer limits are ignored if `force` is set.
+	/// The node is added to the node table once the session is established.
+	pub fn dial(&self, node: Node, force: bool, io: &IoContext<NetworkIoMessage>) -> Receiver<DialResult> {
+		let id = node.id.clone();
+		let target = DialTarget::new(&node);
+		let receiver = self.dials.lock().add(node, force);
+		if target.needs_resolution() {
+			// The dial goes on in `process_resolved`.
+			self.resolve_in_background(&id, AfterResolve::Dial { force: force }, move |resolver| target.addresses(resolver), io);
+			return receiver;
+		}
+		let addresses = target.addresses(&**self.resolver.read());
+		if let Err(e) = self.start_dial(&id, addresses, force, io) {
+			debug!(target: "network", "Dial to {} failed: {:?}", id.hex(), e);
+			self.dials.lock().resolve(&id, Err(e));
+		}
+		receiver
+	}
+
+	fn start_dial(&self, id: &NodeId, addresses: Vec<SocketAddr>, force: bool, io: &IoContext<NetworkIoMessage>) -> Result<(), DialError> {
+		if self.have_session(id) {
+			return Err(DialError::AlreadyConnected);
+		}
+		let (self_id, slots, max_peers, reserved_only) = {
+			let info = self.info.read();
+			let config = &info.config;
+			(info.id().clone(), PeerSlots::new(config.min_peers, config.max_peers, config.inbound_ratio), config.max_peers as usize, config.non_reserved_mode == NonReservedPeerMode::Deny)
+		};
+		if *id == self_id || self.nodes.read().is_self(id) {
+			return Err(DialError::Rejected(DisconnectReason::LocalIdentity));
+		}
+		let reserved = self.reserved_nodes.read().contains(id);
+		if !force && !reserved {
+			let (egress_count, ingress_count) = self.non_reserved_session_count();
+			if reserved_only || !slots.allows(true, egress_count, ingress_count) || egress_count + ingress_count + self.reserved_session_count() >= max_peers {
+				return Err(DialError::Rejected(DisconnectReason::TooManyPeers));
+			}
+		}
+		if addresses.is_empty() {
+			return Err(DialError::HandshakeFailed);
+		}
+		let addresses: Vec<_> = addresses.into_iter().filter(|a| self.address_allowed(&a.ip(), reserved)).collect();
+		let peers: Vec<_> = self.session_addresses().into_iter().map(|(_, ip, direction)| (ip, direction)).collect();
+		if addresses.is_empty() || !self.connection_allowed(&ConnectionContext::new(&self_id, id, ConnectionDirection::Outbound, addresses.first().cloned(), reserved, &peers)) {
+			self.stats.inc_filtered();
+			return Err(DialError::Rejected(DisconnectReason::ConnectionFiltered));
+		}
+
+		// The pending dial is resolved by `connected` if the connection fails.
+		self.connect_and_then(id, addresses, AfterConnect::Dial, io);
+		Ok(())
+	}
+
+	fn remove_handler(&self, protocol: ProtocolId, io: &IoContext<NetworkIoMessage>) {
+		if self.handlers.write().remove(&protocol).is_none() {
+			debug!(target: "network", "Protocol {:?} is not registered", protocol);
 			return;
 		}
-		if self.connecting_to(id) {
-			trace!(target: "network", "Aborted connect. Node already connecting.");
-			return;
+		self.info.write().capabilities.retain(|c| c.protocol != protocol);
+		// Drop the packets held for the removed handler.
+		self.set_read_paused(protocol, false, io);
+
+		self.peer_ticks.lock().remove_protocol(protocol);
+		let timers = self.timers.lock().remove_protocol(protocol);
+		for token in timers {
+			io.clear_timer(token).unwrap_or_else(|e| debug!(target: "network", "Error clearing timer {}: {:?}", token, e));
 		}
 
-		let socket = {
-			let address = {
-				let mut nodes = self.nodes.write();
-				if let Some(node) = nodes.get_mut(id) {
-					node.attempts += 1;
-					node.endpoint.address
-				}
-				else {
-					debug!(target: "network", "Connection to expired node aborted");
-					return;
+		// Sessions with no other shared protocol are of no use anymore.
+		let remaining: Vec<ProtocolId> = self.handlers.read().keys().cloned().collect();
+		let to_kill: Vec<StreamToken> = {
+			self.sessions.read().iter().filter_map(|e| {
+				let s = e.lock();
+				if s.is_ready() && s.have_capability(protocol) && !remaining.iter().any(|p| s.have_capability(*p)) {
+					Some(s.token())
+				} else {
+					None
 				}
+			}).collect()
+		};
+		for token in to_kill {
+			let session = { self.sessions.read().get(token).cloned() };
+			if let Some(session) = session {
+				session.lock().disconnect(io, DisconnectReason::UselessPeer);
+			}
+			trace!(target: "network", "No shared protocols left with {}", token);
+			self.kill_connection(token, io, false);
+		}
+		debug!(target: "network", "Removed protocol handler {:?}", protocol);
+	}
+
+	/// Hold or deliver again the packets of the protocol on all sessions. Packets held
+	/// meanwhile are delivered in order received when reading is resumed.
+	fn set_read_paused(&self, protocol: ProtocolId, paused: bool, io: &IoContext<NetworkIoMessage>) {
+		{
+			let mut paused_protocols = self.paused_protocols.write();
+			let changed = if paused { paused_protocols.insert(protocol) } else { paused_protocols.remove(&protocol) };
+			if !changed {
+				return;
+			}
+		}
+		debug!(target: "network", "Reading {} for protocol {:?}", if paused { "paused" } else { "resumed" }, protocol);
+		let sessions: Vec<SharedSession> = self.sessions.read().iter().cloned().collect();
+		for session in sessions {
+			if paused {
+				session.lock().pause_protocol(protocol);
+				continue;
+			}
+			let (token, held) = {
+				let mut s = session.lock();
+				(s.token(), s.resume_protocol(io, protocol))
 			};
-			match TcpStream::connect(&address) {
-				Ok(socket) => {
-					trace!(target: "network", "Connecting to {:?}", address);
-					socket
-				},
-				Err(e) => {
-					debug!(target: "network", "Can't connect to address {:?}: {:?}", address, e);
-					return;
+			let handler = self.handlers.read().get(&protocol).cloned();
+			if let Some(h) = handler {
+				for (p, packet_id, data) in held {
+					let reserved = self.reserved_nodes.read();
+					self.deliver_packet(&*h, &NetworkContext::new(io, p, Some(session.clone()), self.sessions.clone(), &reserved, &self.timers, &self.requests), token, packet_id, &data);
 				}
 			}
-		};
+		}
+	}
+
+	/// Deliver a packet to the handler, as a response if it answers a pending request.
+	fn deliver_packet(&self, handler: &NetworkProtocolHandler, context: &NetworkContext, peer: PeerId, packet_id: PacketId, data: &[u8]) {
+		let route = self.requests.lock().route(peer, context.protocol, packet_id, data);
+		match route {
+			PacketRoute::Read => handler.read(context, &peer, packet_id, data),
+			PacketRoute::Response(request, payload) => handler.response(context, &peer, request, &payload),
+			PacketRoute::Drop => trace!(target: "network", "{}: Dropped response to no pending request", peer),
+		}
+	}
+
+	/// Notify the handlers of the requests not answered in time.
+	fn expire_requests(&self, io: &IoContext<NetworkIoMessage>) {
+		let expired = self.requests.lock().take_expired(time::precise_time_ns());
+		if expired.is_empty() {
+			return;
+		}
+		let handlers = self.handlers.read().clone();
+		let reserved = self.reserved_nodes.read();
+		for (protocol, request) in expired {
+			let session = self.sessions.read().get(request.peer).cloned();
+			if let Some(h) = handlers.get(&protocol) {
+				h.request_timed_out(&NetworkContext::new(io, protocol, session, self.sessions.clone(), &reserved, &self.timers, &self.requests), &request.peer, request);
+			}
+		}
+	}
 
-		if let Err(e) = self.create_connection(socket, Some(id), io) {
-			debug!(target: "network", "Can't create connection: {:?}", e);
+	fn note_failure(&self, id: &NodeId, failure: DialFailure) {
+		let reserved = self.reserved_nodes.read().contains(id);
+		self.nodes.write().note_dial_failure(id, reserved, failure);
+		self.boot_nodes.lock().note_failure(id, time::precise_time_ns());
+	}
+
+	/// Nodes to dial ahead of the rest of the node table while all boot nodes are failing.
+	fn emergency_seeds(&self) -> Vec<NodeId> {
+		let (fallback, changed) = {
+			let mut boot_nodes = self.boot_nodes.lock();
+			let changed = boot_nodes.update_fallback();
+			(boot_nodes.all_failed(), changed)
+		};
+		if !fallback {
+			if changed.is_some() {
+				info!(target: "network", "Boot nodes reachable again");
+			}
+			return Vec::new();
 		}
+		let seeds = self.nodes.read().recently_contacted(EMERGENCY_SEEDS);
+		if changed.is_some() {
+			info!(target: "network", "All boot nodes are unreachable, using {} recently contacted nodes as seeds", seeds.len());
+			let nodes = self.nodes.read();
+			let entries = seeds.iter()
+				.filter_map(|id| nodes.get(id))
+				.filter(|n| n.hostname.is_none())
+				.map(|n| NodeEntry { id: n.id.clone(), endpoint: n.endpoint.clone() })
+				.collect();
+			if let Some(ref mut discovery) = *self.discovery.lock() {
+				discovery.add_node_list(entries);
+			}
+		}
+		seeds
 	}
 
-	fn create_connection(&self, socket: TcpStream, id: Option<&NodeId>, io: &IoContext<NetworkIoMessage>) -> Result<(), Error> {
+	/// Create a session for the socket. Fails with `ErrorKind::AtCapacity` if all session slots
+	/// are taken. The socket is then dropped before the handshake starts, which closes the connection.
+	/// Sessions over the peer limit take one of the `max_handshakes` slots, and are disconnected with
+	/// `TooManyPeers` once their handshake completes.
+	fn create_connection(&self, socket: TcpStream, id: Option<&NodeId>, proxied_peer: Option<SocketAddr>, io: &IoContext<NetworkIoMessage>) -> Result<StreamToken, Error> {
+		let reserved_nodes = self.reserved_nodes.read().len();
+		let capacity = session_capacity(&self.info.read().config, reserved_nodes);
 		let nonce = self.info.write().next_nonce();
 		let mut sessions = self.sessions.write();
+		// The slab grows with the peer limits and reserved nodes, it is not shrunk when they are lowered.
+		let slots = self.session_slots.load(AtomicOrdering::Relaxed);
+		if capacity > slots {
+			sessions.grow(capacity - slots);
+			self.session_slots.store(capacity, AtomicOrdering::Relaxed);
+		}
 
+		let mut error = None;
 		let token = sessions.insert_with_opt(|token| {
 			match Session::new(io, socket, token, id, &nonce, self.stats.clone(), &self.info.read()) {
-				Ok(s) => Some(Arc::new(Mutex::new(s))),
+				Ok(mut s) => {
+					if let Some(address) = proxied_peer {
+						s.set_proxied_peer(address);
+					}
+					Some(Arc::new(Mutex::new(s)))
+				},
 				Err(e) => {
-					debug!(target: "network", "Session create error: {:?}", e);
+					error = Some(e);
 					None
 				}
 			}
 		});
 
-		match token {
-			Some(t) => io.register_stream(t).map(|_| ()).map_err(Into::into),
-			None => {
-				debug!(target: "network", "Max sessions reached");
-				Ok(())
+		match (token, error) {
+			(Some(t), _) => io.register_stream(t).map(|_| t).map_err(Into::into),
+			(None, Some(e)) => Err(e),
+			(None, None) => {
+				debug!(target: "network", "All {} session slots are taken", slots.max(capacity));
+				self.stats.inc_at_capacity();
+				Err(ErrorKind::AtCapacity.into())
 			}
 		}
 	}
 
-	fn accept(&self, io: &IoContext<NetworkIoMessage>) {
+	fn accept(&self, listener: usize, io: &IoContext<NetworkIoMessage>) {
 		trace!(target: "network", "Accepting incoming connection");
 		loop {
-			let socket = match self.tcp_listener.lock().accept() {
-				Ok((sock, _addr)) => sock,
+			let accepted = match self.tcp_listeners.lock().get(listener) {
+				Some(l) => l.accept(),
+				None => break,
+			};
+			let socket = match accepted {
+				Ok((sock, addr)) => {
+					let reserved = self.is_reserved_address(&addr.ip());
+					if !self.address_allowed(&addr.ip(), reserved) {
+						debug!(target: "network", "Incoming connection from {} not allowed by the IP lists", addr);
+						self.stats.inc_filtered();
+						self.stats.inc_handshake_failure(HandshakeFailure::Filtered);
+						continue;
+					}
+					// Refuse before creating the handshake, dropping the socket closes the connection.
+					let (max_handshakes, max_incoming) = {
+						let info = self.info.read();
+						(info.config.max_handshakes as usize, info.config.max_incoming_handshakes as usize)
+					};
+					let (outgoing, incoming) = self.handshake_count();
+					if !reserved && (incoming >= max_incoming || outgoing + incoming >= max_handshakes) {
+						trace!(target: "network", "Incoming connection from {} refused, {} handshakes in progress", addr, outgoing + incoming);
+						self.stats.inc_handshake_failure(HandshakeFailure::TooManyPeers);
+						continue;
+					}
+					sock
+				},
 				Err(e) => {
 					if e.kind() != io::ErrorKind::WouldBlock {
 						debug!(target: "network", "Error accepting connection: {:?}", e);
@@ -646,28 +2515,52 @@ impl Host {
 					break
 				},
 			};
-			if let Err(e) = self.create_connection(socket, None, io) {
-				debug!(target: "network", "Can't accept connection: {:?}", e);
+			if let Err(e) = self.create_connection(socket, None, None, io) {
+				match *e.kind() {
+					ErrorKind::AtCapacity => self.stats.inc_handshake_failure(HandshakeFailure::TooManyPeers),
+					_ => debug!(target: "network", "Can't accept connection: {:?}", e),
+				}
 			}
 		}
+		// Refresh the handshake count in stats.
+		self.handshake_count();
 	}
 
 	fn session_writable(&self, token: StreamToken, io: &IoContext<NetworkIoMessage>) {
 		let session = { self.sessions.read().get(token).cloned() };
 
 		if let Some(session) = session {
-			let mut s = session.lock();
-			if let Err(e) = s.writable(io, &self.info.read()) {
-				trace!(target: "network", "Session write error: {}: {:?}", token, e);
-			}
-			if s.done() {
+			let (done, draining, flushed) = {
+				let mut s = session.lock();
+				if let Err(e) = s.writable(io, &self.info.read()) {
+					trace!(target: "network", "Session write error: {}: {:?}", token, e);
+				}
+				(s.done(), s.is_draining(), !s.is_sending())
+			};
+			if done {
 				io.deregister_stream(token).unwrap_or_else(|e| debug!("Error deregistering stream: {:?}", e));
+			} else if draining && flushed {
+				self.finish_draining(token, io);
+			}
+			if flushed && self.stopping.load(AtomicOrdering::Acquire) {
+				self.note_flushed(token);
 			}
 		}
 	}
 
+	/// Stop waiting for the session to send its disconnect packet on shutdown.
+	fn note_flushed(&self, token: StreamToken) {
+		if self.shutdown_flush.lock().remove(&token) {
+			self.shutdown_flushed.notify_all();
+		}
+	}
+
 	fn connection_closed(&self, token: TimerToken, io: &IoContext<NetworkIoMessage>) {
 		trace!(target: "network", "Connection closed: {}", token);
+		// Process whatever is left in the socket first, the peer might have sent a Disconnect packet.
+		if !self.stopping.load(AtomicOrdering::Acquire) {
+			self.session_readable(token, io);
+		}
 		self.kill_connection(token, io, true);
 	}
 
@@ -677,12 +2570,20 @@ impl Host {
 		let mut kill = false;
 		let session = { self.sessions.read().get(token).cloned() };
 		let mut ready_id = None;
+		let mut connected_event = None;
+		let mut dialed_peer = None;
+		let mut evict = None;
+		let filter = self.filter.read().clone();
 		if let Some(session) = session.clone() {
 			{
 				loop {
-					let session_result = session.lock().readable(io, &self.info.read());
+					let session_result = session.lock().readable(io, &self.info.read(), filter.as_ref().map(|f| &**f));
 					match session_result {
 						Err(e) => {
+							if self.check_self_connection(&session, &e, io) {
+								kill = true;
+								break;
+							}
 							let s = session.lock();
 							trace!(target: "network", "Session read error: {}:{:?} ({:?}) {:?}", token, s.id(), s.remote_addr(), e);
 							if let ErrorKind::Disconnect(DisconnectReason::IncompatibleProtocol) = *e.kind() {
@@ -696,9 +2597,13 @@ impl Host {
 							break;
 						},
 						Ok(SessionData::Ready) => {
-							let (_, egress_count, ingress_count) = self.session_count();
+							let (egress_count, ingress_count) = self.non_reserved_session_count();
+							let reserved_count = self.reserved_session_count();
+							let peers = other_addresses(&self.session_addresses(), token);
+							let reservations = self.info.read().config.slot_reservations.clone();
+							let reservation_counts = self.reservation_counts(token, &reservations);
 							let mut s = session.lock();
-							let (min_peers, mut max_peers, reserved_only, self_id) = {
+							let (slots, max_peers, reserved_only, self_id, exempt_reserved) = {
 								let info = self.info.read();
 								let mut max_peers = info.config.max_peers;
 								for cap in s.info.capabilities.iter() {
@@ -707,36 +2612,119 @@ impl Host {
 										break;
 									}
 								}
-								(info.config.min_peers as usize, max_peers as usize, info.config.non_reserved_mode == NonReservedPeerMode::Deny, info.id().clone())
+								let slots = PeerSlots::new(info.config.min_peers, max_peers, info.config.inbound_ratio);
+								(slots, max_peers as usize, info.config.non_reserved_mode == NonReservedPeerMode::Deny, info.id().clone(), info.config.rate_limit_exempt_reserved)
 							};
 
-							max_peers = max(max_peers, min_peers);
-
 							let id = s.id().expect("Ready session always has id").clone();
 
-							// Check for the session limit.
-							// Outgoing connections are allowed as long as their count is <= min_peers
-							// Incoming connections are allowed to take all of the max_peers reserve, or at most half of the slots.
-							let max_ingress = max(max_peers - min_peers, min_peers / 2);
-							if reserved_only ||
-								(s.info.originated && egress_count > min_peers) ||
-								(!s.info.originated && ingress_count > max_ingress) {
-								// only proceed if the connecting peer is reserved.
-								if !self.reserved_nodes.read().contains(&id) {
+							if id == self_id {
+								trace!(target: "network", "Connected to self at {:?}", s.remote_addr());
+								self.stats.inc_handshake_failure(HandshakeFailure::SelfConnection);
+								s.disconnect(io, DisconnectReason::LocalIdentity);
+								kill = true;
+								break;
+							}
+
+							// Check for the session limit. Reserved peers are not counted against either direction,
+							// but take one of the `max_peers` slots. They are never refused: if all slots are taken,
+							// a non-reserved peer is disconnected to make room. Existing sessions over the limit
+							// are kept, only new ones are refused. Forced ad-hoc dials are exempt.
+							let forced = s.info.originated && self.dials.lock().is_forced(&id);
+							let reserved = self.reserved_nodes.read().contains(&id);
+							let total_count = egress_count + ingress_count + reserved_count;
+							if reserved && !forced && total_count > max_peers {
+								evict = self.eviction_victim(token, &reservations).or_else(|| self.eviction_victim(token, &[]));
+							}
+							if !forced && !reserved {
+								// Free reserved slots are only taken by peers they are kept for. Such a peer replaces
+								// another one if all slots are taken.
+								let claims_reservation = reservations.iter().zip(&reservation_counts)
+									.any(|(r, &count)| count < r.slots as usize && matches_reservation(&s, r));
+								let free_reserved: usize = reservations.iter().zip(&reservation_counts)
+									.map(|(r, &count)| (r.slots as usize).saturating_sub(count))
+									.sum();
+								let mut admitted = !reserved_only && slots.allows(s.info.originated, egress_count, ingress_count) && total_count <= max_peers;
+								if admitted && !claims_reservation && egress_count + ingress_count + free_reserved > slots.ingress + slots.egress {
+									admitted = false;
+								}
+								if !admitted && !reserved_only && claims_reservation {
+									evict = self.eviction_victim(token, &reservations);
+									admitted = evict.is_some();
+								}
+								if !admitted {
+									self.stats.inc_handshake_failure(HandshakeFailure::TooManyPeers);
 									s.disconnect(io, DisconnectReason::TooManyPeers);
 									kill = true;
 									break;
 								}
 							}
 
-							if !self.filter.as_ref().map_or(true, |f| f.connection_allowed(&self_id, &id, ConnectionDirection::Inbound)) {
-								trace!(target: "network", "Inbound connection not allowed for {:?}", id);
+							// Parked connections don't count against the session limit, it is checked again once the
+							// connection filter has decided.
+							let direction = if s.info.originated { ConnectionDirection::Outbound } else { ConnectionDirection::Inbound };
+							let allowed = match self.connection_decision(token, &ConnectionContext::new(&self_id, &id, direction, s.remote_addr().ok(), reserved, &peers)) {
+								Some(allowed) => allowed,
+								None => {
+									trace!(target: "network", "Waiting for the connection filter to decide on {:?}", id);
+									s.park();
+									evict = None;
+									break;
+								},
+							};
+							if !allowed {
+								trace!(target: "network", "Connection not allowed for {:?}", id);
+								self.stats.inc_filtered();
+								self.stats.inc_handshake_failure(HandshakeFailure::Filtered);
+								s.note_dial_failure(DialFailure::Filtered);
 								s.disconnect(io, DisconnectReason::UnexpectedIdentity);
 								kill = true;
 								break;
 							}
 
+							// Incoming connections from the address of a reserved node are accepted before the node id is known.
+							if !s.remote_addr().map(|a| self.address_allowed(&a.ip(), reserved)).unwrap_or(true) {
+								trace!(target: "network", "Address of {:?} not allowed", id);
+								self.stats.inc_filtered();
+								self.stats.inc_handshake_failure(HandshakeFailure::Filtered);
+								s.note_dial_failure(DialFailure::Filtered);
+								s.disconnect(io, DisconnectReason::ConnectionFiltered);
+								kill = true;
+								break;
+							}
+
+							if !reserved && self.nodes.read().is_blocked(&id, time::get_time().sec as u64) {
+								trace!(target: "network", "Node {:?} is banned or misbehaving", id);
+								self.stats.inc_handshake_failure(HandshakeFailure::Filtered);
+								s.note_dial_failure(DialFailure::Filtered);
+								s.disconnect(io, DisconnectReason::UselessPeer);
+								kill = true;
+								break;
+							}
+
+							if exempt_reserved && self.reserved_nodes.read().contains(&id) {
+								s.disable_rate_limit(io);
+							}
+
 							ready_id = Some(id);
+							connected_event = Some(NetworkEvent::PeerConnected {
+								node_id: id,
+								address: s.remote_addr().ok(),
+								direction: direction,
+								caps: s.info.peer_capabilities.clone(),
+							});
+							if s.info.originated {
+								let dialed = self.dials.lock().take_node(&id);
+								if let Some(node) = dialed {
+									self.nodes.write().add_node(node);
+								}
+								dialed_peer = Some(DialedPeer { node_id: id, peer: token, caps: s.info.capabilities.clone() });
+							}
+							self.nodes.write().note_contact(&id);
+							if s.info.originated {
+								self.nodes.write().note_dialed(&id);
+							}
+							self.boot_nodes.lock().note_success(&id, time::precise_time_ns());
 
 							// Add it to the node table
 							if !s.info.originated {
@@ -746,7 +2734,9 @@ impl Host {
 									let entry = NodeEntry { id: id, endpoint: endpoint };
 									let mut nodes = self.nodes.write();
 									if !nodes.contains(&entry.id) {
-										nodes.add_node(Node::new(entry.id.clone(), entry.endpoint.clone()));
+										let mut node = Node::new(entry.id.clone(), entry.endpoint.clone());
+										node.source = NodeSource::Incoming;
+										nodes.add_node(node);
 										let mut discovery = self.discovery.lock();
 										if let Some(ref mut discovery) = *discovery {
 											discovery.add_node(entry);
@@ -754,6 +2744,7 @@ impl Host {
 									}
 								}
 							}
+							self.nodes.write().note_capabilities(&id, s.info.peer_capabilities.clone(), time::get_time().sec as u64);
 							for (p, _) in self.handlers.read().iter() {
 								if s.have_capability(*p) {
 									ready_data.push(*p);
@@ -766,7 +2757,8 @@ impl Host {
 							packet_id,
 						}) => {
 							match self.handlers.read().get(&protocol) {
-								None => { warn!(target: "network", "No handler found for protocol: {:?}", protocol) },
+								// The handler may have been removed while the session was active.
+								None => { trace!(target: "network", "No handler found for protocol: {:?}", protocol) },
 								Some(_) => packet_data.push((protocol, packet_id, data)),
 							}
 						},
@@ -778,6 +2770,9 @@ impl Host {
 
 			if kill {
 				self.kill_connection(token, io, true);
+			} else if let Some(victim) = evict {
+				debug!(target: "network", "Disconnecting {} to make room for {}", victim, token);
+				self.disconnect_gracefully(victim, DisconnectReason::TooManyPeers, io);
 			}
 
 			let handlers = self.handlers.read();
@@ -792,22 +2787,42 @@ impl Host {
 					self.kill_connection(token, io, false);
 					return;
 				}
+				if let Some(dialed) = dialed_peer {
+					let id = dialed.node_id.clone();
+					self.dials.lock().resolve(&id, Ok(dialed));
+				}
+				if let Some(event) = connected_event {
+					self.stats.inc_established();
+					self.events.publish(event);
+				}
 				for p in ready_data {
 					self.stats.inc_sessions();
 					let reserved = self.reserved_nodes.read();
 					if let Some(h) = handlers.get(&p).clone() {
-						h.connected(&NetworkContext::new(io, p, Some(session.clone()), self.sessions.clone(), &reserved), &token);
+						h.connected(&NetworkContext::new(io, p, Some(session.clone()), self.sessions.clone(), &reserved, &self.timers, &self.requests), &token);
+						{
+							// A session expired meanwhile has already been removed from the schedule.
+							let mut peer_ticks = self.peer_ticks.lock();
+							if !session.lock().expired() {
+								peer_ticks.add_peer(p, token, time::precise_time_ns());
+							}
+						}
 						// accumulate pending packets.
 						let mut session = session.lock();
+						if self.paused_protocols.read().contains(&p) {
+							session.pause_protocol(p);
+						}
 						packet_data.extend(session.mark_connected(p));
 					}
 				}
+				// Packets held until the session was ready go after whatever the handlers sent on connect.
+				session.lock().send_deferred(io);
 			}
 
 			for (p, packet_id, data) in packet_data {
 				let reserved = self.reserved_nodes.read();
 				if let Some(h) = handlers.get(&p).clone() {
-					h.read(&NetworkContext::new(io, p, Some(session.clone()), self.sessions.clone(), &reserved), &token, packet_id, &data);
+					self.deliver_packet(&**h, &NetworkContext::new(io, p, Some(session.clone()), self.sessions.clone(), &reserved, &self.timers, &self.requests), token, packet_id, &data);
 				}
 			}
 		}
@@ -815,19 +2830,61 @@ impl Host {
 
 	fn connection_timeout(&self, token: StreamToken, io: &IoContext<NetworkIoMessage>) {
 		trace!(target: "network", "Connection timeout: {}", token);
+		let session = { self.sessions.read().get(token).cloned() };
+		let mut timed_out = None;
+		if let Some(session) = session {
+			let mut s = session.lock();
+			if s.is_ready() || s.is_parked() {
+				// Expiration of the Hello timer queued before Hello was received. Parked connections
+				// time out with the connection filter decision.
+				return;
+			}
+			if !s.expired() {
+				if s.awaiting_hello() {
+					debug!(target: "network", "{}: No Hello received in time from {:?}", token, s.id());
+					self.stats.inc_handshake_failure(HandshakeFailure::HelloTimeout);
+				} else {
+					self.stats.inc_handshake_failure(HandshakeFailure::Timeout);
+				}
+				if s.info.originated {
+					s.note_timeout();
+					timed_out = s.id().cloned();
+				}
+			}
+		}
+		if let Some(id) = timed_out {
+			// Dials with addresses left are resolved once those have been tried.
+			if !self.dial_fallbacks.lock().contains_key(&token) {
+				self.dials.lock().resolve(&id, Err(DialError::Timeout));
+			}
+		}
 		self.kill_connection(token, io, true)
 	}
 
 	fn kill_connection(&self, token: StreamToken, io: &IoContext<NetworkIoMessage>, remote: bool) {
 		let mut to_disconnect: Vec<ProtocolId> = Vec::new();
-		let mut failure_id = None;
+		let mut failure = None;
 		let mut deregister = false;
 		let mut expired_session = None;
+		let mut disconnected_event = None;
+		let mut dropped_event = None;
+		let mut dial_failure = None;
+		let mut retry = None;
+		let fallback = self.dial_fallbacks.lock().remove(&token);
+		if self.stopping.load(AtomicOrdering::Acquire) {
+			self.note_flushed(token);
+		}
 		if let FIRST_SESSION ... LAST_SESSION = token {
 			let sessions = self.sessions.read();
 			if let Some(session) = sessions.get(token).cloned() {
 				expired_session = Some(session.clone());
+				self.pending_decisions.lock().remove(token);
 				let mut s = session.lock();
+				let dropped = s.drop_deferred();
+				if dropped != 0 {
+					debug!(target: "network", "{}: Dropped {} packets held until ready", token, dropped);
+					dropped_event = Some(NetworkEvent::DeferredPacketsDropped { peer: token, packets: dropped });
+				}
 				if !s.expired() {
 					if s.is_ready() {
 						for (p, _) in self.handlers.read().iter() {
@@ -836,26 +2893,122 @@ impl Host {
 							}
 						}
 					}
+					if s.is_ready() {
+						self.stats.inc_disconnect(s.info.disconnect_reason, s.disconnect_origin());
+					}
+					if s.has_connected_protocol() {
+						self.stats.inc_ended(s.connected_at_ns().map_or(0, |t| time::precise_time_ns().saturating_sub(t) / 1000_000_000));
+						if let Some(id) = s.id() {
+							disconnected_event = Some(NetworkEvent::PeerDisconnected { node_id: id.clone(), reason: s.info.disconnect_reason });
+						}
+					}
+					if s.info.originated {
+						dial_failure = s.id().map(|id| (id.clone(), DialError::from_reason(s.info.disconnect_reason)));
+					}
 					s.set_expired();
-					failure_id = s.id().cloned();
+					// Sessions lost after they were established are not dial failures.
+					let dialed = s.info.originated && !s.has_connected_protocol();
+					failure = s.id().cloned().map(|id| (id, s.dial_failure(), dialed));
 				}
 				deregister = remote || s.done();
 			}
 		}
-		if let Some(id) = failure_id {
+		if let Some((id, kind, dialed)) = failure {
 			if remote {
-				self.nodes.write().note_failure(&id);
+				// Another address of the node may be reachable, or be the node we meant to dial.
+				let unreachable = match kind {
+					DialFailure::Refused | DialFailure::Timeout | DialFailure::WrongNodeId => true,
+					_ => false,
+				};
+				if dialed && unreachable && fallback.is_some() && !self.stopping.load(AtomicOrdering::Acquire) {
+					retry = fallback.map(|addresses| (id, kind, addresses));
+				} else {
+					if dialed {
+						self.stats.inc_dial_failure(kind);
+					}
+					self.note_failure(&id, kind);
+				}
+			}
+		}
+		// Dials retried at another address are resolved by the retry.
+		let dial_error = match dial_failure {
+			Some((id, error)) => if retry.is_none() {
+				self.dials.lock().resolve(&id, Err(error));
+				None
+			} else {
+				Some(error)
+			},
+			None => None,
+		};
+		if let Some(event) = disconnected_event {
+			self.events.publish(event);
+		}
+		if let Some(event) = dropped_event {
+			self.events.publish(event);
+		}
+		if !to_disconnect.is_empty() {
+			// Waits for a tick being delivered to the peer.
+			self.peer_ticks.lock().remove_peer(token);
+		}
+		let unanswered = self.requests.lock().take_peer(token);
+		for (p, request) in unanswered {
+			let reserved = self.reserved_nodes.read();
+			if let Some(h) = self.handlers.read().get(&p).clone() {
+				h.request_timed_out(&NetworkContext::new(io, p, expired_session.clone(), self.sessions.clone(), &reserved, &self.timers, &self.requests), &token, request);
 			}
 		}
 		for p in to_disconnect {
 			let reserved = self.reserved_nodes.read();
 			if let Some(h) = self.handlers.read().get(&p).clone() {
-				h.disconnected(&NetworkContext::new(io, p, expired_session.clone(), self.sessions.clone(), &reserved), &token);
+				h.disconnected(&NetworkContext::new(io, p, expired_session.clone(), self.sessions.clone(), &reserved, &self.timers, &self.requests), &token);
 			}
 		}
 		if deregister {
 			io.deregister_stream(token).unwrap_or_else(|e| debug!("Error deregistering stream: {:?}", e));
 		}
+		if let Some((id, kind, addresses)) = retry {
+			self.dial_fallback(&id, kind, addresses, dial_error, io);
+		}
+	}
+
+	/// Add a misbehaviour report to the node's score. Disconnects the peer when the score reaches the
+	/// threshold, and bans it on fatal reports. Reserved peers are scored but kept.
+	fn report_peer(&self, peer: PeerId, report: &PeerReport, io: &IoContext<NetworkIoMessage>) {
+		let session = { self.sessions.read().get(peer).cloned() };
+		let session = match session {
+			Some(session) => session,
+			None => return,
+		};
+		let id = match session.lock().id() {
+			Some(id) => id.clone(),
+			None => return,
+		};
+		let (threshold, ban) = {
+			let info = self.info.read();
+			(info.config.misbehaviour_threshold, info.config.misbehaviour_ban)
+		};
+		let penalty = match report.severity {
+			Severity::Minor => MINOR_MISBEHAVIOUR_PENALTY,
+			Severity::Major => MAJOR_MISBEHAVIOUR_PENALTY,
+			Severity::Fatal => threshold,
+		};
+		let now = time::get_time().sec as u64;
+		let score = self.nodes.write().report_misbehaviour(&id, penalty, now);
+		debug!(target: "network", "Peer {} reported ({:?}): {}, score {:?}", peer, report.severity, report.reason, score);
+		if self.reserved_nodes.read().contains(&id) {
+			return;
+		}
+
+		let reason = if report.severity == Severity::Fatal {
+			self.nodes.write().ban(&id, now + ban.as_secs());
+			DisconnectReason::BadProtocol
+		} else if threshold != 0 && score.map_or(false, |score| score >= threshold) {
+			DisconnectReason::UselessPeer
+		} else {
+			return;
+		};
+		session.lock().disconnect(io, reason);
+		self.kill_connection(peer, io, false);
 	}
 
 	fn update_nodes(&self, _io: &IoContext<NetworkIoMessage>, node_changes: TableUpdates) {
@@ -874,20 +3027,66 @@ impl Host {
 		for i in to_remove {
 			trace!(target: "network", "Removed from node table: {}", i);
 		}
+		let discovered: Vec<NodeId> = {
+			let nodes = self.nodes.read();
+			node_changes.added.keys().filter(|id| !nodes.contains(id)).cloned().collect()
+		};
 		self.nodes.write().update(node_changes, &*self.reserved_nodes.read());
+		for id in discovered {
+			self.events.publish(NetworkEvent::Discovered { node_id: id });
+		}
+	}
+
+	/// Add the nodes announced on the local network to the node table.
+	fn add_lan_nodes(&self, entries: Vec<NodeEntry>) {
+		let reserved = self.reserved_nodes.read();
+		let mut discovered = Vec::new();
+		{
+			let mut nodes = self.nodes.write();
+			for entry in entries {
+				let id = entry.id.clone();
+				if nodes.add_lan_node(entry, &*reserved) {
+					discovered.push(id);
+				}
+			}
+		}
+		for id in discovered {
+			debug!(target: "network", "Discovered {} on the local network", id);
+			self.events.publish(NetworkEvent::Discovered { node_id: id });
+		}
 	}
 
 	pub fn with_context<F>(&self, protocol: ProtocolId, io: &IoContext<NetworkIoMessage>, action: F) where F: FnOnce(&NetworkContextTrait) {
 		let reserved = { self.reserved_nodes.read() };
 
-		let context = NetworkContext::new(io, protocol, None, self.sessions.clone(), &reserved);
+		let context = NetworkContext::new(io, protocol, None, self.sessions.clone(), &reserved, &self.timers, &self.requests);
 		action(&context);
 	}
 
+	/// Call `peer_tick` of the handlers for the peers whose ticks are due. The schedule stays locked
+	/// meanwhile, so that disconnects wait for the ticks to be delivered.
+	fn deliver_peer_ticks(&self, io: &IoContext<NetworkIoMessage>) {
+		let handlers = self.handlers.read().clone();
+		let reserved = self.reserved_nodes.read();
+		let mut peer_ticks = self.peer_ticks.lock();
+		for (protocol, peer) in peer_ticks.take_due(time::precise_time_ns()) {
+			let session = match self.sessions.read().get(peer).cloned() {
+				Some(session) => session,
+				None => continue,
+			};
+			if session.lock().expired() {
+				continue;
+			}
+			if let Some(h) = handlers.get(&protocol) {
+				h.peer_tick(&NetworkContext::new(io, protocol, Some(session.clone()), self.sessions.clone(), &reserved, &self.timers, &self.requests), &peer);
+			}
+		}
+	}
+
 	pub fn with_context_eval<F, T>(&self, protocol: ProtocolId, io: &IoContext<NetworkIoMessage>, action: F) -> T where F: FnOnce(&NetworkContextTrait) -> T {
 		let reserved = { self.reserved_nodes.read() };
 
-		let context = NetworkContext::new(io, protocol, None, self.sessions.clone(), &reserved);
+		let context = NetworkContext::new(io, protocol, None, self.sessions.clone(), &reserved, &self.timers, &self.requests);
 		action(&context)
 	}
 }
@@ -895,8 +3094,17 @@ impl Host {
 impl IoHandler<NetworkIoMessage> for Host {
 	/// Initialize networking
 	fn initialize(&self, io: &IoContext<NetworkIoMessage>) {
-		io.register_timer(IDLE, MAINTENANCE_TIMEOUT).expect("Error registering Network idle timer");
-		io.message(NetworkIoMessage::InitPublicInterface).unwrap_or_else(|e| warn!("Error sending IO notification: {:?}", e));
+		io.register_timer(IDLE, self.maintain_interval_ms()).expect("Error registering Network idle timer");
+		io.register_timer(FILTER_DECISIONS, FILTER_DECISIONS_TIMEOUT).expect("Error registering connection filter timer");
+		io.register_timer(REQUEST_TIMEOUTS, REQUEST_TIMEOUTS_TIMEOUT).expect("Error registering request timeout timer");
+		let burst_interval = {
+			let burst = self.startup_burst.lock();
+			if burst.is_active() { Some(burst.interval_ms()) } else { None }
+		};
+		if let Some(interval) = burst_interval {
+			io.register_timer(STARTUP_BURST, interval).expect("Error registering startup burst timer");
+		}
+		io.message_self(NetworkIoMessage::InitPublicInterface).unwrap_or_else(|e| warn!("Error sending IO notification: {:?}", e));
 		self.maintain_network(io)
 	}
 
@@ -920,17 +3128,20 @@ impl IoHandler<NetworkIoMessage> for Host {
 					self.update_nodes(io, node_changes);
 				}
 			},
-			TCP_ACCEPT => self.accept(io),
+			LAN_DISCOVERY => {
+				let nodes = { self.lan_discovery.lock().as_ref().map_or_else(Vec::new, |d| d.readable()) };
+				self.add_lan_nodes(nodes);
+			},
+			TCP_ACCEPT ... LAST_TCP_ACCEPT => self.accept(stream - TCP_ACCEPT, io),
 			_ => panic!("Received unknown readable token"),
 		}
 	}
 
 	fn stream_writable(&self, io: &IoContext<NetworkIoMessage>, stream: StreamToken) {
-		if self.stopping.load(AtomicOrdering::Acquire) {
-			return;
-		}
 		match stream {
+			// Sessions keep flushing while stopping to deliver disconnect packets.
 			FIRST_SESSION ... LAST_SESSION => self.session_writable(stream, io),
+			_ if self.stopping.load(AtomicOrdering::Acquire) => {},
 			DISCOVERY => {
 				self.discovery.lock().as_mut().map(|d| d.writable(io));
 			}
@@ -954,22 +3165,47 @@ impl IoHandler<NetworkIoMessage> for Host {
 				if let Some(node_changes) = node_changes {
 					self.update_nodes(io, node_changes);
 				}
+				let finished = { self.discovery.lock().as_mut().map_or_else(Vec::new, |d| d.take_finished_lookups()) };
+				for (result, nearest) in finished {
+					result.send(self.with_node_info(nearest)).ok();
+				}
 				io.update_registration(DISCOVERY).unwrap_or_else(|e| debug!("Error updating discovery registration: {:?}", e));
 			},
 			NODE_TABLE => {
 				trace!(target: "network", "Refreshing node table");
 				self.nodes.write().clear_useless();
-				self.nodes.write().save();
+				if self.info.read().config.node_table_save_interval != Duration::from_secs(0) {
+					self.nodes.read().save();
+				}
 			},
-			_ => match self.timers.read().get(&token).cloned() {
-				Some(timer) => match self.handlers.read().get(&timer.protocol).cloned() {
-					None => { warn!(target: "network", "No handler found for protocol: {:?}", timer.protocol) },
-					Some(h) => {
-						let reserved = self.reserved_nodes.read();
-						h.timeout(&NetworkContext::new(io, timer.protocol, None, self.sessions.clone(), &reserved), timer.token);
-					}
-				},
-				None => { warn!("Unknown timer token: {}", token); } // timer is not registerd through us
+			EXTERNAL_ADDRESS => self.recheck_external_address(io),
+			RESERVED_RESOLVE => self.resolve_reserved_nodes(io),
+			FILTER_DECISIONS => self.resume_parked_connections(io),
+			PEER_TICKS => self.deliver_peer_ticks(io),
+			REQUEST_TIMEOUTS => self.expire_requests(io),
+			STARTUP_BURST => {
+				if self.startup_burst.lock().is_active() {
+					self.connect_peers(io);
+				} else {
+					io.clear_timer(STARTUP_BURST).unwrap_or_else(|e| debug!(target: "network", "Error clearing startup burst timer: {:?}", e));
+				}
+			},
+			LAN_ANNOUNCE => {
+				self.lan_discovery.lock().as_ref().map(|d| d.announce());
+			},
+			_ => {
+				let timer = self.timers.lock().expired(token);
+				match timer {
+					Some(timer) => match self.handlers.read().get(&timer.protocol).cloned() {
+						None => { warn!(target: "network", "No handler found for protocol: {:?}", timer.protocol) },
+						Some(h) => {
+							let reserved = self.reserved_nodes.read();
+							h.timeout(&NetworkContext::new(io, timer.protocol, None, self.sessions.clone(), &reserved, &self.timers, &self.requests), timer.token);
+						}
+					},
+					// cancelled, replaced or not registered through us
+					None => { trace!(target: "network", "Ignoring timer token: {}", token); }
+				}
 			}
 		}
 	}
@@ -983,43 +3219,57 @@ impl IoHandler<NetworkIoMessage> for Host {
 				ref handler,
 				ref protocol,
 				ref versions,
-				ref packet_count,
+				ref peer_tick,
 			} => {
 				let h = handler.clone();
 				let reserved = self.reserved_nodes.read();
 				h.initialize(
-					&NetworkContext::new(io, *protocol, None, self.sessions.clone(), &reserved),
+					&NetworkContext::new(io, *protocol, None, self.sessions.clone(), &reserved, &self.timers, &self.requests),
 					&*self.info.read(),
 				);
 				self.handlers.write().insert(*protocol, h);
+				if let Some(interval) = *peer_tick {
+					if self.peer_ticks.lock().set_interval(*protocol, interval) {
+						io.register_timer(PEER_TICKS, PEER_TICKS_TIMEOUT).unwrap_or_else(|e| debug!(target: "network", "Error registering peer tick timer: {:?}", e));
+					}
+				}
 				let mut info = self.info.write();
-				for v in versions {
-					info.capabilities.push(CapabilityInfo { protocol: *protocol, version: *v, packet_count: *packet_count });
+				for &(version, packet_count) in versions {
+					info.capabilities.push(CapabilityInfo { protocol: *protocol, version: version, packet_count: packet_count });
 				}
 			},
+			NetworkIoMessage::RemoveHandler { ref protocol } => self.remove_handler(*protocol, io),
 			NetworkIoMessage::AddTimer {
 				ref protocol,
 				ref delay,
 				ref token,
 			} => {
-				let handler_token = {
-					let mut timer_counter = self.timer_counter.write();
-					let counter = &mut *timer_counter;
-					let handler_token = *counter;
-					*counter += 1;
-					handler_token
-				};
-				self.timers.write().insert(handler_token, ProtocolTimer { protocol: *protocol, token: *token });
-				io.register_timer(handler_token, *delay).unwrap_or_else(|e| debug!("Error registering timer {}: {:?}", token, e));
+				if !self.handlers.read().contains_key(protocol) {
+					debug!(target: "network", "Ignoring timer for removed protocol {:?}", protocol);
+					return;
+				}
+				let reserved = self.reserved_nodes.read();
+				NetworkContext::new(io, *protocol, None, self.sessions.clone(), &reserved, &self.timers, &self.requests)
+					.register_timer(*token, *delay)
+					.unwrap_or_else(|e| debug!("Error registering timer {}: {:?}", token, e));
 			},
-			NetworkIoMessage::Disconnect(ref peer) => {
+			NetworkIoMessage::Disconnect { ref peer, ref reason, ban } => {
 				let session = { self.sessions.read().get(*peer).cloned() };
 				if let Some(session) = session {
-					session.lock().disconnect(io, DisconnectReason::DisconnectRequested);
+					let mut s = session.lock();
+					s.disconnect(io, *reason);
+					if ban {
+						if let Some(id) = s.id() {
+							self.nodes.write().mark_as_useless(id)
+						}
+					}
+					self.stats.inc_requested_disconnect(*reason);
 				}
-				trace!(target: "network", "Disconnect requested {}", peer);
+				trace!(target: "network", "Disconnect requested {} ({}, ban: {})", peer, reason, ban);
 				self.kill_connection(*peer, io, false);
 			},
+			NetworkIoMessage::ReportPeer { ref peer, ref report } => self.report_peer(*peer, report, io),
+			NetworkIoMessage::SetReadPaused { ref protocol, paused } => self.set_read_paused(*protocol, paused, io),
 			NetworkIoMessage::DisablePeer(ref peer) => {
 				let session = { self.sessions.read().get(*peer).cloned() };
 				if let Some(session) = session {
@@ -1033,56 +3283,243 @@ impl IoHandler<NetworkIoMessage> for Host {
 			},
 			NetworkIoMessage::InitPublicInterface =>
 				self.init_public_interface(io).unwrap_or_else(|e| warn!("Error initializing public interface: {:?}", e)),
+			NetworkIoMessage::NodesResolved => self.process_resolved(io),
+			NetworkIoMessage::ProxyConnected => self.process_proxied(io),
 			_ => {}	// ignore others.
 		}
 	}
 
-	fn register_stream(&self, stream: StreamToken, reg: Token, event_loop: &mut EventLoop<IoManager<NetworkIoMessage>>) {
+	fn register_stream(&self, stream: StreamToken, reg: Token, poll: &Poll) {
 		match stream {
 			FIRST_SESSION ... LAST_SESSION => {
 				let session = { self.sessions.read().get(stream).cloned() };
 				if let Some(session) = session {
-					session.lock().register_socket(reg, event_loop).expect("Error registering socket");
+					session.lock().register_socket(reg, poll).expect("Error registering socket");
 				}
 			}
-			DISCOVERY => self.discovery.lock().as_ref().and_then(|d| d.register_socket(event_loop).ok()).expect("Error registering discovery socket"),
-			TCP_ACCEPT => event_loop.register(&*self.tcp_listener.lock(), Token(TCP_ACCEPT), Ready::all(), PollOpt::edge()).expect("Error registering stream"),
+			DISCOVERY => self.discovery.lock().as_ref().and_then(|d| d.register_socket(poll).ok()).expect("Error registering discovery socket"),
+			LAN_DISCOVERY => self.lan_discovery.lock().as_ref().and_then(|d| d.register_socket(poll).ok()).expect("Error registering LAN discovery socket"),
+			TCP_ACCEPT ... LAST_TCP_ACCEPT => {
+				if let Some(listener) = self.tcp_listeners.lock().get(stream - TCP_ACCEPT) {
+					poll.register(listener, Token(stream), Ready::all(), PollOpt::edge()).expect("Error registering stream");
+				}
+			},
 			_ => warn!("Unexpected stream registration")
 		}
 	}
 
-	fn deregister_stream(&self, stream: StreamToken, event_loop: &mut EventLoop<IoManager<NetworkIoMessage>>) {
+	fn deregister_stream(&self, stream: StreamToken, poll: &Poll) {
 		match stream {
 			FIRST_SESSION ... LAST_SESSION => {
 				let mut connections = self.sessions.write();
 				if let Some(connection) = connections.get(stream).cloned() {
 					let c = connection.lock();
 					if c.expired() { // make sure it is the same connection that the event was generated for
-						c.deregister_socket(event_loop).expect("Error deregistering socket");
+						c.deregister_socket(poll).expect("Error deregistering socket");
 						connections.remove(stream);
 					}
 				}
 			}
-			DISCOVERY => (),
+			DISCOVERY | LAN_DISCOVERY => (),
 			_ => warn!("Unexpected stream deregistration")
 		}
 	}
 
-	fn update_stream(&self, stream: StreamToken, reg: Token, event_loop: &mut EventLoop<IoManager<NetworkIoMessage>>) {
+	fn update_stream(&self, stream: StreamToken, reg: Token, poll: &Poll) {
 		match stream {
 			FIRST_SESSION ... LAST_SESSION => {
 				let connection = { self.sessions.read().get(stream).cloned() };
 				if let Some(connection) = connection {
-					connection.lock().update_socket(reg, event_loop).expect("Error updating socket");
+					connection.lock().update_socket(reg, poll).expect("Error updating socket");
 				}
 			}
-			DISCOVERY => self.discovery.lock().as_ref().and_then(|d| d.update_registration(event_loop).ok()).expect("Error reregistering discovery socket"),
-			TCP_ACCEPT => event_loop.reregister(&*self.tcp_listener.lock(), Token(TCP_ACCEPT), Ready::all(), PollOpt::edge()).expect("Error reregistering stream"),
+			DISCOVERY => self.discovery.lock().as_ref().and_then(|d| d.update_registration(poll).ok()).expect("Error reregistering discovery socket"),
+			LAN_DISCOVERY => self.lan_discovery.lock().as_ref().and_then(|d| d.update_registration(poll).ok()).expect("Error reregistering LAN discovery socket"),
+			TCP_ACCEPT ... LAST_TCP_ACCEPT => {
+				if let Some(listener) = self.tcp_listeners.lock().get(stream - TCP_ACCEPT) {
+					poll.reregister(listener, Token(stream), Ready::all(), PollOpt::edge()).expect("Error reregistering stream");
+				}
+			},
 			_ => warn!("Unexpected stream update")
 		}
 	}
 }
 
+/// Connect through the proxy to the first of the addresses it can reach. Blocks for up to the proxy
+/// timeout for each step of each attempt, so it is run off the IO thread.
+fn connect_first_through(proxy: &SocksConfig, addresses: Vec<SocketAddr>) -> Result<(SocketAddr, ::std::net::TcpStream, Vec<SocketAddr>), DialFailure> {
+	let mut failure = DialFailure::Other;
+	let mut addresses = addresses.into_iter();
+	while let Some(address) = addresses.next() {
+		match socks::connect(proxy, &address) {
+			Ok(stream) => return Ok((address, stream, addresses.collect())),
+			Err(e) => {
+				debug!(target: "network", "Can't connect to address {:?} through the proxy: {:?}", address, e);
+				failure = dial_failure(&e);
+			}
+		}
+	}
+	Err(failure)
+}
+
+/// Dial failure cause of a connection error.
+fn dial_failure(e: &io::Error) -> DialFailure {
+	match e.kind() {
+		io::ErrorKind::ConnectionRefused => DialFailure::Refused,
+		io::ErrorKind::TimedOut => DialFailure::Timeout,
+		_ => DialFailure::Other,
+	}
+}
+
+fn other_addresses(peers: &[(StreamToken, IpAddr, ConnectionDirection)], token: StreamToken) -> Vec<(IpAddr, ConnectionDirection)> {
+	peers.iter().filter(|&&(t, _, _)| t != token).map(|&(_, ip, direction)| (ip, direction)).collect()
+}
+
+/// Number of sessions kept at most: room for `max_peers` with the extra slots of reserved protocols
+/// and reserved nodes, and for `max_handshakes` more in progress. Bounded by the session token range.
+fn session_capacity(config: &NetworkConfiguration, reserved_nodes: usize) -> usize {
+	let reserved_protocols: usize = config.reserved_protocols.values().map(|n| *n as usize).sum();
+	let peers = config.max_peers as usize + reserved_protocols + reserved_nodes;
+	min(peers + config.max_handshakes as usize, MAX_SESSIONS)
+}
+
+/// Directory the node table of the configured network is stored in.
+fn node_table_path(config: &NetworkConfiguration) -> Option<String> {
+	config.net_config_path.as_ref().map(|path| match config.network_id {
+		Some(id) => Path::new(path).join(format!("network-{}", id)).to_string_lossy().into_owned(),
+		None => path.clone(),
+	})
+}
+
+/// Load the node table of the configured network.
+fn create_node_table(config: &NetworkConfiguration) -> NodeTable {
+	let mut node_table = NodeTable::new(node_table_path(config));
+	node_table.set_max_size(config.node_table_max_size);
+	node_table.set_misbehaviour_limits(config.misbehaviour_threshold, config.misbehaviour_window.as_secs());
+	node_table.set_dial_backoff(config.dial_backoff, config.dial_max_backoff, config.reserved_dial_max_backoff);
+	node_table.set_quarantine(config.quarantine_threshold, config.quarantine_period);
+	node_table.set_capability_max_age(config.capability_max_age);
+	node_table
+}
+
+/// Boot node health tracking for the configured boot nodes.
+fn create_boot_nodes(config: &NetworkConfiguration) -> BootNodes {
+	BootNodes::new(
+		config.boot_nodes.iter().filter_map(|n| Node::from_str(n).ok()).map(|n| n.id),
+		config.boot_node_backoff,
+		config.boot_node_max_backoff,
+		config.boot_node_fallback_threshold,
+	)
+}
+
+/// Public address detectors enabled by the configuration, in order of precedence.
+fn address_detectors(config: &NetworkConfiguration) -> Vec<Box<AddressDetector>> {
+	let mut detectors: Vec<Box<AddressDetector>> = Vec::new();
+	if let Some(address) = config.public_address {
+		detectors.push(Box::new(ConfiguredAddress(address)));
+	}
+	if config.nat_enabled {
+		detectors.push(Box::new(UpnpDetector { timeout: config.external_ip_timeout }));
+	}
+	if config.external_ip_quorum > 0 {
+		detectors.push(Box::new(PeerQuorumDetector { quorum: config.external_ip_quorum }));
+	}
+	if !config.external_ip_probe_urls.is_empty() {
+		detectors.push(Box::new(HttpProbeDetector { urls: config.external_ip_probe_urls.clone(), timeout: config.external_ip_timeout }));
+	}
+	detectors
+}
+
+/// Node id allowlist enabled by the configuration.
+fn configured_node_allowlist(config: &NetworkConfiguration) -> Result<Option<Arc<NodeIdAllowlistFilter>>, Error> {
+	if config.node_allowlist.is_none() && config.node_allowlist_path.is_none() {
+		return Ok(None);
+	}
+	let mut ids = Vec::new();
+	for id in config.node_allowlist.iter().flat_map(|ids| ids.iter()) {
+		ids.push(parse_node_id(id)?);
+	}
+	let path = config.node_allowlist_path.as_ref().map(PathBuf::from);
+	Ok(Some(Arc::new(NodeIdAllowlistFilter::new(ids, path)?)))
+}
+
+/// Bind a listening socket. `SO_REUSEADDR` lets a restarted node bind its port while connections
+/// of the previous instance are still in `TIME_WAIT`.
+fn bind_listener(address: &SocketAddr) -> io::Result<TcpListener> {
+	let builder = match *address {
+		SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+		SocketAddr::V6(_) => TcpBuilder::new_v6()?,
+	};
+	// On Windows the option allows binding a port another socket is listening on.
+	if cfg!(unix) {
+		builder.reuse_address(true)?;
+	}
+	builder.bind(address)?;
+	let listener = builder.listen(1024)?;
+	TcpListener::from_std(listener)
+}
+
+/// Bind the main listen address. While the port is in use binding is retried up to `listen_bind_attempts`
+/// times, then the ports of `port_fallback_range` are tried in order.
+fn bind_listen_address(address: &SocketAddr, config: &NetworkConfiguration) -> io::Result<TcpListener> {
+	let attempts = max(config.listen_bind_attempts, 1);
+	let mut attempt = 1;
+	let error = loop {
+		match bind_listener(address) {
+			Ok(listener) => return Ok(listener),
+			Err(ref e) if e.kind() == io::ErrorKind::AddrInUse && attempt < attempts => {
+				debug!(target: "network", "{} is in use, retrying in {:?}", address, config.listen_bind_retry_delay);
+				attempt += 1;
+				thread::sleep(config.listen_bind_retry_delay);
+			},
+			Err(e) => break e,
+		}
+	};
+	if error.kind() != io::ErrorKind::AddrInUse || address.port() == 0 {
+		return Err(error);
+	}
+	for offset in 1..(config.port_fallback_range.unwrap_or(0) as u32 + 1) {
+		let port = match address.port().checked_add(offset as u16) {
+			Some(port) => port,
+			None => break,
+		};
+		let mut fallback = *address;
+		fallback.set_port(port);
+		match bind_listener(&fallback) {
+			Ok(listener) => {
+				warn!(target: "network", "{} is in use, listening at {} instead", address, fallback);
+				return Ok(listener);
+			},
+			Err(ref e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+			Err(e) => return Err(e),
+		}
+	}
+	Err(error)
+}
+
+/// Check that a client identifier is printable ASCII of at most `MAX_CLIENT_VERSION_LEN` bytes.
+pub fn validate_client_version(version: &str) -> Result<(), Error> {
+	if version.is_empty() || version.len() > MAX_CLIENT_VERSION_LEN || !version.bytes().all(|b| b >= 0x20 && b < 0x7f) {
+		bail!(ErrorKind::InvalidClientVersion);
+	}
+	Ok(())
+}
+
+/// Node id and enode URL derived from the configuration before a host is created.
+/// `None` if the node key is neither configured nor stored in `config_path`.
+pub fn configured_enode(config: &NetworkConfiguration) -> Option<(NodeId, String)> {
+	let secret = config.use_secret.clone().or_else(|| config.config_path.as_ref().and_then(|p| load_key(Path::new(p))));
+	let id = match secret.and_then(|s| KeyPair::from_secret(s).ok()) {
+		Some(keys) => keys.public().clone(),
+		None => return None,
+	};
+	let address = config.public_address.or(config.listen_address)
+		.unwrap_or_else(|| SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), DEFAULT_PORT)));
+	let endpoint = NodeEndpoint { address: address, udp_port: config.udp_port.unwrap_or(address.port()) };
+	let url = format!("{}", Node::new(id.clone(), endpoint));
+	Some((id, url))
+}
+
 fn save_key(path: &Path, key: &Secret) {
 	let mut path_buf = PathBuf::from(path);
 	if let Err(e) = fs::create_dir_all(path_buf.as_path()) {
@@ -1150,6 +3587,97 @@ fn host_client_url() {
 	let mut config = NetworkConfiguration::new_local();
 	let key = "6f7b0d801bc7b5ce7bbd930b84fd0369b3eb25d09be58d64ba811091046f3aa2".parse().unwrap();
 	config.use_secret = Some(key);
-	let host: Host = Host::new(config, Arc::new(NetworkStats::new()), None).unwrap();
+	let host: Host = Host::new(config, Arc::new(NetworkStats::new()), Arc::new(EventSubscribers::new()), None).unwrap();
 	assert!(host.local_url().starts_with("enode://101b3ef5a4ea7a1c7928e24c4c75fd053c235d7b80c22ae5c03d145d0ac7396e2a4ffff9adee3133a7b05044a5cee08115fd65145e5165d646bde371010d803c@"));
 }
+
+#[test]
+fn peer_slots_default_split() {
+	let slots = PeerSlots::new(25, 50, None);
+	assert_eq!(slots, PeerSlots { ingress: 25, egress: 25 });
+	let slots = PeerSlots::new(40, 50, None);
+	assert_eq!(slots, PeerSlots { ingress: 20, egress: 40 });
+}
+
+#[test]
+fn peer_slots_ratio() {
+	assert_eq!(PeerSlots::new(25, 50, Some((0, 1))), PeerSlots { ingress: 0, egress: 50 });
+	assert_eq!(PeerSlots::new(25, 50, Some((1, 0))), PeerSlots { ingress: 50, egress: 0 });
+	assert_eq!(PeerSlots::new(25, 50, Some((3, 1))), PeerSlots { ingress: 37, egress: 13 });
+	assert_eq!(PeerSlots::new(25, 50, Some((0, 0))), PeerSlots::new(25, 50, None));
+}
+
+#[test]
+fn peer_slots_admission_boundaries() {
+	let slots = PeerSlots::new(25, 50, Some((4, 1)));
+	assert_eq!(slots, PeerSlots { ingress: 40, egress: 10 });
+
+	// outgoing
+	assert!(slots.allows(true, 10, 40));
+	assert!(!slots.allows(true, 11, 0));
+	// incoming
+	assert!(slots.allows(false, 10, 40));
+	assert!(!slots.allows(false, 0, 41));
+
+	let outbound_only = PeerSlots::new(25, 50, Some((0, 1)));
+	assert!(!outbound_only.allows(false, 0, 1));
+	assert!(outbound_only.allows(true, 50, 0));
+}
+
+#[test]
+fn session_capacity_covers_peers_and_handshakes() {
+	let mut config = NetworkConfiguration::new();
+	config.max_peers = 50;
+	config.max_handshakes = 64;
+	assert_eq!(session_capacity(&config, 0), 114);
+	assert_eq!(session_capacity(&config, 3), 117);
+	config.reserved_protocols.insert(*b"par", 5);
+	assert_eq!(session_capacity(&config, 3), 122);
+
+	config.max_peers = 100_000;
+	assert_eq!(session_capacity(&config, 0), MAX_SESSIONS);
+}
+
+#[test]
+fn create_connection_at_capacity() {
+	use std::net::TcpListener as StdListener;
+
+	let mut config = NetworkConfiguration::new_local();
+	config.max_peers = 2;
+	config.max_handshakes = 1;
+	let stats = Arc::new(NetworkStats::new());
+	let host = Host::new(config, stats.clone(), Arc::new(EventSubscribers::new()), None).unwrap();
+	let io = IoContext::new(IoChannel::disconnected(), 0);
+	let listener = StdListener::bind("127.0.0.1:0").unwrap();
+	let address = listener.local_addr().unwrap();
+	let connect = || TcpStream::from_stream(::std::net::TcpStream::connect(address).unwrap()).unwrap();
+
+	// Incoming sessions wait for the remote node to start the handshake.
+	for _ in 0..3 {
+		assert!(host.create_connection(connect(), None, None, &io).is_ok());
+	}
+	assert_eq!(stats.at_capacity(), 0);
+	match host.create_connection(connect(), None, None, &io) {
+		Err(ref e) => match *e.kind() {
+			ErrorKind::AtCapacity => {},
+			_ => panic!("Unexpected error {:?}", e),
+		},
+		Ok(token) => panic!("Session {} created over capacity", token),
+	}
+	assert_eq!(stats.at_capacity(), 1);
+	assert_eq!(host.sessions.read().count(), 3);
+
+	// A removed session frees its slot.
+	host.sessions.write().remove(FIRST_SESSION);
+	assert!(host.create_connection(connect(), None, None, &io).is_ok());
+	assert_eq!(stats.at_capacity(), 1);
+
+	// Raising the peer limit grows the slab.
+	host.info.write().config.max_peers = 4;
+	for _ in 0..2 {
+		assert!(host.create_connection(connect(), None, None, &io).is_ok());
+	}
+	assert!(host.create_connection(connect(), None, None, &io).is_err());
+	assert_eq!(stats.at_capacity(), 2);
+	assert_eq!(host.sessions.read().count(), 5);
+}
@@ -16,8 +16,9 @@
 
 // Based on original work by David Levy https://raw.githubusercontent.com/dlevy47/rust-interfaces
 
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
-use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, TcpStream, ToSocketAddrs};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
 use igd::{PortMappingProtocol, search_gateway_from_timeout};
 use std::time::Duration;
 use node_table::{NodeEndpoint};
@@ -210,6 +211,91 @@ impl SocketAddrExt for IpAddr {
 	}
 }
 
+/// Address class of an IP address, as far as peer discovery is concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpClass {
+	/// Globally routable unicast address.
+	Global,
+	/// Private network: 10/8, 172.16/12, 192.168/16, carrier-grade NAT 100.64/10, fc00::/7 and fec0::/10.
+	Private,
+	/// Loopback: 127/8 and ::1.
+	Loopback,
+	/// Link-local: 169.254/16 and fe80::/10.
+	LinkLocal,
+	/// Multicast and broadcast: 224/4, 255.255.255.255 and ff00::/8.
+	Multicast,
+	/// Unspecified, "this network", documentation, benchmarking, IANA special purpose, discard-only
+	/// and reserved ranges.
+	Reserved,
+}
+
+impl IpClass {
+	/// Check if a node advertising an address of this class may be used. Non-global unicast addresses
+	/// are only accepted on local networks; multicast and reserved addresses are never accepted.
+	pub fn is_accepted(&self, allow_non_global: bool) -> bool {
+		match *self {
+			IpClass::Global => true,
+			IpClass::Private | IpClass::Loopback | IpClass::LinkLocal => allow_non_global,
+			IpClass::Multicast | IpClass::Reserved => false,
+		}
+	}
+}
+
+/// Classify an IP address. IPv4-mapped IPv6 addresses are classified as the embedded IPv4 address.
+pub fn ip_class(ip: &IpAddr) -> IpClass {
+	match *ip {
+		IpAddr::V4(ref ip) => ipv4_class(ip),
+		IpAddr::V6(ref ip) => ipv6_class(ip),
+	}
+}
+
+fn ipv4_class(ip: &Ipv4Addr) -> IpClass {
+	if ip.octets()[0] == 0 {
+		IpClass::Reserved
+	} else if ip.is_loopback() {
+		IpClass::Loopback
+	} else if ip.is_private() || ip.is_shared_space() {
+		IpClass::Private
+	} else if ip.is_link_local() {
+		IpClass::LinkLocal
+	} else if ip.is_multicast() || ip.is_broadcast() {
+		IpClass::Multicast
+	} else if ip.is_documentation() || ip.is_benchmarking() || ip.is_special_purpose() || ip.is_future_use() {
+		IpClass::Reserved
+	} else {
+		IpClass::Global
+	}
+}
+
+fn ipv6_class(ip: &Ipv6Addr) -> IpClass {
+	let segments = ip.segments();
+	if segments[0..5].iter().all(|s| *s == 0) && segments[5] == 0xffff {
+		let octets = ip.octets();
+		return ipv4_class(&Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]));
+	}
+	if ip.is_loopback() {
+		IpClass::Loopback
+	} else if ip.is_multicast() {
+		IpClass::Multicast
+	} else if ip.is_unique_local_s() || (segments[0] & 0xffc0) == 0xfec0 {
+		IpClass::Private
+	} else if ip.is_unicast_link_local_s() {
+		IpClass::LinkLocal
+	} else if segments[0] == 0 || ip.is_documentation_s() || (segments[0] == 0x100 && segments[1..4].iter().all(|s| *s == 0)) {
+		// ::/8 covers the unspecified and IPv4-compatible addresses, 100::/64 is discard-only.
+		IpClass::Reserved
+	} else {
+		IpClass::Global
+	}
+}
+
+/// Check an address against allow and deny lists. The deny list takes precedence,
+/// an empty allow list allows all addresses.
+pub fn is_allowed_by_lists(ip: &IpAddr, allowlist: &[IpNetwork], denylist: &[IpNetwork]) -> bool {
+	!denylist.iter().any(|net| ip.is_within(net)) &&
+		(allowlist.is_empty() || allowlist.iter().any(|net| ip.is_within(net)))
+}
+
 #[cfg(not(windows))]
 mod getinterfaces {
 	use std::{mem, io, ptr};
@@ -317,9 +403,23 @@ pub fn select_public_address(port: u16) -> SocketAddr {
 	SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port))
 }
 
-pub fn map_external_address(local: &NodeEndpoint) -> Option<NodeEndpoint> {
+/// Select the best address to advertise out of the addresses we are listening on. Specific usable
+/// public addresses are preferred, IPv4 first. Otherwise falls back to interface enumeration using
+/// the port of the first address.
+pub fn select_public_listen_address(listen: &[SocketAddr]) -> SocketAddr {
+	if let Some(addr) = listen.iter().find(|a| a.is_ipv4() && a.ip().is_usable_public()) {
+		return *addr;
+	}
+	if let Some(addr) = listen.iter().find(|a| a.is_ipv6() && a.ip().is_usable_public()) {
+		return *addr;
+	}
+	select_public_address(listen.first().map_or(0, |a| a.port()))
+}
+
+/// Map the local endpoint on the UPnP gateway, waiting at most `timeout` for the gateway to respond.
+pub fn map_external_address(local: &NodeEndpoint, timeout: Duration) -> Option<NodeEndpoint> {
 	if let SocketAddr::V4(ref local_addr) = local.address {
-		match search_gateway_from_timeout(local_addr.ip().clone(), Duration::new(5, 0)) {
+		match search_gateway_from_timeout(local_addr.ip().clone(), timeout) {
 			Err(ref err) => debug!("Gateway search error: {}", err),
 			Ok(gateway) => {
 				match gateway.get_external_ip() {
@@ -350,17 +450,211 @@ pub fn map_external_address(local: &NodeEndpoint) -> Option<NodeEndpoint> {
 	None
 }
 
+/// Mechanism the advertised public address was obtained with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSource {
+	/// `public_address` from the configuration.
+	Config,
+	/// UPnP port mapping on the gateway.
+	Upnp,
+	/// Address reported by a quorum of peers.
+	PeerQuorum,
+	/// HTTP "what is my IP" service.
+	HttpProbe,
+	/// No other source succeeded, the listen address is advertised.
+	ListenAddress,
+}
+
+/// Inputs for external address detection.
+pub struct DetectionContext<'a> {
+	/// Local listen endpoint.
+	pub local: &'a NodeEndpoint,
+	/// Own IP address as reported by each peer that has seen it.
+	pub observed: &'a [IpAddr],
+}
+
+impl<'a> DetectionContext<'a> {
+	/// Local ports with the given IP address.
+	fn endpoint(&self, ip: IpAddr) -> NodeEndpoint {
+		NodeEndpoint { address: SocketAddr::new(ip, self.local.address.port()), udp_port: self.local.udp_port }
+	}
+}
+
+/// A step of the external address detection chain.
+pub trait AddressDetector: Send + Sync {
+	/// Source reported for addresses found by this detector.
+	fn source(&self) -> AddressSource;
+	/// Try to find the public endpoint. Should give up after the detector's timeout.
+	fn detect(&self, context: &DetectionContext) -> Option<NodeEndpoint>;
+}
+
+/// Address given in the configuration.
+pub struct ConfiguredAddress(pub SocketAddr);
+
+impl AddressDetector for ConfiguredAddress {
+	fn source(&self) -> AddressSource { AddressSource::Config }
+
+	fn detect(&self, context: &DetectionContext) -> Option<NodeEndpoint> {
+		Some(NodeEndpoint { address: self.0, udp_port: context.local.udp_port })
+	}
+}
+
+/// Port mapping on the UPnP gateway.
+pub struct UpnpDetector {
+	/// Gateway search timeout.
+	pub timeout: Duration,
+}
+
+impl AddressDetector for UpnpDetector {
+	fn source(&self) -> AddressSource { AddressSource::Upnp }
+
+	fn detect(&self, context: &DetectionContext) -> Option<NodeEndpoint> {
+		map_external_address(context.local, self.timeout)
+	}
+}
+
+/// Most common address reported by peers, if at least `quorum` peers agree on it.
+pub struct PeerQuorumDetector {
+	/// Number of peers that have to report the same address.
+	pub quorum: usize,
+}
+
+impl AddressDetector for PeerQuorumDetector {
+	fn source(&self) -> AddressSource { AddressSource::PeerQuorum }
+
+	fn detect(&self, context: &DetectionContext) -> Option<NodeEndpoint> {
+		let mut votes: HashMap<IpAddr, usize> = HashMap::new();
+		for ip in context.observed.iter().filter(|ip| !ip.is_unspecified()) {
+			*votes.entry(*ip).or_insert(0) += 1;
+		}
+		votes.into_iter()
+			.filter(|&(_, count)| count >= self.quorum)
+			.max_by_key(|&(_, count)| count)
+			.map(|(ip, _)| context.endpoint(ip))
+	}
+}
+
+/// Maximum size of an HTTP probe response.
+const MAX_PROBE_RESPONSE: u64 = 4096;
+
+/// Services that respond with the caller's IP address in a plain text body, tried in order.
+/// Only `http://` URLs are supported, other schemes are skipped.
+pub struct HttpProbeDetector {
+	/// Service URLs.
+	pub urls: Vec<String>,
+	/// Connect, write and read timeout of each request.
+	pub timeout: Duration,
+}
+
+impl HttpProbeDetector {
+	fn probe(&self, url: &str) -> io::Result<IpAddr> {
+		let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", url, msg));
+		if !url.starts_with("http://") {
+			return Err(invalid("unsupported scheme"));
+		}
+		let rest = &url["http://".len()..];
+		let (host, path) = match rest.find('/') {
+			Some(pos) => (&rest[..pos], &rest[pos..]),
+			None => (rest, "/"),
+		};
+		let address = if host.contains(':') {
+			host.to_socket_addrs()?.next()
+		} else {
+			(host, 80).to_socket_addrs()?.next()
+		};
+		let address = address.ok_or_else(|| invalid("host not found"))?;
+
+		let mut stream = TcpStream::connect_timeout(&address, self.timeout)?;
+		stream.set_read_timeout(Some(self.timeout))?;
+		stream.set_write_timeout(Some(self.timeout))?;
+		write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n", path, host)?;
+		let mut response = String::new();
+		stream.take(MAX_PROBE_RESPONSE).read_to_string(&mut response)?;
+
+		if !response.lines().next().map_or(false, |status| status.split_whitespace().nth(1) == Some("200")) {
+			return Err(invalid("unexpected response status"));
+		}
+		let body = response.find("\r\n\r\n").map(|pos| &response[pos + 4..]).ok_or_else(|| invalid("no response body"))?;
+		body.trim().parse().map_err(|_| invalid("response is not an IP address"))
+	}
+}
+
+impl AddressDetector for HttpProbeDetector {
+	fn source(&self) -> AddressSource { AddressSource::HttpProbe }
+
+	fn detect(&self, context: &DetectionContext) -> Option<NodeEndpoint> {
+		for url in &self.urls {
+			match self.probe(url) {
+				Ok(ip) => return Some(context.endpoint(ip)),
+				Err(e) => debug!(target: "network", "External address probe failed: {}", e),
+			}
+		}
+		None
+	}
+}
+
+/// Runs the external address detection chain and keeps the current result.
+pub struct ExternalAddress {
+	detectors: Vec<Box<AddressDetector>>,
+	current: Option<(NodeEndpoint, AddressSource)>,
+}
+
+impl ExternalAddress {
+	/// Create with detectors in order of precedence.
+	pub fn new(detectors: Vec<Box<AddressDetector>>) -> ExternalAddress {
+		ExternalAddress {
+			detectors: detectors,
+			current: None,
+		}
+	}
+
+	/// Current public endpoint and its source. `None` before the first check.
+	pub fn current(&self) -> Option<&(NodeEndpoint, AddressSource)> {
+		self.current.as_ref()
+	}
+
+	/// Try the detectors in order until one succeeds. If all fail, a previously detected address is kept,
+	/// or `fallback` is used on the first check. Returns the new endpoint and source if either has changed.
+	pub fn check(&mut self, context: &DetectionContext, fallback: NodeEndpoint) -> Option<(NodeEndpoint, AddressSource)> {
+		let detected = self.detectors.iter().filter_map(|d| d.detect(context).map(|e| (e, d.source()))).next();
+		let detected = match (detected, self.current.is_some()) {
+			(Some(detected), _) => detected,
+			(None, true) => return None,
+			(None, false) => (fallback, AddressSource::ListenAddress),
+		};
+		let changed = self.current.as_ref().map_or(true, |&(ref endpoint, source)| {
+			endpoint.address != detected.0.address || endpoint.udp_port != detected.0.udp_port || source != detected.1
+		});
+		if !changed {
+			return None;
+		}
+		self.current = Some(detected.clone());
+		Some(detected)
+	}
+}
+
 #[test]
 fn can_select_public_address() {
 	let pub_address = select_public_address(40477);
 	assert!(pub_address.port() == 40477);
 }
 
+#[test]
+fn select_public_listen_address_prefers_public() {
+	use std::str::FromStr;
+	let v4 = SocketAddr::from_str("123.99.55.44:30303").unwrap();
+	let v6 = SocketAddr::from_str("[2a00:1450:4001:81b::200e]:30303").unwrap();
+	let private = SocketAddr::from_str("10.0.0.1:30303").unwrap();
+	assert_eq!(select_public_listen_address(&[private, v6, v4]), v4);
+	assert_eq!(select_public_listen_address(&[private, v6]), v6);
+	assert_eq!(select_public_listen_address(&[private]).port(), 30303);
+}
+
 #[ignore]
 #[test]
 fn can_map_external_address_or_fail() {
 	let pub_address = select_public_address(40478);
-	let _ = map_external_address(&NodeEndpoint { address: pub_address, udp_port: 40478 });
+	let _ = map_external_address(&NodeEndpoint { address: pub_address, udp_port: 40478 }, Duration::new(5, 0));
 }
 
 #[test]
@@ -535,3 +829,282 @@ fn ipv6_properties() {
 }
 
 
+
+#[test]
+fn ipv4_classes() {
+	fn class(a: u8, b: u8, c: u8, d: u8) -> IpClass {
+		ip_class(&IpAddr::V4(Ipv4Addr::new(a, b, c, d)))
+	}
+
+	assert_eq!(class(0, 0, 0, 0), IpClass::Reserved);
+	assert_eq!(class(0, 255, 255, 255), IpClass::Reserved);
+	assert_eq!(class(1, 0, 0, 0), IpClass::Global);
+	assert_eq!(class(9, 255, 255, 255), IpClass::Global);
+	assert_eq!(class(10, 0, 0, 0), IpClass::Private);
+	assert_eq!(class(10, 255, 255, 255), IpClass::Private);
+	assert_eq!(class(11, 0, 0, 0), IpClass::Global);
+	assert_eq!(class(100, 63, 255, 255), IpClass::Global);
+	assert_eq!(class(100, 64, 0, 0), IpClass::Private);
+	assert_eq!(class(100, 127, 255, 255), IpClass::Private);
+	assert_eq!(class(100, 128, 0, 0), IpClass::Global);
+	assert_eq!(class(126, 255, 255, 255), IpClass::Global);
+	assert_eq!(class(127, 0, 0, 0), IpClass::Loopback);
+	assert_eq!(class(127, 255, 255, 255), IpClass::Loopback);
+	assert_eq!(class(128, 0, 0, 0), IpClass::Global);
+	assert_eq!(class(169, 253, 255, 255), IpClass::Global);
+	assert_eq!(class(169, 254, 0, 0), IpClass::LinkLocal);
+	assert_eq!(class(169, 254, 255, 255), IpClass::LinkLocal);
+	assert_eq!(class(169, 255, 0, 0), IpClass::Global);
+	assert_eq!(class(172, 15, 255, 255), IpClass::Global);
+	assert_eq!(class(172, 16, 0, 0), IpClass::Private);
+	assert_eq!(class(172, 31, 255, 255), IpClass::Private);
+	assert_eq!(class(172, 32, 0, 0), IpClass::Global);
+	assert_eq!(class(192, 0, 0, 0), IpClass::Reserved);
+	assert_eq!(class(192, 0, 0, 255), IpClass::Reserved);
+	assert_eq!(class(192, 0, 1, 0), IpClass::Global);
+	assert_eq!(class(192, 0, 2, 0), IpClass::Reserved);
+	assert_eq!(class(192, 0, 2, 255), IpClass::Reserved);
+	assert_eq!(class(192, 0, 3, 0), IpClass::Global);
+	assert_eq!(class(192, 167, 255, 255), IpClass::Global);
+	assert_eq!(class(192, 168, 0, 0), IpClass::Private);
+	assert_eq!(class(192, 168, 255, 255), IpClass::Private);
+	assert_eq!(class(192, 169, 0, 0), IpClass::Global);
+	assert_eq!(class(198, 17, 255, 255), IpClass::Global);
+	assert_eq!(class(198, 18, 0, 0), IpClass::Reserved);
+	assert_eq!(class(198, 19, 255, 255), IpClass::Reserved);
+	assert_eq!(class(198, 20, 0, 0), IpClass::Global);
+	assert_eq!(class(198, 51, 100, 0), IpClass::Reserved);
+	assert_eq!(class(198, 51, 100, 255), IpClass::Reserved);
+	assert_eq!(class(203, 0, 113, 0), IpClass::Reserved);
+	assert_eq!(class(203, 0, 113, 255), IpClass::Reserved);
+	assert_eq!(class(203, 0, 114, 0), IpClass::Global);
+	assert_eq!(class(223, 255, 255, 255), IpClass::Global);
+	assert_eq!(class(224, 0, 0, 0), IpClass::Multicast);
+	assert_eq!(class(239, 255, 255, 255), IpClass::Multicast);
+	assert_eq!(class(240, 0, 0, 0), IpClass::Reserved);
+	assert_eq!(class(255, 255, 255, 254), IpClass::Reserved);
+	assert_eq!(class(255, 255, 255, 255), IpClass::Multicast);
+}
+
+#[test]
+fn ipv6_classes() {
+	use std::str::FromStr;
+	fn class(s: &str) -> IpClass {
+		ip_class(&IpAddr::V6(Ipv6Addr::from_str(s).unwrap()))
+	}
+
+	assert_eq!(class("::"), IpClass::Reserved);
+	assert_eq!(class("::1"), IpClass::Loopback);
+	assert_eq!(class("::2"), IpClass::Reserved);
+	assert_eq!(class("::10.0.0.1"), IpClass::Reserved);
+	assert_eq!(class("::ffff:10.0.0.1"), IpClass::Private);
+	assert_eq!(class("::ffff:127.0.0.1"), IpClass::Loopback);
+	assert_eq!(class("::ffff:8.8.8.8"), IpClass::Global);
+	assert_eq!(class("64:ff9b::808:808"), IpClass::Global);
+	assert_eq!(class("100::"), IpClass::Reserved);
+	assert_eq!(class("100::ffff:ffff:ffff:ffff"), IpClass::Reserved);
+	assert_eq!(class("100:0:0:1::"), IpClass::Global);
+	assert_eq!(class("2001:db7:ffff::"), IpClass::Global);
+	assert_eq!(class("2001:db8::"), IpClass::Reserved);
+	assert_eq!(class("2001:db8:ffff:ffff:ffff:ffff:ffff:ffff"), IpClass::Reserved);
+	assert_eq!(class("2001:db9::"), IpClass::Global);
+	assert_eq!(class("2a00:1450:4001:81b::200e"), IpClass::Global);
+	assert_eq!(class("fbff:ffff::"), IpClass::Global);
+	assert_eq!(class("fc00::"), IpClass::Private);
+	assert_eq!(class("fdff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"), IpClass::Private);
+	assert_eq!(class("fe7f:ffff::"), IpClass::Global);
+	assert_eq!(class("fe80::"), IpClass::LinkLocal);
+	assert_eq!(class("febf:ffff::"), IpClass::LinkLocal);
+	assert_eq!(class("fec0::"), IpClass::Private);
+	assert_eq!(class("feff:ffff::"), IpClass::Private);
+	assert_eq!(class("ff00::"), IpClass::Multicast);
+	assert_eq!(class("ff0e::1"), IpClass::Multicast);
+	assert_eq!(class("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"), IpClass::Multicast);
+}
+
+#[test]
+fn ip_class_acceptance() {
+	let classes = [IpClass::Global, IpClass::Private, IpClass::Loopback, IpClass::LinkLocal, IpClass::Multicast, IpClass::Reserved];
+	let public: Vec<bool> = classes.iter().map(|c| c.is_accepted(false)).collect();
+	let local: Vec<bool> = classes.iter().map(|c| c.is_accepted(true)).collect();
+	assert_eq!(public, vec![true, false, false, false, false, false]);
+	assert_eq!(local, vec![true, true, true, true, false, false]);
+}
+
+#[test]
+fn ip_allow_deny_lists() {
+	use std::str::FromStr;
+	let ip = |s: &str| IpAddr::from_str(s).unwrap();
+	let nets = |n: &[&str]| -> Vec<IpNetwork> { n.iter().map(|s| IpNetwork::from_str(s).unwrap()).collect() };
+
+	// Empty lists allow everything.
+	assert!(is_allowed_by_lists(&ip("8.8.8.8"), &[], &[]));
+	assert!(is_allowed_by_lists(&ip("2a00:1450::1"), &[], &[]));
+
+	// Overlapping ranges, deny wins.
+	let allow = nets(&["10.0.0.0/8"]);
+	let deny = nets(&["10.1.0.0/16", "10.1.2.0/24"]);
+	assert!(is_allowed_by_lists(&ip("10.0.0.1"), &allow, &deny));
+	assert!(is_allowed_by_lists(&ip("10.2.255.255"), &allow, &deny));
+	assert!(!is_allowed_by_lists(&ip("10.1.0.0"), &allow, &deny));
+	assert!(!is_allowed_by_lists(&ip("10.1.2.3"), &allow, &deny));
+	assert!(!is_allowed_by_lists(&ip("11.0.0.1"), &allow, &deny));
+	assert!(!is_allowed_by_lists(&ip("10.0.0.1"), &allow, &nets(&["0.0.0.0/0"])));
+
+	// IPv6 networks do not match IPv4 addresses and the other way around.
+	let allow = nets(&["fd00::/8", "10.0.0.0/8"]);
+	let deny = nets(&["fd00:dead::/32"]);
+	assert!(is_allowed_by_lists(&ip("fd00::1"), &allow, &deny));
+	assert!(!is_allowed_by_lists(&ip("fd00:dead::1"), &allow, &deny));
+	assert!(!is_allowed_by_lists(&ip("fe80::1"), &allow, &deny));
+	assert!(is_allowed_by_lists(&ip("10.0.0.1"), &allow, &deny));
+	assert!(is_allowed_by_lists(&ip("2a00:1450::1"), &[], &nets(&["10.0.0.0/8"])));
+}
+
+#[cfg(test)]
+struct MockDetector {
+	source: AddressSource,
+	result: ::parking_lot::Mutex<Option<IpAddr>>,
+	calls: ::std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(test)]
+impl MockDetector {
+	fn new(source: AddressSource, result: Option<&str>) -> ::std::sync::Arc<MockDetector> {
+		::std::sync::Arc::new(MockDetector {
+			source: source,
+			result: ::parking_lot::Mutex::new(result.map(|ip| ip.parse().unwrap())),
+			calls: ::std::sync::atomic::AtomicUsize::new(0),
+		})
+	}
+
+	fn set(&self, result: Option<&str>) {
+		*self.result.lock() = result.map(|ip| ip.parse().unwrap());
+	}
+
+	fn calls(&self) -> usize {
+		self.calls.load(::std::sync::atomic::Ordering::SeqCst)
+	}
+}
+
+#[cfg(test)]
+impl AddressDetector for ::std::sync::Arc<MockDetector> {
+	fn source(&self) -> AddressSource { self.source }
+
+	fn detect(&self, context: &DetectionContext) -> Option<NodeEndpoint> {
+		self.calls.fetch_add(1, ::std::sync::atomic::Ordering::SeqCst);
+		self.result.lock().map(|ip| context.endpoint(ip))
+	}
+}
+
+#[test]
+fn external_address_precedence() {
+	let local = NodeEndpoint { address: "10.0.0.1:30303".parse().unwrap(), udp_port: 30301 };
+	let context = DetectionContext { local: &local, observed: &[] };
+	let fallback = local.clone();
+	let upnp = MockDetector::new(AddressSource::Upnp, None);
+	let quorum = MockDetector::new(AddressSource::PeerQuorum, Some("1.1.1.1"));
+	let probe = MockDetector::new(AddressSource::HttpProbe, Some("2.2.2.2"));
+	let chain = || -> Vec<Box<AddressDetector>> { vec![Box::new(upnp.clone()), Box::new(quorum.clone()), Box::new(probe.clone())] };
+	let mut detectors: Vec<Box<AddressDetector>> = vec![Box::new(ConfiguredAddress("3.3.3.3:30000".parse().unwrap()))];
+	detectors.extend(chain());
+	let mut external = ExternalAddress::new(detectors);
+	// Configured address wins, nothing else is tried.
+	let (endpoint, source) = external.check(&context, fallback.clone()).unwrap();
+	assert_eq!((endpoint.address, endpoint.udp_port, source), ("3.3.3.3:30000".parse().unwrap(), 30301, AddressSource::Config));
+	assert_eq!(upnp.calls(), 0);
+
+	// Failing detectors fall through to the next one.
+	let mut external = ExternalAddress::new(chain());
+	let (endpoint, source) = external.check(&context, fallback.clone()).unwrap();
+	assert_eq!((endpoint.address, source), ("1.1.1.1:30303".parse().unwrap(), AddressSource::PeerQuorum));
+	assert_eq!((upnp.calls(), quorum.calls(), probe.calls()), (1, 1, 0));
+
+	quorum.set(None);
+	let mut external = ExternalAddress::new(chain());
+	assert_eq!(external.check(&context, fallback.clone()).unwrap().1, AddressSource::HttpProbe);
+
+	// Nothing works, the fallback is advertised.
+	probe.set(None);
+	let mut external = ExternalAddress::new(chain());
+	let (endpoint, source) = external.check(&context, fallback.clone()).unwrap();
+	assert_eq!((endpoint.address, source), (local.address, AddressSource::ListenAddress));
+	assert_eq!(external.current().map(|c| c.1), Some(AddressSource::ListenAddress));
+}
+
+#[test]
+fn external_address_recheck() {
+	let local = NodeEndpoint { address: "10.0.0.1:30303".parse().unwrap(), udp_port: 30303 };
+	let context = DetectionContext { local: &local, observed: &[] };
+	let upnp = MockDetector::new(AddressSource::Upnp, None);
+	let probe = MockDetector::new(AddressSource::HttpProbe, None);
+	let detectors: Vec<Box<AddressDetector>> = vec![Box::new(upnp.clone()), Box::new(probe.clone())];
+	let mut external = ExternalAddress::new(detectors);
+	assert_eq!(external.check(&context, local.clone()).unwrap().1, AddressSource::ListenAddress);
+	// Unchanged results are not reported.
+	assert!(external.check(&context, local.clone()).is_none());
+
+	probe.set(Some("2.2.2.2"));
+	assert_eq!(external.check(&context, local.clone()).unwrap().1, AddressSource::HttpProbe);
+	assert!(external.check(&context, local.clone()).is_none());
+	// A higher precedence source takes over once it works.
+	upnp.set(Some("1.1.1.1"));
+	let (endpoint, source) = external.check(&context, local.clone()).unwrap();
+	assert_eq!((endpoint.address, source), ("1.1.1.1:30303".parse().unwrap(), AddressSource::Upnp));
+	upnp.set(Some("1.1.1.2"));
+	assert_eq!(external.check(&context, local.clone()).unwrap().0.address, "1.1.1.2:30303".parse().unwrap());
+	// Failures keep the last detected address.
+	upnp.set(None);
+	probe.set(None);
+	assert!(external.check(&context, local.clone()).is_none());
+	assert_eq!(external.current().unwrap().0.address, "1.1.1.2:30303".parse().unwrap());
+	assert_eq!(upnp.calls(), 7);
+}
+
+#[test]
+fn peer_quorum_address() {
+	let local = NodeEndpoint { address: "10.0.0.1:30303".parse().unwrap(), udp_port: 30303 };
+	let ip = |s: &str| -> IpAddr { s.parse().unwrap() };
+	let detector = PeerQuorumDetector { quorum: 3 };
+	let observed = vec![ip("1.1.1.1"), ip("2.2.2.2"), ip("1.1.1.1"), ip("2.2.2.2")];
+	assert!(detector.detect(&DetectionContext { local: &local, observed: &observed }).is_none());
+	let observed = vec![ip("1.1.1.1"), ip("2.2.2.2"), ip("1.1.1.1"), ip("2.2.2.2"), ip("2.2.2.2"), ip("0.0.0.0")];
+	let endpoint = detector.detect(&DetectionContext { local: &local, observed: &observed }).unwrap();
+	assert_eq!(endpoint.address, "2.2.2.2:30303".parse().unwrap());
+}
+
+#[test]
+fn http_probe_address() {
+	use std::net::TcpListener;
+	use std::thread;
+
+	let serve = |response: &'static str| {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!("http://{}/ip", listener.local_addr().unwrap());
+		thread::spawn(move || {
+			let (mut stream, _) = listener.accept().unwrap();
+			let mut request = [0u8; 1024];
+			let _ = stream.read(&mut request);
+			stream.write_all(response.as_bytes()).unwrap();
+		});
+		url
+	};
+	let local = NodeEndpoint { address: "10.0.0.1:30303".parse().unwrap(), udp_port: 30303 };
+	let context = DetectionContext { local: &local, observed: &[] };
+
+	let ok = serve("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\n5.6.7.8\n");
+	let error = serve("HTTP/1.1 500 Internal Server Error\r\n\r\n9.9.9.9");
+	// Accepts the connection but never responds.
+	let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+	let silent_url = format!("http://{}/", silent.local_addr().unwrap());
+
+	let detector = HttpProbeDetector {
+		urls: vec!["https://example.com/".into(), silent_url, error, ok],
+		timeout: Duration::from_millis(200),
+	};
+	let endpoint = detector.detect(&context).unwrap();
+	assert_eq!(endpoint.address, "5.6.7.8:30303".parse().unwrap());
+
+	let detector = HttpProbeDetector { urls: vec![serve("HTTP/1.1 200 OK\r\n\r\nnot an address")], timeout: Duration::from_millis(200) };
+	assert!(detector.detect(&context).is_none());
+}
@@ -0,0 +1,279 @@
+// Copyright 2015-2017 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Local network peer discovery. Nodes periodically send a signed announcement of their TCP port
+//! to a broadcast or multicast address and dial the other nodes announcing themselves there.
+
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use ethcore_bytes::Bytes;
+use mio::*;
+use mio::net::UdpSocket;
+use connection::map_non_block;
+use net2::UdpBuilder;
+use hash::keccak;
+use time;
+use ethereum_types::H520;
+use rlp::{RlpStream, UntrustedRlp};
+use ethkey::{KeyPair, Secret, sign, recover};
+use io::StreamToken;
+use network::{Error, ErrorKind, LanDiscoveryConfig};
+use node_table::{NodeId, NodeEndpoint};
+use discovery::NodeEntry;
+
+const ANNOUNCEMENT_VERSION: u32 = 1;
+const ANNOUNCEMENT_EXPIRY_SECS: u64 = 60;
+const MAX_ANNOUNCEMENT_SIZE: usize = 512;
+
+pub struct LanDiscovery {
+	id: NodeId,
+	secret: Secret,
+	socket: UdpSocket,
+	token: StreamToken,
+	address: SocketAddr,
+	network_id: u64,
+	tcp_port: u16,
+}
+
+/// Bind a UDP socket to the port of `address` that other processes on the host can share,
+/// and enable sending to and receiving from `address`.
+fn bind_socket(address: &SocketAddr) -> io::Result<UdpSocket> {
+	let socket = match address.ip() {
+		IpAddr::V4(ip) => {
+			let builder = UdpBuilder::new_v4()?;
+			builder.reuse_address(true)?;
+			let socket = builder.bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), address.port()))?;
+			if ip.is_multicast() {
+				socket.join_multicast_v4(&ip, &Ipv4Addr::new(0, 0, 0, 0))?;
+				socket.set_multicast_loop_v4(true)?;
+			} else {
+				socket.set_broadcast(true)?;
+			}
+			socket
+		},
+		IpAddr::V6(ip) => {
+			if !ip.is_multicast() {
+				return Err(io::Error::new(io::ErrorKind::InvalidInput, "IPv6 LAN discovery requires a multicast address"));
+			}
+			let builder = UdpBuilder::new_v6()?;
+			builder.reuse_address(true)?;
+			let socket = builder.bind(SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)), address.port()))?;
+			socket.join_multicast_v6(&ip, 0)?;
+			socket.set_multicast_loop_v6(true)?;
+			socket
+		},
+	};
+	UdpSocket::from_socket(socket)
+}
+
+impl LanDiscovery {
+	/// Bind the announcement socket. `tcp_port` is the port announced for incoming connections.
+	pub fn new(key: &KeyPair, config: &LanDiscoveryConfig, tcp_port: u16, token: StreamToken) -> io::Result<LanDiscovery> {
+		let socket = bind_socket(&config.address)?;
+		Ok(LanDiscovery {
+			id: key.public().clone(),
+			secret: key.secret().clone(),
+			socket: socket,
+			token: token,
+			address: config.address,
+			network_id: config.network_id,
+			tcp_port: tcp_port,
+		})
+	}
+
+	/// Build a signed announcement expiring `ANNOUNCEMENT_EXPIRY_SECS` after `now`.
+	fn announcement(&self, now: u64) -> Result<Bytes, Error> {
+		let mut rlp = RlpStream::new_list(4);
+		rlp.append(&ANNOUNCEMENT_VERSION);
+		rlp.append(&self.network_id);
+		rlp.append(&self.tcp_port);
+		rlp.append(&(now + ANNOUNCEMENT_EXPIRY_SECS));
+		let bytes = rlp.drain();
+		let signature = sign(&self.secret, &keccak(bytes.as_ref()))?;
+		let mut packet = Bytes::with_capacity(32 + 65 + bytes.len());
+		packet.extend_from_slice(&[0u8; 32]);
+		packet.extend(signature.iter());
+		packet.extend(bytes.iter());
+		let signed_hash = keccak(&packet[32..]);
+		packet[0..32].clone_from_slice(&signed_hash);
+		Ok(packet)
+	}
+
+	/// Send an announcement. Announcements that can't be sent right away are dropped,
+	/// the next one follows after the announcement interval.
+	pub fn announce(&self) {
+		let packet = match self.announcement(time::get_time().sec as u64) {
+			Ok(packet) => packet,
+			Err(e) => {
+				warn!(target: "network", "Error signing LAN announcement: {:?}", e);
+				return;
+			}
+		};
+		match map_non_block(self.socket.send_to(&packet, &self.address)) {
+			Ok(Some(_)) => trace!(target: "network", "Sent LAN announcement to {}", self.address),
+			Ok(None) => debug!(target: "network", "LAN announcement to {} dropped, socket is busy", self.address),
+			Err(e) => debug!(target: "network", "Error sending LAN announcement to {}: {:?}", self.address, e),
+		}
+	}
+
+	/// Read all pending announcements. Returns the nodes of the valid ones.
+	pub fn readable(&self) -> Vec<NodeEntry> {
+		let mut buf: [u8; MAX_ANNOUNCEMENT_SIZE] = unsafe { mem::uninitialized() };
+		let mut nodes = Vec::new();
+		loop {
+			match map_non_block(self.socket.recv_from(&mut buf)) {
+				Ok(Some((len, from))) => match self.on_announcement(&buf[0..len], from, time::get_time().sec as u64) {
+					Ok(Some(node)) => nodes.push(node),
+					Ok(None) => {},
+					Err(e) => debug!(target: "network", "Error processing LAN announcement from {}: {:?}", from, e),
+				},
+				Ok(None) => break,
+				Err(e) => {
+					debug!(target: "network", "Error reading LAN discovery socket: {:?}", e);
+					break;
+				}
+			}
+		}
+		nodes
+	}
+
+	/// Validate an announcement received from `from`. Returns `None` for our own announcements
+	/// and those of other networks.
+	fn on_announcement(&self, packet: &[u8], from: SocketAddr, now: u64) -> Result<Option<NodeEntry>, Error> {
+		if packet.len() < 32 + 65 + 1 {
+			return Err(ErrorKind::BadProtocol.into());
+		}
+		let hash_signed = keccak(&packet[32..]);
+		if hash_signed[..] != packet[0..32] {
+			return Err(ErrorKind::BadProtocol.into());
+		}
+		let signed = &packet[(32 + 65)..];
+		let signature = H520::from_slice(&packet[32..(32 + 65)]);
+		let node_id = recover(&signature.into(), &keccak(signed))?;
+		if node_id == self.id {
+			return Ok(None);
+		}
+
+		let rlp = UntrustedRlp::new(signed);
+		// Later versions may append fields.
+		let _version: u32 = rlp.val_at(0)?;
+		let network_id: u64 = rlp.val_at(1)?;
+		let tcp_port: u16 = rlp.val_at(2)?;
+		let expiration: u64 = rlp.val_at(3)?;
+		if expiration < now {
+			return Err(ErrorKind::Expired.into());
+		}
+		if network_id != self.network_id {
+			trace!(target: "network", "Ignoring LAN announcement of network {} from {}", network_id, from);
+			return Ok(None);
+		}
+		if tcp_port == 0 {
+			return Err(ErrorKind::BadProtocol.into());
+		}
+		trace!(target: "network", "Got LAN announcement from {:?}@{}", node_id, from);
+		Ok(Some(NodeEntry {
+			id: node_id,
+			endpoint: NodeEndpoint { address: SocketAddr::new(from.ip(), tcp_port), udp_port: tcp_port },
+		}))
+	}
+
+	pub fn register_socket(&self, poll: &Poll) -> Result<(), Error> {
+		poll.register(&self.socket, Token(self.token), Ready::readable(), PollOpt::edge()).expect("Error registering LAN discovery socket");
+		Ok(())
+	}
+
+	pub fn update_registration(&self, poll: &Poll) -> Result<(), Error> {
+		poll.reregister(&self.socket, Token(self.token), Ready::readable(), PollOpt::edge()).expect("Error reregistering LAN discovery socket");
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::thread;
+	use std::time::Duration;
+	use std::str::FromStr;
+	use ethkey::{Random, Generator};
+
+	fn config(port: u16, network_id: u64) -> LanDiscoveryConfig {
+		LanDiscoveryConfig {
+			address: SocketAddr::from_str(&format!("239.255.42.99:{}", port)).unwrap(),
+			interval: Duration::from_secs(5),
+			network_id: network_id,
+			force: false,
+		}
+	}
+
+	/// Read announcements until one arrives or the attempts run out.
+	fn receive(discovery: &LanDiscovery) -> Vec<NodeEntry> {
+		for _ in 0..50 {
+			let nodes = discovery.readable();
+			if !nodes.is_empty() {
+				return nodes;
+			}
+			thread::sleep(Duration::from_millis(20));
+		}
+		Vec::new()
+	}
+
+	#[test]
+	fn announcements_are_exchanged() {
+		let key1 = Random.generate().unwrap();
+		let key2 = Random.generate().unwrap();
+		let lan1 = LanDiscovery::new(&key1, &config(40490, 1), 30401, 0).unwrap();
+		let lan2 = LanDiscovery::new(&key2, &config(40490, 1), 30402, 0).unwrap();
+
+		lan1.announce();
+		let nodes = receive(&lan2);
+		assert_eq!(nodes.len(), 1);
+		assert_eq!(nodes[0].id, *key1.public());
+		assert_eq!(nodes[0].endpoint.address.port(), 30401);
+		// Our own announcement is looped back and ignored.
+		assert!(lan1.readable().is_empty());
+
+		lan2.announce();
+		let nodes = receive(&lan1);
+		assert_eq!(nodes.len(), 1);
+		assert_eq!(nodes[0].id, *key2.public());
+		assert_eq!(nodes[0].endpoint.address.port(), 30402);
+	}
+
+	#[test]
+	fn announcements_are_validated() {
+		let key1 = Random.generate().unwrap();
+		let key2 = Random.generate().unwrap();
+		let lan1 = LanDiscovery::new(&key1, &config(40491, 1), 30401, 0).unwrap();
+		let lan2 = LanDiscovery::new(&key2, &config(40491, 2), 30402, 0).unwrap();
+		let from = SocketAddr::from_str("192.168.1.5:40491").unwrap();
+		let now = 1_500_000_000;
+
+		let packet = lan1.announcement(now).unwrap();
+		// Other network.
+		assert!(lan2.on_announcement(&packet, from, now).unwrap().is_none());
+		let lan2 = LanDiscovery::new(&key2, &config(40492, 1), 30402, 0).unwrap();
+		let node = lan2.on_announcement(&packet, from, now).unwrap().unwrap();
+		assert_eq!(node.endpoint.address, SocketAddr::from_str("192.168.1.5:30401").unwrap());
+		// Expired.
+		assert!(lan2.on_announcement(&packet, from, now + ANNOUNCEMENT_EXPIRY_SECS + 1).is_err());
+		// Tampered.
+		let mut tampered = packet.clone();
+		let last = tampered.len() - 1;
+		tampered[last] ^= 1;
+		assert!(lan2.on_announcement(&tampered, from, now).is_err());
+	}
+}
@@ -56,14 +56,12 @@
 //! }
 //! ```
 
-//TODO: use Poll from mio
-#![allow(deprecated)]
-
 extern crate ethcore_io as io;
 extern crate ethcore_bytes;
 extern crate ethereum_types;
 extern crate parking_lot;
 extern crate mio;
+extern crate net2;
 extern crate tiny_keccak;
 extern crate crypto as rcrypto;
 extern crate rand;
@@ -81,6 +79,7 @@ extern crate path;
 extern crate ethcore_logger;
 extern crate ethcore_network as network;
 extern crate ipnetwork;
+extern crate lru_cache;
 extern crate keccak_hash as hash;
 extern crate serde;
 extern crate serde_json;
@@ -101,18 +100,43 @@ mod connection;
 mod handshake;
 mod session;
 mod discovery;
+mod lan_discovery;
 mod service;
 mod node_table;
 mod stats;
 mod ip_utils;
 mod connection_filter;
+mod rate_limit;
+mod buffer_pool;
+mod boot_nodes;
+mod peer_watermarks;
+mod startup_burst;
+mod peer_ticks;
+mod status_report;
+mod events;
+mod timers;
+mod dial;
+mod socks;
+mod packet_trace;
+mod peer_sampling;
+mod requests;
 
 pub use service::NetworkService;
-pub use stats::NetworkStats;
-pub use connection_filter::{ConnectionFilter, ConnectionDirection};
-pub use host::NetworkContext;
+pub use stats::{NetworkStats, HandshakeFailure, HandshakeFailures, DialFailure, DialFailures, DisconnectOrigin, DisconnectCounts, DisconnectHistory, DISCONNECT_HISTORY_MINUTES};
+pub use stats::{NetworkRates, TrafficRates, TrafficRate, CompressionCounters, SessionChurn, FLAPPING_SESSION_SECS};
+pub use discovery::{DiscoveryStats, DiscoveryPacketCounts, DiscoveryRejections, NearNode};
+pub use status_report::{NetworkStatusReport, DiscoveryStatusReport};
+pub use peer_watermarks::PeerCountEvent;
+pub use events::{NetworkEvent, EventReceiver};
+pub use dial::{DialedPeer, DialError, DialResult};
+pub use packet_trace::{TraceEvent, TraceDirection};
+pub use ip_utils::AddressSource;
+pub use connection_filter::{ConnectionFilter, ConnectionDirection, ConnectionContext, SubnetLimitFilter, NodeIdAllowlistFilter, FilterStats, FilterDenial, FilterDecision};
+pub use host::{NetworkContext, PeerInfo, PeerProtocolInfo, PeerSocketInfo};
 
 pub use io::TimerToken;
-pub use node_table::{validate_node_url, NodeId};
+pub use node_table::{validate_node_url, NodeId, NodeEndpoint, NodeSource, QuarantinedNode, DialCandidate, HostResolver, DnsResolver};
 
 const PROTOCOL_VERSION: u32 = 5;
+/// Latest p2p protocol version without snappy compression.
+const UNCOMPRESSED_PROTOCOL_VERSION: u32 = 4;
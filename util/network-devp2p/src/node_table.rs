@@ -14,19 +14,25 @@
 // You should have received a copy of the GNU General Public License
 // along with Parity.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::collections::{HashMap, HashSet};
+use std::cmp::{min, max};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::{self, Display, Formatter};
 use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
 use std::net::{SocketAddr, ToSocketAddrs, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 use std::{fs, mem, slice};
 use ethereum_types::H512;
+use rand::{self, Rng};
 use rlp::*;
-use network::{Error, ErrorKind, AllowIP, IpFilter};
+use network::{Error, ErrorKind, AllowIP, IpFilter, PeerCapabilityInfo, ProtocolId};
 use discovery::{TableUpdates, NodeEntry};
+use stats::DialFailure;
 use ip_utils::*;
 use serde_json;
+use time;
 
 /// Node public key
 pub type NodeId = H512;
@@ -128,21 +134,135 @@ impl FromStr for NodeEndpoint {
 	}
 }
 
+/// Origin of a node table entry.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeSource {
+	/// Configured boot node.
+	Boot,
+	/// Added by the user, e.g. as a reserved peer or an ad-hoc dial.
+	Manual,
+	/// Learned from discovery or a LAN announcement.
+	Discovered,
+	/// Connected to us and never dialed successfully. Its listening port is a guess.
+	Incoming,
+}
+
+impl NodeSource {
+	/// Check if entries from this source may be evicted to keep the table under its size limit.
+	pub fn is_evictable(&self) -> bool {
+		match *self {
+			NodeSource::Boot | NodeSource::Manual => false,
+			NodeSource::Discovered | NodeSource::Incoming => true,
+		}
+	}
+}
+
 #[derive(PartialEq, Eq, Copy, Clone)]
 pub enum PeerType {
 	_Required,
 	Optional
 }
 
+#[derive(Clone)]
 pub struct Node {
 	pub id: NodeId,
+	/// Node endpoint. For nodes given by host name this is the last resolved address.
 	pub endpoint: NodeEndpoint,
 	pub peer_type: PeerType,
 	pub attempts: u32,
 	pub failures: u32,
+	/// Unix time in seconds of the last failed connection attempt.
+	pub last_failure: Option<u64>,
+	/// Cause of the last failed connection attempt, if it was recorded.
+	pub last_failure_kind: Option<DialFailure>,
+	/// Where the node came from.
+	pub source: NodeSource,
+	/// Failed connection attempts since the last successful session.
+	pub consecutive_failures: u32,
+	/// Unix time in seconds before which the node is not dialed again.
+	pub next_attempt: Option<u64>,
+	/// Host name to be resolved at dial time, if the node was specified by name.
+	pub hostname: Option<String>,
+	/// Unix time in seconds of the last successful session with this node.
+	pub last_contact: Option<u64>,
+	/// Unix time in seconds the node was last added or reported by discovery.
+	pub last_seen: u64,
+	/// Misbehaviour score reported by protocol handlers, as of `misbehaviour_updated`.
+	pub misbehaviour_score: u32,
+	/// Unix time in seconds `misbehaviour_score` was last changed.
+	pub misbehaviour_updated: u64,
+	/// Unix time in seconds until which the node is banned.
+	pub banned_until: Option<u64>,
+	/// Unix time in seconds the node was quarantined for failing too many times in a row.
+	pub quarantined_since: Option<u64>,
+	/// Unix time in seconds the quarantine ends.
+	pub quarantined_until: Option<u64>,
+	/// Capabilities the node offered in its last session.
+	pub capabilities: Vec<PeerCapabilityInfo>,
+	/// Unix time in seconds `capabilities` were recorded.
+	pub capabilities_seen: Option<u64>,
+	/// Set when dialing the node's address reached this node itself. Such nodes are never dialed.
+	pub is_self: bool,
+}
+
+/// Node that is not dialed because of repeated connection failures.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuarantinedNode {
+	/// Node id.
+	pub id: NodeId,
+	/// Unix time in seconds the quarantine started.
+	pub since: u64,
+	/// Unix time in seconds the quarantine ends.
+	pub until: u64,
+	/// Failed connection attempts since the last successful session.
+	pub consecutive_failures: u32,
+}
+
+/// Node table entry as a dial candidate, with the last failed connection attempt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DialCandidate {
+	/// Node id.
+	pub id: NodeId,
+	/// Where the node came from.
+	pub source: NodeSource,
+	/// Whether the node may be dialed now, i.e. it is not backed off, quarantined or banned.
+	pub dialable: bool,
+	/// Failed connection attempts since the last successful session.
+	pub consecutive_failures: u32,
+	/// Unix time in seconds of the last failed connection attempt.
+	pub last_failure: Option<u64>,
+	/// Cause of the last failed connection attempt.
+	pub last_failure_kind: Option<DialFailure>,
 }
 
 const DEFAULT_FAILURE_PERCENTAGE: usize = 50;
+/// Dial score of a node contacted just now. Halves after `RECENT_CONTACT_SECS`.
+const RECENCY_POINTS: i64 = 1000;
+/// Dial score per percent of successful connection attempts.
+const RELIABILITY_POINTS: i64 = 5;
+/// Dial score lost per failed connection attempt.
+const FAILURE_PENALTY: i64 = 50;
+/// Failures beyond this count do not lower the dial score further.
+const MAX_PENALISED_FAILURES: u32 = 20;
+/// Dial score bonus of nodes added by the user.
+const MANUAL_NODE_POINTS: i64 = 100;
+/// Dial score penalty of nodes only known from incoming connections.
+const INCOMING_NODE_PENALTY: i64 = 200;
+/// Failures count half after this many seconds.
+const FAILURE_HALF_LIFE_SECS: u64 = 6 * 60 * 60;
+const DEFAULT_DIAL_BACKOFF_SECS: u64 = 5;
+const DEFAULT_DIAL_MAX_BACKOFF_SECS: u64 = 10 * 60;
+const DEFAULT_RESERVED_DIAL_MAX_BACKOFF_SECS: u64 = 30;
+const DEFAULT_CAPABILITY_MAX_AGE_SECS: u64 = 24 * 60 * 60;
+
+/// Dial delay after `failures` consecutive failures: `base` doubled for every failure after the first, up to `cap`.
+fn dial_backoff(failures: u32, base: u64, cap: u64) -> u64 {
+	if failures == 0 {
+		return 0;
+	}
+	min(base.saturating_mul(1u64 << min(failures - 1, 32)), cap)
+}
 
 impl Node {
 	pub fn new(id: NodeId, endpoint: NodeEndpoint) -> Node {
@@ -152,6 +272,22 @@ impl Node {
 			peer_type: PeerType::Optional,
 			attempts: 0,
 			failures: 0,
+			last_failure: None,
+			last_failure_kind: None,
+			source: NodeSource::Manual,
+			consecutive_failures: 0,
+			next_attempt: None,
+			hostname: None,
+			last_contact: None,
+			last_seen: 0,
+			misbehaviour_score: 0,
+			misbehaviour_updated: 0,
+			banned_until: None,
+			quarantined_since: None,
+			quarantined_until: None,
+			capabilities: Vec::new(),
+			capabilities_seen: None,
+			is_self: false,
 		}
 	}
 
@@ -164,11 +300,68 @@ impl Node {
 			(self.failures * 100 / self.attempts / 5 * 5) as usize
 		}
 	}
+
+	/// Check if the node had a successful session within `RECENT_CONTACT_SECS` of `now`.
+	fn recently_contacted(&self, now: u64) -> bool {
+		self.last_contact.map_or(false, |t| t + RECENT_CONTACT_SECS > now)
+	}
+
+	/// Failure count at `now`, halved for every `FAILURE_HALF_LIFE_SECS` since the last failure.
+	fn decayed_failures(&self, now: u64) -> u32 {
+		let half_lives = self.last_failure.map_or(0, |t| now.saturating_sub(t) / FAILURE_HALF_LIFE_SECS);
+		self.failures >> min(half_lives, 31)
+	}
+
+	/// Dial preference at `now`, higher is better. Nodes with a recent successful session, a good
+	/// success ratio and few recent failures score high; boot nodes and nodes added by the user get
+	/// a bonus, nodes only known from incoming connections a penalty.
+	pub fn dial_score(&self, now: u64) -> i64 {
+		let recency = self.last_contact.map_or(0, |t| {
+			RECENCY_POINTS * RECENT_CONTACT_SECS as i64 / (RECENT_CONTACT_SECS + now.saturating_sub(t)) as i64
+		});
+		let reliability = (100 - self.failure_percentage() as i64) * RELIABILITY_POINTS;
+		let failures = min(self.decayed_failures(now), MAX_PENALISED_FAILURES) as i64 * FAILURE_PENALTY;
+		let source = match self.source {
+			NodeSource::Boot | NodeSource::Manual => MANUAL_NODE_POINTS,
+			NodeSource::Discovered => 0,
+			NodeSource::Incoming => -INCOMING_NODE_PENALTY,
+		};
+		recency + reliability + source - failures
+	}
+
+	/// Misbehaviour score at `now`, after losing `points` every `period` seconds.
+	fn decayed_score(&self, now: u64, points: u32, period: u64) -> u32 {
+		let elapsed = now.saturating_sub(self.misbehaviour_updated);
+		let decay = elapsed.saturating_mul(points as u64) / max(period, 1);
+		self.misbehaviour_score.saturating_sub(min(decay, u32::max_value() as u64) as u32)
+	}
+
+	/// Check if capabilities recorded no more than `max_age` seconds before `now` include the protocol
+	/// in `min_version` or later.
+	fn supports(&self, protocol: ProtocolId, min_version: u8, now: u64, max_age: u64) -> bool {
+		self.capabilities_seen.map_or(false, |seen| now.saturating_sub(seen) <= max_age) &&
+			self.capabilities.iter().any(|c| c.protocol == protocol && c.version >= min_version)
+	}
+
+	/// Address a node given by host name was last resolved and dialed at.
+	pub fn last_resolved_address(&self) -> Option<SocketAddr> {
+		match self.hostname {
+			Some(_) if !self.endpoint.address.ip().is_unspecified() => Some(self.endpoint.address),
+			_ => None,
+		}
+	}
+
+	/// Check if the node is in quarantine at `now`.
+	fn is_quarantined(&self, now: u64) -> bool {
+		self.quarantined_until.map_or(false, |until| now < until)
+	}
 }
 
 impl Display for Node {
 	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-		if self.endpoint.udp_port != self.endpoint.address.port() {
+		if let Some(ref hostname) = self.hostname {
+			write!(f, "enode://{}@{}:{}", self.id.hex(), hostname, self.endpoint.address.port())?;
+		} else if self.endpoint.udp_port != self.endpoint.address.port() {
 			write!(f, "enode://{}@{}+{}", self.id.hex(), self.endpoint.address, self.endpoint.udp_port)?;
 		} else {
 			write!(f, "enode://{}@{}", self.id.hex(), self.endpoint.address)?;
@@ -180,23 +373,121 @@ impl Display for Node {
 impl FromStr for Node {
 	type Err = Error;
 	fn from_str(s: &str) -> Result<Self, Self::Err> {
-		let (id, endpoint) = if s.len() > 136 && &s[0..8] == "enode://" && &s[136..137] == "@" {
-			(s[8..136].parse().map_err(|_| ErrorKind::InvalidNodeId)?, NodeEndpoint::from_str(&s[137..])?)
+		let (id, endpoint, hostname) = if s.len() > 136 && &s[0..8] == "enode://" && &s[136..137] == "@" {
+			let id = s[8..136].parse().map_err(|_| ErrorKind::InvalidNodeId)?;
+			let (endpoint, hostname) = parse_enode_address(&s[137..])?;
+			(id, endpoint, hostname)
 		}
 		else {
-			(NodeId::new(), NodeEndpoint::from_str(s)?)
+			(NodeId::new(), NodeEndpoint::from_str(s)?, None)
 		};
 
 		Ok(Node {
-			id: id,
-			endpoint: endpoint,
-			peer_type: PeerType::Optional,
-			attempts: 0,
-			failures: 0,
+			hostname: hostname,
+			..Node::new(id, endpoint)
 		})
 	}
 }
 
+/// Parse the address part of an enode URL. Numeric addresses are used as is, host names are
+/// validated but not resolved; the returned endpoint then has an unspecified IP.
+fn parse_enode_address(s: &str) -> Result<(NodeEndpoint, Option<String>), Error> {
+	if let Ok(address) = SocketAddr::from_str(s) {
+		return Ok((NodeEndpoint { address: address, udp_port: address.port() }, None));
+	}
+	let (host, port) = match s.rfind(':') {
+		Some(pos) => (&s[..pos], &s[pos + 1..]),
+		None => return Err(ErrorKind::AddressResolve(None).into()),
+	};
+	let port: u16 = port.parse().map_err(|_| ErrorKind::AddressResolve(None))?;
+	if !is_valid_hostname(host) {
+		return Err(ErrorKind::AddressResolve(None).into());
+	}
+	let address = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port));
+	Ok((NodeEndpoint { address: address, udp_port: port }, Some(host.to_owned())))
+}
+
+/// Check that the string is a syntactically valid DNS host name.
+fn is_valid_hostname(host: &str) -> bool {
+	!host.is_empty() && host.len() <= 253 && host.split('.').all(|label| {
+		!label.is_empty() && label.len() <= 63 &&
+			!label.starts_with('-') && !label.ends_with('-') &&
+			label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+	})
+}
+
+/// Host name resolver used when dialing nodes specified by name.
+pub trait HostResolver: Send + Sync {
+	/// Resolve host name into a list of socket addresses with the given port.
+	fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// Resolver backed by the system DNS configuration.
+pub struct DnsResolver;
+
+impl HostResolver for DnsResolver {
+	fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+		(host, port).to_socket_addrs().map(|addrs| addrs.collect())
+	}
+}
+
+/// What is needed to find the addresses to dial for a node, taken from the node table so that host
+/// names can be resolved without holding the table lock.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DialTarget {
+	/// Address of the node, or the address its host name was last resolved to.
+	address: SocketAddr,
+	hostname: Option<String>,
+	last_resolved: Option<SocketAddr>,
+	/// Resolved address that failed last.
+	failed: Option<SocketAddr>,
+}
+
+impl DialTarget {
+	/// Target for a node that is not in the table.
+	pub fn new(node: &Node) -> DialTarget {
+		DialTarget {
+			address: node.endpoint.address,
+			hostname: node.hostname.clone(),
+			last_resolved: node.last_resolved_address(),
+			failed: None,
+		}
+	}
+
+	/// Check if the addresses are found with a host name lookup.
+	pub fn needs_resolution(&self) -> bool {
+		self.hostname.is_some()
+	}
+
+	/// Returns addresses to dial, most preferred first. Host names are resolved on every call and
+	/// the address that failed last is moved to the end of the list. If resolution fails, the address
+	/// the name was last resolved to is returned.
+	pub fn addresses(&self, resolver: &HostResolver) -> Vec<SocketAddr> {
+		let hostname = match self.hostname {
+			Some(ref hostname) => hostname,
+			None => return vec![self.address],
+		};
+		let mut addresses = match resolver.resolve(hostname, self.address.port()) {
+			Ok(ref addresses) if addresses.is_empty() => {
+				debug!(target: "network", "No address found for {}", hostname);
+				return self.last_resolved.into_iter().collect();
+			},
+			Ok(addresses) => addresses,
+			Err(e) => {
+				debug!(target: "network", "Error resolving {}: {:?}", hostname, e);
+				return self.last_resolved.into_iter().collect();
+			}
+		};
+		if let Some(ref failed) = self.failed {
+			if let Some(pos) = addresses.iter().position(|a| a == failed) {
+				let failed = addresses.remove(pos);
+				addresses.push(failed);
+			}
+		}
+		addresses
+	}
+}
+
 impl PartialEq for Node {
 	fn eq(&self, other: &Self) -> bool {
 		self.id == other.id
@@ -212,12 +503,89 @@ impl Hash for Node {
 
 const MAX_NODES: usize = 1024;
 const NODES_FILE: &str = "nodes.json";
+/// File the new node table is written to before it replaces `NODES_FILE`.
+const NODES_TEMP_FILE: &str = "nodes.json.tmp";
+/// Previous node table, loaded if `NODES_FILE` is missing or unreadable.
+const NODES_BACKUP_FILE: &str = "nodes.json.bak";
+/// Version of the node table file format.
+const NODES_FILE_VERSION: u32 = 1;
+/// Default limit for the number of entries in the table.
+const DEFAULT_MAX_TABLE_SIZE: usize = 8192;
+/// Nodes with a successful session within this many seconds are never evicted.
+const RECENT_CONTACT_SECS: u64 = 24 * 60 * 60;
+/// Maximum number of entries evicted for each inserted one.
+const MAX_EVICTIONS_PER_INSERT: usize = 2;
+/// Maximum number of eviction candidates selected in one pass over the table.
+const EVICTION_BATCH: usize = 256;
+
+/// Reorder dial candidates sorted best first so that `exploration_percent` of the picks, on average,
+/// are taken at random. This way nodes without a track record still get tried.
+pub fn with_exploration<R: Rng>(nodes: Vec<NodeId>, exploration_percent: u32, rng: &mut R) -> Vec<NodeId> {
+	if exploration_percent == 0 || nodes.len() < 2 {
+		return nodes;
+	}
+	let mut shuffled = nodes.clone();
+	rng.shuffle(&mut shuffled);
+	let mut taken = HashSet::with_capacity(nodes.len());
+	let mut ordered = Vec::with_capacity(nodes.len());
+	let mut best = nodes.into_iter();
+	let mut random = shuffled.into_iter();
+	loop {
+		let next = if rng.gen_range(0, 100) < exploration_percent {
+			random.find(|id| !taken.contains(id))
+		} else {
+			best.find(|id| !taken.contains(id))
+		};
+		// Either iterator yields every node, so once one runs dry all nodes are taken.
+		match next {
+			Some(id) => {
+				taken.insert(id);
+				ordered.push(id);
+			},
+			None => return ordered,
+		}
+	}
+}
+
+/// Move the `preferred` nodes to the front of `nodes`, keeping the order within both groups.
+pub fn prefer_nodes(nodes: Vec<NodeId>, preferred: &HashSet<NodeId>) -> Vec<NodeId> {
+	if preferred.is_empty() {
+		return nodes;
+	}
+	let (mut first, rest): (Vec<_>, Vec<_>) = nodes.into_iter().partition(|id| preferred.contains(id));
+	first.extend(rest);
+	first
+}
 
 /// Node table backed by disk file.
 pub struct NodeTable {
 	nodes: HashMap<NodeId, Node>,
 	useless_nodes: HashSet<NodeId>,
+	/// Last resolved address that failed for nodes given by host name.
+	failed_addresses: HashMap<NodeId, SocketAddr>,
 	path: Option<String>,
+	/// Maximum number of entries. Exceeding entries are evicted as new ones are inserted.
+	max_size: usize,
+	/// Entries to evict next, worst first. Rebuilt when exhausted.
+	eviction_queue: VecDeque<NodeId>,
+	/// Accept nodes with private, loopback and link-local addresses from discovery.
+	allow_non_global: bool,
+	/// Misbehaviour score at which a node is blocked. Zero disables scoring.
+	misbehaviour_threshold: u32,
+	/// Scores lose `misbehaviour_threshold` points over this many seconds.
+	misbehaviour_window: u64,
+	/// Dial delay after the first failure, in seconds.
+	dial_backoff: u64,
+	/// Upper bound for the dial delay, in seconds.
+	dial_max_backoff: u64,
+	/// Upper bound for the dial delay of reserved nodes, in seconds.
+	reserved_dial_max_backoff: u64,
+	/// Consecutive failures after which a node is quarantined. Zero disables quarantine.
+	quarantine_threshold: u32,
+	/// Quarantine duration, in seconds.
+	quarantine_period: u64,
+	/// Age in seconds after which recorded capabilities are not relied upon.
+	capability_max_age: u64,
 }
 
 impl NodeTable {
@@ -226,44 +594,171 @@ impl NodeTable {
 			path: path.clone(),
 			nodes: NodeTable::load(path),
 			useless_nodes: HashSet::new(),
+			failed_addresses: HashMap::new(),
+			max_size: DEFAULT_MAX_TABLE_SIZE,
+			eviction_queue: VecDeque::new(),
+			allow_non_global: true,
+			misbehaviour_threshold: 0,
+			misbehaviour_window: 1,
+			dial_backoff: DEFAULT_DIAL_BACKOFF_SECS,
+			dial_max_backoff: DEFAULT_DIAL_MAX_BACKOFF_SECS,
+			reserved_dial_max_backoff: DEFAULT_RESERVED_DIAL_MAX_BACKOFF_SECS,
+			quarantine_threshold: 0,
+			quarantine_period: 0,
+			capability_max_age: DEFAULT_CAPABILITY_MAX_AGE_SECS,
 		}
 	}
 
+	/// Set the dial delay after the first failure and its upper bounds for all and for reserved nodes.
+	/// The delay doubles with every consecutive failure.
+	pub fn set_dial_backoff(&mut self, backoff: Duration, max_backoff: Duration, reserved_max_backoff: Duration) {
+		self.dial_backoff = backoff.as_secs();
+		self.dial_max_backoff = max_backoff.as_secs();
+		self.reserved_dial_max_backoff = reserved_max_backoff.as_secs();
+	}
+
+	/// Set the number of consecutive failures after which a node is not dialed for `period`.
+	/// A zero threshold disables quarantine.
+	pub fn set_quarantine(&mut self, threshold: u32, period: Duration) {
+		self.quarantine_threshold = threshold;
+		self.quarantine_period = period.as_secs();
+	}
+
+	/// Set the age after which the capabilities recorded for a node are considered stale.
+	pub fn set_capability_max_age(&mut self, max_age: Duration) {
+		self.capability_max_age = max_age.as_secs();
+	}
+
+	/// Set the misbehaviour score at which nodes are blocked and the time it takes for a score
+	/// of that size to decay.
+	pub fn set_misbehaviour_limits(&mut self, threshold: u32, window_secs: u64) {
+		self.misbehaviour_threshold = threshold;
+		self.misbehaviour_window = window_secs;
+	}
+
+	/// Set whether discovery may add nodes with private, loopback and link-local addresses.
+	pub fn set_allow_non_global(&mut self, allow: bool) {
+		self.allow_non_global = allow;
+	}
+
+	/// Set the maximum number of entries. A table that is already larger shrinks gradually as
+	/// discovery inserts new nodes.
+	pub fn set_max_size(&mut self, max_size: usize) {
+		self.max_size = max_size;
+	}
+
 	/// Add a node to table
 	pub fn add_node(&mut self, mut node: Node) {
-		// preserve attempts and failure counter
-		let (attempts, failures) =
-			self.nodes.get(&node.id).map_or((0, 0), |n| (n.attempts, n.failures));
-
-		node.attempts = attempts;
-		node.failures = failures;
+		// preserve attempts, failure counters, dial backoff, quarantine, last contact time, misbehaviour record and capabilities
+		if let Some(old) = self.nodes.get(&node.id) {
+			node.attempts = old.attempts;
+			node.failures = old.failures;
+			node.last_failure = old.last_failure;
+			node.last_failure_kind = old.last_failure_kind;
+			node.consecutive_failures = old.consecutive_failures;
+			node.next_attempt = old.next_attempt;
+			node.last_contact = old.last_contact;
+			node.misbehaviour_score = old.misbehaviour_score;
+			node.misbehaviour_updated = old.misbehaviour_updated;
+			node.banned_until = old.banned_until;
+			node.quarantined_since = old.quarantined_since;
+			node.quarantined_until = old.quarantined_until;
+			node.capabilities = old.capabilities.clone();
+			node.capabilities_seen = old.capabilities_seen;
+			// a new address may lead to the real node
+			node.is_self = old.is_self && old.endpoint.address == node.endpoint.address;
+		}
+		node.last_seen = time::get_time().sec as u64;
 
 		self.nodes.insert(node.id.clone(), node);
 	}
 
-	/// Returns node ids sorted by failure percentage, for nodes with the same failure percentage the absolute number of
-	/// failures is considered.
+	/// Returns what is needed to find the addresses to dial for the node. Resolve them with
+	/// `DialTarget::addresses` once the table lock is released.
+	pub fn dial_target(&self, id: &NodeId) -> Option<DialTarget> {
+		self.nodes.get(id).map(|node| DialTarget {
+			failed: self.failed_addresses.get(id).cloned(),
+			..DialTarget::new(node)
+		})
+	}
+
+	/// Returns addresses to dial for the node, most preferred first. See `DialTarget::addresses`.
+	pub fn dial_addresses(&self, id: &NodeId, resolver: &HostResolver) -> Vec<SocketAddr> {
+		self.dial_target(id).map_or_else(Vec::new, |target| target.addresses(resolver))
+	}
+
+	/// Record the address a node given by host name was last resolved and dialed at.
+	pub fn note_resolved(&mut self, id: &NodeId, address: SocketAddr) {
+		if let Some(node) = self.nodes.get_mut(id) {
+			if node.hostname.is_some() {
+				node.endpoint.address = address;
+				node.endpoint.udp_port = address.port();
+			}
+		}
+	}
+
+	/// Returns node ids sorted by dial score, best first. For nodes with the same score the failure percentage and
+	/// the absolute number of failures are considered.
 	pub fn nodes(&self, filter: IpFilter) -> Vec<NodeId> {
+		let now = time::get_time().sec as u64;
 		let mut refs: Vec<&Node> = self.nodes.values()
-			.filter(|n| !self.useless_nodes.contains(&n.id))
-			.filter(|n| n.endpoint.is_allowed(&filter))
+			.filter(|n| !self.useless_nodes.contains(&n.id) && !n.is_self)
+			.filter(|n| n.hostname.is_some() || n.endpoint.is_allowed(&filter))
+			.filter(|n| n.hostname.is_some() || ip_class(&n.endpoint.address.ip()).is_accepted(true))
 			.collect();
 		refs.sort_by(|a, b| {
-			a.failure_percentage().cmp(&b.failure_percentage())
+			b.dial_score(now).cmp(&a.dial_score(now))
+				.then_with(|| a.failure_percentage().cmp(&b.failure_percentage()))
 				.then_with(|| a.failures.cmp(&b.failures))
 				.then_with(|| b.attempts.cmp(&a.attempts)) // we use reverse ordering for number of attempts
 		});
 		refs.into_iter().map(|n| n.id).collect()
 	}
 
-	/// Unordered list of all entries
+	/// Returns the ids of nodes recorded as supporting the protocol in `min_version` or later, sorted by
+	/// dial score. Capabilities older than the configured maximum age are ignored.
+	pub fn nodes_supporting(&self, protocol: ProtocolId, min_version: u8) -> Vec<NodeId> {
+		let now = time::get_time().sec as u64;
+		let mut refs: Vec<&Node> = self.nodes.values()
+			.filter(|n| !self.useless_nodes.contains(&n.id) && !n.is_self)
+			.filter(|n| n.supports(protocol, min_version, now, self.capability_max_age))
+			.collect();
+		refs.sort_by(|a, b| b.dial_score(now).cmp(&a.dial_score(now)));
+		refs.into_iter().map(|n| n.id).collect()
+	}
+
+	/// Record the capabilities a node offered in a session established at `now`.
+	pub fn note_capabilities(&mut self, id: &NodeId, capabilities: Vec<PeerCapabilityInfo>, now: u64) {
+		if let Some(node) = self.nodes.get_mut(id) {
+			node.capabilities = capabilities;
+			node.capabilities_seen = Some(now);
+		}
+	}
+
+	/// Returns up to `count` node ids with the most recent successful sessions, latest first.
+	pub fn recently_contacted(&self, count: usize) -> Vec<NodeId> {
+		let mut refs: Vec<(&NodeId, u64)> = self.nodes.values()
+			.filter(|n| !self.useless_nodes.contains(&n.id))
+			.filter_map(|n| n.last_contact.map(|t| (&n.id, t)))
+			.collect();
+		refs.sort_by(|a, b| b.1.cmp(&a.1));
+		refs.into_iter().take(count).map(|(id, _)| id.clone()).collect()
+	}
+
+	/// Unordered list of all entries. Nodes given by host name are not included as
+	/// discovery only deals with numeric endpoints.
 	pub fn unordered_entries(&self) -> Vec<NodeEntry> {
-		self.nodes.values().map(|n| NodeEntry {
+		self.nodes.values().filter(|n| n.hostname.is_none()).map(|n| NodeEntry {
 			endpoint: n.endpoint.clone(),
 			id: n.id.clone(),
 		}).collect()
 	}
 
+	/// Get particular node
+	pub fn get(&self, id: &NodeId) -> Option<&Node> {
+		self.nodes.get(id)
+	}
+
 	/// Get particular node
 	pub fn get_mut(&mut self, id: &NodeId) -> Option<&mut Node> {
 		self.nodes.get_mut(id)
@@ -274,11 +769,16 @@ impl NodeTable {
 		self.nodes.contains_key(id)
 	}
 
-	/// Apply table changes coming from discovery
+	/// Apply table changes coming from discovery. Every new entry that takes the table over
+	/// its size limit evicts up to `MAX_EVICTIONS_PER_INSERT` low quality entries.
 	pub fn update(&mut self, mut update: TableUpdates, reserved: &HashSet<NodeId>) {
+		let now = time::get_time().sec as u64;
 		for (_, node) in update.added.drain() {
-			let entry = self.nodes.entry(node.id.clone()).or_insert_with(|| Node::new(node.id.clone(), node.endpoint.clone()));
-			entry.endpoint = node.endpoint;
+			if !ip_class(&node.endpoint.address.ip()).is_accepted(self.allow_non_global) {
+				trace!(target: "network", "Ignoring node {} with address {}", node.id, node.endpoint.address);
+				continue;
+			}
+			self.insert_discovered(node, reserved, now);
 		}
 		for r in update.removed {
 			if !reserved.contains(&r) {
@@ -287,10 +787,233 @@ impl NodeTable {
 		}
 	}
 
-	/// Increase failure counte for a node
-	pub fn note_failure(&mut self, id: &NodeId) {
+	/// Add a node announced on the local network. Its address is accepted even if non-global
+	/// addresses are not. Returns `true` if the node is new to the table.
+	pub fn add_lan_node(&mut self, node: NodeEntry, reserved: &HashSet<NodeId>) -> bool {
+		let now = time::get_time().sec as u64;
+		self.insert_discovered(node, reserved, now)
+	}
+
+	fn insert_discovered(&mut self, node: NodeEntry, reserved: &HashSet<NodeId>, now: u64) -> bool {
+		let inserted = !self.nodes.contains_key(&node.id);
+		{
+			let entry = self.nodes.entry(node.id.clone()).or_insert_with(|| {
+				let mut entry = Node::new(node.id.clone(), node.endpoint.clone());
+				entry.source = NodeSource::Discovered;
+				entry
+			});
+			// discovery knows the real listening port
+			if entry.source == NodeSource::Incoming {
+				entry.source = NodeSource::Discovered;
+			}
+			entry.endpoint = node.endpoint;
+			entry.last_seen = now;
+		}
+		if inserted {
+			self.evict(reserved, now);
+		}
+		inserted
+	}
+
+	/// Check if a node may be evicted. Boot, manual, reserved and recently contacted nodes are kept.
+	fn evictable(&self, id: &NodeId, reserved: &HashSet<NodeId>, now: u64) -> bool {
+		!reserved.contains(id) && self.nodes.get(id).map_or(false, |n| n.source.is_evictable() && !n.recently_contacted(now))
+	}
+
+	/// Remove entries while the table is over its size limit, at most `MAX_EVICTIONS_PER_INSERT`
+	/// at a time. Nodes that never had a successful session go first, then the ones seen least recently.
+	fn evict(&mut self, reserved: &HashSet<NodeId>, now: u64) {
+		let mut evicted = 0;
+		while self.nodes.len() > self.max_size && evicted < MAX_EVICTIONS_PER_INSERT {
+			let id = match self.eviction_queue.pop_front() {
+				Some(id) => id,
+				None => {
+					self.eviction_queue = self.eviction_candidates(reserved, now);
+					match self.eviction_queue.pop_front() {
+						Some(id) => id,
+						// everything left is protected.
+						None => return,
+					}
+				}
+			};
+			// The queue may be stale, re-check the entry.
+			if self.evictable(&id, reserved, now) {
+				trace!(target: "network", "Evicting node {} from the node table", id);
+				self.nodes.remove(&id);
+				self.useless_nodes.remove(&id);
+				self.failed_addresses.remove(&id);
+				evicted += 1;
+			}
+		}
+	}
+
+	/// Select up to `EVICTION_BATCH` evictable entries, worst first.
+	fn eviction_candidates(&self, reserved: &HashSet<NodeId>, now: u64) -> VecDeque<NodeId> {
+		let mut refs: Vec<&Node> = self.nodes.values()
+			.filter(|n| !reserved.contains(&n.id) && n.source.is_evictable() && !n.recently_contacted(now))
+			.collect();
+		refs.sort_by(|a, b| {
+			a.last_contact.is_some().cmp(&b.last_contact.is_some())
+				.then_with(|| a.last_seen.cmp(&b.last_seen))
+		});
+		refs.into_iter().take(EVICTION_BATCH).map(|n| n.id.clone()).collect()
+	}
+
+	/// Increase failure counte for a node and delay its next dial attempt.
+	pub fn note_failure(&mut self, id: &NodeId, reserved: bool) {
+		self.note_failure_at(id, reserved, time::get_time().sec as u64, &mut rand::thread_rng());
+	}
+
+	/// Record a failed connection attempt with its cause.
+	pub fn note_dial_failure(&mut self, id: &NodeId, reserved: bool, failure: DialFailure) {
+		self.note_failure(id, reserved);
+		if let Some(node) = self.nodes.get_mut(id) {
+			node.last_failure_kind = Some(failure);
+		}
+	}
+
+	/// Record a failed connection attempt at `now`. The node is not dialed again for the backoff delay,
+	/// shortened by a random jitter of up to half of it so that nodes failing together are retried apart.
+	/// Non-reserved nodes reaching the quarantine threshold are not dialed for the quarantine period.
+	pub fn note_failure_at<R: Rng>(&mut self, id: &NodeId, reserved: bool, now: u64, rng: &mut R) {
+		let cap = if reserved { self.reserved_dial_max_backoff } else { self.dial_max_backoff };
+		let base = self.dial_backoff;
+		let (threshold, period) = (self.quarantine_threshold, self.quarantine_period);
 		if let Some(node) = self.nodes.get_mut(id) {
 			node.failures += 1;
+			node.consecutive_failures += 1;
+			node.last_failure = Some(now);
+			let delay = dial_backoff(node.consecutive_failures, base, cap);
+			let jitter = rng.gen_range(0, delay / 2 + 1);
+			node.next_attempt = Some(now + delay - jitter);
+			trace!(target: "network", "Node {} failed {} times in a row, next attempt in {}s", id, node.consecutive_failures, delay - jitter);
+			if !reserved && threshold != 0 && node.consecutive_failures >= threshold && !node.is_quarantined(now) {
+				debug!(target: "network", "Quarantining node {} for {}s after {} failures", id, period, node.consecutive_failures);
+				node.quarantined_since = Some(now);
+				node.quarantined_until = Some(now + period);
+			}
+			if node.hostname.is_some() {
+				self.failed_addresses.insert(id.clone(), node.endpoint.address);
+			}
+		}
+	}
+
+	/// Check if the dial backoff and the quarantine of the node have passed and the node is not
+	/// ourselves. Always true for unknown nodes.
+	pub fn can_dial(&self, id: &NodeId, now: u64) -> bool {
+		self.nodes.get(id).map_or(true, |n| !n.is_self && n.next_attempt.map_or(true, |t| now >= t) && !n.is_quarantined(now))
+	}
+
+	/// Mark the node as pointing at ourselves, so that it is never dialed again. The mark is kept
+	/// until the node is added with a different address.
+	pub fn mark_as_self(&mut self, id: &NodeId) {
+		if let Some(node) = self.nodes.get_mut(id) {
+			debug!(target: "network", "Node {} resolves to ourselves at {}", id, node.endpoint.address);
+			node.is_self = true;
+		}
+	}
+
+	/// Check if the node was found to point at ourselves.
+	pub fn is_self(&self, id: &NodeId) -> bool {
+		self.nodes.get(id).map_or(false, |n| n.is_self)
+	}
+
+	/// Record a successful session with a node. Resets the dial backoff and lifts the quarantine.
+	pub fn note_contact(&mut self, id: &NodeId) {
+		self.note_contact_at(id, time::get_time().sec as u64);
+	}
+
+	/// Record a successful session with a node at `now`.
+	pub fn note_contact_at(&mut self, id: &NodeId, now: u64) {
+		if let Some(node) = self.nodes.get_mut(id) {
+			node.last_contact = Some(now);
+			node.last_seen = now;
+			node.consecutive_failures = 0;
+			node.next_attempt = None;
+			if node.quarantined_until.take().is_some() {
+				debug!(target: "network", "Node {} released from quarantine", id);
+			}
+			node.quarantined_since = None;
+		}
+	}
+
+	/// Record a successful outbound session. A node only known from incoming connections has proven
+	/// dialable and is treated as discovered from now on.
+	pub fn note_dialed(&mut self, id: &NodeId) {
+		if let Some(node) = self.nodes.get_mut(id) {
+			if node.source == NodeSource::Incoming {
+				node.source = NodeSource::Discovered;
+			}
+		}
+	}
+
+	/// Enode URLs of the entries from `source`, or of all entries if `None`, best dial candidates first.
+	pub fn export(&self, source: Option<NodeSource>) -> Vec<String> {
+		let now = time::get_time().sec as u64;
+		let mut refs: Vec<&Node> = self.nodes.values()
+			.filter(|n| source.map_or(true, |s| n.source == s))
+			.collect();
+		refs.sort_by(|a, b| b.dial_score(now).cmp(&a.dial_score(now)));
+		refs.into_iter().map(|n| n.to_string()).collect()
+	}
+
+	/// All entries as dial candidates at `now`, best first.
+	pub fn dial_candidates(&self, now: u64) -> Vec<DialCandidate> {
+		let mut refs: Vec<&Node> = self.nodes.values().collect();
+		refs.sort_by(|a, b| b.dial_score(now).cmp(&a.dial_score(now)));
+		refs.into_iter().map(|n| DialCandidate {
+			id: n.id.clone(),
+			source: n.source,
+			dialable: self.can_dial(&n.id, now) && !self.is_blocked(&n.id, now),
+			consecutive_failures: n.consecutive_failures,
+			last_failure: n.last_failure,
+			last_failure_kind: n.last_failure_kind,
+		}).collect()
+	}
+
+	/// Nodes in quarantine at `now`, latest ending first.
+	pub fn quarantined(&self, now: u64) -> Vec<QuarantinedNode> {
+		let mut nodes: Vec<QuarantinedNode> = self.nodes.values()
+			.filter(|n| n.is_quarantined(now))
+			.map(|n| QuarantinedNode {
+				id: n.id.clone(),
+				since: n.quarantined_since.unwrap_or(now),
+				until: n.quarantined_until.unwrap_or(now),
+				consecutive_failures: n.consecutive_failures,
+			})
+			.collect();
+		nodes.sort_by(|a, b| b.until.cmp(&a.until));
+		nodes
+	}
+
+	/// Current misbehaviour score of a node.
+	pub fn misbehaviour_score(&self, id: &NodeId, now: u64) -> u32 {
+		self.nodes.get(id).map_or(0, |n| n.decayed_score(now, self.misbehaviour_threshold, self.misbehaviour_window))
+	}
+
+	/// Add a misbehaviour penalty to the node's score. Returns the new score, `None` if the node is unknown.
+	pub fn report_misbehaviour(&mut self, id: &NodeId, penalty: u32, now: u64) -> Option<u32> {
+		let (threshold, window) = (self.misbehaviour_threshold, self.misbehaviour_window);
+		self.nodes.get_mut(id).map(|node| {
+			node.misbehaviour_score = node.decayed_score(now, threshold, window).saturating_add(penalty);
+			node.misbehaviour_updated = now;
+			node.misbehaviour_score
+		})
+	}
+
+	/// Ban a node until the given unix time.
+	pub fn ban(&mut self, id: &NodeId, until: u64) {
+		if let Some(node) = self.nodes.get_mut(id) {
+			node.banned_until = Some(max(until, node.banned_until.unwrap_or(0)));
+		}
+	}
+
+	/// Check if the node is banned or its misbehaviour score is at the threshold.
+	pub fn is_blocked(&self, id: &NodeId, now: u64) -> bool {
+		match self.nodes.get(id) {
+			Some(node) => node.banned_until.map_or(false, |until| now < until) ||
+				(self.misbehaviour_threshold != 0 && node.decayed_score(now, self.misbehaviour_threshold, self.misbehaviour_window) >= self.misbehaviour_threshold),
+			None => false,
 		}
 	}
 
@@ -305,61 +1028,90 @@ impl NodeTable {
 	}
 
 	/// Save the nodes.json file.
+	/// Write the table to disk, logging errors.
 	pub fn save(&self) {
-		let mut path = match self.path {
+		if let Err(e) = self.flush() {
+			warn!("Error saving node table: {:?}", e);
+		}
+	}
+
+	/// Write the table to disk. The file is replaced atomically: the table is written to a temporary
+	/// file which is synced and renamed over the previous one. The previous file is kept as a backup.
+	pub fn flush(&self) -> io::Result<()> {
+		let dir = match self.path {
 			Some(ref path) => PathBuf::from(path),
-			None => return,
+			None => return Ok(()),
 		};
-		if let Err(e) = fs::create_dir_all(&path) {
-			warn!("Error creating node table directory: {:?}", e);
-			return;
-		}
-		path.push(NODES_FILE);
+		fs::create_dir_all(&dir)?;
 		let node_ids = self.nodes(IpFilter::default());
-		let nodes = node_ids.into_iter()
-			.map(|id| self.nodes.get(&id).expect("self.nodes() only returns node IDs from self.nodes"))
+		// nodes found to be ourselves are kept so that they are not dialed again after a restart
+		let nodes = self.nodes.values().filter(|n| n.is_self)
+			.chain(node_ids.into_iter().map(|id| self.nodes.get(&id).expect("self.nodes() only returns node IDs from self.nodes")))
 			.take(MAX_NODES)
-			.map(|node| node.clone())
 			.map(Into::into)
 			.collect();
-		let table = json::NodeTable { nodes };
+		let table = json::NodeTable { version: NODES_FILE_VERSION, nodes: nodes };
+		let data = serde_json::to_vec_pretty(&table).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
 
-		match fs::File::create(&path) {
-			Ok(file) => {
-				if let Err(e) = serde_json::to_writer_pretty(file, &table) {
-					warn!("Error writing node table file: {:?}", e);
-				}
-			},
-			Err(e) => {
-				warn!("Error creating node table file: {:?}", e);
-			}
+		let path = dir.join(NODES_FILE);
+		let temp_path = dir.join(NODES_TEMP_FILE);
+		{
+			let mut file = fs::File::create(&temp_path)?;
+			file.write_all(&data)?;
+			file.sync_all()?;
+		}
+		if path.exists() {
+			fs::rename(&path, dir.join(NODES_BACKUP_FILE))?;
+		}
+		fs::rename(&temp_path, &path)?;
+		// Persist the renames. Directories can't be opened for syncing on all platforms.
+		if let Ok(dir) = fs::File::open(&dir) {
+			let _ = dir.sync_all();
 		}
+		Ok(())
 	}
 
 	fn load(path: Option<String>) -> HashMap<NodeId, Node> {
-		let path = match path {
-			Some(path) => PathBuf::from(path).join(NODES_FILE),
+		let dir = match path {
+			Some(path) => PathBuf::from(path),
 			None => return Default::default(),
 		};
+		let table = NodeTable::load_file(&dir.join(NODES_FILE)).or_else(|| {
+			let backup = NodeTable::load_file(&dir.join(NODES_BACKUP_FILE));
+			if backup.is_some() {
+				info!("Node table restored from backup");
+			}
+			backup
+		});
+		match table {
+			Some(table) => {
+				table.nodes.into_iter()
+					.filter_map(|n| n.into_node())
+					.map(|n| (n.id.clone(), n))
+					.collect()
+			},
+			None => Default::default(),
+		}
+	}
 
-		let file = match fs::File::open(&path) {
+	fn load_file(path: &Path) -> Option<json::NodeTable> {
+		let file = match fs::File::open(path) {
 			Ok(file) => file,
 			Err(e) => {
-				debug!("Error opening node table file: {:?}", e);
-				return Default::default();
+				debug!("Error opening node table file {}: {:?}", path.display(), e);
+				return None;
 			},
 		};
-		let res: Result<json::NodeTable, _> = serde_json::from_reader(file);
+		let res: Result<json::NodeTable, _> = serde_json::from_reader(io::BufReader::new(file));
 		match res {
-			Ok(table) => {
-				table.nodes.into_iter()
-					.filter_map(|n| n.into_node())
-					.map(|n| (n.id.clone(), n))
-					.collect()
+			Ok(ref table) if table.version > NODES_FILE_VERSION => {
+				warn!("Node table file {} has unsupported version {}", path.display(), table.version);
+				None
 			},
+			Ok(table) => Some(table),
 			Err(e) => {
-				warn!("Error reading node table file: {:?}", e);
-				Default::default()
+				warn!("Error reading node table file {}: {:?}", path.display(), e);
+				None
 			},
 		}
 	}
@@ -384,6 +1136,9 @@ mod json {
 
 	#[derive(Serialize, Deserialize)]
 	pub struct NodeTable {
+		/// File format version. Files written before versioning have none.
+		#[serde(default)]
+		pub version: u32,
 		pub nodes: Vec<Node>,
 	}
 
@@ -392,6 +1147,51 @@ mod json {
 		pub url: String,
 		pub attempts: u32,
 		pub failures: u32,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		pub last_failure: Option<u64>,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		pub last_failure_kind: Option<DialFailure>,
+		/// Kept besides `source` for older versions reading the file.
+		#[serde(default)]
+		pub discovered: bool,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		pub source: Option<NodeSource>,
+		#[serde(default)]
+		pub consecutive_failures: u32,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		pub next_attempt: Option<u64>,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		pub resolved_address: Option<String>,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		pub last_contact: Option<u64>,
+		#[serde(default)]
+		pub last_seen: u64,
+		#[serde(default)]
+		pub misbehaviour_score: u32,
+		#[serde(default)]
+		pub misbehaviour_updated: u64,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		pub banned_until: Option<u64>,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		pub quarantined_since: Option<u64>,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		pub quarantined_until: Option<u64>,
+		#[serde(default, skip_serializing_if = "Vec::is_empty")]
+		pub capabilities: Vec<String>,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		pub capabilities_seen: Option<u64>,
+		#[serde(default)]
+		pub is_self: bool,
+	}
+
+	fn parse_capability(s: &str) -> Option<PeerCapabilityInfo> {
+		let mut parts = s.splitn(2, '/');
+		let name = parts.next().map_or(&[][..], |n| n.as_bytes());
+		let version = parts.next().and_then(|v| v.parse().ok());
+		match version {
+			Some(version) if name.len() == 3 => Some(PeerCapabilityInfo { protocol: [name[0], name[1], name[2]], version: version }),
+			_ => None,
+		}
 	}
 
 	impl Node {
@@ -400,6 +1200,27 @@ mod json {
 				Ok(mut node) => {
 					node.attempts = self.attempts;
 					node.failures = self.failures;
+					node.last_failure = self.last_failure;
+					node.last_failure_kind = self.last_failure_kind;
+					node.source = self.source.unwrap_or(if self.discovered { NodeSource::Discovered } else { NodeSource::Manual });
+					node.consecutive_failures = self.consecutive_failures;
+					node.next_attempt = self.next_attempt;
+					node.last_contact = self.last_contact;
+					node.last_seen = self.last_seen;
+					node.misbehaviour_score = self.misbehaviour_score;
+					node.misbehaviour_updated = self.misbehaviour_updated;
+					node.banned_until = self.banned_until;
+					node.quarantined_since = self.quarantined_since;
+					node.quarantined_until = self.quarantined_until;
+					node.capabilities = self.capabilities.iter().filter_map(|c| parse_capability(c)).collect();
+					node.capabilities_seen = self.capabilities_seen;
+					node.is_self = self.is_self;
+					if node.hostname.is_some() {
+						if let Some(address) = self.resolved_address.and_then(|a| a.parse::<SocketAddr>().ok()) {
+							node.endpoint.address = address;
+							node.endpoint.udp_port = address.port();
+						}
+					}
 					Some(node)
 				},
 				_ => None,
@@ -413,6 +1234,23 @@ mod json {
 				url: format!("{}", node),
 				attempts: node.attempts,
 				failures: node.failures,
+				last_failure: node.last_failure,
+				last_failure_kind: node.last_failure_kind,
+				discovered: node.source.is_evictable(),
+				source: Some(node.source),
+				consecutive_failures: node.consecutive_failures,
+				next_attempt: node.next_attempt,
+				resolved_address: node.hostname.as_ref().map(|_| node.endpoint.address.to_string()),
+				last_contact: node.last_contact,
+				last_seen: node.last_seen,
+				misbehaviour_score: node.misbehaviour_score,
+				misbehaviour_updated: node.misbehaviour_updated,
+				banned_until: node.banned_until,
+				quarantined_since: node.quarantined_since,
+				quarantined_until: node.quarantined_until,
+				capabilities: node.capabilities.iter().map(|c| format!("{}/{}", String::from_utf8_lossy(&c.protocol), c.version)).collect(),
+				capabilities_seen: node.capabilities_seen,
+				is_self: node.is_self,
 			}
 		}
 	}
@@ -421,11 +1259,30 @@ mod json {
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use std::io::Read;
 	use std::net::{SocketAddr, SocketAddrV4, Ipv4Addr};
 	use ethereum_types::H512;
 	use std::str::FromStr;
 	use tempdir::TempDir;
 	use ipnetwork::IpNetwork;
+	use parking_lot::Mutex;
+
+	struct TestResolver {
+		responses: Mutex<Vec<io::Result<Vec<SocketAddr>>>>,
+	}
+
+	impl TestResolver {
+		fn new(mut responses: Vec<io::Result<Vec<SocketAddr>>>) -> TestResolver {
+			responses.reverse();
+			TestResolver { responses: Mutex::new(responses) }
+		}
+	}
+
+	impl HostResolver for TestResolver {
+		fn resolve(&self, _host: &str, _port: u16) -> io::Result<Vec<SocketAddr>> {
+			self.responses.lock().pop().expect("Unexpected resolve call")
+		}
+	}
 
 	#[test]
 	fn endpoint_parse() {
@@ -473,12 +1330,12 @@ mod tests {
 
 		// node 1 - failure percentage 100%
 		table.get_mut(&id1).unwrap().attempts = 2;
-		table.note_failure(&id1);
-		table.note_failure(&id1);
+		table.note_failure(&id1, false);
+		table.note_failure(&id1, false);
 
 		// node2 - failure percentage 33%
 		table.get_mut(&id2).unwrap().attempts = 3;
-		table.note_failure(&id2);
+		table.note_failure(&id2, false);
 
 		// node3 - failure percentage 0%
 		table.get_mut(&id3).unwrap().attempts = 1;
@@ -507,7 +1364,7 @@ mod tests {
 
 			table.get_mut(&id1).unwrap().attempts = 1;
 			table.get_mut(&id2).unwrap().attempts = 1;
-			table.note_failure(&id2);
+			table.note_failure(&id2, false);
 		}
 
 		{
@@ -515,9 +1372,292 @@ mod tests {
 			let r = table.nodes(IpFilter::default());
 			assert_eq!(r[0][..], id1[..]);
 			assert_eq!(r[1][..], id2[..]);
+			let node2 = table.get(&id2).unwrap();
+			assert_eq!(node2.consecutive_failures, 1);
+			assert!(node2.next_attempt.is_some());
+		}
+	}
+
+	#[test]
+	fn recently_contacted_save_load() {
+		let tempdir = TempDir::new("").unwrap();
+		let ids: Vec<NodeId> = (1..5).map(H512::from).collect();
+		{
+			let mut table = NodeTable::new(Some(tempdir.path().to_str().unwrap().to_owned()));
+			for (i, id) in ids.iter().enumerate() {
+				table.add_node(Node::new(id.clone(), NodeEndpoint::from_str(&format!("22.99.55.44:{}", 7770 + i)).unwrap()));
+			}
+			table.get_mut(&ids[0]).unwrap().last_contact = Some(100);
+			table.get_mut(&ids[1]).unwrap().last_contact = Some(300);
+			table.get_mut(&ids[2]).unwrap().last_contact = Some(200);
+			assert_eq!(table.recently_contacted(2), vec![ids[1], ids[2]]);
+		}
+
+		{
+			let mut table = NodeTable::new(Some(tempdir.path().to_str().unwrap().to_owned()));
+			assert_eq!(table.recently_contacted(10), vec![ids[1], ids[2], ids[0]]);
+			table.note_contact(&ids[3]);
+			assert_eq!(table.recently_contacted(1), vec![ids[3]]);
+			table.mark_as_useless(&ids[3]);
+			assert_eq!(table.recently_contacted(1), vec![ids[1]]);
+		}
+	}
+
+	#[test]
+	fn dial_failure_kind_save_load() {
+		let tempdir = TempDir::new("").unwrap();
+		let ids: Vec<NodeId> = (1..4).map(H512::from).collect();
+		{
+			let mut table = NodeTable::new(Some(tempdir.path().to_str().unwrap().to_owned()));
+			for (i, id) in ids.iter().enumerate() {
+				table.add_node(Node::new(id.clone(), NodeEndpoint::from_str(&format!("22.99.55.44:{}", 7770 + i)).unwrap()));
+			}
+			table.note_dial_failure(&ids[0], false, DialFailure::Refused);
+			table.note_dial_failure(&ids[1], false, DialFailure::Refused);
+			table.note_dial_failure(&ids[1], false, DialFailure::WrongNodeId);
+			// Re-adding the node keeps the record.
+			table.add_node(Node::new(ids[1].clone(), NodeEndpoint::from_str("22.99.55.44:7771").unwrap()));
+		}
+		let mut data = String::new();
+		fs::File::open(tempdir.path().join(NODES_FILE)).unwrap().read_to_string(&mut data).unwrap();
+		assert!(data.contains("\"last_failure_kind\": \"wrong_node_id\""));
+
+		let table = NodeTable::new(Some(tempdir.path().to_str().unwrap().to_owned()));
+		let now = time::get_time().sec as u64;
+		let candidates = table.dial_candidates(now);
+		assert_eq!(candidates.len(), 3);
+		// Nodes without failures come first.
+		assert_eq!(candidates[0].id, ids[2]);
+		assert!(candidates[0].dialable);
+		assert_eq!(candidates[0].last_failure_kind, None);
+		let failed = candidates.iter().find(|c| c.id == ids[1]).unwrap();
+		assert_eq!(failed.last_failure_kind, Some(DialFailure::WrongNodeId));
+		assert_eq!(failed.consecutive_failures, 2);
+		assert!(failed.last_failure.map_or(false, |t| t <= now));
+		assert!(!failed.dialable);
+		let refused = candidates.iter().find(|c| c.id == ids[0]).unwrap();
+		assert_eq!(refused.last_failure_kind, Some(DialFailure::Refused));
+	}
+
+	#[test]
+	fn interrupted_save_loads_backup() {
+		let tempdir = TempDir::new("").unwrap();
+		let path = tempdir.path().to_str().unwrap().to_owned();
+		let ids: Vec<NodeId> = (1..4).map(H512::from).collect();
+		{
+			let mut table = NodeTable::new(Some(path.clone()));
+			table.add_node(Node::new(ids[0], NodeEndpoint::from_str("22.99.55.44:7770").unwrap()));
+			table.flush().unwrap();
+			table.add_node(Node::new(ids[1], NodeEndpoint::from_str("22.99.55.44:7771").unwrap()));
+			table.flush().unwrap();
+			table.add_node(Node::new(ids[2], NodeEndpoint::from_str("22.99.55.44:7772").unwrap()));
+			table.path = None;
+		}
+		let file = tempdir.path().join(NODES_FILE);
+		let temp = tempdir.path().join(NODES_TEMP_FILE);
+		let backup = tempdir.path().join(NODES_BACKUP_FILE);
+		let mut data = Vec::new();
+		fs::File::open(&file).unwrap().read_to_end(&mut data).unwrap();
+		assert!(fs::metadata(&backup).is_ok());
+
+		// Interrupted after the previous file was moved to the backup, with the new one partially written.
+		fs::rename(&file, &backup).unwrap();
+		fs::File::create(&temp).unwrap().write_all(&data[..data.len() / 2]).unwrap();
+		assert_eq!(NodeTable::load(Some(path.clone())).len(), 2);
+
+		// Truncated file in place.
+		fs::rename(&temp, &file).unwrap();
+		let nodes = NodeTable::load(Some(path.clone()));
+		assert_eq!(nodes.len(), 2);
+		assert!(nodes.contains_key(&ids[1]));
+
+		// Saving replaces the corrupt file.
+		NodeTable::new(Some(path.clone())).save();
+		assert_eq!(NodeTable::load(Some(path.clone())).len(), 2);
+		assert!(fs::metadata(&temp).is_err());
+	}
+
+	#[test]
+	fn node_table_file_version() {
+		let tempdir = TempDir::new("").unwrap();
+		let path = tempdir.path().to_str().unwrap().to_owned();
+		let url = "enode://a979fb575495b8d6db44f750317d0f4622bf4c2aa3365d6af7c284339968eef29b69ad0dce72a4d8db5ebb4968de0e3bec910127f134779fbcb0cb6d3331163c@22.99.55.44:7770";
+		// Files written before versioning are loaded.
+		let unversioned = format!(r#"{{ "nodes": [ {{ "url": "{}", "attempts": 0, "failures": 0 }} ] }}"#, url);
+		fs::File::create(tempdir.path().join(NODES_FILE)).unwrap().write_all(unversioned.as_bytes()).unwrap();
+		assert_eq!(NodeTable::load(Some(path.clone())).len(), 1);
+
+		// Newer versions are not.
+		let newer = format!(r#"{{ "version": {}, "nodes": [] }}"#, NODES_FILE_VERSION + 1);
+		fs::File::create(tempdir.path().join(NODES_FILE)).unwrap().write_all(newer.as_bytes()).unwrap();
+		assert!(NodeTable::load(Some(path.clone())).is_empty());
+	}
+
+	fn discovered(ids: &[NodeId]) -> TableUpdates {
+		TableUpdates {
+			added: ids.iter().enumerate().map(|(i, id)| (id.clone(), NodeEntry {
+				id: id.clone(),
+				endpoint: NodeEndpoint::from_str(&format!("22.99.55.44:{}", 7770 + i)).unwrap(),
+			})).collect(),
+			removed: HashSet::new(),
 		}
 	}
 
+	#[test]
+	fn table_size_cap_eviction() {
+		let ids: Vec<NodeId> = (1..8).map(H512::from).collect();
+		let now = time::get_time().sec as u64;
+		let reserved: HashSet<NodeId> = vec![ids[0]].into_iter().collect();
+		let mut table = NodeTable::new(None);
+		table.set_max_size(5);
+		table.update(discovered(&ids[0..5]), &reserved);
+
+		// 0: reserved, never connected, seen long ago.
+		table.get_mut(&ids[0]).unwrap().last_seen = 0;
+		// 1: recently contacted, seen long ago.
+		table.get_mut(&ids[1]).unwrap().last_seen = 0;
+		table.get_mut(&ids[1]).unwrap().last_contact = Some(now);
+		// 2: contacted long ago.
+		table.get_mut(&ids[2]).unwrap().last_seen = 20;
+		table.get_mut(&ids[2]).unwrap().last_contact = Some(20);
+		// 3 and 4: never connected.
+		table.get_mut(&ids[3]).unwrap().last_seen = 50;
+		table.get_mut(&ids[4]).unwrap().last_seen = 100;
+
+		// Every insert over the cap evicts the worst entry.
+		table.update(discovered(&ids[5..6]), &reserved);
+		assert!(!table.contains(&ids[3]));
+		assert!(table.contains(&ids[4]));
+		table.update(discovered(&ids[6..7]), &reserved);
+		assert!(!table.contains(&ids[4]));
+
+		let survivors: Vec<bool> = ids[0..7].iter().map(|id| table.contains(id)).collect();
+		assert_eq!(survivors, vec![true, true, true, false, false, true, true]);
+	}
+
+	#[test]
+	fn table_size_cap_keeps_manual_nodes() {
+		let ids: Vec<NodeId> = (1..9).map(H512::from).collect();
+		let reserved = HashSet::new();
+		let mut table = NodeTable::new(None);
+		table.set_max_size(4);
+		let mut boot = Node::new(ids[0], NodeEndpoint::from_str("22.99.55.44:7770").unwrap());
+		boot.source = NodeSource::Boot;
+		table.add_node(boot);
+		table.add_node(Node::new(ids[1], NodeEndpoint::from_str("22.99.55.44:7771").unwrap()));
+		let mut incoming = Node::new(ids[2], NodeEndpoint::from_str("22.99.55.44:30303").unwrap());
+		incoming.source = NodeSource::Incoming;
+		table.add_node(incoming);
+		for id in &ids[0..3] {
+			table.get_mut(id).unwrap().last_seen = 0;
+		}
+
+		for i in 3..8 {
+			table.update(discovered(&ids[i..i + 1]), &reserved);
+			if let Some(node) = table.get_mut(&ids[i]) {
+				node.last_seen = i as u64;
+			}
+		}
+		// The boot and the manual node survive, the others make room in order of age.
+		assert_eq!(table.get(&ids[0]).unwrap().source, NodeSource::Boot);
+		assert_eq!(table.get(&ids[1]).unwrap().source, NodeSource::Manual);
+		let survivors: Vec<bool> = ids[0..8].iter().map(|id| table.contains(id)).collect();
+		assert_eq!(survivors, vec![true, true, false, false, false, false, true, true]);
+
+		// Nothing but boot and manual nodes left to evict.
+		let mut table = NodeTable::new(None);
+		table.set_max_size(1);
+		table.add_node(Node::new(ids[0], NodeEndpoint::from_str("22.99.55.44:7770").unwrap()));
+		table.add_node(Node::new(ids[1], NodeEndpoint::from_str("22.99.55.44:7771").unwrap()));
+		table.update(discovered(&ids[2..3]), &reserved);
+		assert!(table.contains(&ids[0]) && table.contains(&ids[1]));
+	}
+
+	#[test]
+	fn node_source_export_save_load() {
+		let tempdir = TempDir::new("").unwrap();
+		let path = tempdir.path().to_str().unwrap().to_owned();
+		let url = |i: u64| format!("enode://{}@22.99.55.44:{}", H512::from(i).hex(), 7770 + i);
+		{
+			let mut table = NodeTable::new(Some(path.clone()));
+			for (i, source) in [NodeSource::Boot, NodeSource::Manual, NodeSource::Discovered, NodeSource::Incoming].iter().enumerate() {
+				let mut node = Node::from_str(&url(i as u64 + 1)).unwrap();
+				node.source = *source;
+				table.add_node(node);
+			}
+			// Incoming nodes are the last dial candidates, until they are dialed successfully.
+			assert_eq!(table.nodes(IpFilter::default()).last(), Some(&H512::from(4)));
+			assert_eq!(table.export(Some(NodeSource::Incoming)), vec![url(4)]);
+			table.note_dialed(&H512::from(4));
+			assert!(table.export(Some(NodeSource::Incoming)).is_empty());
+			let mut discovered = table.export(Some(NodeSource::Discovered));
+			discovered.sort();
+			assert_eq!(discovered, vec![url(3), url(4)]);
+			assert_eq!(table.export(None).len(), 4);
+		}
+
+		{
+			let table = NodeTable::new(Some(path.clone()));
+			assert_eq!(table.export(Some(NodeSource::Boot)), vec![url(1)]);
+			assert_eq!(table.export(Some(NodeSource::Manual)), vec![url(2)]);
+			assert_eq!(table.export(Some(NodeSource::Discovered)).len(), 2);
+		}
+
+		// Files written before sources were recorded only tell discovered nodes apart.
+		let old = format!(r#"{{ "version": 1, "nodes": [
+			{{ "url": "{}", "attempts": 0, "failures": 0 }},
+			{{ "url": "{}", "attempts": 0, "failures": 0, "discovered": true }}
+		] }}"#, url(1), url(2));
+		fs::File::create(tempdir.path().join(NODES_FILE)).unwrap().write_all(old.as_bytes()).unwrap();
+		let table = NodeTable::new(Some(path));
+		assert_eq!(table.get(&H512::from(1)).unwrap().source, NodeSource::Manual);
+		assert_eq!(table.get(&H512::from(2)).unwrap().source, NodeSource::Discovered);
+	}
+
+	#[test]
+	fn table_update_rejects_non_global() {
+		let ids: Vec<NodeId> = (1..6).map(H512::from).collect();
+		let addresses = ["22.99.55.44:7770", "10.0.0.1:7770", "127.0.0.1:7770", "224.0.0.1:7770", "198.18.0.1:7770"];
+		let update = || TableUpdates {
+			added: ids.iter().zip(addresses.iter()).map(|(id, a)| (id.clone(), NodeEntry {
+				id: id.clone(),
+				endpoint: NodeEndpoint::from_str(a).unwrap(),
+			})).collect(),
+			removed: HashSet::new(),
+		};
+
+		let mut table = NodeTable::new(None);
+		table.set_allow_non_global(false);
+		table.update(update(), &HashSet::new());
+		let added: Vec<bool> = ids.iter().map(|id| table.contains(id)).collect();
+		assert_eq!(added, vec![true, false, false, false, false]);
+
+		let mut table = NodeTable::new(None);
+		table.set_allow_non_global(true);
+		table.update(update(), &HashSet::new());
+		let added: Vec<bool> = ids.iter().map(|id| table.contains(id)).collect();
+		assert_eq!(added, vec![true, true, true, false, false]);
+	}
+
+	#[test]
+	fn table_size_cap_shrinks_gradually() {
+		let ids: Vec<NodeId> = (1..11).map(H512::from).collect();
+		let reserved = HashSet::new();
+		let mut table = NodeTable::new(None);
+		table.update(discovered(&ids[0..8]), &reserved);
+		for id in &ids[0..8] {
+			table.get_mut(id).unwrap().last_seen = 0;
+		}
+		table.set_max_size(2);
+		assert!(ids[0..8].iter().all(|id| table.contains(id)));
+		// Lowering the limit does not sweep the table, each insert evicts at most two entries.
+		table.update(discovered(&ids[8..9]), &reserved);
+		assert_eq!(ids.iter().filter(|id| table.contains(id)).count(), 7);
+		table.update(discovered(&ids[9..10]), &reserved);
+		assert_eq!(ids.iter().filter(|id| table.contains(id)).count(), 6);
+		assert!(table.contains(&ids[8]) && table.contains(&ids[9]));
+	}
+
 	#[test]
 	fn custom_allow() {
 		let filter = IpFilter {
@@ -563,4 +1703,403 @@ mod tests {
 		assert!(!NodeEndpoint::from_str("[fc00::]:5550").unwrap().is_allowed(&filter));
 		assert!(NodeEndpoint::from_str("[fd00::]:5550").unwrap().is_allowed(&filter));
 	}
+
+	#[test]
+	fn node_parse_hostname() {
+		let url = "enode://a979fb575495b8d6db44f750317d0f4622bf4c2aa3365d6af7c284339968eef29b69ad0dce72a4d8db5ebb4968de0e3bec910127f134779fbcb0cb6d3331163c@host.example.com:30303";
+		assert!(validate_node_url(url).is_none());
+		let node = Node::from_str(url).unwrap();
+		assert_eq!(node.hostname, Some("host.example.com".to_owned()));
+		assert_eq!(node.endpoint.address.port(), 30303);
+		assert_eq!(format!("{}", node), url);
+
+		assert!(validate_node_url("enode://a979fb575495b8d6db44f750317d0f4622bf4c2aa3365d6af7c284339968eef29b69ad0dce72a4d8db5ebb4968de0e3bec910127f134779fbcb0cb6d3331163c@host_example.com:30303").is_some());
+		assert!(validate_node_url("enode://a979fb575495b8d6db44f750317d0f4622bf4c2aa3365d6af7c284339968eef29b69ad0dce72a4d8db5ebb4968de0e3bec910127f134779fbcb0cb6d3331163c@host.example.com").is_some());
+	}
+
+	#[test]
+	fn hostname_resolution_failure() {
+		let node = Node::from_str("enode://a979fb575495b8d6db44f750317d0f4622bf4c2aa3365d6af7c284339968eef29b69ad0dce72a4d8db5ebb4968de0e3bec910127f134779fbcb0cb6d3331163c@host.example.com:30303").unwrap();
+		let id = node.id.clone();
+		let mut table = NodeTable::new(None);
+		table.add_node(node);
+		let resolver = TestResolver::new(vec![Err(io::Error::new(io::ErrorKind::Other, "no such host"))]);
+		assert!(table.dial_addresses(&id, &resolver).is_empty());
+	}
+
+	#[test]
+	fn hostname_resolution_failure_keeps_last_address() {
+		let node = Node::from_str("enode://a979fb575495b8d6db44f750317d0f4622bf4c2aa3365d6af7c284339968eef29b69ad0dce72a4d8db5ebb4968de0e3bec910127f134779fbcb0cb6d3331163c@host.example.com:30303").unwrap();
+		let id = node.id.clone();
+		let a = SocketAddr::from_str("10.0.0.1:30303").unwrap();
+		let mut table = NodeTable::new(None);
+		table.add_node(node);
+		let resolver = TestResolver::new(vec![Ok(vec![a]), Err(io::Error::new(io::ErrorKind::Other, "no such host")), Ok(vec![])]);
+
+		assert_eq!(table.dial_addresses(&id, &resolver), vec![a]);
+		table.note_resolved(&id, a);
+		assert_eq!(table.dial_addresses(&id, &resolver), vec![a]);
+		assert_eq!(table.dial_addresses(&id, &resolver), vec![a]);
+	}
+
+	#[test]
+	fn hostname_multi_record_fallback() {
+		let node = Node::from_str("enode://a979fb575495b8d6db44f750317d0f4622bf4c2aa3365d6af7c284339968eef29b69ad0dce72a4d8db5ebb4968de0e3bec910127f134779fbcb0cb6d3331163c@host.example.com:30303").unwrap();
+		let id = node.id.clone();
+		let a = SocketAddr::from_str("10.0.0.1:30303").unwrap();
+		let b = SocketAddr::from_str("[fc00::1]:30303").unwrap();
+		let mut table = NodeTable::new(None);
+		table.add_node(node);
+		let resolver = TestResolver::new(vec![Ok(vec![a, b]), Ok(vec![a, b])]);
+
+		assert_eq!(table.dial_addresses(&id, &resolver), vec![a, b]);
+		table.note_resolved(&id, a);
+		table.note_failure(&id, false);
+		assert_eq!(table.dial_addresses(&id, &resolver), vec![b, a]);
+	}
+
+	#[test]
+	fn hostname_address_change() {
+		let node = Node::from_str("enode://a979fb575495b8d6db44f750317d0f4622bf4c2aa3365d6af7c284339968eef29b69ad0dce72a4d8db5ebb4968de0e3bec910127f134779fbcb0cb6d3331163c@host.example.com:30303").unwrap();
+		let id = node.id.clone();
+		let a = SocketAddr::from_str("10.0.0.1:30303").unwrap();
+		let b = SocketAddr::from_str("10.0.0.2:30303").unwrap();
+		let mut table = NodeTable::new(None);
+		table.add_node(node);
+		let resolver = TestResolver::new(vec![Ok(vec![a]), Ok(vec![b])]);
+
+		assert_eq!(table.dial_addresses(&id, &resolver), vec![a]);
+		table.note_resolved(&id, a);
+		assert_eq!(table.dial_addresses(&id, &resolver), vec![b]);
+		table.note_resolved(&id, b);
+		assert_eq!(table.get_mut(&id).unwrap().endpoint.address, b);
+		assert_eq!(table.get_mut(&id).unwrap().hostname, Some("host.example.com".to_owned()));
+	}
+
+	#[test]
+	fn hostname_save_load() {
+		let tempdir = TempDir::new("").unwrap();
+		let node = Node::from_str("enode://a979fb575495b8d6db44f750317d0f4622bf4c2aa3365d6af7c284339968eef29b69ad0dce72a4d8db5ebb4968de0e3bec910127f134779fbcb0cb6d3331163c@host.example.com:30303").unwrap();
+		let id = node.id.clone();
+		let address = SocketAddr::from_str("10.0.0.1:30303").unwrap();
+		{
+			let mut table = NodeTable::new(Some(tempdir.path().to_str().unwrap().to_owned()));
+			table.add_node(node);
+			table.note_resolved(&id, address);
+		}
+
+		{
+			let mut table = NodeTable::new(Some(tempdir.path().to_str().unwrap().to_owned()));
+			let node = table.get_mut(&id).unwrap();
+			assert_eq!(node.hostname, Some("host.example.com".to_owned()));
+			assert_eq!(node.endpoint.address, address);
+		}
+	}
+
+	#[test]
+	fn misbehaviour_score_decays() {
+		let mut table = NodeTable::new(None);
+		table.set_misbehaviour_limits(100, 600);
+		let id = H512::from(1);
+		table.add_node(Node::new(id.clone(), NodeEndpoint::from_str("22.99.55.44:7770").unwrap()));
+		assert_eq!(table.report_misbehaviour(&H512::from(2), 10, 1000), None);
+
+		for i in 1..6 {
+			assert_eq!(table.report_misbehaviour(&id, 10, 1000), Some(i * 10));
+		}
+		assert!(!table.is_blocked(&id, 1000));
+		// 100 points per 600 seconds.
+		assert_eq!(table.misbehaviour_score(&id, 1300), 0);
+		assert_eq!(table.misbehaviour_score(&id, 1060), 40);
+		assert_eq!(table.report_misbehaviour(&id, 60, 1060), Some(100));
+		assert!(table.is_blocked(&id, 1060));
+		assert!(!table.is_blocked(&id, 1066));
+
+		table.ban(&id, 2000);
+		assert!(table.is_blocked(&id, 1999));
+		assert!(!table.is_blocked(&id, 2000));
+		// Disabled scoring never blocks, bans still apply.
+		table.set_misbehaviour_limits(0, 600);
+		table.report_misbehaviour(&id, 1000, 1500);
+		assert!(table.is_blocked(&id, 1999));
+		assert!(!table.is_blocked(&id, 2000));
+	}
+
+	#[test]
+	fn dial_score_order() {
+		let now = time::get_time().sec as u64;
+		let mut table = NodeTable::new(None);
+		for i in 1..7 {
+			let mut node = Node::new(H512::from(i), NodeEndpoint::from_str(&format!("22.99.55.44:{}", 7770 + i)).unwrap());
+			node.source = if i == 2 { NodeSource::Manual } else { NodeSource::Discovered };
+			table.add_node(node);
+		}
+		let set = |table: &mut NodeTable, id: u64, attempts: u32, failures: u32, last_failure: Option<u64>, last_contact: Option<u64>| {
+			let node = table.get_mut(&H512::from(id)).unwrap();
+			node.attempts = attempts;
+			node.failures = failures;
+			node.last_failure = last_failure;
+			node.last_contact = last_contact;
+		};
+		// recently connected.
+		set(&mut table, 1, 5, 1, None, Some(now - 60));
+		// 2 is added by the user, 3 is discovered, neither was tried yet.
+		// failing right now.
+		set(&mut table, 4, 10, 10, Some(now), None);
+		// failed a week ago.
+		set(&mut table, 5, 10, 10, Some(now - 7 * 24 * 60 * 60), None);
+		// connected a month ago.
+		set(&mut table, 6, 2, 0, None, Some(now - 30 * 24 * 60 * 60));
+
+		let order: Vec<NodeId> = [1, 6, 2, 3, 5, 4].iter().map(|i| H512::from(*i)).collect();
+		assert_eq!(table.nodes(IpFilter::default()), order);
+
+		// Scores follow connection outcomes.
+		let id = H512::from(3);
+		let score = table.get(&id).unwrap().dial_score(now);
+		table.get_mut(&id).unwrap().attempts += 1;
+		table.note_failure(&id, false);
+		let failed = table.get(&id).unwrap().dial_score(now);
+		assert!(failed < score);
+		table.note_contact(&id);
+		assert!(table.get(&id).unwrap().dial_score(now) > failed);
+	}
+
+	#[test]
+	fn dial_exploration_share() {
+		use rand::{XorShiftRng, SeedableRng};
+		let nodes: Vec<NodeId> = (0..1000).map(H512::from).collect();
+		let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+		assert_eq!(with_exploration(nodes.clone(), 0, &mut rng), nodes);
+
+		let ordered = with_exploration(nodes.clone(), 20, &mut rng);
+		let mut sorted = ordered.clone();
+		sorted.sort();
+		assert_eq!(sorted, nodes);
+		// Count the picks that skipped the best remaining node.
+		let mut taken = HashSet::new();
+		let mut best = nodes.iter();
+		let mut next_best = best.next();
+		let mut explored = 0;
+		for id in &ordered {
+			if Some(id) != next_best {
+				explored += 1;
+			}
+			taken.insert(*id);
+			while next_best.map_or(false, |b| taken.contains(b)) {
+				next_best = best.next();
+			}
+		}
+		assert!(explored > 100 && explored < 300, "explored {}", explored);
+	}
+
+	/// Draws zeros, so no jitter is applied.
+	struct NoJitter;
+
+	impl Rng for NoJitter {
+		fn next_u32(&mut self) -> u32 {
+			0
+		}
+	}
+
+	#[test]
+	fn dial_backoff_schedule() {
+		use rand::{XorShiftRng, SeedableRng};
+		let mut table = NodeTable::new(None);
+		table.set_dial_backoff(Duration::from_secs(5), Duration::from_secs(60), Duration::from_secs(10));
+		for i in 1..3 {
+			table.add_node(Node::new(H512::from(i), NodeEndpoint::from_str(&format!("22.99.55.44:{}", 7770 + i)).unwrap()));
+		}
+		let id = H512::from(1);
+		let reserved = H512::from(2);
+		let mut now = 1000;
+		let mut delays = Vec::new();
+		for _ in 0..6 {
+			table.note_failure_at(&id, false, now, &mut NoJitter);
+			let next = table.get(&id).unwrap().next_attempt.unwrap();
+			assert!(!table.can_dial(&id, next - 1));
+			assert!(table.can_dial(&id, next));
+			delays.push(next - now);
+			now = next;
+		}
+		assert_eq!(delays, vec![5, 10, 20, 40, 60, 60]);
+
+		// Reserved nodes are retried sooner.
+		for _ in 0..4 {
+			table.note_failure_at(&reserved, true, now, &mut NoJitter);
+		}
+		assert_eq!(table.get(&reserved).unwrap().next_attempt, Some(now + 10));
+
+		// Jitter takes up to half of the delay.
+		let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+		for _ in 0..20 {
+			table.note_failure_at(&id, false, now, &mut rng);
+			let delay = table.get(&id).unwrap().next_attempt.unwrap() - now;
+			assert!(delay >= 30 && delay <= 60, "delay {}", delay);
+		}
+
+		// A successful session resets the backoff.
+		table.note_contact(&id);
+		assert_eq!(table.get(&id).unwrap().consecutive_failures, 0);
+		assert!(table.can_dial(&id, now));
+		table.note_failure_at(&id, false, now, &mut NoJitter);
+		assert_eq!(table.get(&id).unwrap().next_attempt, Some(now + 5));
+	}
+
+	#[test]
+	fn misbehaviour_save_load() {
+		let tempdir = TempDir::new("").unwrap();
+		let id = H512::from(1);
+		{
+			let mut table = NodeTable::new(Some(tempdir.path().to_str().unwrap().to_owned()));
+			table.set_misbehaviour_limits(100, 600);
+			table.add_node(Node::new(id.clone(), NodeEndpoint::from_str("22.99.55.44:7770").unwrap()));
+			table.report_misbehaviour(&id, 70, 1000);
+			table.ban(&id, 5000);
+			// Re-adding the node keeps its record.
+			table.add_node(Node::new(id.clone(), NodeEndpoint::from_str("22.99.55.44:7770").unwrap()));
+		}
+
+		{
+			let mut table = NodeTable::new(Some(tempdir.path().to_str().unwrap().to_owned()));
+			table.set_misbehaviour_limits(100, 600);
+			assert_eq!(table.misbehaviour_score(&id, 1060), 60);
+			assert!(table.is_blocked(&id, 4999));
+		}
+	}
+
+	#[test]
+	fn quarantine_after_repeated_failures() {
+		let mut table = NodeTable::new(None);
+		table.set_dial_backoff(Duration::from_secs(5), Duration::from_secs(60), Duration::from_secs(10));
+		table.set_quarantine(3, Duration::from_secs(3600));
+		let id = H512::from(1);
+		table.add_node(Node::new(id.clone(), NodeEndpoint::from_str("22.99.55.44:7770").unwrap()));
+
+		let mut now = 1000;
+		for _ in 0..2 {
+			table.note_failure_at(&id, false, now, &mut NoJitter);
+			now = table.get(&id).unwrap().next_attempt.unwrap();
+		}
+		assert!(table.quarantined(now).is_empty());
+		table.note_failure_at(&id, false, now, &mut NoJitter);
+		let started = now;
+		assert_eq!(table.quarantined(now), vec![QuarantinedNode { id: id.clone(), since: started, until: started + 3600, consecutive_failures: 3 }]);
+
+		// The quarantine outlasts the dial backoff.
+		assert!(!table.can_dial(&id, started + 60));
+		assert!(!table.can_dial(&id, started + 3599));
+		assert!(table.can_dial(&id, started + 3600));
+		assert!(table.quarantined(started + 3600).is_empty());
+
+		// Failing again after expiry starts a new quarantine.
+		now = started + 3600;
+		table.note_failure_at(&id, false, now, &mut NoJitter);
+		assert_eq!(table.get(&id).unwrap().quarantined_since, Some(now));
+		assert!(!table.can_dial(&id, now + 3599));
+
+		// A successful session, e.g. an inbound connection, lifts it.
+		table.note_contact_at(&id, now + 10);
+		assert!(table.can_dial(&id, now + 10));
+		assert!(table.quarantined(now + 10).is_empty());
+		assert_eq!(table.get(&id).unwrap().consecutive_failures, 0);
+
+		// Reserved nodes are never quarantined.
+		for _ in 0..5 {
+			table.note_failure_at(&id, true, now, &mut NoJitter);
+		}
+		assert!(table.quarantined(now).is_empty());
+	}
+
+	#[test]
+	fn quarantine_save_load() {
+		let tempdir = TempDir::new("").unwrap();
+		let id = H512::from(1);
+		let now = time::get_time().sec as u64;
+		{
+			let mut table = NodeTable::new(Some(tempdir.path().to_str().unwrap().to_owned()));
+			table.set_quarantine(1, Duration::from_secs(3600));
+			table.add_node(Node::new(id.clone(), NodeEndpoint::from_str("22.99.55.44:7770").unwrap()));
+			table.note_failure_at(&id, false, now, &mut NoJitter);
+			// Re-adding the node keeps the quarantine.
+			table.add_node(Node::new(id.clone(), NodeEndpoint::from_str("22.99.55.44:7770").unwrap()));
+		}
+
+		{
+			let table = NodeTable::new(Some(tempdir.path().to_str().unwrap().to_owned()));
+			assert_eq!(table.quarantined(now), vec![QuarantinedNode { id: id.clone(), since: now, until: now + 3600, consecutive_failures: 1 }]);
+			assert!(!table.can_dial(&id, now + 3599));
+		}
+	}
+
+	#[test]
+	fn nodes_supporting_capabilities() {
+		let tempdir = TempDir::new("").unwrap();
+		let now = time::get_time().sec as u64;
+		let cap = |protocol: &[u8; 3], version: u8| PeerCapabilityInfo { protocol: *protocol, version: version };
+		{
+			let mut table = NodeTable::new(Some(tempdir.path().to_str().unwrap().to_owned()));
+			table.set_capability_max_age(Duration::from_secs(3600));
+			for i in 1..6 {
+				table.add_node(Node::new(H512::from(i), NodeEndpoint::from_str(&format!("22.99.55.44:{}", 7770 + i)).unwrap()));
+			}
+			table.note_capabilities(&H512::from(1), vec![cap(b"eth", 62)], now);
+			table.note_capabilities(&H512::from(2), vec![cap(b"eth", 63), cap(b"par", 1)], now);
+			table.note_capabilities(&H512::from(3), vec![cap(b"par", 2)], now);
+			// Stale.
+			table.note_capabilities(&H512::from(4), vec![cap(b"eth", 63)], now - 3601);
+			// Re-adding a node keeps its capabilities.
+			table.add_node(Node::new(H512::from(2), NodeEndpoint::from_str("22.99.55.44:7772").unwrap()));
+			table.get_mut(&H512::from(2)).unwrap().last_contact = Some(now);
+
+			assert_eq!(table.nodes_supporting(*b"eth", 62), vec![H512::from(2), H512::from(1)]);
+			assert_eq!(table.nodes_supporting(*b"eth", 63), vec![H512::from(2)]);
+			assert_eq!(table.nodes_supporting(*b"par", 1).len(), 2);
+			assert!(table.nodes_supporting(*b"les", 1).is_empty());
+		}
+
+		{
+			let mut table = NodeTable::new(Some(tempdir.path().to_str().unwrap().to_owned()));
+			assert_eq!(table.get(&H512::from(2)).unwrap().capabilities, vec![cap(b"eth", 63), cap(b"par", 1)]);
+			assert_eq!(table.get(&H512::from(2)).unwrap().capabilities_seen, Some(now));
+			assert_eq!(table.nodes_supporting(*b"eth", 63), vec![H512::from(2), H512::from(4)]);
+			table.set_capability_max_age(Duration::from_secs(3600));
+			assert_eq!(table.nodes_supporting(*b"eth", 63), vec![H512::from(2)]);
+		}
+	}
+
+	#[test]
+	fn preferred_nodes_come_first() {
+		let nodes: Vec<NodeId> = (1..7).map(H512::from).collect();
+		let preferred: HashSet<NodeId> = [5, 2].iter().map(|i| H512::from(*i)).collect();
+		let order: Vec<NodeId> = [2, 5, 1, 3, 4, 6].iter().map(|i| H512::from(*i)).collect();
+		assert_eq!(prefer_nodes(nodes.clone(), &preferred), order);
+		assert_eq!(prefer_nodes(nodes.clone(), &HashSet::new()), nodes);
+	}
+
+	#[test]
+	fn self_mark_save_load() {
+		let tempdir = TempDir::new("").unwrap();
+		let id = H512::from(1);
+		let now = time::get_time().sec as u64;
+		{
+			let mut table = NodeTable::new(Some(tempdir.path().to_str().unwrap().to_owned()));
+			table.add_node(Node::new(id.clone(), NodeEndpoint::from_str("22.99.55.44:7770").unwrap()));
+			table.add_node(Node::new(H512::from(2), NodeEndpoint::from_str("22.99.55.44:7771").unwrap()));
+			table.mark_as_self(&id);
+			// Re-adding the node at the same address keeps the mark.
+			table.add_node(Node::new(id.clone(), NodeEndpoint::from_str("22.99.55.44:7770").unwrap()));
+			assert_eq!(table.nodes(IpFilter::default()), vec![H512::from(2)]);
+		}
+
+		{
+			let mut table = NodeTable::new(Some(tempdir.path().to_str().unwrap().to_owned()));
+			assert!(table.is_self(&id));
+			assert!(!table.can_dial(&id, now));
+			// A new address may lead to the real node.
+			table.add_node(Node::new(id.clone(), NodeEndpoint::from_str("22.99.55.45:7770").unwrap()));
+			assert!(!table.is_self(&id));
+			assert!(table.can_dial(&id, now));
+		}
+	}
 }
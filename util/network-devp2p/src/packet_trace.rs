@@ -0,0 +1,106 @@
+// Copyright 2015-2017 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Packet tracing hook for debugging sub-protocol exchanges.
+
+use std::sync::Arc;
+use parking_lot::RwLock;
+use ethcore_bytes::Bytes;
+use network::ProtocolId;
+use node_table::NodeId;
+
+/// Direction of a traced packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceDirection {
+	/// Received from the peer, traced after decryption.
+	Inbound,
+	/// Sent to the peer, traced before encryption.
+	Outbound,
+}
+
+/// Packet received or sent by a session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEvent {
+	/// Packet direction.
+	pub direction: TraceDirection,
+	/// Peer node id.
+	pub node_id: NodeId,
+	/// Sub-protocol of the packet. `None` for devp2p packets such as Hello, Ping or Disconnect.
+	pub protocol: Option<ProtocolId>,
+	/// Packet id within the protocol.
+	pub packet_id: u8,
+	/// Size of the uncompressed payload.
+	pub length: usize,
+	/// Start of the uncompressed payload, up to `packet_trace_payload_bytes` long.
+	pub payload: Bytes,
+}
+
+/// Tracer callback. It is called on the IO threads and must not block; heavy tracers should
+/// hand the events over to a thread of their own.
+pub type PacketTracer = Arc<Fn(TraceEvent) + Send + Sync>;
+
+/// Tracer shared by the service and all sessions.
+#[derive(Clone, Default)]
+pub struct PacketTrace {
+	tracer: Arc<RwLock<Option<PacketTracer>>>,
+}
+
+impl PacketTrace {
+	/// Install the tracer, replacing the previous one. `None` disables tracing.
+	pub fn set(&self, tracer: Option<PacketTracer>) {
+		*self.tracer.write() = tracer;
+	}
+
+	/// Pass the event built by `event` to the tracer. `event` is only called if a tracer is installed.
+	pub fn trace<F>(&self, event: F) where F: FnOnce() -> TraceEvent {
+		let tracer = match *self.tracer.read() {
+			Some(ref tracer) => tracer.clone(),
+			None => return,
+		};
+		tracer(event());
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use parking_lot::Mutex;
+
+	fn event(packet_id: u8) -> TraceEvent {
+		TraceEvent {
+			direction: TraceDirection::Outbound,
+			node_id: NodeId::new(),
+			protocol: Some(*b"tst"),
+			packet_id: packet_id,
+			length: 0,
+			payload: Bytes::new(),
+		}
+	}
+
+	#[test]
+	fn events_reach_installed_tracer() {
+		let trace = PacketTrace::default();
+		trace.trace(|| panic!("No tracer installed"));
+
+		let events = Arc::new(Mutex::new(Vec::new()));
+		let recorded = events.clone();
+		trace.clone().set(Some(Arc::new(move |e: TraceEvent| recorded.lock().push(e.packet_id))));
+		trace.trace(|| event(1));
+		trace.set(None);
+		trace.trace(|| event(2));
+		assert_eq!(*events.lock(), vec![1]);
+	}
+}
@@ -0,0 +1,153 @@
+// Copyright 2015-2017 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Random choice of peers to gossip to.
+
+use std::cmp::{min, Ordering, Reverse};
+use std::collections::{BinaryHeap, HashSet};
+use rand::Rng;
+use network::PeerId;
+
+/// Pick up to `count` of `peers` not in `exclude` uniformly at random. Partial Fisher-Yates
+/// shuffle, linear in the number of peers.
+pub fn sample<R: Rng>(mut peers: Vec<PeerId>, count: usize, exclude: &HashSet<PeerId>, rng: &mut R) -> Vec<PeerId> {
+	peers.retain(|peer| !exclude.contains(peer));
+	let count = min(count, peers.len());
+	for i in 0..count {
+		let j = rng.gen_range(i, peers.len());
+		peers.swap(i, j);
+	}
+	peers.truncate(count);
+	peers
+}
+
+/// Sampling key of a peer. Never NaN.
+#[derive(PartialEq, PartialOrd)]
+struct Key(f64, PeerId);
+
+impl Eq for Key {}
+
+impl Ord for Key {
+	fn cmp(&self, other: &Key) -> Ordering {
+		self.partial_cmp(other).unwrap_or(Ordering::Equal)
+	}
+}
+
+/// Pick up to `count` of `peers` not in `exclude` without replacement, each with a chance
+/// proportional to its weight. Peers weighing zero or less are never picked. Each peer gets a
+/// random key `ln(u) / weight` and the `count` largest keys win (Efraimidis-Spirakis), keeping
+/// them in a heap: `O(peers * log(count))`.
+pub fn sample_weighted<R: Rng>(peers: &[(PeerId, f64)], count: usize, exclude: &HashSet<PeerId>, rng: &mut R) -> Vec<PeerId> {
+	if count == 0 {
+		return Vec::new();
+	}
+	let mut best: BinaryHeap<Reverse<Key>> = BinaryHeap::with_capacity(count + 1);
+	for &(peer, weight) in peers {
+		// Also skips NaN weights.
+		if !(weight > 0.0) || exclude.contains(&peer) {
+			continue;
+		}
+		// In (0, 1], so that the logarithm is finite.
+		let u = 1.0 - rng.gen::<f64>();
+		let key = Key(u.ln() / weight, peer);
+		if best.len() < count {
+			best.push(Reverse(key));
+		} else if best.peek().map_or(false, |smallest| key > smallest.0) {
+			best.pop();
+			best.push(Reverse(key));
+		}
+	}
+	best.into_sorted_vec().into_iter().map(|Reverse(key)| key.1).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use rand::{XorShiftRng, SeedableRng};
+
+	const PEERS: usize = 10;
+	const DRAWS: usize = 20_000;
+
+	fn peers() -> Vec<PeerId> {
+		(0..PEERS).collect()
+	}
+
+	#[test]
+	fn sample_excludes_and_clamps() {
+		let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+		let exclude: HashSet<PeerId> = [2, 5, 7].iter().cloned().collect();
+		for _ in 0..100 {
+			let picked = sample(peers(), 4, &exclude, &mut rng);
+			assert_eq!(picked.len(), 4);
+			assert!(picked.iter().all(|peer| !exclude.contains(peer)));
+			assert_eq!(picked.iter().collect::<HashSet<_>>().len(), 4);
+		}
+
+		let mut all = sample(peers(), 100, &exclude, &mut rng);
+		all.sort();
+		assert_eq!(all, vec![0, 1, 3, 4, 6, 8, 9]);
+		assert!(sample(peers(), 0, &exclude, &mut rng).is_empty());
+		assert!(sample(Vec::new(), 3, &exclude, &mut rng).is_empty());
+
+		let weighted: Vec<(PeerId, f64)> = peers().into_iter().map(|peer| (peer, 1.0)).collect();
+		let mut all = sample_weighted(&weighted, 100, &exclude, &mut rng);
+		all.sort();
+		assert_eq!(all, vec![0, 1, 3, 4, 6, 8, 9]);
+		assert!(sample_weighted(&weighted, 0, &exclude, &mut rng).is_empty());
+	}
+
+	#[test]
+	fn sample_is_uniform() {
+		let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+		let mut hits = vec![0; PEERS];
+		for _ in 0..DRAWS {
+			for peer in sample(peers(), 3, &HashSet::new(), &mut rng) {
+				hits[peer] += 1;
+			}
+		}
+		// Each peer is expected in 3 of 10 draws.
+		let expected = DRAWS * 3 / PEERS;
+		for &h in &hits {
+			assert!(h > expected * 9 / 10 && h < expected * 11 / 10, "hits {:?}", hits);
+		}
+	}
+
+	#[test]
+	fn sample_follows_weights() {
+		let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+		// Peer 0 weighs four times the others, peer 1 is never picked.
+		let weighted: Vec<(PeerId, f64)> = peers().into_iter().map(|peer| match peer {
+			0 => (peer, 4.0),
+			1 => (peer, 0.0),
+			_ => (peer, 1.0),
+		}).collect();
+		let mut hits = vec![0; PEERS];
+		for _ in 0..DRAWS {
+			let picked = sample_weighted(&weighted, 1, &HashSet::new(), &mut rng);
+			assert_eq!(picked.len(), 1);
+			hits[picked[0]] += 1;
+		}
+		assert_eq!(hits[1], 0);
+		// 4 of 12 for peer 0, 1 of 12 for the others.
+		assert!(hits[0] > DRAWS * 4 / 12 * 9 / 10 && hits[0] < DRAWS * 4 / 12 * 11 / 10, "hits {:?}", hits);
+		for &h in &hits[2..] {
+			assert!(h > DRAWS / 12 * 8 / 10 && h < DRAWS / 12 * 12 / 10, "hits {:?}", hits);
+		}
+
+		// Zero weights are left out even if that returns fewer peers.
+		assert_eq!(sample_weighted(&weighted, PEERS, &HashSet::new(), &mut rng).len(), PEERS - 1);
+	}
+}
@@ -0,0 +1,132 @@
+// Copyright 2015-2017 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Schedule of the per-peer ticks requested by protocol handlers.
+
+use std::cmp::max;
+use std::collections::HashMap;
+use std::time::Duration;
+use network::{ProtocolId, PeerId};
+
+fn duration_ns(d: Duration) -> u64 {
+	d.as_secs() * 1000_000_000 + d.subsec_nanos() as u64
+}
+
+/// Offset of the first tick of a peer within the interval. Spreads the peers over the interval
+/// by the golden ratio, so that peers connected at once don't tick at once.
+fn stagger(peer: PeerId, interval_ns: u64) -> u64 {
+	let fraction = (peer as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32;
+	let permille = (fraction * 1000) >> 32;
+	interval_ns / 1000 * permille
+}
+
+/// Tick intervals of the protocols and due times of their peers.
+#[derive(Default)]
+pub struct PeerTicks {
+	intervals: HashMap<ProtocolId, u64>,
+	due: HashMap<(ProtocolId, PeerId), u64>,
+}
+
+impl PeerTicks {
+	/// Set the tick interval of the protocol. Returns true if it is the first protocol with ticks.
+	pub fn set_interval(&mut self, protocol: ProtocolId, interval: Duration) -> bool {
+		let first = self.intervals.is_empty();
+		self.intervals.insert(protocol, max(duration_ns(interval), 1));
+		first
+	}
+
+	/// Stop the ticks of a protocol.
+	pub fn remove_protocol(&mut self, protocol: ProtocolId) {
+		self.intervals.remove(&protocol);
+		self.due.retain(|&(p, _), _| p != protocol);
+	}
+
+	/// Start ticking a peer that has connected with the protocol, if the protocol has ticks.
+	pub fn add_peer(&mut self, protocol: ProtocolId, peer: PeerId, now_ns: u64) {
+		if let Some(interval) = self.intervals.get(&protocol).cloned() {
+			self.due.insert((protocol, peer), now_ns + stagger(peer, interval));
+		}
+	}
+
+	/// Stop ticking a disconnected peer.
+	pub fn remove_peer(&mut self, peer: PeerId) {
+		self.due.retain(|&(_, p), _| p != peer);
+	}
+
+	/// Take the ticks due at `now_ns`, in order of due time, and schedule the next ones.
+	/// Ticks missed meanwhile are skipped.
+	pub fn take_due(&mut self, now_ns: u64) -> Vec<(ProtocolId, PeerId)> {
+		let mut due: Vec<(u64, ProtocolId, PeerId)> = Vec::new();
+		for (&(protocol, peer), at) in self.due.iter_mut() {
+			if *at > now_ns {
+				continue;
+			}
+			due.push((*at, protocol, peer));
+			let interval = self.intervals[&protocol];
+			*at += interval;
+			if *at <= now_ns {
+				*at = now_ns + interval;
+			}
+		}
+		due.sort();
+		due.into_iter().map(|(_, protocol, peer)| (protocol, peer)).collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const MS: u64 = 1000_000;
+
+	#[test]
+	fn peers_tick_staggered() {
+		let mut ticks = PeerTicks::default();
+		assert!(ticks.set_interval(*b"tst", Duration::from_millis(100)));
+		assert!(!ticks.set_interval(*b"aaa", Duration::from_millis(100)));
+		// The peers connect at once. The protocol of the third one has no ticks.
+		ticks.add_peer(*b"tst", 0, 0);
+		ticks.add_peer(*b"tst", 1, 0);
+		ticks.add_peer(*b"bbb", 2, 0);
+
+		let mut delivered = Vec::new();
+		for now in 0..300 {
+			for (_, peer) in ticks.take_due(now * MS) {
+				delivered.push((now, peer));
+			}
+		}
+		assert_eq!(delivered, vec![(24, 1), (62, 0), (124, 1), (162, 0), (224, 1), (262, 0)]);
+
+		// Ticks missed while busy are not delivered all at once.
+		assert_eq!(ticks.take_due(1000 * MS).len(), 2);
+		assert!(ticks.take_due(1050 * MS).is_empty());
+		assert_eq!(ticks.take_due(1100 * MS).len(), 2);
+	}
+
+	#[test]
+	fn removed_peers_stop_ticking() {
+		let mut ticks = PeerTicks::default();
+		ticks.set_interval(*b"tst", Duration::from_millis(100));
+		ticks.set_interval(*b"aaa", Duration::from_millis(100));
+		ticks.add_peer(*b"tst", 0, 0);
+		ticks.add_peer(*b"aaa", 0, 0);
+		ticks.add_peer(*b"tst", 1, 0);
+		ticks.remove_peer(0);
+		assert_eq!(ticks.take_due(100 * MS), vec![(*b"tst", 1)]);
+		ticks.remove_protocol(*b"tst");
+		assert!(ticks.take_due(1000 * MS).is_empty());
+	}
+}
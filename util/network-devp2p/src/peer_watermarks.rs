@@ -0,0 +1,146 @@
+// Copyright 2015-2017 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Low peer count alerts.
+
+use std::cmp::max;
+use std::time::Duration;
+
+/// Peer count transition reported to the callback registered with `NetworkService::on_peer_count_change`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerCountEvent {
+	/// The number of peers has stayed under the low watermark for the grace period.
+	BelowLow {
+		/// Current number of connected peers.
+		peers: usize,
+	},
+	/// The number of peers has reached the high watermark after a `BelowLow` event.
+	Recovered {
+		/// Current number of connected peers.
+		peers: usize,
+	},
+}
+
+/// Tracks the peer count against the low and high watermarks. Disabled while the low watermark is zero.
+pub struct PeerWatermarks {
+	low: usize,
+	high: usize,
+	grace_ns: u64,
+	/// Time the count dropped under the low watermark.
+	below_since: Option<u64>,
+	/// Set after `BelowLow` was raised, until the count recovers.
+	alerted: bool,
+}
+
+fn duration_ns(d: Duration) -> u64 {
+	d.as_secs() * 1000_000_000 + d.subsec_nanos() as u64
+}
+
+impl PeerWatermarks {
+	/// Create a tracker with both watermarks unset.
+	pub fn new(grace: Duration) -> PeerWatermarks {
+		PeerWatermarks {
+			low: 0,
+			high: 0,
+			grace_ns: duration_ns(grace),
+			below_since: None,
+			alerted: false,
+		}
+	}
+
+	/// Set the watermarks. `high` is raised to `low` if it is lower. Resets the tracking state.
+	pub fn set(&mut self, low: usize, high: usize) {
+		self.low = low;
+		self.high = max(low, high);
+		self.below_since = None;
+		self.alerted = false;
+	}
+
+	/// Current low and high watermarks.
+	pub fn watermarks(&self) -> (usize, usize) {
+		(self.low, self.high)
+	}
+
+	/// Update with the current peer count. Returns an event if the state has changed.
+	pub fn update(&mut self, peers: usize, now_ns: u64) -> Option<PeerCountEvent> {
+		if self.low == 0 {
+			return None;
+		}
+		if self.alerted {
+			if peers >= self.high {
+				self.alerted = false;
+				self.below_since = None;
+				return Some(PeerCountEvent::Recovered { peers: peers });
+			}
+			return None;
+		}
+		if peers >= self.low {
+			self.below_since = None;
+			return None;
+		}
+		let since = *self.below_since.get_or_insert(now_ns);
+		if now_ns >= since + self.grace_ns {
+			self.alerted = true;
+			return Some(PeerCountEvent::BelowLow { peers: peers });
+		}
+		None
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const SEC: u64 = 1000_000_000;
+
+	#[test]
+	fn one_event_per_transition() {
+		let mut marks = PeerWatermarks::new(Duration::from_secs(10));
+		marks.set(5, 8);
+		assert_eq!(marks.update(2, 0), None);
+		assert_eq!(marks.update(2, 5 * SEC), None);
+		assert_eq!(marks.update(3, 10 * SEC), Some(PeerCountEvent::BelowLow { peers: 3 }));
+		assert_eq!(marks.update(1, 11 * SEC), None);
+		// Between the watermarks the alert stands.
+		assert_eq!(marks.update(6, 12 * SEC), None);
+		assert_eq!(marks.update(8, 13 * SEC), Some(PeerCountEvent::Recovered { peers: 8 }));
+		assert_eq!(marks.update(9, 14 * SEC), None);
+		assert_eq!(marks.update(4, 15 * SEC), None);
+		assert_eq!(marks.update(4, 25 * SEC), Some(PeerCountEvent::BelowLow { peers: 4 }));
+	}
+
+	#[test]
+	fn short_dips_are_ignored() {
+		let mut marks = PeerWatermarks::new(Duration::from_secs(10));
+		marks.set(5, 8);
+		assert_eq!(marks.update(2, 0), None);
+		assert_eq!(marks.update(5, 9 * SEC), None);
+		// The grace period starts over.
+		assert_eq!(marks.update(2, 10 * SEC), None);
+		assert_eq!(marks.update(2, 19 * SEC), None);
+		assert_eq!(marks.update(2, 20 * SEC), Some(PeerCountEvent::BelowLow { peers: 2 }));
+	}
+
+	#[test]
+	fn disabled_without_low_watermark() {
+		let mut marks = PeerWatermarks::new(Duration::from_secs(0));
+		assert_eq!(marks.update(0, 0), None);
+		marks.set(2, 1);
+		assert_eq!(marks.watermarks(), (2, 2));
+		assert_eq!(marks.update(0, 0), Some(PeerCountEvent::BelowLow { peers: 0 }));
+		assert_eq!(marks.update(2, 0), Some(PeerCountEvent::Recovered { peers: 2 }));
+	}
+}
@@ -0,0 +1,154 @@
+// Copyright 2015-2017 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Per-peer inbound traffic limiting.
+
+use std::cmp::min;
+use network::RateLimit;
+
+const NS_PER_SEC: u64 = 1000_000_000;
+
+/// Result of accounting a received packet.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RateLimitStatus {
+	/// Packet is within limits.
+	Allowed,
+	/// Peer is over the limit. Reading should be paused for the given number of nanoseconds.
+	Throttle(u64),
+	/// Peer has exceeded the hard limit.
+	Exceeded,
+}
+
+/// Token bucket refilled at a constant rate. Holds at most one second worth of tokens
+/// and may go into debt up to `hard_limit_secs` worth of tokens.
+/// Token amounts are kept scaled by `NS_PER_SEC` to avoid rounding on refill.
+#[derive(Debug)]
+struct TokenBucket {
+	rate: u64,
+	max_debt_secs: u64,
+	tokens: i64,
+	last_refill_ns: u64,
+}
+
+impl TokenBucket {
+	fn new(rate: u32, max_debt_secs: u32, now_ns: u64) -> TokenBucket {
+		TokenBucket {
+			rate: rate as u64,
+			max_debt_secs: max_debt_secs as u64,
+			tokens: (rate as u64 * NS_PER_SEC) as i64,
+			last_refill_ns: now_ns,
+		}
+	}
+
+	fn consume(&mut self, amount: u64, now_ns: u64) -> RateLimitStatus {
+		if self.rate == 0 {
+			return RateLimitStatus::Allowed;
+		}
+		let capacity = (self.rate * NS_PER_SEC) as i64;
+		// Refilling for longer than the full debt + burst window has no further effect.
+		let elapsed = min(now_ns.saturating_sub(self.last_refill_ns), (self.max_debt_secs + 1) * NS_PER_SEC);
+		self.last_refill_ns = now_ns;
+		self.tokens = min(capacity, self.tokens + (elapsed * self.rate) as i64);
+		self.tokens -= (amount * NS_PER_SEC) as i64;
+		if self.tokens >= 0 {
+			RateLimitStatus::Allowed
+		} else if self.tokens < -((self.max_debt_secs * self.rate * NS_PER_SEC) as i64) {
+			RateLimitStatus::Exceeded
+		} else {
+			// Time needed to pay the debt back.
+			RateLimitStatus::Throttle((-self.tokens) as u64 / self.rate)
+		}
+	}
+}
+
+/// Limits packets and bytes received from a single peer.
+#[derive(Debug)]
+pub struct PeerRateLimiter {
+	packets: TokenBucket,
+	bytes: TokenBucket,
+}
+
+impl PeerRateLimiter {
+	/// Create a new limiter with full buckets.
+	pub fn new(limit: &RateLimit, now_ns: u64) -> PeerRateLimiter {
+		PeerRateLimiter {
+			packets: TokenBucket::new(limit.packets_per_sec, limit.hard_limit_secs, now_ns),
+			bytes: TokenBucket::new(limit.bytes_per_sec, limit.hard_limit_secs, now_ns),
+		}
+	}
+
+	/// Account a received packet of `size` bytes.
+	pub fn on_packet(&mut self, size: usize, now_ns: u64) -> RateLimitStatus {
+		let packets = self.packets.consume(1, now_ns);
+		let bytes = self.bytes.consume(size as u64, now_ns);
+		match (packets, bytes) {
+			(RateLimitStatus::Exceeded, _) | (_, RateLimitStatus::Exceeded) => RateLimitStatus::Exceeded,
+			(RateLimitStatus::Throttle(a), RateLimitStatus::Throttle(b)) => RateLimitStatus::Throttle(::std::cmp::max(a, b)),
+			(RateLimitStatus::Throttle(a), _) | (_, RateLimitStatus::Throttle(a)) => RateLimitStatus::Throttle(a),
+			_ => RateLimitStatus::Allowed,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use network::RateLimit;
+
+	fn limit(packets_per_sec: u32, bytes_per_sec: u32) -> RateLimit {
+		RateLimit { packets_per_sec: packets_per_sec, bytes_per_sec: bytes_per_sec, hard_limit_secs: 2 }
+	}
+
+	#[test]
+	fn allows_traffic_within_limits() {
+		let mut limiter = PeerRateLimiter::new(&limit(10, 1000), 0);
+		for i in 0..100 {
+			// 5 packets per second, 50 bytes each
+			assert_eq!(limiter.on_packet(50, i * NS_PER_SEC / 5), RateLimitStatus::Allowed);
+		}
+	}
+
+	#[test]
+	fn throttles_then_exceeds_packet_rate() {
+		let mut limiter = PeerRateLimiter::new(&limit(10, 0), 0);
+		for _ in 0..10 {
+			assert_eq!(limiter.on_packet(1, 0), RateLimitStatus::Allowed);
+		}
+		assert_eq!(limiter.on_packet(1, 0), RateLimitStatus::Throttle(NS_PER_SEC / 10));
+		for _ in 0..19 {
+			match limiter.on_packet(1, 0) {
+				RateLimitStatus::Throttle(_) => {},
+				s => panic!("Unexpected status {:?}", s),
+			}
+		}
+		assert_eq!(limiter.on_packet(1, 0), RateLimitStatus::Exceeded);
+	}
+
+	#[test]
+	fn recovers_after_pause() {
+		let mut limiter = PeerRateLimiter::new(&limit(0, 1000), 0);
+		assert_eq!(limiter.on_packet(1500, 0), RateLimitStatus::Throttle(NS_PER_SEC / 2));
+		assert_eq!(limiter.on_packet(100, NS_PER_SEC), RateLimitStatus::Allowed);
+	}
+
+	#[test]
+	fn zero_rate_is_unlimited() {
+		let mut limiter = PeerRateLimiter::new(&limit(0, 0), 0);
+		for _ in 0..10000 {
+			assert_eq!(limiter.on_packet(1 << 20, 0), RateLimitStatus::Allowed);
+		}
+	}
+}
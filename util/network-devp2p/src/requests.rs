@@ -0,0 +1,159 @@
+// Copyright 2015-2017 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Requests sent with `NetworkContext::send_request` awaiting a response.
+
+use std::collections::{HashMap, HashSet};
+use network::{ProtocolId, PeerId, PacketId, RequestHandle, RequestFrame, decode_request_frame};
+
+/// What to do with a packet received from a peer.
+#[derive(Debug, PartialEq)]
+pub enum PacketRoute {
+	/// Not a response, deliver it to `read`.
+	Read,
+	/// Response to a pending request.
+	Response(RequestHandle, Vec<u8>),
+	/// Response to a request that is no longer pending.
+	Drop,
+}
+
+/// Pending requests by peer and protocol.
+#[derive(Default)]
+pub struct PendingRequests {
+	next_id: u64,
+	/// Requests and their deadlines by request id.
+	pending: HashMap<(PeerId, ProtocolId), HashMap<u64, (RequestHandle, u64)>>,
+	/// Packet ids used for requests. Responses in these are never read as plain packets.
+	request_packets: HashSet<(ProtocolId, PacketId)>,
+}
+
+impl PendingRequests {
+	/// Add a request to the peer, answered by `deadline_ns` at the latest.
+	pub fn add(&mut self, peer: PeerId, protocol: ProtocolId, packet_id: PacketId, deadline_ns: u64) -> RequestHandle {
+		let handle = RequestHandle { peer: peer, packet_id: packet_id, id: self.next_id };
+		self.next_id += 1;
+		self.request_packets.insert((protocol, packet_id));
+		self.pending.entry((peer, protocol)).or_insert_with(HashMap::new).insert(handle.id, (handle, deadline_ns));
+		handle
+	}
+
+	/// Forget a request that could not be sent.
+	pub fn cancel(&mut self, protocol: ProtocolId, handle: &RequestHandle) {
+		self.take(handle.peer, protocol, handle.id);
+	}
+
+	fn take(&mut self, peer: PeerId, protocol: ProtocolId, id: u64) -> Option<RequestHandle> {
+		let key = (peer, protocol);
+		let (handle, empty) = match self.pending.get_mut(&key) {
+			Some(requests) => (requests.remove(&id).map(|(handle, _)| handle), requests.is_empty()),
+			None => return None,
+		};
+		if empty {
+			self.pending.remove(&key);
+		}
+		handle
+	}
+
+	/// Match a packet received from the peer against the pending requests.
+	pub fn route(&mut self, peer: PeerId, protocol: ProtocolId, packet_id: PacketId, data: &[u8]) -> PacketRoute {
+		if !self.request_packets.contains(&(protocol, packet_id)) {
+			return PacketRoute::Read;
+		}
+		let (id, payload) = match decode_request_frame(data) {
+			Ok(RequestFrame::Response(id, payload)) => (id, payload),
+			_ => return PacketRoute::Read,
+		};
+		// A response in another packet than the request leaves it pending.
+		let pending = self.pending.get(&(peer, protocol)).and_then(|requests| requests.get(&id))
+			.map_or(false, |&(ref handle, _)| handle.packet_id == packet_id);
+		if !pending {
+			return PacketRoute::Drop;
+		}
+		match self.take(peer, protocol, id) {
+			Some(handle) => PacketRoute::Response(handle, payload),
+			None => PacketRoute::Drop,
+		}
+	}
+
+	/// Take the requests not answered by `now_ns`, in order of deadline.
+	pub fn take_expired(&mut self, now_ns: u64) -> Vec<(ProtocolId, RequestHandle)> {
+		let mut expired = Vec::new();
+		for (&(_, protocol), requests) in self.pending.iter_mut() {
+			let ids: Vec<u64> = requests.iter().filter(|&(_, &(_, deadline))| deadline <= now_ns).map(|(id, _)| *id).collect();
+			for id in ids {
+				if let Some((handle, deadline)) = requests.remove(&id) {
+					expired.push((deadline, protocol, handle));
+				}
+			}
+		}
+		self.pending.retain(|_, requests| !requests.is_empty());
+		expired.sort_by_key(|&(deadline, _, ref handle)| (deadline, handle.id));
+		expired.into_iter().map(|(_, protocol, handle)| (protocol, handle)).collect()
+	}
+
+	/// Take the requests to a disconnected peer, in order sent.
+	pub fn take_peer(&mut self, peer: PeerId) -> Vec<(ProtocolId, RequestHandle)> {
+		let keys: Vec<(PeerId, ProtocolId)> = self.pending.keys().filter(|&&(p, _)| p == peer).cloned().collect();
+		let mut taken = Vec::new();
+		for key in keys {
+			if let Some(requests) = self.pending.remove(&key) {
+				taken.extend(requests.into_iter().map(|(_, (handle, _))| (key.1, handle)));
+			}
+		}
+		taken.sort_by_key(|&(_, ref handle)| handle.id);
+		taken
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use network::{encode_request, encode_response};
+
+	const MS: u64 = 1000_000;
+
+	#[test]
+	fn responses_are_matched_to_requests() {
+		let mut requests = PendingRequests::default();
+		let first = requests.add(1, *b"tst", 0x10, 100 * MS);
+		let second = requests.add(1, *b"tst", 0x10, 100 * MS);
+
+		// Requests and other packets are read as usual.
+		assert_eq!(requests.route(1, *b"tst", 0x10, &encode_request(first.id, b"req")), PacketRoute::Read);
+		assert_eq!(requests.route(1, *b"tst", 0x11, &encode_response(first.id, b"res")), PacketRoute::Read);
+		assert_eq!(requests.route(1, *b"tst", 0x10, b"raw"), PacketRoute::Read);
+
+		// Responses of other peers and protocols don't match.
+		assert_eq!(requests.route(2, *b"tst", 0x10, &encode_response(first.id, b"res")), PacketRoute::Drop);
+		assert_eq!(requests.route(1, *b"tst", 0x10, &encode_response(second.id, b"res")), PacketRoute::Response(second, b"res".to_vec()));
+		// A response is delivered once.
+		assert_eq!(requests.route(1, *b"tst", 0x10, &encode_response(second.id, b"res")), PacketRoute::Drop);
+		assert_eq!(requests.take_peer(1), vec![(*b"tst", first)]);
+		assert_eq!(requests.route(1, *b"tst", 0x10, &encode_response(first.id, b"res")), PacketRoute::Drop);
+	}
+
+	#[test]
+	fn requests_expire_in_order() {
+		let mut requests = PendingRequests::default();
+		let late = requests.add(1, *b"tst", 0x10, 300 * MS);
+		let early = requests.add(2, *b"tst", 0x10, 100 * MS);
+		let other = requests.add(1, *b"aaa", 0x01, 200 * MS);
+		assert!(requests.take_expired(50 * MS).is_empty());
+		assert_eq!(requests.take_expired(200 * MS), vec![(*b"tst", early), (*b"aaa", other)]);
+		assert_eq!(requests.take_expired(1000 * MS), vec![(*b"tst", late)]);
+		assert!(requests.take_peer(1).is_empty());
+	}
+}
@@ -14,76 +14,150 @@
 // You should have received a copy of the GNU General Public License
 // along with Parity.  If not, see <http://www.gnu.org/licenses/>.
 
-use network::{Error, NetworkConfiguration, NetworkProtocolHandler, NonReservedPeerMode};
+use network::{Error, ErrorKind, NetworkConfiguration, NetworkProtocolHandler, NonReservedPeerMode};
 use network::{NetworkContext, PeerId, ProtocolId, NetworkIoMessage};
-use host::Host;
+use host::{Host, PeerInfo, PeerCountCallback, configured_enode, validate_client_version};
+use peer_watermarks::PeerCountEvent;
+use events::{EventSubscribers, EventReceiver};
+use session::MAX_PACKET_COUNT;
+use node_table::{Node, NodeId, NodeSource, QuarantinedNode, DialCandidate, HostResolver};
+use dial::DialResult;
+use packet_trace::{PacketTrace, PacketTracer, TraceEvent};
+use discovery::{DiscoveryStats, NearNode};
+use status_report::{NetworkStatusReport, DiscoveryStatusReport};
+use ip_utils::AddressSource;
 use stats::NetworkStats;
 use io::*;
 use parking_lot::RwLock;
+use ethereum_types::H256;
+use std::net::SocketAddr;
+use std::str::FromStr;
 use std::sync::Arc;
-use ansi_term::Colour;
-use connection_filter::ConnectionFilter;
-
-struct HostHandler {
-	public_url: RwLock<Option<String>>
-}
-
-impl IoHandler<NetworkIoMessage> for HostHandler {
-	fn message(&self, _io: &IoContext<NetworkIoMessage>, message: &NetworkIoMessage) {
-		if let NetworkIoMessage::NetworkStarted(ref public_url) = *message {
-			let mut url = self.public_url.write();
-			if url.as_ref().map_or(true, |uref| uref != public_url) {
-				info!(target: "network", "Public node URL: {}", Colour::White.bold().paint(public_url.as_ref()));
-			}
-			*url = Some(public_url.to_owned());
-		}
-	}
-}
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::mpsc::{self, Receiver};
+use std::time::Duration;
+use connection_filter::{ConnectionFilter, FilterStats};
 
 /// IO Service with networking
 /// `Message` defines a notification data type.
 pub struct NetworkService {
-	io_service: IoService<NetworkIoMessage>,
-	host_info: String,
+	io_service: Arc<IoService<NetworkIoMessage>>,
+	/// Client identifier set by `set_client_version`, replacing the configured one.
+	client_version: RwLock<Option<String>>,
 	host: RwLock<Option<Arc<Host>>>,
+	/// Id of the host's IO handler, valid while the host is running.
+	handler_id: AtomicUsize,
 	stats: Arc<NetworkStats>,
-	host_handler: Arc<HostHandler>,
 	config: NetworkConfiguration,
-	filter: Option<Arc<ConnectionFilter>>,
+	filter: RwLock<Option<Arc<ConnectionFilter>>>,
+	peer_watermarks: RwLock<(usize, usize)>,
+	peer_count_callback: RwLock<Option<PeerCountCallback>>,
+	events: Arc<EventSubscribers>,
+	packet_trace: PacketTrace,
+	resolver: RwLock<Option<Arc<HostResolver>>>,
+	/// Network id and boot nodes set by `switch_network`, replacing the configured ones.
+	network: RwLock<Option<(u64, Vec<String>)>>,
 }
 
 impl NetworkService {
 	/// Starts IO event loop
 	pub fn new(config: NetworkConfiguration, filter: Option<Arc<ConnectionFilter>>) -> Result<NetworkService, Error> {
-		let host_handler = Arc::new(HostHandler { public_url: RwLock::new(None) });
 		let io_service = IoService::<NetworkIoMessage>::start()?;
+		Ok(NetworkService::new_with_io(config, filter, Arc::new(io_service)))
+	}
 
+	/// Create a service that runs on an existing IO event loop instead of starting its own.
+	/// The host is registered as a handler of `io_service` when the service is started, and `stop`
+	/// only removes it again: the event loop is left running and is only shut down once the last
+	/// reference to it is dropped. Each service takes one of the event loop's handler slots while running.
+	pub fn new_with_io(config: NetworkConfiguration, filter: Option<Arc<ConnectionFilter>>, io_service: Arc<IoService<NetworkIoMessage>>) -> NetworkService {
 		let stats = Arc::new(NetworkStats::new());
-		Ok(NetworkService {
+		NetworkService {
 			io_service: io_service,
-			host_info: config.client_version.clone(),
+			client_version: RwLock::new(None),
 			stats: stats,
 			host: RwLock::new(None),
+			handler_id: AtomicUsize::new(0),
 			config: config,
-			host_handler: host_handler,
-			filter: filter,
-		})
+			filter: RwLock::new(filter),
+			peer_watermarks: RwLock::new((0, 0)),
+			peer_count_callback: RwLock::new(None),
+			events: Arc::new(EventSubscribers::new()),
+			packet_trace: PacketTrace::default(),
+			resolver: RwLock::new(None),
+			network: RwLock::new(None),
+		}
 	}
 
-	/// Regiter a new protocol handler with the event loop.
+	/// IO context of the host handler, for calls made outside of the event loop.
+	fn io_context(&self) -> IoContext<NetworkIoMessage> {
+		IoContext::new(self.io_service.channel(), self.handler_id.load(AtomicOrdering::Acquire))
+	}
+
+	/// Regiter a new protocol handler with the event loop. All versions reserve `packet_count` packet ids.
 	pub fn register_protocol(&self, handler: Arc<NetworkProtocolHandler + Send + Sync>, protocol: ProtocolId, packet_count: u8, versions: &[u8]) -> Result<(), Error> {
-		self.io_service.send_message(NetworkIoMessage::AddHandler {
-			handler: handler,
-			protocol: protocol,
-			versions: versions.to_vec(),
-			packet_count: packet_count,
-		})?;
+		let versions: Vec<(u8, u8)> = versions.iter().map(|v| (*v, packet_count)).collect();
+		self.register_protocol_versions(handler, protocol, &versions)
+	}
+
+	/// Register a new protocol handler with the number of packet ids reserved by each version.
+	/// Fails with `InvalidPacketCount` if a version reserves no packet ids or more than fit in the packet id space.
+	pub fn register_protocol_versions(&self, handler: Arc<NetworkProtocolHandler + Send + Sync>, protocol: ProtocolId, versions: &[(u8, u8)]) -> Result<(), Error> {
+		self.add_handler(handler, protocol, versions, None)
+	}
+
+	/// Register a new protocol handler like `register_protocol_versions`, with its `peer_tick`
+	/// called for each connected peer every `peer_tick` interval.
+	pub fn register_protocol_with_peer_tick(&self, handler: Arc<NetworkProtocolHandler + Send + Sync>, protocol: ProtocolId, versions: &[(u8, u8)], peer_tick: Duration) -> Result<(), Error> {
+		self.add_handler(handler, protocol, versions, Some(peer_tick))
+	}
+
+	fn add_handler(&self, handler: Arc<NetworkProtocolHandler + Send + Sync>, protocol: ProtocolId, versions: &[(u8, u8)], peer_tick: Option<Duration>) -> Result<(), Error> {
+		if let Some(&(version, count)) = versions.iter().find(|&&(_, count)| count == 0 || count > MAX_PACKET_COUNT) {
+			bail!(ErrorKind::InvalidPacketCount(version, count));
+		}
+		if self.host.read().is_some() {
+			self.io_context().message_self(NetworkIoMessage::AddHandler {
+				handler: handler,
+				protocol: protocol,
+				versions: versions.to_vec(),
+				peer_tick: peer_tick,
+			})?;
+		}
+		Ok(())
+	}
+
+	/// Remove a protocol handler registered with `register_protocol`. The handler receives no further
+	/// packets or timer events and the protocol is no longer advertised to new peers.
+	/// Peers that have no other protocol in common are disconnected.
+	pub fn deregister_protocol(&self, protocol: ProtocolId) -> Result<(), Error> {
+		if self.host.read().is_some() {
+			self.io_context().message_self(NetworkIoMessage::RemoveHandler {
+				protocol: protocol,
+			})?;
+		}
 		Ok(())
 	}
 
 	/// Returns host identifier string as advertised to other peers
 	pub fn host_info(&self) -> String {
-		self.host_info.clone()
+		if let Some(ref host) = *self.host.read() {
+			return host.client_version();
+		}
+		self.client_version.read().clone()
+			.or_else(|| self.config.client_version_override.clone())
+			.unwrap_or_else(|| self.config.client_version.clone())
+	}
+
+	/// Change the client identifier sent in the Hello packet. Only sessions established afterwards see the
+	/// new one. Fails with `InvalidClientVersion` unless it is printable ASCII of at most 256 bytes.
+	pub fn set_client_version(&self, version: String) -> Result<(), Error> {
+		validate_client_version(&version)?;
+		*self.client_version.write() = Some(version.clone());
+		if let Some(ref host) = *self.host.read() {
+			host.set_client_version(version);
+		}
+		Ok(())
 	}
 
 	/// Returns underlying io service.
@@ -113,38 +187,296 @@ impl NetworkService {
 		host.as_ref().map(|h| h.local_url())
 	}
 
+	/// Returns the address the main listener is bound to. `None` before the service is started.
+	pub fn local_addr(&self) -> Option<SocketAddr> {
+		self.host.read().as_ref().map(|h| h.local_addr())
+	}
+
 	/// Start network IO
 	pub fn start(&self) -> Result<(), Error> {
 		let mut host = self.host.write();
 		if host.is_none() {
-			let h = Arc::new(Host::new(self.config.clone(), self.stats.clone(), self.filter.clone())?);
-			self.io_service.register_handler(h.clone())?;
+			let mut config = self.config.clone();
+			if let Some((network_id, ref boot_nodes)) = *self.network.read() {
+				config.network_id = Some(network_id);
+				config.boot_nodes = boot_nodes.clone();
+			}
+			if let Some(ref version) = *self.client_version.read() {
+				config.client_version_override = Some(version.clone());
+			}
+			let h = Arc::new(Host::new(config, self.stats.clone(), self.events.clone(), self.filter.read().clone())?);
+			let (low, high) = *self.peer_watermarks.read();
+			h.set_peer_watermarks(low, high);
+			h.set_peer_count_callback(self.peer_count_callback.read().clone());
+			h.set_packet_trace(self.packet_trace.clone());
+			if let Some(ref resolver) = *self.resolver.read() {
+				h.set_resolver(resolver.clone());
+			}
+			let handler_id = self.io_service.register_handler_with_id(h.clone())?;
+			self.handler_id.store(handler_id, AtomicOrdering::Release);
 			*host = Some(h);
 		}
+		Ok(())
+	}
+
+	/// Returns the enode URL other nodes can use to connect to this one.
+	/// See `local_enode_info` for when the URL is provisional.
+	pub fn local_enode(&self) -> Option<String> {
+		self.local_enode_info().map(|(url, _)| url)
+	}
 
-		if self.host_handler.public_url.read().is_none() {
-			self.io_service.register_handler(self.host_handler.clone())?;
+	/// Returns the enode URL and a flag that is set while the URL is provisional, i.e. before the service is
+	/// started or the external address is resolved. Before start the URL is only known if the node key is
+	/// configured or stored in `config_path`.
+	pub fn local_enode_info(&self) -> Option<(String, bool)> {
+		let host = self.host.read();
+		match *host {
+			Some(ref h) => Some(h.local_enode()),
+			None => configured_enode(&self.config).map(|(_, url)| (url, true)),
 		}
+	}
 
-		Ok(())
+	/// Returns how the public address in the enode URL was obtained. `None` before the service is started.
+	pub fn external_address_source(&self) -> Option<AddressSource> {
+		self.host.read().as_ref().and_then(|h| h.external_address_source())
 	}
 
-	/// Stop network IO
+	/// Returns the node id. Before start it is only known if the node key is configured or stored in `config_path`.
+	pub fn node_id(&self) -> Option<NodeId> {
+		let host = self.host.read();
+		match *host {
+			Some(ref h) => Some(h.node_id()),
+			None => configured_enode(&self.config).map(|(id, _)| id),
+		}
+	}
+
+	/// Stop network IO. Peers are disconnected and the host is removed from the IO event loop,
+	/// which keeps running until the last reference to it is dropped.
 	pub fn stop(&self) -> Result<(), Error> {
 		let mut host = self.host.write();
 		if let Some(ref host) = *host {
-			let io = IoContext::new(self.io_service.channel(), 0); //TODO: take token id from host
+			let io = self.io_context();
 			host.stop(&io)?;
 		}
 		*host = None;
 		Ok(())
 	}
 
+	/// Replace the connection filter. `None` disables filtering.
+	/// If `recheck_sessions` is set, connected peers rejected by the new filter are disconnected.
+	pub fn set_connection_filter(&self, filter: Option<Arc<ConnectionFilter>>, recheck_sessions: bool) {
+		*self.filter.write() = filter.clone();
+		let host = self.host.read();
+		if let Some(ref host) = *host {
+			let io = self.io_context();
+			host.set_connection_filter(filter, recheck_sessions, &io);
+		}
+	}
+
+	/// Connection filter decisions with the most recent denials, up to `filter_audit_size`.
+	/// Empty if the service is not running.
+	pub fn filter_stats(&self) -> FilterStats {
+		self.host.read().as_ref().map(|h| h.filter_stats()).unwrap_or_default()
+	}
+
+	/// Replace the node id allowlist of the running host. Sessions with nodes missing from the new list are
+	/// disconnected. `None` allows all nodes. A list loaded from `node_allowlist_path` is replaced until reloaded.
+	pub fn set_node_allowlist(&self, ids: Option<Vec<NodeId>>) {
+		let host = self.host.read();
+		if let Some(ref host) = *host {
+			let io = self.io_context();
+			host.set_node_allowlist(ids, &io);
+		}
+	}
+
+	/// Reload the node id allowlist from `node_allowlist_path` and the configured ids.
+	/// Sessions with nodes that are no longer on the list are disconnected.
+	pub fn reload_node_allowlist(&self) -> Result<(), Error> {
+		let host = self.host.read();
+		if let Some(ref host) = *host {
+			let io = self.io_context();
+			host.reload_node_allowlist(&io)?;
+		}
+		Ok(())
+	}
+
+	/// Returns the allowed node ids, `None` if all nodes are allowed or the service is not started.
+	pub fn node_allowlist(&self) -> Option<Vec<NodeId>> {
+		self.host.read().as_ref().and_then(|h| h.node_allowlist()).map(|l| l.ids())
+	}
+
+	/// Update the minimum and maximum number of peers of the running host.
+	/// Peers above the new maximum are disconnected gradually, reserved peers are kept.
+	pub fn set_peer_limits(&self, min_peers: u32, max_peers: u32) {
+		let host = self.host.read();
+		if let Some(ref host) = *host {
+			let io = self.io_context();
+			host.set_peer_limits(min_peers, max_peers, &io);
+		}
+	}
+
+	/// Returns the current minimum and maximum number of peers.
+	pub fn peer_limits(&self) -> (u32, u32) {
+		let host = self.host.read();
+		host.as_ref().map_or((self.config.min_peers, self.config.max_peers), |h| h.peer_limits())
+	}
+
+	/// Set the interval of the connection maintenance and the number of dials started per round.
+	/// The interval is at least 100 ms. Has no effect if the service is not running.
+	pub fn set_maintenance_pace(&self, maintain_interval: Duration, max_dials_per_tick: u32) {
+		let host = self.host.read();
+		if let Some(ref host) = *host {
+			let io = self.io_context();
+			host.set_maintenance_pace(maintain_interval, max_dials_per_tick, &io);
+		}
+	}
+
+	/// Returns the current interval of the connection maintenance and number of dials per round.
+	pub fn maintenance_pace(&self) -> (Duration, u32) {
+		let host = self.host.read();
+		host.as_ref().map_or((self.config.maintain_interval, self.config.max_dials_per_tick), |h| h.maintenance_pace())
+	}
+
+	/// Keep `count` peer slots for peers supporting `protocol` in `min_version` or later. Other peers are
+	/// refused once only the reserved slots are left, and a peer the slots are kept for replaces the lowest
+	/// scored other peer if all slots are taken. Replaces the previous reservation for the protocol,
+	/// zero `count` removes it.
+	pub fn reserve_slots_for(&self, protocol: ProtocolId, min_version: u8, count: u32) {
+		if let Some(ref host) = *self.host.read() {
+			host.reserve_slots_for(protocol, min_version, count);
+		}
+	}
+
+	/// Set the peer count watermarks. `PeerCountEvent::BelowLow` is raised once the number of connected peers
+	/// stays under `low` for `peer_count_grace`, `PeerCountEvent::Recovered` once it reaches `high` again.
+	/// A zero `low` disables the alerts.
+	pub fn set_peer_watermarks(&self, low: usize, high: usize) {
+		*self.peer_watermarks.write() = (low, high);
+		if let Some(ref host) = *self.host.read() {
+			host.set_peer_watermarks(low, high);
+		}
+	}
+
+	/// Register the callback notified of peer count watermark transitions. Replaces any previous callback.
+	pub fn on_peer_count_change(&self, callback: Box<Fn(PeerCountEvent) + Send + Sync>) {
+		let callback: PeerCountCallback = Arc::new(move |event| callback(event));
+		*self.peer_count_callback.write() = Some(callback.clone());
+		if let Some(ref host) = *self.host.read() {
+			host.set_peer_count_callback(Some(callback));
+		}
+	}
+
+	/// Install a tracer called for every packet received or sent by a session, after decryption and
+	/// before encryption respectively. Replaces any previous tracer, `None` removes it. The tracer is
+	/// called on the IO threads and must return quickly.
+	pub fn set_packet_tracer(&self, tracer: Option<Box<Fn(TraceEvent) + Send + Sync>>) {
+		self.packet_trace.set(tracer.map(|tracer| -> PacketTracer { Arc::new(move |event| tracer(event)) }));
+	}
+
+	/// Resolve nodes given by host name with `resolver` instead of the system DNS configuration.
+	pub fn set_host_resolver(&self, resolver: Arc<HostResolver>) {
+		*self.resolver.write() = Some(resolver.clone());
+		if let Some(ref host) = *self.host.read() {
+			host.set_resolver(resolver);
+		}
+	}
+
+	/// Subscribe to connection lifecycle events. Each subscriber buffers up to `event_queue_size` events and
+	/// drops the oldest ones when it falls behind. The receiver is closed when the service is dropped.
+	pub fn subscribe_events(&self) -> EventReceiver {
+		self.events.subscribe(self.config.event_queue_size)
+	}
+
+	/// Returns discovery counters and table occupancy. `None` if discovery is disabled or not started yet.
+	pub fn discovery_stats(&self) -> Option<DiscoveryStats> {
+		self.host.read().as_ref().and_then(|h| h.discovery_stats())
+	}
+
+	/// Snapshot of peer counts, traffic and discovery health for monitoring.
+	pub fn status_report(&self) -> NetworkStatusReport {
+		let mut report = NetworkStatusReport::from(&*self.stats);
+		if let Some(ref host) = *self.host.read() {
+			let (egress, ingress) = host.peer_counts();
+			let (min_peers, max_peers) = host.peer_limits();
+			report.peers = egress + ingress;
+			report.egress_peers = egress;
+			report.ingress_peers = ingress;
+			report.min_peers = min_peers;
+			report.max_peers = max_peers;
+			report.discovery = host.discovery_stats().as_ref().map(DiscoveryStatusReport::from);
+		}
+		report
+	}
+
+	/// Reset discovery counters.
+	pub fn reset_discovery_stats(&self) {
+		if let Some(ref host) = *self.host.read() {
+			host.reset_discovery_stats();
+		}
+	}
+
+	/// Nodes of the discovery table nearest to `target`, a node id hash, closest first. Without `live`
+	/// the current table is answered from right away. With `live` an iterative lookup is run first
+	/// and its result sent when done, within a few seconds. The channel is closed without a result
+	/// if discovery is disabled or the service is not running.
+	pub fn find_nodes_near(&self, target: H256, live: bool) -> Receiver<Vec<NearNode>> {
+		match *self.host.read() {
+			Some(ref host) => host.find_nodes_near(target, live),
+			None => mpsc::channel().1,
+		}
+	}
+
+	/// Nodes not dialed because of repeated connection failures, with the time their quarantine ends.
+	pub fn quarantined_nodes(&self) -> Vec<QuarantinedNode> {
+		self.host.read().as_ref().map(|h| h.quarantined_nodes()).unwrap_or_else(Vec::new)
+	}
+
+	/// Node table entries as dial candidates, best first, with the time and cause of their last
+	/// failed connection attempt. Empty if the service is not running.
+	pub fn dial_candidates(&self) -> Vec<DialCandidate> {
+		self.host.read().as_ref().map(|h| h.dial_candidates()).unwrap_or_else(Vec::new)
+	}
+
+	/// Enode URLs of the node table entries, best dial candidates first. If `source` is given, only
+	/// entries from that source are returned. Empty if the service is not running.
+	pub fn export_nodes(&self, source: Option<NodeSource>) -> Vec<String> {
+		self.host.read().as_ref().map(|h| h.export_nodes(source)).unwrap_or_else(Vec::new)
+	}
+
+	/// Switch to another network. All peers are disconnected, the node table of the current network is
+	/// saved and the one of `network_id` is loaded, and `boot_nodes` replace the configured boot nodes.
+	/// Reserved peers are kept. If the service is not running, the network is used when it is started.
+	pub fn switch_network(&self, network_id: u64, boot_nodes: Vec<String>) -> Result<(), Error> {
+		for n in &boot_nodes {
+			Node::from_str(n)?;
+		}
+		*self.network.write() = Some((network_id, boot_nodes.clone()));
+		if let Some(ref host) = *self.host.read() {
+			let io = self.io_context();
+			host.switch_network(network_id, boot_nodes, &io)?;
+		}
+		Ok(())
+	}
+
+	/// Write the node table to disk now, for embedders that manage shutdown themselves.
+	/// Does nothing if the service is not started or `net_config_path` is not set.
+	pub fn flush_node_table(&self) -> Result<(), Error> {
+		match *self.host.read() {
+			Some(ref host) => host.flush_node_table(),
+			None => Ok(()),
+		}
+	}
+
 	/// Get a list of all connected peers by id.
 	pub fn connected_peers(&self) -> Vec<PeerId> {
 		self.host.read().as_ref().map(|h| h.connected_peers()).unwrap_or_else(Vec::new)
 	}
 
+	/// Get information about all connected peers.
+	pub fn peers_info(&self) -> Vec<PeerInfo> {
+		self.host.read().as_ref().map(|h| h.peers_info()).unwrap_or_else(Vec::new)
+	}
+
 	/// Try to add a reserved peer.
 	pub fn add_reserved_peer(&self, peer: &str) -> Result<(), Error> {
 		let host = self.host.read();
@@ -155,6 +487,25 @@ impl NetworkService {
 		}
 	}
 
+	/// Dial the node given by an enode URL right away and report whether a session was established.
+	/// The peer limits apply unless `force` is set. The node is added to the node table on success.
+	/// The receiver is disconnected without a result if the service is not started or stops before
+	/// the dial completes.
+	pub fn connect_peer(&self, url: &str, force: bool) -> Result<Receiver<DialResult>, Error> {
+		if !url.starts_with("enode://") {
+			bail!(ErrorKind::InvalidNodeId);
+		}
+		let node = Node::from_str(url)?;
+		let host = self.host.read();
+		match *host {
+			Some(ref host) => {
+				let io = self.io_context();
+				Ok(host.dial(node, force, &io))
+			},
+			None => Ok(mpsc::channel().1),
+		}
+	}
+
 	/// Try to remove a reserved peer.
 	pub fn remove_reserved_peer(&self, peer: &str) -> Result<(), Error> {
 		let host = self.host.read();
@@ -165,18 +516,25 @@ impl NetworkService {
 		}
 	}
 
-	/// Set the non-reserved peer mode.
+	/// Set the non-reserved peer mode. Switching to `Deny` disconnects non-reserved peers, either at once
+	/// or gradually if `graceful_non_reserved_disconnect` is set. Switching back to `Accept` resumes dialing.
 	pub fn set_non_reserved_mode(&self, mode: NonReservedPeerMode) {
 		let host = self.host.read();
 		if let Some(ref host) = *host {
-			let io_ctxt = IoContext::new(self.io_service.channel(), 0);
+			let io_ctxt = self.io_context();
 			host.set_non_reserved_mode(mode, &io_ctxt);
 		}
 	}
 
+	/// Returns the current non-reserved peer mode.
+	pub fn non_reserved_mode(&self) -> NonReservedPeerMode {
+		let host = self.host.read();
+		host.as_ref().map_or_else(|| self.config.non_reserved_mode.clone(), |h| h.non_reserved_mode())
+	}
+
 	/// Executes action in the network context
 	pub fn with_context<F>(&self, protocol: ProtocolId, action: F) where F: FnOnce(&NetworkContext) {
-		let io = IoContext::new(self.io_service.channel(), 0);
+		let io = self.io_context();
 		let host = self.host.read();
 		if let Some(ref host) = host.as_ref() {
 			host.with_context(protocol, &io, action);
@@ -185,8 +543,14 @@ impl NetworkService {
 
 	/// Evaluates function in the network context
 	pub fn with_context_eval<F, T>(&self, protocol: ProtocolId, action: F) -> Option<T> where F: FnOnce(&NetworkContext) -> T {
-		let io = IoContext::new(self.io_service.channel(), 0);
+		let io = self.io_context();
 		let host = self.host.read();
 		host.as_ref().map(|ref host| host.with_context_eval(protocol, &io, action))
 	}
 }
+
+impl Drop for NetworkService {
+	fn drop(&mut self) {
+		self.stop().unwrap_or_else(|e| warn!(target: "network", "Error stopping network: {:?}", e));
+	}
+}
@@ -17,29 +17,37 @@
 use std::{str, io};
 use std::net::SocketAddr;
 use std::sync::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 
 use mio::*;
-use mio::deprecated::{Handler, EventLoop};
-use mio::tcp::*;
+use mio::net::TcpStream;
 use ethereum_types::H256;
 use rlp::*;
-use connection::{EncryptedConnection, Packet, Connection, MAX_PAYLOAD_SIZE};
+use connection::{EncryptedConnection, Packet, Connection, MAX_PAYLOAD_SIZE, SMALL_PACKET_SIZE};
 use handshake::Handshake;
 use io::{IoContext, StreamToken};
 use network::{Error, ErrorKind, DisconnectReason, SessionInfo, ProtocolId, PeerCapabilityInfo};
-use network::{SessionCapabilityInfo, HostInfo as HostInfoTrait};
+use network::{SessionCapabilityInfo, HostInfo as HostInfoTrait, ClientVersion, PeerTraffic, SocketOptions, ConnectionDirection};
 use host::*;
 use node_table::NodeId;
-use stats::NetworkStats;
+use stats::{NetworkStats, DisconnectOrigin, CompressionCounters, HandshakeFailure, DialFailure};
+use connection_filter::ConnectionFilter;
+use packet_trace::{PacketTrace, TraceDirection, TraceEvent};
+use rate_limit::{PeerRateLimiter, RateLimitStatus};
 use time;
 use snappy;
 
-// Timeout must be less than (interval - 1).
-const PING_TIMEOUT_SEC: u64 = 60;
-const PING_INTERVAL_SEC: u64 = 120;
 const MIN_PROTOCOL_VERSION: u32 = 4;
 const MIN_COMPRESSION_PROTOCOL_VERSION: u32 = 5;
+// A peer with this many times the send queue limit queued is disconnected.
+const SEND_QUEUE_HARD_LIMIT_FACTOR: usize = 4;
+// Longest client version accepted in Hello.
+const MAX_CLIENT_VERSION_LEN: usize = 1024;
+// Most capabilities accepted in Hello.
+const MAX_HELLO_CAPABILITIES: usize = 64;
+// Most packets held with `send_when_ready` until the session is ready.
+const MAX_DEFERRED_PACKETS: usize = 16;
 
 #[derive(Debug, Clone)]
 enum ProtocolState {
@@ -47,6 +55,8 @@ enum ProtocolState {
 	Pending(Vec<(Vec<u8>, u8)>),
 	// Protocol connected.
 	Connected,
+	// Protocol connected, packets held while its handler has paused reading.
+	Paused(Vec<(Vec<u8>, u8)>),
 }
 
 /// Peer session over encrypted connection.
@@ -57,14 +67,58 @@ pub struct Session {
 	pub info: SessionInfo,
 	/// Session ready flag. Set after successfull Hello packet exchange
 	had_hello: bool,
+	/// Hello was received and the connection filter has not decided yet. Nothing is read meanwhile.
+	parked: bool,
+	/// Report `SessionData::Ready` again on the next read, after the connection filter has decided.
+	replay_ready: bool,
 	/// Session is no longer active flag.
 	expired: bool,
+	/// `info.disconnect_reason` was received from the peer.
+	disconnected_by_peer: bool,
+	/// Deadline of a graceful disconnect. No protocol packets are sent or delivered meanwhile,
+	/// the Disconnect packet is sent once the send queue is empty or the deadline has passed.
+	draining_until_ns: Option<u64>,
 	ping_time_ns: u64,
-	pong_time_ns: Option<u64>,
+	/// Time of the last packet received from the peer.
+	last_received_ns: u64,
+	ping_interval_ns: u64,
+	idle_timeout_ns: u64,
+	/// Time the peer has to send Hello once the encrypted connection is established.
+	hello_timeout_ms: u64,
+	/// Inbound traffic limiter, if enabled.
+	rate_limiter: Option<PeerRateLimiter>,
+	/// Reading is paused until this time.
+	throttled_until_ns: Option<u64>,
+	/// Protocol packets are refused while this many bytes are queued. Zero means no limit.
+	send_queue_limit: usize,
+	/// Protocols whose packets are held instead of delivered.
+	paused_protocols: HashSet<ProtocolId>,
+	/// Reading stops once this many packets are held for a paused protocol. Zero means no limit.
+	paused_packet_limit: usize,
+	/// Disconnect on packets outside of all negotiated protocols.
+	disconnect_on_unknown_packet: bool,
+	/// Negotiated capabilities refused by the connection filter. Their packets are dropped.
+	filtered_capabilities: Vec<SessionCapabilityInfo>,
+	packet_trace: PacketTrace,
+	/// Number of payload bytes included in trace events.
+	trace_payload_bytes: usize,
+	stats: Arc<NetworkStats>,
+	/// Per-protocol traffic counters.
+	traffic: HashMap<ProtocolId, PeerTraffic>,
+	/// Unix time in seconds at which the Hello exchange completed.
+	connected_since: Option<u64>,
+	/// Same as `connected_since`, in nanoseconds of `time::precise_time_ns`.
+	connected_at_ns: Option<u64>,
 	state: State,
 	// Protocol states -- accumulates pending packets until signaled as ready.
 	protocol_states: HashMap<ProtocolId, ProtocolState>,
+	/// Protocol packets to be sent once the session is ready.
+	deferred: Vec<(ProtocolId, u8, Vec<u8>)>,
 	compression: bool,
+	/// Payload sizes before and after compression.
+	compressed_traffic: CompressionCounters,
+	/// Cause of the failure of an outbound connection, once known.
+	dial_failure: Option<DialFailure>,
 }
 
 enum State {
@@ -72,6 +126,31 @@ enum State {
 	Session(EncryptedConnection),
 }
 
+/// Outcome of a session liveness check.
+#[derive(Debug, PartialEq, Eq)]
+enum KeepAlive {
+	/// Peer is alive and no ping is due.
+	Alive,
+	/// Peer is alive but should be pinged.
+	Ping,
+	/// Peer has not sent anything for too long.
+	TimedOut,
+}
+
+fn keep_alive_state(now_ns: u64, last_received_ns: u64, ping_time_ns: u64, ping_interval_ns: u64, idle_timeout_ns: u64) -> KeepAlive {
+	if now_ns.saturating_sub(last_received_ns) > idle_timeout_ns {
+		KeepAlive::TimedOut
+	} else if now_ns.saturating_sub(ping_time_ns) > ping_interval_ns {
+		KeepAlive::Ping
+	} else {
+		KeepAlive::Alive
+	}
+}
+
+fn duration_ns(d: Duration) -> u64 {
+	d.as_secs() * 1000_000_000 + d.subsec_nanos() as u64
+}
+
 /// Structure used to report various session events.
 pub enum SessionData {
 	None,
@@ -98,6 +177,8 @@ const PACKET_GET_PEERS: u8 = 0x04;
 const PACKET_PEERS: u8 = 0x05;
 const PACKET_USER: u8 = 0x10;
 const PACKET_LAST: u8 = 0x7f;
+/// Number of packet ids available to subprotocols.
+pub const MAX_PACKET_COUNT: u8 = PACKET_LAST - PACKET_USER + 1;
 
 impl Session {
 	/// Create a new session out of comepleted handshake. This clones the handshake connection object
@@ -106,28 +187,60 @@ impl Session {
 		nonce: &H256, stats: Arc<NetworkStats>, host: &HostInfo) -> Result<Session, Error>
 		where Message: Send + Clone + Sync + 'static {
 		let originated = id.is_some();
-		let mut handshake = Handshake::new(token, id, socket, nonce, stats).expect("Can't create handshake");
+		let mut handshake = Handshake::new(token, id, socket, nonce, stats.clone()).expect("Can't create handshake");
 		let local_addr = handshake.connection.local_addr_str();
+		handshake.connection.apply_socket_options(&host.config().socket_options);
 		handshake.start(io, host, originated)?;
+		// Timeouts are taken from the configuration at the time the session is created.
+		let ping_interval_ns = duration_ns(host.config().ping_interval);
+		let idle_timeout_ns = duration_ns(host.config().session_idle_timeout);
+		let hello_timeout_ms = duration_ns(host.config().hello_timeout) / 1000_000;
+		let rate_limiter = host.config().peer_rate_limit.as_ref().map(|l| PeerRateLimiter::new(l, time::precise_time_ns()));
 		Ok(Session {
 			state: State::Handshake(handshake),
 			had_hello: false,
+			parked: false,
+			replay_ready: false,
 			info: SessionInfo {
 				id: id.cloned(),
-				client_version: String::new(),
+				client_version: ClientVersion::default(),
 				protocol_version: 0,
 				capabilities: Vec::new(),
 				peer_capabilities: Vec::new(),
 				ping_ms: None,
+				disconnect_reason: None,
 				originated: originated,
 				remote_address: "Handshake".to_owned(),
 				local_address: local_addr,
+				remote_socket_addr: None,
+				direction: if originated { ConnectionDirection::Outbound } else { ConnectionDirection::Inbound },
 			},
 			ping_time_ns: 0,
-			pong_time_ns: None,
+			last_received_ns: time::precise_time_ns(),
+			ping_interval_ns: ping_interval_ns,
+			idle_timeout_ns: idle_timeout_ns,
+			hello_timeout_ms: hello_timeout_ms,
+			rate_limiter: rate_limiter,
+			throttled_until_ns: None,
+			send_queue_limit: host.config().send_queue_limit,
+			paused_protocols: HashSet::new(),
+			paused_packet_limit: host.config().paused_packet_limit,
+			disconnect_on_unknown_packet: host.config().disconnect_on_unknown_packet,
+			filtered_capabilities: Vec::new(),
+			packet_trace: host.packet_trace.clone(),
+			trace_payload_bytes: host.config().packet_trace_payload_bytes,
+			stats: stats,
+			traffic: HashMap::new(),
+			connected_since: None,
+			connected_at_ns: None,
 			expired: false,
+			disconnected_by_peer: false,
+			draining_until_ns: None,
 			protocol_states: HashMap::new(),
+			deferred: Vec::new(),
 			compression: false,
+			compressed_traffic: CompressionCounters::default(),
+			dial_failure: None,
 		})
 	}
 
@@ -135,11 +248,22 @@ impl Session {
 		let connection = if let State::Handshake(ref mut h) = self.state {
 			self.info.id = Some(h.id.clone());
 			self.info.remote_address = h.connection.remote_addr_str();
-			EncryptedConnection::new(h)?
+			self.info.remote_socket_addr = h.connection.remote_addr().ok();
+			// Refuse nodes missing from the allowlist before our hello is sent.
+			if !host.node_allowlist.as_ref().map_or(true, |allowlist| allowlist.contains(&h.id)) {
+				trace!(target: "network", "Node {:?} is not on the allowlist", h.id);
+				bail!(ErrorKind::Disconnect(DisconnectReason::ConnectionFiltered));
+			}
+			let mut connection = EncryptedConnection::new(h)?;
+			let config = host.config();
+			connection.set_frame_limits(config.max_frame_size, config.max_chunked_packet_size, config.chunked_packet_timeout);
+			connection
 		} else {
 			panic!("Unexpected state");
 		};
 		self.state = State::Session(connection);
+		// The handshake timer has been cleared, the session token timer now waits for Hello.
+		io.register_timer(self.token(), ::std::cmp::max(self.hello_timeout_ms, 1)).ok();
 		self.write_hello(io, host)?;
 		Ok(())
 	}
@@ -151,6 +275,13 @@ impl Session {
 		}
 	}
 
+	fn connection_mut(&mut self) -> &mut Connection {
+		match self.state {
+			State::Handshake(ref mut h) => &mut h.connection,
+			State::Session(ref mut s) => &mut s.connection,
+		}
+	}
+
 	/// Get id of the remote peer
 	pub fn id(&self) -> Option<&NodeId> {
 		self.info.id.as_ref()
@@ -158,7 +289,67 @@ impl Session {
 
 	/// Check if session is ready to send/receive data
 	pub fn is_ready(&self) -> bool {
-		self.had_hello
+		self.had_hello && !self.parked
+	}
+
+	/// Hold the session until the connection filter has decided. Parked sessions are not ready
+	/// and don't read from the connection.
+	pub fn park(&mut self) {
+		self.parked = true;
+	}
+
+	/// Check if the session waits for a decision of the connection filter.
+	pub fn is_parked(&self) -> bool {
+		self.parked
+	}
+
+	/// Continue a parked session. The next read reports `SessionData::Ready` again.
+	pub fn resume(&mut self) {
+		if self.parked {
+			self.parked = false;
+			self.replay_ready = true;
+		}
+	}
+
+	/// Check if the encrypted connection is established and the peer's Hello is still missing.
+	pub fn awaiting_hello(&self) -> bool {
+		match self.state {
+			State::Session(_) => !self.had_hello,
+			State::Handshake(_) => false,
+		}
+	}
+
+	/// Record the cause of a failed connection, unless one is known already.
+	pub fn note_dial_failure(&mut self, failure: DialFailure) {
+		if self.dial_failure.is_none() {
+			self.dial_failure = Some(failure);
+		}
+	}
+
+	/// Record that the handshake or Hello did not complete in time.
+	pub fn note_timeout(&mut self) {
+		let failure = match self.dial_failure() {
+			DialFailure::WrongNodeId => DialFailure::WrongNodeId,
+			_ => DialFailure::Timeout,
+		};
+		self.note_dial_failure(failure);
+	}
+
+	/// Cause of the failure of this connection. Unless recorded or told by the peer's disconnect reason,
+	/// a handshake that still has our auth queued never got connected, and one that has sent the auth
+	/// and waits for the ack was not understood by the peer.
+	pub fn dial_failure(&self) -> DialFailure {
+		if let Some(failure) = self.dial_failure {
+			return failure;
+		}
+		if self.info.disconnect_reason == Some(DisconnectReason::ConnectionFiltered) {
+			return DialFailure::Filtered;
+		}
+		match self.state {
+			State::Handshake(ref h) if h.connection.is_sending() => DialFailure::Refused,
+			State::Handshake(ref h) if h.awaiting_ack() => DialFailure::WrongNodeId,
+			_ => DialFailure::Other,
+		}
 	}
 
 	/// Mark this session as inactive to be deleted lated.
@@ -174,26 +365,196 @@ impl Session {
 		}
 	}
 
+	/// Start a graceful disconnect with the given reason. Returns false if the session is not
+	/// established or has nothing queued; it should be disconnected right away then.
+	pub fn start_draining(&mut self, reason: DisconnectReason, deadline_ns: u64) -> bool {
+		if self.expired() || !self.is_ready() || !self.is_sending() {
+			return false;
+		}
+		if self.draining_until_ns.is_none() {
+			trace!(target: "network", "{}: Draining {} bytes before disconnecting", self.token(), self.queue_depth());
+			self.info.disconnect_reason = Some(reason);
+			self.draining_until_ns = Some(deadline_ns);
+		}
+		true
+	}
+
+	/// Check if a graceful disconnect is in progress.
+	pub fn is_draining(&self) -> bool {
+		self.draining_until_ns.is_some()
+	}
+
+	/// Reason of a graceful disconnect that can be completed: the send queue is empty or the
+	/// deadline has passed.
+	pub fn drained(&self, now_ns: u64) -> Option<DisconnectReason> {
+		match self.draining_until_ns {
+			Some(deadline) if !self.is_sending() || now_ns >= deadline => self.info.disconnect_reason,
+			_ => None,
+		}
+	}
+
 	/// Check if this session is over and there is nothing to be sent.
 	pub fn done(&self) -> bool {
 		self.expired() && !self.connection().is_sending()
 	}
 
+	/// Check if there is data queued to be sent.
+	pub fn is_sending(&self) -> bool {
+		self.connection().is_sending()
+	}
+
+	/// Number of bytes queued to be sent.
+	pub fn queue_depth(&self) -> usize {
+		self.connection().queue_depth()
+	}
+
+	/// Check if the send queue is over the hard limit. Such a peer is too slow to keep the session.
+	pub fn send_queue_overflow(&self) -> bool {
+		self.send_queue_limit != 0 && self.queue_depth() > self.send_queue_limit * SEND_QUEUE_HARD_LIMIT_FACTOR
+	}
+
+	/// Get protocol packet counters for this session.
+	pub fn traffic(&self, protocol: ProtocolId) -> PeerTraffic {
+		self.traffic.get(&protocol).cloned().unwrap_or_default()
+	}
+
+	/// Traffic counters summed over all protocols.
+	pub fn total_traffic(&self) -> PeerTraffic {
+		self.traffic.values().fold(PeerTraffic::default(), |acc, t| PeerTraffic {
+			packets_sent: acc.packets_sent + t.packets_sent,
+			packets_received: acc.packets_received + t.packets_received,
+			bytes_sent: acc.bytes_sent + t.bytes_sent,
+			bytes_received: acc.bytes_received + t.bytes_received,
+		})
+	}
+
+	/// Check if packets are snappy compressed. Known once the Hello packet has been received.
+	pub fn compression_enabled(&self) -> bool {
+		self.compression
+	}
+
+	/// Payload sizes before and after compression. Zero if compression is not enabled.
+	pub fn compression_counters(&self) -> CompressionCounters {
+		self.compressed_traffic
+	}
+
+	/// Unix time in seconds at which the session became ready.
+	pub fn connected_since(&self) -> Option<u64> {
+		self.connected_since
+	}
+
+	/// Time at which the session became ready, in nanoseconds of `time::precise_time_ns`.
+	pub fn connected_at_ns(&self) -> Option<u64> {
+		self.connected_at_ns
+	}
+
+	/// Stop limiting inbound traffic for this session.
+	pub fn disable_rate_limit<Message>(&mut self, io: &IoContext<Message>) where Message: Send + Sync + Clone {
+		self.rate_limiter = None;
+		self.resume_reading(io);
+	}
+
+	/// Stop polling for incoming data. Whatever has already been received is still processed.
+	fn throttle<Message>(&mut self, io: &IoContext<Message>, duration_ns: u64) where Message: Send + Sync + Clone {
+		let throttled = self.throttled_until_ns.is_some();
+		self.throttled_until_ns = Some(time::precise_time_ns() + duration_ns);
+		if !throttled {
+			trace!(target: "network", "{}: Throttling reads for {} ms", self.token(), duration_ns / 1000_000);
+			self.stats.inc_throttled();
+			self.update_reading(io);
+		}
+	}
+
+	fn resume_reading<Message>(&mut self, io: &IoContext<Message>) where Message: Send + Sync + Clone {
+		if self.throttled_until_ns.take().is_some() {
+			self.update_reading(io);
+		}
+	}
+
+	/// Check if the limit of packets held for a paused protocol is reached.
+	fn read_backlogged(&self) -> bool {
+		self.paused_packet_limit != 0 && self.protocol_states.values().any(|s| match *s {
+			ProtocolState::Paused(ref held) => held.len() >= self.paused_packet_limit,
+			_ => false,
+		})
+	}
+
+	/// Poll for incoming data unless throttled or backlogged. Whatever has already been received is still processed.
+	fn update_reading<Message>(&mut self, io: &IoContext<Message>) where Message: Send + Sync + Clone {
+		let reading = self.throttled_until_ns.is_none() && !self.read_backlogged();
+		if let State::Session(ref mut c) = self.state {
+			if c.connection.is_reading() == reading {
+				return;
+			}
+			c.connection.set_reading(reading);
+		}
+		io.update_registration(self.token()).unwrap_or_else(|e| debug!(target: "network", "Token registration error: {:?}", e));
+	}
+
+	/// Hold the packets of the protocol instead of delivering them until `resume_protocol` is called.
+	pub fn pause_protocol(&mut self, protocol: ProtocolId) {
+		self.paused_protocols.insert(protocol);
+		if let Some(state) = self.protocol_states.get_mut(&protocol) {
+			if let ProtocolState::Connected = *state {
+				*state = ProtocolState::Paused(Vec::new());
+			}
+		}
+	}
+
+	/// Deliver the packets of the protocol again. Returns the packets held meanwhile in order received.
+	pub fn resume_protocol<Message>(&mut self, io: &IoContext<Message>, protocol: ProtocolId) -> Vec<(ProtocolId, u8, Vec<u8>)> where Message: Send + Sync + Clone {
+		self.paused_protocols.remove(&protocol);
+		let held = match self.protocol_states.remove(&protocol) {
+			Some(ProtocolState::Paused(held)) => {
+				self.protocol_states.insert(protocol, ProtocolState::Connected);
+				held
+			},
+			Some(state) => {
+				self.protocol_states.insert(protocol, state);
+				Vec::new()
+			},
+			None => Vec::new(),
+		};
+		self.update_reading(io);
+		held.into_iter().map(|(data, id)| (protocol, id, data)).collect()
+	}
+
+	/// TCP socket options in effect for this session.
+	pub fn socket_options(&self) -> SocketOptions {
+		self.connection().socket_options()
+	}
+
 	/// Get remote peer address
 	pub fn remote_addr(&self) -> io::Result<SocketAddr> {
 		self.connection().remote_addr()
 	}
 
-	/// Readable IO handler. Returns packet data if available.
-	pub fn readable<Message>(&mut self, io: &IoContext<Message>, host: &HostInfo) -> Result<SessionData, Error>  where Message: Send + Sync + Clone {
-		if self.expired() {
+	/// Get local address of the connection
+	pub fn local_addr(&self) -> io::Result<SocketAddr> {
+		self.connection().local_addr()
+	}
+
+	/// Set the address of the peer for a session connected through a proxy.
+	pub fn set_proxied_peer(&mut self, address: SocketAddr) {
+		self.connection_mut().set_proxied_peer(address);
+	}
+
+	/// Readable IO handler. Returns packet data if available. Protocols negotiated in the Hello are
+	/// checked against `filter`.
+	pub fn readable<Message>(&mut self, io: &IoContext<Message>, host: &HostInfo, filter: Option<&ConnectionFilter>) -> Result<SessionData, Error>  where Message: Send + Sync + Clone {
+		if self.expired() || self.parked {
 			return Ok(SessionData::None)
 		}
+		if self.replay_ready {
+			self.replay_ready = false;
+			return Ok(SessionData::Ready);
+		}
 		let mut create_session = false;
 		let mut packet_data = None;
+		let mut handshake_result = Ok(());
 		match self.state {
 			State::Handshake(ref mut h) => {
-				h.readable(io, host)?;
+				handshake_result = h.readable(io, host);
 				if h.done() {
 					create_session = true;
 				}
@@ -205,11 +566,18 @@ impl Session {
 				}
 			}
 		}
+		if let Err(e) = handshake_result {
+			self.note_handshake_error(&e);
+			return Err(e);
+		}
 		if let Some(data) = packet_data {
-			return Ok(self.read_packet(io, data, host)?);
+			return Ok(self.read_packet(io, data, host, filter)?);
 		}
 		if create_session {
-			self.complete_handshake(io, host)?;
+			if let Err(e) = self.complete_handshake(io, host) {
+				self.note_handshake_error(&e);
+				return Err(e);
+			}
             io.update_registration(self.token()).unwrap_or_else(|e| debug!(target: "network", "Token registration error: {:?}", e));
 		}
 		Ok(SessionData::None)
@@ -217,10 +585,29 @@ impl Session {
 
 	/// Writable IO handler. Sends pending packets.
 	pub fn writable<Message>(&mut self, io: &IoContext<Message>, _host: &HostInfo) -> Result<(), Error> where Message: Send + Sync + Clone {
-		match self.state {
+		let result = match self.state {
 			State::Handshake(ref mut h) => h.writable(io),
-			State::Session(ref mut s) => s.writable(io),
+			State::Session(ref mut s) => return s.writable(io),
+		};
+		if let Err(ref e) = result {
+			self.note_handshake_error(e);
 		}
+		result
+	}
+
+	/// Record the cause of a failed handshake, if the error tells it.
+	fn note_handshake_error(&mut self, e: &Error) {
+		let failure = match *e.kind() {
+			ErrorKind::Io(ref e) => match e.kind() {
+				io::ErrorKind::ConnectionRefused => DialFailure::Refused,
+				io::ErrorKind::TimedOut => DialFailure::Timeout,
+				_ => return,
+			},
+			ErrorKind::Disconnect(DisconnectReason::ConnectionFiltered) => DialFailure::Filtered,
+			ErrorKind::SocketIo(_) | ErrorKind::SelfConnection => return,
+			_ => DialFailure::Auth,
+		};
+		self.note_dial_failure(failure);
 	}
 
 	/// Checks if peer supports given capability
@@ -234,35 +621,39 @@ impl Session {
 	}
 
 	/// Register the session socket with the event loop
-	pub fn register_socket<Host:Handler<Timeout = Token>>(&self, reg: Token, event_loop: &mut EventLoop<Host>) -> Result<(), Error> {
+	pub fn register_socket(&self, reg: Token, poll: &Poll) -> Result<(), Error> {
 		if self.expired() {
 			return Ok(());
 		}
-		self.connection().register_socket(reg, event_loop)?;
+		self.connection().register_socket(reg, poll)?;
 		Ok(())
 	}
 
 	/// Update registration with the event loop. Should be called at the end of the IO handler.
-	pub fn update_socket<Host:Handler>(&self, reg:Token, event_loop: &mut EventLoop<Host>) -> Result<(), Error> {
-		self.connection().update_socket(reg, event_loop)?;
+	pub fn update_socket(&self, reg:Token, poll: &Poll) -> Result<(), Error> {
+		self.connection().update_socket(reg, poll)?;
 		Ok(())
 	}
 
 	/// Delete registration
-	pub fn deregister_socket<Host:Handler>(&self, event_loop: &mut EventLoop<Host>) -> Result<(), Error> {
-		self.connection().deregister_socket(event_loop)?;
+	pub fn deregister_socket(&self, poll: &Poll) -> Result<(), Error> {
+		self.connection().deregister_socket(poll)?;
 		Ok(())
 	}
 
 	/// Send a protocol packet to peer.
 	pub fn send_packet<Message>(&mut self, io: &IoContext<Message>, protocol: Option<[u8; 3]>, packet_id: u8, data: &[u8]) -> Result<(), Error>
         where Message: Send + Sync + Clone {
-		if protocol.is_some() && (self.info.capabilities.is_empty() || !self.had_hello) {
+		if self.expired() || (protocol.is_some() && self.is_draining()) {
+			bail!(ErrorKind::PeerGone);
+		}
+		if protocol.is_some() && (self.info.capabilities.is_empty() || !self.is_ready()) {
 			debug!(target: "network", "Sending to unconfirmed session {}, protocol: {:?}, packet: {}", self.token(), protocol.as_ref().map(|p| str::from_utf8(&p[..]).unwrap_or("??")), packet_id);
-			bail!(ErrorKind::BadProtocol);
+			bail!(ErrorKind::SessionNotReady);
 		}
-		if self.expired() {
-			return Err(ErrorKind::Expired.into());
+		if protocol.is_some() && self.send_queue_limit != 0 && self.queue_depth() >= self.send_queue_limit {
+			trace!(target: "network", "{}: Send queue full, {} bytes queued", self.token(), self.queue_depth());
+			bail!(ErrorKind::SendQueueFull);
 		}
 		let mut i = 0usize;
 		let pid = match protocol {
@@ -274,43 +665,115 @@ impl Session {
 						return Ok(())
 					}
 				}
+				if packet_id >= self.info.capabilities[i].packet_count {
+					bail!(ErrorKind::PacketIdOutOfRange(packet_id, self.info.capabilities[i].packet_count));
+				}
+				let traffic = self.traffic.entry(protocol).or_insert_with(PeerTraffic::default);
+				traffic.packets_sent += 1;
+				traffic.bytes_sent += data.len() as u64;
+				self.stats.inc_protocol_send(protocol, data.len());
 				self.info.capabilities[i].id_offset + packet_id
 			},
 			None => packet_id
 		};
-		let mut rlp = RlpStream::new();
-		rlp.append(&(pid as u32));
-		let mut compressed = Vec::new();
-		let mut payload = data; // create a reference with local lifetime
+		self.trace_packet(TraceDirection::Outbound, protocol, packet_id, data);
 		if self.compression {
-			if payload.len() > MAX_PAYLOAD_SIZE {
+			if data.len() > MAX_PAYLOAD_SIZE {
 				bail!(ErrorKind::OversizedPacket);
 			}
-			let len = snappy::compress_into(&payload, &mut compressed);
-			trace!(target: "network", "compressed {} to {}", payload.len(), len);
-			payload = &compressed[0..len];
+			let mut compressed = self.connection_mut().buffer_pool().take(snappy::max_compressed_len(data.len()));
+			let len = snappy::compress_into(data, &mut compressed);
+			trace!(target: "network", "compressed {} to {} bytes ({}%)", data.len(), len, len * 100 / ::std::cmp::max(data.len(), 1));
+			self.compressed_traffic.note_sent(data.len(), len);
+			self.stats.inc_compressed_send(data.len(), len);
+			let result = self.send_with_id(io, pid, &compressed[0..len]);
+			self.connection_mut().recycle(compressed);
+			result
+		} else {
+			self.send_with_id(io, pid, data)
 		}
+	}
+
+	/// Send a protocol packet once the session is ready. Packets are held in order until then,
+	/// and also while earlier held packets have not been sent yet.
+	pub fn send_when_ready<Message>(&mut self, io: &IoContext<Message>, protocol: ProtocolId, packet_id: u8, data: Vec<u8>) -> Result<(), Error>
+		where Message: Send + Sync + Clone {
+		if self.expired() || self.is_draining() {
+			bail!(ErrorKind::PeerGone);
+		}
+		if self.is_ready() && self.deferred.is_empty() {
+			return self.send_packet(io, Some(protocol), packet_id, &data);
+		}
+		if self.deferred.len() >= MAX_DEFERRED_PACKETS {
+			trace!(target: "network", "{}: Too many packets held until ready", self.token());
+			bail!(ErrorKind::SendQueueFull);
+		}
+		self.deferred.push((protocol, packet_id, data));
+		Ok(())
+	}
+
+	/// Send the packets held until the session became ready, in order. Packets of protocols the
+	/// peer does not support are dropped.
+	pub fn send_deferred<Message>(&mut self, io: &IoContext<Message>) where Message: Send + Sync + Clone {
+		for (protocol, packet_id, data) in ::std::mem::replace(&mut self.deferred, Vec::new()) {
+			if let Err(e) = self.send_packet(io, Some(protocol), packet_id, &data) {
+				debug!(target: "network", "{}: Held packet not sent: {}", self.token(), e);
+			}
+		}
+	}
+
+	/// Drop the packets held for a session that is closing. Returns the number of packets dropped.
+	pub fn drop_deferred(&mut self) -> usize {
+		let dropped = self.deferred.len();
+		self.deferred.clear();
+		dropped
+	}
+
+	/// Send a packet id followed by the payload. Small packets are assembled on the stack.
+	fn send_with_id<Message>(&mut self, io: &IoContext<Message>, pid: u8, payload: &[u8]) -> Result<(), Error> where Message: Send + Sync + Clone {
+		let mut id = [0u8; 2];
+		let id_len = write_packet_id(pid, &mut id);
+		let len = id_len + payload.len();
+		if len < SMALL_PACKET_SIZE {
+			let mut packet = [0u8; SMALL_PACKET_SIZE];
+			packet[0..id_len].copy_from_slice(&id[0..id_len]);
+			packet[id_len..len].copy_from_slice(payload);
+			return self.send(io, &packet[0..len]);
+		}
+		let mut rlp = RlpStream::new();
+		rlp.append(&(pid as u32));
 		rlp.append_raw(payload, 1);
 		self.send(io, &rlp.drain())
 	}
 
-	/// Keep this session alive. Returns false if ping timeout happened
+	/// Keep this session alive. Returns false if the peer has been idle for too long.
+	/// Also resumes reading from a throttled peer once the throttling period is over.
 	pub fn keep_alive<Message>(&mut self, io: &IoContext<Message>) -> bool where Message: Send + Sync + Clone {
 		if let State::Handshake(_) = self.state {
 			return true;
 		}
-		let timed_out = if let Some(pong) = self.pong_time_ns {
-			pong - self.ping_time_ns > PING_TIMEOUT_SEC * 1000_000_000
-		} else {
-			time::precise_time_ns() - self.ping_time_ns > PING_TIMEOUT_SEC * 1000_000_000
-		};
-
-		if !timed_out && time::precise_time_ns() - self.ping_time_ns > PING_INTERVAL_SEC * 1000_000_000 {
-			if let Err(e) = self.send_ping(io) {
-				debug!("Error sending ping message: {:?}", e);
+		if self.throttled_until_ns.map_or(false, |until| time::precise_time_ns() >= until) {
+			self.resume_reading(io);
+		}
+		self.connection_mut().buffer_pool().shrink_idle();
+		if let State::Session(ref mut c) = self.state {
+			let expired = c.expire_chunks(time::precise_time_ns());
+			if expired != 0 {
+				debug!(target: "network", "{}: Dropped {} incomplete chunked packets", c.connection.token, expired);
 			}
 		}
-		!timed_out
+		match keep_alive_state(time::precise_time_ns(), self.last_received_ns, self.ping_time_ns, self.ping_interval_ns, self.idle_timeout_ns) {
+			// Nothing is read from a backlogged peer, its silence is no sign of a dead connection.
+			KeepAlive::TimedOut if self.read_backlogged() => true,
+			KeepAlive::TimedOut => false,
+			KeepAlive::Ping => {
+				if let Err(e) = self.send_ping(io) {
+					debug!("Error sending ping message: {:?}", e);
+				}
+				true
+			},
+			KeepAlive::Alive => true,
+		}
 	}
 
 	pub fn token(&self) -> StreamToken {
@@ -319,19 +782,39 @@ impl Session {
 
 	/// Signal that a subprotocol has handled the connection successfully and
 	/// get all pending packets in order received.
+	/// Packets of a paused protocol are held until it is resumed.
 	pub fn mark_connected(&mut self, protocol: ProtocolId) -> Vec<(ProtocolId, u8, Vec<u8>)> {
-		match self.protocol_states.insert(protocol, ProtocolState::Connected) {
-			None => Vec::new(),
-			Some(ProtocolState::Connected) => {
+		let paused = self.paused_protocols.contains(&protocol);
+		match self.protocol_states.remove(&protocol) {
+			None => {
+				self.protocol_states.insert(protocol, if paused { ProtocolState::Paused(Vec::new()) } else { ProtocolState::Connected });
+				Vec::new()
+			},
+			Some(ProtocolState::Pending(pending)) => {
+				if paused {
+					self.protocol_states.insert(protocol, ProtocolState::Paused(pending));
+					return Vec::new();
+				}
+				self.protocol_states.insert(protocol, ProtocolState::Connected);
+				pending.into_iter().map(|(data, id)| (protocol, id, data)).collect()
+			},
+			Some(state) => {
 				debug!(target: "network", "Protocol {:?} marked as connected more than once", protocol);
+				self.protocol_states.insert(protocol, state);
 				Vec::new()
-			}
-			Some(ProtocolState::Pending(pending)) =>
-				pending.into_iter().map(|(data, id)| (protocol, id, data)).collect(),
+			},
 		}
 	}
 
-	fn read_packet<Message>(&mut self, io: &IoContext<Message>, packet: Packet, host: &HostInfo) -> Result<SessionData, Error>
+	/// Check if any subprotocol has been notified of the connection.
+	pub fn has_connected_protocol(&self) -> bool {
+		self.protocol_states.values().any(|s| match *s {
+			ProtocolState::Connected | ProtocolState::Paused(_) => true,
+			ProtocolState::Pending(_) => false,
+		})
+	}
+
+	fn read_packet<Message>(&mut self, io: &IoContext<Message>, packet: Packet, host: &HostInfo, filter: Option<&ConnectionFilter>) -> Result<SessionData, Error>
 	where Message: Send + Sync + Clone {
 		if packet.data.len() < 2 {
 			return Err(ErrorKind::BadProtocol.into());
@@ -340,28 +823,38 @@ impl Session {
 		if packet_id != PACKET_HELLO && packet_id != PACKET_DISCONNECT && !self.had_hello {
 			return Err(ErrorKind::BadProtocol.into());
 		}
+		// Any packet from the peer counts as a sign of life.
+		self.last_received_ns = time::precise_time_ns();
 		let data = if self.compression {
 			let compressed = &packet.data[1..];
 			if snappy::decompressed_len(&compressed)? > MAX_PAYLOAD_SIZE {
 				bail!(ErrorKind::OversizedPacket);
 			}
-			snappy::decompress(&compressed)?
+			let data = snappy::decompress(&compressed)?;
+			self.compressed_traffic.note_received(data.len(), compressed.len());
+			self.stats.inc_compressed_recv(data.len(), compressed.len());
+			data
 		} else {
 			packet.data[1..].to_owned()
 		};
+		let packet_size = packet.data.len();
+		self.connection_mut().recycle(packet.data);
+		let (protocol, protocol_packet_id) = self.packet_protocol(packet_id);
+		self.trace_packet(TraceDirection::Inbound, protocol, protocol_packet_id, &data);
 		match packet_id {
 			PACKET_HELLO => {
-				let rlp = UntrustedRlp::new(&data); //TODO: validate rlp expected size
-				self.read_hello(io, &rlp, host)?;
+				let rlp = UntrustedRlp::new(&data);
+				self.read_hello(io, &rlp, host, filter)?;
 				Ok(SessionData::Ready)
 			},
 			PACKET_DISCONNECT => {
-				let rlp = UntrustedRlp::new(&data);
-				let reason: u8 = rlp.val_at(0)?;
+				let reason = decode_disconnect_reason(&UntrustedRlp::new(&data));
 				if self.had_hello {
-					debug!(target:"network", "Disconnected: {}: {:?}", self.token(), DisconnectReason::from_u8(reason));
+					debug!(target:"network", "Disconnected: {}: {:?}", self.token(), reason);
 				}
-				Err(ErrorKind::Disconnect(DisconnectReason::from_u8(reason)).into())
+				self.info.disconnect_reason = Some(reason);
+				self.disconnected_by_peer = true;
+				Err(ErrorKind::Disconnect(reason).into())
 			}
 			PACKET_PING => {
 				self.send_pong(io)?;
@@ -369,46 +862,118 @@ impl Session {
 			},
 			PACKET_PONG => {
 				let time = time::precise_time_ns();
-				self.pong_time_ns = Some(time);
 				self.info.ping_ms = Some((time - self.ping_time_ns) / 1000_000);
 				Ok(SessionData::Continue)
 			},
 			PACKET_GET_PEERS => Ok(SessionData::None), //TODO;
 			PACKET_PEERS => Ok(SessionData::None),
 			PACKET_USER ... PACKET_LAST => {
+				let status = match self.rate_limiter {
+					Some(ref mut limiter) => limiter.on_packet(packet_size, time::precise_time_ns()),
+					None => RateLimitStatus::Allowed,
+				};
+				match status {
+					RateLimitStatus::Exceeded => {
+						debug!(target: "network", "Peer {} exceeded inbound rate limit", self.token());
+						self.stats.inc_rate_limited();
+						return Err(From::from(self.disconnect(io, DisconnectReason::BadProtocol)));
+					},
+					RateLimitStatus::Throttle(duration) => self.throttle(io, duration),
+					RateLimitStatus::Allowed => {},
+				}
+
+				if self.is_draining() {
+					trace!(target: "network", "Dropped packet {} of a peer being disconnected", packet_id);
+					return Ok(SessionData::Continue);
+				}
+
+				if self.filtered_capabilities.iter().any(|c| packet_id >= c.id_offset && packet_id - c.id_offset < c.packet_count) {
+					trace!(target: "network", "Dropped packet {} of a protocol refused by the connection filter", packet_id);
+					return Ok(SessionData::Continue);
+				}
+
 				let mut i = 0usize;
 				while packet_id >= self.info.capabilities[i].id_offset + self.info.capabilities[i].packet_count {
 					i += 1;
 					if i == self.info.capabilities.len() {
-						debug!(target: "network", "Unknown packet: {:?}", packet_id);
-						return Ok(SessionData::Continue)
+						return self.unknown_packet(io, packet_id);
 					}
 				}
 
 				// map to protocol
 				let protocol = self.info.capabilities[i].protocol;
 				let protocol_packet_id = packet_id - self.info.capabilities[i].id_offset;
+				{
+					let traffic = self.traffic.entry(protocol).or_insert_with(PeerTraffic::default);
+					traffic.packets_received += 1;
+					traffic.bytes_received += data.len() as u64;
+				}
+				self.stats.inc_protocol_recv(protocol, data.len());
 
-				match *self.protocol_states.entry(protocol).or_insert_with(|| ProtocolState::Pending(Vec::new())) {
+				let held = match *self.protocol_states.entry(protocol).or_insert_with(|| ProtocolState::Pending(Vec::new())) {
 					ProtocolState::Connected => {
 						trace!(target: "network", "Packet {} mapped to {:?}:{}, i={}, capabilities={:?}", packet_id, protocol, protocol_packet_id, i, self.info.capabilities);
-						Ok(SessionData::Packet { data: data, protocol: protocol, packet_id: protocol_packet_id } )
+						return Ok(SessionData::Packet { data: data, protocol: protocol, packet_id: protocol_packet_id } );
 					}
 					ProtocolState::Pending(ref mut pending) => {
 						trace!(target: "network", "Packet {} deferred until protocol connection event completion", packet_id);
 						pending.push((data, protocol_packet_id));
-
-						Ok(SessionData::Continue)
+						false
 					}
+					ProtocolState::Paused(ref mut held) => {
+						trace!(target: "network", "Packet {} held while reading is paused", packet_id);
+						held.push((data, protocol_packet_id));
+						true
+					}
+				};
+				if held {
+					self.update_reading(io);
 				}
-			},
-			_ => {
-				debug!(target: "network", "Unknown packet: {:?}", packet_id);
 				Ok(SessionData::Continue)
-			}
+			},
+			_ => self.unknown_packet(io, packet_id),
 		}
 	}
 
+	/// Protocol and protocol packet id of a received packet. Packets outside of all negotiated protocols
+	/// are attributed to devp2p.
+	fn packet_protocol(&self, packet_id: u8) -> (Option<ProtocolId>, u8) {
+		if packet_id == PACKET_HELLO {
+			return (None, 0);
+		}
+		match self.info.capabilities.iter().find(|c| packet_id >= c.id_offset && packet_id - c.id_offset < c.packet_count) {
+			Some(c) if packet_id >= PACKET_USER => (Some(c.protocol), packet_id - c.id_offset),
+			_ => (None, packet_id),
+		}
+	}
+
+	/// Pass the packet to the packet tracer, if one is installed.
+	fn trace_packet(&self, direction: TraceDirection, protocol: Option<ProtocolId>, packet_id: u8, data: &[u8]) {
+		let node_id = match self.info.id {
+			Some(ref id) => id,
+			None => return,
+		};
+		let payload_bytes = self.trace_payload_bytes;
+		self.packet_trace.trace(|| TraceEvent {
+			direction: direction,
+			node_id: node_id.clone(),
+			protocol: protocol,
+			packet_id: packet_id,
+			length: data.len(),
+			payload: data[..::std::cmp::min(data.len(), payload_bytes)].to_vec(),
+		});
+	}
+
+	/// Count a packet outside of all negotiated protocols. Disconnects the peer if configured.
+	fn unknown_packet<Message>(&mut self, io: &IoContext<Message>, packet_id: u8) -> Result<SessionData, Error> where Message: Send + Sync + Clone {
+		debug!(target: "network", "Unknown packet: {:?}", packet_id);
+		self.stats.inc_unknown_packets();
+		if self.disconnect_on_unknown_packet {
+			return Err(From::from(self.disconnect(io, DisconnectReason::BadProtocol)));
+		}
+		Ok(SessionData::Continue)
+	}
+
 	fn write_hello<Message>(&mut self, io: &IoContext<Message>, host: &HostInfo) -> Result<(), Error> where Message: Send + Sync + Clone {
 		let mut rlp = RlpStream::new();
 		rlp.append_raw(&[PACKET_HELLO as u8], 0);
@@ -418,54 +983,42 @@ impl Session {
 			.append_list(&host.capabilities)
 			.append(&host.local_endpoint.address.port())
 			.append(host.id());
-		self.send(io, &rlp.drain())
+		let packet = rlp.drain();
+		self.trace_packet(TraceDirection::Outbound, None, 0, &packet[1..]);
+		self.send(io, &packet)
 	}
 
-	fn read_hello<Message>(&mut self, io: &IoContext<Message>, rlp: &UntrustedRlp, host: &HostInfo) -> Result<(), Error>
+	fn read_hello<Message>(&mut self, io: &IoContext<Message>, rlp: &UntrustedRlp, host: &HostInfo, filter: Option<&ConnectionFilter>) -> Result<(), Error>
 	where Message: Send + Sync + Clone {
-		let protocol = rlp.val_at::<u32>(0)?;
-		let client_version = rlp.val_at::<String>(1)?;
-		let peer_caps: Vec<PeerCapabilityInfo> = rlp.list_at(2)?;
-		let id = rlp.val_at::<NodeId>(4)?;
-
-		// Intersect with host capabilities
-		// Leave only highset mutually supported capability version
-		let mut caps: Vec<SessionCapabilityInfo> = Vec::new();
-		for hc in &host.capabilities {
-			if peer_caps.iter().any(|c| c.protocol == hc.protocol && c.version == hc.version) {
-				caps.push(SessionCapabilityInfo {
-					protocol: hc.protocol,
-					version: hc.version,
-					id_offset: 0,
-					packet_count: hc.packet_count,
-				});
-			}
-		}
+		let Hello { protocol, client_version, peer_caps, id } = match decode_hello(rlp) {
+			Ok(hello) => hello,
+			Err(e) => {
+				debug!(target: "network", "{}: Malformed Hello: {:?}", self.token(), e);
+				self.stats.inc_handshake_failure(HandshakeFailure::BadHello);
+				return Err(From::from(self.disconnect(io, DisconnectReason::BadProtocol)));
+			},
+		};
 
-		caps.retain(|c| host.capabilities.iter().any(|hc| hc.protocol == c.protocol && hc.version == c.version));
-		let mut i = 0;
-		while i < caps.len() {
-			if caps.iter().any(|c| c.protocol == caps[i].protocol && c.version > caps[i].version) {
-				caps.remove(i);
-			} else {
-				i += 1;
-			}
+		let mut caps = negotiate_capabilities(&host.capabilities, &peer_caps);
+		debug!(target: "network", "Hello: {} v{} {} {:?}", client_version, protocol, id, caps);
+		if !packet_ranges_disjoint(&caps) {
+			warn!(target: "network", "Overlapping packet id ranges negotiated: {:?}", caps);
+			return Err(From::from(self.disconnect(io, DisconnectReason::BadProtocol)));
 		}
-
-		// Sort capabilities alphabeticaly.
-		caps.sort();
-
-		i = 0;
-		let mut offset: u8 = PACKET_USER;
-		while i < caps.len() {
-			caps[i].id_offset = offset;
-			offset += caps[i].packet_count;
-			i += 1;
+		// Refused protocols keep their packet id ranges, the peer computes the offsets from the full set.
+		if let Some(filter) = filter {
+			let direction = self.info.direction;
+			let (allowed, refused): (Vec<_>, Vec<_>) = caps.into_iter().partition(|c| filter.protocol_allowed(&id, c.protocol, direction));
+			for cap in &refused {
+				debug!(target: "network", "Protocol {} refused for {} by the connection filter", String::from_utf8_lossy(&cap.protocol), id);
+				self.stats.inc_filtered_protocols();
+			}
+			caps = allowed;
+			self.filtered_capabilities = refused;
 		}
-		debug!(target: "network", "Hello: {} v{} {} {:?}", client_version, protocol, id, caps);
 		let protocol = ::std::cmp::min(protocol, host.protocol_version);
 		self.info.protocol_version = protocol;
-		self.info.client_version = client_version;
+		self.info.client_version = ClientVersion::from(client_version);
 		self.info.capabilities = caps;
 		self.info.peer_capabilities = peer_caps;
 		if self.info.capabilities.is_empty() {
@@ -478,7 +1031,10 @@ impl Session {
 		}
 		self.compression = protocol >= MIN_COMPRESSION_PROTOCOL_VERSION;
 		self.send_ping(io)?;
+		io.clear_timer(self.token()).ok();
 		self.had_hello = true;
+		self.connected_since = Some(time::get_time().sec as u64);
+		self.connected_at_ns = Some(time::precise_time_ns());
 		Ok(())
 	}
 
@@ -486,7 +1042,6 @@ impl Session {
 	pub fn send_ping<Message>(&mut self, io: &IoContext<Message>) -> Result<(), Error> where Message: Send + Sync + Clone {
 		self.send_packet(io, None, PACKET_PING, &EMPTY_LIST_RLP)?;
 		self.ping_time_ns = time::precise_time_ns();
-		self.pong_time_ns = None;
 		Ok(())
 	}
 
@@ -494,12 +1049,24 @@ impl Session {
 		self.send_packet(io, None, PACKET_PONG, &EMPTY_LIST_RLP)
 	}
 
+	/// Side that ended the session, based on who sent the disconnect packet.
+	pub fn disconnect_origin(&self) -> DisconnectOrigin {
+		match self.info.disconnect_reason {
+			None => DisconnectOrigin::Dropped,
+			Some(_) if self.disconnected_by_peer => DisconnectOrigin::Remote,
+			Some(_) => DisconnectOrigin::Local,
+		}
+	}
+
 	/// Disconnect this session
 	pub fn disconnect<Message>(&mut self, io: &IoContext<Message>, reason: DisconnectReason) -> Error where Message: Send + Sync + Clone {
+		if self.info.disconnect_reason.is_none() {
+			self.info.disconnect_reason = Some(reason);
+		}
 		if let State::Session(_) = self.state {
 			let mut rlp = RlpStream::new();
 			rlp.begin_list(1);
-			rlp.append(&(reason as u32));
+			rlp.append(&(reason.wire_code() as u32));
 			self.send_packet(io, None, PACKET_DISCONNECT, &rlp.drain()).ok();
 		}
 		ErrorKind::Disconnect(reason).into()
@@ -518,3 +1085,322 @@ impl Session {
 	}
 }
 
+/// Decoded Hello packet.
+struct Hello {
+	protocol: u32,
+	client_version: String,
+	peer_caps: Vec<PeerCapabilityInfo>,
+	id: NodeId,
+}
+
+/// Decode a Hello packet. Items past the fifth are ignored, the listen port is not used.
+fn decode_hello(rlp: &UntrustedRlp) -> Result<Hello, DecoderError> {
+	if rlp.item_count()? < 5 {
+		return Err(DecoderError::RlpIncorrectListLen);
+	}
+	let client_version = rlp.at(1)?;
+	if client_version.size() > MAX_CLIENT_VERSION_LEN {
+		return Err(DecoderError::Custom("Client version too long"));
+	}
+	let caps = rlp.at(2)?;
+	if caps.item_count()? > MAX_HELLO_CAPABILITIES {
+		return Err(DecoderError::Custom("Too many capabilities"));
+	}
+	Ok(Hello {
+		protocol: rlp.val_at(0)?,
+		client_version: client_version.as_val()?,
+		peer_caps: caps.as_list()?,
+		id: rlp.val_at(4)?,
+	})
+}
+
+/// Reason of a Disconnect packet. The reason is a list of one item, but some clients send it bare.
+/// Unknown reason codes and malformed packets are `Unknown`, the peer is gone either way.
+fn decode_disconnect_reason(rlp: &UntrustedRlp) -> DisconnectReason {
+	let code = if rlp.is_list() { rlp.val_at::<u32>(0) } else { rlp.as_val::<u32>() };
+	match code {
+		Ok(code) if code <= 0xff => DisconnectReason::from_u8(code as u8),
+		_ => DisconnectReason::Unknown,
+	}
+}
+
+/// Write the RLP of a packet id, the same as appending it as `u32` to an `RlpStream`. Returns the length.
+fn write_packet_id(id: u8, out: &mut [u8; 2]) -> usize {
+	match id {
+		0 => {
+			out[0] = 0x80;
+			1
+		},
+		1...0x7f => {
+			out[0] = id;
+			1
+		},
+		_ => {
+			out[0] = 0x81;
+			out[1] = id;
+			2
+		},
+	}
+}
+
+/// Highest version of each protocol present in both lists, ordered by protocol name.
+/// The result does not depend on the order of the lists or duplicate entries.
+fn shared_versions(ours: &[(ProtocolId, u8)], theirs: &[(ProtocolId, u8)]) -> Vec<(ProtocolId, u8)> {
+	let mut ours = ours.to_vec();
+	ours.sort();
+	ours.dedup();
+	let mut shared: Vec<(ProtocolId, u8)> = Vec::new();
+	// Versions of each protocol are visited from the highest one.
+	for &(protocol, version) in ours.iter().rev() {
+		if shared.last().map_or(false, |&(p, _)| p == protocol) {
+			continue;
+		}
+		if theirs.contains(&(protocol, version)) {
+			shared.push((protocol, version));
+		}
+	}
+	shared.reverse();
+	shared
+}
+
+/// Select the highest mutually supported version of each protocol and assign packet id offsets
+/// in alphabetical protocol order.
+fn negotiate_capabilities(host_caps: &[CapabilityInfo], peer_caps: &[PeerCapabilityInfo]) -> Vec<SessionCapabilityInfo> {
+	let ours: Vec<(ProtocolId, u8)> = host_caps.iter().map(|c| (c.protocol, c.version)).collect();
+	let theirs: Vec<(ProtocolId, u8)> = peer_caps.iter().map(|c| (c.protocol, c.version)).collect();
+	let shared = shared_versions(&ours, &theirs);
+	// The peer computes the offsets from the same set, in the same order.
+	debug_assert_eq!(shared, shared_versions(&theirs, &ours));
+
+	let mut caps = Vec::with_capacity(shared.len());
+	let mut offset: u8 = PACKET_USER;
+	for (protocol, version) in shared {
+		let packet_count = host_caps.iter()
+			.find(|c| c.protocol == protocol && c.version == version)
+			.expect("shared versions are a subset of host capabilities; qed")
+			.packet_count;
+		if offset as usize + packet_count as usize > PACKET_LAST as usize + 1 {
+			warn!(target: "network", "Packet id space exhausted, ignoring capability {}/{}", String::from_utf8_lossy(&protocol), version);
+			break;
+		}
+		caps.push(SessionCapabilityInfo {
+			protocol: protocol,
+			version: version,
+			id_offset: offset,
+			packet_count: packet_count,
+		});
+		offset += packet_count;
+	}
+	caps
+}
+
+/// Check that the packet id ranges of negotiated capabilities are within the subprotocol space and don't overlap.
+fn packet_ranges_disjoint(caps: &[SessionCapabilityInfo]) -> bool {
+	let mut ranges: Vec<(usize, usize)> = caps.iter().map(|c| (c.id_offset as usize, c.id_offset as usize + c.packet_count as usize)).collect();
+	ranges.sort();
+	ranges.iter().all(|&(start, end)| start >= PACKET_USER as usize && end <= PACKET_LAST as usize + 1) &&
+		ranges.windows(2).all(|w| w[0].1 <= w[1].0)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{keep_alive_state, negotiate_capabilities, packet_ranges_disjoint, write_packet_id, KeepAlive};
+	use super::{decode_hello, decode_disconnect_reason, MAX_CLIENT_VERSION_LEN, MAX_HELLO_CAPABILITIES};
+	use rlp::{RlpStream, UntrustedRlp};
+	use network::DisconnectReason;
+	use host::CapabilityInfo;
+	use network::{PeerCapabilityInfo, SessionCapabilityInfo};
+
+	const SEC: u64 = 1000_000_000;
+
+	#[test]
+	fn packet_ids_encoded_as_rlp() {
+		for id in 0..256 {
+			let mut rlp = RlpStream::new();
+			rlp.append(&(id as u32));
+			let mut out = [0u8; 2];
+			let len = write_packet_id(id as u8, &mut out);
+			assert_eq!(&out[0..len], &rlp.drain()[..]);
+		}
+	}
+
+	/// Hello with the given raw protocol version, client version, capabilities and node id length,
+	/// followed by `extra` raw items.
+	fn hello(version: &[u8], client_version: &[u8], caps: &[&str], id_len: usize, extra: &[&[u8]]) -> Vec<u8> {
+		let mut rlp = RlpStream::new_list(5 + extra.len());
+		rlp.append_raw(version, 1);
+		rlp.append(&client_version);
+		rlp.begin_list(caps.len());
+		for cap in caps {
+			rlp.begin_list(2).append(cap).append(&63u8);
+		}
+		rlp.append(&30303u16);
+		rlp.append(&vec![0x42u8; id_len]);
+		for item in extra {
+			rlp.append_raw(item, 1);
+		}
+		rlp.out()
+	}
+
+	#[test]
+	fn hello_decoding() {
+		let valid = hello(&[0x05], b"Parity/v1.11.0", &["eth", "par"], 64, &[]);
+		let decoded = decode_hello(&UntrustedRlp::new(&valid)).unwrap();
+		assert_eq!(decoded.protocol, 5);
+		assert_eq!(decoded.client_version, "Parity/v1.11.0");
+		assert_eq!(decoded.peer_caps.len(), 2);
+		// Items added by future protocol versions are ignored.
+		assert!(decode_hello(&UntrustedRlp::new(&hello(&[0x05], b"Parity", &["eth"], 64, &[&[0x01u8][..]]))).is_ok());
+		let max_caps: Vec<&str> = (0..MAX_HELLO_CAPABILITIES).map(|_| "eth").collect();
+		assert!(decode_hello(&UntrustedRlp::new(&hello(&[0x05], b"Parity", &max_caps, 64, &[]))).is_ok());
+	}
+
+	#[test]
+	fn malformed_hello_rejected() {
+		let too_many_caps: Vec<&str> = (0..MAX_HELLO_CAPABILITIES + 1).map(|_| "eth").collect();
+		let valid = hello(&[0x05], b"Parity", &["eth"], 64, &[]);
+		let mut four_items = RlpStream::new_list(4);
+		four_items.append(&5u32).append(&"Parity").begin_list(0);
+		four_items.append(&30303u16);
+		let corpus: Vec<Vec<u8>> = vec![
+			vec![],
+			vec![0x80],
+			vec![0xc0],
+			four_items.out(),
+			hello(&[0x05], &vec![b'a'; MAX_CLIENT_VERSION_LEN + 1], &["eth"], 64, &[]),
+			hello(&[0x05], &[0xff, 0xfe], &["eth"], 64, &[]),
+			hello(&[0x05], b"Parity", &too_many_caps, 64, &[]),
+			hello(&[0x05], b"Parity", &["eth!"], 64, &[]),
+			hello(&[0x05], b"Parity", &["eth"], 63, &[]),
+			hello(&[0x05], b"Parity", &["eth"], 65, &[]),
+			// Non-canonical protocol versions: a single byte with a prefix, and a leading zero.
+			hello(&[0x81, 0x05], b"Parity", &["eth"], 64, &[]),
+			hello(&[0x82, 0x00, 0x05], b"Parity", &["eth"], 64, &[]),
+			// Client version claiming 4 GB.
+			vec![0xc9, 0x05, 0xbb, 0xff, 0xff, 0xff, 0xff, 0xc0, 0x80, 0x80],
+		];
+		for (i, packet) in corpus.iter().enumerate() {
+			assert!(decode_hello(&UntrustedRlp::new(packet)).is_err(), "packet {} accepted", i);
+		}
+		for len in 0..valid.len() {
+			assert!(decode_hello(&UntrustedRlp::new(&valid[0..len])).is_err(), "truncated to {} accepted", len);
+		}
+		// Corrupting any byte is survived.
+		for i in 0..valid.len() {
+			for &bits in &[0x01u8, 0x40, 0x80, 0xff] {
+				let mut corrupt = valid.clone();
+				corrupt[i] ^= bits;
+				let _ = decode_hello(&UntrustedRlp::new(&corrupt));
+			}
+		}
+	}
+
+	#[test]
+	fn disconnect_reason_decoding() {
+		let decode = |data: &[u8]| decode_disconnect_reason(&UntrustedRlp::new(data));
+		assert_eq!(decode(&[0xc1, 0x04]), DisconnectReason::TooManyPeers);
+		assert_eq!(decode(&[0xc1, 0x80]), DisconnectReason::DisconnectRequested);
+		// Bare reason.
+		assert_eq!(decode(&[0x04]), DisconnectReason::TooManyPeers);
+		// Unknown and out of range codes.
+		assert_eq!(decode(&[0xc1, 0x0d]), DisconnectReason::Unknown);
+		assert_eq!(decode(&[0xc2, 0x81, 0xff]), DisconnectReason::Unknown);
+		assert_eq!(decode(&[0xc3, 0x82, 0x01, 0x00]), DisconnectReason::Unknown);
+		assert_eq!(decode(&[0xc5, 0x84, 0xff, 0xff, 0xff, 0xff]), DisconnectReason::Unknown);
+		// Malformed.
+		assert_eq!(decode(&[]), DisconnectReason::Unknown);
+		assert_eq!(decode(&[0xc0]), DisconnectReason::Unknown);
+		assert_eq!(decode(&[0xc2, 0x81]), DisconnectReason::Unknown);
+		assert_eq!(decode(&[0xc2, 0x81, 0x04]), DisconnectReason::Unknown);
+		assert_eq!(decode(&[0xb9, 0xff, 0xff]), DisconnectReason::Unknown);
+	}
+
+	#[test]
+	fn keep_alive_active_peer() {
+		// Packets keep arriving, pings are sent at the configured interval.
+		assert_eq!(keep_alive_state(10 * SEC, 9 * SEC, 8 * SEC, 5 * SEC, 15 * SEC), KeepAlive::Alive);
+		assert_eq!(keep_alive_state(14 * SEC, 13 * SEC, 8 * SEC, 5 * SEC, 15 * SEC), KeepAlive::Ping);
+		assert_eq!(keep_alive_state(100 * SEC, 99 * SEC, 96 * SEC, 5 * SEC, 15 * SEC), KeepAlive::Alive);
+	}
+
+	#[test]
+	fn keep_alive_unresponsive_peer() {
+		// Ping sent at 10s was never answered, last packet seen at 9s.
+		assert_eq!(keep_alive_state(20 * SEC, 9 * SEC, 10 * SEC, 5 * SEC, 15 * SEC), KeepAlive::Ping);
+		assert_eq!(keep_alive_state(24 * SEC, 9 * SEC, 20 * SEC, 5 * SEC, 15 * SEC), KeepAlive::Alive);
+		assert_eq!(keep_alive_state(25 * SEC, 9 * SEC, 20 * SEC, 5 * SEC, 15 * SEC), KeepAlive::TimedOut);
+	}
+
+	#[test]
+	fn keep_alive_short_timeouts() {
+		let ms = SEC / 1000;
+		assert_eq!(keep_alive_state(250 * ms, 0, 0, 100 * ms, 500 * ms), KeepAlive::Ping);
+		assert_eq!(keep_alive_state(501 * ms, 0, 400 * ms, 100 * ms, 500 * ms), KeepAlive::TimedOut);
+		assert_eq!(keep_alive_state(501 * ms, 450 * ms, 400 * ms, 100 * ms, 500 * ms), KeepAlive::Alive);
+	}
+
+	fn host_caps(caps: &[(&[u8; 3], u8, u8)]) -> Vec<CapabilityInfo> {
+		caps.iter().map(|&(p, v, c)| CapabilityInfo { protocol: *p, version: v, packet_count: c }).collect()
+	}
+
+	fn peer_caps(caps: &[(&[u8; 3], u8)]) -> Vec<PeerCapabilityInfo> {
+		caps.iter().map(|&(p, v)| PeerCapabilityInfo { protocol: *p, version: v }).collect()
+	}
+
+	fn negotiated(caps: &[SessionCapabilityInfo]) -> Vec<(&[u8], u8, u8)> {
+		caps.iter().map(|c| (&c.protocol[..], c.version, c.id_offset)).collect()
+	}
+
+	#[test]
+	fn negotiates_highest_shared_version() {
+		let host = host_caps(&[(b"eth", 62, 8), (b"eth", 63, 17), (b"par", 1, 21)]);
+		let peer = peer_caps(&[(b"par", 1), (b"eth", 63), (b"eth", 62), (b"les", 1)]);
+		let caps = negotiate_capabilities(&host, &peer);
+		assert_eq!(negotiated(&caps), vec![(&b"eth"[..], 63, 0x10), (&b"par"[..], 1, 0x10 + 17)]);
+
+		// The highest version the peer does not support is skipped.
+		let peer = peer_caps(&[(b"eth", 62), (b"eth", 64)]);
+		let caps = negotiate_capabilities(&host, &peer);
+		assert_eq!(negotiated(&caps), vec![(&b"eth"[..], 62, 0x10)]);
+		assert_eq!(caps[0].packet_count, 8);
+
+		assert!(negotiate_capabilities(&host, &peer_caps(&[(b"eth", 61)])).is_empty());
+	}
+
+	#[test]
+	fn negotiation_ignores_order_and_duplicates() {
+		let host = host_caps(&[(b"par", 1, 21), (b"eth", 62, 8), (b"eth", 63, 17), (b"par", 1, 21), (b"bzz", 2, 4)]);
+		let sorted = host_caps(&[(b"bzz", 2, 4), (b"eth", 62, 8), (b"eth", 63, 17), (b"par", 1, 21)]);
+		let peer = peer_caps(&[(b"par", 1), (b"eth", 63), (b"par", 1), (b"bzz", 2), (b"eth", 62), (b"eth", 63)]);
+		let mut reversed = peer.clone();
+		reversed.reverse();
+
+		let caps = negotiate_capabilities(&host, &peer);
+		assert_eq!(negotiated(&caps), vec![(&b"bzz"[..], 2, 0x10), (&b"eth"[..], 63, 0x10 + 4), (&b"par"[..], 1, 0x10 + 4 + 17)]);
+		assert_eq!(caps, negotiate_capabilities(&sorted, &peer));
+		assert_eq!(caps, negotiate_capabilities(&host, &reversed));
+	}
+
+	#[test]
+	fn negotiation_stops_when_packet_ids_run_out() {
+		let host = host_caps(&[(b"aaa", 1, 100), (b"bbb", 1, 20), (b"ccc", 1, 10)]);
+		let peer = peer_caps(&[(b"aaa", 1), (b"bbb", 1), (b"ccc", 1)]);
+		let caps = negotiate_capabilities(&host, &peer);
+		assert_eq!(negotiated(&caps), vec![(&b"aaa"[..], 1, 0x10)]);
+	}
+
+	#[test]
+	fn negotiated_packet_ranges_are_disjoint() {
+		let host = host_caps(&[(b"aaa", 1, 2), (b"aab", 1, 3), (b"bbb", 1, 107)]);
+		let peer = peer_caps(&[(b"aaa", 1), (b"aab", 1), (b"bbb", 1)]);
+		let caps = negotiate_capabilities(&host, &peer);
+		assert_eq!(negotiated(&caps), vec![(&b"aaa"[..], 1, 0x10), (&b"aab"[..], 1, 0x12), (&b"bbb"[..], 1, 0x15)]);
+		assert!(packet_ranges_disjoint(&caps));
+
+		let cap = |offset, count| SessionCapabilityInfo { protocol: *b"aaa", version: 1, id_offset: offset, packet_count: count };
+		assert!(!packet_ranges_disjoint(&[cap(0x10, 3), cap(0x12, 3)]));
+		assert!(!packet_ranges_disjoint(&[cap(0x0f, 1)]));
+		assert!(!packet_ranges_disjoint(&[cap(0x7f, 2)]));
+		assert!(packet_ranges_disjoint(&[cap(0x13, 1), cap(0x10, 3)]));
+	}
+}
@@ -0,0 +1,189 @@
+// Copyright 2015-2017 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Minimal SOCKS5 client for outbound peer connections. See RFC 1928 and RFC 1929.
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use network::SocksConfig;
+
+const SOCKS_VERSION: u8 = 5;
+const AUTH_VERSION: u8 = 1;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_PASSWORD: u8 = 0x02;
+const COMMAND_CONNECT: u8 = 0x01;
+const ADDRESS_IPV4: u8 = 0x01;
+const ADDRESS_DOMAIN: u8 = 0x03;
+const ADDRESS_IPV6: u8 = 0x04;
+const REPLY_SUCCEEDED: u8 = 0x00;
+
+fn protocol_error(message: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, format!("SOCKS5 proxy: {}", message))
+}
+
+/// Error for a failure reply to the CONNECT request.
+fn reply_error(reply: u8) -> io::Error {
+	let (kind, message) = match reply {
+		0x02 => (io::ErrorKind::PermissionDenied, "connection not allowed by ruleset"),
+		0x03 => (io::ErrorKind::ConnectionRefused, "network unreachable"),
+		0x04 => (io::ErrorKind::ConnectionRefused, "host unreachable"),
+		0x05 => (io::ErrorKind::ConnectionRefused, "connection refused"),
+		0x06 => (io::ErrorKind::TimedOut, "TTL expired"),
+		0x07 => (io::ErrorKind::InvalidInput, "command not supported"),
+		0x08 => (io::ErrorKind::InvalidInput, "address type not supported"),
+		_ => (io::ErrorKind::Other, "general failure"),
+	};
+	io::Error::new(kind, format!("SOCKS5 proxy: {}", message))
+}
+
+fn authenticate(stream: &mut TcpStream, auth: &Option<(String, String)>) -> io::Result<()> {
+	let methods: &[u8] = if auth.is_some() { &[METHOD_NO_AUTH, METHOD_PASSWORD] } else { &[METHOD_NO_AUTH] };
+	let mut greeting = vec![SOCKS_VERSION, methods.len() as u8];
+	greeting.extend_from_slice(methods);
+	stream.write_all(&greeting)?;
+
+	let mut choice = [0u8; 2];
+	stream.read_exact(&mut choice)?;
+	if choice[0] != SOCKS_VERSION {
+		return Err(protocol_error("unsupported version"));
+	}
+	match (choice[1], auth) {
+		(METHOD_NO_AUTH, _) => Ok(()),
+		(METHOD_PASSWORD, &Some((ref user, ref password))) => {
+			if user.len() > 255 || password.len() > 255 {
+				return Err(io::Error::new(io::ErrorKind::InvalidInput, "SOCKS5 proxy: username or password too long"));
+			}
+			let mut request = vec![AUTH_VERSION, user.len() as u8];
+			request.extend_from_slice(user.as_bytes());
+			request.push(password.len() as u8);
+			request.extend_from_slice(password.as_bytes());
+			stream.write_all(&request)?;
+			let mut status = [0u8; 2];
+			stream.read_exact(&mut status)?;
+			if status[1] != 0 {
+				return Err(io::Error::new(io::ErrorKind::PermissionDenied, "SOCKS5 proxy: authentication failed"));
+			}
+			Ok(())
+		},
+		_ => Err(io::Error::new(io::ErrorKind::PermissionDenied, "SOCKS5 proxy: no acceptable authentication method")),
+	}
+}
+
+fn request_connect(stream: &mut TcpStream, target: &SocketAddr) -> io::Result<()> {
+	let mut request = vec![SOCKS_VERSION, COMMAND_CONNECT, 0];
+	match *target {
+		SocketAddr::V4(ref a) => {
+			request.push(ADDRESS_IPV4);
+			request.extend_from_slice(&a.ip().octets());
+		},
+		SocketAddr::V6(ref a) => {
+			request.push(ADDRESS_IPV6);
+			request.extend_from_slice(&a.ip().octets());
+		},
+	}
+	request.push((target.port() >> 8) as u8);
+	request.push(target.port() as u8);
+	stream.write_all(&request)?;
+
+	let mut reply = [0u8; 4];
+	stream.read_exact(&mut reply)?;
+	if reply[0] != SOCKS_VERSION {
+		return Err(protocol_error("unsupported version"));
+	}
+	if reply[1] != REPLY_SUCCEEDED {
+		return Err(reply_error(reply[1]));
+	}
+	// Skip the bound address and port.
+	let address_len = match reply[3] {
+		ADDRESS_IPV4 => 4,
+		ADDRESS_IPV6 => 16,
+		ADDRESS_DOMAIN => {
+			let mut len = [0u8; 1];
+			stream.read_exact(&mut len)?;
+			len[0] as usize
+		},
+		_ => return Err(protocol_error("unknown address type")),
+	};
+	let mut bound = vec![0u8; address_len + 2];
+	stream.read_exact(&mut bound)?;
+	Ok(())
+}
+
+/// Open a connection to `target` through the proxy. Blocks until the proxy has connected
+/// or one of the steps has timed out.
+pub fn connect(proxy: &SocksConfig, target: &SocketAddr) -> io::Result<TcpStream> {
+	let mut stream = TcpStream::connect_timeout(&proxy.address, proxy.timeout)?;
+	stream.set_read_timeout(Some(proxy.timeout))?;
+	stream.set_write_timeout(Some(proxy.timeout))?;
+	authenticate(&mut stream, &proxy.auth)?;
+	request_connect(&mut stream, target)?;
+	stream.set_read_timeout(None)?;
+	stream.set_write_timeout(None)?;
+	Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::net::TcpListener;
+	use std::str::FromStr;
+	use std::thread;
+	use std::time::Duration;
+
+	fn config(address: SocketAddr, auth: Option<(String, String)>) -> SocksConfig {
+		SocksConfig { address: address, auth: auth, timeout: Duration::from_secs(5) }
+	}
+
+	/// Serve one connection: expect the given bytes and answer with the given replies in turn.
+	fn scripted_proxy(script: Vec<(Vec<u8>, Vec<u8>)>) -> (SocketAddr, thread::JoinHandle<()>) {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let address = listener.local_addr().unwrap();
+		let handle = thread::spawn(move || {
+			let (mut stream, _) = listener.accept().unwrap();
+			for (expected, reply) in script {
+				let mut received = vec![0u8; expected.len()];
+				stream.read_exact(&mut received).unwrap();
+				assert_eq!(received, expected);
+				stream.write_all(&reply).unwrap();
+			}
+		});
+		(address, handle)
+	}
+
+	#[test]
+	fn connects_with_password() {
+		let target = SocketAddr::from_str("10.0.0.1:30303").unwrap();
+		let (address, handle) = scripted_proxy(vec![
+			(vec![5, 2, 0, 2], vec![5, 2]),
+			(b"\x01\x04user\x04pass".to_vec(), vec![1, 0]),
+			(vec![5, 1, 0, 1, 10, 0, 0, 1, 0x76, 0x5f], vec![5, 0, 0, 1, 127, 0, 0, 1, 0x12, 0x34]),
+		]);
+		assert!(connect(&config(address, Some(("user".into(), "pass".into()))), &target).is_ok());
+		handle.join().unwrap();
+	}
+
+	#[test]
+	fn refused_connect_fails() {
+		let target = SocketAddr::from_str("[::1]:30303").unwrap();
+		let (address, handle) = scripted_proxy(vec![
+			(vec![5, 1, 0], vec![5, 0]),
+			(vec![5, 1, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x76, 0x5f], vec![5, 5, 0, 1, 0, 0, 0, 0, 0, 0]),
+		]);
+		let error = connect(&config(address, None), &target).unwrap_err();
+		assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+		handle.join().unwrap();
+	}
+}
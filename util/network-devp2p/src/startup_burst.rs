@@ -0,0 +1,175 @@
+// Copyright 2015-2017 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Pace of dialing: dials per connection round, faster right after start until `min_peers` is reached.
+
+use std::cmp::{min, max};
+use std::time::Duration;
+use network::StartupBurstConfig;
+
+fn duration_ns(d: Duration) -> u64 {
+	d.as_secs() * 1000_000_000 + d.subsec_nanos() as u64
+}
+
+/// Dials allowed in a connection round: the burst rate while the burst lasts, `max_dials_per_tick`
+/// otherwise. Never more than the handshake slots left.
+pub fn round_dials(burst: Option<usize>, max_dials_per_tick: usize, max_handshakes: usize, handshakes: usize) -> usize {
+	min(burst.unwrap_or(max_dials_per_tick), max_handshakes.saturating_sub(handshakes))
+}
+
+/// Startup burst state. Once over, the burst never resumes.
+pub struct StartupBurst {
+	/// Time the burst ends at. `None` if disabled or over.
+	ends_at: Option<u64>,
+	interval_ns: u64,
+	dials_per_round: usize,
+}
+
+impl StartupBurst {
+	/// Start the burst at `now_ns`. `None` disables it.
+	pub fn new(config: Option<&StartupBurstConfig>, now_ns: u64) -> StartupBurst {
+		match config {
+			Some(config) if config.duration != Duration::from_secs(0) => StartupBurst {
+				ends_at: Some(now_ns + duration_ns(config.duration)),
+				interval_ns: max(duration_ns(config.interval), 1000_000),
+				dials_per_round: config.dials_per_round as usize,
+			},
+			_ => StartupBurst { ends_at: None, interval_ns: 0, dials_per_round: 0 },
+		}
+	}
+
+	/// Check if the burst has not ended yet.
+	pub fn is_active(&self) -> bool {
+		self.ends_at.is_some()
+	}
+
+	/// Interval of the extra connection rounds in milliseconds.
+	pub fn interval_ms(&self) -> u64 {
+		self.interval_ns / 1000_000
+	}
+
+	/// Update with the current peer count. Ends the burst once `min_peers` is reached or its
+	/// duration has passed. Returns the number of dials allowed per round while it lasts.
+	pub fn update(&mut self, peers: usize, min_peers: usize, now_ns: u64) -> Option<usize> {
+		let ends_at = match self.ends_at {
+			Some(ends_at) => ends_at,
+			None => return None,
+		};
+		if peers >= min_peers || now_ns >= ends_at {
+			debug!(target: "network", "Startup burst over with {} peers", peers);
+			self.ends_at = None;
+			return None;
+		}
+		Some(self.dials_per_round)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const MS: u64 = 1000_000;
+	const MAINTENANCE_MS: u64 = 1000;
+	const HANDSHAKE_MS: u64 = 300;
+	const MAX_HANDSHAKES: usize = 16;
+	const MIN_PEERS: usize = 25;
+
+	/// Simulate connection rounds against plenty of reachable candidates, each handshake taking
+	/// `HANDSHAKE_MS`. Returns the time `MIN_PEERS` is reached at in ms and the number of dials made.
+	fn time_to_min_peers(config: Option<StartupBurstConfig>) -> (u64, usize) {
+		let mut burst = StartupBurst::new(config.as_ref(), 0);
+		let mut handshakes: Vec<u64> = Vec::new();
+		let mut peers = 0;
+		let mut dials = 0;
+		let mut now = 0;
+		while peers < MIN_PEERS {
+			let finished = handshakes.iter().filter(|started| **started + HANDSHAKE_MS * MS <= now).count();
+			handshakes.retain(|started| *started + HANDSHAKE_MS * MS > now);
+			peers += finished;
+
+			let burst_round = burst.is_active() && now % (burst.interval_ms() * MS) == 0;
+			if now % (MAINTENANCE_MS * MS) == 0 || burst_round {
+				let started = round_dials(burst.update(peers, MIN_PEERS, now), MAX_HANDSHAKES / 2, MAX_HANDSHAKES, handshakes.len());
+				handshakes.extend((0..started).map(|_| now));
+				dials += started;
+				assert!(handshakes.len() <= MAX_HANDSHAKES);
+			}
+			now += 100 * MS;
+			assert!(now < 600_000 * MS, "min_peers never reached");
+		}
+		(now / MS, dials)
+	}
+
+	#[test]
+	fn burst_reaches_min_peers_faster() {
+		let (steady_ms, steady_dials) = time_to_min_peers(None);
+		let (burst_ms, burst_dials) = time_to_min_peers(Some(StartupBurstConfig {
+			duration: Duration::from_secs(30),
+			interval: Duration::from_millis(200),
+			dials_per_round: 16,
+		}));
+		assert_eq!((steady_ms, steady_dials), (3400, 32));
+		// Two rounds of 16 dials, the second right after the first handshakes are done.
+		assert_eq!((burst_ms, burst_dials), (800, 32));
+	}
+
+	#[test]
+	fn burst_ends() {
+		let config = StartupBurstConfig {
+			duration: Duration::from_secs(10),
+			interval: Duration::from_millis(200),
+			dials_per_round: 16,
+		};
+		let mut burst = StartupBurst::new(Some(&config), 0);
+		assert_eq!(burst.update(0, MIN_PEERS, 9_000 * MS), Some(16));
+		assert_eq!(burst.update(MIN_PEERS, MIN_PEERS, 9_100 * MS), None);
+		assert!(!burst.is_active());
+		// Losing peers later doesn't restart it.
+		assert_eq!(burst.update(0, MIN_PEERS, 9_200 * MS), None);
+
+		let mut burst = StartupBurst::new(Some(&config), 0);
+		assert_eq!(burst.update(0, MIN_PEERS, 10_000 * MS), None);
+		assert!(!StartupBurst::new(None, 0).is_active());
+	}
+
+	/// Dials started in each of `ticks` maintenance rounds `interval_ms` apart, with handshakes
+	/// taking `handshake_ms`.
+	fn dials_per_tick(interval_ms: u64, max_dials_per_tick: usize, max_handshakes: usize, handshake_ms: u64, ticks: u64) -> Vec<usize> {
+		let mut handshakes: Vec<u64> = Vec::new();
+		(0..ticks).map(|tick| {
+			let now = tick * interval_ms * MS;
+			handshakes.retain(|started| *started + handshake_ms * MS > now);
+			let dials = round_dials(None, max_dials_per_tick, max_handshakes, handshakes.len());
+			handshakes.extend((0..dials).map(|_| now));
+			dials
+		}).collect()
+	}
+
+	#[test]
+	fn dial_pacing_follows_config() {
+		// Handshakes finish within a round, every round dials the configured number.
+		assert_eq!(dials_per_tick(500, 4, 10, 300, 5), vec![4, 4, 4, 4, 4]);
+		// Handshakes outlast two rounds, the handshake limit caps the third one.
+		assert_eq!(dials_per_tick(500, 4, 10, 1200, 5), vec![4, 4, 2, 4, 4]);
+		// Over the same 5 seconds, a faster cadence dials more.
+		let fast: usize = dials_per_tick(100, 2, 64, 300, 50).iter().sum();
+		let slow: usize = dials_per_tick(1000, 2, 64, 300, 5).iter().sum();
+		assert_eq!((fast, slow), (100, 10));
+		// The burst overrides the configured number, the handshake limit still applies.
+		assert_eq!(round_dials(Some(16), 4, 64, 60), 4);
+		assert_eq!(round_dials(Some(16), 4, 64, 0), 16);
+	}
+}
@@ -15,7 +15,578 @@
 // along with Parity.  If not, see <http://www.gnu.org/licenses/>.
 
 //! Network Statistics
+use std::fmt;
+use std::collections::HashMap;
 use std::sync::atomic::*;
+use parking_lot::{Mutex, RwLock};
+use time;
+use network::{DisconnectReason, ProtocolId};
+
+/// Number of `DisconnectReason` variants, including `Unknown`.
+const DISCONNECT_REASONS: usize = 14;
+/// Number of `HandshakeFailure` variants.
+const HANDSHAKE_FAILURES: usize = 9;
+/// Number of `DialFailure` variants.
+const DIAL_FAILURES: usize = 6;
+/// Number of minutes covered by the disconnect history.
+pub const DISCONNECT_HISTORY_MINUTES: usize = 60;
+/// Length of the short traffic rate window in seconds.
+const SHORT_RATE_SECS: u64 = 10;
+/// Length of the long traffic rate window in seconds.
+const LONG_RATE_SECS: u64 = 5 * 60;
+/// Per-second traffic buckets. The current second is kept besides the long window.
+const RATE_BUCKETS: usize = LONG_RATE_SECS as usize + 1;
+/// Number of minutes covered by the session churn counters.
+const CHURN_MINUTES: usize = 60;
+/// Sessions ending within this many seconds count as flapping.
+pub const FLAPPING_SESSION_SECS: u64 = 30;
+/// Number of session duration bins. Bin 0 holds sessions shorter than a second, bin `i` those
+/// of `2^(i-1)` up to `2^i` seconds and the last bin all longer ones.
+const DURATION_BINS: usize = 18;
+
+/// Reason a connection failed before the session was established.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeFailure {
+	/// Auth packet could not be decrypted or decoded, e.g. it was encrypted for a different node id.
+	AuthDecrypt = 0,
+	/// Ack packet could not be decrypted or decoded.
+	AckDecode = 1,
+	/// Handshake did not complete in time.
+	Timeout = 2,
+	/// Rejected because there are no free peer slots.
+	TooManyPeers = 3,
+	/// Rejected by the connection filter or the IP lists.
+	Filtered = 4,
+	/// Connected to ourselves, e.g. by dialing our own address under a different node id.
+	SelfConnection = 5,
+	/// Peer did not send its Hello packet in time after the encrypted connection was established.
+	HelloTimeout = 6,
+	/// Inbound peer other than ourselves presented our node id.
+	Impersonation = 7,
+	/// Peer sent a malformed Hello packet.
+	BadHello = 8,
+}
+
+/// Cause of a failed outbound connection attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DialFailure {
+	/// TCP connection refused.
+	Refused = 0,
+	/// TCP connection or handshake did not complete in time.
+	Timeout = 1,
+	/// Handshake failed, e.g. the ack could not be decrypted.
+	Auth = 2,
+	/// Peer did not answer our auth. The auth is encrypted for the node id we dialed, a peer with
+	/// another id can't read it.
+	WrongNodeId = 3,
+	/// Rejected by the connection filter, the IP lists or the node allowlist.
+	Filtered = 4,
+	/// Any other failure, including sessions lost after the handshake.
+	Other = 5,
+}
+
+/// Snapshot of dial failure counters.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DialFailures {
+	/// Refused TCP connections.
+	pub refused: usize,
+	/// TCP connection or handshake timeouts.
+	pub timeout: usize,
+	/// Handshake failures.
+	pub auth: usize,
+	/// Peers not answering our auth.
+	pub wrong_node_id: usize,
+	/// Rejections by the connection filter, the IP lists or the node allowlist.
+	pub filtered: usize,
+	/// Other failures.
+	pub other: usize,
+}
+
+impl DialFailures {
+	/// Total number of failures.
+	pub fn total(&self) -> usize {
+		self.refused + self.timeout + self.auth + self.wrong_node_id + self.filtered + self.other
+	}
+}
+
+impl fmt::Display for DialFailures {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "refused {}, timeout {}, auth {}, wrong node id {}, filtered {}, other {}",
+			self.refused, self.timeout, self.auth, self.wrong_node_id, self.filtered, self.other)
+	}
+}
+
+/// Snapshot of handshake failure counters.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeFailures {
+	/// Auth packet decryption or decoding failures.
+	pub auth_decrypt: usize,
+	/// Ack packet decryption or decoding failures.
+	pub ack_decode: usize,
+	/// Handshake timeouts.
+	pub timeout: usize,
+	/// Rejections for lack of peer slots.
+	pub too_many_peers: usize,
+	/// Rejections by the connection filter or the IP lists.
+	pub filtered: usize,
+	/// Connections to ourselves.
+	pub self_connection: usize,
+	/// Peers that did not send Hello in time.
+	pub hello_timeout: usize,
+	/// Inbound peers presenting our node id.
+	pub impersonation: usize,
+	/// Malformed Hello packets.
+	pub bad_hello: usize,
+}
+
+impl HandshakeFailures {
+	/// Total number of failures.
+	pub fn total(&self) -> usize {
+		self.auth_decrypt + self.ack_decode + self.timeout + self.too_many_peers + self.filtered + self.self_connection + self.hello_timeout + self.impersonation + self.bad_hello
+	}
+
+	/// Failures counted after the `earlier` snapshot was taken.
+	pub fn since(&self, earlier: &HandshakeFailures) -> HandshakeFailures {
+		HandshakeFailures {
+			auth_decrypt: self.auth_decrypt.saturating_sub(earlier.auth_decrypt),
+			ack_decode: self.ack_decode.saturating_sub(earlier.ack_decode),
+			timeout: self.timeout.saturating_sub(earlier.timeout),
+			too_many_peers: self.too_many_peers.saturating_sub(earlier.too_many_peers),
+			filtered: self.filtered.saturating_sub(earlier.filtered),
+			self_connection: self.self_connection.saturating_sub(earlier.self_connection),
+			hello_timeout: self.hello_timeout.saturating_sub(earlier.hello_timeout),
+			impersonation: self.impersonation.saturating_sub(earlier.impersonation),
+			bad_hello: self.bad_hello.saturating_sub(earlier.bad_hello),
+		}
+	}
+}
+
+impl fmt::Display for HandshakeFailures {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "auth {}, ack {}, timeout {}, too many peers {}, filtered {}, self {}, hello timeout {}, impersonation {}, bad hello {}",
+			self.auth_decrypt, self.ack_decode, self.timeout, self.too_many_peers, self.filtered, self.self_connection, self.hello_timeout, self.impersonation, self.bad_hello)
+	}
+}
+
+/// Side that ended a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectOrigin {
+	/// We sent the disconnect packet.
+	Local,
+	/// The peer sent the disconnect packet.
+	Remote,
+	/// The connection was lost or timed out without a disconnect packet.
+	Dropped,
+}
+
+/// Session disconnect counters by reason.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DisconnectCounts {
+	/// Disconnects sent by us, indexed by `DisconnectReason`.
+	pub local: [usize; DISCONNECT_REASONS],
+	/// Disconnects sent by the peer, indexed by `DisconnectReason`.
+	pub remote: [usize; DISCONNECT_REASONS],
+	/// Sessions that ended without a disconnect packet.
+	pub dropped: usize,
+}
+
+impl DisconnectCounts {
+	/// Number of disconnects with the given reason sent by us.
+	pub fn local(&self, reason: DisconnectReason) -> usize {
+		self.local[reason as usize]
+	}
+
+	/// Number of disconnects with the given reason sent by the peer.
+	pub fn remote(&self, reason: DisconnectReason) -> usize {
+		self.remote[reason as usize]
+	}
+
+	/// Total number of ended sessions.
+	pub fn total(&self) -> usize {
+		self.local.iter().sum::<usize>() + self.remote.iter().sum::<usize>() + self.dropped
+	}
+
+	fn note(&mut self, reason: Option<DisconnectReason>, origin: DisconnectOrigin) {
+		match (origin, reason) {
+			(DisconnectOrigin::Local, Some(reason)) => self.local[reason as usize] += 1,
+			(DisconnectOrigin::Remote, Some(reason)) => self.remote[reason as usize] += 1,
+			_ => self.dropped += 1,
+		}
+	}
+
+	fn add(&mut self, other: &DisconnectCounts) {
+		for i in 0..DISCONNECT_REASONS {
+			self.local[i] += other.local[i];
+			self.remote[i] += other.remote[i];
+		}
+		self.dropped += other.dropped;
+	}
+}
+
+/// Snapshot of the disconnect history.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisconnectHistory {
+	/// Per-minute counters of the last `DISCONNECT_HISTORY_MINUTES` minutes, oldest first.
+	/// The last entry is the current minute.
+	pub minutes: Vec<DisconnectCounts>,
+	/// Sum of `minutes`.
+	pub last_hour: DisconnectCounts,
+	/// Counters since the service was started.
+	pub total: DisconnectCounts,
+}
+
+/// Ring buffer of per-minute disconnect counters.
+#[derive(Debug)]
+struct DisconnectBuckets {
+	/// Counters indexed by minute modulo `DISCONNECT_HISTORY_MINUTES`.
+	buckets: Vec<DisconnectCounts>,
+	/// Minute of the most recent bucket.
+	current: u64,
+	total: DisconnectCounts,
+}
+
+impl Default for DisconnectBuckets {
+	fn default() -> Self {
+		DisconnectBuckets {
+			buckets: vec![DisconnectCounts::default(); DISCONNECT_HISTORY_MINUTES],
+			current: 0,
+			total: DisconnectCounts::default(),
+		}
+	}
+}
+
+impl DisconnectBuckets {
+	/// Move the window forward to `minute`, clearing buckets that fall out of it.
+	fn advance(&mut self, minute: u64) {
+		if minute <= self.current {
+			return;
+		}
+		let stale = ::std::cmp::min(minute - self.current, DISCONNECT_HISTORY_MINUTES as u64);
+		for m in 0..stale {
+			let index = ((self.current + 1 + m) % DISCONNECT_HISTORY_MINUTES as u64) as usize;
+			self.buckets[index] = DisconnectCounts::default();
+		}
+		self.current = minute;
+	}
+
+	/// Count a disconnect. Disconnects reported for a minute older than the current one are
+	/// counted in the current minute.
+	fn note(&mut self, minute: u64, reason: Option<DisconnectReason>, origin: DisconnectOrigin) {
+		self.advance(minute);
+		let index = (self.current % DISCONNECT_HISTORY_MINUTES as u64) as usize;
+		self.buckets[index].note(reason, origin);
+		self.total.note(reason, origin);
+	}
+
+	fn snapshot(&mut self, minute: u64) -> DisconnectHistory {
+		self.advance(minute);
+		let mut last_hour = DisconnectCounts::default();
+		let minutes: Vec<_> = (0..DISCONNECT_HISTORY_MINUTES as u64)
+			.map(|m| self.buckets[((self.current + 1 + m) % DISCONNECT_HISTORY_MINUTES as u64) as usize])
+			.collect();
+		for counts in &minutes {
+			last_hour.add(counts);
+		}
+		DisconnectHistory {
+			minutes: minutes,
+			last_hour: last_hour,
+			total: self.total,
+		}
+	}
+}
+
+/// Established and ended sessions of the last hour.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SessionChurn {
+	/// Sessions established in the last hour.
+	pub established: usize,
+	/// Sessions ended in the last hour.
+	pub ended: usize,
+	/// Mean duration of the sessions ended in the last hour, in seconds.
+	pub mean_duration_secs: u64,
+	/// Median duration of the sessions ended in the last hour, in seconds. Estimated from
+	/// duration bins of doubling width.
+	pub median_duration_secs: u64,
+	/// Fraction of the sessions ended in the last hour that lasted less than `FLAPPING_SESSION_SECS`.
+	pub flapping: f64,
+}
+
+/// Session counters of one minute.
+#[derive(Debug, Default, Clone, Copy)]
+struct ChurnCounts {
+	established: usize,
+	ended: usize,
+	/// Sum of the durations of ended sessions in seconds.
+	duration_secs: u64,
+	flapping: usize,
+	/// Ended sessions by duration bin.
+	durations: [usize; DURATION_BINS],
+}
+
+fn duration_bin(secs: u64) -> usize {
+	::std::cmp::min(64 - secs.leading_zeros() as usize, DURATION_BINS - 1)
+}
+
+/// Ring buffer of per-minute session counters.
+#[derive(Debug)]
+struct ChurnBuckets {
+	/// Counters indexed by minute modulo `CHURN_MINUTES`.
+	buckets: Vec<ChurnCounts>,
+	/// Minute of the most recent bucket.
+	current: u64,
+}
+
+impl Default for ChurnBuckets {
+	fn default() -> Self {
+		ChurnBuckets {
+			buckets: vec![ChurnCounts::default(); CHURN_MINUTES],
+			current: 0,
+		}
+	}
+}
+
+impl ChurnBuckets {
+	/// Move the window forward to `minute`, clearing buckets that fall out of it.
+	fn advance(&mut self, minute: u64) {
+		if minute <= self.current {
+			return;
+		}
+		let stale = ::std::cmp::min(minute - self.current, CHURN_MINUTES as u64);
+		for m in 0..stale {
+			let index = ((self.current + 1 + m) % CHURN_MINUTES as u64) as usize;
+			self.buckets[index] = ChurnCounts::default();
+		}
+		self.current = minute;
+	}
+
+	fn bucket(&mut self, minute: u64) -> &mut ChurnCounts {
+		self.advance(minute);
+		&mut self.buckets[(self.current % CHURN_MINUTES as u64) as usize]
+	}
+
+	fn established(&mut self, minute: u64) {
+		self.bucket(minute).established += 1;
+	}
+
+	fn ended(&mut self, minute: u64, duration_secs: u64) {
+		let counts = self.bucket(minute);
+		counts.ended += 1;
+		counts.duration_secs += duration_secs;
+		if duration_secs < FLAPPING_SESSION_SECS {
+			counts.flapping += 1;
+		}
+		counts.durations[duration_bin(duration_secs)] += 1;
+	}
+
+	fn snapshot(&mut self, minute: u64) -> SessionChurn {
+		self.advance(minute);
+		let mut total = ChurnCounts::default();
+		for counts in &self.buckets {
+			total.established += counts.established;
+			total.ended += counts.ended;
+			total.duration_secs += counts.duration_secs;
+			total.flapping += counts.flapping;
+			for (sum, count) in total.durations.iter_mut().zip(counts.durations.iter()) {
+				*sum += *count;
+			}
+		}
+		if total.ended == 0 {
+			return SessionChurn { established: total.established, ..Default::default() };
+		}
+		SessionChurn {
+			established: total.established,
+			ended: total.ended,
+			mean_duration_secs: total.duration_secs / total.ended as u64,
+			median_duration_secs: median_duration(&total.durations, total.ended),
+			flapping: total.flapping as f64 / total.ended as f64,
+		}
+	}
+}
+
+/// Estimate the median of `count` durations from their bins, assuming the durations of a bin are evenly spread.
+fn median_duration(durations: &[usize; DURATION_BINS], count: usize) -> u64 {
+	let rank = (count + 1) / 2;
+	let mut below = 0;
+	for (bin, &in_bin) in durations.iter().enumerate() {
+		if below + in_bin >= rank {
+			let low = if bin == 0 { 0 } else { 1u64 << (bin - 1) };
+			if bin == DURATION_BINS - 1 {
+				return low;
+			}
+			let width = (1u64 << bin) - low;
+			let position = (rank - below) as u64;
+			return low + width * (2 * position - 1) / (2 * in_bin as u64);
+		}
+		below += in_bin;
+	}
+	0
+}
+
+fn current_minute() -> u64 {
+	time::get_time().sec as u64 / 60
+}
+
+fn current_second() -> u64 {
+	time::get_time().sec as u64
+}
+
+/// Average traffic in bytes per second.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TrafficRate {
+	/// Bytes received per second.
+	pub recv: u64,
+	/// Bytes sent per second.
+	pub send: u64,
+}
+
+impl TrafficRate {
+	/// Bytes received and sent per second.
+	pub fn total(&self) -> u64 {
+		self.recv + self.send
+	}
+}
+
+/// Average traffic over the last 10 seconds and the last 5 minutes. The current second is not included.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TrafficRates {
+	/// Average over the last 10 seconds.
+	pub last_10s: TrafficRate,
+	/// Average over the last 5 minutes.
+	pub last_5min: TrafficRate,
+}
+
+/// Snapshot of traffic rates.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct NetworkRates {
+	/// Rates of all session traffic, including encryption and framing overhead.
+	pub total: TrafficRates,
+	/// Rates of protocol packet payloads, by protocol.
+	pub protocols: HashMap<ProtocolId, TrafficRates>,
+}
+
+/// Payload sizes of snappy compressed packets before and after compression.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct CompressionCounters {
+	/// Sent payload bytes before compression.
+	pub sent_uncompressed: u64,
+	/// Sent payload bytes after compression.
+	pub sent_compressed: u64,
+	/// Received payload bytes after decompression.
+	pub received_uncompressed: u64,
+	/// Received payload bytes before decompression.
+	pub received_compressed: u64,
+}
+
+impl CompressionCounters {
+	/// Bytes saved by compression in both directions. Incompressible payloads grow slightly when compressed.
+	pub fn saved(&self) -> u64 {
+		(self.sent_uncompressed + self.received_uncompressed).saturating_sub(self.sent_compressed + self.received_compressed)
+	}
+
+	/// Compressed size of the sent payloads relative to their uncompressed size. `None` if nothing was compressed.
+	pub fn send_ratio(&self) -> Option<f64> {
+		if self.sent_uncompressed == 0 {
+			return None;
+		}
+		Some(self.sent_compressed as f64 / self.sent_uncompressed as f64)
+	}
+
+	/// Count a compressed payload sent.
+	pub fn note_sent(&mut self, uncompressed: usize, compressed: usize) {
+		self.sent_uncompressed += uncompressed as u64;
+		self.sent_compressed += compressed as u64;
+	}
+
+	/// Count a compressed payload received.
+	pub fn note_received(&mut self, uncompressed: usize, compressed: usize) {
+		self.received_uncompressed += uncompressed as u64;
+		self.received_compressed += compressed as u64;
+	}
+}
+
+/// Traffic counted in one second.
+#[derive(Debug, Default)]
+struct RateBucket {
+	/// Second the counters belong to.
+	second: AtomicUsize,
+	recv: AtomicUsize,
+	send: AtomicUsize,
+}
+
+/// Ring buffer of per-second traffic counters. Counting is an atomic add into the bucket of the
+/// current second; the lock is only taken when a bucket is reused for a new second.
+#[derive(Debug)]
+struct RateBuckets {
+	/// Counters indexed by second modulo `RATE_BUCKETS`.
+	buckets: Vec<RateBucket>,
+	rollover: Mutex<()>,
+}
+
+impl Default for RateBuckets {
+	fn default() -> Self {
+		RateBuckets {
+			buckets: (0..RATE_BUCKETS).map(|_| RateBucket::default()).collect(),
+			rollover: Mutex::new(()),
+		}
+	}
+}
+
+impl RateBuckets {
+	/// Count traffic in the given second.
+	fn add(&self, second: u64, recv: usize, send: usize) {
+		let bucket = &self.buckets[(second % RATE_BUCKETS as u64) as usize];
+		if bucket.second.load(Ordering::Acquire) != second as usize {
+			let _rollover = self.rollover.lock();
+			if bucket.second.load(Ordering::Acquire) != second as usize {
+				bucket.recv.store(0, Ordering::Relaxed);
+				bucket.send.store(0, Ordering::Relaxed);
+				bucket.second.store(second as usize, Ordering::Release);
+			}
+		}
+		if recv != 0 {
+			bucket.recv.fetch_add(recv, Ordering::Relaxed);
+		}
+		if send != 0 {
+			bucket.send.fetch_add(send, Ordering::Relaxed);
+		}
+	}
+
+	/// Counters of a completed second. Zero if nothing was counted in that second.
+	fn get(&self, second: u64) -> (u64, u64) {
+		let bucket = &self.buckets[(second % RATE_BUCKETS as u64) as usize];
+		if bucket.second.load(Ordering::Acquire) != second as usize {
+			return (0, 0);
+		}
+		let counters = (bucket.recv.load(Ordering::Relaxed) as u64, bucket.send.load(Ordering::Relaxed) as u64);
+		// Reused for a later second while reading.
+		if bucket.second.load(Ordering::Acquire) != second as usize {
+			return (0, 0);
+		}
+		counters
+	}
+
+	/// Average rates over the windows ending before the second `now`. Both windows are computed from the
+	/// same bucket reads, so the short window is always part of the long one.
+	fn rates(&self, now: u64) -> TrafficRates {
+		let mut short = (0, 0);
+		let mut long = (0, 0);
+		for age in 1..(LONG_RATE_SECS + 1) {
+			if age > now {
+				break;
+			}
+			let (recv, send) = self.get(now - age);
+			if age <= SHORT_RATE_SECS {
+				short = (short.0 + recv, short.1 + send);
+			}
+			long = (long.0 + recv, long.1 + send);
+		}
+		TrafficRates {
+			last_10s: TrafficRate { recv: short.0 / SHORT_RATE_SECS, send: short.1 / SHORT_RATE_SECS },
+			last_5min: TrafficRate { recv: long.0 / LONG_RATE_SECS, send: long.1 / LONG_RATE_SECS },
+		}
+	}
+}
 
 /// Network statistics structure
 #[derive(Default, Debug)]
@@ -26,6 +597,46 @@ pub struct NetworkStats {
 	send: AtomicUsize,
 	/// Total number of sessions created
 	sessions: AtomicUsize,
+	/// Number of sessions disconnected for exceeding the rate limit
+	rate_limited: AtomicUsize,
+	/// Number of times reading from a session was paused by the rate limit
+	throttled: AtomicUsize,
+	/// Number of sessions rejected or disconnected by the connection filter
+	filtered: AtomicUsize,
+	/// Number of negotiated protocols refused by the connection filter
+	filtered_protocols: AtomicUsize,
+	/// Number of connections refused because all session slots were taken
+	at_capacity: AtomicUsize,
+	/// Number of repeated discovery pings
+	discovery_ping_retries: AtomicUsize,
+	/// Number of discovery nodes evicted after all pings failed
+	discovery_ping_failures: AtomicUsize,
+	/// Number of disconnects requested by protocol handlers, by reason
+	requested_disconnects: [AtomicUsize; DISCONNECT_REASONS],
+	/// Number of failed handshakes, by failure class
+	handshake_failures: [AtomicUsize; HANDSHAKE_FAILURES],
+	/// Number of failed outbound connection attempts, by cause
+	dial_failures: [AtomicUsize; DIAL_FAILURES],
+	/// Number of received packets with an id outside of all negotiated protocols
+	unknown_packets: AtomicUsize,
+	/// Number of handshakes in progress as of the last check
+	handshakes: AtomicUsize,
+	/// Per-minute counters of ended sessions, by reason
+	disconnects: Mutex<DisconnectBuckets>,
+	/// Per-minute counters of established and ended sessions with their durations
+	churn: Mutex<ChurnBuckets>,
+	/// Sent payload bytes of compressed sessions before compression
+	sent_uncompressed: AtomicUsize,
+	/// Sent payload bytes of compressed sessions after compression
+	sent_compressed: AtomicUsize,
+	/// Received payload bytes of compressed sessions after decompression
+	received_uncompressed: AtomicUsize,
+	/// Received payload bytes of compressed sessions before decompression
+	received_compressed: AtomicUsize,
+	/// Per-second counters of session traffic
+	rates: RateBuckets,
+	/// Per-second counters of protocol packet payloads, by protocol
+	protocol_rates: RwLock<HashMap<ProtocolId, RateBuckets>>,
 }
 
 impl NetworkStats {
@@ -33,12 +644,48 @@ impl NetworkStats {
 	#[inline]
 	pub fn inc_recv(&self, size: usize) {
 		self.recv.fetch_add(size, Ordering::Relaxed);
+		self.rates.add(current_second(), size, 0);
 	}
 
 	/// Increase bytes sent.
 	#[inline]
 	pub fn inc_send(&self, size: usize) {
 		self.send.fetch_add(size, Ordering::Relaxed);
+		self.rates.add(current_second(), 0, size);
+	}
+
+	/// Count a protocol packet payload received.
+	#[inline]
+	pub fn inc_protocol_recv(&self, protocol: ProtocolId, size: usize) {
+		self.add_protocol_traffic(current_second(), protocol, size, 0);
+	}
+
+	/// Count a protocol packet payload sent.
+	#[inline]
+	pub fn inc_protocol_send(&self, protocol: ProtocolId, size: usize) {
+		self.add_protocol_traffic(current_second(), protocol, 0, size);
+	}
+
+	fn add_protocol_traffic(&self, second: u64, protocol: ProtocolId, recv: usize, send: usize) {
+		if let Some(buckets) = self.protocol_rates.read().get(&protocol) {
+			buckets.add(second, recv, send);
+			return;
+		}
+		self.protocol_rates.write().entry(protocol).or_insert_with(RateBuckets::default).add(second, recv, send);
+	}
+
+	/// Count a compressed payload sent.
+	#[inline]
+	pub fn inc_compressed_send(&self, uncompressed: usize, compressed: usize) {
+		self.sent_uncompressed.fetch_add(uncompressed, Ordering::Relaxed);
+		self.sent_compressed.fetch_add(compressed, Ordering::Relaxed);
+	}
+
+	/// Count a compressed payload received.
+	#[inline]
+	pub fn inc_compressed_recv(&self, uncompressed: usize, compressed: usize) {
+		self.received_uncompressed.fetch_add(uncompressed, Ordering::Relaxed);
+		self.received_compressed.fetch_add(compressed, Ordering::Relaxed);
 	}
 
 	/// Increase number of sessions.
@@ -47,6 +694,93 @@ impl NetworkStats {
 		self.sessions.fetch_add(1, Ordering::Relaxed);
 	}
 
+	/// Increase number of sessions disconnected for exceeding the rate limit.
+	#[inline]
+	pub fn inc_rate_limited(&self) {
+		self.rate_limited.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Increase number of times reading was paused by the rate limit.
+	#[inline]
+	pub fn inc_throttled(&self) {
+		self.throttled.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Increase number of sessions rejected by the connection filter.
+	#[inline]
+	pub fn inc_filtered(&self) {
+		self.filtered.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Increase number of protocols refused by the connection filter.
+	#[inline]
+	pub fn inc_filtered_protocols(&self) {
+		self.filtered_protocols.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Increase number of connections refused because all session slots were taken.
+	#[inline]
+	pub fn inc_at_capacity(&self) {
+		self.at_capacity.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Increase number of received packets with an unknown id.
+	#[inline]
+	pub fn inc_unknown_packets(&self) {
+		self.unknown_packets.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Set number of handshakes in progress.
+	#[inline]
+	pub fn set_handshakes(&self, count: usize) {
+		self.handshakes.store(count, Ordering::Relaxed);
+	}
+
+	/// Increase number of repeated discovery pings.
+	#[inline]
+	pub fn inc_discovery_ping_retries(&self) {
+		self.discovery_ping_retries.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Increase number of discovery nodes that failed to respond to all pings.
+	#[inline]
+	pub fn inc_discovery_ping_failures(&self) {
+		self.discovery_ping_failures.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Increase number of disconnects requested by protocol handlers with the given reason.
+	#[inline]
+	pub fn inc_requested_disconnect(&self, reason: DisconnectReason) {
+		self.requested_disconnects[reason as usize].fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Increase number of failed handshakes of the given class.
+	#[inline]
+	pub fn inc_handshake_failure(&self, failure: HandshakeFailure) {
+		self.handshake_failures[failure as usize].fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Increase number of failed outbound connection attempts with the given cause.
+	#[inline]
+	pub fn inc_dial_failure(&self, failure: DialFailure) {
+		self.dial_failures[failure as usize].fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Count an ended session. `reason` is the disconnect reason sent or received, if any.
+	pub fn inc_disconnect(&self, reason: Option<DisconnectReason>, origin: DisconnectOrigin) {
+		self.disconnects.lock().note(current_minute(), reason, origin);
+	}
+
+	/// Count an established session.
+	pub fn inc_established(&self) {
+		self.churn.lock().established(current_minute());
+	}
+
+	/// Count an ended session that was established `duration_secs` seconds before.
+	pub fn inc_ended(&self, duration_secs: u64) {
+		self.churn.lock().ended(current_minute(), duration_secs);
+	}
+
 	/// Get bytes sent.
 	#[inline]
 	pub fn send(&self) -> usize {
@@ -65,12 +799,348 @@ impl NetworkStats {
 		self.sessions.load(Ordering::Relaxed)
 	}
 
+	/// Get number of sessions disconnected for exceeding the rate limit.
+	#[inline]
+	pub fn rate_limited(&self) -> usize {
+		self.rate_limited.load(Ordering::Relaxed)
+	}
+
+	/// Get number of times reading was paused by the rate limit.
+	#[inline]
+	pub fn throttled(&self) -> usize {
+		self.throttled.load(Ordering::Relaxed)
+	}
+
+	/// Get number of sessions rejected by the connection filter.
+	#[inline]
+	pub fn filtered(&self) -> usize {
+		self.filtered.load(Ordering::Relaxed)
+	}
+
+	/// Get number of negotiated protocols refused by the connection filter.
+	#[inline]
+	pub fn filtered_protocols(&self) -> usize {
+		self.filtered_protocols.load(Ordering::Relaxed)
+	}
+
+	/// Get number of connections refused because all session slots were taken.
+	#[inline]
+	pub fn at_capacity(&self) -> usize {
+		self.at_capacity.load(Ordering::Relaxed)
+	}
+
+	/// Get number of repeated discovery pings.
+	#[inline]
+	pub fn discovery_ping_retries(&self) -> usize {
+		self.discovery_ping_retries.load(Ordering::Relaxed)
+	}
+
+	/// Get number of discovery nodes that failed to respond to all pings.
+	#[inline]
+	pub fn discovery_ping_failures(&self) -> usize {
+		self.discovery_ping_failures.load(Ordering::Relaxed)
+	}
+
+	/// Get number of handshakes in progress. Updated when connections are accepted or dialed.
+	#[inline]
+	pub fn handshakes(&self) -> usize {
+		self.handshakes.load(Ordering::Relaxed)
+	}
+
+	/// Get number of received packets with an id outside of all negotiated protocols.
+	#[inline]
+	pub fn unknown_packets(&self) -> usize {
+		self.unknown_packets.load(Ordering::Relaxed)
+	}
+
+	/// Get number of disconnects requested by protocol handlers with the given reason.
+	#[inline]
+	pub fn requested_disconnects(&self, reason: DisconnectReason) -> usize {
+		self.requested_disconnects[reason as usize].load(Ordering::Relaxed)
+	}
+
+	/// Get number of failed handshakes by failure class.
+	pub fn handshake_failures(&self) -> HandshakeFailures {
+		let get = |failure: HandshakeFailure| self.handshake_failures[failure as usize].load(Ordering::Relaxed);
+		HandshakeFailures {
+			auth_decrypt: get(HandshakeFailure::AuthDecrypt),
+			ack_decode: get(HandshakeFailure::AckDecode),
+			timeout: get(HandshakeFailure::Timeout),
+			too_many_peers: get(HandshakeFailure::TooManyPeers),
+			filtered: get(HandshakeFailure::Filtered),
+			self_connection: get(HandshakeFailure::SelfConnection),
+			hello_timeout: get(HandshakeFailure::HelloTimeout),
+			impersonation: get(HandshakeFailure::Impersonation),
+			bad_hello: get(HandshakeFailure::BadHello),
+		}
+	}
+
+	/// Reset handshake failure counters. Returns the values before the reset.
+	pub fn reset_handshake_failures(&self) -> HandshakeFailures {
+		let take = |failure: HandshakeFailure| self.handshake_failures[failure as usize].swap(0, Ordering::Relaxed);
+		HandshakeFailures {
+			auth_decrypt: take(HandshakeFailure::AuthDecrypt),
+			ack_decode: take(HandshakeFailure::AckDecode),
+			timeout: take(HandshakeFailure::Timeout),
+			too_many_peers: take(HandshakeFailure::TooManyPeers),
+			filtered: take(HandshakeFailure::Filtered),
+			self_connection: take(HandshakeFailure::SelfConnection),
+			hello_timeout: take(HandshakeFailure::HelloTimeout),
+			impersonation: take(HandshakeFailure::Impersonation),
+			bad_hello: take(HandshakeFailure::BadHello),
+		}
+	}
+
+	/// Get number of failed outbound connection attempts by cause.
+	pub fn dial_failures(&self) -> DialFailures {
+		let get = |failure: DialFailure| self.dial_failures[failure as usize].load(Ordering::Relaxed);
+		DialFailures {
+			refused: get(DialFailure::Refused),
+			timeout: get(DialFailure::Timeout),
+			auth: get(DialFailure::Auth),
+			wrong_node_id: get(DialFailure::WrongNodeId),
+			filtered: get(DialFailure::Filtered),
+			other: get(DialFailure::Other),
+		}
+	}
+
+	/// Get payload sizes before and after compression, summed over all sessions with compression enabled.
+	pub fn compression(&self) -> CompressionCounters {
+		CompressionCounters {
+			sent_uncompressed: self.sent_uncompressed.load(Ordering::Relaxed) as u64,
+			sent_compressed: self.sent_compressed.load(Ordering::Relaxed) as u64,
+			received_uncompressed: self.received_uncompressed.load(Ordering::Relaxed) as u64,
+			received_compressed: self.received_compressed.load(Ordering::Relaxed) as u64,
+		}
+	}
+
+	/// Get ended sessions by reason for each minute of the last hour and since start.
+	pub fn disconnects(&self) -> DisconnectHistory {
+		self.disconnects.lock().snapshot(current_minute())
+	}
+
+	/// Get established and ended sessions of the last hour with the durations of the ended ones.
+	pub fn churn(&self) -> SessionChurn {
+		self.churn.lock().snapshot(current_minute())
+	}
+
+	/// Get average traffic rates of the last 10 seconds and 5 minutes, in total and by protocol.
+	pub fn rates(&self) -> NetworkRates {
+		self.rates_at(current_second())
+	}
+
+	fn rates_at(&self, now: u64) -> NetworkRates {
+		NetworkRates {
+			total: self.rates.rates(now),
+			protocols: self.protocol_rates.read().iter().map(|(p, buckets)| (*p, buckets.rates(now))).collect(),
+		}
+	}
+
 	/// Create a new empty instance.
 	pub fn new() -> NetworkStats {
 		NetworkStats {
 			recv: AtomicUsize::new(0),
 			send: AtomicUsize::new(0),
 			sessions: AtomicUsize::new(0),
+			rate_limited: AtomicUsize::new(0),
+			throttled: AtomicUsize::new(0),
+			filtered: AtomicUsize::new(0),
+			filtered_protocols: AtomicUsize::new(0),
+			at_capacity: AtomicUsize::new(0),
+			discovery_ping_retries: AtomicUsize::new(0),
+			discovery_ping_failures: AtomicUsize::new(0),
+			requested_disconnects: Default::default(),
+			handshake_failures: Default::default(),
+			dial_failures: Default::default(),
+			unknown_packets: AtomicUsize::new(0),
+			handshakes: AtomicUsize::new(0),
+			disconnects: Mutex::new(DisconnectBuckets::default()),
+			churn: Mutex::new(ChurnBuckets::default()),
+			sent_uncompressed: AtomicUsize::new(0),
+			sent_compressed: AtomicUsize::new(0),
+			received_uncompressed: AtomicUsize::new(0),
+			received_compressed: AtomicUsize::new(0),
+			rates: RateBuckets::default(),
+			protocol_rates: RwLock::new(HashMap::new()),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn disconnect_buckets() {
+		let mut buckets = DisconnectBuckets::default();
+		buckets.note(1000, Some(DisconnectReason::TooManyPeers), DisconnectOrigin::Remote);
+		buckets.note(1000, Some(DisconnectReason::TooManyPeers), DisconnectOrigin::Remote);
+		buckets.note(1000, Some(DisconnectReason::PingTimeout), DisconnectOrigin::Local);
+		buckets.note(1002, Some(DisconnectReason::BadProtocol), DisconnectOrigin::Local);
+		buckets.note(1002, None, DisconnectOrigin::Dropped);
+		// Late reports go to the current minute.
+		buckets.note(1001, Some(DisconnectReason::UselessPeer), DisconnectOrigin::Remote);
+
+		let history = buckets.snapshot(1002);
+		assert_eq!(history.minutes.len(), DISCONNECT_HISTORY_MINUTES);
+		let last = DISCONNECT_HISTORY_MINUTES - 1;
+		assert_eq!(history.minutes[last].local(DisconnectReason::BadProtocol), 1);
+		assert_eq!(history.minutes[last].remote(DisconnectReason::UselessPeer), 1);
+		assert_eq!(history.minutes[last].dropped, 1);
+		assert_eq!(history.minutes[last - 1].total(), 0);
+		assert_eq!(history.minutes[last - 2].remote(DisconnectReason::TooManyPeers), 2);
+		assert_eq!(history.minutes[last - 2].local(DisconnectReason::PingTimeout), 1);
+		assert_eq!(history.minutes[last - 2].local(DisconnectReason::TooManyPeers), 0);
+		assert_eq!(history.last_hour.total(), 6);
+		assert_eq!(history.total, history.last_hour);
+	}
+
+	#[test]
+	fn disconnect_buckets_roll_over() {
+		let mut buckets = DisconnectBuckets::default();
+		buckets.note(1000, Some(DisconnectReason::TooManyPeers), DisconnectOrigin::Remote);
+		buckets.note(1010, Some(DisconnectReason::PingTimeout), DisconnectOrigin::Local);
+
+		// Minute 1000 is the oldest one still in the window.
+		let history = buckets.snapshot(1000 + DISCONNECT_HISTORY_MINUTES as u64 - 1);
+		assert_eq!(history.minutes[0].remote(DisconnectReason::TooManyPeers), 1);
+		assert_eq!(history.minutes[10].local(DisconnectReason::PingTimeout), 1);
+		assert_eq!(history.last_hour.total(), 2);
+
+		let history = buckets.snapshot(1000 + DISCONNECT_HISTORY_MINUTES as u64);
+		assert_eq!(history.minutes[9].local(DisconnectReason::PingTimeout), 1);
+		assert_eq!(history.last_hour.total(), 1);
+
+		buckets.note(1100, Some(DisconnectReason::UselessPeer), DisconnectOrigin::Remote);
+		let history = buckets.snapshot(1100);
+		assert_eq!(history.last_hour.total(), 1);
+		assert_eq!(history.minutes[DISCONNECT_HISTORY_MINUTES - 1].remote(DisconnectReason::UselessPeer), 1);
+		assert_eq!(history.total.total(), 3);
+
+		// Idle for longer than the window.
+		let history = buckets.snapshot(5000);
+		assert_eq!(history.last_hour.total(), 0);
+		assert_eq!(history.total.total(), 3);
+		assert_eq!(history.total.remote(DisconnectReason::TooManyPeers), 1);
+	}
+
+	#[test]
+	fn disconnect_history_in_stats() {
+		let stats = NetworkStats::new();
+		stats.inc_disconnect(Some(DisconnectReason::ClientQuit), DisconnectOrigin::Remote);
+		stats.inc_disconnect(None, DisconnectOrigin::Dropped);
+		let history = stats.disconnects();
+		assert_eq!(history.total.remote(DisconnectReason::ClientQuit), 1);
+		assert_eq!(history.total.dropped, 1);
+		assert_eq!(history.last_hour.total(), 2);
+	}
+
+	#[test]
+	fn dial_failure_counters() {
+		let stats = NetworkStats::new();
+		stats.inc_dial_failure(DialFailure::Refused);
+		stats.inc_dial_failure(DialFailure::Refused);
+		stats.inc_dial_failure(DialFailure::WrongNodeId);
+		let failures = stats.dial_failures();
+		assert_eq!((failures.refused, failures.wrong_node_id, failures.timeout), (2, 1, 0));
+		assert_eq!(failures.total(), 3);
+		assert_eq!(failures.to_string(), "refused 2, timeout 0, auth 0, wrong node id 1, filtered 0, other 0");
+	}
+
+	#[test]
+	fn session_churn() {
+		let mut buckets = ChurnBuckets::default();
+		// Sessions started at minute 1000 and ended after the given number of seconds.
+		let durations = [5u64, 10, 20, 45, 100, 600];
+		for _ in &durations {
+			buckets.established(1000);
+		}
+		for &duration in &durations {
+			buckets.ended(1000 + duration / 60, duration);
+		}
+
+		let churn = buckets.snapshot(1010);
+		assert_eq!(churn.established, 6);
+		assert_eq!(churn.ended, 6);
+		assert_eq!(churn.mean_duration_secs, 130);
+		// The third shortest of 20 seconds, in the bin of 16 up to 32 seconds.
+		assert!(churn.median_duration_secs >= 16 && churn.median_duration_secs < 32);
+		assert_eq!(churn.flapping, 0.5);
+
+		// An hour later only the sessions ended after minute 1000 are counted.
+		let churn = buckets.snapshot(1060);
+		assert_eq!(churn.established, 0);
+		assert_eq!(churn.ended, 2);
+		assert_eq!(churn.mean_duration_secs, 350);
+		assert_eq!(churn.flapping, 0.0);
+
+		// Idle for longer than the window.
+		assert_eq!(buckets.snapshot(5000), SessionChurn::default());
+	}
+
+	#[test]
+	fn median_duration_estimate() {
+		let mut durations = [0usize; DURATION_BINS];
+		for &secs in &[0u64, 1, 3, 3, 1000, 1_000_000] {
+			durations[duration_bin(secs)] += 1;
+		}
+		assert_eq!(duration_bin(1_000_000), DURATION_BINS - 1);
+		// The third of six durations is the first of two in the bin of 2 up to 4 seconds.
+		assert_eq!(median_duration(&durations, 6), 2);
+		// Longer durations than the last bin bound are estimated as the bound.
+		durations[DURATION_BINS - 1] += 10;
+		assert_eq!(median_duration(&durations, 16), 1 << (DURATION_BINS - 2));
+	}
+
+	#[test]
+	fn rate_buckets() {
+		let buckets = RateBuckets::default();
+		// 1000 bytes received in each of the last 10 seconds, 30000 sent two minutes ago.
+		for second in 1990..2000 {
+			buckets.add(second, 1000, 0);
+		}
+		buckets.add(1880, 0, 30000);
+		// The current second is not included.
+		buckets.add(2000, 5000, 5000);
+
+		let rates = buckets.rates(2000);
+		assert_eq!(rates.last_10s, TrafficRate { recv: 1000, send: 0 });
+		assert_eq!(rates.last_5min, TrafficRate { recv: 10000 / 300, send: 100 });
+		assert_eq!(rates.last_5min.total(), 133);
+
+		// A minute later the short window is empty.
+		let rates = buckets.rates(2060);
+		assert_eq!(rates.last_10s.total(), 0);
+		assert_eq!(rates.last_5min, TrafficRate { recv: 15000 / 300, send: 35000 / 300 });
+	}
+
+	#[test]
+	fn rate_buckets_roll_over() {
+		let buckets = RateBuckets::default();
+		buckets.add(1000, 3000, 0);
+		assert_eq!(buckets.rates(1001).last_10s.recv, 300);
+		// The bucket of second 1000 is reused for a later second.
+		buckets.add(1000 + RATE_BUCKETS as u64, 0, 600);
+		assert_eq!(buckets.rates(1000 + RATE_BUCKETS as u64 + 1).last_10s, TrafficRate { recv: 0, send: 60 });
+		// Old seconds read as empty once their bucket is reused.
+		assert_eq!(buckets.rates(1001).last_10s.total(), 0);
+		// Idle for longer than the window.
+		assert_eq!(buckets.rates(5000).last_5min.total(), 0);
+	}
+
+	#[test]
+	fn protocol_rates() {
+		let stats = NetworkStats::new();
+		for second in 995..1000 {
+			stats.rates.add(second, 200, 100);
+			stats.add_protocol_traffic(second, *b"eth", 100, 0);
+			stats.add_protocol_traffic(second, *b"par", 0, 20);
 		}
+		let rates = stats.rates_at(1000);
+		assert_eq!(rates.total.last_10s, TrafficRate { recv: 100, send: 50 });
+		assert_eq!(rates.protocols.len(), 2);
+		assert_eq!(rates.protocols[b"eth"].last_10s, TrafficRate { recv: 50, send: 0 });
+		assert_eq!(rates.protocols[b"par"].last_10s, TrafficRate { recv: 0, send: 10 });
+		assert_eq!(rates.protocols[b"par"].last_5min.send, 0);
 	}
 }
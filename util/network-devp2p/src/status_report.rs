@@ -0,0 +1,119 @@
+// Copyright 2015-2017 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Network half of the status report for monitoring. Field names are serialized as they are
+//! and dashboards key on them, so they must not be renamed.
+
+use stats::NetworkStats;
+use discovery::DiscoveryStats;
+
+/// Discovery health.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct DiscoveryStatusReport {
+	/// Nodes in the routing table.
+	pub nodes: usize,
+	/// Nodes waiting to answer a ping before they are added to the table.
+	pub pending_bonds: usize,
+	/// Completed lookups.
+	pub lookups: u64,
+	/// Completed lookups that got at least one neighbour.
+	pub successful_lookups: u64,
+	/// Unix time in seconds of the last lookup that got at least one neighbour.
+	pub last_successful_lookup: Option<u64>,
+}
+
+impl<'a> From<&'a DiscoveryStats> for DiscoveryStatusReport {
+	fn from(stats: &'a DiscoveryStats) -> DiscoveryStatusReport {
+		DiscoveryStatusReport {
+			nodes: stats.nodes,
+			pending_bonds: stats.pending_bonds,
+			lookups: stats.lookups,
+			successful_lookups: stats.successful_lookups,
+			last_successful_lookup: stats.last_successful_lookup,
+		}
+	}
+}
+
+/// Peer counts, traffic and discovery health of the network service.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct NetworkStatusReport {
+	/// Connected peers.
+	pub peers: usize,
+	/// Connected peers we have dialed.
+	pub egress_peers: usize,
+	/// Connected peers that have dialed us.
+	pub ingress_peers: usize,
+	/// Configured minimum number of peers.
+	pub min_peers: u32,
+	/// Configured maximum number of peers.
+	pub max_peers: u32,
+	/// Handshakes in progress.
+	pub handshakes: usize,
+	/// Sessions established since start.
+	pub sessions: usize,
+	/// Failed handshakes since start or the last reset of the counters.
+	pub handshake_failures: usize,
+	/// Bytes received since start.
+	pub bytes_received: usize,
+	/// Bytes sent since start.
+	pub bytes_sent: usize,
+	/// Bytes received per second over the last 10 seconds.
+	pub receive_rate: u64,
+	/// Bytes sent per second over the last 10 seconds.
+	pub send_rate: u64,
+	/// Discovery health. `None` if discovery is disabled.
+	pub discovery: Option<DiscoveryStatusReport>,
+}
+
+/// Counters and traffic. Peer counts and discovery health are left empty.
+impl<'a> From<&'a NetworkStats> for NetworkStatusReport {
+	fn from(stats: &'a NetworkStats) -> NetworkStatusReport {
+		let rates = stats.rates().total.last_10s;
+		NetworkStatusReport {
+			handshakes: stats.handshakes(),
+			sessions: stats.sessions(),
+			handshake_failures: stats.handshake_failures().total(),
+			bytes_received: stats.recv(),
+			bytes_sent: stats.send(),
+			receive_rate: rates.recv,
+			send_rate: rates.send,
+			..NetworkStatusReport::default()
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json;
+
+	#[test]
+	fn serializes_stable_names() {
+		let stats = NetworkStats::new();
+		stats.inc_recv(100);
+		stats.inc_sessions();
+		let mut report = NetworkStatusReport::from(&stats);
+		report.peers = 3;
+		report.discovery = Some(DiscoveryStatusReport { nodes: 42, ..DiscoveryStatusReport::default() });
+
+		let json = serde_json::to_value(&report).unwrap();
+		assert_eq!(json["peers"], 3);
+		assert_eq!(json["sessions"], 1);
+		assert_eq!(json["bytes_received"], 100);
+		assert_eq!(json["discovery"]["nodes"], 42);
+		assert!(json["discovery"]["last_successful_lookup"].is_null());
+	}
+}
@@ -0,0 +1,147 @@
+// Copyright 2015-2017 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Protocol handler timers.
+
+use std::collections::HashMap;
+use io::{TimerToken, TOKENS_PER_HANDLER};
+use network::ProtocolId;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ProtocolTimer {
+	pub protocol: ProtocolId,
+	pub token: TimerToken, // Handler level token
+	pub once: bool,
+}
+
+/// Maps IO timer tokens to protocol handler timers. Every schedule gets its own IO token, so expirations
+/// of a cancelled or replaced schedule that are already queued are recognised and dropped.
+pub struct ProtocolTimers {
+	timers: HashMap<TimerToken, ProtocolTimer>,
+	first: TimerToken,
+	next: TimerToken,
+}
+
+impl ProtocolTimers {
+	/// Create an empty set allocating IO tokens from `first` up to `TOKENS_PER_HANDLER`.
+	pub fn new(first: TimerToken) -> ProtocolTimers {
+		ProtocolTimers {
+			timers: HashMap::new(),
+			first: first,
+			next: first,
+		}
+	}
+
+	fn find(&self, protocol: ProtocolId, token: TimerToken) -> Option<TimerToken> {
+		self.timers.iter().find(|&(_, t)| t.protocol == protocol && t.token == token).map(|(io_token, _)| *io_token)
+	}
+
+	/// Allocate the next free IO token. Tokens are reused only after the whole range has been cycled through.
+	fn allocate(&mut self) -> Option<TimerToken> {
+		for _ in self.first..TOKENS_PER_HANDLER {
+			let io_token = self.next;
+			self.next = if io_token + 1 >= TOKENS_PER_HANDLER { self.first } else { io_token + 1 };
+			if !self.timers.contains_key(&io_token) {
+				return Some(io_token);
+			}
+		}
+		None
+	}
+
+	/// Add a schedule for the handler token. Returns the IO token for the new schedule and the IO token
+	/// of the schedule it replaces, if any. `None` if all IO tokens are in use.
+	pub fn add(&mut self, protocol: ProtocolId, token: TimerToken, once: bool) -> Option<(TimerToken, Option<TimerToken>)> {
+		let replaced = self.cancel(protocol, token);
+		let io_token = match self.allocate() {
+			Some(io_token) => io_token,
+			None => return None,
+		};
+		self.timers.insert(io_token, ProtocolTimer { protocol: protocol, token: token, once: once });
+		Some((io_token, replaced))
+	}
+
+	/// Remove the schedule of the handler token. Returns its IO token.
+	pub fn cancel(&mut self, protocol: ProtocolId, token: TimerToken) -> Option<TimerToken> {
+		let io_token = self.find(protocol, token);
+		if let Some(ref io_token) = io_token {
+			self.timers.remove(io_token);
+		}
+		io_token
+	}
+
+	/// Remove all schedules of the protocol. Returns their IO tokens.
+	pub fn remove_protocol(&mut self, protocol: ProtocolId) -> Vec<TimerToken> {
+		let io_tokens: Vec<TimerToken> = self.timers.iter().filter(|&(_, t)| t.protocol == protocol).map(|(io_token, _)| *io_token).collect();
+		for io_token in &io_tokens {
+			self.timers.remove(io_token);
+		}
+		io_tokens
+	}
+
+	/// Returns the handler timer for an expired IO token and removes one-shot schedules.
+	/// `None` if the schedule has been cancelled or replaced.
+	pub fn expired(&mut self, io_token: TimerToken) -> Option<ProtocolTimer> {
+		let timer = self.timers.get(&io_token).cloned();
+		if let Some(ProtocolTimer { once: true, .. }) = timer {
+			self.timers.remove(&io_token);
+		}
+		timer
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const FIRST: TimerToken = TOKENS_PER_HANDLER - 4;
+
+	#[test]
+	fn one_shot_expires_once() {
+		let mut timers = ProtocolTimers::new(FIRST);
+		let (io_token, replaced) = timers.add(*b"tst", 1, true).unwrap();
+		assert_eq!(replaced, None);
+		assert_eq!(timers.expired(io_token), Some(ProtocolTimer { protocol: *b"tst", token: 1, once: true }));
+		assert_eq!(timers.expired(io_token), None);
+
+		let (io_token, _) = timers.add(*b"tst", 2, false).unwrap();
+		assert!(timers.expired(io_token).is_some());
+		assert!(timers.expired(io_token).is_some());
+	}
+
+	#[test]
+	fn cancelled_and_replaced_schedules_are_dropped() {
+		let mut timers = ProtocolTimers::new(FIRST);
+		let (first, _) = timers.add(*b"tst", 1, false).unwrap();
+		let (second, replaced) = timers.add(*b"tst", 1, false).unwrap();
+		assert_eq!(replaced, Some(first));
+		assert!(first != second);
+		assert_eq!(timers.expired(first), None);
+		assert_eq!(timers.cancel(*b"tst", 1), Some(second));
+		assert_eq!(timers.expired(second), None);
+		assert_eq!(timers.cancel(*b"tst", 1), None);
+	}
+
+	#[test]
+	fn tokens_are_recycled() {
+		let mut timers = ProtocolTimers::new(FIRST);
+		for token in 0..4 {
+			timers.add(*b"tst", token, false).unwrap();
+		}
+		assert_eq!(timers.add(*b"xyz", 0, false), None);
+		assert_eq!(timers.remove_protocol(*b"tst").len(), 4);
+		assert!(timers.add(*b"xyz", 0, false).is_some());
+	}
+}
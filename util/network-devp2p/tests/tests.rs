@@ -23,23 +23,58 @@ extern crate ethcore_logger;
 extern crate ethcore_network;
 extern crate ethcore_network_devp2p;
 extern crate ethkey;
+extern crate ethcrypto;
+extern crate keccak_hash;
+extern crate tempdir;
 
-use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
-use std::sync::Arc;
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{mpsc, Arc};
 use std::thread;
 use std::time::*;
 use parking_lot::Mutex;
 use ethcore_bytes::Bytes;
 use ethcore_network::*;
-use ethcore_network_devp2p::NetworkService;
-use ethkey::{Random, Generator};
-use io::TimerToken;
+use ethcore_network_devp2p::{NetworkService, ConnectionFilter, ConnectionDirection, ConnectionContext, FilterDecision, FilterStats, PeerProtocolInfo, HandshakeFailures, PeerCountEvent, NetworkEvent, EventReceiver, AddressSource, DialError, TraceEvent, TraceDirection, HostResolver, CompressionCounters, DialFailure, validate_node_url};
+use ethkey::{Random, Generator, KeyPair, Message, Public, sign};
+use io::{TimerToken, IoService};
+use tempdir::TempDir;
+
+/// Poll the condition until it holds. Fails the test if it does not within 20 seconds.
+macro_rules! wait_for {
+	($condition:expr) => {{
+		let start = Instant::now();
+		while !$condition {
+			assert!(start.elapsed() < Duration::from_secs(20), concat!("Timed out waiting for ", stringify!($condition)));
+			thread::sleep(Duration::from_millis(50));
+		}
+	}};
+}
+
+/// Check the condition every 50ms for the given time. Fails the test as soon as it does not hold.
+macro_rules! assert_holds {
+	($condition:expr, $period:expr) => {{
+		let start = Instant::now();
+		loop {
+			assert!($condition, concat!("No longer holds: ", stringify!($condition)));
+			if start.elapsed() >= $period {
+				break;
+			}
+			thread::sleep(Duration::from_millis(50));
+		}
+	}};
+}
 
 pub struct TestProtocol {
 	drop_session: bool,
 	pub packet: Mutex<Bytes>,
 	pub got_timeout: AtomicBool,
 	pub got_disconnect: AtomicBool,
+	pub disconnect_reason: Mutex<Option<DisconnectReason>>,
+	pub connected: AtomicUsize,
 }
 
 impl TestProtocol {
@@ -48,6 +83,8 @@ impl TestProtocol {
 			packet: Mutex::new(Vec::new()),
 			got_timeout: AtomicBool::new(false),
 			got_disconnect: AtomicBool::new(false),
+			disconnect_reason: Mutex::new(None),
+			connected: AtomicUsize::new(0),
 			drop_session: drop_session,
 		}
 	}
@@ -82,15 +119,17 @@ impl NetworkProtocolHandler for TestProtocol {
 	}
 
 	fn connected(&self, io: &NetworkContext, peer: &PeerId) {
-		assert!(io.peer_client_version(*peer).contains("Parity"));
+		self.connected.fetch_add(1, AtomicOrdering::SeqCst);
+		assert_eq!(io.peer_client_version(*peer).name(), "Parity-network");
 		if self.drop_session {
-			io.disconnect_peer(*peer)
+			io.disconnect_peer(*peer, DisconnectReason::DisconnectRequested, false)
 		} else {
 			io.respond(33, "hello".to_owned().into_bytes()).unwrap();
 		}
 	}
 
-	fn disconnected(&self, _io: &NetworkContext, _peer: &PeerId) {
+	fn disconnected(&self, io: &NetworkContext, peer: &PeerId) {
+		*self.disconnect_reason.lock() = io.session_info(*peer).and_then(|info| info.disconnect_reason);
 		self.got_disconnect.store(true, AtomicOrdering::Relaxed);
 	}
 
@@ -102,6 +141,295 @@ impl NetworkProtocolHandler for TestProtocol {
 }
 
 
+/// Sends a burst of packets to each connected peer and counts received packets.
+pub struct BlastProtocol {
+	burst: usize,
+	pub received: AtomicUsize,
+	pub got_disconnect: AtomicBool,
+	pub peers: Mutex<Vec<PeerId>>,
+	pub last_packet: Mutex<Bytes>,
+}
+
+impl BlastProtocol {
+	pub fn register(service: &mut NetworkService, burst: usize) -> Arc<BlastProtocol> {
+		let handler = Arc::new(BlastProtocol { burst: burst, received: AtomicUsize::new(0), got_disconnect: AtomicBool::new(false), peers: Mutex::new(Vec::new()), last_packet: Mutex::new(Vec::new()) });
+		service.register_protocol(handler.clone(), *b"bls", 1, &[1u8]).expect("Error registering test protocol handler");
+		handler
+	}
+
+	pub fn received(&self) -> usize {
+		self.received.load(AtomicOrdering::SeqCst)
+	}
+}
+
+impl NetworkProtocolHandler for BlastProtocol {
+	fn read(&self, _io: &NetworkContext, _peer: &PeerId, _packet_id: u8, data: &[u8]) {
+		*self.last_packet.lock() = data.to_vec();
+		self.received.fetch_add(1, AtomicOrdering::SeqCst);
+	}
+
+	fn connected(&self, io: &NetworkContext, peer: &PeerId) {
+		self.peers.lock().push(*peer);
+		for _ in 0..self.burst {
+			io.send(*peer, 0, vec![0u8; 64]).unwrap();
+		}
+	}
+
+	fn disconnected(&self, _io: &NetworkContext, peer: &PeerId) {
+		self.peers.lock().retain(|p| p != peer);
+		self.got_disconnect.store(true, AtomicOrdering::SeqCst);
+	}
+}
+
+/// Sends a packet to every connected peer on each timer tick and counts callbacks.
+pub struct CountingProtocol {
+	peers: Mutex<Vec<PeerId>>,
+	pub timeouts: AtomicUsize,
+	pub received: AtomicUsize,
+	/// Protocols negotiated with each peer, as seen when it connected.
+	pub peer_protocols: Mutex<Vec<Vec<(ProtocolId, u8)>>>,
+}
+
+impl CountingProtocol {
+	pub fn register(service: &mut NetworkService, protocol: ProtocolId) -> Arc<CountingProtocol> {
+		CountingProtocol::register_versions(service, protocol, &[1u8])
+	}
+
+	pub fn register_versions(service: &mut NetworkService, protocol: ProtocolId, versions: &[u8]) -> Arc<CountingProtocol> {
+		let handler = Arc::new(CountingProtocol { peers: Mutex::new(Vec::new()), timeouts: AtomicUsize::new(0), received: AtomicUsize::new(0), peer_protocols: Mutex::new(Vec::new()) });
+		service.register_protocol(handler.clone(), protocol, 1, versions).expect("Error registering test protocol handler");
+		handler
+	}
+
+	pub fn counts(&self) -> (usize, usize) {
+		(self.timeouts.load(AtomicOrdering::SeqCst), self.received.load(AtomicOrdering::SeqCst))
+	}
+}
+
+impl NetworkProtocolHandler for CountingProtocol {
+	fn initialize(&self, io: &NetworkContext, _host_info: &HostInfo) {
+		io.register_timer(0, 20).unwrap();
+	}
+
+	fn read(&self, _io: &NetworkContext, _peer: &PeerId, _packet_id: u8, _data: &[u8]) {
+		self.received.fetch_add(1, AtomicOrdering::SeqCst);
+	}
+
+	fn connected(&self, io: &NetworkContext, peer: &PeerId) {
+		self.peers.lock().push(*peer);
+		self.peer_protocols.lock().push(io.peer_protocols(*peer));
+	}
+
+	fn disconnected(&self, _io: &NetworkContext, peer: &PeerId) {
+		self.peers.lock().retain(|p| p != peer);
+	}
+
+	fn timeout(&self, io: &NetworkContext, _timer: TimerToken) {
+		self.timeouts.fetch_add(1, AtomicOrdering::SeqCst);
+		for peer in self.peers.lock().iter() {
+			io.send(*peer, 0, vec![1u8]).ok();
+		}
+	}
+}
+
+/// Records the ids of received packets.
+pub struct RecordingProtocol {
+	pub peers: Mutex<Vec<PeerId>>,
+	pub packets: Mutex<Vec<u8>>,
+}
+
+impl RecordingProtocol {
+	pub fn register(service: &mut NetworkService, protocol: ProtocolId, packet_count: u8) -> Arc<RecordingProtocol> {
+		let handler = Arc::new(RecordingProtocol { peers: Mutex::new(Vec::new()), packets: Mutex::new(Vec::new()) });
+		service.register_protocol_versions(handler.clone(), protocol, &[(1u8, packet_count)]).expect("Error registering test protocol handler");
+		handler
+	}
+}
+
+impl NetworkProtocolHandler for RecordingProtocol {
+	fn read(&self, _io: &NetworkContext, _peer: &PeerId, packet_id: u8, _data: &[u8]) {
+		self.packets.lock().push(packet_id);
+	}
+
+	fn connected(&self, _io: &NetworkContext, peer: &PeerId) {
+		self.peers.lock().push(*peer);
+	}
+
+	fn disconnected(&self, _io: &NetworkContext, peer: &PeerId) {
+		self.peers.lock().retain(|p| p != peer);
+	}
+}
+
+/// Answers requests with the payload reversed, unless silent. Records responses and timeouts.
+pub struct RequestProtocol {
+	silent: bool,
+	pub peers: Mutex<Vec<PeerId>>,
+	pub responses: Mutex<Vec<(RequestHandle, Bytes)>>,
+	pub timed_out: Mutex<Vec<RequestHandle>>,
+}
+
+impl RequestProtocol {
+	pub fn register(service: &mut NetworkService, silent: bool) -> Arc<RequestProtocol> {
+		let handler = Arc::new(RequestProtocol {
+			silent: silent,
+			peers: Mutex::new(Vec::new()),
+			responses: Mutex::new(Vec::new()),
+			timed_out: Mutex::new(Vec::new()),
+		});
+		service.register_protocol(handler.clone(), *b"req", 1, &[1u8]).expect("Error registering test protocol handler");
+		handler
+	}
+}
+
+impl NetworkProtocolHandler for RequestProtocol {
+	fn read(&self, io: &NetworkContext, peer: &PeerId, packet_id: u8, data: &[u8]) {
+		let (request, mut payload) = RequestHandle::decode(*peer, packet_id, data).expect("Only requests are read");
+		if !self.silent {
+			payload.reverse();
+			io.respond_to(request, payload).unwrap();
+		}
+	}
+
+	fn connected(&self, _io: &NetworkContext, peer: &PeerId) {
+		self.peers.lock().push(*peer);
+	}
+
+	fn disconnected(&self, _io: &NetworkContext, _peer: &PeerId) {}
+
+	fn response(&self, _io: &NetworkContext, _peer: &PeerId, request: RequestHandle, data: &[u8]) {
+		self.responses.lock().push((request, data.to_vec()));
+	}
+
+	fn request_timed_out(&self, _io: &NetworkContext, _peer: &PeerId, request: RequestHandle) {
+		self.timed_out.lock().push(request);
+	}
+}
+
+const ONE_SHOT_TIMER: TimerToken = 1;
+const CANCELLED_TIMER: TimerToken = 2;
+const SELF_CANCELLING_TIMER: TimerToken = 3;
+const REPLACED_TIMER: TimerToken = 4;
+
+/// Records timer ticks.
+pub struct TimerProtocol {
+	pub ticks: Mutex<Vec<TimerToken>>,
+}
+
+impl TimerProtocol {
+	pub fn register(service: &mut NetworkService) -> Arc<TimerProtocol> {
+		let handler = Arc::new(TimerProtocol { ticks: Mutex::new(Vec::new()) });
+		service.register_protocol(handler.clone(), *b"tmr", 1, &[1u8]).expect("Error registering test protocol handler");
+		handler
+	}
+
+	pub fn count(&self, token: TimerToken) -> usize {
+		self.ticks.lock().iter().filter(|t| **t == token).count()
+	}
+}
+
+impl NetworkProtocolHandler for TimerProtocol {
+	fn initialize(&self, io: &NetworkContext, _host_info: &HostInfo) {
+		io.register_timer_once(ONE_SHOT_TIMER, Duration::from_millis(10)).unwrap();
+		io.register_timer(CANCELLED_TIMER, 10).unwrap();
+		io.cancel_timer(CANCELLED_TIMER).unwrap();
+		io.register_timer(SELF_CANCELLING_TIMER, 10).unwrap();
+		io.register_timer(REPLACED_TIMER, 10).unwrap();
+		io.register_timer_once(REPLACED_TIMER, Duration::from_millis(30)).unwrap();
+	}
+
+	fn timeout(&self, io: &NetworkContext, timer: TimerToken) {
+		self.ticks.lock().push(timer);
+		if timer == SELF_CANCELLING_TIMER {
+			io.cancel_timer(SELF_CANCELLING_TIMER).unwrap();
+			// Let further ticks expire while the handler is busy.
+			thread::sleep(Duration::from_millis(50));
+		}
+	}
+}
+
+/// Records the peer address and direction seen by the handler.
+#[derive(Default)]
+pub struct AddressProtocol {
+	pub connected: Mutex<Option<(Option<SocketAddr>, Option<ConnectionDirection>, String)>>,
+	pub disconnected: Mutex<Option<(Option<SocketAddr>, Option<ConnectionDirection>)>>,
+}
+
+impl AddressProtocol {
+	pub fn register(service: &mut NetworkService) -> Arc<AddressProtocol> {
+		let handler = Arc::new(AddressProtocol::default());
+		service.register_protocol(handler.clone(), *b"adr", 1, &[1u8]).expect("Error registering test protocol handler");
+		handler
+	}
+}
+
+impl NetworkProtocolHandler for AddressProtocol {
+	fn read(&self, _io: &NetworkContext, _peer: &PeerId, _packet_id: u8, _data: &[u8]) {}
+
+	fn connected(&self, io: &NetworkContext, peer: &PeerId) {
+		let local_address = io.session_info(*peer).map(|i| i.local_address).unwrap_or_default();
+		*self.connected.lock() = Some((io.peer_socket_addr(*peer), io.peer_direction(*peer), local_address));
+	}
+
+	fn disconnected(&self, io: &NetworkContext, peer: &PeerId) {
+		*self.disconnected.lock() = Some((io.peer_socket_addr(*peer), io.peer_direction(*peer)));
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerEvent {
+	Connected,
+	Tick,
+	Disconnected,
+}
+
+/// Records connections and peer ticks in order.
+#[derive(Default)]
+pub struct TickProtocol {
+	pub events: Mutex<Vec<(PeerId, PeerEvent)>>,
+}
+
+impl TickProtocol {
+	pub fn register(service: &mut NetworkService, peer_tick: Option<Duration>) -> Arc<TickProtocol> {
+		let handler = Arc::new(TickProtocol::default());
+		let result = match peer_tick {
+			Some(interval) => service.register_protocol_with_peer_tick(handler.clone(), *b"tck", &[(1u8, 1u8)], interval),
+			None => service.register_protocol_versions(handler.clone(), *b"tck", &[(1u8, 1u8)]),
+		};
+		result.expect("Error registering test protocol handler");
+		handler
+	}
+
+	pub fn count(&self, peer: PeerId, event: PeerEvent) -> usize {
+		self.events.lock().iter().filter(|e| **e == (peer, event)).count()
+	}
+
+	/// Check that ticks were only delivered to connected peers.
+	pub fn ticked_while_connected(&self) -> bool {
+		let mut connected = HashSet::new();
+		self.events.lock().iter().all(|&(peer, event)| match event {
+			PeerEvent::Connected => connected.insert(peer),
+			PeerEvent::Tick => connected.contains(&peer),
+			PeerEvent::Disconnected => connected.remove(&peer),
+		})
+	}
+}
+
+impl NetworkProtocolHandler for TickProtocol {
+	fn read(&self, _io: &NetworkContext, _peer: &PeerId, _packet_id: u8, _data: &[u8]) {}
+
+	fn connected(&self, _io: &NetworkContext, peer: &PeerId) {
+		self.events.lock().push((*peer, PeerEvent::Connected));
+	}
+
+	fn disconnected(&self, _io: &NetworkContext, peer: &PeerId) {
+		self.events.lock().push((*peer, PeerEvent::Disconnected));
+	}
+
+	fn peer_tick(&self, _io: &NetworkContext, peer: &PeerId) {
+		self.events.lock().push((*peer, PeerEvent::Tick));
+	}
+}
+
 #[test]
 fn net_service() {
 	let service = NetworkService::new(NetworkConfiguration::new_local(), None).expect("Error creating network service");
@@ -125,13 +453,34 @@ fn net_connect() {
 	let mut service2 = NetworkService::new(config2, None).unwrap();
 	service2.start().unwrap();
 	let handler2 = TestProtocol::register(&mut service2, false);
-	while !handler1.got_packet() && !handler2.got_packet() && (service1.stats().sessions() == 0 || service2.stats().sessions() == 0) {
-		thread::sleep(Duration::from_millis(50));
-	}
+	wait_for!(handler1.got_packet() || handler2.got_packet() || (service1.stats().sessions() != 0 && service2.stats().sessions() != 0));
 	assert!(service1.stats().sessions() >= 1);
 	assert!(service2.stats().sessions() >= 1);
 }
 
+#[test]
+fn net_maintenance_pace() {
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.maintain_interval = Duration::from_millis(10);
+	config1.max_dials_per_tick = 0;
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let _handler1 = TestProtocol::register(&mut service1, false);
+	// Out of bounds values are raised to the minimum.
+	assert_eq!(service1.maintenance_pace(), (Duration::from_millis(100), 1));
+
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.boot_nodes = vec![service1.local_url().unwrap()];
+	config2.maintain_interval = Duration::from_secs(3600);
+	config2.startup_burst = None;
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let _handler2 = TestProtocol::register(&mut service2, false);
+	service2.set_maintenance_pace(Duration::from_millis(200), 4);
+	assert_eq!(service2.maintenance_pace(), (Duration::from_millis(200), 4));
+	wait_for!(service1.stats().sessions() != 0 && service2.stats().sessions() != 0);
+}
+
 #[test]
 fn net_start_stop() {
 	let config = NetworkConfiguration::new_local();
@@ -155,9 +504,7 @@ fn net_disconnect() {
 	let mut service2 = NetworkService::new(config2, None).unwrap();
 	service2.start().unwrap();
 	let handler2 = TestProtocol::register(&mut service2, true);
-	while !(handler1.got_disconnect() && handler2.got_disconnect()) {
-		thread::sleep(Duration::from_millis(50));
-	}
+	wait_for!(handler1.got_disconnect() && handler2.got_disconnect());
 	assert!(handler1.got_disconnect());
 	assert!(handler2.got_disconnect());
 }
@@ -168,7 +515,2219 @@ fn net_timeout() {
 	let mut service = NetworkService::new(config, None).unwrap();
 	service.start().unwrap();
 	let handler = TestProtocol::register(&mut service, false);
-	while !handler.got_timeout() {
-		thread::sleep(Duration::from_millis(50));
+	wait_for!(handler.got_timeout());
+}
+
+#[test]
+fn net_connect_multiple_listen_addresses() {
+	let key1 = Random.generate().unwrap();
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.use_secret = Some(key1.secret().clone());
+	config1.listen_address = Some(SocketAddr::from_str("127.0.0.1:30421").unwrap());
+	config1.additional_listen_addresses = vec![SocketAddr::from_str("127.0.0.2:30421").unwrap()];
+	config1.discovery_enabled = false;
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let _handler1 = TestProtocol::register(&mut service1, false);
+
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.boot_nodes = vec![format!("enode://{}@127.0.0.1:30421", key1.public().hex())];
+	config2.discovery_enabled = false;
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let _handler2 = TestProtocol::register(&mut service2, false);
+
+	let mut config3 = NetworkConfiguration::new_local();
+	config3.boot_nodes = vec![format!("enode://{}@127.0.0.2:30421", key1.public().hex())];
+	config3.discovery_enabled = false;
+	let mut service3 = NetworkService::new(config3, None).unwrap();
+	service3.start().unwrap();
+	let _handler3 = TestProtocol::register(&mut service3, false);
+
+	wait_for!(service1.stats().sessions() >= 2 && service2.stats().sessions() != 0 && service3.stats().sessions() != 0);
+	assert!(service1.connected_peers().len() >= 2);
+}
+
+#[test]
+fn net_graceful_shutdown() {
+	let key1 = Random.generate().unwrap();
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.use_secret = Some(key1.secret().clone());
+	config1.boot_nodes = vec![ ];
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let handler1 = TestProtocol::register(&mut service1, false);
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.boot_nodes = vec![ service1.local_url().unwrap() ];
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let handler2 = TestProtocol::register(&mut service2, false);
+	wait_for!(handler1.got_packet() && handler2.got_packet());
+	service2.stop().unwrap();
+	assert!(handler2.got_disconnect());
+	wait_for!(handler1.got_disconnect());
+	assert_eq!(*handler1.disconnect_reason.lock(), Some(DisconnectReason::ClientQuit));
+}
+
+/// Relays connections to `target`. Once muted, what the connecting side sends is read and dropped
+/// while the connection stays open, as if the peer had stopped answering.
+fn mute_proxy(target: SocketAddr) -> (SocketAddr, Arc<AtomicBool>) {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let address = listener.local_addr().unwrap();
+	let muted = Arc::new(AtomicBool::new(false));
+	let flag = muted.clone();
+	thread::spawn(move || {
+		for stream in listener.incoming() {
+			let client = match stream {
+				Ok(client) => client,
+				Err(_) => return,
+			};
+			let server = TcpStream::connect(target).unwrap();
+			let (mut client_read, mut server_write) = (client.try_clone().unwrap(), server.try_clone().unwrap());
+			let flag = flag.clone();
+			thread::spawn(move || {
+				let mut buf = [0u8; 4096];
+				loop {
+					let n = match client_read.read(&mut buf) {
+						Ok(0) | Err(_) => return,
+						Ok(n) => n,
+					};
+					if !flag.load(AtomicOrdering::SeqCst) && server_write.write_all(&buf[..n]).is_err() {
+						return;
+					}
+				}
+			});
+			let (mut server_read, mut client_write) = (server, client);
+			thread::spawn(move || { ::std::io::copy(&mut server_read, &mut client_write).ok(); });
+		}
+	});
+	(address, muted)
+}
+
+/// Network service with a test protocol handler, pinging every 200ms and dropping peers idle for 1.5s.
+fn keep_alive_service() -> (NetworkService, Arc<TestProtocol>) {
+	let mut config = NetworkConfiguration::new_local();
+	config.discovery_enabled = false;
+	config.ping_interval = Duration::from_millis(200);
+	config.session_idle_timeout = Duration::from_millis(1500);
+	let mut service = NetworkService::new(config, None).unwrap();
+	service.start().unwrap();
+	let handler = TestProtocol::register(&mut service, false);
+	(service, handler)
+}
+
+/// Dial `service` from `dialer` through a muting proxy. Returns the flag that mutes the dialer.
+fn dial_through_mute_proxy(dialer: &NetworkService, service: &NetworkService) -> Arc<AtomicBool> {
+	let (proxy, muted) = mute_proxy(service.local_addr().unwrap());
+	let url = format!("enode://{}@{}", service.node_id().unwrap().hex(), proxy);
+	let peer = dialer.connect_peer(&url, false).unwrap().recv_timeout(Duration::from_secs(10)).unwrap().unwrap();
+	assert_eq!(Some(peer.node_id), service.node_id());
+	muted
+}
+
+/// Wait for the `PeerDisconnected` event of the node. Panics if another peer is disconnected first.
+fn next_disconnect_of(events: &EventReceiver, node_id: &NodeId) -> Option<DisconnectReason> {
+	loop {
+		match next_event(events) {
+			NetworkEvent::PeerDisconnected { node_id: ref id, reason } => {
+				assert_eq!(id, node_id, "Unexpected disconnect");
+				return reason;
+			},
+			_ => continue,
+		}
+	}
+}
+
+#[test]
+fn net_idle_peer_disconnected() {
+	let (service1, _handler1) = keep_alive_service();
+	let events1 = service1.subscribe_events();
+	let (service2, _handler2) = keep_alive_service();
+	let muted = dial_through_mute_proxy(&service2, &service1);
+
+	muted.store(true, AtomicOrdering::SeqCst);
+	let muted_at = Instant::now();
+	let reason = next_disconnect_of(&events1, &service2.node_id().unwrap());
+	assert_eq!(reason, Some(DisconnectReason::PingTimeout));
+	// Dropped for idleness, at most one ping interval early as the last pong may predate muting.
+	assert!(muted_at.elapsed() >= Duration::from_millis(1300));
+}
+
+#[test]
+fn net_keep_alive_active_peer() {
+	let (service1, _handler1) = keep_alive_service();
+	let events1 = service1.subscribe_events();
+	let (service2, _handler2) = keep_alive_service();
+	let (service3, _handler3) = keep_alive_service();
+	dial_through_mute_proxy(&service2, &service1);
+	let muted = dial_through_mute_proxy(&service3, &service1);
+
+	// A silent peer connected at the same time is the clock: once it is dropped for idleness, the
+	// pinging peer has outlived the idle timeout too, and must still be connected.
+	muted.store(true, AtomicOrdering::SeqCst);
+	assert_eq!(next_disconnect_of(&events1, &service3.node_id().unwrap()), Some(DisconnectReason::PingTimeout));
+	let peers: Vec<_> = service1.peers_info().into_iter().map(|p| p.id).collect();
+	assert_eq!(peers, vec![service2.node_id().unwrap().hex()]);
+}
+
+#[test]
+fn net_rate_limit_throttles() {
+	let key1 = Random.generate().unwrap();
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.use_secret = Some(key1.secret().clone());
+	config1.peer_rate_limit = Some(RateLimit { packets_per_sec: 20, bytes_per_sec: 0, hard_limit_secs: 60 });
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let handler1 = BlastProtocol::register(&mut service1, 5);
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.boot_nodes = vec![ service1.local_url().unwrap() ];
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let handler2 = BlastProtocol::register(&mut service2, 100);
+	wait_for!(handler1.received() >= 100 && handler2.received() >= 5);
+	// Reading from the flooding peer was paused, but nothing was dropped and the session stays up.
+	assert!(service1.stats().throttled() >= 1);
+	assert_eq!(service1.stats().rate_limited(), 0);
+	assert!(!handler1.got_disconnect.load(AtomicOrdering::SeqCst));
+	// The well-behaved direction is not limited.
+	assert_eq!(service2.stats().throttled(), 0);
+}
+
+#[test]
+fn net_rate_limit_disconnects() {
+	let key1 = Random.generate().unwrap();
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.use_secret = Some(key1.secret().clone());
+	config1.peer_rate_limit = Some(RateLimit { packets_per_sec: 10, bytes_per_sec: 0, hard_limit_secs: 1 });
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let handler1 = BlastProtocol::register(&mut service1, 0);
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.boot_nodes = vec![ service1.local_url().unwrap() ];
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let _handler2 = BlastProtocol::register(&mut service2, 500);
+	wait_for!(handler1.got_disconnect.load(AtomicOrdering::SeqCst));
+	assert_eq!(service1.stats().rate_limited(), 1);
+}
+
+#[test]
+fn net_peer_traffic() {
+	let key1 = Random.generate().unwrap();
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.use_secret = Some(key1.secret().clone());
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let handler1 = BlastProtocol::register(&mut service1, 7);
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.boot_nodes = vec![ service1.local_url().unwrap() ];
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let handler2 = BlastProtocol::register(&mut service2, 3);
+	wait_for!(handler1.received() >= 3 && handler2.received() >= 7);
+	let peer1 = service1.connected_peers()[0];
+	let peer2 = service2.connected_peers()[0];
+	let traffic1 = service1.with_context_eval(*b"bls", |io| io.peer_traffic(peer1)).unwrap();
+	let traffic2 = service2.with_context_eval(*b"bls", |io| io.peer_traffic(peer2)).unwrap();
+	assert_eq!(traffic1, PeerTraffic { packets_sent: 7, packets_received: 3, bytes_sent: 7 * 64, bytes_received: 3 * 64 });
+	assert_eq!(traffic2, PeerTraffic { packets_sent: 3, packets_received: 7, bytes_sent: 3 * 64, bytes_received: 7 * 64 });
+	// Counters are kept per protocol.
+	assert_eq!(service1.with_context_eval(*b"tst", |io| io.peer_traffic(peer1)).unwrap(), PeerTraffic::default());
+}
+
+struct DenyNode(NodeId);
+
+impl ConnectionFilter for DenyNode {
+	fn connection_allowed(&self, _own_id: &NodeId, connecting_id: &NodeId, _direction: ConnectionDirection) -> bool {
+		*connecting_id != self.0
+	}
+}
+
+#[test]
+fn net_set_connection_filter() {
+	let key1 = Random.generate().unwrap();
+	let key2 = Random.generate().unwrap();
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.use_secret = Some(key1.secret().clone());
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let handler1 = TestProtocol::register(&mut service1, false);
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.use_secret = Some(key2.secret().clone());
+	config2.boot_nodes = vec![ service1.local_url().unwrap() ];
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let handler2 = TestProtocol::register(&mut service2, false);
+	wait_for!(handler1.got_packet() && handler2.got_packet());
+
+	service1.set_connection_filter(Some(Arc::new(DenyNode(key2.public().clone()))), true);
+	assert!(handler1.got_disconnect());
+	wait_for!(handler2.got_disconnect());
+	// The peer is told a standard reason, the filtering is only known locally.
+	assert_eq!(*handler2.disconnect_reason.lock(), Some(DisconnectReason::UselessPeer));
+	assert!(service1.stats().disconnects().total.local(DisconnectReason::ConnectionFiltered) >= 1);
+
+	// service2 keeps dialing its boot node, but every attempt is refused.
+	wait_for!(service1.stats().filtered() >= 2);
+	assert_eq!(handler1.connected.load(AtomicOrdering::SeqCst), 1);
+}
+
+struct DenyAll;
+
+impl ConnectionFilter for DenyAll {
+	fn connection_allowed(&self, _own_id: &NodeId, _connecting_id: &NodeId, _direction: ConnectionDirection) -> bool {
+		false
 	}
 }
+
+#[test]
+fn net_filter_stats() {
+	let key2 = Random.generate().unwrap();
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.discovery_enabled = false;
+	config1.filter_audit_size = 4;
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let handler1 = TestProtocol::register(&mut service1, false);
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.discovery_enabled = false;
+	config2.use_secret = Some(key2.secret().clone());
+	config2.boot_nodes = vec![ service1.local_url().unwrap() ];
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let handler2 = TestProtocol::register(&mut service2, false);
+	wait_for!(handler1.got_packet() && handler2.got_packet());
+	assert_eq!(service1.filter_stats(), FilterStats::default());
+
+	// The established session is checked again and dropped.
+	service1.set_connection_filter(Some(Arc::new(DenyAll)), true);
+	let stats = service1.filter_stats();
+	assert_eq!(stats.dropped_sessions, 1);
+	assert_eq!(stats.denied_inbound, 1);
+	let denial = stats.recent_denials.iter().find(|d| d.direction == ConnectionDirection::Inbound).unwrap();
+	assert_eq!(denial.node_id, *key2.public());
+	assert!(denial.address.is_some());
+	assert_eq!(service1.stats().disconnects().total.local(DisconnectReason::ConnectionFiltered), 1);
+
+	// The nodes keep dialing each other, only the most recent denials are kept.
+	wait_for!({ let stats = service1.filter_stats(); stats.denied_inbound + stats.denied_outbound >= 6 });
+	let stats = service1.filter_stats();
+	assert_eq!(stats.allowed_inbound + stats.allowed_outbound, 0);
+	assert_eq!(stats.recent_denials.len(), 4);
+	assert!(stats.recent_denials.iter().all(|d| d.node_id == *key2.public()));
+	assert!(stats.recent_denials.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+}
+
+/// Decides on a thread of its own after a delay, or never if there is no decision.
+struct DelayedFilter {
+	delay: Duration,
+	decision: Option<bool>,
+	undecided: Mutex<Vec<mpsc::Sender<bool>>>,
+	/// Number of decisions asked for.
+	asked: Arc<AtomicUsize>,
+}
+
+impl DelayedFilter {
+	fn new(delay: Duration, decision: Option<bool>) -> DelayedFilter {
+		DelayedFilter { delay: delay, decision: decision, undecided: Mutex::new(Vec::new()), asked: Arc::new(AtomicUsize::new(0)) }
+	}
+}
+
+impl ConnectionFilter for DelayedFilter {
+	fn connection_decision(&self, _context: &ConnectionContext) -> FilterDecision {
+		self.asked.fetch_add(1, AtomicOrdering::SeqCst);
+		let (sender, receiver) = mpsc::channel();
+		match self.decision {
+			Some(allowed) => {
+				let delay = self.delay;
+				thread::spawn(move || {
+					thread::sleep(delay);
+					sender.send(allowed).ok();
+				});
+			},
+			None => self.undecided.lock().push(sender),
+		}
+		FilterDecision::Pending(receiver)
+	}
+}
+
+/// Start a service with the filter and connect a second one to it.
+fn connect_filtered(filter: DelayedFilter, decision_timeout: Duration) -> (NetworkService, Arc<TestProtocol>, NetworkService, Arc<TestProtocol>) {
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.discovery_enabled = false;
+	config1.filter_decision_timeout = decision_timeout;
+	let mut service1 = NetworkService::new(config1, Some(Arc::new(filter))).unwrap();
+	service1.start().unwrap();
+	let handler1 = TestProtocol::register(&mut service1, false);
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.discovery_enabled = false;
+	config2.boot_nodes = vec![ service1.local_url().unwrap() ];
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let handler2 = TestProtocol::register(&mut service2, false);
+	(service1, handler1, service2, handler2)
+}
+
+#[test]
+fn net_filter_decision_delayed() {
+	let filter = DelayedFilter::new(Duration::from_millis(800), Some(true));
+	let asked = filter.asked.clone();
+	let (service1, handler1, _service2, handler2) = connect_filtered(filter, Duration::from_secs(5));
+	wait_for!(asked.load(AtomicOrdering::SeqCst) != 0);
+	// The connection is held until the decision arrives.
+	assert_eq!(handler1.connected.load(AtomicOrdering::SeqCst), 0);
+	assert!(service1.peers_info().is_empty());
+	wait_for!(handler1.got_packet() && handler2.got_packet());
+	let stats = service1.filter_stats();
+	assert_eq!(stats.allowed_inbound, 1);
+	assert_eq!(stats.decision_timeouts, 0);
+	assert_eq!(service1.peers_info().len(), 1);
+}
+
+#[test]
+fn net_filter_decision_timeout() {
+	let (service1, handler1, _service2, handler2) = connect_filtered(DelayedFilter::new(Duration::from_millis(0), None), Duration::from_millis(300));
+	wait_for!(service1.filter_stats().decision_timeouts != 0);
+	// Timeouts are refusals.
+	wait_for!(handler2.got_disconnect());
+	let stats = service1.filter_stats();
+	assert!(stats.denied_inbound >= 1);
+	assert_eq!(stats.allowed_inbound + stats.allowed_outbound, 0);
+	assert_eq!(handler1.connected.load(AtomicOrdering::SeqCst), 0);
+	assert!(!handler2.got_packet());
+}
+
+struct DenyProtocols(Vec<ProtocolId>);
+
+impl ConnectionFilter for DenyProtocols {
+	fn protocol_allowed(&self, _node_id: &NodeId, protocol: ProtocolId, _direction: ConnectionDirection) -> bool {
+		!self.0.contains(&protocol)
+	}
+}
+
+#[test]
+fn net_protocol_filter() {
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.discovery_enabled = false;
+	let mut service1 = NetworkService::new(config1, Some(Arc::new(DenyProtocols(vec![*b"cnt"])))).unwrap();
+	service1.start().unwrap();
+	let handler1 = TestProtocol::register(&mut service1, false);
+	let counting1 = CountingProtocol::register(&mut service1, *b"cnt");
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.boot_nodes = vec![service1.local_url().unwrap()];
+	config2.discovery_enabled = false;
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let handler2 = TestProtocol::register(&mut service2, false);
+	let counting2 = CountingProtocol::register(&mut service2, *b"cnt");
+	wait_for!(handler1.got_packet() && handler2.got_packet());
+
+	// The peer still sends packets of the refused protocol, they are dropped.
+	let ticks = counting2.counts().0;
+	wait_for!(counting2.counts().0 >= ticks + 5);
+	let peers = service1.peers_info();
+	assert_eq!(peers.len(), 1);
+	assert_eq!(peers[0].protocols, vec![PeerProtocolInfo { protocol: "tst".to_owned(), version: 43 }]);
+	assert!(counting1.peers.lock().is_empty());
+	assert_eq!(counting1.counts().1, 0);
+	assert_eq!(service1.stats().unknown_packets(), 0);
+	assert_eq!(service1.stats().filtered_protocols(), 1);
+	assert!(!handler1.got_disconnect());
+
+	// Sessions without any allowed protocol are refused.
+	let mut config3 = NetworkConfiguration::new_local();
+	config3.discovery_enabled = false;
+	let mut service3 = NetworkService::new(config3, Some(Arc::new(DenyProtocols(vec![*b"cnt", *b"tst"])))).unwrap();
+	service3.start().unwrap();
+	let handler3 = TestProtocol::register(&mut service3, false);
+	CountingProtocol::register(&mut service3, *b"cnt");
+	service2.add_reserved_peer(&service3.local_url().unwrap()).unwrap();
+	wait_for!(service3.stats().filtered_protocols() >= 2);
+	assert_eq!(handler3.connected.load(AtomicOrdering::SeqCst), 0);
+	assert!(service3.peers_info().is_empty());
+}
+
+#[test]
+fn net_peers_info() {
+	let key1 = Random.generate().unwrap();
+	let key2 = Random.generate().unwrap();
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.use_secret = Some(key1.secret().clone());
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let handler1 = TestProtocol::register(&mut service1, false);
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.use_secret = Some(key2.secret().clone());
+	config2.boot_nodes = vec![ service1.local_url().unwrap() ];
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let handler2 = TestProtocol::register(&mut service2, false);
+	wait_for!(handler1.got_packet() && handler2.got_packet());
+
+	let peers1 = service1.peers_info();
+	let peers2 = service2.peers_info();
+	assert_eq!(peers1.len(), 1);
+	assert_eq!(peers2.len(), 1);
+	let tst = PeerProtocolInfo { protocol: "tst".to_owned(), version: 43 };
+
+	assert_eq!(peers1[0].id, key2.public().hex());
+	assert!(peers1[0].inbound);
+	assert!(peers1[0].protocols.contains(&tst));
+	assert_eq!(ClientVersion::from(peers1[0].client_version.as_str()).name(), "Parity-network");
+	assert!(peers1[0].connected_since > 0);
+	assert!(peers1[0].bytes_received > 0);
+
+	assert_eq!(peers2[0].id, key1.public().hex());
+	assert!(!peers2[0].inbound);
+	assert!(peers2[0].protocols.contains(&tst));
+	assert_eq!(peers2[0].enode, service1.local_url().unwrap());
+	assert!(peers2[0].bytes_sent > 0);
+}
+
+#[test]
+fn net_shared_io_service() {
+	let io_service = Arc::new(IoService::<NetworkIoMessage>::start().unwrap());
+	let key1 = Random.generate().unwrap();
+	let key2 = Random.generate().unwrap();
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.use_secret = Some(key1.secret().clone());
+	let mut service1 = NetworkService::new_with_io(config1, None, io_service.clone());
+	service1.start().unwrap();
+	let handler1 = TestProtocol::register(&mut service1, false);
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.use_secret = Some(key2.secret().clone());
+	config2.boot_nodes = vec![ service1.local_url().unwrap() ];
+	let mut service2 = NetworkService::new_with_io(config2, None, io_service.clone());
+	service2.start().unwrap();
+	let handler2 = TestProtocol::register(&mut service2, false);
+	wait_for!(handler1.got_packet() && handler2.got_packet());
+	assert_eq!(service1.peers_info().len(), 1);
+	assert_eq!(service1.peers_info()[0].id, key2.public().hex());
+	assert_eq!(service2.peers_info().len(), 1);
+	assert_eq!(service2.peers_info()[0].id, key1.public().hex());
+
+	// The disconnect request only reaches the host of service 2.
+	let peer = service2.connected_peers()[0];
+	service2.with_context(*b"tst", |io| io.disconnect_peer(peer, DisconnectReason::DisconnectRequested, false));
+	wait_for!(handler1.got_disconnect() && handler2.got_disconnect());
+	assert_eq!(*handler1.disconnect_reason.lock(), Some(DisconnectReason::DisconnectRequested));
+
+	// Stopping a service leaves the event loop running for the other one.
+	service1.stop().unwrap();
+	let mut config3 = NetworkConfiguration::new_local();
+	config3.boot_nodes = vec![ service2.local_url().unwrap() ];
+	let mut service3 = NetworkService::new_with_io(config3, None, io_service.clone());
+	service3.start().unwrap();
+	let handler3 = TestProtocol::register(&mut service3, false);
+	wait_for!(handler3.got_packet());
+}
+
+#[test]
+fn net_compression() {
+	let key2 = Random.generate().unwrap();
+	let key3 = Random.generate().unwrap();
+	let mut service1 = NetworkService::new(NetworkConfiguration::new_local(), None).unwrap();
+	service1.start().unwrap();
+	TestProtocol::register(&mut service1, false);
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.use_secret = Some(key2.secret().clone());
+	config2.boot_nodes = vec![ service1.local_url().unwrap() ];
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let handler2 = TestProtocol::register(&mut service2, false);
+	let mut config3 = NetworkConfiguration::new_local();
+	config3.use_secret = Some(key3.secret().clone());
+	config3.boot_nodes = vec![ service1.local_url().unwrap() ];
+	config3.compression = false;
+	let mut service3 = NetworkService::new(config3, None).unwrap();
+	service3.start().unwrap();
+	let handler3 = TestProtocol::register(&mut service3, false);
+	wait_for!(handler2.got_packet() && handler3.got_packet());
+
+	let peers1 = service1.peers_info();
+	let v5 = peers1.iter().find(|p| p.id == key2.public().hex()).unwrap();
+	assert_eq!(v5.p2p_version, 5);
+	assert!(v5.compression);
+	assert!(v5.compression_counters.sent_uncompressed > 0);
+	assert!(v5.compression_counters.sent_compressed > 0);
+	let v4 = peers1.iter().find(|p| p.id == key3.public().hex()).unwrap();
+	assert_eq!(v4.p2p_version, 4);
+	assert!(!v4.compression);
+	assert_eq!(v4.compression_counters, CompressionCounters::default());
+
+	let peer2 = service2.connected_peers()[0];
+	let peer3 = service3.connected_peers()[0];
+	assert!(service2.with_context_eval(*b"tst", |io| io.peer_compression_enabled(peer2)).unwrap());
+	assert!(!service3.with_context_eval(*b"tst", |io| io.peer_compression_enabled(peer3)).unwrap());
+	assert!(service2.peers_info()[0].compression_counters.received_uncompressed > 0);
+	assert!(service1.stats().compression().send_ratio().is_some());
+	assert_eq!(service3.stats().compression(), CompressionCounters::default());
+}
+
+#[test]
+fn net_disconnect_with_reason() {
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.boot_nodes = vec![ ];
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let handler1 = TestProtocol::register(&mut service1, false);
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.boot_nodes = vec![ service1.local_url().unwrap() ];
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let handler2 = TestProtocol::register(&mut service2, false);
+	wait_for!(handler1.got_packet() && handler2.got_packet());
+
+	let peer = service2.connected_peers()[0];
+	service2.with_context(*b"tst", |io| io.disconnect_peer(peer, DisconnectReason::UselessPeer, true));
+	wait_for!(handler1.got_disconnect() && handler2.got_disconnect());
+	assert_eq!(*handler1.disconnect_reason.lock(), Some(DisconnectReason::UselessPeer));
+	assert_eq!(*handler2.disconnect_reason.lock(), Some(DisconnectReason::UselessPeer));
+	assert_eq!(service2.stats().requested_disconnects(DisconnectReason::UselessPeer), 1);
+	assert_eq!(service2.stats().requested_disconnects(DisconnectReason::DisconnectRequested), 0);
+}
+
+#[test]
+fn net_socket_options() {
+	let socket_options = SocketOptions {
+		nodelay: Some(true),
+		keepalive: Some(Duration::from_secs(45)),
+		send_buffer_size: Some(48 * 1024),
+		recv_buffer_size: Some(40 * 1024),
+	};
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.socket_options = socket_options;
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let handler1 = TestProtocol::register(&mut service1, false);
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.boot_nodes = vec![ service1.local_url().unwrap() ];
+	config2.socket_options = socket_options;
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let handler2 = TestProtocol::register(&mut service2, false);
+	wait_for!(handler1.got_packet() && handler2.got_packet());
+
+	// Both the accepted and the dialed socket are configured.
+	for service in &[&service1, &service2] {
+		let peers = service.peers_info();
+		assert_eq!(peers.len(), 1);
+		let socket = &peers[0].socket;
+		assert_eq!(socket.nodelay, Some(true));
+		assert_eq!(socket.keepalive_secs, Some(45));
+		// Some systems report a larger value than requested to account for bookkeeping overhead.
+		assert!(socket.send_buffer_size.unwrap() >= 48 * 1024);
+		assert!(socket.recv_buffer_size.unwrap() >= 40 * 1024);
+	}
+}
+
+#[test]
+fn net_deregister_protocol() {
+	let mut service1 = NetworkService::new(NetworkConfiguration::new_local(), None).unwrap();
+	service1.start().unwrap();
+	let handler1 = TestProtocol::register(&mut service1, false);
+	let removed1 = CountingProtocol::register(&mut service1, *b"cn1");
+	let kept1 = CountingProtocol::register(&mut service1, *b"cn2");
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.boot_nodes = vec![ service1.local_url().unwrap() ];
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let handler2 = TestProtocol::register(&mut service2, false);
+	let removed2 = CountingProtocol::register(&mut service2, *b"cn1");
+	let kept2 = CountingProtocol::register(&mut service2, *b"cn2");
+	wait_for!(handler1.got_packet() && handler2.got_packet() && removed1.counts().1 != 0 && kept1.counts().1 != 0);
+
+	service1.deregister_protocol(*b"cn1").unwrap();
+	// Let events queued before the removal drain.
+	let ticks = kept1.counts().0;
+	wait_for!(kept1.counts().0 >= ticks + 3);
+	let removed_counts = removed1.counts();
+	let kept_counts = kept1.counts();
+	let remote_counts = removed2.counts();
+
+	// The remote handler keeps running; its packets are dropped.
+	wait_for!(kept1.counts().0 > kept_counts.0 && kept1.counts().1 > kept_counts.1 && removed2.counts().0 > remote_counts.0);
+	assert_eq!(removed1.counts(), removed_counts);
+	// Other protocols are still shared, so the session stays up.
+	assert!(!handler1.got_disconnect());
+	assert!(!handler2.got_disconnect());
+	assert!(kept2.counts().1 > 0);
+}
+
+/// Start `count` clients booting from `service`. Returns the clients with their handlers and ids.
+fn connect_clients(service: &NetworkService, count: usize, reserved: &[usize]) -> (Vec<(NetworkService, Arc<TestProtocol>)>, Vec<String>) {
+	let mut clients = Vec::new();
+	let mut ids = Vec::new();
+	for i in 0..count {
+		let key = Random.generate().unwrap();
+		let mut config = NetworkConfiguration::new_local();
+		config.use_secret = Some(key.secret().clone());
+		config.boot_nodes = vec![ service.local_url().unwrap() ];
+		let mut client = NetworkService::new(config, None).unwrap();
+		client.start().unwrap();
+		if reserved.contains(&i) {
+			service.add_reserved_peer(&client.local_url().unwrap()).unwrap();
+		}
+		let handler = TestProtocol::register(&mut client, false);
+		let id = key.public().hex();
+		wait_for!(service.peers_info().iter().any(|p| p.id == id));
+		clients.push((client, handler));
+		ids.push(id);
+	}
+	(clients, ids)
+}
+
+#[test]
+fn net_set_peer_limits() {
+	let mut service1 = NetworkService::new(NetworkConfiguration::new_local(), None).unwrap();
+	service1.start().unwrap();
+	let _handler1 = TestProtocol::register(&mut service1, false);
+	let has_peer = |service: &NetworkService, id: &str| service.peers_info().iter().any(|p| p.id == id);
+
+	let (_clients, ids) = connect_clients(&service1, 3, &[2]);
+
+	service1.set_peer_limits(0, 0);
+	assert_eq!(service1.peer_limits(), (0, 0));
+
+	// Peers are shed one at a time, longest connected first.
+	wait_for!(!has_peer(&service1, &ids[0]));
+	assert!(has_peer(&service1, &ids[1]));
+	wait_for!(!has_peer(&service1, &ids[1]));
+	assert!(!has_peer(&service1, &ids[0]));
+	// Reserved peers are kept.
+	assert_holds!(has_peer(&service1, &ids[2]) && !has_peer(&service1, &ids[0]) && !has_peer(&service1, &ids[1]), Duration::from_millis(1500));
+}
+
+#[test]
+fn net_reserved_peer_evicts() {
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.min_peers = 0;
+	config1.max_peers = 2;
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let _handler1 = TestProtocol::register(&mut service1, false);
+	let has_peer = |id: &str| service1.peers_info().iter().any(|p| p.id == id);
+	let (clients, ids) = connect_clients(&service1, 2, &[]);
+
+	// A reserved peer connecting to the full node replaces one of the others.
+	let (_reserved, reserved_ids) = connect_clients(&service1, 1, &[0]);
+	wait_for!(!has_peer(&ids[0]) || !has_peer(&ids[1]));
+	let (evicted, kept) = if has_peer(&ids[0]) { (1, 0) } else { (0, 1) };
+	wait_for!(clients[evicted].1.got_disconnect());
+	assert_eq!(*clients[evicted].1.disconnect_reason.lock(), Some(DisconnectReason::TooManyPeers));
+	assert!(has_peer(&reserved_ids[0]));
+
+	// Reserved nodes are dialed even if the node is full.
+	let mut config4 = NetworkConfiguration::new_local();
+	config4.min_peers = 0;
+	let mut service4 = NetworkService::new(config4, None).unwrap();
+	service4.start().unwrap();
+	let _handler4 = TestProtocol::register(&mut service4, false);
+	service1.add_reserved_peer(&service4.local_url().unwrap()).unwrap();
+	let id4 = service4.node_id().unwrap().hex();
+	wait_for!(has_peer(&id4) && !has_peer(&ids[kept]));
+
+	// The ordinary peers keep being refused.
+	let mut expected = vec![reserved_ids[0].clone(), id4];
+	expected.sort();
+	let peers = || {
+		let mut peers: Vec<String> = service1.peers_info().into_iter().map(|p| p.id).collect();
+		peers.sort();
+		peers
+	};
+	assert_holds!(peers() == expected, Duration::from_millis(1500));
+}
+
+#[test]
+fn net_non_reserved_mode_toggle() {
+	let mut service1 = NetworkService::new(NetworkConfiguration::new_local(), None).unwrap();
+	service1.start().unwrap();
+	let _handler1 = TestProtocol::register(&mut service1, false);
+	let has_peer = |service: &NetworkService, id: &str| service.peers_info().iter().any(|p| p.id == id);
+
+	let (clients, ids) = connect_clients(&service1, 2, &[0]);
+	let reserved: Vec<bool> = ids.iter().map(|id| service1.peers_info().iter().find(|p| p.id == *id).unwrap().reserved).collect();
+	assert_eq!(reserved, vec![true, false]);
+	assert_eq!(service1.non_reserved_mode(), NonReservedPeerMode::Accept);
+
+	service1.set_non_reserved_mode(NonReservedPeerMode::Deny);
+	assert_eq!(service1.non_reserved_mode(), NonReservedPeerMode::Deny);
+	wait_for!(!has_peer(&service1, &ids[1]));
+	wait_for!(clients[1].1.got_disconnect());
+	assert_eq!(*clients[1].1.disconnect_reason.lock(), Some(DisconnectReason::TooManyPeers));
+	assert!(has_peer(&service1, &ids[0]));
+
+	// The ordinary peer is accepted again once the mode is switched back.
+	service1.set_non_reserved_mode(NonReservedPeerMode::Accept);
+	assert_eq!(service1.non_reserved_mode(), NonReservedPeerMode::Accept);
+	wait_for!(has_peer(&service1, &ids[1]));
+	assert!(has_peer(&service1, &ids[0]));
+}
+
+#[test]
+fn net_non_reserved_mode_graceful() {
+	let mut config = NetworkConfiguration::new_local();
+	config.graceful_non_reserved_disconnect = true;
+	let mut service1 = NetworkService::new(config, None).unwrap();
+	service1.start().unwrap();
+	let _handler1 = TestProtocol::register(&mut service1, false);
+	let has_peer = |service: &NetworkService, id: &str| service.peers_info().iter().any(|p| p.id == id);
+
+	let (_clients, ids) = connect_clients(&service1, 3, &[2]);
+
+	service1.set_non_reserved_mode(NonReservedPeerMode::Deny);
+	// Non-reserved peers are shed one at a time, longest connected first.
+	wait_for!(!has_peer(&service1, &ids[0]));
+	assert!(has_peer(&service1, &ids[1]));
+	wait_for!(!has_peer(&service1, &ids[1]));
+	assert_holds!(has_peer(&service1, &ids[2]) && !has_peer(&service1, &ids[0]) && !has_peer(&service1, &ids[1]), Duration::from_millis(1500));
+}
+
+#[test]
+fn net_ip_lists_reserved_bypass() {
+	for &bypass in &[true, false] {
+		let mut config = NetworkConfiguration::new_local();
+		config.ip_denylist = vec!["127.0.0.0/8".parse().unwrap()];
+		config.reserved_bypass_ip_lists = bypass;
+		let mut service1 = NetworkService::new(config, None).unwrap();
+		service1.start().unwrap();
+		let _handler1 = TestProtocol::register(&mut service1, false);
+		let has_peer = |service: &NetworkService, id: &str| service.peers_info().iter().any(|p| p.id == id);
+
+		let key3 = Random.generate().unwrap();
+		let mut config3 = NetworkConfiguration::new_local();
+		config3.use_secret = Some(key3.secret().clone());
+		let mut service3 = NetworkService::new(config3, None).unwrap();
+		service3.start().unwrap();
+		let _handler3 = TestProtocol::register(&mut service3, false);
+		service1.add_reserved_peer(&service3.local_url().unwrap()).unwrap();
+
+		let key2 = Random.generate().unwrap();
+		let mut config2 = NetworkConfiguration::new_local();
+		config2.use_secret = Some(key2.secret().clone());
+		config2.boot_nodes = vec![ service1.local_url().unwrap() ];
+		let mut service2 = NetworkService::new(config2, None).unwrap();
+		service2.start().unwrap();
+		let _handler2 = TestProtocol::register(&mut service2, false);
+
+		if bypass {
+			wait_for!(has_peer(&service1, &key3.public().hex()));
+		} else {
+			wait_for!(service1.stats().dial_failures().filtered != 0);
+			assert!(!has_peer(&service1, &key3.public().hex()));
+		}
+		// The ordinary peer shares the address of the reserved one but is rejected after the handshake.
+		wait_for!(service1.stats().filtered() > 0);
+		assert!(!has_peer(&service1, &key2.public().hex()));
+	}
+}
+
+#[test]
+fn net_capability_versions() {
+	let mut service1 = NetworkService::new(NetworkConfiguration::new_local(), None).unwrap();
+	service1.start().unwrap();
+	let tst1 = CountingProtocol::register_versions(&mut service1, *b"tst", &[43, 41, 42, 42]);
+	let aaa1 = CountingProtocol::register_versions(&mut service1, *b"aaa", &[1, 2]);
+
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.boot_nodes = vec![ service1.local_url().unwrap() ];
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let aaa2 = CountingProtocol::register_versions(&mut service2, *b"aaa", &[2]);
+	let tst2 = CountingProtocol::register_versions(&mut service2, *b"tst", &[44, 42, 43]);
+
+	wait_for!([&tst1, &aaa1, &tst2, &aaa2].iter().all(|h| h.counts().1 != 0));
+	let expected = vec![
+		PeerProtocolInfo { protocol: "aaa".to_owned(), version: 2 },
+		PeerProtocolInfo { protocol: "tst".to_owned(), version: 43 },
+	];
+	assert_eq!(service1.peers_info()[0].protocols, expected);
+	assert_eq!(service2.peers_info()[0].protocols, expected);
+	// Each handler sees the versions negotiated for both protocols.
+	for h in &[&tst1, &aaa1, &tst2, &aaa2] {
+		assert_eq!(*h.peer_protocols.lock(), vec![vec![(*b"aaa", 2), (*b"tst", 43)]]);
+	}
+	let peer = service1.connected_peers()[0];
+	assert_eq!(service1.with_context_eval(*b"tst", |io| io.peer_protocols(peer)).unwrap(), vec![(*b"aaa", 2), (*b"tst", 43)]);
+}
+
+#[test]
+fn net_handshake_failure_stats() {
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.min_peers = 0;
+	config1.max_peers = 0;
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let _handler1 = TestProtocol::register(&mut service1, false);
+
+	// Auth packet that can not be decrypted.
+	let url = service1.local_url().unwrap();
+	let address: SocketAddr = url[url.find('@').unwrap() + 1..].parse().unwrap();
+	let mut stream = TcpStream::connect(address).unwrap();
+	stream.write_all(&[0u8; 307]).unwrap();
+
+	// Valid handshake, rejected for lack of peer slots.
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.boot_nodes = vec![ url ];
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let _handler2 = TestProtocol::register(&mut service2, false);
+
+	wait_for!(service1.stats().handshake_failures().auth_decrypt != 0 && service1.stats().handshake_failures().too_many_peers != 0);
+	let failures = service1.stats().reset_handshake_failures();
+	assert_eq!(failures.since(&HandshakeFailures::default()), failures);
+	assert!(failures.total() >= 2);
+	assert_eq!(failures.self_connection, 0);
+	assert_eq!(service1.stats().handshake_failures().auth_decrypt, 0);
+}
+
+#[test]
+fn net_connection_churn() {
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.max_handshakes = 256;
+	config1.max_incoming_handshakes = 256;
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let handler1 = TestProtocol::register(&mut service1, false);
+	let url = service1.local_url().unwrap();
+	let address: SocketAddr = url[url.find('@').unwrap() + 1..].parse().unwrap();
+
+	// 100 connections from several threads, registered and deregistered while others are accepted.
+	// Half of them send an auth packet that can not be decrypted, the others hang up right away.
+	let churners: Vec<_> = (0..4).map(|t| thread::spawn(move || {
+		for i in 0..25 {
+			let mut stream = TcpStream::connect(address).unwrap();
+			if (t + i) % 2 == 0 {
+				stream.write_all(&[0u8; 307]).unwrap();
+			}
+		}
+	})).collect();
+	for churner in churners {
+		churner.join().unwrap();
+	}
+	wait_for!(service1.stats().handshake_failures().auth_decrypt >= 50);
+
+	// The host still accepts and serves peers.
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.boot_nodes = vec![ url ];
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let handler2 = TestProtocol::register(&mut service2, false);
+	wait_for!(handler1.got_packet() && handler2.got_packet());
+	assert_eq!(service1.stats().handshake_failures().auth_decrypt, 50);
+	assert!(handler1.got_timeout());
+}
+
+#[test]
+fn net_broadcast() {
+	let mut service1 = NetworkService::new(NetworkConfiguration::new_local(), None).unwrap();
+	service1.start().unwrap();
+	let _handler1 = TestProtocol::register(&mut service1, false);
+	let (clients, _) = connect_clients(&service1, 3, &[]);
+	wait_for!(clients.iter().all(|&(_, ref h)| h.got_packet()));
+	let broadcast = |data: &[u8], selector: PeerSelector| service1.with_context_eval(*b"tst", |io| io.broadcast(33, data.to_vec(), selector)).unwrap();
+	let received = |data: &[u8]| clients.iter().filter(|&&(_, ref h)| h.packet.lock().windows(data.len()).any(|w| w == data)).count();
+
+	let result = broadcast(b"all", PeerSelector::AllPeers);
+	assert_eq!(result.sent.len(), 3);
+	assert!(result.failed.is_empty());
+	wait_for!(received(b"all") >= 3);
+
+	assert_eq!(broadcast(b"some", PeerSelector::RandomSubset(2)).sent.len(), 2);
+	wait_for!(received(b"some") >= 2);
+
+	let first = result.sent[0];
+	let only_first = move |peer: PeerId| peer == first;
+	assert_eq!(broadcast(b"one", PeerSelector::Matching(&only_first)).sent, vec![first]);
+	wait_for!(received(b"one") >= 1);
+	assert_eq!(broadcast(b"many", PeerSelector::RandomSubset(10)).sent.len(), 3);
+
+	wait_for!(received(b"many") >= 3);
+	assert_eq!(received(b"some"), 2);
+	assert_eq!(received(b"one"), 1);
+}
+
+/// Pseudo-random bytes that do not compress.
+fn noise(len: usize) -> Vec<u8> {
+	let mut x = 0x2545_f491u32;
+	(0..len).map(|_| {
+		x ^= x << 13;
+		x ^= x >> 17;
+		x ^= x << 5;
+		x as u8
+	}).collect()
+}
+
+#[test]
+fn net_send_queue_limit() {
+	let limit = 64 * 1024;
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.send_queue_limit = limit;
+	config1.socket_options.send_buffer_size = Some(4096);
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let handler1 = BlastProtocol::register(&mut service1, 0);
+	let peers = || handler1.peers.lock().clone();
+	let send = |peer: PeerId, len: usize| service1.with_context_eval(*b"bls", |io| io.send(peer, 0, noise(len))).unwrap();
+	let queue_depth = |peer: PeerId| service1.with_context_eval(*b"bls", |io| io.queue_depth(peer)).unwrap();
+
+	// Clients stop reading for a long time once they get the first packet.
+	let start_client = || {
+		let mut config = NetworkConfiguration::new_local();
+		config.boot_nodes = vec![ service1.local_url().unwrap() ];
+		config.peer_rate_limit = Some(RateLimit { packets_per_sec: 0, bytes_per_sec: 1, hard_limit_secs: 1000_000 });
+		config.socket_options.recv_buffer_size = Some(4096);
+		let mut client = NetworkService::new(config, None).unwrap();
+		client.start().unwrap();
+		let handler = BlastProtocol::register(&mut client, 0);
+		let known = peers();
+		wait_for!(peers().iter().any(|p| !known.contains(p)));
+		let peer = peers().into_iter().find(|p| !known.contains(p)).unwrap();
+		(client, handler, peer)
+	};
+
+	let (_client1, reader1, peer1) = start_client();
+	send(peer1, 16 * 1024).unwrap();
+	wait_for!(reader1.received() >= 1);
+	let mut full = false;
+	for _ in 0..100 {
+		match send(peer1, 16 * 1024) {
+			Ok(()) => {},
+			Err(e) => match *e.kind() {
+				ErrorKind::SendQueueFull => { full = true; break; },
+				_ => panic!("Unexpected send error: {}", e),
+			},
+		}
+	}
+	assert!(full);
+	// The queue is not drained by the peer and does not grow past the limit by more than a packet.
+	assert_holds!(queue_depth(peer1) >= limit && queue_depth(peer1) < limit + 17 * 1024, Duration::from_millis(500));
+	assert!(send(peer1, 16 * 1024).is_err());
+
+	// A single packet over the hard limit gets the peer disconnected.
+	let (_client2, reader2, peer2) = start_client();
+	send(peer2, 16 * 1024).unwrap();
+	wait_for!(reader2.received() >= 1);
+	send(peer2, 1024 * 1024).unwrap();
+	wait_for!(!peers().contains(&peer2));
+	assert!(peers().contains(&peer1));
+}
+
+#[test]
+fn net_graceful_disconnect_drains_queue() {
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.disconnect_drain_timeout = Duration::from_secs(60);
+	config1.socket_options.send_buffer_size = Some(4096);
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let handler1 = BlastProtocol::register(&mut service1, 0);
+	let send = |peer: PeerId, len: usize| service1.with_context_eval(*b"bls", |io| io.send(peer, 0, noise(len))).unwrap();
+	let queue_depth = |peer: PeerId| service1.with_context_eval(*b"bls", |io| io.queue_depth(peer)).unwrap();
+
+	// The client reads slowly, the packets take a few seconds to arrive.
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.boot_nodes = vec![ service1.local_url().unwrap() ];
+	config2.peer_rate_limit = Some(RateLimit { packets_per_sec: 0, bytes_per_sec: 128 * 1024, hard_limit_secs: 1000 });
+	config2.socket_options.recv_buffer_size = Some(4096);
+	config2.maintain_interval = Duration::from_millis(100);
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let handler2 = BlastProtocol::register(&mut service2, 0);
+	wait_for!(!handler1.peers.lock().is_empty() && !handler2.peers.lock().is_empty());
+	let peer = handler1.peers.lock()[0];
+
+	for _ in 0..32 {
+		send(peer, 16 * 1024).unwrap();
+	}
+	assert!(queue_depth(peer) > 0);
+	service1.set_connection_filter(Some(Arc::new(DenyAll)), true);
+
+	// Nothing more is accepted for the peer while the queue drains.
+	assert!(handler1.peers.lock().contains(&peer));
+	match send(peer, 16) {
+		Err(ref e) => match *e.kind() {
+			ErrorKind::PeerGone => {},
+			_ => panic!("Unexpected send error: {}", e),
+		},
+		Ok(()) => panic!("Packet sent to a draining peer"),
+	}
+
+	// The client gets every packet, then the Disconnect packet.
+	wait_for!(handler2.got_disconnect.load(AtomicOrdering::SeqCst));
+	assert_eq!(handler2.received(), 32);
+	assert_eq!(*handler2.last_packet.lock(), noise(16 * 1024));
+	assert!(service2.stats().disconnects().total.remote(DisconnectReason::UselessPeer) >= 1);
+	wait_for!(!handler1.peers.lock().contains(&peer));
+	assert!(service1.stats().disconnects().total.local(DisconnectReason::ConnectionFiltered) >= 1);
+}
+
+#[test]
+fn net_report_peer() {
+	let mut service1 = NetworkService::new(NetworkConfiguration::new_local(), None).unwrap();
+	service1.start().unwrap();
+	let handler1 = BlastProtocol::register(&mut service1, 0);
+	let report = |peer: PeerId, severity: Severity| service1.with_context(*b"bls", |io| io.report_peer(peer, PeerReport::new(severity, "test")));
+
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.boot_nodes = vec![ service1.local_url().unwrap() ];
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let _handler2 = BlastProtocol::register(&mut service2, 0);
+	wait_for!(!handler1.peers.lock().is_empty());
+	let peer = handler1.peers.lock()[0];
+
+	// Minor reports add up without reaching the threshold.
+	for _ in 0..5 {
+		report(peer, Severity::Minor);
+	}
+	wait_for!(service1.peers_info().iter().any(|p| p.misbehaviour_score > 40));
+	assert!(handler1.peers.lock().contains(&peer));
+	let score = service1.peers_info()[0].misbehaviour_score;
+	assert!(score > 40 && score <= 50, "score {}", score);
+
+	// Reaching the threshold disconnects the peer and it is not accepted again.
+	report(peer, Severity::Major);
+	wait_for!(!handler1.peers.lock().contains(&peer));
+	assert_holds!(handler1.peers.lock().is_empty(), Duration::from_millis(1500));
+}
+
+#[test]
+fn net_local_enode() {
+	let key = Random.generate().unwrap();
+	let mut config = NetworkConfiguration::new_local();
+	config.use_secret = Some(key.secret().clone());
+	config.listen_address = Some(SocketAddr::from_str("127.0.0.1:30444").unwrap());
+	let service = NetworkService::new(config, None).unwrap();
+
+	// The configured key and listen address are known before start.
+	let (url, provisional) = service.local_enode_info().unwrap();
+	assert!(provisional);
+	assert!(validate_node_url(&url).is_none());
+	assert!(url.ends_with("@127.0.0.1:30444"));
+	assert_eq!(service.node_id(), Some(key.public().clone()));
+
+	service.start().unwrap();
+	wait_for!(!service.local_enode_info().unwrap().1);
+	let url = service.local_enode_info().unwrap().0;
+	assert!(validate_node_url(&url).is_none());
+	assert!(url.starts_with(&format!("enode://{}@", key.public().hex())));
+	assert!(url.ends_with(":30444"));
+	assert_eq!(service.local_enode(), Some(url));
+	assert_eq!(service.node_id(), Some(key.public().clone()));
+	// NAT is disabled and no other source is configured.
+	assert_eq!(service.external_address_source(), Some(AddressSource::ListenAddress));
+
+	// A configured public address takes precedence.
+	let mut config = NetworkConfiguration::new_local();
+	config.public_address = Some(SocketAddr::from_str("1.2.3.4:30445").unwrap());
+	config.nat_enabled = true;
+	let service = NetworkService::new(config, None).unwrap();
+	assert_eq!(service.external_address_source(), None);
+	service.start().unwrap();
+	wait_for!(service.external_address_source().is_some());
+	assert_eq!(service.external_address_source(), Some(AddressSource::Config));
+	assert!(service.local_enode().unwrap().ends_with("@1.2.3.4:30445"));
+
+	// Without a configured key the node id is only known once started.
+	let service = NetworkService::new(NetworkConfiguration::new_local(), None).unwrap();
+	assert_eq!(service.local_enode(), None);
+	assert_eq!(service.node_id(), None);
+}
+
+#[test]
+fn net_chunked_packets() {
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.max_frame_size = 1024;
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let handler1 = BlastProtocol::register(&mut service1, 0);
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.boot_nodes = vec![ service1.local_url().unwrap() ];
+	config2.max_chunked_packet_size = 64 * 1024;
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let handler2 = BlastProtocol::register(&mut service2, 0);
+	wait_for!(!handler1.peers.lock().is_empty());
+	let peer = handler1.peers.lock()[0];
+	let send = |len: usize| service1.with_context_eval(*b"bls", |io| io.send(peer, 0, noise(len))).unwrap();
+
+	// Several times the frame size arrives in one piece.
+	send(10 * 1024 + 100).unwrap();
+	wait_for!(handler2.received() >= 1);
+	assert_eq!(*handler2.last_packet.lock(), noise(10 * 1024 + 100));
+
+	// Over the receiver's limit for chunked packets.
+	send(128 * 1024).unwrap();
+	wait_for!(handler2.got_disconnect.load(AtomicOrdering::SeqCst));
+	assert_eq!(handler2.received(), 1);
+}
+
+#[test]
+fn net_packet_id_ranges() {
+	let mut service1 = NetworkService::new(NetworkConfiguration::new_local(), None).unwrap();
+	service1.start().unwrap();
+	let aaa1 = RecordingProtocol::register(&mut service1, *b"aaa", 2);
+	let aab1 = RecordingProtocol::register(&mut service1, *b"aab", 3);
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.boot_nodes = vec![ service1.local_url().unwrap() ];
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let aaa2 = RecordingProtocol::register(&mut service2, *b"aaa", 2);
+	let aab2 = RecordingProtocol::register(&mut service2, *b"aab", 3);
+	wait_for!([&aaa1, &aab1, &aaa2, &aab2].iter().all(|h| !h.peers.lock().is_empty()));
+	let peer = aaa1.peers.lock()[0];
+	let send = |protocol: ProtocolId, packet_id: u8| service1.with_context_eval(protocol, |io| io.send(peer, packet_id, vec![1u8])).unwrap();
+
+	// The last id of a protocol and the first id of the next one are routed to their own handlers.
+	send(*b"aaa", 1).unwrap();
+	send(*b"aab", 0).unwrap();
+	send(*b"aab", 2).unwrap();
+	match send(*b"aaa", 2) {
+		Err(e) => match *e.kind() {
+			ErrorKind::PacketIdOutOfRange(2, 2) => {},
+			_ => panic!("Unexpected send error: {}", e),
+		},
+		Ok(()) => panic!("Packet id out of range was sent"),
+	}
+	assert!(send(*b"aab", 3).is_err());
+	wait_for!(aab2.packets.lock().len() >= 2);
+	assert_eq!(*aaa2.packets.lock(), vec![1u8]);
+	assert_eq!(*aab2.packets.lock(), vec![0u8, 2]);
+
+	// Versions without packet ids or with more than fit are refused.
+	assert!(service1.register_protocol_versions(aaa1.clone(), *b"ccc", &[(1, 0)]).is_err());
+	assert!(service1.register_protocol_versions(aaa1.clone(), *b"ccc", &[(1, 10), (2, 113)]).is_err());
+}
+
+#[test]
+fn net_max_incoming_handshakes() {
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.max_incoming_handshakes = 4;
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let _handler1 = TestProtocol::register(&mut service1, false);
+	let (_clients, ids) = connect_clients(&service1, 1, &[]);
+
+	// Connections that never start a handshake.
+	let url = service1.local_url().unwrap();
+	let address: SocketAddr = url[url.find('@').unwrap() + 1..].parse().unwrap();
+	let streams: Vec<TcpStream> = (0..20).map(|_| TcpStream::connect(address).unwrap()).collect();
+	wait_for!(service1.stats().handshake_failures().too_many_peers >= 16);
+	assert_eq!(service1.stats().handshakes(), 4);
+
+	// Connections over the cap are closed right away.
+	let mut closed = 0;
+	for mut stream in streams {
+		stream.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+		if let Ok(0) = stream.read(&mut [0u8; 1]) {
+			closed += 1;
+		}
+	}
+	assert_eq!(closed, 16);
+	assert!(service1.stats().handshake_failures().too_many_peers >= 16);
+	assert!(service1.peers_info().iter().any(|p| p.id == ids[0]));
+}
+
+#[test]
+fn net_session_capacity_refuses_with_too_many_peers() {
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.min_peers = 0;
+	config1.max_peers = 1;
+	config1.max_handshakes = 1;
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let _handler1 = TestProtocol::register(&mut service1, false);
+	let (_clients, _) = connect_clients(&service1, 1, &[]);
+
+	// The session slot left for handshakes is used to tell the peer why it is refused.
+	let mut service2 = NetworkService::new(NetworkConfiguration::new_local(), None).unwrap();
+	service2.start().unwrap();
+	let _handler2 = TestProtocol::register(&mut service2, false);
+	let dial = service2.connect_peer(&service1.local_url().unwrap(), false).unwrap().recv_timeout(Duration::from_secs(10)).unwrap();
+	assert_eq!(dial, Err(DialError::Rejected(DisconnectReason::TooManyPeers)));
+	assert!(service1.stats().handshake_failures().too_many_peers >= 1);
+	assert_eq!(service1.stats().at_capacity(), 0);
+}
+
+#[test]
+fn net_peer_count_watermarks() {
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.peer_count_grace = Duration::from_millis(0);
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	let events = Arc::new(Mutex::new(Vec::new()));
+	let recorded = events.clone();
+	service1.on_peer_count_change(Box::new(move |event| recorded.lock().push(event)));
+	service1.set_peer_watermarks(1, 2);
+	service1.start().unwrap();
+	let _handler1 = TestProtocol::register(&mut service1, false);
+	let wait_events = |count: usize| {
+		wait_for!(events.lock().len() >= count);
+		// Give the maintenance timer a chance to raise a duplicate.
+		assert_holds!(events.lock().len() == count, Duration::from_millis(1500));
+	};
+
+	wait_events(1);
+	assert_eq!(*events.lock(), vec![PeerCountEvent::BelowLow { peers: 0 }]);
+
+	// Between the watermarks nothing is reported.
+	let (client1, _) = connect_clients(&service1, 1, &[]);
+	assert_holds!(events.lock().len() == 1, Duration::from_millis(1500));
+
+	let (client2, _) = connect_clients(&service1, 1, &[]);
+	wait_events(2);
+	assert_eq!(events.lock()[1], PeerCountEvent::Recovered { peers: 2 });
+
+	drop(client1);
+	drop(client2);
+	wait_events(3);
+	assert_eq!(*events.lock(), vec![
+		PeerCountEvent::BelowLow { peers: 0 },
+		PeerCountEvent::Recovered { peers: 2 },
+		PeerCountEvent::BelowLow { peers: 0 },
+	]);
+}
+
+/// Next event other than discovery results.
+fn next_event(events: &EventReceiver) -> NetworkEvent {
+	loop {
+		match events.recv_timeout(Duration::from_secs(10)).expect("Timed out waiting for network event") {
+			NetworkEvent::Discovered { .. } => continue,
+			event => return event,
+		}
+	}
+}
+
+#[test]
+fn net_subscribe_events() {
+	let mut service1 = NetworkService::new(NetworkConfiguration::new_local(), None).unwrap();
+	let events1 = service1.subscribe_events();
+	let events2 = service1.subscribe_events();
+	service1.start().unwrap();
+	let _handler1 = TestProtocol::register(&mut service1, false);
+
+	match next_event(&events1) {
+		NetworkEvent::ExternalAddressChanged { url, .. } => assert_eq!(Some(url), service1.external_url()),
+		e => panic!("Unexpected event {:?}", e),
+	}
+
+	let key = Random.generate().unwrap();
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.use_secret = Some(key.secret().clone());
+	config2.boot_nodes = vec![ service1.local_url().unwrap() ];
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let _handler2 = TestProtocol::register(&mut service2, false);
+
+	let connected = next_event(&events1);
+	match connected {
+		NetworkEvent::PeerConnected { node_id, address, direction, ref caps } => {
+			assert_eq!(node_id, *key.public());
+			assert!(address.unwrap().ip().is_loopback());
+			assert_eq!(direction, ConnectionDirection::Inbound);
+			assert!(caps.contains(&PeerCapabilityInfo { protocol: *b"tst", version: 43 }));
+		},
+		ref e => panic!("Unexpected event {:?}", e),
+	}
+
+	drop(service2);
+	assert_eq!(next_event(&events1), NetworkEvent::PeerDisconnected { node_id: *key.public(), reason: Some(DisconnectReason::ClientQuit) });
+
+	// Every subscriber gets the full sequence.
+	next_event(&events2);
+	assert_eq!(next_event(&events2), connected);
+	assert!(match next_event(&events2) { NetworkEvent::PeerDisconnected { .. } => true, _ => false });
+	assert_eq!(events1.dropped(), 0);
+}
+
+#[test]
+fn net_send_to_gone_peer() {
+	let mut service1 = NetworkService::new(NetworkConfiguration::new_local(), None).unwrap();
+	service1.start().unwrap();
+	let _handler1 = TestProtocol::register(&mut service1, false);
+	let (clients, _) = connect_clients(&service1, 1, &[]);
+	let peer = service1.connected_peers()[0];
+	let send = |service: &NetworkService| service.with_context_eval(*b"tst", |io| io.send(peer, 0, b"hello".to_vec())).unwrap();
+	assert!(send(&service1).is_ok());
+
+	drop(clients);
+	wait_for!(service1.connected_peers().is_empty());
+	match send(&service1) {
+		Err(Error(ErrorKind::PeerGone, _)) => {},
+		r => panic!("Unexpected send result {:?}", r),
+	}
+	// Fire-and-forget sends ignore the failure.
+	service1.with_context(*b"tst", |io| io.send_lossy(peer, 0, b"hello".to_vec()));
+}
+
+/// Wait for the only session of the service to show up, while it is not ready yet.
+fn unready_session(service: &NetworkService) -> PeerId {
+	// Sessions are numbered from zero.
+	let peer = 0;
+	let start = Instant::now();
+	loop {
+		match service.with_context_eval(*b"tst", |io| io.send(peer, 33, b"early".to_vec())).unwrap() {
+			Err(Error(ErrorKind::PeerGone, _)) => {
+				assert!(start.elapsed() < Duration::from_secs(20), "Timed out waiting for the session");
+				thread::sleep(Duration::from_millis(10));
+			},
+			Err(Error(ErrorKind::SessionNotReady, _)) => return peer,
+			r => panic!("Unexpected send result {:?}", r),
+		}
+	}
+}
+
+#[test]
+fn net_send_when_ready() {
+	let (service1, _handler1, _service2, handler2) = connect_filtered(DelayedFilter::new(Duration::from_millis(800), Some(true)), Duration::from_secs(5));
+	let peer = unready_session(&service1);
+	for i in 0..3u8 {
+		service1.with_context_eval(*b"tst", |io| io.send_when_ready(peer, 33, vec![b'0' + i])).unwrap().unwrap();
+	}
+	wait_for!(handler2.packet.lock().len() >= 8);
+	// Held packets follow the one sent by the connect handler, the early send is lost.
+	assert_eq!(&handler2.packet.lock()[..], &b"hello012"[..]);
+}
+
+#[test]
+fn net_send_when_ready_dropped() {
+	let (service1, _handler1, _service2, handler2) = connect_filtered(DelayedFilter::new(Duration::from_millis(800), Some(false)), Duration::from_secs(5));
+	let events = service1.subscribe_events();
+	let peer = unready_session(&service1);
+	let send = || service1.with_context_eval(*b"tst", |io| io.send_when_ready(peer, 33, b"held".to_vec())).unwrap();
+	for _ in 0..16 {
+		assert!(send().is_ok());
+	}
+	match send() {
+		Err(Error(ErrorKind::SendQueueFull, _)) => {},
+		r => panic!("Unexpected send result {:?}", r),
+	}
+	loop {
+		match next_event(&events) {
+			NetworkEvent::DeferredPacketsDropped { peer: dropped_peer, packets } => {
+				assert_eq!((dropped_peer, packets), (peer, 16));
+				break;
+			},
+			_ => continue,
+		}
+	}
+	assert!(handler2.packet.lock().is_empty());
+}
+
+/// Connect a requesting service to a serving one.
+fn connect_requests(silent: bool) -> (NetworkService, NetworkService, Arc<RequestProtocol>, PeerId) {
+	let mut service1 = NetworkService::new(NetworkConfiguration::new_local(), None).unwrap();
+	service1.start().unwrap();
+	RequestProtocol::register(&mut service1, silent);
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.boot_nodes = vec![ service1.local_url().unwrap() ];
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let handler2 = RequestProtocol::register(&mut service2, false);
+	wait_for!(!handler2.peers.lock().is_empty());
+	let peer = handler2.peers.lock()[0];
+	(service1, service2, handler2, peer)
+}
+
+#[test]
+fn net_request_response() {
+	let (_service1, service2, handler2, peer) = connect_requests(false);
+	let request = service2.with_context_eval(*b"req", |io| io.send_request(peer, 0, b"abc".to_vec(), Duration::from_secs(30))).unwrap().unwrap();
+	assert_eq!((request.peer, request.packet_id), (peer, 0));
+	wait_for!(!handler2.responses.lock().is_empty());
+	assert_eq!(*handler2.responses.lock(), vec![(request, b"cba".to_vec())]);
+	assert!(handler2.timed_out.lock().is_empty());
+}
+
+#[test]
+fn net_request_timed_out_on_disconnect() {
+	let (service1, service2, handler2, peer) = connect_requests(true);
+	let request = service2.with_context_eval(*b"req", |io| io.send_request(peer, 0, b"abc".to_vec(), Duration::from_secs(30))).unwrap().unwrap();
+	assert_holds!(handler2.timed_out.lock().is_empty(), Duration::from_millis(200));
+
+	// Well before the timeout.
+	drop(service1);
+	wait_for!(!handler2.timed_out.lock().is_empty());
+	assert_eq!(*handler2.timed_out.lock(), vec![request]);
+	assert!(handler2.responses.lock().is_empty());
+}
+
+#[test]
+fn net_one_shot_and_cancelled_timers() {
+	let mut service = NetworkService::new(NetworkConfiguration::new_local(), None).unwrap();
+	service.start().unwrap();
+	let handler = TimerProtocol::register(&mut service);
+	let counts = || (handler.count(ONE_SHOT_TIMER), handler.count(CANCELLED_TIMER), handler.count(SELF_CANCELLING_TIMER), handler.count(REPLACED_TIMER));
+	wait_for!(counts().0 != 0 && counts().2 != 0 && counts().3 != 0);
+	// Each of the timers fires once, the cancelled one never.
+	assert_holds!(counts() == (1, 0, 1, 1), Duration::from_millis(500));
+
+	// Cancelling from outside the handler suppresses the timer as well.
+	service.with_context(*b"tmr", |io| io.register_timer(CANCELLED_TIMER, 10).unwrap());
+	wait_for!(handler.count(CANCELLED_TIMER) != 0);
+	service.with_context(*b"tmr", |io| io.cancel_timer(CANCELLED_TIMER).unwrap());
+	let ticks = handler.count(CANCELLED_TIMER);
+	assert_holds!(handler.count(CANCELLED_TIMER) == ticks, Duration::from_millis(100));
+}
+
+#[test]
+fn net_node_allowlist() {
+	let key1 = Random.generate().unwrap();
+	let key2 = Random.generate().unwrap();
+	let key3 = Random.generate().unwrap();
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.use_secret = Some(key1.secret().clone());
+	config1.node_allowlist = Some(vec![key2.public().hex()]);
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let _handler1 = TestProtocol::register(&mut service1, false);
+	let url1 = service1.local_url().unwrap();
+
+	let start = |key: &KeyPair, allowlist: Option<Vec<String>>| {
+		let mut config = NetworkConfiguration::new_local();
+		config.use_secret = Some(key.secret().clone());
+		config.reserved_nodes = vec![url1.clone()];
+		config.node_allowlist = allowlist;
+		let mut service = NetworkService::new(config, None).unwrap();
+		service.start().unwrap();
+		let handler = TestProtocol::register(&mut service, false);
+		(service, handler)
+	};
+	let (service2, _handler2) = start(&key2, None);
+	// The third node allows the first one, but not the other way round.
+	let (service3, _handler3) = start(&key3, Some(vec![key1.public().hex()]));
+
+	wait_for!(!service2.connected_peers().is_empty());
+	assert_holds!(service1.connected_peers().len() == 1 && service3.connected_peers().is_empty(), Duration::from_millis(1500));
+
+	// Removal from the list disconnects the session.
+	service1.set_node_allowlist(Some(vec![key3.public().clone()]));
+	assert_eq!(service1.node_allowlist(), Some(vec![key3.public().clone()]));
+	wait_for!(service2.connected_peers().is_empty() && !service3.connected_peers().is_empty());
+	assert_eq!(service1.connected_peers().len(), 1);
+}
+
+#[test]
+fn net_connect_peer() {
+	let key1 = Random.generate().unwrap();
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.use_secret = Some(key1.secret().clone());
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let _handler1 = TestProtocol::register(&mut service1, false);
+	let url1 = service1.local_url().unwrap();
+	let mut service2 = NetworkService::new(NetworkConfiguration::new_local(), None).unwrap();
+	service2.start().unwrap();
+	let handler2 = TestProtocol::register(&mut service2, false);
+	// Let the protocol registration reach the host.
+	wait_for!(handler2.got_timeout());
+	let dial = |url: &str| service2.connect_peer(url, false).unwrap().recv_timeout(Duration::from_secs(10)).unwrap();
+
+	assert!(service2.connect_peer("127.0.0.1:30303", false).is_err());
+
+	// The first node can't decrypt a handshake meant for a different id.
+	let other_id = Random.generate().unwrap().public().hex();
+	assert_eq!(dial(&url1.replace(&key1.public().hex(), &other_id)), Err(DialError::HandshakeFailed));
+
+	let closed_port = {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		listener.local_addr().unwrap().port()
+	};
+	assert_eq!(dial(&format!("enode://{}@127.0.0.1:{}", other_id, closed_port)), Err(DialError::HandshakeFailed));
+
+	let peer = dial(&url1).unwrap();
+	assert_eq!(peer.node_id, key1.public().clone());
+	assert!(peer.caps.iter().any(|c| c.protocol == *b"tst" && c.version == 43));
+	wait_for!(!service2.connected_peers().is_empty());
+	assert_eq!(service2.connected_peers(), vec![peer.peer]);
+	assert_eq!(dial(&url1), Err(DialError::AlreadyConnected));
+}
+
+#[test]
+fn net_dial_failure_causes() {
+	let key1 = Random.generate().unwrap();
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.use_secret = Some(key1.secret().clone());
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let _handler1 = TestProtocol::register(&mut service1, false);
+	// The first node's address under another node id, and a port nobody listens on.
+	let wrong_id = Random.generate().unwrap().public().clone();
+	let wrong_url = service1.local_url().unwrap().replace(&key1.public().hex(), &wrong_id.hex());
+	let refused_id = Random.generate().unwrap().public().clone();
+	let closed_port = {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		listener.local_addr().unwrap().port()
+	};
+
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.boot_nodes = vec![wrong_url, format!("enode://{}@127.0.0.1:{}", refused_id.hex(), closed_port)];
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let _handler2 = TestProtocol::register(&mut service2, false);
+
+	let kind = |id: &Public| service2.dial_candidates().into_iter().find(|c| c.id == *id).and_then(|c| c.last_failure_kind);
+	wait_for!(kind(&refused_id).is_some() && kind(&wrong_id).is_some());
+	assert_eq!(kind(&refused_id), Some(DialFailure::Refused));
+	assert_eq!(kind(&wrong_id), Some(DialFailure::WrongNodeId));
+	let candidates = service2.dial_candidates();
+	assert_eq!(candidates.len(), 2);
+	assert!(candidates.iter().all(|c| c.last_failure.is_some() && c.consecutive_failures >= 1));
+	assert!(!candidates.iter().find(|c| c.id == wrong_id).unwrap().dialable);
+
+	let failures = service2.stats().dial_failures();
+	assert!(failures.refused >= 1);
+	assert!(failures.wrong_node_id >= 1);
+	assert_eq!(failures.auth + failures.filtered + failures.timeout, 0);
+	assert!(service2.peers_info().is_empty());
+}
+
+#[test]
+fn net_peer_address_and_direction() {
+	let mut service1 = NetworkService::new(NetworkConfiguration::new_local(), None).unwrap();
+	service1.start().unwrap();
+	let handler1 = AddressProtocol::register(&mut service1);
+	let url1 = service1.local_url().unwrap();
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.boot_nodes = vec![url1.clone()];
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let handler2 = AddressProtocol::register(&mut service2);
+	wait_for!(handler1.connected.lock().is_some() && handler2.connected.lock().is_some());
+
+	let (addr1, direction1, local1) = handler1.connected.lock().clone().unwrap();
+	let (addr2, direction2, local2) = handler2.connected.lock().clone().unwrap();
+	assert_eq!(direction1, Some(ConnectionDirection::Inbound));
+	assert_eq!(direction2, Some(ConnectionDirection::Outbound));
+	// The dialer reports the listener address and the listener reports the address the dialer connected from.
+	let addr2 = addr2.unwrap();
+	assert!(url1.ends_with(&format!("@{}", addr2)));
+	assert_eq!(addr2.to_string(), local1);
+	assert_eq!(addr1.unwrap().to_string(), local2);
+
+	drop(service2);
+	wait_for!(handler1.disconnected.lock().is_some());
+	assert_eq!(*handler1.disconnected.lock(), Some((None, None)));
+}
+
+#[test]
+fn net_listen_port_fallback() {
+	let _taken = TcpListener::bind("127.0.0.1:30470").unwrap();
+	let mut config = NetworkConfiguration::new_local();
+	config.listen_address = Some(SocketAddr::from_str("127.0.0.1:30470").unwrap());
+	config.listen_bind_attempts = 2;
+	config.listen_bind_retry_delay = Duration::from_millis(10);
+	config.discovery_enabled = false;
+
+	// The port stays in use and there is nothing to fall back to.
+	let service = NetworkService::new(config.clone(), None).unwrap();
+	assert!(service.start().is_err());
+	assert_eq!(service.local_addr(), None);
+
+	config.port_fallback_range = Some(3);
+	let mut service1 = NetworkService::new(config, None).unwrap();
+	service1.start().unwrap();
+	let _handler1 = TestProtocol::register(&mut service1, false);
+	assert_eq!(service1.local_addr(), Some(SocketAddr::from_str("127.0.0.1:30471").unwrap()));
+	wait_for!(!service1.local_enode_info().unwrap().1);
+	let url = service1.local_enode_info().unwrap().0;
+	assert!(url.ends_with("@127.0.0.1:30471"));
+
+	// Peers reach the node at the advertised port.
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.boot_nodes = vec![url];
+	config2.discovery_enabled = false;
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let _handler2 = TestProtocol::register(&mut service2, false);
+	wait_for!(service1.stats().sessions() != 0 && service2.stats().sessions() != 0);
+}
+
+#[test]
+fn net_local_is_hermetic() {
+	let mut service1 = NetworkService::new(NetworkConfiguration::new_local(), None).unwrap();
+	service1.start().unwrap();
+	let handler1 = TestProtocol::register(&mut service1, false);
+	let address1 = service1.local_addr().unwrap();
+	assert!(address1.ip().is_loopback());
+	assert!(address1.port() != 0);
+
+	let mut config2 = NetworkConfiguration::new_local_with_port(30475);
+	config2.boot_nodes = vec![ service1.local_url().unwrap() ];
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let handler2 = TestProtocol::register(&mut service2, false);
+	assert_eq!(service2.local_addr(), Some(SocketAddr::from_str("127.0.0.1:30475").unwrap()));
+	wait_for!(handler1.got_packet() && handler2.got_packet());
+
+	// Neither service has bound a discovery socket.
+	assert!(service1.discovery_stats().is_none());
+	assert!(service2.discovery_stats().is_none());
+	UdpSocket::bind(address1).unwrap();
+	UdpSocket::bind(service2.local_addr().unwrap()).unwrap();
+	assert_eq!(service1.external_address_source(), Some(AddressSource::ListenAddress));
+}
+
+#[test]
+fn net_lan_discovery() {
+	let lan = LanDiscoveryConfig {
+		address: SocketAddr::from_str("239.255.42.99:40493").unwrap(),
+		interval: Duration::from_millis(100),
+		network_id: 7,
+		force: false,
+	};
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.discovery_enabled = false;
+	config1.lan_discovery = Some(lan);
+	let service1 = NetworkService::new(config1, None).unwrap();
+	let events1 = service1.subscribe_events();
+	service1.start().unwrap();
+
+	// Nodes of another network are ignored.
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.discovery_enabled = false;
+	config2.lan_discovery = Some(LanDiscoveryConfig { network_id: 8, ..lan });
+	let service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+
+	let mut config3 = NetworkConfiguration::new_local();
+	config3.discovery_enabled = false;
+	config3.lan_discovery = Some(lan);
+	let service3 = NetworkService::new(config3, None).unwrap();
+	service3.start().unwrap();
+
+	loop {
+		match events1.recv_timeout(Duration::from_secs(10)).expect("Timed out waiting for LAN discovery") {
+			NetworkEvent::Discovered { node_id } => {
+				assert!(Some(node_id) != service2.node_id());
+				assert_eq!(Some(node_id), service3.node_id());
+				break;
+			},
+			_ => {},
+		}
+	}
+
+	// A public address disables LAN discovery.
+	let mut config4 = NetworkConfiguration::new_local();
+	config4.discovery_enabled = false;
+	config4.public_address = Some(SocketAddr::from_str("8.8.8.8:30303").unwrap());
+	config4.lan_discovery = Some(lan);
+	let service4 = NetworkService::new(config4, None).unwrap();
+	service4.start().unwrap();
+	let start = Instant::now();
+	while start.elapsed() < Duration::from_millis(500) {
+		match events1.recv_timeout(Duration::from_millis(50)) {
+			Ok(NetworkEvent::Discovered { node_id }) => assert!(Some(node_id) != service4.node_id()),
+			_ => {},
+		}
+	}
+}
+
+/// Plain `RLPx` auth packet from `key` to `remote`.
+fn rlpx_auth(key: &KeyPair, remote: &Public) -> Vec<u8> {
+	let ephemeral = Random.generate().unwrap();
+	let nonce = Random.generate().unwrap().secret().clone();
+	let shared = ethcrypto::ecdh::agree(key.secret(), remote).unwrap();
+	let mut signed = [0u8; 32];
+	for i in 0..32 {
+		signed[i] = shared[i] ^ nonce[i];
+	}
+	let mut data = Vec::with_capacity(65 + 32 + 64 + 32 + 1);
+	data.extend_from_slice(&*sign(ephemeral.secret(), &Message::from_slice(&signed)).unwrap());
+	data.extend_from_slice(&keccak_hash::keccak(ephemeral.public()));
+	data.extend_from_slice(key.public());
+	data.extend_from_slice(&nonce);
+	data.push(0);
+	ethcrypto::ecies::encrypt(remote, &[], &data).unwrap()
+}
+
+#[test]
+fn net_hello_timeout() {
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.discovery_enabled = false;
+	config1.hello_timeout = Duration::from_millis(300);
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let _handler1 = TestProtocol::register(&mut service1, false);
+
+	// Complete the encryption handshake and never send Hello.
+	let key = Random.generate().unwrap();
+	let mut stream = TcpStream::connect(service1.local_addr().unwrap()).unwrap();
+	stream.write_all(&rlpx_auth(&key, &service1.node_id().unwrap())).unwrap();
+	wait_for!(service1.stats().handshake_failures().hello_timeout != 0);
+	assert_eq!(service1.stats().handshake_failures().timeout, 0);
+
+	// The connection is closed after the ack and our Hello.
+	stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+	let mut buf = [0u8; 1024];
+	loop {
+		match stream.read(&mut buf) {
+			Ok(0) => break,
+			Ok(_) => {},
+			Err(e) => {
+				assert!(e.kind() != ::std::io::ErrorKind::WouldBlock && e.kind() != ::std::io::ErrorKind::TimedOut);
+				break;
+			}
+		}
+	}
+}
+
+/// SOCKS5 proxy without authentication serving CONNECT requests. Returns its address and the number of
+/// requests received.
+fn socks_proxy(refuse: bool) -> (SocketAddr, Arc<AtomicUsize>) {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let address = listener.local_addr().unwrap();
+	let requests = Arc::new(AtomicUsize::new(0));
+	let counter = requests.clone();
+	thread::spawn(move || {
+		for stream in listener.incoming() {
+			let mut client = match stream {
+				Ok(client) => client,
+				Err(_) => return,
+			};
+			let counter = counter.clone();
+			thread::spawn(move || {
+				let mut greeting = [0u8; 2];
+				client.read_exact(&mut greeting).unwrap();
+				let mut methods = vec![0u8; greeting[1] as usize];
+				client.read_exact(&mut methods).unwrap();
+				client.write_all(&[5, 0]).unwrap();
+				let mut request = [0u8; 4];
+				client.read_exact(&mut request).unwrap();
+				assert_eq!(request[3], 1);
+				let mut target = [0u8; 6];
+				client.read_exact(&mut target).unwrap();
+				counter.fetch_add(1, AtomicOrdering::SeqCst);
+				let target = SocketAddr::from(([target[0], target[1], target[2], target[3]], (target[4] as u16) << 8 | target[5] as u16));
+				if refuse {
+					client.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+					return;
+				}
+				let server = TcpStream::connect(target).unwrap();
+				client.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).unwrap();
+				let (mut client_read, mut server_write) = (client.try_clone().unwrap(), server.try_clone().unwrap());
+				thread::spawn(move || { ::std::io::copy(&mut client_read, &mut server_write).ok(); });
+				let (mut server_read, mut client_write) = (server, client);
+				::std::io::copy(&mut server_read, &mut client_write).ok();
+			});
+		}
+	});
+	(address, requests)
+}
+
+#[test]
+fn net_outbound_proxy() {
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.discovery_enabled = false;
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let _handler1 = TestProtocol::register(&mut service1, false);
+	let url1 = service1.local_url().unwrap();
+
+	let (proxy, requests) = socks_proxy(false);
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.discovery_enabled = false;
+	config2.outbound_proxy = Some(SocksConfig { address: proxy, auth: None, timeout: Duration::from_secs(5) });
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let handler2 = TestProtocol::register(&mut service2, false);
+	wait_for!(handler2.got_timeout());
+
+	let peer = service2.connect_peer(&url1, false).unwrap().recv_timeout(Duration::from_secs(10)).unwrap().unwrap();
+	assert_eq!(Some(peer.node_id), service1.node_id());
+	assert_eq!(requests.load(AtomicOrdering::SeqCst), 1);
+	// The session reports the peer's address, not the proxy's.
+	let info = service2.peers_info();
+	assert_eq!(info.len(), 1);
+	assert_eq!(info[0].remote_address, service1.local_addr().unwrap().to_string());
+
+	// A refused CONNECT fails the dial.
+	let (proxy, requests) = socks_proxy(true);
+	let mut config3 = NetworkConfiguration::new_local();
+	config3.discovery_enabled = false;
+	config3.outbound_proxy = Some(SocksConfig { address: proxy, auth: None, timeout: Duration::from_secs(5) });
+	let mut service3 = NetworkService::new(config3, None).unwrap();
+	service3.start().unwrap();
+	let handler3 = TestProtocol::register(&mut service3, false);
+	wait_for!(handler3.got_timeout());
+	assert_eq!(service3.connect_peer(&url1, false).unwrap().recv_timeout(Duration::from_secs(10)).unwrap(), Err(DialError::HandshakeFailed));
+	assert_eq!(requests.load(AtomicOrdering::SeqCst), 1);
+	assert!(service3.connected_peers().is_empty());
+}
+
+#[test]
+fn net_packet_tracer() {
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.discovery_enabled = false;
+	config1.packet_trace_payload_bytes = 3;
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	let events = Arc::new(Mutex::new(Vec::new()));
+	let recorded = events.clone();
+	service1.set_packet_tracer(Some(Box::new(move |event: TraceEvent| recorded.lock().push(event))));
+	service1.start().unwrap();
+	let handler1 = TestProtocol::register(&mut service1, false);
+
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.discovery_enabled = false;
+	config2.boot_nodes = vec![ service1.local_url().unwrap() ];
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let handler2 = TestProtocol::register(&mut service2, false);
+	wait_for!(handler1.got_packet() && handler2.got_packet());
+
+	let events = events.lock().clone();
+	let node_id = service2.node_id().unwrap();
+	assert!(events.iter().all(|e| e.node_id == node_id));
+	// Each side sends Hello and "hello" on the test protocol.
+	for direction in &[TraceDirection::Inbound, TraceDirection::Outbound] {
+		assert!(events.iter().any(|e| e.direction == *direction && e.protocol.is_none() && e.packet_id == 0));
+		let packet = events.iter().find(|e| e.direction == *direction && e.protocol == Some(*b"tst")).unwrap();
+		assert_eq!(packet.packet_id, 33);
+		assert_eq!(packet.length, 5);
+		assert_eq!(packet.payload, b"hel".to_vec());
+	}
+
+	service1.set_packet_tracer(None);
+}
+
+struct SwitchableResolver {
+	address: Mutex<SocketAddr>,
+}
+
+impl HostResolver for SwitchableResolver {
+	fn resolve(&self, _host: &str, _port: u16) -> std::io::Result<Vec<SocketAddr>> {
+		Ok(vec![*self.address.lock()])
+	}
+}
+
+struct StaticResolver(Vec<SocketAddr>);
+
+/// Resolver that takes its time to fail.
+struct SlowResolver(Duration);
+
+impl HostResolver for SlowResolver {
+	fn resolve(&self, host: &str, _port: u16) -> std::io::Result<Vec<SocketAddr>> {
+		thread::sleep(self.0);
+		Err(std::io::Error::new(std::io::ErrorKind::TimedOut, format!("{} timed out", host)))
+	}
+}
+
+impl HostResolver for StaticResolver {
+	fn resolve(&self, _host: &str, _port: u16) -> std::io::Result<Vec<SocketAddr>> {
+		Ok(self.0.clone())
+	}
+}
+
+/// Next `PeerConnected` event, skipping disconnections in between.
+fn next_connected_address(events: &EventReceiver) -> Option<SocketAddr> {
+	loop {
+		match next_event(events) {
+			NetworkEvent::PeerConnected { address, .. } => return address,
+			_ => continue,
+		}
+	}
+}
+
+#[test]
+fn net_reserved_node_address_change() {
+	let old_address = SocketAddr::from_str("127.0.0.1:30472").unwrap();
+	let new_address = SocketAddr::from_str("127.0.0.2:30472").unwrap();
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.discovery_enabled = false;
+	config1.listen_address = Some(old_address);
+	config1.additional_listen_addresses = vec![new_address];
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let _handler1 = TestProtocol::register(&mut service1, false);
+
+	let resolver = Arc::new(SwitchableResolver { address: Mutex::new(old_address) });
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.discovery_enabled = false;
+	config2.reserved_resolve_interval = Duration::from_millis(500);
+	config2.dial_backoff = Duration::from_millis(200);
+	config2.reserved_dial_max_backoff = Duration::from_secs(1);
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.set_host_resolver(resolver.clone());
+	let events2 = service2.subscribe_events();
+	service2.start().unwrap();
+	let _handler2 = TestProtocol::register(&mut service2, false);
+	service2.add_reserved_peer(&format!("enode://{}@validator.example.com:30472", service1.node_id().unwrap().hex())).unwrap();
+	assert_eq!(next_connected_address(&events2), Some(old_address));
+
+	// The session moves over once the name resolves to the new address.
+	*resolver.address.lock() = new_address;
+	assert_eq!(next_connected_address(&events2), Some(new_address));
+	let info = service2.peers_info();
+	assert_eq!(info.len(), 1);
+	assert_eq!(info[0].remote_address, new_address.to_string());
+}
+
+#[test]
+fn net_dial_next_address_after_failed_handshake() {
+	// Accepts connections but never answers the handshake.
+	let silent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+	let silent_address = silent.local_addr().unwrap();
+	let address = SocketAddr::from_str("127.0.0.1:30476").unwrap();
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.discovery_enabled = false;
+	config1.listen_address = Some(address);
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let _handler1 = TestProtocol::register(&mut service1, false);
+
+	// Backoff long enough that only the fallback to the second address connects in time.
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.discovery_enabled = false;
+	config2.dial_backoff = Duration::from_secs(60);
+	config2.reserved_dial_max_backoff = Duration::from_secs(60);
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.set_host_resolver(Arc::new(StaticResolver(vec![silent_address, address])));
+	let events2 = service2.subscribe_events();
+	service2.start().unwrap();
+	let _handler2 = TestProtocol::register(&mut service2, false);
+	service2.add_reserved_peer(&format!("enode://{}@node.example.com:30476", service1.node_id().unwrap().hex())).unwrap();
+	assert_eq!(next_connected_address(&events2), Some(address));
+	assert_eq!(service2.stats().dial_failures().total(), 0);
+}
+
+#[test]
+fn net_slow_resolver_does_not_block() {
+	let mut service1 = NetworkService::new(NetworkConfiguration::new_local(), None).unwrap();
+	service1.set_host_resolver(Arc::new(SlowResolver(Duration::from_secs(5))));
+	service1.start().unwrap();
+	let _handler1 = TestProtocol::register(&mut service1, false);
+	let unreachable = Random.generate().unwrap().public().hex();
+	service1.add_reserved_peer(&format!("enode://{}@slow.example.com:30303", unreachable)).unwrap();
+
+	// Peers are served while the name of the reserved node is looked up.
+	let start = Instant::now();
+	let (_clients, _) = connect_clients(&service1, 1, &[]);
+	assert!(start.elapsed() < Duration::from_secs(4));
+}
+
+#[test]
+fn net_silent_proxy_does_not_block() {
+	// Accepts connections but never answers the SOCKS greeting.
+	let silent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.outbound_proxy = Some(SocksConfig { address: silent.local_addr().unwrap(), auth: None, timeout: Duration::from_secs(5) });
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let _handler1 = TestProtocol::register(&mut service1, false);
+	let unreachable = Random.generate().unwrap().public().hex();
+	let dial = service1.connect_peer(&format!("enode://{}@127.0.0.1:30303", unreachable), false).unwrap();
+
+	// Peers are served while the proxy keeps the dial waiting.
+	let start = Instant::now();
+	let (_clients, _) = connect_clients(&service1, 1, &[]);
+	assert!(start.elapsed() < Duration::from_secs(4));
+	assert_eq!(dial.recv_timeout(Duration::from_secs(10)).unwrap(), Err(DialError::HandshakeFailed));
+}
+
+/// Node table file of a network as a string.
+fn node_table_file(dir: &TempDir, network_id: u64) -> String {
+	let mut content = String::new();
+	std::fs::File::open(dir.path().join(format!("network-{}", network_id)).join("nodes.json")).unwrap().read_to_string(&mut content).unwrap();
+	content
+}
+
+#[test]
+fn net_switch_network() {
+	let mut config = NetworkConfiguration::new_local();
+	config.discovery_enabled = false;
+	let mut service1 = NetworkService::new(config.clone(), None).unwrap();
+	service1.start().unwrap();
+	let _handler1 = TestProtocol::register(&mut service1, false);
+	let mut service2 = NetworkService::new(config.clone(), None).unwrap();
+	service2.start().unwrap();
+	let _handler2 = TestProtocol::register(&mut service2, false);
+	let id1 = service1.node_id().unwrap();
+	let id2 = service2.node_id().unwrap();
+
+	let tempdir = TempDir::new("").unwrap();
+	let mut config3 = config.clone();
+	config3.net_config_path = Some(tempdir.path().to_str().unwrap().to_owned());
+	config3.network_id = Some(1);
+	config3.boot_nodes = vec![ service1.local_url().unwrap() ];
+	let mut service3 = NetworkService::new(config3, None).unwrap();
+	service3.start().unwrap();
+	let _handler3 = TestProtocol::register(&mut service3, false);
+	wait_for!(service3.peers_info().iter().any(|p| p.id == id1.hex()));
+
+	service3.switch_network(2, vec![ service2.local_url().unwrap() ]).unwrap();
+	wait_for!(service3.peers_info().iter().any(|p| p.id == id2.hex()));
+	assert_eq!(service3.peers_info().len(), 1);
+	let network1 = node_table_file(&tempdir, 1);
+	assert!(network1.contains(&id1.hex()) && !network1.contains(&id2.hex()));
+
+	service3.flush_node_table().unwrap();
+	let network2 = node_table_file(&tempdir, 2);
+	assert!(network2.contains(&id2.hex()) && !network2.contains(&id1.hex()));
+	// Invalid boot nodes are refused before anything is torn down.
+	assert!(service3.switch_network(1, vec!["enode://invalid".into()]).is_err());
+	assert_eq!(service3.peers_info().len(), 1);
+}
+
+#[test]
+fn net_self_dial_rejected() {
+	let tempdir = TempDir::new("").unwrap();
+	let mut config = NetworkConfiguration::new_local();
+	config.discovery_enabled = false;
+	config.net_config_path = Some(tempdir.path().to_str().unwrap().to_owned());
+	config.network_id = Some(1);
+	let mut service = NetworkService::new(config, None).unwrap();
+	service.start().unwrap();
+	let _handler = TestProtocol::register(&mut service, false);
+	let own_id = service.node_id().unwrap().hex();
+	// Our own address under a different node id, as left behind by a stale entry or NAT hairpinning.
+	let url = service.local_url().unwrap().replace(&own_id, &Random.generate().unwrap().public().hex());
+
+	service.add_reserved_peer(&url).unwrap();
+	wait_for!(service.stats().handshake_failures().self_connection != 0);
+	assert!(service.peers_info().is_empty());
+	assert_eq!(service.stats().handshake_failures().impersonation, 0);
+	// The node is marked and not dialed again, even when forced.
+	let dial = service.connect_peer(&url, true).unwrap().recv_timeout(Duration::from_secs(10)).unwrap();
+	assert_eq!(dial, Err(DialError::Rejected(DisconnectReason::LocalIdentity)));
+	service.flush_node_table().unwrap();
+	assert!(node_table_file(&tempdir, 1).contains("\"is_self\": true"));
+}
+
+#[test]
+fn net_client_version_change() {
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.client_version_override = Some("Parity-network/operator-a".into());
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let _handler1 = TestProtocol::register(&mut service1, false);
+	let url1 = service1.local_url().unwrap();
+	let connect = || {
+		let mut service = NetworkService::new(NetworkConfiguration::new_local(), None).unwrap();
+		service.start().unwrap();
+		let handler = TestProtocol::register(&mut service, false);
+		wait_for!(handler.got_timeout());
+		service.connect_peer(&url1, false).unwrap().recv_timeout(Duration::from_secs(10)).unwrap().unwrap();
+		(service, handler)
+	};
+	let client_version = |service: &NetworkService| service.peers_info()[0].client_version.clone();
+
+	let (service2, _handler2) = connect();
+	assert_eq!(client_version(&service2), "Parity-network/operator-a");
+
+	assert!(service1.set_client_version("Parity-network/\u{e9}".into()).is_err());
+	assert!(service1.set_client_version("x".repeat(257)).is_err());
+	assert_eq!(service1.host_info(), "Parity-network/operator-a");
+	service1.set_client_version("Parity-network/operator-b".into()).unwrap();
+	assert_eq!(service1.host_info(), "Parity-network/operator-b");
+
+	let (service3, _handler3) = connect();
+	assert_eq!(client_version(&service3), "Parity-network/operator-b");
+	// The existing session keeps what was sent in its handshake.
+	assert_eq!(client_version(&service2), "Parity-network/operator-a");
+}
+
+#[test]
+fn net_read_paused() {
+	let mut config1 = NetworkConfiguration::new_local();
+	// Small enough for the sent packets to stop reading from the peer.
+	config1.paused_packet_limit = 4;
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let handler1 = RecordingProtocol::register(&mut service1, *b"aaa", 20);
+	service1.with_context(*b"aaa", |io| io.set_read_paused(*b"aaa", true));
+	let mut config2 = NetworkConfiguration::new_local();
+	config2.boot_nodes = vec![ service1.local_url().unwrap() ];
+	let mut service2 = NetworkService::new(config2, None).unwrap();
+	service2.start().unwrap();
+	let handler2 = RecordingProtocol::register(&mut service2, *b"aaa", 20);
+	wait_for!(!handler1.peers.lock().is_empty() && !handler2.peers.lock().is_empty());
+	let peer = handler2.peers.lock()[0];
+	for packet_id in 0..20 {
+		service2.with_context_eval(*b"aaa", |io| io.send(peer, packet_id, vec![packet_id])).unwrap().unwrap();
+	}
+	// The session is kept while nothing is read from the peer.
+	assert_holds!(handler1.packets.lock().is_empty() && service1.peers_info().len() == 1, Duration::from_millis(500));
+
+	service1.with_context(*b"aaa", |io| io.set_read_paused(*b"aaa", false));
+	wait_for!(handler1.packets.lock().len() >= 20);
+	assert_eq!(*handler1.packets.lock(), (0..20).collect::<Vec<u8>>());
+}
+
+/// Start a client booting from `service` that only supports protocol `aaa`. Returns the client, its handler and id.
+fn connect_aaa_client(service: &NetworkService) -> (NetworkService, Arc<CountingProtocol>, String) {
+	let key = Random.generate().unwrap();
+	let mut config = NetworkConfiguration::new_local();
+	config.use_secret = Some(key.secret().clone());
+	config.boot_nodes = vec![ service.local_url().unwrap() ];
+	let mut client = NetworkService::new(config, None).unwrap();
+	client.start().unwrap();
+	let handler = CountingProtocol::register(&mut client, *b"aaa");
+	(client, handler, key.public().hex())
+}
+
+#[test]
+fn net_reserved_slots() {
+	let mut config1 = NetworkConfiguration::new_local();
+	config1.min_peers = 0;
+	config1.max_peers = 3;
+	let mut service1 = NetworkService::new(config1, None).unwrap();
+	service1.start().unwrap();
+	let _tst1 = TestProtocol::register(&mut service1, false);
+	let _aaa1 = CountingProtocol::register(&mut service1, *b"aaa");
+	service1.reserve_slots_for(*b"aaa", 1, 1);
+	let has_peer = |id: &str| service1.peers_info().iter().any(|p| p.id == id);
+
+	// Other peers take all but the reserved slot.
+	let (_clients, ids) = connect_clients(&service1, 2, &[]);
+	let mut config3 = NetworkConfiguration::new_local();
+	config3.boot_nodes = vec![ service1.local_url().unwrap() ];
+	let mut service3 = NetworkService::new(config3, None).unwrap();
+	service3.start().unwrap();
+	let _tst3 = TestProtocol::register(&mut service3, false);
+	wait_for!(service1.stats().handshake_failures().too_many_peers != 0);
+	assert_eq!(service1.peers_info().len(), 2);
+
+	// A peer the slot is kept for still gets in.
+	let (_aaa2, _, id2) = connect_aaa_client(&service1);
+	wait_for!(has_peer(&id2));
+	let reserved_slot: Vec<bool> = [&ids[0], &ids[1], &id2].iter().map(|id| service1.peers_info().iter().find(|p| p.id == **id).unwrap().reserved_slot).collect();
+	assert_eq!(reserved_slot, vec![false, false, true]);
+
+	// With another slot reserved at runtime, the next one replaces one of the other peers.
+	service1.reserve_slots_for(*b"aaa", 1, 2);
+	let (_aaa4, _, id4) = connect_aaa_client(&service1);
+	wait_for!(has_peer(&id4));
+	wait_for!(!has_peer(&ids[0]) || !has_peer(&ids[1]));
+	assert!(has_peer(&id2));
+	assert_eq!(service1.peers_info().len(), 3);
+}
+
+#[test]
+fn net_peer_tick() {
+	let mut service1 = NetworkService::new(NetworkConfiguration::new_local(), None).unwrap();
+	service1.start().unwrap();
+	let handler1 = TickProtocol::register(&mut service1, Some(Duration::from_millis(50)));
+	let clients: Vec<(NetworkService, Arc<TickProtocol>)> = (0..2).map(|_| {
+		let mut config = NetworkConfiguration::new_local();
+		config.boot_nodes = vec![ service1.local_url().unwrap() ];
+		let mut client = NetworkService::new(config, None).unwrap();
+		client.start().unwrap();
+		let handler = TickProtocol::register(&mut client, None);
+		(client, handler)
+	}).collect();
+
+	// Each peer gets ticks of its own.
+	let peers = || service1.connected_peers();
+	wait_for!(peers().len() >= 2 && peers().iter().all(|p| handler1.count(*p, PeerEvent::Tick) >= 3));
+	for &(_, ref handler) in &clients {
+		assert!(handler.events.lock().iter().all(|&(_, e)| e != PeerEvent::Tick));
+	}
+
+	// No ticks after a peer has disconnected, the other one keeps ticking.
+	let connected = peers();
+	let (gone, kept) = (connected[0], connected[1]);
+	service1.with_context(*b"tck", |io| io.disconnect_peer(gone, DisconnectReason::ClientQuit, false));
+	wait_for!(handler1.count(gone, PeerEvent::Disconnected) != 0);
+	let ticks = handler1.count(kept, PeerEvent::Tick);
+	wait_for!(handler1.count(kept, PeerEvent::Tick) > ticks);
+	assert!(handler1.ticked_while_connected());
+}
@@ -0,0 +1,243 @@
+// Copyright 2015-2017 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Parsing of the client identifier peers send in the Hello packet.
+
+use std::fmt;
+use semver::{Version, VersionReq};
+
+/// Client identifier split into its well-known parts,
+/// e.g. `Parity/v1.10.0-stable-b9d9b9e-20180315/x86_64-linux-gnu/rustc1.24.1`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedClientVersion {
+	name: String,
+	identity: Option<String>,
+	version: Version,
+	version_suffix: Option<String>,
+	os: Option<String>,
+	compiler: Option<String>,
+	raw: String,
+}
+
+impl ParsedClientVersion {
+	/// Client name, e.g. `Parity` or `Geth`.
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+
+	/// Optional node identity set by the operator, e.g. `Geth/my-node/v1.8.3/...`.
+	pub fn identity(&self) -> Option<&str> {
+		self.identity.as_ref().map(|s| s.as_str())
+	}
+
+	/// Release version number.
+	pub fn version(&self) -> &Version {
+		&self.version
+	}
+
+	/// Everything following the version number, e.g. `stable-b9d9b9e-20180315`.
+	pub fn version_suffix(&self) -> Option<&str> {
+		self.version_suffix.as_ref().map(|s| s.as_str())
+	}
+
+	/// Operating system / target triple.
+	pub fn os(&self) -> Option<&str> {
+		self.os.as_ref().map(|s| s.as_str())
+	}
+
+	/// Compiler or language runtime the client was built with, e.g. `rustc1.24.1` or `go1.10`.
+	pub fn compiler(&self) -> Option<&str> {
+		self.compiler.as_ref().map(|s| s.as_str())
+	}
+}
+
+/// Peer client identifier.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientVersion {
+	/// Identifier in the common `name/version/os/compiler` format.
+	Parsed(ParsedClientVersion),
+	/// Identifier that could not be parsed.
+	Raw(String),
+}
+
+impl Default for ClientVersion {
+	fn default() -> Self {
+		ClientVersion::Raw(String::new())
+	}
+}
+
+impl ClientVersion {
+	/// Client name. Unparsed identifiers are returned as is.
+	pub fn name(&self) -> &str {
+		match *self {
+			ClientVersion::Parsed(ref v) => v.name(),
+			ClientVersion::Raw(ref s) => s,
+		}
+	}
+
+	/// Release version, if known.
+	pub fn version(&self) -> Option<&Version> {
+		match *self {
+			ClientVersion::Parsed(ref v) => Some(v.version()),
+			ClientVersion::Raw(_) => None,
+		}
+	}
+
+	/// Original identifier string.
+	pub fn as_str(&self) -> &str {
+		match *self {
+			ClientVersion::Parsed(ref v) => &v.raw,
+			ClientVersion::Raw(ref s) => s,
+		}
+	}
+
+	/// Check if this client matches the given name (case-insensitive) and version requirement,
+	/// e.g. `is_compatible_with("Parity", ">=1.9")`. Returns false for unparsed identifiers
+	/// and invalid requirements.
+	pub fn is_compatible_with(&self, name: &str, requirement: &str) -> bool {
+		match (self, VersionReq::parse(requirement)) {
+			(&ClientVersion::Parsed(ref v), Ok(ref req)) => v.name.to_lowercase() == name.to_lowercase() && req.matches(&v.version),
+			_ => false,
+		}
+	}
+}
+
+impl fmt::Display for ClientVersion {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(self.as_str())
+	}
+}
+
+/// Parse `vMAJOR.MINOR.PATCH[-suffix]`. Missing minor and patch numbers default to zero.
+fn parse_version(s: &str) -> Option<(Version, Option<String>)> {
+	let s = if s.starts_with('v') || s.starts_with('V') { &s[1..] } else { s };
+	let (numbers, suffix) = match s.find(|c: char| c == '-' || c == '+') {
+		Some(pos) => (&s[..pos], Some(s[pos + 1..].to_owned())),
+		None => (s, None),
+	};
+	let mut parts = numbers.split('.');
+	let major = match parts.next().and_then(|p| p.parse().ok()) {
+		Some(major) => major,
+		None => return None,
+	};
+	let mut minor_patch = [0u64; 2];
+	for n in minor_patch.iter_mut() {
+		*n = match parts.next() {
+			Some(p) => match p.parse() {
+				Ok(v) => v,
+				Err(_) => return None,
+			},
+			None => 0,
+		};
+	}
+	if parts.next().is_some() {
+		return None;
+	}
+	Some((Version::new(major, minor_patch[0], minor_patch[1]), suffix.and_then(|s| if s.is_empty() { None } else { Some(s) })))
+}
+
+fn non_empty(s: Option<&&str>) -> Option<String> {
+	s.and_then(|s| if s.is_empty() { None } else { Some((*s).to_owned()) })
+}
+
+impl<'a> From<&'a str> for ClientVersion {
+	fn from(s: &'a str) -> Self {
+		let parts: Vec<&str> = s.split('/').collect();
+		if parts.len() < 2 || parts[0].is_empty() {
+			return ClientVersion::Raw(s.to_owned());
+		}
+		// The version is either the second part or, for clients with a custom identity, the third.
+		let (identity, index, version) = match parse_version(parts[1]) {
+			Some(v) => (None, 1, v),
+			None => match parts.get(2).and_then(|p| parse_version(p)) {
+				Some(v) => (Some(parts[1].to_owned()), 2, v),
+				None => return ClientVersion::Raw(s.to_owned()),
+			},
+		};
+		ClientVersion::Parsed(ParsedClientVersion {
+			name: parts[0].to_owned(),
+			identity: identity,
+			version: version.0,
+			version_suffix: version.1,
+			os: non_empty(parts.get(index + 1)),
+			compiler: non_empty(parts.get(index + 2)),
+			raw: s.to_owned(),
+		})
+	}
+}
+
+impl From<String> for ClientVersion {
+	fn from(s: String) -> Self {
+		ClientVersion::from(s.as_str())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use semver::Version;
+	use super::ClientVersion;
+
+	#[test]
+	fn parses_real_world_client_ids() {
+		// id, name, identity, version, suffix, os, compiler
+		let cases: Vec<(&str, &str, Option<&str>, (u64, u64, u64), Option<&str>, Option<&str>, Option<&str>)> = vec![
+			("Parity/v1.10.0-stable-b9d9b9e-20180315/x86_64-linux-gnu/rustc1.24.1", "Parity", None, (1, 10, 0), Some("stable-b9d9b9e-20180315"), Some("x86_64-linux-gnu"), Some("rustc1.24.1")),
+			("Parity/v1.9.5-stable-ff821da-20180321/x86_64-macos/rustc1.24.1", "Parity", None, (1, 9, 5), Some("stable-ff821da-20180321"), Some("x86_64-macos"), Some("rustc1.24.1")),
+			("Parity/v1.11.0-unstable-3a0bc2d-20180410/x86_64-windows-msvc/rustc1.25.0", "Parity", None, (1, 11, 0), Some("unstable-3a0bc2d-20180410"), Some("x86_64-windows-msvc"), Some("rustc1.25.0")),
+			("Parity/v1.7.0-beta-5f2cabd-20170727/x86_64-linux-gnu/rustc1.18.0", "Parity", None, (1, 7, 0), Some("beta-5f2cabd-20170727"), Some("x86_64-linux-gnu"), Some("rustc1.18.0")),
+			("Geth/v1.8.3-stable-329ac18e/linux-amd64/go1.10", "Geth", None, (1, 8, 3), Some("stable-329ac18e"), Some("linux-amd64"), Some("go1.10")),
+			("Geth/v1.7.3-stable/darwin-amd64/go1.9.2", "Geth", None, (1, 7, 3), Some("stable"), Some("darwin-amd64"), Some("go1.9.2")),
+			("Geth/my-node-01/v1.8.2-stable-b8b9f7f4/linux-amd64/go1.9.4", "Geth", Some("my-node-01"), (1, 8, 2), Some("stable-b8b9f7f4"), Some("linux-amd64"), Some("go1.9.4")),
+			("Geth/v1.6.7-stable-ab5646c5/windows-amd64/go1.8.3", "Geth", None, (1, 6, 7), Some("stable-ab5646c5"), Some("windows-amd64"), Some("go1.8.3")),
+			("Ethereum(++)/v1.3.0/Linux/g++", "Ethereum(++)", None, (1, 3, 0), None, Some("Linux"), Some("g++")),
+			("pyethapp/v1.5.0/linux2/py2.7.12", "pyethapp", None, (1, 5, 0), None, Some("linux2"), Some("py2.7.12")),
+			("Parity-network/v1.11", "Parity-network", None, (1, 11, 0), None, None, None),
+			("ethereumjs-devp2p/v2.1.3/linux-x64/nodejs", "ethereumjs-devp2p", None, (2, 1, 3), None, Some("linux-x64"), Some("nodejs")),
+		];
+
+		for (id, name, identity, (major, minor, patch), suffix, os, compiler) in cases {
+			let parsed = match ClientVersion::from(id) {
+				ClientVersion::Parsed(v) => v,
+				ClientVersion::Raw(_) => panic!("Failed to parse {}", id),
+			};
+			assert_eq!(parsed.name(), name, "{}", id);
+			assert_eq!(parsed.identity(), identity, "{}", id);
+			assert_eq!(parsed.version(), &Version::new(major, minor, patch), "{}", id);
+			assert_eq!(parsed.version_suffix(), suffix, "{}", id);
+			assert_eq!(parsed.os(), os, "{}", id);
+			assert_eq!(parsed.compiler(), compiler, "{}", id);
+		}
+	}
+
+	#[test]
+	fn falls_back_to_raw() {
+		for id in &["", "Parity", "unknown", "Geth/unstable/linux", "/v1.0.0/linux", "Foo/v1.x.0"] {
+			assert_eq!(ClientVersion::from(*id), ClientVersion::Raw(id.to_string()));
+			assert_eq!(ClientVersion::from(*id).to_string(), *id);
+		}
+	}
+
+	#[test]
+	fn compatibility() {
+		let parity = ClientVersion::from("Parity/v1.10.0-stable-b9d9b9e-20180315/x86_64-linux-gnu/rustc1.24.1");
+		assert!(parity.is_compatible_with("Parity", ">=1.9"));
+		assert!(parity.is_compatible_with("parity", "^1.10"));
+		assert!(!parity.is_compatible_with("Parity", ">=1.11"));
+		assert!(!parity.is_compatible_with("Geth", ">=1.0"));
+		assert!(!parity.is_compatible_with("Parity", "not a requirement"));
+		assert!(!ClientVersion::from("garbage").is_compatible_with("garbage", "*"));
+	}
+}
@@ -33,6 +33,8 @@ pub enum DisconnectReason
 	UnexpectedIdentity,
 	LocalIdentity,
 	PingTimeout,
+	/// Dropped by the local connection filter. Not a devp2p reason, peers are sent `UselessPeer`.
+	ConnectionFiltered,
 	Unknown,
 }
 
@@ -54,6 +56,14 @@ impl DisconnectReason {
 			_ => DisconnectReason::Unknown,
 		}
 	}
+
+	/// Reason code sent to the peer. Local reasons are sent as the closest devp2p reason.
+	pub fn wire_code(&self) -> u8 {
+		match *self {
+			DisconnectReason::ConnectionFiltered => DisconnectReason::UselessPeer as u8,
+			reason => reason as u8,
+		}
+	}
 }
 
 impl fmt::Display for DisconnectReason {
@@ -73,6 +83,7 @@ impl fmt::Display for DisconnectReason {
 			UnexpectedIdentity => "unexpected identity",
 			LocalIdentity => "local identity",
 			PingTimeout => "ping timeout",
+			ConnectionFiltered => "connection filtered",
 			Unknown => "unknown",
 		};
 
@@ -136,6 +147,60 @@ error_chain! {
 			description("Packet is too large"),
 			display("Packet is too large"),
 		}
+
+		#[doc = "Packet id is outside the range declared by the protocol"]
+		PacketIdOutOfRange(id: u8, count: u8) {
+			description("Packet id out of range"),
+			display("Packet id {} is outside the protocol range of {} packets", id, count),
+		}
+
+		#[doc = "Protocol version declares an invalid number of packet ids"]
+		InvalidPacketCount(version: u8, count: u8) {
+			description("Invalid packet count"),
+			display("Invalid packet count {} for protocol version {}", count, version),
+		}
+
+		#[doc = "The peer has disconnected"]
+		PeerGone {
+			description("Peer is gone"),
+			display("Peer is gone"),
+		}
+
+		#[doc = "The session is still handshaking"]
+		SessionNotReady {
+			description("Session is not ready"),
+			display("Session is not ready"),
+		}
+
+		#[doc = "All timer tokens are in use"]
+		TooManyTimers {
+			description("Too many timers"),
+			display("Too many timers"),
+		}
+
+		#[doc = "Too much data is queued for sending to the peer"]
+		SendQueueFull {
+			description("Send queue is full"),
+			display("Send queue is full"),
+		}
+
+		#[doc = "Client identifier is not printable ASCII or too long"]
+		InvalidClientVersion {
+			description("Invalid client version"),
+			display("Client version must be printable ASCII of at most 256 bytes"),
+		}
+
+		#[doc = "All session slots are taken"]
+		AtCapacity {
+			description("At capacity"),
+			display("All session slots are taken"),
+		}
+
+		#[doc = "The remote node presented our own node id during the handshake"]
+		SelfConnection {
+			description("Connection to self"),
+			display("Remote node presented our own node id"),
+		}
 	}
 }
 
@@ -160,6 +225,9 @@ impl From<crypto::Error> for Error {
 #[test]
 fn test_errors() {
 	assert_eq!(DisconnectReason::ClientQuit, DisconnectReason::from_u8(8));
+	assert_eq!(DisconnectReason::Unknown, DisconnectReason::from_u8(12));
+	assert_eq!(DisconnectReason::UselessPeer, DisconnectReason::from_u8(DisconnectReason::ConnectionFiltered.wire_code()));
+	assert_eq!(DisconnectReason::PingTimeout, DisconnectReason::from_u8(DisconnectReason::PingTimeout.wire_code()));
 	let mut r = DisconnectReason::DisconnectRequested;
 	for i in 0 .. 20 {
 		r = DisconnectReason::from_u8(i);
@@ -23,20 +23,26 @@ extern crate ethkey;
 extern crate rlp;
 extern crate ipnetwork;
 extern crate snappy;
+extern crate semver;
 
 #[macro_use]
 extern crate error_chain;
 
+mod client_version;
 mod error;
+mod request;
 
 pub use io::TimerToken;
 pub use error::{Error, ErrorKind, DisconnectReason};
+pub use client_version::{ClientVersion, ParsedClientVersion};
+pub use request::{RequestHandle, RequestFrame, encode_request, encode_response, decode_request_frame};
 
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::{SocketAddr, SocketAddrV4, Ipv4Addr};
 use std::str::{self, FromStr};
 use std::sync::Arc;
+use std::time::Duration;
 use ipnetwork::{IpNetwork, IpNetworkError};
 use io::IoChannel;
 use ethkey::Secret;
@@ -63,10 +69,15 @@ pub enum NetworkIoMessage {
 		handler: Arc<NetworkProtocolHandler + Sync>,
 		/// Protocol Id.
 		protocol: ProtocolId,
-		/// Supported protocol versions.
-		versions: Vec<u8>,
-		/// Number of packet IDs reserved by the protocol.
-		packet_count: u8,
+		/// Supported protocol versions with the number of packet IDs reserved by each of them.
+		versions: Vec<(u8, u8)>,
+		/// Interval of `NetworkProtocolHandler::peer_tick` calls. `None` disables them.
+		peer_tick: Option<Duration>,
+	},
+	/// Remove a protocol handler.
+	RemoveHandler {
+		/// Protocol Id.
+		protocol: ProtocolId,
 	},
 	/// Register a new protocol timer
 	AddTimer {
@@ -80,11 +91,36 @@ pub enum NetworkIoMessage {
 	/// Initliaze public interface.
 	InitPublicInterface,
 	/// Disconnect a peer.
-	Disconnect(PeerId),
+	Disconnect {
+		/// Peer to disconnect.
+		peer: PeerId,
+		/// Reason sent to the peer.
+		reason: DisconnectReason,
+		/// Prevent the peer from connecting again.
+		ban: bool,
+	},
 	/// Disconnect and temporary disable peer.
 	DisablePeer(PeerId),
+	/// Misbehaviour reported by a protocol handler.
+	ReportPeer {
+		/// Reported peer.
+		peer: PeerId,
+		/// Report details.
+		report: PeerReport,
+	},
 	/// Network has been started with the host as the given enode.
 	NetworkStarted(String),
+	/// Host names of nodes to dial have been resolved in the background.
+	NodesResolved,
+	/// Connections through the outbound proxy have been made in the background.
+	ProxyConnected,
+	/// Pause or resume delivering the packets of a protocol.
+	SetReadPaused {
+		/// Protocol Id.
+		protocol: ProtocolId,
+		/// Hold packets if set, deliver the held ones and resume otherwise.
+		paused: bool,
+	},
 }
 
 /// Shared session information
@@ -93,8 +129,8 @@ pub struct SessionInfo {
 	/// Peer public key
 	pub id: Option<NodeId>,
 	/// Peer client ID
-	pub client_version: String,
-	/// Peer RLPx protocol version
+	pub client_version: ClientVersion,
+	/// RLPx protocol version negotiated with the peer: the lower of both advertised versions.
 	pub protocol_version: u32,
 	/// Session protocol capabilities
 	pub capabilities: Vec<SessionCapabilityInfo>,
@@ -102,12 +138,25 @@ pub struct SessionInfo {
 	pub peer_capabilities: Vec<PeerCapabilityInfo>,
 	/// Peer ping delay in milliseconds
 	pub ping_ms: Option<u64>,
+	/// Reason the session was disconnected with, if any.
+	pub disconnect_reason: Option<DisconnectReason>,
 	/// True if this session was originated by us.
 	pub originated: bool,
 	/// Remote endpoint address of the session
 	pub remote_address: String,
 	/// Local endpoint address of the session
 	pub local_address: String,
+	/// Remote socket address, known once the handshake is complete.
+	pub remote_socket_addr: Option<SocketAddr>,
+	/// Direction of the connection.
+	pub direction: ConnectionDirection,
+}
+
+/// Connection direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionDirection {
+	Inbound,
+	Outbound,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -137,6 +186,69 @@ impl ToString for PeerCapabilityInfo {
 	}
 }
 
+/// Packets exchanged with a peer over a single protocol.
+/// Sizes are payload sizes before compression.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerTraffic {
+	/// Number of packets sent.
+	pub packets_sent: u64,
+	/// Number of packets received.
+	pub packets_received: u64,
+	/// Total payload bytes sent.
+	pub bytes_sent: u64,
+	/// Total payload bytes received.
+	pub bytes_received: u64,
+}
+
+/// How bad a reported peer misbehaviour is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+	/// Unhelpful but possibly honest behaviour, e.g. a slow or incomplete response.
+	Minor,
+	/// Clearly wrong behaviour, e.g. an invalid response.
+	Major,
+	/// Malicious or broken peer. The peer is banned.
+	Fatal,
+}
+
+/// Peer misbehaviour report for `NetworkContext::report_peer`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerReport {
+	/// How bad the misbehaviour is.
+	pub severity: Severity,
+	/// Human readable description, for logging.
+	pub reason: String,
+}
+
+impl PeerReport {
+	/// Create a new report.
+	pub fn new(severity: Severity, reason: &str) -> PeerReport {
+		PeerReport {
+			severity: severity,
+			reason: reason.to_owned(),
+		}
+	}
+}
+
+/// Selects the peers a packet is broadcast to. Only peers of the current protocol are considered.
+pub enum PeerSelector<'a> {
+	/// All connected peers.
+	AllPeers,
+	/// Up to the given number of peers, chosen at random.
+	RandomSubset(usize),
+	/// Peers for which the predicate returns true.
+	Matching(&'a Fn(PeerId) -> bool),
+}
+
+/// Outcome of `NetworkContext::broadcast`.
+#[derive(Debug, Default)]
+pub struct BroadcastResult {
+	/// Peers the packet was sent to.
+	pub sent: Vec<PeerId>,
+	/// Peers the packet could not be sent to, with the error.
+	pub failed: Vec<(PeerId, Error)>,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SessionCapabilityInfo {
 	pub protocol: [u8; 3],
@@ -162,6 +274,98 @@ impl Ord for SessionCapabilityInfo {
 	}
 }
 
+/// Inbound traffic limits applied to each peer session. A zero rate means no limit.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct RateLimit {
+	/// Sustained number of packets per second.
+	pub packets_per_sec: u32,
+	/// Sustained number of bytes per second.
+	pub bytes_per_sec: u32,
+	/// Excess traffic tolerated, in seconds worth of the rate, before the peer is disconnected.
+	pub hard_limit_secs: u32,
+}
+
+/// Limits for the FindNode requests discovery serves to a single node. Zero disables a limit.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct FindNodeRateLimit {
+	/// Sustained number of requests per minute.
+	pub requests_per_minute: u32,
+	/// Number of requests that may be served at once after a quiet period.
+	pub burst: u32,
+	/// Number of distinct lookup targets per minute.
+	pub targets_per_minute: u32,
+}
+
+/// Local network peer discovery settings.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct LanDiscoveryConfig {
+	/// Broadcast or multicast address announcements are sent to and received on.
+	pub address: SocketAddr,
+	/// Interval between announcements.
+	pub interval: Duration,
+	/// Network id carried by announcements. Announcements of other networks are ignored.
+	pub network_id: u64,
+	/// Run even if a public `public_address` is configured.
+	pub force: bool,
+}
+
+/// Dialing pace right after start.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct StartupBurstConfig {
+	/// Time after start the burst lasts at most. It ends early once `min_peers` is reached.
+	pub duration: Duration,
+	/// Interval of connection rounds during the burst.
+	pub interval: Duration,
+	/// Dials started per connection round during the burst, up to `max_handshakes` in progress.
+	pub dials_per_round: u32,
+}
+
+/// Desired number of peers supporting a protocol.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ProtocolPeerTarget {
+	/// Protocol id.
+	pub protocol: ProtocolId,
+	/// Lowest protocol version counted.
+	pub min_version: u8,
+	/// Number of peers.
+	pub peers: u32,
+}
+
+/// Peer slots kept for peers supporting a protocol.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct SlotReservation {
+	/// Protocol id.
+	pub protocol: ProtocolId,
+	/// Lowest protocol version the slots are kept for.
+	pub min_version: u8,
+	/// Number of slots.
+	pub slots: u32,
+}
+
+/// SOCKS5 proxy for outbound connections.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SocksConfig {
+	/// Proxy address.
+	pub address: SocketAddr,
+	/// Username and password, if the proxy requires authentication.
+	pub auth: Option<(String, String)>,
+	/// Timeout of connecting to the proxy and of each step of the proxy negotiation. Must not be zero.
+	pub timeout: Duration,
+}
+
+/// TCP options applied to every peer socket. `None` leaves the OS default in place.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct SocketOptions {
+	/// Disable Nagle's algorithm (`TCP_NODELAY`).
+	pub nodelay: Option<bool>,
+	/// Idle time before TCP keepalive probes are sent.
+	pub keepalive: Option<Duration>,
+	/// Socket send buffer size in bytes (`SO_SNDBUF`).
+	pub send_buffer_size: Option<usize>,
+	/// Socket receive buffer size in bytes (`SO_RCVBUF`).
+	pub recv_buffer_size: Option<usize>,
+}
+
 /// Network service configuration
 #[derive(Debug, PartialEq, Clone)]
 pub struct NetworkConfiguration {
@@ -171,12 +375,37 @@ pub struct NetworkConfiguration {
 	pub net_config_path: Option<String>,
 	/// IP address to listen for incoming connections. Listen to all connections by default
 	pub listen_address: Option<SocketAddr>,
+	/// Additional addresses to listen for incoming connections on, e.g. a second interface or an IPv6 address.
+	pub additional_listen_addresses: Vec<SocketAddr>,
+	/// Number of times binding `listen_address` is attempted while the port is in use.
+	pub listen_bind_attempts: u32,
+	/// Delay between attempts to bind `listen_address`.
+	pub listen_bind_retry_delay: Duration,
+	/// If set, the listener binds the first free port among the given number of ports following the
+	/// configured one when that is still in use after all attempts. The bound port is advertised.
+	pub port_fallback_range: Option<u16>,
+	/// Proxy outbound peer connections are made through. Discovery does not use the proxy and
+	/// should be disabled when the node must not be reached directly.
+	pub outbound_proxy: Option<SocksConfig>,
 	/// IP address to advertise. Detected automatically if none.
 	pub public_address: Option<SocketAddr>,
 	/// Port for UDP connections, same as TCP by default
 	pub udp_port: Option<u16>,
 	/// Enable NAT configuration
 	pub nat_enabled: bool,
+	/// Announce this node on the local network and dial the nodes announcing themselves there.
+	/// `None` disables it.
+	pub lan_discovery: Option<LanDiscoveryConfig>,
+	/// Number of discovery peers that have to report the same address for it to be used as the public
+	/// address when neither `public_address` nor NAT configuration gives one. Zero disables it.
+	pub external_ip_quorum: usize,
+	/// Plain HTTP services that respond with the caller's IP address, tried in order when no other source
+	/// gives a public address.
+	pub external_ip_probe_urls: Vec<String>,
+	/// Timeout of UPnP gateway search and each HTTP probe.
+	pub external_ip_timeout: Duration,
+	/// Interval of public address re-detection.
+	pub external_address_recheck: Duration,
 	/// Enable discovery
 	pub discovery_enabled: bool,
 	/// List of initial node addresses
@@ -187,18 +416,182 @@ pub struct NetworkConfiguration {
 	pub min_peers: u32,
 	/// Maximum allowed number of peers
 	pub max_peers: u32,
+	/// Split of non-reserved peer slots between incoming and outgoing connections, as
+	/// (incoming, outgoing) weights. When not set at most `min_peers` slots are used for outgoing
+	/// connections and the rest of `max_peers` is left for incoming ones.
+	pub inbound_ratio: Option<(u32, u32)>,
+	/// Time the peer count has to stay under the low watermark before `PeerCountEvent::BelowLow` is raised.
+	pub peer_count_grace: Duration,
+	/// Interval of the connection maintenance: dialing, keep-alive pings and expiry of stale connections.
+	/// At least 100 ms.
+	pub maintain_interval: Duration,
+	/// Dials started per maintenance round, up to `max_handshakes` in progress. At least 1.
+	pub max_dials_per_tick: u32,
+	/// Dial faster after start until `min_peers` is reached. `None` dials at the regular pace from the start.
+	pub startup_burst: Option<StartupBurstConfig>,
+	/// Number of events buffered for each `NetworkService::subscribe_events` subscriber.
+	/// The oldest events are dropped when a subscriber falls behind.
+	pub event_queue_size: usize,
+	/// Percentage of outgoing connection attempts made to randomly chosen nodes instead of the best scored ones.
+	pub dial_exploration_percent: u32,
+	/// Desired peer counts for protocols. While a registered protocol has fewer peers than its target,
+	/// nodes known to support it are dialed first.
+	pub protocol_peer_targets: Vec<ProtocolPeerTarget>,
+	/// Age after which the capabilities recorded for a node are no longer used to prefer it.
+	pub capability_max_age: Duration,
 	/// Maximum handshakes
 	pub max_handshakes: u32,
+	/// Maximum handshakes in progress for incoming connections. Further incoming connections are
+	/// closed right away, except from addresses of reserved nodes.
+	pub max_incoming_handshakes: u32,
 	/// Reserved protocols. Peers with <key> protocol get additional <value> connection slots.
 	pub reserved_protocols: HashMap<ProtocolId, u32>,
+	/// Slots out of `max_peers` that other peers can't take. A peer a reservation is kept for replaces
+	/// the lowest scored other peer if all slots are taken.
+	pub slot_reservations: Vec<SlotReservation>,
 	/// List of reserved node addresses.
 	pub reserved_nodes: Vec<String>,
 	/// The non-reserved peer mode.
 	pub non_reserved_mode: NonReservedPeerMode,
+	/// When switching to `NonReservedPeerMode::Deny` at runtime, disconnect non-reserved peers one per
+	/// maintenance round instead of all at once.
+	pub graceful_non_reserved_disconnect: bool,
 	/// IP filter
 	pub ip_filter: IpFilter,
+	/// Accept nodes advertising private, loopback or link-local addresses from discovery even though the
+	/// public address is global. Useful for test networks. Always allowed when the public address is not global.
+	pub allow_non_global_ips: bool,
+	/// Only connect to and accept peers within these networks. Empty list allows all addresses.
+	pub ip_allowlist: Vec<IpNetwork>,
+	/// Never connect to or accept peers within these networks. Takes precedence over `ip_allowlist`.
+	pub ip_denylist: Vec<IpNetwork>,
+	/// Reserved peers are not checked against `ip_allowlist` and `ip_denylist`.
+	pub reserved_bypass_ip_lists: bool,
+	/// Only connect to and accept peers with these node ids, given as hex ids or enode URLs, and do not
+	/// bond with other nodes in discovery. Reserved peers are not exempt. `None` allows all nodes.
+	pub node_allowlist: Option<Vec<String>>,
+	/// File with further allowed node ids, one hex id or enode URL per line. Enables the node allowlist.
+	pub node_allowlist_path: Option<String>,
+	/// Number of recent connection filter denials kept for `NetworkService::filter_stats`.
+	pub filter_audit_size: usize,
+	/// Maximum number of connections held while the connection filter decides. Further connections
+	/// waiting for a decision are refused.
+	pub max_pending_filter_decisions: usize,
+	/// Time to wait for a pending connection filter decision before refusing the connection.
+	pub filter_decision_timeout: Duration,
 	/// Client identifier
 	pub client_version: String,
+	/// Client identifier advertised in the Hello packet instead of `client_version`, e.g. with an operator
+	/// tag or without OS details. Must be printable ASCII of at most 256 bytes.
+	pub client_version_override: Option<String>,
+	/// Time given to peers to receive the disconnect packets on shutdown.
+	pub shutdown_drain_timeout: Duration,
+	/// Time given to a peer disconnected by the host, e.g. over the peer limit or by the connection
+	/// filter, to receive the data already queued for it before the Disconnect packet is sent.
+	/// Misbehaving peers are disconnected right away.
+	pub disconnect_drain_timeout: Duration,
+	/// Interval between keep-alive pings sent to connected peers.
+	pub ping_interval: Duration,
+	/// Peers that send nothing for this long are disconnected. Should be greater than `ping_interval`.
+	pub session_idle_timeout: Duration,
+	/// Time a peer has to send its Hello packet after the encrypted connection is established.
+	pub hello_timeout: Duration,
+	/// Inbound protocol packet limits for each peer. `None` disables rate limiting.
+	pub peer_rate_limit: Option<RateLimit>,
+	/// Do not apply `peer_rate_limit` to reserved peers.
+	pub rate_limit_exempt_reserved: bool,
+	/// Maximum number of bytes queued for sending to a peer. Protocol packets are refused with
+	/// `SendQueueFull` while the queue is at the limit. Peers with more than four times the limit
+	/// queued are disconnected. Zero disables the limit.
+	pub send_queue_limit: usize,
+	/// Number of packets held for a protocol that paused reading with `NetworkContext::set_read_paused`
+	/// after which nothing more is read from the peer until reading is resumed. Zero disables the limit.
+	pub paused_packet_limit: usize,
+	/// Log the number of sessions established and ended in the last hour and their durations once per hour.
+	pub log_session_churn: bool,
+	/// Disconnect peers that send packets with an id outside of all negotiated protocols.
+	/// Such packets are always counted and dropped.
+	pub disconnect_on_unknown_packet: bool,
+	/// Number of payload bytes included in packet trace events. Zero leaves the payload out.
+	pub packet_trace_payload_bytes: usize,
+	/// Advertise p2p protocol version 5 and snappy compress packets exchanged with peers that advertise it too.
+	/// When disabled, version 4 is advertised and packets are never compressed.
+	pub compression: bool,
+	/// Maximum payload size of a single RLPx frame. Larger packets are sent as chunked frames.
+	pub max_frame_size: usize,
+	/// Maximum size of a packet received in chunked frames. Also limits all partially received
+	/// chunked packets of a session together.
+	pub max_chunked_packet_size: usize,
+	/// Time to receive all frames of a chunked packet. Incomplete packets are dropped after that.
+	pub chunked_packet_timeout: Duration,
+	/// Time to wait for a discovery pong.
+	pub discovery_ping_timeout: Duration,
+	/// Number of times an unanswered discovery ping is repeated before the node is evicted.
+	pub discovery_ping_retries: u32,
+	/// Discovery packets are accepted for this long past their expiry time, to allow for clocks running apart.
+	pub discovery_clock_skew: Duration,
+	/// Number of recent discovery packets whose sender and arrival time are remembered. Zero disables the cache.
+	pub discovery_packet_cache_size: usize,
+	/// Max number of discovery packets waiting to be sent. Further requests of our own are dropped,
+	/// pongs replace them.
+	pub discovery_send_queue_limit: usize,
+	/// Copies of a discovery packet from the same address are dropped for this long. Zero disables the check.
+	pub discovery_duplicate_window: Duration,
+	/// Slow discovery lookups down as the number of connected peers grows from `min_peers` to `max_peers`.
+	/// Queries are still answered and bonds kept.
+	pub discovery_adaptive: bool,
+	/// Interval between discovery lookups below `min_peers`, or always if discovery is not adaptive.
+	pub discovery_min_lookup_interval: Duration,
+	/// Interval between adaptive discovery lookups at `max_peers`.
+	pub discovery_max_lookup_interval: Duration,
+	/// Limits for the FindNode requests served to a single node. Requests over the limits are dropped.
+	pub discovery_find_node_limit: FindNodeRateLimit,
+	/// Limits for the FindNode requests served to reserved nodes in the discovery table.
+	pub discovery_reserved_find_node_limit: FindNodeRateLimit,
+	/// TCP options for peer connections.
+	pub socket_options: SocketOptions,
+	/// Delay before redialing a boot node after its first failure. Doubles with every consecutive failure.
+	pub boot_node_backoff: Duration,
+	/// Upper bound for the boot node redial delay.
+	pub boot_node_max_backoff: Duration,
+	/// Once every boot node has failed this many times in a row, the most recently contacted nodes from
+	/// the node table are used as seeds.
+	pub boot_node_fallback_threshold: u32,
+	/// Delay before redialing a node after a failed attempt. Doubles with every consecutive failure
+	/// and is shortened by a random jitter of up to half of it.
+	pub dial_backoff: Duration,
+	/// Upper bound for the redial delay.
+	pub dial_max_backoff: Duration,
+	/// Upper bound for the redial delay of reserved nodes.
+	pub reserved_dial_max_backoff: Duration,
+	/// Interval at which reserved nodes given by host name are resolved again while connected. Zero disables it.
+	pub reserved_resolve_interval: Duration,
+	/// Disconnect a reserved node that no longer resolves to the address of its session,
+	/// so that it is dialed again at the new address.
+	pub reconnect_on_address_change: bool,
+	/// Consecutive failed connection attempts after which a node is quarantined: it is not dialed
+	/// until `quarantine_period` has passed or it connects to us. Reserved nodes are exempt. Zero disables quarantine.
+	pub quarantine_threshold: u32,
+	/// How long a quarantined node is not dialed for.
+	pub quarantine_period: Duration,
+	/// Maximum number of entries in the node table. Nodes that never connected and those seen least
+	/// recently are evicted first; reserved and recently contacted nodes are kept.
+	pub node_table_max_size: usize,
+	/// Interval at which the node table is saved to `net_config_path`. Zero saves it only on shutdown
+	/// and on `flush_node_table`.
+	pub node_table_save_interval: Duration,
+	/// Network the node table is kept for. The table is stored in a `network-<id>` subdirectory of
+	/// `net_config_path`, so that networks don't share nodes. `None` stores it in `net_config_path`.
+	/// Discovery packets carry no network id, so nodes of other networks are still found by discovery.
+	pub network_id: Option<u64>,
+	/// Misbehaviour score at which a peer is disconnected. The peer is not accepted again until its
+	/// score has decayed below the threshold. Zero disables scoring.
+	pub misbehaviour_threshold: u32,
+	/// Scores decay by `misbehaviour_threshold` points over this period, so a peer is only disconnected
+	/// for reports adding up to the threshold within about this time.
+	pub misbehaviour_window: Duration,
+	/// How long a peer reported with `Severity::Fatal` is banned for.
+	pub misbehaviour_ban: Duration,
 }
 
 impl Default for NetworkConfiguration {
@@ -214,20 +607,100 @@ impl NetworkConfiguration {
 			config_path: None,
 			net_config_path: None,
 			listen_address: None,
+			additional_listen_addresses: Vec::new(),
+			listen_bind_attempts: 3,
+			listen_bind_retry_delay: Duration::from_millis(500),
+			port_fallback_range: None,
+			outbound_proxy: None,
 			public_address: None,
 			udp_port: None,
 			nat_enabled: true,
+			lan_discovery: None,
+			external_ip_quorum: 3,
+			external_ip_probe_urls: Vec::new(),
+			external_ip_timeout: Duration::from_secs(5),
+			external_address_recheck: Duration::from_secs(10 * 60),
 			discovery_enabled: true,
 			boot_nodes: Vec::new(),
 			use_secret: None,
 			min_peers: 25,
 			max_peers: 50,
+			inbound_ratio: None,
+			peer_count_grace: Duration::from_secs(60),
+			maintain_interval: Duration::from_secs(1),
+			max_dials_per_tick: 32,
+			startup_burst: Some(StartupBurstConfig {
+				duration: Duration::from_secs(30),
+				interval: Duration::from_millis(200),
+				dials_per_round: 32,
+			}),
+			event_queue_size: 1024,
+			dial_exploration_percent: 10,
+			protocol_peer_targets: Vec::new(),
+			capability_max_age: Duration::from_secs(24 * 60 * 60),
 			max_handshakes: 64,
+			max_incoming_handshakes: 32,
 			reserved_protocols: HashMap::new(),
+			slot_reservations: Vec::new(),
 			ip_filter: IpFilter::default(),
+			allow_non_global_ips: false,
+			ip_allowlist: Vec::new(),
+			ip_denylist: Vec::new(),
+			reserved_bypass_ip_lists: true,
+			node_allowlist: None,
+			node_allowlist_path: None,
+			filter_audit_size: 64,
+			max_pending_filter_decisions: 32,
+			filter_decision_timeout: Duration::from_secs(5),
 			reserved_nodes: Vec::new(),
 			non_reserved_mode: NonReservedPeerMode::Accept,
+			graceful_non_reserved_disconnect: false,
 			client_version: "Parity-network".into(),
+			client_version_override: None,
+			shutdown_drain_timeout: Duration::from_secs(2),
+			disconnect_drain_timeout: Duration::from_secs(5),
+			ping_interval: Duration::from_secs(120),
+			session_idle_timeout: Duration::from_secs(180),
+			hello_timeout: Duration::from_secs(5),
+			peer_rate_limit: None,
+			rate_limit_exempt_reserved: true,
+			send_queue_limit: 16 * 1024 * 1024,
+			paused_packet_limit: 256,
+			log_session_churn: false,
+			disconnect_on_unknown_packet: false,
+			packet_trace_payload_bytes: 0,
+			compression: true,
+			max_frame_size: (1 << 24) - 1,
+			max_chunked_packet_size: 32 * 1024 * 1024,
+			chunked_packet_timeout: Duration::from_secs(30),
+			discovery_ping_timeout: Duration::from_millis(1000),
+			discovery_ping_retries: 2,
+			discovery_clock_skew: Duration::from_secs(0),
+			discovery_packet_cache_size: 1024,
+			discovery_send_queue_limit: 1024,
+			discovery_duplicate_window: Duration::from_millis(500),
+			discovery_adaptive: true,
+			discovery_min_lookup_interval: Duration::from_secs(60),
+			discovery_max_lookup_interval: Duration::from_secs(30 * 60),
+			discovery_find_node_limit: FindNodeRateLimit { requests_per_minute: 60, burst: 20, targets_per_minute: 30 },
+			discovery_reserved_find_node_limit: FindNodeRateLimit { requests_per_minute: 240, burst: 80, targets_per_minute: 120 },
+			socket_options: SocketOptions::default(),
+			boot_node_backoff: Duration::from_secs(5),
+			boot_node_max_backoff: Duration::from_secs(300),
+			boot_node_fallback_threshold: 3,
+			dial_backoff: Duration::from_secs(5),
+			dial_max_backoff: Duration::from_secs(10 * 60),
+			reserved_dial_max_backoff: Duration::from_secs(30),
+			reserved_resolve_interval: Duration::from_secs(5 * 60),
+			reconnect_on_address_change: true,
+			quarantine_threshold: 10,
+			quarantine_period: Duration::from_secs(6 * 60 * 60),
+			node_table_max_size: 8192,
+			node_table_save_interval: Duration::from_secs(5 * 60),
+			network_id: None,
+			misbehaviour_threshold: 100,
+			misbehaviour_window: Duration::from_secs(600),
+			misbehaviour_ban: Duration::from_secs(3600),
 		}
 	}
 
@@ -238,24 +711,70 @@ impl NetworkConfiguration {
 		config
 	}
 
-	/// Create new default configuration for localhost-only connection with random port (usefull for testing)
+	/// Create new default configuration for localhost-only connection with random port (usefull for testing).
+	/// The bound port is available from the service once started.
 	pub fn new_local() -> NetworkConfiguration {
+		NetworkConfiguration::new_local_with_port(0)
+	}
+
+	/// Create new configuration for localhost-only connection with specified listen port. Nothing is sent
+	/// off the host: discovery, UPnP and external address detection are disabled, and the node table
+	/// is only saved if `net_config_path` is set.
+	pub fn new_local_with_port(port: u16) -> NetworkConfiguration {
 		let mut config = NetworkConfiguration::new();
-		config.listen_address = Some(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 0)));
+		config.listen_address = Some(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port)));
 		config.nat_enabled = false;
+		config.discovery_enabled = false;
+		config.lan_discovery = None;
+		config.external_ip_quorum = 0;
+		config.external_ip_probe_urls = Vec::new();
 		config
 	}
 }
 
 /// IO access point. This is passed to all IO handlers and provides an interface to the IO subsystem.
 pub trait NetworkContext {
-	/// Send a packet over the network to another peer.
+	/// Send a packet over the network to another peer. Fails with `PeerGone` if the peer has disconnected,
+	/// `SessionNotReady` if the handshake is not complete or the connection filter has not decided yet, `SendQueueFull` if too much data is already
+	/// queued for the peer and `OversizedPacket` if the packet is too large.
 	fn send(&self, peer: PeerId, packet_id: PacketId, data: Vec<u8>) -> Result<(), Error>;
 
-	/// Send a packet over the network to another peer using specified protocol.
+	/// Send a packet over the network to another peer using specified protocol. Fails like `send`.
 	fn send_protocol(&self, protocol: ProtocolId, peer: PeerId, packet_id: PacketId, data: Vec<u8>) -> Result<(), Error>;
 
-	/// Respond to a current network message. Panics if no there is no packet in the context. If the session is expired returns nothing.
+	/// Send a packet to another peer, ignoring failures.
+	fn send_lossy(&self, peer: PeerId, packet_id: PacketId, data: Vec<u8>);
+
+	/// Send a packet to another peer once its handshake is complete. Until then a few packets are held for
+	/// the peer and sent in order right after the protocol handlers are notified of the connection.
+	/// Fails with `SendQueueFull` once too many packets are held. Packets held for a session that
+	/// closes before it is ready are dropped.
+	fn send_when_ready(&self, peer: PeerId, packet_id: PacketId, data: Vec<u8>) -> Result<(), Error>;
+
+	/// Send a request to another peer and track its response. The peer decodes the request with
+	/// `RequestHandle::decode` and answers it with `respond_to`. The response is delivered to
+	/// `NetworkProtocolHandler::response`, or `request_timed_out` is called if none arrives within `timeout`
+	/// or the peer disconnects first. Once a packet id has been used for requests, responses received in
+	/// it are never delivered to `read`. Fails like `send`.
+	fn send_request(&self, peer: PeerId, packet_id: PacketId, data: Vec<u8>, timeout: Duration) -> Result<RequestHandle, Error>;
+
+	/// Answer a request received from a peer. Fails like `send`.
+	fn respond_to(&self, request: RequestHandle, data: Vec<u8>) -> Result<(), Error>;
+
+	/// Send a packet to the selected peers of this protocol. The same payload is used for every peer.
+	/// A failure to send to one peer does not stop the broadcast, errors are returned per peer.
+	fn broadcast(&self, packet_id: PacketId, data: Vec<u8>, selector: PeerSelector) -> BroadcastResult;
+
+	/// Pick up to `count` connected peers of this protocol uniformly at random, leaving out `exclude`.
+	/// Fewer are returned if there are not enough peers.
+	fn sample_peers(&self, count: usize, exclude: &HashSet<PeerId>) -> Vec<PeerId>;
+
+	/// Like `sample_peers`, but each peer is picked with a chance proportional to `weight(peer)`.
+	/// Peers with a weight of zero or less are never picked. `weight` is called without any
+	/// session locked, so it may use the context.
+	fn sample_peers_weighted(&self, count: usize, exclude: &HashSet<PeerId>, weight: &Fn(PeerId) -> f64) -> Vec<PeerId>;
+
+	/// Respond to a current network message. Panics if no there is no packet in the context. Fails like `send`.
 	fn respond(&self, packet_id: PacketId, data: Vec<u8>) -> Result<(), Error>;
 
 	/// Get an IoChannel.
@@ -264,24 +783,65 @@ pub trait NetworkContext {
 	/// Disconnect a peer and prevent it from connecting again.
 	fn disable_peer(&self, peer: PeerId);
 
-	/// Disconnect peer. Reconnect can be attempted later.
-	fn disconnect_peer(&self, peer: PeerId);
+	/// Disconnect peer with the given reason. The session is closed once the Disconnect
+	/// packet has been sent. If `ban` is set the peer is prevented from connecting again,
+	/// otherwise reconnect can be attempted later.
+	fn disconnect_peer(&self, peer: PeerId, reason: DisconnectReason, ban: bool);
+
+	/// Report peer misbehaviour. Reports add up to a score for the node, the peer is disconnected
+	/// once the score reaches the configured threshold. Fatal reports ban the node.
+	fn report_peer(&self, peer: PeerId, report: PeerReport);
+
+	/// Hold the packets of `protocol` received from all peers instead of delivering them, or deliver the
+	/// held packets in order and resume. Ping, pong and disconnect packets are still processed while paused.
+	/// Once `paused_packet_limit` packets are held for a peer, nothing more is read from it until resumed.
+	fn set_read_paused(&self, protocol: ProtocolId, paused: bool);
 
 	/// Check if the session is still active.
 	fn is_expired(&self) -> bool;
 
 	/// Register a new IO timer. 'IoHandler::timeout' will be called with the token.
+	/// Replaces any previous schedule of the token.
 	fn register_timer(&self, token: TimerToken, ms: u64) -> Result<(), Error>;
 
+	/// Register a timer that fires once after `delay`. Replaces any previous schedule of the token.
+	fn register_timer_once(&self, token: TimerToken, delay: Duration) -> Result<(), Error>;
+
+	/// Cancel a timer. Once this returns no `timeout` call is made for the token, even if the timer
+	/// has already expired and the call is queued.
+	fn cancel_timer(&self, token: TimerToken) -> Result<(), Error>;
+
 	/// Returns peer identification string
-	fn peer_client_version(&self, peer: PeerId) -> String;
+	fn peer_client_version(&self, peer: PeerId) -> ClientVersion;
 
 	/// Returns information on p2p session
 	fn session_info(&self, peer: PeerId) -> Option<SessionInfo>;
 
+	/// Returns packet counters for the peer session, for the current protocol only.
+	/// Counters start from zero for every new session.
+	fn peer_traffic(&self, peer: PeerId) -> PeerTraffic;
+
+	/// Returns true if packets exchanged with the peer are snappy compressed, which is the case when both
+	/// sides advertise p2p protocol version 5 or later. False once the session is closed.
+	fn peer_compression_enabled(&self, peer: PeerId) -> bool;
+
+	/// Returns the number of bytes queued for sending to the peer.
+	fn queue_depth(&self, peer: PeerId) -> usize;
+
+	/// Returns the remote socket address of the peer. `None` once the session is closed or before the
+	/// handshake is complete.
+	fn peer_socket_addr(&self, peer: PeerId) -> Option<SocketAddr>;
+
+	/// Returns the direction of the peer connection. `None` once the session is closed.
+	fn peer_direction(&self, peer: PeerId) -> Option<ConnectionDirection>;
+
 	/// Returns max version for a given protocol.
 	fn protocol_version(&self, protocol: ProtocolId, peer: PeerId) -> Option<u8>;
 
+	/// Returns all protocols negotiated with the peer and their versions, whichever protocol the
+	/// context belongs to. Empty once the session is closed.
+	fn peer_protocols(&self, peer: PeerId) -> Vec<(ProtocolId, u8)>;
+
 	/// Returns this object's subprotocol name.
 	fn subprotocol_name(&self) -> ProtocolId;
 }
@@ -295,6 +855,34 @@ impl<'a, T> NetworkContext for &'a T where T: ?Sized + NetworkContext {
 		(**self).send_protocol(protocol, peer, packet_id, data)
 	}
 
+	fn send_lossy(&self, peer: PeerId, packet_id: PacketId, data: Vec<u8>) {
+		(**self).send_lossy(peer, packet_id, data)
+	}
+
+	fn send_when_ready(&self, peer: PeerId, packet_id: PacketId, data: Vec<u8>) -> Result<(), Error> {
+		(**self).send_when_ready(peer, packet_id, data)
+	}
+
+	fn send_request(&self, peer: PeerId, packet_id: PacketId, data: Vec<u8>, timeout: Duration) -> Result<RequestHandle, Error> {
+		(**self).send_request(peer, packet_id, data, timeout)
+	}
+
+	fn respond_to(&self, request: RequestHandle, data: Vec<u8>) -> Result<(), Error> {
+		(**self).respond_to(request, data)
+	}
+
+	fn broadcast(&self, packet_id: PacketId, data: Vec<u8>, selector: PeerSelector) -> BroadcastResult {
+		(**self).broadcast(packet_id, data, selector)
+	}
+
+	fn sample_peers(&self, count: usize, exclude: &HashSet<PeerId>) -> Vec<PeerId> {
+		(**self).sample_peers(count, exclude)
+	}
+
+	fn sample_peers_weighted(&self, count: usize, exclude: &HashSet<PeerId>, weight: &Fn(PeerId) -> f64) -> Vec<PeerId> {
+		(**self).sample_peers_weighted(count, exclude, weight)
+	}
+
 	fn respond(&self, packet_id: PacketId, data: Vec<u8>) -> Result<(), Error> {
 		(**self).respond(packet_id, data)
 	}
@@ -307,8 +895,16 @@ impl<'a, T> NetworkContext for &'a T where T: ?Sized + NetworkContext {
 		(**self).disable_peer(peer)
 	}
 
-	fn disconnect_peer(&self, peer: PeerId) {
-		(**self).disconnect_peer(peer)
+	fn disconnect_peer(&self, peer: PeerId, reason: DisconnectReason, ban: bool) {
+		(**self).disconnect_peer(peer, reason, ban)
+	}
+
+	fn report_peer(&self, peer: PeerId, report: PeerReport) {
+		(**self).report_peer(peer, report)
+	}
+
+	fn set_read_paused(&self, protocol: ProtocolId, paused: bool) {
+		(**self).set_read_paused(protocol, paused)
 	}
 
 	fn is_expired(&self) -> bool {
@@ -319,7 +915,15 @@ impl<'a, T> NetworkContext for &'a T where T: ?Sized + NetworkContext {
 		(**self).register_timer(token, ms)
 	}
 
-	fn peer_client_version(&self, peer: PeerId) -> String {
+	fn register_timer_once(&self, token: TimerToken, delay: Duration) -> Result<(), Error> {
+		(**self).register_timer_once(token, delay)
+	}
+
+	fn cancel_timer(&self, token: TimerToken) -> Result<(), Error> {
+		(**self).cancel_timer(token)
+	}
+
+	fn peer_client_version(&self, peer: PeerId) -> ClientVersion {
 		(**self).peer_client_version(peer)
 	}
 
@@ -327,10 +931,34 @@ impl<'a, T> NetworkContext for &'a T where T: ?Sized + NetworkContext {
 		(**self).session_info(peer)
 	}
 
+	fn peer_traffic(&self, peer: PeerId) -> PeerTraffic {
+		(**self).peer_traffic(peer)
+	}
+
+	fn peer_compression_enabled(&self, peer: PeerId) -> bool {
+		(**self).peer_compression_enabled(peer)
+	}
+
+	fn queue_depth(&self, peer: PeerId) -> usize {
+		(**self).queue_depth(peer)
+	}
+
+	fn peer_socket_addr(&self, peer: PeerId) -> Option<SocketAddr> {
+		(**self).peer_socket_addr(peer)
+	}
+
+	fn peer_direction(&self, peer: PeerId) -> Option<ConnectionDirection> {
+		(**self).peer_direction(peer)
+	}
+
 	fn protocol_version(&self, protocol: ProtocolId, peer: PeerId) -> Option<u8> {
 		(**self).protocol_version(protocol, peer)
 	}
 
+	fn peer_protocols(&self, peer: PeerId) -> Vec<(ProtocolId, u8)> {
+		(**self).peer_protocols(peer)
+	}
+
 	fn subprotocol_name(&self) -> ProtocolId {
 		(**self).subprotocol_name()
 	}
@@ -356,11 +984,21 @@ pub trait NetworkProtocolHandler: Sync + Send {
 	/// Called when new network packet received.
 	fn read(&self, io: &NetworkContext, peer: &PeerId, packet_id: u8, data: &[u8]);
 	/// Called when new peer is connected. Only called when peer supports the same protocol.
+	/// The other protocols negotiated with the peer are available from `io.peer_protocols`.
 	fn connected(&self, io: &NetworkContext, peer: &PeerId);
 	/// Called when a previously connected peer disconnects.
 	fn disconnected(&self, io: &NetworkContext, peer: &PeerId);
 	/// Timer function called after a timeout created with `NetworkContext::timeout`.
 	fn timeout(&self, _io: &NetworkContext, _timer: TimerToken) {}
+	/// Called for each connected peer at the interval requested on registration. Peers are ticked
+	/// at different times within the interval. Never called after `disconnected` for the peer.
+	/// Disconnects wait for a running tick to return, so it must not block.
+	fn peer_tick(&self, _io: &NetworkContext, _peer: &PeerId) {}
+	/// Called with the response to a request sent with `NetworkContext::send_request`.
+	fn response(&self, _io: &NetworkContext, _peer: &PeerId, _request: RequestHandle, _data: &[u8]) {}
+	/// Called when a request sent with `NetworkContext::send_request` got no response in time, or the peer
+	/// disconnected first. A response arriving later is dropped.
+	fn request_timed_out(&self, _io: &NetworkContext, _peer: &PeerId, _request: RequestHandle) {}
 }
 
 /// Non-reserved peer modes.
@@ -0,0 +1,105 @@
+// Copyright 2015-2017 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Packets of the request/response helper. The payload of a request or response is framed
+//! as `[kind, request id, payload]`.
+
+use rlp::{RlpStream, UntrustedRlp, DecoderError};
+use {PeerId, PacketId};
+
+const KIND_REQUEST: u8 = 0;
+const KIND_RESPONSE: u8 = 1;
+
+/// Request sent with `NetworkContext::send_request`, or received from a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RequestHandle {
+	/// Peer the request was sent to or received from.
+	pub peer: PeerId,
+	/// Packet id of the request and of its response.
+	pub packet_id: PacketId,
+	/// Request id. Chosen by the peer for received requests.
+	pub id: u64,
+}
+
+impl RequestHandle {
+	/// Decode a request packet received from `peer`. Returns the handle to answer it with
+	/// `NetworkContext::respond_to` and the request payload.
+	pub fn decode(peer: PeerId, packet_id: PacketId, data: &[u8]) -> Result<(RequestHandle, Vec<u8>), DecoderError> {
+		match decode_request_frame(data)? {
+			RequestFrame::Request(id, payload) => Ok((RequestHandle { peer: peer, packet_id: packet_id, id: id }, payload)),
+			RequestFrame::Response(..) => Err(DecoderError::Custom("Expected a request, got a response")),
+		}
+	}
+}
+
+/// Decoded request or response packet.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RequestFrame {
+	/// Request id and payload of a request.
+	Request(u64, Vec<u8>),
+	/// Id of the request answered and payload of a response.
+	Response(u64, Vec<u8>),
+}
+
+/// Frame the payload of a request.
+pub fn encode_request(id: u64, payload: &[u8]) -> Vec<u8> {
+	encode_frame(KIND_REQUEST, id, payload)
+}
+
+/// Frame the payload of a response.
+pub fn encode_response(id: u64, payload: &[u8]) -> Vec<u8> {
+	encode_frame(KIND_RESPONSE, id, payload)
+}
+
+fn encode_frame(kind: u8, id: u64, payload: &[u8]) -> Vec<u8> {
+	let mut stream = RlpStream::new_list(3);
+	stream.append(&kind);
+	stream.append(&id);
+	stream.append(&payload);
+	stream.out()
+}
+
+/// Decode a request or response packet.
+pub fn decode_request_frame(data: &[u8]) -> Result<RequestFrame, DecoderError> {
+	let rlp = UntrustedRlp::new(data);
+	if rlp.item_count()? != 3 {
+		return Err(DecoderError::RlpIncorrectListLen);
+	}
+	let id = rlp.val_at(1)?;
+	let payload = rlp.val_at(2)?;
+	match rlp.val_at::<u8>(0)? {
+		KIND_REQUEST => Ok(RequestFrame::Request(id, payload)),
+		KIND_RESPONSE => Ok(RequestFrame::Response(id, payload)),
+		_ => Err(DecoderError::Custom("Unknown request packet kind")),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn frames_roundtrip() {
+		let request = encode_request(7, b"ping");
+		assert_eq!(decode_request_frame(&request), Ok(RequestFrame::Request(7, b"ping".to_vec())));
+		assert_eq!(RequestHandle::decode(3, 0x10, &request), Ok((RequestHandle { peer: 3, packet_id: 0x10, id: 7 }, b"ping".to_vec())));
+
+		let response = encode_response(7, b"");
+		assert_eq!(decode_request_frame(&response), Ok(RequestFrame::Response(7, Vec::new())));
+		assert!(RequestHandle::decode(3, 0x10, &response).is_err());
+		assert!(decode_request_frame(b"ping").is_err());
+	}
+}
@@ -23,7 +23,7 @@ use std::time::{Duration, SystemTime};
 use std::sync::Arc;
 
 use ethereum_types::{H256, H512};
-use network::{self, HostInfo, NetworkContext, NodeId, PeerId, ProtocolId, TimerToken};
+use network::{self, DisconnectReason, HostInfo, NetworkContext, NodeId, PeerId, ProtocolId, TimerToken};
 use ordered_float::OrderedFloat;
 use parking_lot::{Mutex, RwLock};
 use rlp::{DecoderError, RlpStream, UntrustedRlp};
@@ -394,7 +394,7 @@ pub trait Context {
 
 impl<T> Context for T where T: ?Sized + NetworkContext {
 	fn disconnect_peer(&self, peer: PeerId) {
-		NetworkContext::disconnect_peer(self, peer);
+		NetworkContext::disconnect_peer(self, peer, DisconnectReason::DisconnectRequested, false);
 	}
 	fn disable_peer(&self, peer: PeerId) {
 		NetworkContext::disable_peer(self, peer)
@@ -407,11 +407,17 @@ impl<T> Context for T where T: ?Sized + NetworkContext {
 	}
 
 	fn send(&self, peer: PeerId, packet_id: u8, message: Vec<u8>) {
-		if let Err(e) = NetworkContext::send(self, peer, packet_id, message) {
-			debug!(target: "whisper", "Failed to send packet {} to peer {}: {}",
-				packet_id, peer, e);
+		match NetworkContext::send(self, peer, packet_id, message) {
+			Ok(()) => {},
+			Err(network::Error(network::ErrorKind::PeerGone, _)) => {
+				trace!(target: "whisper", "Peer {} is gone", peer);
+			},
+			Err(e) => {
+				debug!(target: "whisper", "Failed to send packet {} to peer {}: {}",
+					packet_id, peer, e);
 
-			self.disconnect_peer(peer)
+				self.disconnect_peer(peer)
+			},
 		}
 	}
 }